@@ -1,152 +1,32 @@
 use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
 
 use dialoguer::{theme::ColorfulTheme, MultiSelect};
 use filecoin_proofs::{
+    cache_params,
     constants::{
-        DefaultPieceHasher, POREP_PARTITIONS, PUBLISHED_SECTOR_SIZES, WINDOW_POST_CHALLENGE_COUNT,
-        WINDOW_POST_SECTOR_COUNT, WINNING_POST_CHALLENGE_COUNT, WINNING_POST_SECTOR_COUNT,
+        POREP_PARTITIONS, PUBLISHED_SECTOR_SIZES, UNPUBLISHED_SECTOR_SIZES,
+        WINDOW_POST_CHALLENGE_COUNT, WINDOW_POST_SECTOR_COUNT, WINNING_POST_CHALLENGE_COUNT,
+        WINNING_POST_SECTOR_COUNT,
     },
-    parameters::{public_params, window_post_public_params, winning_post_public_params},
-    types::{PaddedBytesAmount, PoRepConfig, PoRepProofPartitions, PoStConfig, SectorSize},
-    with_shape, PoStType,
+    param::{get_digest_for_file_within_cache, has_extension},
+    register_sector_size,
+    types::{PoRepConfig, PoRepProofPartitions, PoStConfig, SectorSize},
+    CustomSectorSize, ParameterSet, PoStType,
 };
 use humansize::{file_size_opts, FileSize};
 use indicatif::ProgressBar;
 use log::{error, info, warn};
-use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
 use storage_proofs_core::{
-    api_version::ApiVersion, compound_proof::CompoundProof, merkle::MerkleTreeTrait,
-    parameter_cache::CacheableParameters,
-};
-use storage_proofs_porep::stacked::{StackedCircuit, StackedCompound, StackedDrg};
-use storage_proofs_post::fallback::{FallbackPoSt, FallbackPoStCircuit, FallbackPoStCompound};
-use storage_proofs_update::constants::TreeRHasher;
-use storage_proofs_update::{
-    circuit::EmptySectorUpdateCircuit, compound::EmptySectorUpdateCompound, EmptySectorUpdate,
-    PublicParams,
+    api_version::ApiVersion,
+    parameter_cache::{ParameterData, ParameterMap, GROTH_PARAMETER_EXT, VERIFYING_KEY_EXT},
 };
 use structopt::StructOpt;
 
-fn cache_porep_params<Tree: 'static + MerkleTreeTrait>(porep_config: PoRepConfig) {
-    info!("generating PoRep groth params");
-
-    let public_params = public_params(
-        PaddedBytesAmount::from(porep_config),
-        usize::from(PoRepProofPartitions::from(porep_config)),
-        porep_config.porep_id,
-        porep_config.api_version,
-    )
-    .expect("failed to get public params from config");
-
-    let circuit = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
-        StackedDrg<Tree, DefaultPieceHasher>,
-        StackedCircuit<Tree, DefaultPieceHasher>,
-    >>::blank_circuit(&public_params);
-
-    let _ = StackedCompound::<Tree, DefaultPieceHasher>::get_param_metadata(
-        circuit.clone(),
-        &public_params,
-    )
-    .expect("failed to get metadata");
-
-    let _ = StackedCompound::<Tree, DefaultPieceHasher>::get_groth_params(
-        Some(&mut OsRng),
-        circuit.clone(),
-        &public_params,
-    )
-    .expect("failed to get groth params");
-
-    let _ = StackedCompound::<Tree, DefaultPieceHasher>::get_verifying_key(
-        Some(&mut OsRng),
-        circuit,
-        &public_params,
-    )
-    .expect("failed to get verifying key");
-}
-
-fn cache_winning_post_params<Tree: 'static + MerkleTreeTrait>(post_config: &PoStConfig) {
-    info!("generating Winning-PoSt groth params");
-
-    let public_params = winning_post_public_params::<Tree>(post_config)
-        .expect("failed to get public params from config");
-
-    let circuit = <FallbackPoStCompound<Tree> as CompoundProof<
-        FallbackPoSt<Tree>,
-        FallbackPoStCircuit<Tree>,
-    >>::blank_circuit(&public_params);
-
-    let _ = <FallbackPoStCompound<Tree>>::get_param_metadata(circuit.clone(), &public_params)
-        .expect("failed to get metadata");
-
-    let _ = <FallbackPoStCompound<Tree>>::get_groth_params(
-        Some(&mut OsRng),
-        circuit.clone(),
-        &public_params,
-    )
-    .expect("failed to get groth params");
-
-    let _ =
-        <FallbackPoStCompound<Tree>>::get_verifying_key(Some(&mut OsRng), circuit, &public_params)
-            .expect("failed to get verifying key");
-}
-
-fn cache_window_post_params<Tree: 'static + MerkleTreeTrait>(post_config: &PoStConfig) {
-    info!("generating Window-PoSt groth params");
-
-    let public_params = window_post_public_params::<Tree>(post_config)
-        .expect("failed to get public params from config");
-
-    let circuit: FallbackPoStCircuit<Tree> = <FallbackPoStCompound<Tree> as CompoundProof<
-        FallbackPoSt<Tree>,
-        FallbackPoStCircuit<Tree>,
-    >>::blank_circuit(&public_params);
-
-    let _ = <FallbackPoStCompound<Tree>>::get_param_metadata(circuit.clone(), &public_params)
-        .expect("failed to get metadata");
-
-    let _ = <FallbackPoStCompound<Tree>>::get_groth_params(
-        Some(&mut OsRng),
-        circuit.clone(),
-        &public_params,
-    )
-    .expect("failed to get groth params");
-
-    let _ =
-        <FallbackPoStCompound<Tree>>::get_verifying_key(Some(&mut OsRng), circuit, &public_params)
-            .expect("failed to get verifying key");
-}
-
-fn cache_empty_sector_update_params<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
-    porep_config: PoRepConfig,
-) {
-    info!("generating EmptySectorUpdate groth params");
-
-    let public_params: storage_proofs_update::PublicParams =
-        PublicParams::from_sector_size(u64::from(porep_config.sector_size));
-
-    let circuit = <EmptySectorUpdateCompound<Tree> as CompoundProof<
-        EmptySectorUpdate<Tree>,
-        EmptySectorUpdateCircuit<Tree>,
-    >>::blank_circuit(&public_params);
-
-    let _ = <EmptySectorUpdateCompound<Tree> as CompoundProof<
-        EmptySectorUpdate<Tree>,
-        EmptySectorUpdateCircuit<Tree>,
-    >>::groth_params::<OsRng>(Some(&mut OsRng), &public_params)
-    .expect("failed to get groth params");
-
-    let _ = <EmptySectorUpdateCompound<Tree>>::get_param_metadata(circuit, &public_params)
-        .expect("failed to get metadata");
-
-    let _ = <EmptySectorUpdateCompound<Tree> as CompoundProof<
-        EmptySectorUpdate<Tree>,
-        EmptySectorUpdateCircuit<Tree>,
-    >>::verifying_key::<OsRng>(Some(&mut OsRng), &public_params)
-    .expect("failed to get verifying key");
-}
-
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "paramcache",
@@ -161,6 +41,11 @@ struct Opt {
         help = "Only cache EmptySectorUpdate groth params."
     )]
     only_sector_update: bool,
+    #[structopt(
+        long,
+        help = "Also cache the groth params of the non-interactive PoRep."
+    )]
+    ni_porep: bool,
     #[structopt(
         short = "z",
         long,
@@ -175,26 +60,44 @@ struct Opt {
         help = "Use a specific rust-fil-proofs API version."
     )]
     api_version: String,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        value_name = "JSON FILE",
+        help = "Generate insecure, test-only params for the custom sector sizes listed in the file."
+    )]
+    custom: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        value_name = "JSON FILE",
+        default_value = "custom-parameters.json",
+        help = "Where to write the manifest of the params generated for custom sector sizes."
+    )]
+    manifest: PathBuf,
 }
 
-fn generate_params_post(sector_size: u64, api_version: ApiVersion) {
-    with_shape!(
-        sector_size,
-        cache_winning_post_params,
-        &PoStConfig {
-            sector_size: SectorSize(sector_size),
-            challenge_count: WINNING_POST_CHALLENGE_COUNT,
-            sector_count: WINNING_POST_SECTOR_COUNT,
-            typ: PoStType::Winning,
-            priority: true,
-            api_version,
-        }
-    );
+/// Describes the params generated for custom sector sizes. Nodes of a devnet register the
+/// `sector_sizes` before use and can check the cached files against `parameters`.
+#[derive(Debug, Serialize, Deserialize)]
+struct CustomManifest {
+    sector_sizes: Vec<CustomSectorSize>,
+    parameters: ParameterMap,
+}
 
-    with_shape!(
-        sector_size,
-        cache_window_post_params,
-        &PoStConfig {
+fn generate_params_post(sector_size: u64, api_version: ApiVersion) -> Vec<PathBuf> {
+    let mut paths = cache_params(&ParameterSet::PoSt(PoStConfig {
+        sector_size: SectorSize(sector_size),
+        challenge_count: WINNING_POST_CHALLENGE_COUNT,
+        sector_count: WINNING_POST_SECTOR_COUNT,
+        typ: PoStType::Winning,
+        priority: true,
+        api_version,
+    }))
+    .expect("failed to cache Winning-PoSt params");
+
+    paths.extend(
+        cache_params(&ParameterSet::PoSt(PoStConfig {
             sector_size: SectorSize(sector_size),
             challenge_count: WINDOW_POST_CHALLENGE_COUNT,
             sector_count: *WINDOW_POST_SECTOR_COUNT
@@ -205,46 +108,54 @@ fn generate_params_post(sector_size: u64, api_version: ApiVersion) {
             typ: PoStType::Window,
             priority: true,
             api_version,
-        }
+        }))
+        .expect("failed to cache Window-PoSt params"),
     );
+
+    paths
+}
+
+fn generate_params_porep(sector_size: u64, api_version: ApiVersion) -> Vec<PathBuf> {
+    cache_params(&ParameterSet::PoRep(PoRepConfig {
+        sector_size: SectorSize(sector_size),
+        partitions: PoRepProofPartitions(
+            *POREP_PARTITIONS
+                .read()
+                .expect("POREP_PARTITIONS poisoned")
+                .get(&sector_size)
+                .expect("unknown sector size"),
+        ),
+        porep_id: [0; 32],
+        api_version,
+        api_features: &[],
+    }))
+    .expect("failed to cache PoRep params")
 }
 
-fn generate_params_porep(sector_size: u64, api_version: ApiVersion) {
-    with_shape!(
+fn generate_params_ni_porep(sector_size: u64, api_version: ApiVersion) -> Vec<PathBuf> {
+    cache_params(&ParameterSet::PoRep(PoRepConfig::new_non_interactive(
         sector_size,
-        cache_porep_params,
-        PoRepConfig {
-            sector_size: SectorSize(sector_size),
-            partitions: PoRepProofPartitions(
-                *POREP_PARTITIONS
-                    .read()
-                    .expect("POREP_PARTITIONS poisoned")
-                    .get(&sector_size)
-                    .expect("unknown sector size"),
-            ),
-            porep_id: [0; 32],
-            api_version,
-        }
-    );
+        [0; 32],
+        api_version,
+    )))
+    .expect("failed to cache non-interactive PoRep params")
 }
 
 fn generate_params_empty_sector_update(sector_size: u64, api_version: ApiVersion) {
-    with_shape!(
-        sector_size,
-        cache_empty_sector_update_params,
-        PoRepConfig {
-            sector_size: SectorSize(sector_size),
-            partitions: PoRepProofPartitions(
-                *POREP_PARTITIONS
-                    .read()
-                    .expect("POREP_PARTITIONS poisoned")
-                    .get(&sector_size)
-                    .expect("unknown sector size"),
-            ),
-            porep_id: [0; 32],
-            api_version,
-        }
-    );
+    cache_params(&ParameterSet::EmptySectorUpdate(PoRepConfig {
+        sector_size: SectorSize(sector_size),
+        partitions: PoRepProofPartitions(
+            *POREP_PARTITIONS
+                .read()
+                .expect("POREP_PARTITIONS poisoned")
+                .get(&sector_size)
+                .expect("unknown sector size"),
+        ),
+        porep_id: [0; 32],
+        api_version,
+        api_features: &[],
+    }))
+    .expect("failed to cache EmptySectorUpdate params");
 }
 
 pub fn main() {
@@ -254,10 +165,25 @@ pub fn main() {
 
     let mut opts = Opt::from_args();
 
+    let api_version = ApiVersion::from_str(&opts.api_version)
+        .expect("Cannot parse API version from semver string (e.g. 1.1.0)");
+
+    if let Some(custom) = opts.custom.take() {
+        generate_custom(&custom, &opts.manifest, opts.only_post, api_version);
+        return;
+    }
+
+    // Parameters of the unpublished sector sizes can only be generated here.
+    let sector_sizes: Vec<u64> = PUBLISHED_SECTOR_SIZES
+        .iter()
+        .chain(UNPUBLISHED_SECTOR_SIZES.iter())
+        .copied()
+        .collect();
+
     // If no sector-sizes were given provided via. the CLI, display an interactive menu. Otherwise,
     // filter out invalid CLI sector-size arguments.
     if opts.sector_sizes.is_empty() {
-        let sector_size_strings: Vec<String> = PUBLISHED_SECTOR_SIZES
+        let sector_size_strings: Vec<String> = sector_sizes
             .iter()
             .map(|sector_size| {
                 let human_size = sector_size
@@ -277,11 +203,11 @@ pub fn main() {
             .interact()
             .expect("interaction failed")
             .into_iter()
-            .map(|i| PUBLISHED_SECTOR_SIZES[i])
+            .map(|i| sector_sizes[i])
             .collect();
     } else {
         opts.sector_sizes.retain(|size| {
-            if PUBLISHED_SECTOR_SIZES.contains(size) {
+            if sector_sizes.contains(size) {
                 true
             } else {
                 let human_size = size
@@ -298,9 +224,6 @@ pub fn main() {
         exit(1);
     }
 
-    let api_version = ApiVersion::from_str(&opts.api_version)
-        .expect("Cannot parse API version from semver string (e.g. 1.1.0)");
-
     for sector_size in opts.sector_sizes {
         let human_size = sector_size
             .file_size(file_size_opts::BINARY)
@@ -319,6 +242,9 @@ pub fn main() {
 
             if !opts.only_post {
                 generate_params_porep(sector_size, api_version);
+                if opts.ni_porep {
+                    generate_params_ni_porep(sector_size, api_version);
+                }
                 generate_params_empty_sector_update(sector_size, api_version);
             }
         }
@@ -326,3 +252,70 @@ pub fn main() {
         spinner.finish_with_message(&format!("✔ {}", &message));
     }
 }
+
+/// Registers the custom sector sizes of `custom_path`, generates their params and writes the
+/// manifest. The params come from a local, insecure setup and must never be used in production.
+fn generate_custom(
+    custom_path: &Path,
+    manifest_path: &Path,
+    only_post: bool,
+    api_version: ApiVersion,
+) {
+    let contents = fs::read_to_string(custom_path).expect("failed to read custom sector sizes");
+    let sector_sizes: Vec<CustomSectorSize> =
+        serde_json::from_str(&contents).expect("failed to parse custom sector sizes");
+    if sector_sizes.is_empty() {
+        error!("no custom sector sizes given, aborting");
+        exit(1);
+    }
+
+    warn!("generating INSECURE params for custom sector sizes, for test networks only");
+
+    let mut parameters = ParameterMap::new();
+    for custom in &sector_sizes {
+        let sector_size = custom.sector_size;
+        register_sector_size(custom).expect("failed to register custom sector size");
+
+        let human_size = sector_size
+            .file_size(file_size_opts::BINARY)
+            .expect("failed to format sector size");
+        info!("Generating custom sector size: {}", human_size);
+
+        let mut paths = generate_params_post(sector_size, api_version);
+        if !only_post {
+            paths.extend(generate_params_porep(sector_size, api_version));
+        }
+        // EmptySectorUpdate only supports the published sector sizes.
+
+        for path in paths {
+            if !has_extension(&path, GROTH_PARAMETER_EXT)
+                && !has_extension(&path, VERIFYING_KEY_EXT)
+            {
+                continue;
+            }
+            let filename = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .expect("invalid params path")
+                .to_string();
+            let digest =
+                get_digest_for_file_within_cache(&filename).expect("failed to hash cached params");
+            parameters.insert(
+                filename,
+                ParameterData {
+                    cid: String::new(),
+                    digest,
+                    sector_size,
+                },
+            );
+        }
+    }
+
+    let manifest = CustomManifest {
+        sector_sizes,
+        parameters,
+    };
+    let file = File::create(manifest_path).expect("failed to create manifest");
+    serde_json::to_writer_pretty(file, &manifest).expect("failed to write manifest");
+    info!("wrote manifest to {}", manifest_path.display());
+}
@@ -6,6 +6,7 @@ use std::process::{exit, Command};
 
 use anyhow::{ensure, Context, Result};
 use dialoguer::{theme::ColorfulTheme, MultiSelect, Select};
+use fil_proofs_param::fetch::{fetch_parameter_file, FetchOptions};
 use filecoin_proofs::param::{
     get_digest_for_file_within_cache, get_full_path_for_file_within_cache, has_extension,
 };
@@ -23,7 +24,8 @@ use tar::Archive;
 
 lazy_static! {
     static ref CLI_ABOUT: String = format!(
-        "Downloads missing or outdated Groth parameter files from ipfs using ipget.\n\n
+        "Downloads missing or outdated Groth parameter files from ipfs using ipget, or from an \
+        ipfs gateway with --gateway.\n\n
 
         Set the $FIL_PROOFS_PARAMETER_CACHE env-var to specify the path to the parameter cache
         directory (location where params are written), otherwise params will be written to '{}'.",
@@ -279,6 +281,23 @@ struct Cli {
         help = "Specify additional arguments for ipget."
     )]
     ipget_args: Option<String>,
+    #[structopt(
+        long,
+        short = "g",
+        value_name = "URL",
+        conflicts_with_all = &["ipget-bin", "ipget-version", "ipget-args"],
+        long_help = "Download the files over HTTP from this ipfs gateway (e.g. \
+            https://proofs.filecoin.io/ipfs/) instead of with ipget. The files are downloaded in \
+            chunks, in parallel, and interrupted downloads are resumed with the missing chunks."
+    )]
+    gateway: Option<String>,
+    #[structopt(
+        long,
+        value_name = "JOBS",
+        default_value = "8",
+        help = "Number of chunks downloaded from the gateway in parallel."
+    )]
+    jobs: usize,
 }
 
 pub fn main() {
@@ -376,7 +395,9 @@ pub fn main() {
         return;
     }
 
-    let ipget_path = if let Some(path_str) = cli.ipget_bin {
+    let ipget_path = if cli.gateway.is_some() {
+        None
+    } else if let Some(path_str) = cli.ipget_bin {
         let path = PathBuf::from(path_str);
         if !path.exists() {
             error!(
@@ -386,7 +407,7 @@ pub fn main() {
             exit(1);
         }
 
-        path
+        Some(path)
     } else {
         let ipget_version = cli
             .ipget_version
@@ -398,24 +419,42 @@ pub fn main() {
             download_ipget(&ipget_version, cli.verbose).expect("ipget download failed");
         }
 
-        path
+        Some(path)
     };
-    trace!("using ipget binary: {}", ipget_path.display());
+    if let Some(ipget_path) = &ipget_path {
+        trace!("using ipget binary: {}", ipget_path.display());
+    }
+    let fetch_options = cli.gateway.as_ref().map(|gateway| FetchOptions {
+        gateway: gateway.clone(),
+        parallelism: cli.jobs,
+        ..Default::default()
+    });
 
     trace!("creating param cache dir(s) if they don't exist");
     create_dir_all(parameter_cache_dir()).expect("failed to create param cache dir");
 
     loop {
         for filename in &filenames {
-            info!("downloading params file with ipget: {}", filename);
-            let path = get_full_path_for_file_within_cache(filename);
-            match download_file_with_ipget(
-                &parameter_map[filename].cid,
-                &path,
-                &ipget_path,
-                &cli.ipget_args,
-                cli.verbose,
-            ) {
+            // Files are fetched into the writable directory of the cache.
+            let path = parameter_cache_dir().join(filename);
+            let result = match (&fetch_options, &ipget_path) {
+                (Some(fetch_options), _) => {
+                    info!("downloading params file from the gateway: {}", filename);
+                    fetch_parameter_file(&parameter_map[filename], &path, fetch_options)
+                }
+                (None, Some(ipget_path)) => {
+                    info!("downloading params file with ipget: {}", filename);
+                    download_file_with_ipget(
+                        &parameter_map[filename].cid,
+                        &path,
+                        ipget_path,
+                        &cli.ipget_args,
+                        cli.verbose,
+                    )
+                }
+                (None, None) => unreachable!("ipget is used without a gateway"),
+            };
+            match result {
                 Ok(_) => info!("finished downloading params file"),
                 Err(e) => warn!("failed to download params file: {}", e),
             };
@@ -0,0 +1,480 @@
+//! Downloading parameter files from an IPFS gateway over HTTP.
+//!
+//! A file is downloaded with HTTP range requests, in chunks which are fetched in parallel into
+//! `<path>.partial`. The chunks written so far are recorded in `<path>.partial.json` along with
+//! their BLAKE2b checksums, so that an interrupted download resumes with the missing chunks,
+//! after the chunks on disk are checked against their checksums. Once all chunks are written, the
+//! digest of the whole file is checked against its `ParameterData` before it is moved to `path`.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, ensure, Context, Result};
+use blake2b_simd::State as Blake2b;
+use log::{info, trace, warn};
+use rayon::{
+    prelude::{IntoParallelIterator, ParallelIterator},
+    ThreadPoolBuilder,
+};
+use reqwest::{blocking::Client, header, Proxy, StatusCode};
+use serde::{Deserialize, Serialize};
+use storage_proofs_core::parameter_cache::ParameterData;
+
+pub const DEFAULT_GATEWAY: &str = "https://proofs.filecoin.io/ipfs/";
+
+#[derive(Clone, Debug)]
+pub struct FetchOptions {
+    /// The URL the CIDs of the files are appended to.
+    pub gateway: String,
+    /// The number of bytes requested at once.
+    pub chunk_size: u64,
+    /// The number of chunks downloaded in parallel.
+    pub parallelism: usize,
+    /// How often a failed chunk is requested again before the download fails.
+    pub retries: usize,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            gateway: DEFAULT_GATEWAY.to_string(),
+            chunk_size: 16 << 20,
+            parallelism: 8,
+            retries: 3,
+        }
+    }
+}
+
+/// The chunks of a partial download written so far, with their checksums.
+#[derive(Debug, Serialize, Deserialize)]
+struct Journal {
+    cid: String,
+    len: u64,
+    chunk_size: u64,
+    chunks: BTreeMap<u64, String>,
+}
+
+impl Journal {
+    fn chunk_range(&self, index: u64) -> (u64, u64) {
+        let start = index * self.chunk_size;
+        (start, (start + self.chunk_size).min(self.len))
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn checksum(bytes: &[u8]) -> String {
+    let mut hasher = Blake2b::new();
+    hasher.update(bytes);
+    hasher.finalize().to_hex().to_string()
+}
+
+/// The digest of the file at `path`, as listed in `parameters.json`.
+pub fn file_digest(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("could not open path={:?}", path))?;
+    let mut hasher = Blake2b::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hasher.finalize().to_hex()[..32].into())
+}
+
+/// Downloads the file of `data` from the gateway of `options` to `path`, see the
+/// [module documentation](self). A partial download of the file to `path` is resumed.
+pub fn fetch_parameter_file(
+    data: &ParameterData,
+    path: &Path,
+    options: &FetchOptions,
+) -> Result<()> {
+    ensure!(options.chunk_size > 0, "the chunk size must not be 0");
+
+    let client = Client::builder()
+        .proxy(Proxy::custom(move |url| env_proxy::for_url(url).to_url()))
+        .build()?;
+    let url = format!("{}/{}", options.gateway.trim_end_matches('/'), data.cid);
+    let partial_path = with_suffix(path, ".partial");
+    let journal_path = with_suffix(path, ".partial.json");
+
+    match content_len(&client, &url)? {
+        Some(len) => fetch_chunks(
+            &client,
+            &url,
+            &data.cid,
+            len,
+            &partial_path,
+            &journal_path,
+            options,
+        )?,
+        None => {
+            warn!(
+                "{} doesn't answer range requests, downloading it at once",
+                url
+            );
+            fetch_whole(&client, &url, &partial_path)?;
+        }
+    }
+
+    let digest = file_digest(&partial_path)?;
+    if digest != data.digest {
+        // The chunks matched what was downloaded, so the gateway served the wrong file.
+        let _ = fs::remove_file(&partial_path);
+        let _ = fs::remove_file(&journal_path);
+        bail!(
+            "{} has the digest {}, expected {}",
+            url,
+            digest,
+            data.digest
+        );
+    }
+
+    fs::rename(&partial_path, path)
+        .with_context(|| format!("could not move {:?} to {:?}", partial_path, path))?;
+    if journal_path.exists() {
+        fs::remove_file(&journal_path)?;
+    }
+    info!("downloaded {} to {:?}", url, path);
+
+    Ok(())
+}
+
+/// The length of the file at `url`, or `None` if the server doesn't answer range requests.
+fn content_len(client: &Client, url: &str) -> Result<Option<u64>> {
+    trace!("making GET request: {}", url);
+    let resp = client.get(url).header(header::RANGE, "bytes=0-0").send()?;
+    ensure!(
+        resp.status().is_success(),
+        "GET {} failed: {}",
+        url,
+        resp.status()
+    );
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Ok(None);
+    }
+
+    Ok(resp
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.rsplit('/').next())
+        .and_then(|len| len.parse().ok()))
+}
+
+fn fetch_whole(client: &Client, url: &str, partial_path: &Path) -> Result<()> {
+    let mut resp = client.get(url).send()?;
+    ensure!(
+        resp.status().is_success(),
+        "GET {} failed: {}",
+        url,
+        resp.status()
+    );
+
+    let mut file = File::create(partial_path)
+        .with_context(|| format!("could not create path={:?}", partial_path))?;
+    io::copy(&mut resp, &mut file)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+fn read_journal(journal_path: &Path) -> Option<Journal> {
+    let bytes = fs::read(journal_path).ok()?;
+    match serde_json::from_slice(&bytes) {
+        Ok(journal) => Some(journal),
+        Err(err) => {
+            warn!("ignoring the invalid journal {:?}: {}", journal_path, err);
+            None
+        }
+    }
+}
+
+fn write_journal(journal_path: &Path, journal: &Journal) -> Result<()> {
+    let tmp_path = with_suffix(journal_path, ".tmp");
+    fs::write(&tmp_path, serde_json::to_vec(journal)?)
+        .with_context(|| format!("could not write path={:?}", tmp_path))?;
+    fs::rename(&tmp_path, journal_path)
+        .with_context(|| format!("could not move {:?} to {:?}", tmp_path, journal_path))?;
+
+    Ok(())
+}
+
+/// Drops the chunks of `journal` which don't match their checksums in `file`.
+fn drop_corrupt_chunks(file: &mut File, journal: &mut Journal) -> Result<()> {
+    let mut corrupt = Vec::new();
+    let mut buf = Vec::new();
+    for (index, sum) in &journal.chunks {
+        let (start, end) = journal.chunk_range(*index);
+        buf.resize((end - start) as usize, 0);
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        if checksum(&buf) != *sum {
+            corrupt.push(*index);
+        }
+    }
+    for index in corrupt {
+        warn!(
+            "chunk {} of {} is corrupt, downloading it again",
+            index, journal.cid
+        );
+        journal.chunks.remove(&index);
+    }
+
+    Ok(())
+}
+
+fn fetch_chunks(
+    client: &Client,
+    url: &str,
+    cid: &str,
+    len: u64,
+    partial_path: &Path,
+    journal_path: &Path,
+    options: &FetchOptions,
+) -> Result<()> {
+    let mut journal = read_journal(journal_path)
+        .filter(|journal| {
+            journal.cid == cid && journal.len == len && journal.chunk_size == options.chunk_size
+        })
+        .unwrap_or_else(|| Journal {
+            cid: cid.to_string(),
+            len,
+            chunk_size: options.chunk_size,
+            chunks: BTreeMap::new(),
+        });
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(partial_path)
+        .with_context(|| format!("could not open path={:?}", partial_path))?;
+    file.set_len(len)?;
+    drop_corrupt_chunks(&mut file, &mut journal)?;
+
+    let chunk_count = (len + options.chunk_size - 1) / options.chunk_size;
+    let missing: Vec<u64> = (0..chunk_count)
+        .filter(|index| !journal.chunks.contains_key(index))
+        .collect();
+    info!(
+        "downloading {} of {} chunks of {}",
+        missing.len(),
+        chunk_count,
+        url
+    );
+
+    let journal = Mutex::new(journal);
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.parallelism.max(1))
+        .build()?;
+    pool.install(|| {
+        missing.into_par_iter().try_for_each(|index| {
+            let (start, end) = journal.lock().expect("poisoned lock").chunk_range(index);
+            let bytes = fetch_range(client, url, start, end, options.retries)?;
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(partial_path)
+                .with_context(|| format!("could not open path={:?}", partial_path))?;
+            file.seek(SeekFrom::Start(start))?;
+            file.write_all(&bytes)?;
+            file.sync_data()?;
+
+            let mut journal = journal.lock().expect("poisoned lock");
+            journal.chunks.insert(index, checksum(&bytes));
+            write_journal(journal_path, &journal)
+        })
+    })
+}
+
+fn fetch_range(
+    client: &Client,
+    url: &str,
+    start: u64,
+    end: u64,
+    retries: usize,
+) -> Result<Vec<u8>> {
+    let mut attempt = 0;
+    loop {
+        match try_fetch_range(client, url, start, end) {
+            Ok(bytes) => return Ok(bytes),
+            Err(err) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "failed to download bytes {}-{} of {} (attempt {}): {:?}",
+                    start, end, url, attempt, err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn try_fetch_range(client: &Client, url: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+    let mut resp = client
+        .get(url)
+        .header(header::RANGE, format!("bytes={}-{}", start, end - 1))
+        .send()?;
+    ensure!(
+        resp.status() == StatusCode::PARTIAL_CONTENT,
+        "GET {} bytes {}-{} failed: {}",
+        url,
+        start,
+        end,
+        resp.status()
+    );
+
+    let mut bytes = Vec::with_capacity((end - start) as usize);
+    resp.read_to_end(&mut bytes)?;
+    ensure!(
+        bytes.len() as u64 == end - start,
+        "got {} of the bytes {}-{} of {}",
+        bytes.len(),
+        start,
+        end,
+        url
+    );
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::thread;
+
+    use tempfile::tempdir;
+
+    // Serves `data` at every path, answering range requests, and counts the requests.
+    fn serve(data: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let gateway = format!(
+            "http://{}/ipfs/",
+            listener.local_addr().expect("no local address")
+        );
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                respond(stream.expect("failed to accept"), &data);
+            }
+        });
+
+        (gateway, requests)
+    }
+
+    fn respond(mut stream: TcpStream, data: &[u8]) {
+        let mut range = None;
+        let mut reader = BufReader::new(stream.try_clone().expect("failed to clone"));
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("failed to read");
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(bytes) = line.strip_prefix("range: bytes=") {
+                let mut bounds = bytes
+                    .split('-')
+                    .map(|b| b.parse::<usize>().expect("bad range"));
+                range = Some((
+                    bounds.next().expect("no start"),
+                    bounds.next().expect("no end"),
+                ));
+            }
+        }
+
+        let (status, headers, body) = match range {
+            Some((start, end)) => (
+                "206 Partial Content",
+                format!("Content-Range: bytes {}-{}/{}\r\n", start, end, data.len()),
+                &data[start..=end],
+            ),
+            None => ("200 OK", String::new(), data),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            headers,
+            body.len()
+        )
+        .expect("failed to write");
+        stream.write_all(body).expect("failed to write");
+    }
+
+    #[test]
+    fn test_fetch_parameter_file_resumes() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let dir = tempdir().expect("failed to create tempdir");
+        let source = dir.path().join("source");
+        fs::write(&source, &data).expect("failed to write");
+        let parameter_data = ParameterData {
+            cid: "QmTest".to_string(),
+            digest: file_digest(&source).expect("file_digest failed"),
+            sector_size: 2048,
+        };
+
+        let (gateway, requests) = serve(data.clone());
+        let options = FetchOptions {
+            gateway,
+            chunk_size: 1000,
+            parallelism: 4,
+            retries: 0,
+        };
+
+        let path = dir.path().join("test.params");
+        fetch_parameter_file(&parameter_data, &path, &options).expect("fetch failed");
+        assert_eq!(fs::read(&path).expect("failed to read"), data);
+        // The length and the chunks.
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 11);
+        assert!(!with_suffix(&path, ".partial").exists());
+        assert!(!with_suffix(&path, ".partial.json").exists());
+
+        // An interrupted download with the first 5 chunks, the fourth of which was corrupted.
+        let journal = Journal {
+            cid: parameter_data.cid.clone(),
+            len: data.len() as u64,
+            chunk_size: 1000,
+            chunks: (0..5)
+                .map(|index| {
+                    let start = index as usize * 1000;
+                    (index, checksum(&data[start..start + 1000]))
+                })
+                .collect(),
+        };
+        write_journal(&with_suffix(&path, ".partial.json"), &journal)
+            .expect("write_journal failed");
+        let mut partial = data.clone();
+        partial[3500] ^= 1;
+        partial[5000..].iter_mut().for_each(|byte| *byte = 0);
+        fs::write(with_suffix(&path, ".partial"), partial).expect("failed to write");
+
+        fetch_parameter_file(&parameter_data, &path, &options).expect("fetch failed");
+        assert_eq!(fs::read(&path).expect("failed to read"), data);
+        // The length and the chunks 3 and 5 to 9.
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 7);
+
+        let wrong_data = ParameterData {
+            digest: "0".repeat(32),
+            ..parameter_data
+        };
+        let wrong_path = dir.path().join("wrong.params");
+        assert!(fetch_parameter_file(&wrong_data, &wrong_path, &options).is_err());
+        assert!(!wrong_path.exists());
+        assert!(!with_suffix(&wrong_path, ".partial").exists());
+    }
+}
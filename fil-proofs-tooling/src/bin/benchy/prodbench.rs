@@ -318,6 +318,7 @@ fn generate_params(i: &ProdbenchInputs) {
         partitions,
         porep_id: dummy_porep_id,
         api_version: i.api_version(),
+        api_features: &[],
     });
 }
 
@@ -327,6 +328,7 @@ fn cache_porep_params(porep_config: PoRepConfig) {
         usize::from(PoRepProofPartitions::from(porep_config)),
         porep_config.porep_id,
         porep_config.api_version,
+        porep_config.api_features,
     )
     .expect("failed to get public_params");
 
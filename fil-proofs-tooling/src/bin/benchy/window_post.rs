@@ -93,6 +93,7 @@ fn get_porep_config(sector_size: u64, api_version: ApiVersion) -> PoRepConfig {
         ),
         porep_id: arbitrary_porep_id,
         api_version,
+        api_features: &[],
     }
 }
 
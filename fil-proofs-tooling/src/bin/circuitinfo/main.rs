@@ -44,6 +44,7 @@ fn get_porep_info<Tree: 'static + MerkleTreeTrait>(porep_config: PoRepConfig) ->
         usize::from(PoRepProofPartitions::from(porep_config)),
         porep_config.porep_id,
         porep_config.api_version,
+        porep_config.api_features,
     )
     .expect("failed to get public params from config");
 
@@ -148,6 +149,7 @@ fn porep_info(sector_size: u64, api_version: ApiVersion) -> (CircuitInfo, usize)
             partitions,
             porep_id: [0; 32],
             api_version,
+            api_features: &[],
         }
     );
     (info, partitions.into())
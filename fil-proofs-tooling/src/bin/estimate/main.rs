@@ -0,0 +1,92 @@
+use std::fs;
+use std::io::stdout;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use byte_unit::Byte;
+use clap::{value_t, App, AppSettings, Arg, SubCommand};
+use fil_proofs_tooling::estimate::{estimate_throughput, Calibration, MachineDescription};
+use storage_proofs_core::settings::SETTINGS;
+
+fn read_toml<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
+    let contents = fs::read_to_string(path).with_context(|| format!("could not read {}", path))?;
+    toml::from_str(&contents).with_context(|| format!("could not parse {}", path))
+}
+
+fn read_calibration(path: Option<&str>) -> Result<Calibration> {
+    match path {
+        Some(path) => read_toml(path),
+        None => Ok(Calibration::default()),
+    }
+}
+
+fn main() -> Result<()> {
+    fil_logger::init();
+
+    let machine_arg = Arg::with_name("machine")
+        .long("machine")
+        .required(true)
+        .help("TOML file describing the hardware of the machine")
+        .takes_value(true);
+    let calibration_arg = Arg::with_name("calibration")
+        .long("calibration")
+        .required(false)
+        .help("TOML file with the calibration (default: built-in reference figures)")
+        .takes_value(true);
+
+    let throughput_cmd = SubCommand::with_name("throughput")
+        .about("Estimate the sectors sealed per day with the configured settings")
+        .arg(machine_arg.clone())
+        .arg(calibration_arg.clone())
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .required(true)
+                .help("The sector size (e.g. 32GiB)")
+                .takes_value(true),
+        );
+
+    let calibrate_cmd = SubCommand::with_name("calibrate")
+        .about("Derive a calibration from a benchy window-post report of the machine")
+        .arg(machine_arg)
+        .arg(calibration_arg)
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .required(true)
+                .help("The JSON report of `benchy window-post`")
+                .takes_value(true),
+        );
+
+    let matches = App::new("estimate")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .version("0.1")
+        .subcommand(throughput_cmd)
+        .subcommand(calibrate_cmd)
+        .get_matches();
+
+    match matches.subcommand() {
+        ("throughput", Some(m)) => {
+            let machine: MachineDescription = read_toml(&value_t!(m, "machine", String)?)?;
+            let calibration = read_calibration(m.value_of("calibration"))?;
+            let sector_size = Byte::from_str(value_t!(m, "size", String)?)?.get_bytes() as u64;
+
+            let estimate = estimate_throughput(&machine, &SETTINGS, &calibration, sector_size)?;
+            serde_json::to_writer_pretty(stdout(), &estimate)?;
+            println!();
+        }
+        ("calibrate", Some(m)) => {
+            let machine: MachineDescription = read_toml(&value_t!(m, "machine", String)?)?;
+            let mut calibration = read_calibration(m.value_of("calibration"))?;
+            let report_path = value_t!(m, "report", String)?;
+            let report = fs::read_to_string(&report_path)
+                .with_context(|| format!("could not read {}", report_path))?;
+
+            calibration.calibrate(&report, &machine, &SETTINGS)?;
+            print!("{}", toml::to_string(&calibration)?);
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
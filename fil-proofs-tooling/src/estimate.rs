@@ -0,0 +1,497 @@
+//! Capacity planning: estimates how many sectors per day a machine seals, from a description of
+//! its hardware and the configured settings.
+//!
+//! The estimates scale per-phase timings of a sector, the [`Calibration`], by the resources of
+//! the machine. The default calibration holds representative figures of an AMD Threadripper 3970x
+//! (core complexes of 4 cores) and common GPUs. For planning purchases it should be replaced by a
+//! calibration derived from a `benchy window-post` report of a comparable machine, see
+//! [`Calibration::calibrate`].
+//!
+//! The phases are assumed to run on dedicated resources, except PC2 and C2, which share the GPUs.
+
+use anyhow::{anyhow, ensure, Context, Result};
+use filecoin_proofs::{LAYERS, POREP_PARTITIONS};
+use serde::{Deserialize, Serialize};
+use storage_proofs_core::{settings::Settings, util::NODE_SIZE};
+
+const SECS_PER_DAY: f64 = 86_400.0;
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// Hardware of the machine to estimate, usually read from a TOML file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MachineDescription {
+    /// Number of core groups sharing an L3 cache (core complexes on AMD CPUs).
+    pub core_groups: usize,
+    /// Physical cores per core group.
+    pub cores_per_group: usize,
+    /// Memory available for sealing, in GiB.
+    pub memory_gib: f64,
+    /// Memory bandwidth, in GB/s.
+    pub memory_bandwidth_gbps: f64,
+    /// Aggregate sequential throughput of the NVMe drives holding the cache directories, in MB/s.
+    pub nvme_throughput_mbps: f64,
+    /// Models of the GPUs, one entry per GPU, e.g. `["RTX 3090", "RTX 3090"]`.
+    #[serde(default)]
+    pub gpus: Vec<String>,
+}
+
+impl MachineDescription {
+    pub fn cores(&self) -> usize {
+        self.core_groups * self.cores_per_group
+    }
+}
+
+/// Timings of a GPU model.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GpuCalibration {
+    pub model: String,
+    /// Seconds for PC2 of one GiB with the GPU column and tree builders.
+    pub pc2_secs_per_gib: f64,
+    /// Seconds for C2 of one partition.
+    pub c2_secs_per_partition: f64,
+}
+
+/// Per-phase timings the estimates are scaled from.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Calibration {
+    /// Seconds to label one GiB of one layer with the multicore SDR, on one core group.
+    pub pc1_multicore_secs_per_gib_layer: f64,
+    /// Seconds to label one GiB of one layer with the single core SDR.
+    pub pc1_single_core_secs_per_gib_layer: f64,
+    /// Memory traffic of labeling one GiB of one layer, in GB.
+    pub pc1_memory_gb_per_gib_layer: f64,
+    /// Core seconds for PC2 of one GiB on the CPU.
+    pub pc2_cpu_core_secs_per_gib: f64,
+    /// Core seconds for C2 of one partition on the CPU.
+    pub c2_cpu_core_secs_per_partition: f64,
+    pub gpus: Vec<GpuCalibration>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        let gpu = |model: &str, pc2_secs_per_gib, c2_secs_per_partition| GpuCalibration {
+            model: model.to_string(),
+            pc2_secs_per_gib,
+            c2_secs_per_partition,
+        };
+
+        Calibration {
+            pc1_multicore_secs_per_gib_layer: 33.0,
+            pc1_single_core_secs_per_gib_layer: 61.0,
+            pc1_memory_gb_per_gib_layer: 20.0,
+            pc2_cpu_core_secs_per_gib: 5_400.0,
+            c2_cpu_core_secs_per_partition: 17_280.0,
+            gpus: vec![
+                gpu("RTX 2080 Ti", 25.0, 84.0),
+                gpu("RTX 3080", 19.0, 58.0),
+                gpu("RTX 3090", 17.0, 48.0),
+                gpu("Tesla V100", 22.0, 62.0),
+                gpu("A100", 14.0, 40.0),
+            ],
+        }
+    }
+}
+
+/// The parts of a `benchy window-post` report the calibration is derived from.
+#[derive(Debug, Deserialize)]
+struct BenchyReport {
+    benchmarks: BenchyBenchmarks,
+}
+
+#[derive(Debug, Deserialize)]
+struct BenchyBenchmarks {
+    inputs: BenchyInputs,
+    outputs: BenchyOutputs,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BenchyInputs {
+    sector_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BenchyOutputs {
+    seal_pre_commit_phase1_wall_time_ms: u64,
+    seal_pre_commit_phase2_wall_time_ms: u64,
+    seal_commit_phase2_wall_time_ms: u64,
+}
+
+impl Calibration {
+    /// Returns the calibration of the GPU `model`.
+    pub fn gpu(&self, model: &str) -> Result<&GpuCalibration> {
+        self.gpus
+            .iter()
+            .find(|gpu| gpu.model.eq_ignore_ascii_case(model))
+            .ok_or_else(|| {
+                let known: Vec<_> = self.gpus.iter().map(|gpu| gpu.model.as_str()).collect();
+                anyhow!("no calibration for GPU {:?}, known: {:?}", model, known)
+            })
+    }
+
+    /// Updates the calibration from the JSON report of a `benchy window-post` run on `machine`
+    /// with `settings`. Only the timings of the code paths used by that run are updated, e.g. the
+    /// multicore SDR timings if it was enabled, and the timings of the first GPU of `machine` if
+    /// it has any.
+    pub fn calibrate(
+        &mut self,
+        benchy_report: &str,
+        machine: &MachineDescription,
+        settings: &Settings,
+    ) -> Result<()> {
+        let report: BenchyReport =
+            serde_json::from_str(benchy_report).context("invalid benchy window-post report")?;
+        let sector_size = report.benchmarks.inputs.sector_size;
+        let outputs = &report.benchmarks.outputs;
+        let (gib, layers, partitions) = sector_parameters(sector_size)?;
+        let secs = |ms: u64| ms as f64 / 1000.0;
+
+        let pc1_secs_per_gib_layer =
+            secs(outputs.seal_pre_commit_phase1_wall_time_ms) / (gib * layers);
+        if settings.use_multicore_sdr {
+            self.pc1_multicore_secs_per_gib_layer = pc1_secs_per_gib_layer;
+        } else {
+            self.pc1_single_core_secs_per_gib_layer = pc1_secs_per_gib_layer;
+        }
+
+        let pc2_secs = secs(outputs.seal_pre_commit_phase2_wall_time_ms);
+        let c2_secs = secs(outputs.seal_commit_phase2_wall_time_ms);
+        match machine.gpus.first() {
+            Some(model) => {
+                let calibration = GpuCalibration {
+                    model: model.clone(),
+                    pc2_secs_per_gib: pc2_secs / gib,
+                    c2_secs_per_partition: c2_secs / partitions,
+                };
+                let uses_gpu_for_pc2 =
+                    settings.use_gpu_column_builder && settings.use_gpu_tree_builder;
+                match self
+                    .gpus
+                    .iter_mut()
+                    .find(|gpu| gpu.model.eq_ignore_ascii_case(model))
+                {
+                    Some(gpu) => {
+                        if uses_gpu_for_pc2 {
+                            gpu.pc2_secs_per_gib = calibration.pc2_secs_per_gib;
+                        }
+                        gpu.c2_secs_per_partition = calibration.c2_secs_per_partition;
+                    }
+                    None => {
+                        ensure!(
+                            uses_gpu_for_pc2,
+                            "GPU {:?} is unknown and PC2 did not run on it",
+                            model
+                        );
+                        self.gpus.push(calibration);
+                    }
+                }
+                if !uses_gpu_for_pc2 {
+                    self.pc2_cpu_core_secs_per_gib = pc2_secs * machine.cores() as f64 / gib;
+                }
+            }
+            None => {
+                self.pc2_cpu_core_secs_per_gib = pc2_secs * machine.cores() as f64 / gib;
+                self.c2_cpu_core_secs_per_partition = c2_secs * machine.cores() as f64 / partitions;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Throughput of one phase.
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseEstimate {
+    /// Wall time of one sector, in seconds.
+    pub secs_per_sector: f64,
+    /// Number of sectors processed at the same time.
+    pub parallel_sectors: f64,
+    pub sectors_per_day: f64,
+    /// The resource limiting the throughput of the phase.
+    pub bottleneck: &'static str,
+}
+
+impl PhaseEstimate {
+    /// Estimates a phase with the given limits on the number of parallel sectors.
+    fn new(secs_per_sector: f64, limits: &[(&'static str, f64)]) -> Self {
+        let (bottleneck, parallel_sectors) =
+            limits
+                .iter()
+                .copied()
+                .fold(("none", f64::INFINITY), |min, limit| {
+                    if limit.1 < min.1 {
+                        limit
+                    } else {
+                        min
+                    }
+                });
+
+        PhaseEstimate {
+            secs_per_sector,
+            parallel_sectors,
+            sectors_per_day: parallel_sectors * SECS_PER_DAY / secs_per_sector,
+            bottleneck,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ThroughputEstimate {
+    pub sector_size: u64,
+    pub pc1: PhaseEstimate,
+    pub pc2: PhaseEstimate,
+    pub c2: PhaseEstimate,
+    /// Sectors sealed per day by the whole pipeline.
+    pub sectors_per_day: f64,
+    /// The phase limiting the throughput of the pipeline.
+    pub bottleneck: &'static str,
+}
+
+/// Returns the size in GiB, the number of layers and the number of partitions of a sector.
+fn sector_parameters(sector_size: u64) -> Result<(f64, f64, f64)> {
+    let layers = LAYERS
+        .read()
+        .expect("LAYERS poisoned")
+        .get(&sector_size)
+        .copied()
+        .with_context(|| format!("unknown sector size {}", sector_size))?;
+    let partitions = POREP_PARTITIONS
+        .read()
+        .expect("POREP_PARTITIONS poisoned")
+        .get(&sector_size)
+        .copied()
+        .with_context(|| format!("unknown sector size {}", sector_size))?;
+
+    Ok((
+        sector_size as f64 / GIB,
+        layers as f64,
+        f64::from(partitions),
+    ))
+}
+
+/// Estimates the sealing throughput of `machine` for sectors of `sector_size` bytes.
+pub fn estimate_throughput(
+    machine: &MachineDescription,
+    settings: &Settings,
+    calibration: &Calibration,
+    sector_size: u64,
+) -> Result<ThroughputEstimate> {
+    ensure!(machine.cores() > 0, "the machine has no cores");
+    let (gib, layers, partitions) = sector_parameters(sector_size)?;
+    let cores = machine.cores() as f64;
+    let nvme_bytes_per_sec = machine.nvme_throughput_mbps * 1e6;
+    let sector_bytes = sector_size as f64;
+
+    // PC1: one sector per core group (or per set of producers within a group) with the
+    // multicore SDR, one sector per core otherwise. All sectors keep two layers in memory and
+    // share the parent cache, which has to stay in the page cache.
+    let (pc1_secs_per_gib_layer, core_slots) = if settings.use_multicore_sdr {
+        let cores_per_sector = settings.multicore_sdr_producers + 1;
+        let sectors_per_group = (machine.cores_per_group / cores_per_sector).max(1);
+        (
+            calibration.pc1_multicore_secs_per_gib_layer,
+            (machine.core_groups * sectors_per_group) as f64,
+        )
+    } else {
+        (calibration.pc1_single_core_secs_per_gib_layer, cores)
+    };
+    let pc1_secs = gib * layers * pc1_secs_per_gib_layer;
+    let parent_cache_gib = gib / NODE_SIZE as f64 * 56.0;
+    let memory_slots = ((machine.memory_gib - parent_cache_gib) / (2.0 * gib)).floor();
+    let memory_bandwidth_slots = machine.memory_bandwidth_gbps * pc1_secs_per_gib_layer
+        / calibration.pc1_memory_gb_per_gib_layer;
+    let pc1_nvme_slots = nvme_bytes_per_sec * pc1_secs / (layers * sector_bytes);
+    let pc1 = PhaseEstimate::new(
+        pc1_secs,
+        &[
+            ("cores", core_slots),
+            ("memory", memory_slots.max(0.0)),
+            ("memory bandwidth", memory_bandwidth_slots.floor()),
+            ("nvme", pc1_nvme_slots.floor()),
+        ],
+    );
+
+    // PC2 reads all layers and writes the replica and the trees, C2 only runs on the GPUs or
+    // the CPU.
+    let pc2_uses_gpu = !machine.gpus.is_empty()
+        && settings.use_gpu_column_builder
+        && settings.use_gpu_tree_builder;
+    let pc2_nvme_secs = (layers + 2.0) * sector_bytes / nvme_bytes_per_sec;
+    let (pc2, c2) = if machine.gpus.is_empty() {
+        let pc2_secs = gib * calibration.pc2_cpu_core_secs_per_gib / cores;
+        let c2_secs = partitions * calibration.c2_cpu_core_secs_per_partition / cores;
+        (
+            PhaseEstimate::new(
+                pc2_secs,
+                &[("cores", 1.0), ("nvme", pc2_secs / pc2_nvme_secs)],
+            ),
+            PhaseEstimate::new(c2_secs, &[("cores", 1.0)]),
+        )
+    } else {
+        let gpus = machine
+            .gpus
+            .iter()
+            .map(|model| calibration.gpu(model))
+            .collect::<Result<Vec<_>>>()?;
+        // The GPUs are described by the mean time of a sector and their count.
+        let gpu_count = gpus.len() as f64;
+        let c2_secs = gpus
+            .iter()
+            .map(|gpu| partitions * gpu.c2_secs_per_partition)
+            .sum::<f64>()
+            / gpu_count;
+        let pc2 = if pc2_uses_gpu {
+            let pc2_secs = gpus
+                .iter()
+                .map(|gpu| gib * gpu.pc2_secs_per_gib)
+                .sum::<f64>()
+                / gpu_count;
+            PhaseEstimate::new(
+                pc2_secs,
+                &[("gpus", gpu_count), ("nvme", pc2_secs / pc2_nvme_secs)],
+            )
+        } else {
+            let pc2_secs = gib * calibration.pc2_cpu_core_secs_per_gib / cores;
+            PhaseEstimate::new(
+                pc2_secs,
+                &[("cores", 1.0), ("nvme", pc2_secs / pc2_nvme_secs)],
+            )
+        };
+        (pc2, PhaseEstimate::new(c2_secs, &[("gpus", gpu_count)]))
+    };
+
+    // PC2 and C2 share the GPUs: each GPU spends the time of both phases on a sector.
+    let (post_pc1_sectors_per_day, post_pc1_bottleneck) = if pc2_uses_gpu {
+        let combined = 1.0 / (1.0 / pc2.sectors_per_day + 1.0 / c2.sectors_per_day);
+        (combined, "pc2+c2")
+    } else if pc2.sectors_per_day < c2.sectors_per_day {
+        (pc2.sectors_per_day, "pc2")
+    } else {
+        (c2.sectors_per_day, "c2")
+    };
+    let (sectors_per_day, bottleneck) = if pc1.sectors_per_day < post_pc1_sectors_per_day {
+        (pc1.sectors_per_day, "pc1")
+    } else {
+        (post_pc1_sectors_per_day, post_pc1_bottleneck)
+    };
+
+    Ok(ThroughputEstimate {
+        sector_size,
+        pc1,
+        pc2,
+        c2,
+        sectors_per_day,
+        bottleneck,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filecoin_proofs::SECTOR_SIZE_32_GIB;
+
+    fn machine() -> MachineDescription {
+        MachineDescription {
+            core_groups: 8,
+            cores_per_group: 4,
+            memory_gib: 640.0,
+            memory_bandwidth_gbps: 80.0,
+            nvme_throughput_mbps: 6_000.0,
+            gpus: vec!["RTX 3090".to_string()],
+        }
+    }
+
+    fn settings() -> Settings {
+        Settings {
+            use_multicore_sdr: true,
+            multicore_sdr_producers: 3,
+            use_gpu_column_builder: true,
+            use_gpu_tree_builder: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_estimate_throughput() {
+        let machine = machine();
+        let estimate = estimate_throughput(
+            &machine,
+            &settings(),
+            &Calibration::default(),
+            SECTOR_SIZE_32_GIB,
+        )
+        .expect("estimate_throughput failed");
+
+        // One sector per core group, 11 layers of 32 GiB each.
+        assert_eq!(estimate.pc1.parallel_sectors, 8.0);
+        assert_eq!(estimate.pc1.bottleneck, "cores");
+        assert!((estimate.pc1.secs_per_sector - 32.0 * 11.0 * 33.0).abs() < 1e-6);
+        assert_eq!(estimate.pc2.bottleneck, "gpus");
+        assert!(estimate.sectors_per_day > 0.0);
+        assert!(estimate.sectors_per_day <= estimate.pc1.sectors_per_day);
+        assert!(estimate.sectors_per_day <= estimate.pc2.sectors_per_day);
+
+        let unknown_gpu = MachineDescription {
+            gpus: vec!["Voodoo 2".to_string()],
+            ..machine.clone()
+        };
+        assert!(estimate_throughput(
+            &unknown_gpu,
+            &settings(),
+            &Calibration::default(),
+            SECTOR_SIZE_32_GIB
+        )
+        .is_err());
+
+        let small_memory = MachineDescription {
+            memory_gib: 256.0,
+            ..machine
+        };
+        let estimate = estimate_throughput(
+            &small_memory,
+            &settings(),
+            &Calibration::default(),
+            SECTOR_SIZE_32_GIB,
+        )
+        .expect("estimate_throughput failed");
+        // 56 GiB of parent cache, 64 GiB per sector.
+        assert_eq!(estimate.pc1.parallel_sectors, 3.0);
+        assert_eq!(estimate.pc1.bottleneck, "memory");
+    }
+
+    #[test]
+    fn test_calibrate() {
+        let report = format!(
+            r#"{{"benchmarks": {{
+                "inputs": {{"sector-size": {}}},
+                "outputs": {{
+                    "seal-pre-commit-phase1-wall-time-ms": 10560000,
+                    "seal-pre-commit-phase2-wall-time-ms": 640000,
+                    "seal-commit-phase2-wall-time-ms": 600000
+                }}
+            }}}}"#,
+            SECTOR_SIZE_32_GIB
+        );
+        let machine = MachineDescription {
+            gpus: vec!["RTX 4090".to_string()],
+            ..machine()
+        };
+
+        let mut calibration = Calibration::default();
+        calibration
+            .calibrate(&report, &machine, &settings())
+            .expect("calibrate failed");
+        assert!((calibration.pc1_multicore_secs_per_gib_layer - 30.0).abs() < 1e-6);
+        let gpu = calibration.gpu("rtx 4090").expect("gpu not calibrated");
+        assert!((gpu.pc2_secs_per_gib - 20.0).abs() < 1e-6);
+        assert!((gpu.c2_secs_per_partition - 60.0).abs() < 1e-6);
+
+        let estimate = estimate_throughput(&machine, &settings(), &calibration, SECTOR_SIZE_32_GIB)
+            .expect("estimate_throughput failed");
+        assert!((estimate.pc1.secs_per_sector - 10_560.0).abs() < 1e-6);
+        assert!((estimate.c2.secs_per_sector - 600.0).abs() < 1e-6);
+    }
+}
@@ -2,6 +2,7 @@
 #![warn(clippy::unwrap_used)]
 #![warn(clippy::needless_collect)]
 
+pub mod estimate;
 pub mod measure;
 pub mod metadata;
 pub mod shared;
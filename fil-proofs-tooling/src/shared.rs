@@ -109,6 +109,7 @@ pub fn create_replicas<Tree: 'static + MerkleTreeTrait>(
         ),
         porep_id,
         api_version,
+        api_features: &[],
     };
 
     let mut out: Vec<(SectorId, PreCommitReplicaOutput<Tree>)> = Default::default();
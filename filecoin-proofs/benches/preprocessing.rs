@@ -125,6 +125,7 @@ fn get_seal_inputs_benchmark(c: &mut Criterion) {
         ),
         porep_id,
         api_version: ApiVersion::V1_1_0,
+        api_features: &[],
     };
     let comm_r: [u8; 32] = [5u8; 32];
     let comm_d: [u8; 32] = [6u8; 32];
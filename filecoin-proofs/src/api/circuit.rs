@@ -0,0 +1,492 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, ensure, Result};
+use bellperson::{
+    util_cs::{metric_cs::MetricCS, test_cs::TestConstraintSystem},
+    Circuit,
+};
+use blstrs::Scalar as Fr;
+use filecoin_hashers::{Domain, Hasher};
+use log::info;
+use serde::Serialize;
+use storage_proofs_core::{
+    compound_proof::{self, CompoundProof},
+    merkle::MerkleTreeTrait,
+};
+use storage_proofs_porep::stacked::{self, StackedCompound, StackedDrg};
+use storage_proofs_post::fallback::{
+    self, FallbackPoSt, FallbackPoStCircuit, FallbackPoStCompound, PublicSector,
+};
+
+use crate::{
+    api::{as_safe_commitment, get_partitions_for_window_post, partition_vanilla_proofs},
+    constants::{DefaultPieceDomain, DefaultPieceHasher},
+    parameters::{
+        public_params, setup_params, window_post_public_params, window_post_setup_params,
+        winning_post_public_params, winning_post_setup_params,
+    },
+    types::{
+        ChallengeSeed, FallbackPoStSectorProof, PaddedBytesAmount, PoRepConfig,
+        PoRepProofPartitions, PoStConfig, PoStType, ProverId, SealCommitPhase1Output,
+    },
+};
+
+/// The proof whose circuit is synthesized by [`test_circuit`].
+#[derive(Clone, Debug)]
+pub enum CircuitProof {
+    SealCommit(PoRepConfig),
+    WinningPoSt(PoStConfig),
+    WindowPoSt(PoStConfig),
+}
+
+/// The witness to synthesize a circuit with.
+pub enum CircuitInputs<Tree: MerkleTreeTrait> {
+    /// Synthesizes the blank circuit, which only yields constraint counts.
+    Blank,
+    /// The output of `seal_commit_phase1`.
+    SealCommit(SealCommitPhase1Output<Tree>),
+    /// Vanilla proofs as returned by `generate_single_vanilla_proof`.
+    PoSt {
+        randomness: ChallengeSeed,
+        prover_id: ProverId,
+        vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
+    },
+}
+
+/// Diagnostics for the circuit of a single partition.
+#[derive(Clone, Debug, Serialize)]
+pub struct PartitionCSReport {
+    pub partition: usize,
+    /// Path of the first unsatisfied constraint, if any.
+    pub unsatisfied_constraint: Option<String>,
+    /// Whether the public inputs generated outside of the circuit match the allocated ones.
+    pub inputs_verified: bool,
+}
+
+/// Result of synthesizing a circuit with [`test_circuit`].
+#[derive(Clone, Debug, Serialize)]
+pub struct MetricCSReport {
+    /// Constraints of a single partition circuit.
+    pub num_constraints: usize,
+    /// Public inputs of a single partition circuit, including the constant one.
+    pub num_inputs: usize,
+    /// One entry per partition, empty for blank circuits.
+    pub partitions: Vec<PartitionCSReport>,
+}
+
+impl MetricCSReport {
+    /// Returns true if all partition circuits are satisfied by their witness and inputs.
+    pub fn is_satisfied(&self) -> bool {
+        self.partitions.iter().all(|partition| {
+            partition.unsatisfied_constraint.is_none() && partition.inputs_verified
+        })
+    }
+
+    fn blank<C: Circuit<Fr>>(circuit: C) -> Result<Self> {
+        let mut cs = MetricCS::<Fr>::new();
+        circuit.synthesize(&mut cs)?;
+
+        Ok(MetricCSReport {
+            num_constraints: cs.num_constraints(),
+            num_inputs: cs.num_inputs(),
+            partitions: Vec::new(),
+        })
+    }
+
+    fn with_witness<C: Circuit<Fr>>(circuits: Vec<(C, Vec<Fr>)>) -> Result<Self> {
+        ensure!(!circuits.is_empty(), "no partitions to synthesize");
+
+        let mut report = MetricCSReport {
+            num_constraints: 0,
+            num_inputs: 0,
+            partitions: Vec::with_capacity(circuits.len()),
+        };
+        for (partition, (circuit, inputs)) in circuits.into_iter().enumerate() {
+            let mut cs = TestConstraintSystem::<Fr>::new();
+            circuit.synthesize(&mut cs)?;
+
+            report.num_constraints = cs.num_constraints();
+            report.num_inputs = cs.num_inputs();
+            report.partitions.push(PartitionCSReport {
+                partition,
+                unsatisfied_constraint: cs.which_is_unsatisfied().map(ToString::to_string),
+                inputs_verified: cs.verify(&inputs),
+            });
+        }
+
+        Ok(report)
+    }
+}
+
+/// Synthesizes the circuit of `proof` on a test constraint system, returning constraint counts and,
+/// unless `inputs` is [`CircuitInputs::Blank`], which constraints the witness fails to satisfy.
+///
+/// This is meant for developing and debugging gadgets: no parameters are needed and no proof is
+/// generated.
+pub fn test_circuit<Tree: 'static + MerkleTreeTrait>(
+    proof: CircuitProof,
+    inputs: CircuitInputs<Tree>,
+) -> Result<MetricCSReport> {
+    info!("test_circuit:start: {:?}", proof);
+
+    let report = match proof {
+        CircuitProof::SealCommit(porep_config) => test_seal_commit_circuit(porep_config, inputs)?,
+        CircuitProof::WinningPoSt(post_config) => {
+            ensure!(
+                post_config.typ == PoStType::Winning,
+                "invalid post config type"
+            );
+            test_post_circuit(&post_config, inputs)?
+        }
+        CircuitProof::WindowPoSt(post_config) => {
+            ensure!(
+                post_config.typ == PoStType::Window,
+                "invalid post config type"
+            );
+            test_post_circuit(&post_config, inputs)?
+        }
+    };
+
+    info!("test_circuit:finish");
+    Ok(report)
+}
+
+fn test_seal_commit_circuit<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    inputs: CircuitInputs<Tree>,
+) -> Result<MetricCSReport> {
+    let partitions = usize::from(PoRepProofPartitions::from(porep_config));
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: setup_params(
+            PaddedBytesAmount::from(porep_config),
+            partitions,
+            porep_config.porep_id,
+            porep_config.api_version,
+            porep_config.api_features,
+        )?,
+        partitions: Some(partitions),
+        priority: false,
+    };
+    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        _,
+    >>::setup(&compound_setup_params)?;
+    let vanilla_params = &compound_public_params.vanilla_params;
+
+    match inputs {
+        CircuitInputs::Blank => MetricCSReport::blank(
+            <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+                StackedDrg<'_, Tree, DefaultPieceHasher>,
+                _,
+            >>::blank_circuit(vanilla_params),
+        ),
+        CircuitInputs::SealCommit(phase1_output) => {
+            let public_inputs = stacked::PublicInputs {
+                replica_id: phase1_output.replica_id,
+                tau: Some(stacked::Tau {
+                    comm_d: DefaultPieceDomain::try_from_bytes(&phase1_output.comm_d)?,
+                    comm_r: as_safe_commitment(&phase1_output.comm_r, "comm_r")?,
+                }),
+                k: None,
+                seed: phase1_output.seed,
+            };
+
+            let circuits = phase1_output
+                .vanilla_proofs
+                .iter()
+                .enumerate()
+                .map(|(k, vanilla_proof)| {
+                    let circuit = StackedCompound::<Tree, DefaultPieceHasher>::circuit(
+                        &public_inputs,
+                        Default::default(),
+                        vanilla_proof,
+                        vanilla_params,
+                        Some(k),
+                    )?;
+                    let inputs =
+                        StackedCompound::<Tree, DefaultPieceHasher>::generate_public_inputs(
+                            &public_inputs,
+                            vanilla_params,
+                            Some(k),
+                        )?;
+                    Ok((circuit, inputs))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            MetricCSReport::with_witness(circuits)
+        }
+        CircuitInputs::PoSt { .. } => bail!("PoSt inputs given for a seal commit circuit"),
+    }
+}
+
+fn test_post_circuit<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    inputs: CircuitInputs<Tree>,
+) -> Result<MetricCSReport> {
+    let (vanilla_params, partitions) = match post_config.typ {
+        PoStType::Winning => (winning_post_setup_params(post_config)?, None),
+        PoStType::Window => {
+            let partitions = match &inputs {
+                CircuitInputs::PoSt { vanilla_proofs, .. } => {
+                    get_partitions_for_window_post(vanilla_proofs.len(), post_config)
+                }
+                _ => None,
+            };
+            (window_post_setup_params(post_config), partitions)
+        }
+    };
+    let setup_params = compound_proof::SetupParams {
+        vanilla_params,
+        partitions,
+        priority: false,
+    };
+    let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+        FallbackPoStCompound::setup(&setup_params)?;
+
+    match inputs {
+        CircuitInputs::Blank => {
+            MetricCSReport::blank(<FallbackPoStCompound<Tree> as CompoundProof<
+                FallbackPoSt<'_, Tree>,
+                FallbackPoStCircuit<Tree>,
+            >>::blank_circuit(&pub_params.vanilla_params))
+        }
+        CircuitInputs::PoSt {
+            randomness,
+            prover_id,
+            vanilla_proofs,
+        } => {
+            let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+                as_safe_commitment(&randomness, "randomness")?;
+            let prover_id_safe: <Tree::Hasher as Hasher>::Domain =
+                as_safe_commitment(&prover_id, "prover_id")?;
+
+            let pub_inputs = fallback::PublicInputs {
+                randomness: randomness_safe,
+                prover_id: prover_id_safe,
+                sectors: vanilla_proofs
+                    .iter()
+                    .map(|vanilla_proof| PublicSector {
+                        id: vanilla_proof.sector_id,
+                        comm_r: vanilla_proof.comm_r,
+                    })
+                    .collect(),
+                k: None,
+            };
+
+            let partitioned_proofs = partition_vanilla_proofs(
+                post_config,
+                &pub_params.vanilla_params,
+                &pub_inputs,
+                pub_params.partitions.unwrap_or(1),
+                &vanilla_proofs,
+            )?;
+
+            let circuits = partitioned_proofs
+                .iter()
+                .enumerate()
+                .map(|(k, partition_proof)| {
+                    let circuit = FallbackPoStCompound::<Tree>::circuit(
+                        &pub_inputs,
+                        Default::default(),
+                        partition_proof,
+                        &pub_params.vanilla_params,
+                        Some(k),
+                    )?;
+                    let inputs = FallbackPoStCompound::<Tree>::generate_public_inputs(
+                        &pub_inputs,
+                        &pub_params.vanilla_params,
+                        Some(k),
+                    )?;
+                    Ok((circuit, inputs))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            MetricCSReport::with_witness(circuits)
+        }
+        CircuitInputs::SealCommit(_) => bail!("seal commit inputs given for a PoSt circuit"),
+    }
+}
+
+/// Number of constraints and allocations below a namespace.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct NamespaceCounts {
+    pub constraints: usize,
+    pub inputs: usize,
+    pub aux: usize,
+}
+
+/// The constraint structure of a blank circuit, as returned by [`dump_circuit_constraints`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ConstraintDump {
+    pub num_constraints: usize,
+    /// Public inputs, including the constant one.
+    pub num_inputs: usize,
+    pub num_aux: usize,
+    /// Counts keyed by namespace path (e.g. `challenge_0/labeling_proof`). Each namespace counts
+    /// everything allocated below it, so parents include the counts of their children.
+    pub namespaces: BTreeMap<String, NamespaceCounts>,
+}
+
+/// Synthesizes the blank circuit of `proof` and returns the number of constraints, inputs and
+/// auxiliary variables per gadget namespace, down to `depth` levels of nesting.
+///
+/// The public input shape (sector size, partitions, sector count, ...) is taken from the config,
+/// so dumps of the same config can be diffed against upstream to audit local circuit changes.
+pub fn dump_circuit_constraints<Tree: 'static + MerkleTreeTrait>(
+    proof: CircuitProof,
+    depth: usize,
+) -> Result<ConstraintDump> {
+    info!("dump_circuit_constraints:start: {:?}", proof);
+
+    let mut cs = MetricCS::<Fr>::new();
+    match proof {
+        CircuitProof::SealCommit(porep_config) => {
+            let vanilla_params = public_params::<Tree>(
+                PaddedBytesAmount::from(porep_config),
+                usize::from(PoRepProofPartitions::from(porep_config)),
+                porep_config.porep_id,
+                porep_config.api_version,
+                porep_config.api_features,
+            )?;
+            <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+                StackedDrg<'_, Tree, DefaultPieceHasher>,
+                _,
+            >>::blank_circuit(&vanilla_params)
+            .synthesize(&mut cs)?;
+        }
+        CircuitProof::WinningPoSt(post_config) => {
+            ensure!(
+                post_config.typ == PoStType::Winning,
+                "invalid post config type"
+            );
+            let vanilla_params = winning_post_public_params::<Tree>(&post_config)?;
+            <FallbackPoStCompound<Tree> as CompoundProof<
+                FallbackPoSt<'_, Tree>,
+                FallbackPoStCircuit<Tree>,
+            >>::blank_circuit(&vanilla_params)
+            .synthesize(&mut cs)?;
+        }
+        CircuitProof::WindowPoSt(post_config) => {
+            ensure!(
+                post_config.typ == PoStType::Window,
+                "invalid post config type"
+            );
+            let vanilla_params = window_post_public_params::<Tree>(&post_config)?;
+            <FallbackPoStCompound<Tree> as CompoundProof<
+                FallbackPoSt<'_, Tree>,
+                FallbackPoStCircuit<Tree>,
+            >>::blank_circuit(&vanilla_params)
+            .synthesize(&mut cs)?;
+        }
+    }
+
+    let dump = count_namespaces(&cs.pretty_print_list(), depth);
+    ensure!(
+        dump.num_constraints == cs.num_constraints() && dump.num_inputs == cs.num_inputs(),
+        "constraint system listing is inconsistent"
+    );
+
+    info!("dump_circuit_constraints:finish");
+    Ok(dump)
+}
+
+/// Like [`dump_circuit_constraints`], but returns the dump as pretty printed JSON.
+pub fn dump_circuit_constraints_json<Tree: 'static + MerkleTreeTrait>(
+    proof: CircuitProof,
+    depth: usize,
+) -> Result<String> {
+    let dump = dump_circuit_constraints::<Tree>(proof, depth)?;
+    Ok(serde_json::to_string_pretty(&dump)?)
+}
+
+#[derive(Clone, Copy)]
+enum Allocation {
+    Input,
+    Aux,
+    Constraint,
+}
+
+/// Aggregates a constraint system listing, which names every input (`INPUT <path>`), auxiliary
+/// variable (`AUX <path>`) and constraint (`<path>`) by its full namespace path.
+fn count_namespaces(list: &[String], depth: usize) -> ConstraintDump {
+    let mut dump = ConstraintDump {
+        num_constraints: 0,
+        num_inputs: 0,
+        num_aux: 0,
+        namespaces: BTreeMap::new(),
+    };
+
+    for entry in list {
+        let (path, kind) = if let Some(path) = entry.strip_prefix("INPUT ") {
+            (path, Allocation::Input)
+        } else if let Some(path) = entry.strip_prefix("AUX ") {
+            (path, Allocation::Aux)
+        } else {
+            (entry.as_str(), Allocation::Constraint)
+        };
+        match kind {
+            Allocation::Input => dump.num_inputs += 1,
+            Allocation::Aux => dump.num_aux += 1,
+            Allocation::Constraint => dump.num_constraints += 1,
+        }
+
+        // The last component is the annotation of the entry itself, not a namespace.
+        let components: Vec<&str> = path.split('/').collect();
+        let namespaces = (components.len() - 1).min(depth);
+        for len in 1..=namespaces {
+            let counts = dump
+                .namespaces
+                .entry(components[..len].join("/"))
+                .or_default();
+            match kind {
+                Allocation::Input => counts.inputs += 1,
+                Allocation::Aux => counts.aux += 1,
+                Allocation::Constraint => counts.constraints += 1,
+            }
+        }
+    }
+
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_namespaces() {
+        let list: Vec<String> = [
+            "INPUT ONE",
+            "INPUT replica_id/input 0",
+            "AUX replica_id/num",
+            "AUX challenge_0/parents/parent_0/num",
+            "challenge_0/parents/parent_0/constraint",
+            "challenge_0/parents/parent_1/constraint",
+            "challenge_1/constraint",
+            "root constraint",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+        let dump = count_namespaces(&list, 2);
+        assert_eq!(dump.num_inputs, 2);
+        assert_eq!(dump.num_aux, 2);
+        assert_eq!(dump.num_constraints, 4);
+
+        let counts = |constraints, inputs, aux| NamespaceCounts {
+            constraints,
+            inputs,
+            aux,
+        };
+        let expected: BTreeMap<String, NamespaceCounts> = vec![
+            ("replica_id".to_string(), counts(0, 1, 1)),
+            ("challenge_0".to_string(), counts(2, 0, 1)),
+            ("challenge_0/parents".to_string(), counts(2, 0, 1)),
+            ("challenge_1".to_string(), counts(1, 0, 0)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(dump.namespaces, expected);
+    }
+}
@@ -0,0 +1,495 @@
+//! Deterministic fake sealing and proving, for devnets and integration tests only.
+//!
+//! Replicas, commitments and proofs are derived from the inputs by hashing, so the same inputs
+//! always give the same outputs and every operation is done in seconds. The proofs have the size
+//! and encoding of real Groth16 proofs, but they prove nothing: they are only accepted by the
+//! `fake_verify_*` functions of this module, which recompute them from the public inputs.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use bellperson::groth16;
+use bincode::serialize;
+use blstrs::{Bls12, G1Projective, G2Projective, Scalar as Fr};
+use ff::Field;
+use filecoin_hashers::{Domain, HashFunction, Hasher};
+use group::{Curve, Group};
+use log::{info, warn};
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+use sha2::{Digest, Sha256};
+use storage_proofs_core::{cache_key::CacheKey, merkle::MerkleTreeTrait, sector::SectorId};
+use storage_proofs_porep::stacked::PersistentAux;
+
+use crate::{
+    api::{compute_comm_d, post_util::get_partitions_for_window_post},
+    constants::SINGLE_PARTITION_PROOF_LEN,
+    types::{
+        ChallengeSeed, Commitment, PieceInfo, PoRepConfig, PoRepProofPartitions, PoStConfig,
+        ProverId, PublicReplicaInfo, SealCommitOutput, SealPreCommitOutput, SnarkProof, Ticket,
+    },
+    PoStType,
+};
+
+/// Seeds a deterministic rng from a domain separation tag and the given inputs.
+fn fake_rng(tag: &str, inputs: &[&[u8]]) -> XorShiftRng {
+    let mut hasher = Sha256::new();
+    hasher.update(tag.as_bytes());
+    for input in inputs {
+        hasher.update(&(input.len() as u64).to_le_bytes());
+        hasher.update(input);
+    }
+    let digest = hasher.finalize();
+
+    let mut seed = [0u8; 16];
+    seed.copy_from_slice(&digest[..16]);
+    XorShiftRng::from_seed(seed)
+}
+
+/// Proof bytes for `partitions` partitions: each partition is a Groth16 proof made of valid
+/// curve points, derived from `rng`.
+fn fake_proof_bytes(rng: &mut XorShiftRng, partitions: usize) -> Result<Vec<u8>> {
+    let mut proof_bytes = Vec::with_capacity(partitions * SINGLE_PARTITION_PROOF_LEN);
+    for _ in 0..partitions {
+        let proof = groth16::Proof::<Bls12> {
+            a: (G1Projective::generator() * Fr::random(&mut *rng)).to_affine(),
+            b: (G2Projective::generator() * Fr::random(&mut *rng)).to_affine(),
+            c: (G1Projective::generator() * Fr::random(&mut *rng)).to_affine(),
+        };
+        proof.write(&mut proof_bytes)?;
+    }
+    ensure!(
+        proof_bytes.len() == partitions * SINGLE_PARTITION_PROOF_LEN,
+        "invalid fake proof length"
+    );
+
+    Ok(proof_bytes)
+}
+
+fn fake_persistent_aux<Tree: 'static + MerkleTreeTrait>(
+    porep_config: &PoRepConfig,
+    prover_id: &ProverId,
+    sector_id: SectorId,
+    ticket: &Ticket,
+    comm_d: &Commitment,
+) -> (
+    <Tree::Hasher as Hasher>::Domain,
+    PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+) {
+    let mut rng = fake_rng(
+        "fake-porep-comm-r",
+        &[
+            &porep_config.porep_id[..],
+            &prover_id[..],
+            &u64::from(sector_id).to_le_bytes(),
+            &ticket[..],
+            &comm_d[..],
+        ],
+    );
+    let comm_c = <Tree::Hasher as Hasher>::Domain::random(&mut rng);
+    let comm_r_last = <Tree::Hasher as Hasher>::Domain::random(&mut rng);
+
+    // comm_r = H(comm_c || comm_r_last), like for real replicas.
+    let comm_r = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &comm_r_last);
+
+    (
+        comm_r,
+        PersistentAux {
+            comm_c,
+            comm_r_last,
+        },
+    )
+}
+
+fn commitment_from_domain<D: Domain>(domain: D) -> Commitment {
+    let mut commitment = [0u8; 32];
+    commitment[..].copy_from_slice(&domain.into_bytes()[..]);
+    commitment
+}
+
+/// Fake counterpart of `seal_pre_commit_phase1` and `seal_pre_commit_phase2`.
+///
+/// The staged sector at `in_path` is copied to `out_path` as the replica, and a `p_aux` with
+/// fake `comm_c` and `comm_r_last` is written to `cache_path`. `comm_d` is the real one of the
+/// pieces, `comm_r` is derived from the inputs.
+#[allow(clippy::too_many_arguments)]
+pub fn fake_seal_pre_commit<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+) -> Result<SealPreCommitOutput>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    info!("fake_seal_pre_commit:start: {:?}", sector_id);
+    warn!("creating a FAKE replica, it can only be proven with fake proofs");
+
+    let comm_d = compute_comm_d(porep_config.sector_size, piece_infos)?;
+
+    if in_path.as_ref() != out_path.as_ref() {
+        fs::copy(&in_path, &out_path).with_context(|| {
+            format!(
+                "could not copy {:?} to {:?}",
+                in_path.as_ref(),
+                out_path.as_ref()
+            )
+        })?;
+    }
+
+    let (comm_r, p_aux) =
+        fake_persistent_aux::<Tree>(&porep_config, &prover_id, sector_id, &ticket, &comm_d);
+
+    let p_aux_path = cache_path.as_ref().join(CacheKey::PAux.to_string());
+    let mut f_p_aux = File::create(&p_aux_path)
+        .with_context(|| format!("could not create file p_aux={:?}", p_aux_path))?;
+    let p_aux_bytes = serialize(&p_aux)?;
+    f_p_aux
+        .write_all(&p_aux_bytes)
+        .with_context(|| format!("could not write to file p_aux={:?}", p_aux_path))?;
+
+    info!("fake_seal_pre_commit:finish: {:?}", sector_id);
+    Ok(SealPreCommitOutput {
+        comm_r: commitment_from_domain(comm_r),
+        comm_d,
+    })
+}
+
+fn fake_seal_proof(
+    porep_config: &PoRepConfig,
+    comm_r: &Commitment,
+    comm_d: &Commitment,
+    prover_id: &ProverId,
+    sector_id: SectorId,
+    ticket: &Ticket,
+    seed: &Ticket,
+) -> Result<Vec<u8>> {
+    let mut rng = fake_rng(
+        "fake-porep-proof",
+        &[
+            &porep_config.porep_id[..],
+            &comm_r[..],
+            &comm_d[..],
+            &prover_id[..],
+            &u64::from(sector_id).to_le_bytes(),
+            &ticket[..],
+            &seed[..],
+        ],
+    );
+    let partitions = usize::from(PoRepProofPartitions::from(*porep_config));
+
+    fake_proof_bytes(&mut rng, partitions)
+}
+
+/// Fake counterpart of `seal_commit_phase1` and `seal_commit_phase2`, for a sector created by
+/// `fake_seal_pre_commit`.
+pub fn fake_seal_commit<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r: Commitment,
+    comm_d: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+) -> Result<SealCommitOutput> {
+    info!("fake_seal_commit:start: {:?}", sector_id);
+
+    let (expected_comm_r, _) =
+        fake_persistent_aux::<Tree>(&porep_config, &prover_id, sector_id, &ticket, &comm_d);
+    ensure!(
+        commitment_from_domain(expected_comm_r) == comm_r,
+        "comm_r does not belong to a fake sector"
+    );
+
+    let proof = fake_seal_proof(
+        &porep_config,
+        &comm_r,
+        &comm_d,
+        &prover_id,
+        sector_id,
+        &ticket,
+        &seed,
+    )?;
+
+    info!("fake_seal_commit:finish: {:?}", sector_id);
+    Ok(SealCommitOutput { proof })
+}
+
+/// Fake counterpart of `verify_seal`: accepts exactly the proofs made by `fake_seal_commit`.
+#[allow(clippy::too_many_arguments)]
+pub fn fake_verify_seal<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    proof_vec: &[u8],
+) -> Result<bool> {
+    ensure!(comm_d_in != [0; 32], "Invalid all zero commitment (comm_d)");
+    ensure!(comm_r_in != [0; 32], "Invalid all zero commitment (comm_r)");
+    ensure!(!proof_vec.is_empty(), "Invalid proof bytes (empty vector)");
+
+    let (expected_comm_r, _) =
+        fake_persistent_aux::<Tree>(&porep_config, &prover_id, sector_id, &ticket, &comm_d_in);
+    if commitment_from_domain(expected_comm_r) != comm_r_in {
+        return Ok(false);
+    }
+
+    let expected = fake_seal_proof(
+        &porep_config,
+        &comm_r_in,
+        &comm_d_in,
+        &prover_id,
+        sector_id,
+        &ticket,
+        &seed,
+    )?;
+
+    Ok(expected == proof_vec)
+}
+
+fn fake_post_proof<'a, Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: impl Iterator<Item = (&'a SectorId, &'a PublicReplicaInfo)>,
+    prover_id: &ProverId,
+    partitions: usize,
+) -> Result<SnarkProof> {
+    let mut inputs = vec![
+        u64::from(post_config.sector_size).to_le_bytes().to_vec(),
+        randomness.to_vec(),
+        prover_id.to_vec(),
+    ];
+    for (sector_id, replica) in replicas {
+        let comm_r: <Tree::Hasher as Hasher>::Domain = replica.safe_comm_r()?;
+        inputs.push(u64::from(*sector_id).to_le_bytes().to_vec());
+        inputs.push(comm_r.into_bytes());
+    }
+    let inputs: Vec<&[u8]> = inputs.iter().map(|input| &input[..]).collect();
+
+    let tag = match post_config.typ {
+        PoStType::Winning => "fake-winning-post-proof",
+        PoStType::Window => "fake-window-post-proof",
+    };
+    let mut rng = fake_rng(tag, &inputs);
+
+    fake_proof_bytes(&mut rng, partitions)
+}
+
+/// Fake counterpart of `generate_winning_post`. Only the public information of the replicas is
+/// needed, as nothing is read from them.
+pub fn fake_generate_winning_post<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &[(SectorId, PublicReplicaInfo)],
+    prover_id: ProverId,
+) -> Result<SnarkProof> {
+    ensure!(
+        post_config.typ == PoStType::Winning,
+        "invalid post config type"
+    );
+    ensure!(
+        replicas.len() == post_config.sector_count,
+        "invalid amount of replicas"
+    );
+
+    fake_post_proof::<Tree>(
+        post_config,
+        randomness,
+        replicas.iter().map(|(id, replica)| (id, replica)),
+        &prover_id,
+        1,
+    )
+}
+
+/// Fake counterpart of `verify_winning_post`: accepts exactly the proofs made by
+/// `fake_generate_winning_post`.
+pub fn fake_verify_winning_post<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &[(SectorId, PublicReplicaInfo)],
+    prover_id: ProverId,
+    proof: &[u8],
+) -> Result<bool> {
+    let expected =
+        fake_generate_winning_post::<Tree>(post_config, randomness, replicas, prover_id)?;
+
+    Ok(expected == proof)
+}
+
+/// Fake counterpart of `generate_window_post`, with one proof partition per
+/// `post_config.sector_count` sectors like the real one.
+pub fn fake_generate_window_post<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    prover_id: ProverId,
+) -> Result<SnarkProof> {
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+    ensure!(!replicas.is_empty(), "no replicas given");
+
+    let partitions = get_partitions_for_window_post(replicas.len(), post_config).unwrap_or(1);
+
+    fake_post_proof::<Tree>(
+        post_config,
+        randomness,
+        replicas.iter(),
+        &prover_id,
+        partitions,
+    )
+}
+
+/// Fake counterpart of `verify_window_post`: accepts exactly the proofs made by
+/// `fake_generate_window_post`.
+pub fn fake_verify_window_post<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+    prover_id: ProverId,
+    proof: &[u8],
+) -> Result<bool> {
+    let expected = fake_generate_window_post::<Tree>(post_config, randomness, replicas, prover_id)?;
+
+    Ok(expected == proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use storage_proofs_core::api_version::ApiVersion;
+    use tempfile::tempdir;
+
+    use crate::{
+        constants::{SectorShape2KiB, SECTOR_SIZE_2_KIB, WINDOW_POST_CHALLENGE_COUNT},
+        types::{PaddedBytesAmount, SectorSize},
+    };
+
+    fn porep_config() -> PoRepConfig {
+        PoRepConfig {
+            sector_size: SectorSize(SECTOR_SIZE_2_KIB),
+            partitions: PoRepProofPartitions(1),
+            porep_id: [1; 32],
+            api_version: ApiVersion::V1_1_0,
+            api_features: &[],
+        }
+    }
+
+    #[test]
+    fn test_fake_seal_is_deterministic() {
+        let porep_config = porep_config();
+        let dir = tempdir().expect("tempdir failure");
+        let staged_path = dir.path().join("staged");
+        fs::write(
+            &staged_path,
+            vec![0u8; PaddedBytesAmount::from(porep_config).0 as usize],
+        )
+        .expect("failed to write staged sector");
+        let pre_commit = |out: &str| {
+            fake_seal_pre_commit::<_, _, _, SectorShape2KiB>(
+                porep_config,
+                dir.path(),
+                &staged_path,
+                dir.path().join(out),
+                [2; 32],
+                SectorId::from(3),
+                [4; 32],
+                &[],
+            )
+            .expect("fake_seal_pre_commit failure")
+        };
+        let output = pre_commit("replica-a");
+        let again = pre_commit("replica-b");
+        assert_eq!(output.comm_r, again.comm_r);
+        assert_eq!(output.comm_d, again.comm_d);
+
+        let commit = fake_seal_commit::<SectorShape2KiB>(
+            porep_config,
+            output.comm_r,
+            output.comm_d,
+            [2; 32],
+            SectorId::from(3),
+            [4; 32],
+            [5; 32],
+        )
+        .expect("fake_seal_commit failure");
+        assert_eq!(commit.proof.len(), SINGLE_PARTITION_PROOF_LEN);
+        groth16::Proof::<Bls12>::read(&commit.proof[..]).expect("invalid proof encoding");
+
+        let verify = |seed: Ticket| {
+            fake_verify_seal::<SectorShape2KiB>(
+                porep_config,
+                output.comm_r,
+                output.comm_d,
+                [2; 32],
+                SectorId::from(3),
+                [4; 32],
+                seed,
+                &commit.proof,
+            )
+            .expect("fake_verify_seal failure")
+        };
+        assert!(verify([5; 32]));
+        assert!(!verify([6; 32]));
+    }
+
+    #[test]
+    fn test_fake_window_post() {
+        let post_config = PoStConfig {
+            sector_size: SectorSize(SECTOR_SIZE_2_KIB),
+            challenge_count: WINDOW_POST_CHALLENGE_COUNT,
+            sector_count: 2,
+            typ: PoStType::Window,
+            priority: false,
+            api_version: ApiVersion::V1_1_0,
+        };
+        let replicas: BTreeMap<SectorId, PublicReplicaInfo> = (1..=3u64)
+            .map(|i| {
+                (
+                    SectorId::from(i),
+                    PublicReplicaInfo::new([i as u8; 32]).expect("replica info failure"),
+                )
+            })
+            .collect();
+
+        let proof = fake_generate_window_post::<SectorShape2KiB>(
+            &post_config,
+            &[7; 32],
+            &replicas,
+            [2; 32],
+        )
+        .expect("fake_generate_window_post failure");
+        assert_eq!(proof.len(), 2 * SINGLE_PARTITION_PROOF_LEN);
+
+        assert!(fake_verify_window_post::<SectorShape2KiB>(
+            &post_config,
+            &[7; 32],
+            &replicas,
+            [2; 32],
+            &proof
+        )
+        .expect("fake_verify_window_post failure"));
+        assert!(!fake_verify_window_post::<SectorShape2KiB>(
+            &post_config,
+            &[8; 32],
+            &replicas,
+            [2; 32],
+            &proof
+        )
+        .expect("fake_verify_window_post failure"));
+    }
+}
@@ -2,7 +2,7 @@ use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use bincode::serialize;
 use filecoin_hashers::{Domain, Hasher};
 use rand::{thread_rng, Rng};
@@ -10,7 +10,7 @@ use storage_proofs_core::{cache_key::CacheKey, merkle::MerkleTreeTrait};
 use storage_proofs_porep::stacked::StackedDrg;
 
 use crate::{
-    constants::DefaultPieceHasher,
+    constants::{DefaultPieceHasher, LAYERS},
     types::{Commitment, PaddedBytesAmount, PoRepConfig},
 };
 
@@ -23,6 +23,40 @@ pub fn fauxrep<R: AsRef<Path>, S: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
     fauxrep_aux::<_, R, S, Tree>(&mut rng, porep_config, cache_path, out_path)
 }
 
+/// `fauxrep` of a sector of any built-in or registered size, for callers which don't know the
+/// shape of its trees.
+pub fn fauxrep_sector<R: AsRef<Path>, S: AsRef<Path>>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    out_path: S,
+) -> Result<Commitment> {
+    let sector_size = u64::from(porep_config.sector_size);
+    ensure!(
+        LAYERS
+            .read()
+            .expect("LAYERS poisoned")
+            .contains_key(&sector_size),
+        "unknown sector size {}",
+        sector_size
+    );
+
+    crate::with_shape!(
+        sector_size,
+        fauxrep_shaped,
+        porep_config,
+        cache_path.as_ref(),
+        out_path.as_ref(),
+    )
+}
+
+fn fauxrep_shaped<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: &Path,
+    out_path: &Path,
+) -> Result<Commitment> {
+    fauxrep::<_, _, Tree>(porep_config, cache_path, out_path)
+}
+
 pub fn fauxrep_aux<R: Rng, S: AsRef<Path>, T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
     mut rng: &mut R,
     porep_config: PoRepConfig,
@@ -0,0 +1,114 @@
+//! Estimating the device memory of the GPU work of a sector.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use bellperson::{util_cs::bench_cs::BenchCS, Circuit};
+use blstrs::Scalar as Fr;
+use lazy_static::lazy_static;
+use storage_proofs_core::{compound_proof::CompoundProof, merkle::MerkleTreeTrait, tenant};
+use storage_proofs_porep::stacked::{
+    gpu_batch_bytes, tree_r_last_gpu_batch_size, StackedCompound, StackedDrg,
+};
+use typenum::Unsigned;
+
+use crate::{
+    constants::DefaultPieceHasher,
+    parameters::public_params,
+    types::{PaddedBytesAmount, PoRepConfig, PoRepProofPartitions},
+};
+
+lazy_static! {
+    /// The FFT domain sizes of the C2 circuits, by sector size and circuit.
+    static ref DOMAIN_SIZES: Mutex<HashMap<(u64, String), u64>> = Mutex::new(HashMap::new());
+}
+
+/// The GPU work of a sector, see [`estimate_gpu_memory`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuPhase {
+    /// Building tree_c in PC2 with the GPU column builder.
+    TreeC,
+    /// Building tree_r_last in PC2 with the GPU tree builder.
+    TreeRLast,
+    /// Proving the partitions of C2.
+    SealCommitPhase2,
+}
+
+/// Estimates the device memory in bytes which `phase` of a sector of `porep_config` uses on a GPU,
+/// so that a job can be placed on a GPU it fits on.
+///
+/// The trees are hashed by the batchers of the GPU builders, sized by the
+/// `max_gpu_column_batch_size` and `max_gpu_tree_batch_size` settings (with
+/// `gpu_tree_batch_size_auto`, the batch of tree_r_last is tuned to the device instead, which the
+/// first estimate does like the first build). C2 needs the FFTs of its circuits on the device,
+/// whose domain is counted from the blank circuit without loading the Groth parameters; the
+/// multiexps are split into chunks which fit the remaining memory.
+pub fn estimate_gpu_memory(porep_config: &PoRepConfig, phase: GpuPhase) -> Result<u64> {
+    let sector_size = u64::from(porep_config.sector_size);
+    let settings = tenant::settings();
+    let arity = crate::with_shape!(sector_size, tree_arity,);
+    let tree_batch_bytes = gpu_batch_bytes(arity, settings.max_gpu_tree_batch_size as usize);
+
+    match phase {
+        GpuPhase::TreeC => {
+            // The columns are hashed while the trees over their hashes are built.
+            let column_batch_bytes = gpu_batch_bytes(
+                porep_config.layers(),
+                settings.max_gpu_column_batch_size as usize,
+            );
+            Ok(column_batch_bytes + tree_batch_bytes)
+        }
+        GpuPhase::TreeRLast => Ok(gpu_batch_bytes(arity, tree_r_last_gpu_batch_size(arity)?)),
+        GpuPhase::SealCommitPhase2 => {
+            crate::with_shape!(sector_size, seal_commit_phase2_gpu_memory, porep_config,)
+        }
+    }
+}
+
+fn tree_arity<Tree: MerkleTreeTrait>() -> usize {
+    Tree::Arity::to_usize()
+}
+
+fn seal_commit_phase2_gpu_memory<Tree: 'static + MerkleTreeTrait>(
+    porep_config: &PoRepConfig,
+) -> Result<u64> {
+    // An FFT reads its elements from one buffer and writes them to another.
+    Ok(2 * porep_domain_size::<Tree>(porep_config)? * std::mem::size_of::<Fr>() as u64)
+}
+
+/// The size of the FFT domain of the partition circuits of `porep_config`, which holds an element
+/// for every constraint and every public input (the inputs are constrained too), rounded up to a
+/// power of two like the Groth parameters. The blank circuit is synthesized once per circuit to
+/// count them.
+fn porep_domain_size<Tree: 'static + MerkleTreeTrait>(porep_config: &PoRepConfig) -> Result<u64> {
+    let key = (
+        u64::from(porep_config.sector_size),
+        porep_config.get_cache_identifier::<Tree>()?,
+    );
+    if let Some(domain_size) = DOMAIN_SIZES.lock().expect("poisoned cache").get(&key) {
+        return Ok(*domain_size);
+    }
+
+    let public_params = public_params::<Tree>(
+        PaddedBytesAmount::from(*porep_config),
+        usize::from(PoRepProofPartitions::from(*porep_config)),
+        porep_config.porep_id,
+        porep_config.api_version,
+        porep_config.api_features,
+    )?;
+    let circuit = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        _,
+    >>::blank_circuit(&public_params);
+    let mut cs = BenchCS::<Fr>::new();
+    circuit.synthesize(&mut cs)?;
+    let domain_size = ((cs.num_constraints() + cs.num_inputs()) as u64).next_power_of_two();
+
+    DOMAIN_SIZES
+        .lock()
+        .expect("poisoned cache")
+        .insert(key, domain_size);
+
+    Ok(domain_size)
+}
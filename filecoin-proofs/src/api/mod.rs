@@ -14,11 +14,12 @@ use storage_proofs_core::{
     measurements::{measure_op, Operation},
     merkle::get_base_tree_count,
     pieces::generate_piece_commitment_bytes_from_source,
+    priority::{enter_priority_class, PriorityClass},
     sector::SectorId,
     util::default_rows_to_discard,
 };
 use storage_proofs_porep::{
-    stacked::{generate_replica_id, PersistentAux, StackedDrg, TemporaryAux},
+    stacked::{self, PersistentAux, StackedDrg, TemporaryAux},
     PoRep,
 };
 pub use storage_proofs_update::constants::TreeRHasher;
@@ -39,22 +40,47 @@ use crate::{
     },
 };
 
+mod circuit;
+#[cfg(feature = "fake-proofs")]
+mod fake_proofs;
 mod fake_seal;
+mod gpu_memory;
+mod param_gen;
+mod parent_cache;
+mod piece_inclusion;
+mod post_cache;
 mod post_util;
+mod remote_c2;
+mod replica_check;
 mod seal;
+mod sector_store;
 mod update;
 mod util;
 mod window_post;
 mod winning_post;
 
+pub use circuit::*;
+#[cfg(feature = "fake-proofs")]
+pub use fake_proofs::*;
 pub use fake_seal::*;
+pub use gpu_memory::*;
+pub use param_gen::*;
+pub use parent_cache::*;
+pub use piece_inclusion::*;
+pub use post_cache::*;
 pub use post_util::*;
+pub use remote_c2::prove_remote_c2_request;
+pub use replica_check::*;
 pub use seal::*;
+pub use sector_store::*;
 pub use update::*;
 pub use util::*;
 pub use window_post::*;
 pub use winning_post::*;
 
+pub use storage_proofs_core::parameter_cache::{
+    verify_all_cached_parameters, verify_parameter_file, ParameterFileReport, ParameterFileStatus,
+};
 pub use storage_proofs_update::constants::{hs, partition_count};
 
 /// Unseals the sector at `sealed_path` and returns the bytes for a piece
@@ -148,12 +174,13 @@ where
     Tree: 'static + MerkleTreeTrait,
 {
     info!("unseal_range:start");
+    let _priority = enter_priority_class(PriorityClass::Unseal);
     ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
 
     let comm_d =
         as_safe_commitment::<<DefaultPieceHasher as Hasher>::Domain, _>(&comm_d, "comm_d")?;
 
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+    let replica_id = stacked::generate_replica_id::<Tree::Hasher, _>(
         &prover_id,
         sector_id.into(),
         &ticket,
@@ -215,12 +242,13 @@ where
     Tree: 'static + MerkleTreeTrait,
 {
     info!("unseal_range_mapped:start");
+    let _priority = enter_priority_class(PriorityClass::Unseal);
     ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
 
     let comm_d =
         as_safe_commitment::<<DefaultPieceHasher as Hasher>::Domain, _>(&comm_d, "comm_d")?;
 
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+    let replica_id = stacked::generate_replica_id::<Tree::Hasher, _>(
         &prover_id,
         sector_id.into(),
         &ticket,
@@ -297,6 +325,7 @@ where
         usize::from(PoRepProofPartitions::from(porep_config)),
         porep_config.porep_id,
         porep_config.api_version,
+        porep_config.api_features,
     )?;
 
     let offset_padded: PaddedBytesAmount = UnpaddedBytesAmount::from(offset).into();
@@ -714,9 +743,12 @@ where
         res
     };
 
-    // Verify all stores/labels within the Labels object.
+    // Verify all stores/labels within the Labels object. The layers of a synthetic PoRep may be
+    // discarded already, its challenges are proven by the synthetic proofs.
     let cache = cache_path.as_ref().to_path_buf();
-    t_aux.labels.verify_stores(verify_store, &cache)?;
+    if !cache.join(CacheKey::SynthProofs.to_string()).exists() {
+        t_aux.labels.verify_stores(verify_store, &cache)?;
+    }
 
     // Verify each tree disk store.
     verify_store(
@@ -0,0 +1,188 @@
+//! Generating the Groth parameters of a circuit, as `paramcache` does.
+
+use std::env;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+use rand::rngs::OsRng;
+use storage_proofs_core::{
+    compound_proof::CompoundProof,
+    merkle::MerkleTreeTrait,
+    parameter_cache::{
+        parameter_cache_metadata_path, parameter_cache_params_path,
+        parameter_cache_verifying_key_path, CacheableParameters,
+    },
+    settings::Settings,
+    tenant,
+};
+use storage_proofs_porep::stacked::{StackedCircuit, StackedCompound, StackedDrg};
+use storage_proofs_post::fallback::{FallbackPoSt, FallbackPoStCircuit, FallbackPoStCompound};
+use storage_proofs_update::{
+    circuit::EmptySectorUpdateCircuit, compound::EmptySectorUpdateCompound, constants::TreeRHasher,
+    EmptySectorUpdate, PublicParams,
+};
+
+use crate::{
+    constants::DefaultPieceHasher,
+    parameters::{public_params, window_post_public_params, winning_post_public_params},
+    types::{PaddedBytesAmount, PoRepConfig, PoRepProofPartitions, PoStConfig, PoStType},
+};
+
+/// A circuit whose Groth parameters are generated by [`generate_params`], the counterpart of a
+/// registered proof of the FFI.
+#[derive(Clone, Debug)]
+pub enum ParameterSet {
+    /// The PoRep of `porep_config`, interactive or not.
+    PoRep(PoRepConfig),
+    /// The Winning or Window PoSt of `post_config`.
+    PoSt(PoStConfig),
+    /// The EmptySectorUpdate of the sector size of `porep_config`.
+    EmptySectorUpdate(PoRepConfig),
+}
+
+impl ParameterSet {
+    fn sector_size(&self) -> u64 {
+        match self {
+            ParameterSet::PoRep(porep_config) | ParameterSet::EmptySectorUpdate(porep_config) => {
+                u64::from(porep_config.sector_size)
+            }
+            ParameterSet::PoSt(post_config) => u64::from(post_config.sector_size),
+        }
+    }
+}
+
+/// Generates the Groth parameters, the verifying key and the metadata of `parameter_set` into
+/// `output_dir`, unless they are there already, and returns the paths of the three files.
+///
+/// The parameters come from a local, insecure setup. They must never be used in production,
+/// where the parameters of the trusted setup are fetched instead.
+pub fn generate_params(parameter_set: &ParameterSet, output_dir: &Path) -> Result<Vec<PathBuf>> {
+    // The directory is the only one of the cache, it must not read as a list of them.
+    let parameter_cache = env::join_paths(iter::once(output_dir))
+        .ok()
+        .and_then(|dir| dir.into_string().ok())
+        .with_context(|| format!("invalid output directory {:?}", output_dir))?;
+    let settings = Settings {
+        parameter_cache,
+        ..(*tenant::settings()).clone()
+    };
+
+    tenant::with_settings(settings, || cache_params(parameter_set))
+}
+
+/// Generates the Groth parameters, the verifying key and the metadata of `parameter_set` into the
+/// parameter cache, unless they are cached already, and returns the paths of the three files.
+pub fn cache_params(parameter_set: &ParameterSet) -> Result<Vec<PathBuf>> {
+    let sector_size = parameter_set.sector_size();
+    let cache_id = match parameter_set {
+        ParameterSet::PoRep(porep_config) => {
+            crate::with_shape!(sector_size, cache_porep_params, *porep_config,)?
+        }
+        ParameterSet::PoSt(post_config) => {
+            crate::with_shape!(sector_size, cache_post_params, post_config,)?
+        }
+        ParameterSet::EmptySectorUpdate(porep_config) => {
+            crate::with_shape!(sector_size, cache_empty_sector_update_params, *porep_config,)?
+        }
+    };
+
+    Ok(vec![
+        parameter_cache_params_path(&cache_id),
+        parameter_cache_verifying_key_path(&cache_id),
+        parameter_cache_metadata_path(&cache_id),
+    ])
+}
+
+fn cache_porep_params<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+) -> Result<String> {
+    info!("generating PoRep groth params");
+
+    let public_params = public_params(
+        PaddedBytesAmount::from(porep_config),
+        usize::from(PoRepProofPartitions::from(porep_config)),
+        porep_config.porep_id,
+        porep_config.api_version,
+        porep_config.api_features,
+    )?;
+
+    let circuit = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        StackedCircuit<'_, Tree, DefaultPieceHasher>,
+    >>::blank_circuit(&public_params);
+
+    StackedCompound::<Tree, DefaultPieceHasher>::get_param_metadata(
+        circuit.clone(),
+        &public_params,
+    )?;
+    StackedCompound::<Tree, DefaultPieceHasher>::get_groth_params(
+        Some(&mut OsRng),
+        circuit.clone(),
+        &public_params,
+    )?;
+    StackedCompound::<Tree, DefaultPieceHasher>::get_verifying_key(
+        Some(&mut OsRng),
+        circuit,
+        &public_params,
+    )?;
+
+    Ok(StackedCompound::<Tree, DefaultPieceHasher>::cache_identifier(&public_params))
+}
+
+fn cache_post_params<Tree: 'static + MerkleTreeTrait>(post_config: &PoStConfig) -> Result<String> {
+    info!("generating {:?}-PoSt groth params", post_config.typ);
+
+    let public_params = match post_config.typ {
+        PoStType::Winning => winning_post_public_params::<Tree>(post_config)?,
+        PoStType::Window => window_post_public_params::<Tree>(post_config)?,
+    };
+
+    let circuit = <FallbackPoStCompound<Tree> as CompoundProof<
+        FallbackPoSt<'_, Tree>,
+        FallbackPoStCircuit<Tree>,
+    >>::blank_circuit(&public_params);
+
+    <FallbackPoStCompound<Tree>>::get_param_metadata(circuit.clone(), &public_params)?;
+    <FallbackPoStCompound<Tree>>::get_groth_params(
+        Some(&mut OsRng),
+        circuit.clone(),
+        &public_params,
+    )?;
+    <FallbackPoStCompound<Tree>>::get_verifying_key(Some(&mut OsRng), circuit, &public_params)?;
+
+    Ok(<FallbackPoStCompound<Tree>>::cache_identifier(
+        &public_params,
+    ))
+}
+
+fn cache_empty_sector_update_params<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    porep_config: PoRepConfig,
+) -> Result<String> {
+    info!("generating EmptySectorUpdate groth params");
+
+    let public_params: storage_proofs_update::PublicParams =
+        PublicParams::from_sector_size(u64::from(porep_config.sector_size));
+
+    let circuit = <EmptySectorUpdateCompound<Tree> as CompoundProof<
+        EmptySectorUpdate<Tree>,
+        EmptySectorUpdateCircuit<Tree>,
+    >>::blank_circuit(&public_params);
+
+    <EmptySectorUpdateCompound<Tree>>::get_param_metadata(circuit.clone(), &public_params)?;
+    <EmptySectorUpdateCompound<Tree>>::get_groth_params(
+        Some(&mut OsRng),
+        circuit.clone(),
+        &public_params,
+    )?;
+    <EmptySectorUpdateCompound<Tree>>::get_verifying_key(
+        Some(&mut OsRng),
+        circuit,
+        &public_params,
+    )?;
+
+    Ok(<EmptySectorUpdateCompound<Tree>>::cache_identifier(
+        &public_params,
+    ))
+}
@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Context, Result};
+use log::info;
+use storage_proofs_core::{
+    control,
+    priority::{enter_priority_class, PriorityClass},
+    tenant,
+};
+use storage_proofs_porep::stacked::StackedBucketGraph;
+
+use crate::{
+    constants::{DefaultTreeHasher, DRG_DEGREE, EXP_DEGREE},
+    types::{PaddedBytesAmount, PoRepConfig},
+};
+
+/// A parent cache which is generated and verified, see [`generate_parent_caches`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParentCacheStatus {
+    pub sector_size: u64,
+    /// The path of the cache, which is on the shared parent cache directory if it was used.
+    pub path: PathBuf,
+    /// The digest of the cache, as verified against the parent cache manifest or the header
+    /// written when the cache was generated.
+    pub digest: String,
+}
+
+/// Generates the parent caches of `porep_configs`, so that sealing the first sector of a size
+/// doesn't have to.
+///
+/// Existing caches are verified, and regenerated if their digest doesn't match, as if the
+/// verify_cache option was enabled.
+pub fn generate_parent_caches(porep_configs: &[PoRepConfig]) -> Result<Vec<ParentCacheStatus>> {
+    let _job = control::register_job("generate_parent_caches");
+    let _priority = enter_priority_class(PriorityClass::SealPreCommitPhase1);
+
+    porep_configs
+        .iter()
+        .map(|porep_config| {
+            let sector_size = u64::from(PaddedBytesAmount::from(*porep_config));
+            info!("generate_parent_caches: {} byte sectors", sector_size);
+            control::checkpoint("parent cache")?;
+
+            let graph = StackedBucketGraph::<DefaultTreeHasher>::new_stacked(
+                (sector_size / 32) as usize,
+                DRG_DEGREE,
+                EXP_DEGREE,
+                porep_config.porep_id,
+                porep_config.api_version,
+            )?;
+            let cache = graph.verified_parent_cache().with_context(|| {
+                format!("failed to generate the {} byte parent cache", sector_size)
+            })?;
+
+            Ok(ParentCacheStatus {
+                sector_size,
+                path: cache.path.clone(),
+                digest: cache.digest.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Runs [`generate_parent_caches`] on a new thread, on behalf of the tenant of the calling thread.
+pub fn generate_parent_caches_in_background(
+    porep_configs: Vec<PoRepConfig>,
+) -> Result<JoinHandle<Result<Vec<ParentCacheStatus>>>> {
+    let tenant_name = tenant::current_tenant().map(|tenant| tenant.name().to_string());
+
+    thread::Builder::new()
+        .name("parent-cache-generation".to_string())
+        .spawn(move || match tenant_name {
+            Some(name) => tenant::with_tenant(&name, || generate_parent_caches(&porep_configs))?,
+            None => generate_parent_caches(&porep_configs),
+        })
+        .context("failed to spawn the parent cache generation thread")
+}
@@ -0,0 +1,388 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use fr32::write_unpadded;
+use log::info;
+use memmap::MmapOptions;
+use merkletree::store::{DiskStore, StoreConfig};
+use serde::{Deserialize, Serialize};
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    merkle::{create_base_merkle_tree, BinaryMerkleTree},
+    util::{default_rows_to_discard, NODE_SIZE},
+};
+
+use crate::{
+    api::{as_safe_commitment, commitment_from_fr, get_base_tree_leafs, get_base_tree_size},
+    constants::{DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher},
+    pieces::piece_hash,
+    types::{
+        Commitment, PaddedBytesAmount, PieceInfo, SectorSize, UnpaddedByteIndex,
+        UnpaddedBytesAmount, BINARY_ARITY,
+    },
+};
+
+/// Proof that a piece is included in a sector's CommD.
+///
+/// A piece occupies an aligned subtree of the data tree whose root is the piece commitment
+/// (CommP). The proof is the list of siblings on the path from that subtree root up to CommD.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceInclusionProof {
+    pub path: Vec<Commitment>,
+}
+
+/// Generates a [`PieceInclusionProof`] from the data tree (`tree-d`) that sealing stored in
+/// `cache_path`.
+///
+/// # Arguments
+///
+/// * `sector_size` - size of the sector.
+/// * `cache_path` - path to the sector's cache directory, which must still contain `tree-d`.
+/// * `piece_info` - commitment and size of the piece.
+/// * `piece_offset` - offset of the (unpadded) piece within the sector.
+pub fn generate_piece_inclusion_proof<P: AsRef<Path>>(
+    sector_size: SectorSize,
+    cache_path: P,
+    piece_info: &PieceInfo,
+    piece_offset: UnpaddedByteIndex,
+) -> Result<PieceInclusionProof> {
+    info!("generate_piece_inclusion_proof:start");
+
+    let tree = open_tree_d(sector_size, cache_path.as_ref())?;
+    let proof = piece_inclusion_proof(&tree, sector_size, piece_info, piece_offset)?;
+
+    info!("generate_piece_inclusion_proof:finish");
+    Ok(proof)
+}
+
+/// Generates a [`PieceInclusionProof`] by rebuilding the data tree from the padded, unsealed
+/// sector data at `unsealed_path`, e.g. once `tree-d` has been removed from the cache.
+///
+/// The data tree is built in a temporary file of twice the sector size.
+pub fn generate_piece_inclusion_proof_from_data<P: AsRef<Path>>(
+    sector_size: SectorSize,
+    unsealed_path: P,
+    piece_info: &PieceInfo,
+    piece_offset: UnpaddedByteIndex,
+) -> Result<PieceInclusionProof> {
+    info!("generate_piece_inclusion_proof_from_data:start");
+
+    let tree = build_tree_d(sector_size, unsealed_path.as_ref())?;
+    let proof = piece_inclusion_proof(&tree, sector_size, piece_info, piece_offset)?;
+
+    info!("generate_piece_inclusion_proof_from_data:finish");
+    Ok(proof)
+}
+
+/// Verifies that the piece described by `piece_info` is included at `piece_offset` in the sector
+/// with the given `comm_d`.
+pub fn verify_piece_inclusion_proof(
+    sector_size: SectorSize,
+    comm_d: &Commitment,
+    piece_info: &PieceInfo,
+    piece_offset: UnpaddedByteIndex,
+    proof: &PieceInclusionProof,
+) -> Result<bool> {
+    let (height, mut index) = piece_position(sector_size, piece_info, piece_offset)?;
+    let tree_height = (u64::from(sector_size) / NODE_SIZE as u64).trailing_zeros() as usize;
+    if proof.path.len() != tree_height - height {
+        return Ok(false);
+    }
+
+    let mut node: DefaultPieceDomain = as_safe_commitment(&piece_info.commitment, "comm_p")?;
+    for sibling in &proof.path {
+        let sibling: DefaultPieceDomain = as_safe_commitment(sibling, "sibling")?;
+        node = if index % 2 == 0 {
+            piece_hash(node.as_ref(), sibling.as_ref())
+        } else {
+            piece_hash(sibling.as_ref(), node.as_ref())
+        };
+        index /= 2;
+    }
+
+    Ok(commitment_from_fr(node.into()) == *comm_d)
+}
+
+/// Proof that a range of unsealed bytes is included in a sector's CommD, e.g. to prove the
+/// delivery of retrieved data.
+///
+/// Unsealed bytes do not map to whole nodes of the data tree, so the proof carries the leaves
+/// covering the range, extended to whole 127 byte (unpadded) chunks. The siblings needed to
+/// recompute CommD from them are listed from the leaves up, on each level the left one first.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataRangeProof {
+    /// Index of the first leaf in `leaves`.
+    pub first_leaf: u64,
+    pub leaves: Vec<Commitment>,
+    pub path: Vec<Commitment>,
+}
+
+/// Generates a [`DataRangeProof`] for `len` unsealed bytes at `offset`, from the data tree
+/// (`tree-d`) that sealing stored in `cache_path`.
+pub fn generate_data_range_proof<P: AsRef<Path>>(
+    sector_size: SectorSize,
+    cache_path: P,
+    offset: UnpaddedByteIndex,
+    len: UnpaddedBytesAmount,
+) -> Result<DataRangeProof> {
+    info!("generate_data_range_proof:start");
+
+    let tree = open_tree_d(sector_size, cache_path.as_ref())?;
+    let proof = data_range_proof(&tree, sector_size, offset, len)?;
+
+    info!("generate_data_range_proof:finish");
+    Ok(proof)
+}
+
+/// Generates a [`DataRangeProof`] by rebuilding the data tree from the padded, unsealed sector
+/// data at `unsealed_path`, e.g. once `tree-d` has been removed from the cache.
+///
+/// The data tree is built in a temporary file of twice the sector size.
+pub fn generate_data_range_proof_from_data<P: AsRef<Path>>(
+    sector_size: SectorSize,
+    unsealed_path: P,
+    offset: UnpaddedByteIndex,
+    len: UnpaddedBytesAmount,
+) -> Result<DataRangeProof> {
+    info!("generate_data_range_proof_from_data:start");
+
+    let tree = build_tree_d(sector_size, unsealed_path.as_ref())?;
+    let proof = data_range_proof(&tree, sector_size, offset, len)?;
+
+    info!("generate_data_range_proof_from_data:finish");
+    Ok(proof)
+}
+
+/// Verifies that `data` are the unsealed bytes at `offset` of the sector with the given `comm_d`.
+pub fn verify_data_range_proof(
+    sector_size: SectorSize,
+    comm_d: &Commitment,
+    offset: UnpaddedByteIndex,
+    data: &[u8],
+    proof: &DataRangeProof,
+) -> Result<bool> {
+    let (first_leaf, last_leaf) =
+        data_range_leaves(sector_size, offset, UnpaddedBytesAmount(data.len() as u64))?;
+    if proof.first_leaf != first_leaf as u64 || proof.leaves.len() != last_leaf - first_leaf + 1 {
+        return Ok(false);
+    }
+
+    // The bytes of the range, as unpadded from its leaves.
+    let padded: Vec<u8> = proof.leaves.iter().flatten().copied().collect();
+    let chunk_offset = (first_leaf / 4) as u64 * 127;
+    let mut unpadded = Vec::with_capacity(data.len());
+    write_unpadded(
+        &padded,
+        &mut unpadded,
+        (u64::from(offset) - chunk_offset) as usize,
+        data.len(),
+    )
+    .context("write_unpadded failed")?;
+    if unpadded != data {
+        return Ok(false);
+    }
+
+    let mut row = proof
+        .leaves
+        .iter()
+        .map(|leaf| as_safe_commitment::<DefaultPieceDomain, _>(leaf, "leaf"))
+        .collect::<Result<Vec<_>>>()?;
+    let mut siblings = proof.path.iter();
+    let mut row_len = u64::from(sector_size) as usize / NODE_SIZE;
+    let (mut lo, mut hi) = (first_leaf, last_leaf);
+    while row_len > 1 {
+        if lo % 2 == 1 {
+            match siblings.next() {
+                Some(sibling) => row.insert(0, as_safe_commitment(sibling, "sibling")?),
+                None => return Ok(false),
+            }
+        }
+        if hi % 2 == 0 {
+            match siblings.next() {
+                Some(sibling) => row.push(as_safe_commitment(sibling, "sibling")?),
+                None => return Ok(false),
+            }
+        }
+        row = row
+            .chunks(2)
+            .map(|pair| piece_hash(pair[0].as_ref(), pair[1].as_ref()))
+            .collect();
+
+        row_len /= 2;
+        lo /= 2;
+        hi /= 2;
+    }
+    if siblings.next().is_some() {
+        return Ok(false);
+    }
+
+    Ok(commitment_from_fr(row[0].into()) == *comm_d)
+}
+
+/// Returns the first and last leaf covering `len` unsealed bytes at `offset`, extended to whole
+/// 127 byte chunks, as these are the units in which fr32 padding is byte aligned.
+fn data_range_leaves(
+    sector_size: SectorSize,
+    offset: UnpaddedByteIndex,
+    len: UnpaddedBytesAmount,
+) -> Result<(usize, usize)> {
+    let offset = u64::from(offset);
+    let len = u64::from(len);
+    ensure!(len > 0, "data range must not be empty");
+    ensure!(
+        offset + len <= u64::from(UnpaddedBytesAmount::from(sector_size)),
+        "data range must fit into the sector"
+    );
+
+    let first_leaf = (offset / 127 * 4) as usize;
+    let last_leaf = ((offset + len - 1) / 127 * 4 + 3) as usize;
+
+    Ok((first_leaf, last_leaf))
+}
+
+fn data_range_proof(
+    tree: &BinaryMerkleTree<DefaultPieceHasher>,
+    sector_size: SectorSize,
+    offset: UnpaddedByteIndex,
+    len: UnpaddedBytesAmount,
+) -> Result<DataRangeProof> {
+    let (first_leaf, last_leaf) = data_range_leaves(sector_size, offset, len)?;
+
+    let leaves = (first_leaf..=last_leaf)
+        .map(|i| Ok(commitment_from_fr(tree.read_at(i)?.into())))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The tree is stored row by row, starting with the leaves.
+    let mut path = Vec::new();
+    let mut row_start = 0;
+    let mut row_len = tree.leafs();
+    let (mut lo, mut hi) = (first_leaf, last_leaf);
+    while row_len > 1 {
+        if lo % 2 == 1 {
+            path.push(commitment_from_fr(tree.read_at(row_start + lo - 1)?.into()));
+        }
+        if hi % 2 == 0 {
+            path.push(commitment_from_fr(tree.read_at(row_start + hi + 1)?.into()));
+        }
+
+        row_start += row_len;
+        row_len /= 2;
+        lo /= 2;
+        hi /= 2;
+    }
+
+    Ok(DataRangeProof {
+        first_leaf: first_leaf as u64,
+        leaves,
+        path,
+    })
+}
+
+/// Opens the data tree stored in `cache_path`.
+fn open_tree_d(
+    sector_size: SectorSize,
+    cache_path: &Path,
+) -> Result<BinaryMerkleTree<DefaultPieceHasher>> {
+    let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(sector_size)?;
+    let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
+    let config = StoreConfig::new(
+        cache_path,
+        CacheKey::CommDTree.to_string(),
+        default_rows_to_discard(base_tree_leafs, BINARY_ARITY),
+    );
+
+    let store: DiskStore<DefaultPieceDomain> =
+        DiskStore::new_from_disk(base_tree_size, BINARY_ARITY, &config)
+            .with_context(|| format!("could not open tree-d in {:?}", cache_path))?;
+
+    BinaryMerkleTree::<DefaultPieceHasher>::from_data_store(store, base_tree_leafs)
+}
+
+/// Builds the data tree of the padded, unsealed sector data at `unsealed_path`.
+fn build_tree_d(
+    sector_size: SectorSize,
+    unsealed_path: &Path,
+) -> Result<BinaryMerkleTree<DefaultPieceHasher>> {
+    let f_data = File::open(unsealed_path)
+        .with_context(|| format!("could not open unsealed_path={:?}", unsealed_path.display()))?;
+    let data = unsafe {
+        MmapOptions::new().map(&f_data).with_context(|| {
+            format!("could not mmap unsealed_path={:?}", unsealed_path.display())
+        })?
+    };
+    ensure!(
+        data.len() as u64 == u64::from(sector_size),
+        "unsealed data must be padded to the sector size"
+    );
+
+    let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(sector_size)?;
+    let base_tree_leafs = get_base_tree_leafs::<DefaultBinaryTree>(base_tree_size)?;
+
+    create_base_merkle_tree::<BinaryMerkleTree<DefaultPieceHasher>>(None, base_tree_leafs, &data)
+}
+
+/// Returns the height of the piece's subtree above the leaves, and its index within that row.
+fn piece_position(
+    sector_size: SectorSize,
+    piece_info: &PieceInfo,
+    piece_offset: UnpaddedByteIndex,
+) -> Result<(usize, usize)> {
+    let piece_size = u64::from(PaddedBytesAmount::from(piece_info.size));
+    let offset = u64::from(PaddedBytesAmount::from(UnpaddedBytesAmount::from(
+        piece_offset,
+    )));
+
+    ensure!(
+        piece_size.is_power_of_two() && piece_size >= NODE_SIZE as u64,
+        "piece size must be a power of two"
+    );
+    ensure!(
+        offset % piece_size == 0,
+        "piece offset must be aligned to the piece size"
+    );
+    ensure!(
+        offset + piece_size <= u64::from(sector_size),
+        "piece must fit into the sector"
+    );
+
+    let height = (piece_size / NODE_SIZE as u64).trailing_zeros() as usize;
+    let index = (offset / piece_size) as usize;
+
+    Ok((height, index))
+}
+
+fn piece_inclusion_proof(
+    tree: &BinaryMerkleTree<DefaultPieceHasher>,
+    sector_size: SectorSize,
+    piece_info: &PieceInfo,
+    piece_offset: UnpaddedByteIndex,
+) -> Result<PieceInclusionProof> {
+    let (height, mut index) = piece_position(sector_size, piece_info, piece_offset)?;
+
+    // The tree is stored row by row, starting with the leaves.
+    let mut row_start = 0;
+    let mut row_len = tree.leafs();
+    for _ in 0..height {
+        row_start += row_len;
+        row_len /= 2;
+    }
+
+    let piece_root = tree.read_at(row_start + index)?;
+    ensure!(
+        commitment_from_fr(piece_root.into()) == piece_info.commitment,
+        "piece commitment does not match the data at the given offset"
+    );
+
+    let mut path = Vec::new();
+    while row_len > 1 {
+        let sibling = tree.read_at(row_start + (index ^ 1))?;
+        path.push(commitment_from_fr(sibling.into()));
+
+        row_start += row_len;
+        row_len /= 2;
+        index /= 2;
+    }
+
+    Ok(PieceInclusionProof { path })
+}
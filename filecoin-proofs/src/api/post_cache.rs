@@ -0,0 +1,139 @@
+use std::fs::File;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use memmap::{Mmap, MmapOptions};
+use merkletree::store::StoreConfig;
+use storage_proofs_core::{merkle::MerkleTreeTrait, sector::SectorId};
+
+use crate::types::{PoStConfig, PrivateReplicaInfo};
+
+/// The tree_r_last files of the sectors warmed by [`warm_post_cache`], which are kept in memory
+/// for as long as this is.
+#[derive(Debug)]
+pub struct WarmPostCache {
+    maps: Vec<Mmap>,
+    locked: bool,
+}
+
+impl WarmPostCache {
+    /// The number of bytes of the warmed files.
+    pub fn warmed_bytes(&self) -> usize {
+        self.maps.iter().map(|map| map.len()).sum()
+    }
+
+    /// Whether the files are locked in memory, rather than only read ahead into the page cache,
+    /// from which they may be evicted again.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+}
+
+/// Reads the cached tree_r_last files of `replicas`, e.g. the sectors that may be challenged by
+/// the next Winning PoSt, into memory, so that the reads of `generate_winning_post` don't wait
+/// for the disks once the randomness is known. The p_aux and t_aux files are read when the
+/// replicas are created, so they should be kept until the PoSt as well. The replicas are not
+/// warmed: the PoSt reads a single leaf from them per challenge, which isn't known before the
+/// randomness, and reading ahead whole replicas would evict more than it saves.
+///
+/// The files are locked in memory until the returned [`WarmPostCache`] is dropped. If one of them
+/// can't be locked, e.g. because of `RLIMIT_MEMLOCK`, the failure is logged, the files locked so
+/// far are unlocked again and all of them are only read ahead into the page cache.
+pub fn warm_post_cache<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    replicas: &[(SectorId, PrivateReplicaInfo<Tree>)],
+) -> Result<WarmPostCache> {
+    info!("warm_post_cache:start");
+
+    let mut maps = Vec::new();
+    for (sector_id, replica) in replicas {
+        let (configs, _) = replica.tree_r_last_configs(post_config.sector_size)?;
+        for config in &configs {
+            let path = StoreConfig::data_path(&config.path, &config.id);
+            let file = File::open(&path)
+                .with_context(|| format!("could not open tree_r_last of {:?}", sector_id))?;
+            let map = unsafe { MmapOptions::new().map(&file) }
+                .with_context(|| format!("could not mmap path={:?}", path))?;
+            advise_will_need(&map);
+            maps.push(map);
+        }
+    }
+
+    let mut locked = true;
+    for (index, map) in maps.iter().enumerate() {
+        if let Err(err) = lock(map) {
+            warn!(
+                "failed to lock tree_r_last, only reading the files ahead: {:?}",
+                err
+            );
+            // Don't hold on to some of the files locked, whose memory counts against the limit.
+            for map in &maps[..index] {
+                unlock(map);
+            }
+            locked = false;
+            break;
+        }
+    }
+
+    let cache = WarmPostCache { maps, locked };
+    info!(
+        "warm_post_cache:finish: {} bytes, locked: {}",
+        cache.warmed_bytes(),
+        cache.locked
+    );
+
+    Ok(cache)
+}
+
+#[cfg(unix)]
+fn advise_will_need(data: &[u8]) {
+    let result = unsafe {
+        libc::madvise(
+            data.as_ptr() as *mut libc::c_void,
+            data.len(),
+            libc::MADV_WILLNEED,
+        )
+    };
+    if result != 0 {
+        warn!(
+            "madvise(MADV_WILLNEED) failed: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn advise_will_need(_data: &[u8]) {}
+
+// Locks the pages of `data` in memory, which also faults them in. Unmapping them unlocks them.
+#[cfg(unix)]
+fn lock(data: &[u8]) -> std::io::Result<()> {
+    if unsafe { libc::mlock(data.as_ptr() as *const libc::c_void, data.len()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn unlock(data: &[u8]) {
+    if unsafe { libc::munlock(data.as_ptr() as *const libc::c_void, data.len()) } != 0 {
+        warn!("munlock failed: {}", std::io::Error::last_os_error());
+    }
+}
+
+// Faults in the pages of `data`, which can't be locked in memory here.
+#[cfg(not(unix))]
+fn lock(data: &[u8]) -> std::io::Result<()> {
+    for byte in data.iter().step_by(4096) {
+        unsafe { std::ptr::read_volatile(byte) };
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "memory locking is not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+fn unlock(_data: &[u8]) {}
@@ -1,18 +1,28 @@
-use std::collections::BTreeMap;
-use std::fs;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::Path;
 
 use anyhow::{anyhow, ensure, Context, Result};
 use bincode::deserialize;
-use filecoin_hashers::{sha256::Sha256Hasher, Hasher};
-use log::{debug, info};
+use filecoin_hashers::{sha256::Sha256Hasher, Domain, Hasher};
+use log::{debug, info, warn};
+use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_leafs};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use storage_proofs_core::{
-    cache_key::CacheKey, merkle::MerkleTreeTrait, proof::ProofScheme, sector::SectorId,
+    cache_key::CacheKey,
+    merkle::MerkleTreeTrait,
+    proof::ProofScheme,
+    sector::SectorId,
+    tenant::{current_tenant, with_current_tenant},
+    util::{default_rows_to_discard, NODE_SIZE},
 };
 use storage_proofs_post::fallback::{self, generate_leaf_challenge, FallbackPoSt, SectorProof};
+use typenum::Unsigned;
 
 use crate::{
-    api::as_safe_commitment,
+    api::{as_safe_commitment, get_base_tree_leafs, get_base_tree_size},
     constants::DefaultPieceHasher,
     types::{
         ChallengeSeed, FallbackPoStSectorProof, PoStConfig, PrivateReplicaInfo, ProverId,
@@ -34,13 +44,46 @@ pub fn clear_cache<Tree: MerkleTreeTrait>(cache_dir: &Path) -> Result<()> {
     }?;
 
     t_aux.set_cache_path(cache_dir);
-    let result = TemporaryAux::<Tree, DefaultPieceHasher>::clear_temp(t_aux);
+    let result = TemporaryAux::<Tree, DefaultPieceHasher>::clear_temp(t_aux)
+        .and_then(|_| clear_synthetic_proofs(cache_dir));
 
     info!("clear_cache:finish");
 
     result
 }
 
+/// Discards the layers of a sector, keeping the trees. For a synthetic PoRep, the layers are no
+/// longer needed once [`generate_synth_proofs`](crate::generate_synth_proofs) stored its proofs.
+pub fn clear_layer_data<Tree: MerkleTreeTrait>(cache_dir: &Path) -> Result<()> {
+    info!("clear_layer_data:start");
+
+    let mut t_aux: TemporaryAux<Tree, Sha256Hasher> = {
+        let f_aux_path = cache_dir.to_path_buf().join(CacheKey::TAux.to_string());
+        let aux_bytes = fs::read(&f_aux_path)
+            .with_context(|| format!("could not read from path={:?}", f_aux_path))?;
+
+        deserialize(&aux_bytes)
+    }?;
+
+    t_aux.set_cache_path(cache_dir);
+    let result = TemporaryAux::<Tree, DefaultPieceHasher>::clear_labels(&t_aux);
+
+    info!("clear_layer_data:finish");
+
+    result
+}
+
+/// Discards the synthetic proofs of a sector, which are no longer needed once it is committed.
+pub fn clear_synthetic_proofs(cache_dir: &Path) -> Result<()> {
+    let synth_path = cache_dir.join(CacheKey::SynthProofs.to_string());
+    if synth_path.exists() {
+        fs::remove_file(&synth_path)
+            .with_context(|| format!("could not remove path={:?}", synth_path))?;
+    }
+
+    Ok(())
+}
+
 // Ensure that any associated cached data persisted is discarded.
 pub fn clear_caches<Tree: MerkleTreeTrait>(
     replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
@@ -96,24 +139,13 @@ pub fn generate_fallback_sector_challenges<Tree: 'static + MerkleTreeTrait>(
             .nth(partition_index)
             .ok_or_else(|| anyhow!("invalid number of sectors/partition index"))?;
 
-        for (i, sector) in sectors.iter().enumerate() {
-            let mut challenges = Vec::new();
-
-            for n in 0..post_config.challenge_count {
-                let challenge_index = ((partition_index * post_config.sector_count + i)
-                    * post_config.challenge_count
-                    + n) as u64;
-                let challenged_leaf = generate_leaf_challenge(
-                    &public_params,
-                    randomness_safe,
-                    u64::from(*sector),
-                    challenge_index,
-                );
-                challenges.push(challenged_leaf);
-            }
-
-            sector_challenges.insert(*sector, challenges);
-        }
+        sector_challenges.extend(partition_sector_challenges(
+            post_config,
+            &public_params,
+            randomness_safe,
+            sectors,
+            partition_index,
+        ));
     }
 
     info!("generate_sector_challenges:finish");
@@ -121,6 +153,217 @@ pub fn generate_fallback_sector_challenges<Tree: 'static + MerkleTreeTrait>(
     Ok(sector_challenges)
 }
 
+fn partition_sector_challenges<D: Domain>(
+    post_config: &PoStConfig,
+    public_params: &fallback::PublicParams,
+    randomness: D,
+    sectors: &[SectorId],
+    partition_index: usize,
+) -> BTreeMap<SectorId, Vec<u64>> {
+    sectors
+        .iter()
+        .enumerate()
+        .map(|(i, sector)| {
+            let challenges = (0..post_config.challenge_count)
+                .map(|n| {
+                    let challenge_index = ((partition_index * post_config.sector_count + i)
+                        * post_config.challenge_count
+                        + n) as u64;
+                    generate_leaf_challenge(
+                        public_params,
+                        randomness,
+                        u64::from(*sector),
+                        challenge_index,
+                    )
+                })
+                .collect();
+
+            (*sector, challenges)
+        })
+        .collect()
+}
+
+/// Generates the challenges per SectorId of the partition `partition_index` of a Window
+/// proof-of-spacetime, given only the sectors of the partition, in the order of the sectors of
+/// the deadline. They are the challenges `generate_fallback_sector_challenges` generates for these
+/// sectors given all sectors of the deadline.
+pub fn generate_partition_sector_challenges<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    partition_sectors: &[SectorId],
+    partition_index: usize,
+) -> Result<BTreeMap<SectorId, Vec<u64>>> {
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+    ensure!(
+        !partition_sectors.is_empty() && partition_sectors.len() <= post_config.sector_count,
+        "a partition has 1 to {} sectors, got {}",
+        post_config.sector_count,
+        partition_sectors.len()
+    );
+
+    let randomness_safe: <Tree::Hasher as Hasher>::Domain =
+        as_safe_commitment(randomness, "randomness")?;
+    let public_params = fallback::PublicParams {
+        sector_size: u64::from(post_config.sector_size),
+        challenge_count: post_config.challenge_count,
+        sector_count: post_config.sector_count,
+        api_version: post_config.api_version,
+    };
+
+    Ok(partition_sector_challenges(
+        post_config,
+        &public_params,
+        randomness_safe,
+        partition_sectors,
+        partition_index,
+    ))
+}
+
+/// The challenges of a sector in a proof-of-spacetime.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectorChallenges {
+    /// The partition of the PoSt proving the sector.
+    pub partition: usize,
+    /// The challenged leaves of the replica, in the order they are proven.
+    pub leaves: Vec<u64>,
+}
+
+impl SectorChallenges {
+    /// The byte ranges of the replica file read when proving the challenges of `replica`, e.g. to
+    /// prefetch them, in ascending order. Proving a leaf reads the whole segment of the replica
+    /// below the lowest cached row of tree_r_last, to rebuild the rows above it.
+    pub fn replica_ranges<Tree: 'static + MerkleTreeTrait>(
+        &self,
+        post_config: &PoStConfig,
+        replica: &PrivateReplicaInfo<Tree>,
+    ) -> Result<Vec<Range<u64>>> {
+        let segment_len = replica_segment_leafs(post_config, replica)? * NODE_SIZE as u64;
+        let mut starts: Vec<u64> = self
+            .leaves
+            .iter()
+            .map(|leaf| leaf * NODE_SIZE as u64 / segment_len * segment_len)
+            .collect();
+        starts.sort_unstable();
+        starts.dedup();
+
+        Ok(starts
+            .into_iter()
+            .map(|start| start..start + segment_len)
+            .collect())
+    }
+}
+
+/// The number of leaves of the segments of the replica below the lowest cached row of
+/// tree_r_last of `replica`.
+pub(crate) fn replica_segment_leafs<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    replica: &PrivateReplicaInfo<Tree>,
+) -> Result<u64> {
+    let base_tree_leafs =
+        get_base_tree_leafs::<Tree>(get_base_tree_size::<Tree>(post_config.sector_size)?)?;
+    let rows_to_discard = replica
+        .rows_to_discard()
+        .unwrap_or_else(|| default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()));
+    let cache_size =
+        get_merkle_tree_cache_size(base_tree_leafs, Tree::Arity::to_usize(), rows_to_discard)?;
+
+    Ok((base_tree_leafs / get_merkle_tree_leafs(cache_size, Tree::Arity::to_usize())?) as u64)
+}
+
+/// Generates the challenges of every sector of the Window or Winning proof-of-spacetime of
+/// `sectors`, before proving it, e.g. to prefetch the challenged nodes of the replicas. They are
+/// the challenges proven by `generate_window_post` and `generate_winning_post` for the same
+/// sectors, which those order by their id as a `BTreeSet` does.
+pub fn generate_post_challenges<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    prover_id: ProverId,
+    sectors: &BTreeSet<SectorId>,
+) -> Result<BTreeMap<SectorId, SectorChallenges>> {
+    if post_config.typ == PoStType::Winning {
+        ensure!(
+            sectors.len() == post_config.sector_count,
+            "invalid amount of sectors"
+        );
+    }
+
+    let sectors: Vec<SectorId> = sectors.iter().copied().collect();
+    let mut leaves =
+        generate_fallback_sector_challenges::<Tree>(post_config, randomness, &sectors, prover_id)?;
+
+    sectors
+        .iter()
+        .enumerate()
+        .map(|(i, sector_id)| {
+            let challenges = SectorChallenges {
+                partition: i / post_config.sector_count,
+                leaves: leaves
+                    .remove(sector_id)
+                    .ok_or_else(|| anyhow!("no challenges for {:?}", sector_id))?,
+            };
+
+            Ok((*sector_id, challenges))
+        })
+        .collect()
+}
+
+/// Reads the challenged segments of the replicas and opens their tree_r_last, before proving the
+/// proof-of-spacetime whose `challenges` were generated by `generate_post_challenges`. Returns the
+/// sectors which can't be proven, e.g. because their replica is missing or too short, or they
+/// aren't in `replicas` at all. Their errors are logged.
+pub fn check_post_challenges<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
+    challenges: &BTreeMap<SectorId, SectorChallenges>,
+) -> BTreeSet<SectorId> {
+    let tenant = current_tenant();
+    challenges
+        .par_iter()
+        .filter_map(|(sector_id, challenges)| {
+            with_current_tenant(tenant.clone(), || {
+                let checked = replicas
+                    .get(sector_id)
+                    .ok_or_else(|| anyhow!("no replica"))
+                    .and_then(|replica| read_challenged_nodes(post_config, replica, challenges));
+                match checked {
+                    Ok(()) => None,
+                    Err(err) => {
+                        warn!("{:?} can't be proven: {:?}", sector_id, err);
+                        Some(*sector_id)
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+fn read_challenged_nodes<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    replica: &PrivateReplicaInfo<Tree>,
+    challenges: &SectorChallenges,
+) -> Result<()> {
+    replica.merkle_tree(post_config.sector_size)?;
+
+    let path = replica.replica_path();
+    let mut file = File::open(path).with_context(|| format!("could not open path={:?}", path))?;
+    let mut segment = Vec::new();
+    for range in challenges.replica_ranges(post_config, replica)? {
+        segment.resize((range.end - range.start) as usize, 0);
+        file.seek(SeekFrom::Start(range.start))?;
+        file.read_exact(&mut segment).with_context(|| {
+            format!(
+                "could not read the segment at {} of {:?}",
+                range.start, path
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
 /// Generates a single vanilla proof required for either Window proof-of-spacetime
 /// or Winning proof-of-spacetime.
 pub fn generate_single_vanilla_proof<Tree: 'static + MerkleTreeTrait>(
@@ -152,6 +395,7 @@ pub fn generate_single_vanilla_proof<Tree: 'static + MerkleTreeTrait>(
         tree,
         comm_c,
         comm_r_last,
+        rows_to_discard: replica.rows_to_discard(),
     }];
 
     let priv_inputs = fallback::PrivateInputs::<Tree> {
@@ -294,7 +538,7 @@ pub fn single_partition_vanilla_proofs<Tree: MerkleTreeTrait>(
                 let cur_proof = vanilla_proofs
                     .iter()
                     .find(|&proof| proof.sector_id == pub_sector.id)
-                    .expect("failed to locate sector proof");
+                    .ok_or_else(|| anyhow!("missing the vanilla proof of {:?}", pub_sector.id))?;
 
                 // Note: Window post requires all inclusion proofs (based on the challenge
                 // count per sector) per sector proof.
@@ -0,0 +1,176 @@
+//! Proving the commit of a sector on another machine.
+//!
+//! With the `remote-c2` feature and `remote_c2_url` set, `seal_commit_phase2` sends a
+//! `remote-c2-request` map (see [`crate::cbor`]) to a proving service over HTTP rather than
+//! generating the proof here, e.g. on sealing machines without GPUs. The `seal-proof` map it gets
+//! back is verified like a local proof. A proving service built on this crate answers the requests
+//! with [`prove_remote_c2_request`], which registers the custom PoRep parameters of the sector, if
+//! any, and fails if it would prove with other parameters than the ones listed in the request.
+
+use anyhow::{ensure, Result};
+use serde::de::DeserializeOwned;
+use storage_proofs_core::sector::SectorId;
+
+use crate::{
+    api::seal::seal_commit_phase2_here,
+    cbor::{
+        remote_c2_request_from_cbor, seal_commit_output_to_cbor,
+        seal_commit_phase1_output_from_cbor, seal_commit_phase1_sector_size, RemoteC2Request,
+    },
+    constants::LAYERS,
+    types::{MerkleTreeTrait, PoRepConfig, ProverId, SealCommitOutput, SealCommitPhase1Output},
+};
+
+/// The content type of the requests and responses of the proving service.
+#[cfg(feature = "remote-c2")]
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Proves the commit of a `remote-c2-request` map here, and returns the proof as a `seal-proof`
+/// map.
+pub fn prove_remote_c2_request(bytes: &[u8]) -> Result<Vec<u8>> {
+    let request = remote_c2_request_from_cbor(bytes)?;
+    let sector_size = u64::from(request.porep_config.sector_size);
+    ensure!(
+        LAYERS
+            .read()
+            .expect("LAYERS poisoned")
+            .contains_key(&sector_size),
+        "unknown sector size {}",
+        sector_size
+    );
+    ensure!(
+        seal_commit_phase1_sector_size(&request.phase1_output)? == sector_size,
+        "the C1 output is not of a sector of {} bytes",
+        sector_size
+    );
+
+    let output = crate::with_shape!(sector_size, prove_request, &request,)?;
+
+    seal_commit_output_to_cbor(&output)
+}
+
+fn prove_request<Tree: 'static + MerkleTreeTrait>(
+    request: &RemoteC2Request,
+) -> Result<SealCommitOutput>
+where
+    SealCommitPhase1Output<Tree>: DeserializeOwned,
+{
+    let phase1_output = seal_commit_phase1_output_from_cbor::<Tree>(&request.phase1_output)?;
+    let porep_config = request.porep_config;
+    porep_config.adopt_porep_params(
+        phase1_output.porep_params,
+        phase1_output.vanilla_proofs.len(),
+    )?;
+    ensure!(
+        porep_config.resolved_porep_params() == request.porep_params,
+        "the sector was sealed with the PoRep parameters {:?}, this service resolves {:?}",
+        request.porep_params,
+        porep_config.resolved_porep_params()
+    );
+
+    seal_commit_phase2_here(
+        porep_config,
+        phase1_output,
+        request.prover_id,
+        request.sector_id,
+    )
+}
+
+/// The proofs of `phase1_output` by the remote proving service, if one is configured, or `None`
+/// if they are to be generated here.
+#[cfg(feature = "remote-c2")]
+pub(crate) fn seal_commit_phase2_remote<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: &SealCommitPhase1Output<Tree>,
+    prover_id: ProverId,
+    sector_id: SectorId,
+) -> Result<Option<Vec<u8>>> {
+    use std::time::Duration;
+
+    use anyhow::Context;
+    use log::info;
+    use storage_proofs_core::{
+        control,
+        events::{self, Event},
+        tenant,
+    };
+
+    use crate::{
+        cbor::{
+            remote_c2_request_to_cbor, seal_commit_output_from_cbor,
+            seal_commit_phase1_output_to_cbor,
+        },
+        constants::SINGLE_PARTITION_PROOF_LEN,
+        types::PoRepProofPartitions,
+    };
+
+    let settings = tenant::settings();
+    let url = &settings.remote_c2_url;
+    if url.is_empty() {
+        return Ok(None);
+    }
+
+    let request = remote_c2_request_to_cbor(&RemoteC2Request {
+        porep_config,
+        porep_params: porep_config.resolved_porep_params(),
+        prover_id,
+        sector_id,
+        phase1_output: seal_commit_phase1_output_to_cbor(
+            u64::from(porep_config.sector_size),
+            phase1_output,
+        )?,
+    })?;
+    let timeout = match settings.remote_c2_timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+
+    info!("requesting the proofs of {:?} from {}", sector_id, url);
+    let response = reqwest::blocking::Client::builder()
+        .timeout(timeout)
+        .build()
+        .and_then(|client| {
+            client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, CBOR_CONTENT_TYPE)
+                .body(request)
+                .send()
+        })
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .with_context(|| format!("remote C2 request to {} failed", url))?;
+    let output = seal_commit_output_from_cbor(&response)?;
+
+    let partitions = usize::from(PoRepProofPartitions::from(porep_config));
+    ensure!(
+        output.proof.len() == SINGLE_PARTITION_PROOF_LEN * partitions,
+        "the remote C2 proof has {} bytes, expected {} partitions",
+        output.proof.len(),
+        partitions
+    );
+    events::emit(Event::DeviceChoice {
+        job: control::current_job(),
+        operation: "c2".to_string(),
+        device: "remote".to_string(),
+    });
+
+    Ok(Some(output.proof))
+}
+
+/// Fails if a remote proving service is configured, as this build can't reach it.
+#[cfg(not(feature = "remote-c2"))]
+pub(crate) fn seal_commit_phase2_remote<Tree: 'static + MerkleTreeTrait>(
+    _porep_config: PoRepConfig,
+    _phase1_output: &SealCommitPhase1Output<Tree>,
+    _prover_id: ProverId,
+    _sector_id: SectorId,
+) -> Result<Option<Vec<u8>>> {
+    ensure!(
+        storage_proofs_core::tenant::settings()
+            .remote_c2_url
+            .is_empty(),
+        "remote_c2_url is set, but remote C2 requires the remote-c2 feature"
+    );
+
+    Ok(None)
+}
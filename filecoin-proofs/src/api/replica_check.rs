@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use anyhow::Result;
+use filecoin_hashers::{HashFunction, Hasher};
+use log::{info, warn};
+use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_leafs};
+use rand::{rngs::OsRng, Rng};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use storage_proofs_core::{
+    merkle::{MerkleProofTrait, MerkleTreeTrait},
+    util::default_rows_to_discard,
+};
+use typenum::Unsigned;
+
+use crate::{
+    api::{get_base_tree_leafs, get_base_tree_size},
+    types::{Commitment, PrivateReplicaInfo, SectorSize},
+};
+
+/// How much of a replica `check_replica` reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicaCheck {
+    /// Checks this many leaves of tree_r_last, chosen at random. Each of them also checks the
+    /// leaves around it whose rows of the tree are not cached.
+    Sample(usize),
+    /// Checks every leaf of tree_r_last, recomputing the whole tree against its root.
+    Full,
+}
+
+/// Checks that the replica at `replica_path` still matches `comm_r`, without generating a PoSt,
+/// e.g. to find bit rot between PoSts. The leaves of tree_r_last chosen by `check` are read from
+/// the replica and proven against the cached rows of the tree in `cache_path`, whose root must
+/// be the one committed to by `comm_r`.
+///
+/// Returns false if the replica or its cache don't match `comm_r`, and an error if they can't be
+/// read at all.
+pub fn check_replica<Tree: 'static + MerkleTreeTrait>(
+    sector_size: SectorSize,
+    replica_path: &Path,
+    cache_path: &Path,
+    comm_r: Commitment,
+    check: ReplicaCheck,
+) -> Result<bool> {
+    info!("check_replica:start: {:?}", replica_path);
+
+    let replica = PrivateReplicaInfo::<Tree>::new(
+        replica_path.to_path_buf(),
+        comm_r,
+        cache_path.to_path_buf(),
+    )?;
+    let comm_r_last = replica.safe_comm_r_last();
+    if <Tree::Hasher as Hasher>::Function::hash2(&replica.safe_comm_c(), &comm_r_last)
+        != replica.safe_comm_r()?
+    {
+        warn!("the p_aux of {:?} doesn't match comm_r", cache_path);
+        return Ok(false);
+    }
+
+    let tree = replica.merkle_tree(sector_size)?;
+    if tree.root() != comm_r_last {
+        warn!("the tree_r_last in {:?} doesn't match comm_r", cache_path);
+        return Ok(false);
+    }
+
+    let base_tree_leafs = get_base_tree_leafs::<Tree>(get_base_tree_size::<Tree>(sector_size)?)?;
+    let rows_to_discard = replica
+        .rows_to_discard()
+        .unwrap_or_else(|| default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()));
+
+    let leafs = tree.leafs();
+    let challenges: Vec<usize> = match check {
+        ReplicaCheck::Sample(count) => {
+            let mut rng = OsRng;
+            (0..count).map(|_| rng.gen_range(0..leafs)).collect()
+        }
+        ReplicaCheck::Full => {
+            // A proof of a leaf rebuilds the uncached rows of the tree above all the leaves of its
+            // segment, so that one leaf per segment checks all of them.
+            let cache_size = get_merkle_tree_cache_size(
+                base_tree_leafs,
+                Tree::Arity::to_usize(),
+                rows_to_discard,
+            )?;
+            let cached_leafs = get_merkle_tree_leafs(cache_size, Tree::Arity::to_usize())?;
+            (0..leafs).step_by(base_tree_leafs / cached_leafs).collect()
+        }
+    };
+
+    let leaf_matches =
+        |challenge: usize| match tree.gen_cached_proof(challenge, Some(rows_to_discard)) {
+            Ok(proof) => proof.validate(challenge) && proof.root() == comm_r_last,
+            Err(err) => {
+                warn!("failed to prove leaf {}: {:?}", challenge, err);
+                false
+            }
+        };
+    let failed = challenges
+        .into_par_iter()
+        .filter(|&challenge| !leaf_matches(challenge))
+        .count();
+    if failed != 0 {
+        warn!(
+            "{} of the checked leaves of {:?} don't match comm_r",
+            failed, replica_path
+        );
+    }
+
+    info!("check_replica:finish: {:?}", replica_path);
+    Ok(failed == 0)
+}
@@ -1,6 +1,8 @@
 use std::fs::{self, metadata, File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use anyhow::{ensure, Context, Result};
 use bellperson::groth16;
@@ -13,42 +15,56 @@ use merkletree::store::{DiskStore, Store, StoreConfig};
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
 use storage_proofs_core::{
+    api_version::ApiFeature,
     cache_key::CacheKey,
     compound_proof::{self, CompoundProof},
+    control,
     drgraph::Graph,
+    error::Error as StorageProofsError,
     measurements::{measure_op, Operation},
     merkle::{create_base_merkle_tree, BinaryMerkleTree, MerkleTreeTrait},
     multi_proof::MultiProof,
     parameter_cache::SRS_MAX_PROOFS_TO_AGGREGATE,
+    priority::{enter_priority_class, PriorityClass},
     proof::ProofScheme,
     sector::SectorId,
+    tenant,
     util::default_rows_to_discard,
     Data,
 };
 use storage_proofs_porep::stacked::{
-    self, generate_replica_id, ChallengeRequirements, StackedCompound, StackedDrg, Tau,
+    self, generate_replica_id, unwrap_replica_sink, ChallengeRequirements, LabelingOptions,
+    LabelingProgress, LabelingStrategy, Phase2Options, StackedCompound, StackedDrg, Tau,
     TemporaryAux, TemporaryAuxCache,
 };
 
 use crate::{
-    api::{as_safe_commitment, commitment_from_fr, get_base_tree_leafs, get_base_tree_size},
+    api::{
+        as_safe_commitment, commitment_from_fr, get_base_tree_leafs, get_base_tree_size, remote_c2,
+        tree_r_last_rows_to_discard,
+    },
     caches::{
         get_stacked_params, get_stacked_srs_key, get_stacked_srs_verifier_key,
         get_stacked_verifying_key,
     },
     constants::{
-        DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher, POREP_MINIMUM_CHALLENGES,
-        SINGLE_PARTITION_PROOF_LEN,
+        DefaultBinaryTree, DefaultPieceDomain, DefaultPieceHasher, SINGLE_PARTITION_PROOF_LEN,
     },
+    data_source::{DataSource, FileDataSource},
     parameters::setup_params,
     pieces::{self, verify_pieces},
     types::{
         AggregateSnarkProof, Commitment, PaddedBytesAmount, PieceInfo, PoRepConfig,
         PoRepProofPartitions, ProverId, SealCommitOutput, SealCommitPhase1Output,
-        SealPreCommitOutput, SealPreCommitPhase1Output, SectorSize, Ticket, BINARY_ARITY,
+        SealCommitPhase2Input, SealPreCommitOutput, SealPreCommitPhase1Output,
+        SealPreCommitPhase1State, SealPreCommitPhase2State, SealProgress, SealProofToVerify,
+        SectorSize, Ticket, BINARY_ARITY,
     },
 };
 
+/// The seed recorded by a non-interactive PoRep, whose challenges don't depend on one.
+const NI_SEED: Ticket = [0; 32];
+
 #[allow(clippy::too_many_arguments)]
 pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
@@ -64,44 +80,384 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
         R: AsRef<Path>,
         S: AsRef<Path>,
         T: AsRef<Path>,
+{
+    seal_pre_commit_phase1_inner::<_, _, _, Tree>(
+        porep_config,
+        cache_path,
+        in_path,
+        out_path,
+        prover_id,
+        sector_id,
+        ticket,
+        piece_infos,
+        &LabelingOptions::default(),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn seal_pre_commit_phase1_inner<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    options: &LabelingOptions,
+) -> Result<SealPreCommitPhase1Output<Tree>>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    fs::metadata(&in_path)
+        .with_context(|| format!("could not read in_path={:?})", in_path.as_ref().display()))?;
+
+    seal_pre_commit_phase1_from_source_inner::<_, _, Tree>(
+        porep_config,
+        cache_path,
+        &mut FileDataSource::new(in_path),
+        out_path,
+        prover_id,
+        sector_id,
+        ticket,
+        piece_infos,
+        options,
+    )
+}
+
+/// Like [`seal_pre_commit_phase1`], but calls `progress` with the layer being labeled and the
+/// nodes labeled of it, when a layer starts, about every million nodes and when it is complete.
+/// Layers kept from an earlier run are reported as complete.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_with_progress<R, S, T, P, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    progress: P,
+) -> Result<SealPreCommitPhase1Output<Tree>>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+    P: Fn(LabelingProgress) + Send + Sync + 'static,
+{
+    seal_pre_commit_phase1_inner::<_, _, _, Tree>(
+        porep_config,
+        cache_path,
+        in_path,
+        out_path,
+        prover_id,
+        sector_id,
+        ticket,
+        piece_infos,
+        &LabelingOptions {
+            progress: Some(Arc::new(progress)),
+            ..LabelingOptions::default()
+        },
+    )
+}
+
+/// Like [`seal_pre_commit_phase1`], but labels the sector with `strategy` instead of as configured
+/// by `FIL_PROOFS_USE_MULTICORE_SDR`, e.g. on a single core while all core groups are checked out.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_with_strategy<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    strategy: LabelingStrategy,
+) -> Result<SealPreCommitPhase1Output<Tree>>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    seal_pre_commit_phase1_inner::<_, _, _, Tree>(
+        porep_config,
+        cache_path,
+        in_path,
+        out_path,
+        prover_id,
+        sector_id,
+        ticket,
+        piece_infos,
+        &LabelingOptions {
+            strategy,
+            ..LabelingOptions::default()
+        },
+    )
+}
+
+/// Like [`seal_pre_commit_phase1`], but reads the staged data from any [`DataSource`] instead of
+/// a file. The data is written to `out_path`, where it will be sealed in place.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_pre_commit_phase1_from_source<R, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    source: &mut dyn DataSource,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+) -> Result<SealPreCommitPhase1Output<Tree>>
+where
+    R: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    seal_pre_commit_phase1_from_source_inner::<_, _, Tree>(
+        porep_config,
+        cache_path,
+        source,
+        out_path,
+        prover_id,
+        sector_id,
+        ticket,
+        piece_infos,
+        &LabelingOptions::default(),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn seal_pre_commit_phase1_from_source_inner<R, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    source: &mut dyn DataSource,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    options: &LabelingOptions,
+) -> Result<SealPreCommitPhase1Output<Tree>>
+where
+    R: AsRef<Path>,
+    T: AsRef<Path>,
 {
     info!("seal_pre_commit_phase1:start: {:?}", sector_id);
+    let _job = control::register_job(format!("seal_pre_commit_phase1 {:?}", sector_id));
+    let _priority = enter_priority_class(PriorityClass::SealPreCommitPhase1);
 
-    // Sanity check all input path types.
-    ensure!(
-        metadata(in_path.as_ref())?.is_file(),
-        "in_path must be a file"
+    let compound_public_params = pre_commit_phase1_public_params::<Tree>(porep_config)?;
+
+    // The labels of all layers and the data tree stay in the cache until the sector is committed.
+    let _scratch = tenant::reserve_scratch(pre_commit_phase1_scratch_bytes(
+        porep_config,
+        &compound_public_params,
+    ))?;
+
+    let (config, comm_d) = stage_sector_data::<Tree>(
+        porep_config,
+        &compound_public_params,
+        cache_path.as_ref(),
+        source,
+        out_path.as_ref(),
+        piece_infos,
+    )?;
+
+    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+        &prover_id,
+        sector_id.into(),
+        &ticket,
+        comm_d,
+        &porep_config.porep_id,
     );
-    ensure!(
-        metadata(out_path.as_ref())?.is_file(),
-        "out_path must be a file"
+
+    let labels = if tenant::settings().encode_replica_in_pc1 {
+        StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_and_encode(
+            &compound_public_params.vanilla_params,
+            &replica_id,
+            config.clone(),
+            out_path.as_ref(),
+            options,
+        )?
+    } else {
+        StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_with_options(
+            &compound_public_params.vanilla_params,
+            &replica_id,
+            config.clone(),
+            options,
+        )?
+    };
+
+    let out = SealPreCommitPhase1Output {
+        labels,
+        config,
+        comm_d,
+    };
+
+    info!("seal_pre_commit_phase1:finish: {:?}", sector_id);
+    Ok(out)
+}
+
+/// A sector sealed as part of a batch by [`seal_pre_commit_phase1_batch`].
+pub struct BatchSector<'a> {
+    pub cache_path: PathBuf,
+    pub source: &'a mut dyn DataSource,
+    pub out_path: PathBuf,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    pub ticket: Ticket,
+    pub piece_infos: Vec<PieceInfo>,
+}
+
+/// Like [`seal_pre_commit_phase1_from_source`] for several sectors at once, which are labeled in
+/// lockstep. All sectors share the parent cache reads, and the labeling of each sector runs on its
+/// own core, which makes much better use of hosts with many cores than sealing the sectors one
+/// after another. Each sector keeps two layers in memory while labeling.
+///
+/// The outputs are in the order of `sectors`. The replicas are encoded in phase2, independently
+/// of `FIL_PROOFS_ENCODE_REPLICA_IN_PC1`.
+pub fn seal_pre_commit_phase1_batch<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    sectors: &mut [BatchSector<'_>],
+) -> Result<Vec<SealPreCommitPhase1Output<Tree>>> {
+    info!(
+        "seal_pre_commit_phase1_batch:start: {} sectors",
+        sectors.len()
     );
+    ensure!(!sectors.is_empty(), "no sectors to seal");
+    let _job = control::register_job(format!(
+        "seal_pre_commit_phase1_batch {:?}",
+        sectors.iter().map(|s| s.sector_id).collect::<Vec<_>>()
+    ));
+    let _priority = enter_priority_class(PriorityClass::SealPreCommitPhase1);
+
+    let compound_public_params = pre_commit_phase1_public_params::<Tree>(porep_config)?;
+    let _scratch = tenant::reserve_scratch(
+        sectors.len() as u64
+            * pre_commit_phase1_scratch_bytes(porep_config, &compound_public_params),
+    )?;
+
+    let mut configs = Vec::with_capacity(sectors.len());
+    let mut comm_ds = Vec::with_capacity(sectors.len());
+    let mut replica_ids = Vec::with_capacity(sectors.len());
+    for sector in sectors.iter_mut() {
+        let (config, comm_d) = stage_sector_data::<Tree>(
+            porep_config,
+            &compound_public_params,
+            &sector.cache_path,
+            sector.source,
+            &sector.out_path,
+            &sector.piece_infos,
+        )?;
+        replica_ids.push(generate_replica_id::<Tree::Hasher, _>(
+            &sector.prover_id,
+            sector.sector_id.into(),
+            &sector.ticket,
+            comm_d,
+            &porep_config.porep_id,
+        ));
+        configs.push(config);
+        comm_ds.push(comm_d);
+    }
+
+    let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1_batch(
+        &compound_public_params.vanilla_params,
+        &replica_ids,
+        configs.clone(),
+        &LabelingOptions::default(),
+    )?;
+
+    let out = labels
+        .into_iter()
+        .zip(configs.into_iter().zip(comm_ds))
+        .map(|(labels, (config, comm_d))| SealPreCommitPhase1Output {
+            labels,
+            config,
+            comm_d,
+        })
+        .collect();
+
+    info!("seal_pre_commit_phase1_batch:finish");
+    Ok(out)
+}
+
+fn pre_commit_phase1_public_params<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+) -> Result<compound_proof::PublicParams<'static, StackedDrg<'static, Tree, DefaultPieceHasher>>> {
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: setup_params(
+            PaddedBytesAmount::from(porep_config),
+            usize::from(PoRepProofPartitions::from(porep_config)),
+            porep_config.porep_id,
+            porep_config.api_version,
+            porep_config.api_features,
+        )?,
+        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+        priority: false,
+    };
+
+    <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'static, Tree, DefaultPieceHasher>,
+        _,
+    >>::setup(&compound_setup_params)
+}
+
+/// Scratch space of a sector during phase1: the labels of all layers and the data tree.
+fn pre_commit_phase1_scratch_bytes<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    compound_public_params: &compound_proof::PublicParams<
+        'static,
+        StackedDrg<'static, Tree, DefaultPieceHasher>,
+    >,
+) -> u64 {
+    let layers = compound_public_params
+        .vanilla_params
+        .layer_challenges
+        .layers() as u64;
+
+    (layers + 2) * u64::from(PaddedBytesAmount::from(porep_config))
+}
+
+/// Writes the staged data of a sector to `out_path`, where it will be sealed in place, and builds
+/// its data tree. Returns the config of the data tree and CommD.
+fn stage_sector_data<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    compound_public_params: &compound_proof::PublicParams<
+        'static,
+        StackedDrg<'static, Tree, DefaultPieceHasher>,
+    >,
+    cache_path: &Path,
+    source: &mut dyn DataSource,
+    out_path: &Path,
+    piece_infos: &[PieceInfo],
+) -> Result<(StoreConfig, Commitment)> {
+    // Sanity check all input path types.
+    ensure!(metadata(out_path)?.is_file(), "out_path must be a file");
     ensure!(
-        metadata(cache_path.as_ref())?.is_dir(),
+        metadata(cache_path)?.is_dir(),
         "cache_path must be a directory"
     );
 
     let sector_bytes = usize::from(PaddedBytesAmount::from(porep_config));
-    fs::metadata(&in_path)
-        .with_context(|| format!("could not read in_path={:?})", in_path.as_ref().display()))?;
-
-    fs::metadata(&out_path)
-        .with_context(|| format!("could not read out_path={:?}", out_path.as_ref().display()))?;
+    fs::metadata(out_path)
+        .with_context(|| format!("could not read out_path={:?}", out_path.display()))?;
 
     // Copy unsealed data to output location, where it will be sealed in place.
-    fs::copy(&in_path, &out_path).with_context(|| {
-        format!(
-            "could not copy in_path={:?} to out_path={:?}",
-            in_path.as_ref().display(),
-            out_path.as_ref().display()
-        )
-    })?;
+    stacked::clear_replica_encoding(cache_path)?;
+    let written = source.write_to(out_path)?;
+    ensure!(
+        written <= sector_bytes as u64,
+        "staged data is larger than the sector"
+    );
 
     let f_data = OpenOptions::new()
         .read(true)
         .write(true)
-        .open(&out_path)
-        .with_context(|| format!("could not open out_path={:?}", out_path.as_ref().display()))?;
+        .open(out_path)
+        .with_context(|| format!("could not open out_path={:?}", out_path.display()))?;
 
     // Zero-pad the data to the requested size by extending the underlying file if needed.
     f_data.set_len(sector_bytes as u64)?;
@@ -109,25 +465,9 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
     let data = unsafe {
         MmapOptions::new()
             .map_mut(&f_data)
-            .with_context(|| format!("could not mmap out_path={:?}", out_path.as_ref().display()))?
-    };
-
-    let compound_setup_params = compound_proof::SetupParams {
-        vanilla_params: setup_params(
-            PaddedBytesAmount::from(porep_config),
-            usize::from(PoRepProofPartitions::from(porep_config)),
-            porep_config.porep_id,
-            porep_config.api_version,
-        )?,
-        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
-        priority: false,
+            .with_context(|| format!("could not mmap out_path={:?}", out_path.display()))?
     };
 
-    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
-        StackedDrg<'_, Tree, DefaultPieceHasher>,
-        _,
-    >>::setup(&compound_setup_params)?;
-
     trace!("building merkle tree for the original data");
     let (config, comm_d) = measure_op(Operation::CommD, || -> Result<_> {
         let base_tree_size = get_base_tree_size::<DefaultBinaryTree>(porep_config.sector_size)?;
@@ -145,7 +485,7 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
         );
 
         let mut config = StoreConfig::new(
-            cache_path.as_ref(),
+            cache_path,
             CacheKey::CommDTree.to_string(),
             default_rows_to_discard(base_tree_leafs, BINARY_ARITY),
         );
@@ -173,28 +513,7 @@ pub fn seal_pre_commit_phase1<R, S, T, Tree: 'static + MerkleTreeTrait>(
         "pieces and comm_d do not match"
     );
 
-    let replica_id = generate_replica_id::<Tree::Hasher, _>(
-        &prover_id,
-        sector_id.into(),
-        &ticket,
-        comm_d,
-        &porep_config.porep_id,
-    );
-
-    let labels = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase1(
-        &compound_public_params.vanilla_params,
-        &replica_id,
-        config.clone(),
-    )?;
-
-    let out = SealPreCommitPhase1Output {
-        labels,
-        config,
-        comm_d,
-    };
-
-    info!("seal_pre_commit_phase1:finish: {:?}", sector_id);
-    Ok(out)
+    Ok((config, comm_d))
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -207,8 +526,33 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
     where
         R: AsRef<Path>,
         S: AsRef<Path>,
+{
+    seal_pre_commit_phase2_inner(
+        porep_config,
+        phase1_output,
+        cache_path,
+        replica_path,
+        Phase2Options::default(),
+    )
+}
+
+fn seal_pre_commit_phase2_inner<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    mut options: Phase2Options,
+) -> Result<SealPreCommitOutput>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
 {
     info!("seal_pre_commit_phase2:start");
+    let _job = control::register_job(format!(
+        "seal_pre_commit_phase2 {:?}",
+        replica_path.as_ref()
+    ));
+    let _priority = enter_priority_class(PriorityClass::SealPreCommitPhase2);
 
     // Sanity check all input path types.
     ensure!(
@@ -220,6 +564,10 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
         "replica_path must be a file"
     );
 
+    // Upper bound for tree_c and tree_r_last.
+    let sector_bytes = u64::from(PaddedBytesAmount::from(porep_config));
+    let _scratch = tenant::reserve_scratch(2 * sector_bytes)?;
+
     let SealPreCommitPhase1Output {
         mut labels,
         mut config,
@@ -277,6 +625,7 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
             usize::from(PoRepProofPartitions::from(porep_config)),
             porep_config.porep_id,
             porep_config.api_version,
+            porep_config.api_features,
         )?,
         partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
         priority: false,
@@ -287,14 +636,19 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
         _,
     >>::setup(&compound_setup_params)?;
 
-    let (tau, (p_aux, t_aux)) = StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase2(
-        &compound_public_params.vanilla_params,
-        labels,
-        data,
-        data_tree,
-        config,
-        replica_path.as_ref().to_path_buf(),
-    )?;
+    // The chosen value is recorded in t_aux, from which all later proving reads it.
+    let rows_to_discard = tree_r_last_rows_to_discard::<Tree>(porep_config.sector_size)?;
+    options.tree_r_last_rows_to_discard = Some(rows_to_discard);
+    let (tau, (p_aux, t_aux)) =
+        StackedDrg::<Tree, DefaultPieceHasher>::replicate_phase2_with_options(
+            &compound_public_params.vanilla_params,
+            labels,
+            data,
+            data_tree,
+            config,
+            replica_path.as_ref().to_path_buf(),
+            options,
+        )?;
 
     let comm_r = commitment_from_fr(tau.comm_r.into());
 
@@ -321,19 +675,219 @@ pub fn seal_pre_commit_phase2<R, S, Tree: 'static + MerkleTreeTrait>(
     Ok(out)
 }
 
+/// Returns the checkpoint label if `err` was caused by a job reaching its deadline.
+fn deadline_checkpoint(err: &anyhow::Error) -> Option<String> {
+    match err.downcast_ref::<StorageProofsError>() {
+        Some(StorageProofsError::DeadlineReached(checkpoint)) => Some(checkpoint.clone()),
+        _ => None,
+    }
+}
+
+/// Like [`seal_pre_commit_phase1`], but stops at the first layer boundary after `deadline` and
+/// returns the state to pass to [`resume_seal_pre_commit_phase1`]. Completed layers are kept.
 #[allow(clippy::too_many_arguments)]
-pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
+pub fn seal_pre_commit_phase1_with_deadline<R, S, T, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: R,
+    in_path: S,
+    out_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    piece_infos: &[PieceInfo],
+    deadline: SystemTime,
+) -> Result<SealProgress<SealPreCommitPhase1Output<Tree>, SealPreCommitPhase1State>>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    T: AsRef<Path>,
+{
+    let state = SealPreCommitPhase1State {
+        porep_config,
+        cache_path: cache_path.as_ref().to_path_buf(),
+        in_path: in_path.as_ref().to_path_buf(),
+        out_path: out_path.as_ref().to_path_buf(),
+        prover_id,
+        sector_id,
+        ticket,
+        piece_infos: piece_infos.to_vec(),
+        checkpoint: String::new(),
+    };
+
+    resume_seal_pre_commit_phase1(state, deadline)
+}
+
+/// Continues a paused `seal_pre_commit_phase1` with a new deadline.
+pub fn resume_seal_pre_commit_phase1<Tree: 'static + MerkleTreeTrait>(
+    state: SealPreCommitPhase1State,
+    deadline: SystemTime,
+) -> Result<SealProgress<SealPreCommitPhase1Output<Tree>, SealPreCommitPhase1State>> {
+    let result = control::with_deadline(deadline, || {
+        seal_pre_commit_phase1::<_, _, _, Tree>(
+            state.porep_config,
+            &state.cache_path,
+            &state.in_path,
+            &state.out_path,
+            state.prover_id,
+            state.sector_id,
+            state.ticket,
+            &state.piece_infos,
+        )
+    });
+
+    match result {
+        Ok(output) => Ok(SealProgress::Done(output)),
+        Err(err) => match deadline_checkpoint(&err) {
+            Some(checkpoint) => {
+                info!(
+                    "seal_pre_commit_phase1: {:?} paused at {}",
+                    state.sector_id, checkpoint
+                );
+                Ok(SealProgress::Paused(SealPreCommitPhase1State {
+                    checkpoint,
+                    ..state
+                }))
+            }
+            None => Err(err),
+        },
+    }
+}
+
+/// Like [`seal_pre_commit_phase2`], but builds tree_c on the GPUs `devices` (indexes into the GPUs
+/// found), splitting its base trees across them, e.g. to assign the sectors of a machine to its
+/// GPUs. tree_r_last is built as configured.
+pub fn seal_pre_commit_phase2_with_devices<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    devices: &[usize],
+) -> Result<SealPreCommitOutput>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    ensure!(!devices.is_empty(), "no devices assigned to tree_c");
+    seal_pre_commit_phase2_inner(
+        porep_config,
+        phase1_output,
+        cache_path,
+        replica_path,
+        Phase2Options {
+            tree_c_devices: devices.to_vec(),
+            ..Phase2Options::default()
+        },
+    )
+}
+
+/// Like [`seal_pre_commit_phase2`], but also streams the encoded replica to `sink` (e.g. a raw
+/// block device) while tree_r_last is built, in order and from its first byte. The sink is
+/// returned, flushed, along with the output. The sink receives a second copy: the replica is still
+/// written to `replica_path`, which the tree_r_last written to `cache_path` refers to.
+pub fn seal_pre_commit_phase2_with_sink<R, S, W, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    sink: W,
+) -> Result<(SealPreCommitOutput, W)>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+    W: Write + Send + 'static,
+{
+    let sink = Arc::new(Mutex::new(sink));
+    let output = seal_pre_commit_phase2_inner(
+        porep_config,
+        phase1_output,
+        cache_path,
+        replica_path,
+        Phase2Options {
+            replica_sink: Some(sink.clone()),
+            ..Phase2Options::default()
+        },
+    )?;
+
+    Ok((output, unwrap_replica_sink(sink)?))
+}
+
+/// Like [`seal_pre_commit_phase2`], but does not start building a tree after `deadline`, and
+/// returns the state to pass to [`resume_seal_pre_commit_phase2`] instead.
+pub fn seal_pre_commit_phase2_with_deadline<R, S, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealPreCommitPhase1Output<Tree>,
+    cache_path: S,
+    replica_path: R,
+    deadline: SystemTime,
+) -> Result<SealProgress<SealPreCommitOutput, SealPreCommitPhase2State<Tree>>>
+where
+    R: AsRef<Path>,
+    S: AsRef<Path>,
+{
+    let state = SealPreCommitPhase2State {
+        porep_config,
+        phase1_output,
+        cache_path: cache_path.as_ref().to_path_buf(),
+        replica_path: replica_path.as_ref().to_path_buf(),
+        checkpoint: String::new(),
+    };
+
+    resume_seal_pre_commit_phase2(state, deadline)
+}
+
+/// Continues a paused `seal_pre_commit_phase2` with a new deadline.
+pub fn resume_seal_pre_commit_phase2<Tree: 'static + MerkleTreeTrait>(
+    state: SealPreCommitPhase2State<Tree>,
+    deadline: SystemTime,
+) -> Result<SealProgress<SealPreCommitOutput, SealPreCommitPhase2State<Tree>>> {
+    let result = control::with_deadline(deadline, || {
+        seal_pre_commit_phase2(
+            state.porep_config,
+            state.phase1_output.clone(),
+            &state.cache_path,
+            &state.replica_path,
+        )
+    });
+
+    match result {
+        Ok(output) => Ok(SealProgress::Done(output)),
+        Err(err) => match deadline_checkpoint(&err) {
+            Some(checkpoint) => {
+                info!(
+                    "seal_pre_commit_phase2: {:?} paused at {}",
+                    state.replica_path, checkpoint
+                );
+                Ok(SealProgress::Paused(SealPreCommitPhase2State {
+                    checkpoint,
+                    ..state
+                }))
+            }
+            None => Err(err),
+        },
+    }
+}
+
+/// Proves all synthetic challenges of a sector sealed with the synthetic PoRep, and stores the
+/// proofs in its cache, from which [`seal_commit_phase1`] proves the challenges selected by the
+/// seed. Called right after [`seal_pre_commit_phase2`], once the layers can be discarded with
+/// [`clear_layer_data`].
+#[allow(clippy::too_many_arguments)]
+pub fn generate_synth_proofs<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     cache_path: T,
     replica_path: T,
     prover_id: ProverId,
     sector_id: SectorId,
     ticket: Ticket,
-    seed: Ticket,
     pre_commit: SealPreCommitOutput,
     piece_infos: &[PieceInfo],
-) -> Result<SealCommitPhase1Output<Tree>> {
-    info!("seal_commit_phase1:start: {:?}", sector_id);
+) -> Result<()> {
+    info!("generate_synth_proofs:start: {:?}", sector_id);
+    let _job = control::register_job(format!("generate_synth_proofs {:?}", sector_id));
+    ensure!(
+        porep_config.feature_enabled(ApiFeature::SyntheticPoRep),
+        "synthetic proofs require a synthetic PoRep"
+    );
 
     // Sanity check all input path types.
     ensure!(
@@ -370,12 +924,10 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
         let mut res: TemporaryAux<_, _> = deserialize(&t_aux_bytes)?;
 
         // Switch t_aux to the passed in cache_path
-        res.set_cache_path(cache_path);
+        res.set_cache_path(&cache_path);
         res
     };
 
-    // Convert TemporaryAux to TemporaryAuxCache, which instantiates all
-    // elements based on the configs stored in TemporaryAux.
     let t_aux_cache: TemporaryAuxCache<Tree, DefaultPieceHasher> =
         TemporaryAuxCache::new(&t_aux, replica_path.as_ref().to_path_buf())
             .context("failed to restore contents of t_aux")?;
@@ -391,6 +943,7 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
         &porep_config.porep_id,
     );
 
+    // The synthetic challenges don't depend on the seed, which isn't known yet.
     let public_inputs = stacked::PublicInputs {
         replica_id,
         tau: Some(stacked::Tau {
@@ -398,12 +951,81 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
             comm_r: comm_r_safe,
         }),
         k: None,
-        seed,
+        seed: [0; 32],
     };
 
-    let private_inputs = stacked::PrivateInputs::<Tree, DefaultPieceHasher> {
-        p_aux,
-        t_aux: t_aux_cache,
+    let vanilla_params = setup_params(
+        PaddedBytesAmount::from(porep_config),
+        usize::from(PoRepProofPartitions::from(porep_config)),
+        porep_config.porep_id,
+        porep_config.api_version,
+        porep_config.api_features,
+    )?;
+
+    StackedDrg::<Tree, DefaultPieceHasher>::write_synth_proofs(
+        &cache_path.as_ref().join(CacheKey::SynthProofs.to_string()),
+        &vanilla_params,
+        &public_inputs,
+        &p_aux,
+        &t_aux_cache,
+    )?;
+
+    info!("generate_synth_proofs:finish: {:?}", sector_id);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: T,
+    replica_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+    pre_commit: SealPreCommitOutput,
+    piece_infos: &[PieceInfo],
+) -> Result<SealCommitPhase1Output<Tree>> {
+    info!("seal_commit_phase1:start: {:?}", sector_id);
+
+    // Sanity check all input path types.
+    ensure!(
+        metadata(cache_path.as_ref())?.is_dir(),
+        "cache_path must be a directory"
+    );
+    ensure!(
+        metadata(replica_path.as_ref())?.is_file(),
+        "replica_path must be a file"
+    );
+
+    let SealPreCommitOutput { comm_d, comm_r } = pre_commit;
+
+    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+    ensure!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
+    ensure!(
+        verify_pieces(&comm_d, piece_infos, porep_config.into())?,
+        "pieces and comm_d do not match"
+    );
+
+    let comm_r_safe = as_safe_commitment(&comm_r, "comm_r")?;
+    let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
+
+    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+        &prover_id,
+        sector_id.into(),
+        &ticket,
+        comm_d_safe,
+        &porep_config.porep_id,
+    );
+
+    let public_inputs = stacked::PublicInputs {
+        replica_id,
+        tau: Some(stacked::Tau {
+            comm_d: comm_d_safe,
+            comm_r: comm_r_safe,
+        }),
+        k: None,
+        seed,
     };
 
     let compound_setup_params = compound_proof::SetupParams {
@@ -412,6 +1034,7 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
             usize::from(PoRepProofPartitions::from(porep_config)),
             porep_config.porep_id,
             porep_config.api_version,
+            porep_config.api_features,
         )?,
         partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
         priority: false,
@@ -422,12 +1045,55 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
         _,
     >>::setup(&compound_setup_params)?;
 
-    let vanilla_proofs = StackedDrg::prove_all_partitions(
-        &compound_public_params.vanilla_params,
-        &public_inputs,
-        &private_inputs,
-        StackedCompound::partition_count(&compound_public_params),
-    )?;
+    let partition_count = StackedCompound::partition_count(&compound_public_params);
+    let vanilla_proofs = if porep_config.feature_enabled(ApiFeature::SyntheticPoRep) {
+        // The layers may be gone, the challenges are proven by the stored synthetic proofs.
+        StackedDrg::prove_all_partitions_synth(
+            &cache_path.as_ref().join(CacheKey::SynthProofs.to_string()),
+            &compound_public_params.vanilla_params,
+            &public_inputs,
+            partition_count,
+        )
+        .context("failed to read the synthetic proofs")?
+    } else {
+        let p_aux = {
+            let p_aux_path = cache_path.as_ref().join(CacheKey::PAux.to_string());
+            let p_aux_bytes = fs::read(&p_aux_path)
+                .with_context(|| format!("could not read file p_aux={:?}", p_aux_path))?;
+
+            deserialize(&p_aux_bytes)
+        }?;
+
+        let t_aux = {
+            let t_aux_path = cache_path.as_ref().join(CacheKey::TAux.to_string());
+            let t_aux_bytes = fs::read(&t_aux_path)
+                .with_context(|| format!("could not read file t_aux={:?}", t_aux_path))?;
+
+            let mut res: TemporaryAux<_, _> = deserialize(&t_aux_bytes)?;
+
+            // Switch t_aux to the passed in cache_path
+            res.set_cache_path(cache_path);
+            res
+        };
+
+        // Convert TemporaryAux to TemporaryAuxCache, which instantiates all
+        // elements based on the configs stored in TemporaryAux.
+        let t_aux_cache: TemporaryAuxCache<Tree, DefaultPieceHasher> =
+            TemporaryAuxCache::new(&t_aux, replica_path.as_ref().to_path_buf())
+                .context("failed to restore contents of t_aux")?;
+
+        let private_inputs = stacked::PrivateInputs::<Tree, DefaultPieceHasher> {
+            p_aux,
+            t_aux: t_aux_cache,
+        };
+
+        StackedDrg::prove_all_partitions(
+            &compound_public_params.vanilla_params,
+            &public_inputs,
+            &private_inputs,
+            partition_count,
+        )?
+    };
 
     let sanity_check = StackedDrg::<Tree, DefaultPieceHasher>::verify_all_partitions(
         &compound_public_params.vanilla_params,
@@ -443,20 +1109,85 @@ pub fn seal_commit_phase1<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
         replica_id,
         seed,
         ticket,
+        porep_params: porep_config.porep_params(),
     };
 
     info!("seal_commit_phase1:finish: {:?}", sector_id);
     Ok(out)
 }
 
+/// Like [`seal_commit_phase1`], for a non-interactive PoRep, whose challenges only depend on the
+/// commitments of the sector. It doesn't take a seed, it can run right after PC2.
+#[allow(clippy::too_many_arguments)]
+pub fn seal_commit_phase1_non_interactive<T: AsRef<Path>, Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    cache_path: T,
+    replica_path: T,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    pre_commit: SealPreCommitOutput,
+    piece_infos: &[PieceInfo],
+) -> Result<SealCommitPhase1Output<Tree>> {
+    ensure!(
+        porep_config.feature_enabled(ApiFeature::NonInteractivePoRep),
+        "porep_config is not that of a non-interactive PoRep"
+    );
+
+    seal_commit_phase1(
+        porep_config,
+        cache_path,
+        replica_path,
+        prover_id,
+        sector_id,
+        ticket,
+        NI_SEED,
+        pre_commit,
+        piece_infos,
+    )
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     phase1_output: SealCommitPhase1Output<Tree>,
     prover_id: ProverId,
     sector_id: SectorId,
+) -> Result<SealCommitOutput> {
+    prove_seal_commit(porep_config, phase1_output, prover_id, sector_id, true)
+}
+
+/// Like `seal_commit_phase2`, but never requests the proof from the remote proving service, e.g.
+/// when answering its requests.
+pub(crate) fn seal_commit_phase2_here<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealCommitPhase1Output<Tree>,
+    prover_id: ProverId,
+    sector_id: SectorId,
+) -> Result<SealCommitOutput> {
+    prove_seal_commit(porep_config, phase1_output, prover_id, sector_id, false)
+}
+
+fn prove_seal_commit<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    phase1_output: SealCommitPhase1Output<Tree>,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    remote: bool,
 ) -> Result<SealCommitOutput> {
     info!("seal_commit_phase2:start: {:?}", sector_id);
+    let _priority = enter_priority_class(PriorityClass::SealCommitPhase2);
+
+    porep_config.adopt_porep_params(
+        phase1_output.porep_params,
+        phase1_output.vanilla_proofs.len(),
+    )?;
+
+    let remote_proof = if remote {
+        remote_c2::seal_commit_phase2_remote(porep_config, &phase1_output, prover_id, sector_id)?
+    } else {
+        None
+    };
 
     let SealCommitPhase1Output {
         vanilla_proofs,
@@ -465,6 +1196,7 @@ pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
         replica_id,
         seed,
         ticket,
+        ..
     } = phase1_output;
 
     ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
@@ -473,56 +1205,65 @@ pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
     let comm_r_safe = as_safe_commitment(&comm_r, "comm_r")?;
     let comm_d_safe = DefaultPieceDomain::try_from_bytes(&comm_d)?;
 
-    let public_inputs = stacked::PublicInputs {
-        replica_id,
-        tau: Some(stacked::Tau {
-            comm_d: comm_d_safe,
-            comm_r: comm_r_safe,
-        }),
-        k: None,
-        seed,
-    };
-
-    let groth_params = get_stacked_params::<Tree>(porep_config)?;
-
-    trace!(
-        "got groth params ({}) while sealing",
-        u64::from(PaddedBytesAmount::from(porep_config))
-    );
-
-    let compound_setup_params = compound_proof::SetupParams {
-        vanilla_params: setup_params(
-            PaddedBytesAmount::from(porep_config),
-            usize::from(PoRepProofPartitions::from(porep_config)),
-            porep_config.porep_id,
-            porep_config.api_version,
-        )?,
-        partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
-        priority: false,
-    };
-
-    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
-        StackedDrg<'_, Tree, DefaultPieceHasher>,
-        _,
-    >>::setup(&compound_setup_params)?;
+    let buf = match remote_proof {
+        Some(buf) => buf,
+        None => {
+            let public_inputs = stacked::PublicInputs {
+                replica_id,
+                tau: Some(stacked::Tau {
+                    comm_d: comm_d_safe,
+                    comm_r: comm_r_safe,
+                }),
+                k: None,
+                seed,
+            };
+
+            let groth_params = get_stacked_params::<Tree>(porep_config)?;
+
+            trace!(
+                "got groth params ({}) while sealing",
+                u64::from(PaddedBytesAmount::from(porep_config))
+            );
+
+            let compound_setup_params = compound_proof::SetupParams {
+                vanilla_params: setup_params(
+                    PaddedBytesAmount::from(porep_config),
+                    usize::from(PoRepProofPartitions::from(porep_config)),
+                    porep_config.porep_id,
+                    porep_config.api_version,
+                    porep_config.api_features,
+                )?,
+                partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
+                priority: false,
+            };
+
+            let compound_public_params =
+                <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+                    StackedDrg<'_, Tree, DefaultPieceHasher>,
+                    _,
+                >>::setup(&compound_setup_params)?;
+
+            trace!("snark_proof:start");
+            let groth_proofs = StackedCompound::<Tree, DefaultPieceHasher>::circuit_proofs(
+                &public_inputs,
+                vanilla_proofs,
+                &compound_public_params.vanilla_params,
+                &groth_params,
+                compound_public_params.priority,
+            )?;
+            trace!("snark_proof:finish");
 
-    trace!("snark_proof:start");
-    let groth_proofs = StackedCompound::<Tree, DefaultPieceHasher>::circuit_proofs(
-        &public_inputs,
-        vanilla_proofs,
-        &compound_public_params.vanilla_params,
-        &groth_params,
-        compound_public_params.priority,
-    )?;
-    trace!("snark_proof:finish");
+            let proof = MultiProof::new(groth_proofs, &groth_params.pvk);
 
-    let proof = MultiProof::new(groth_proofs, &groth_params.pvk);
+            let mut buf = Vec::with_capacity(
+                SINGLE_PARTITION_PROOF_LEN * usize::from(PoRepProofPartitions::from(porep_config)),
+            );
 
-    let mut buf = Vec::with_capacity(
-        SINGLE_PARTITION_PROOF_LEN * usize::from(PoRepProofPartitions::from(porep_config)),
-    );
+            proof.write(&mut buf)?;
 
-    proof.write(&mut buf)?;
+            buf
+        }
+    };
 
     // Verification is cheap when parameters are cached,
     // and it is never correct to return a proof which does not verify.
@@ -544,6 +1285,119 @@ pub fn seal_commit_phase2<Tree: 'static + MerkleTreeTrait>(
     Ok(out)
 }
 
+/// Like [`seal_commit_phase2`], for several sectors of the same shape. The circuits of up to
+/// `c2_batch_sectors` sectors are proven together, so that they share the FFT and multiexp passes
+/// on the GPU. The proofs are always generated here, not by the remote proving service, and are
+/// verified like those of `seal_commit_phase2`. The outputs are in the order of `sectors`.
+pub fn seal_commit_phase2_batch<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    sectors: Vec<SealCommitPhase2Input<Tree>>,
+) -> Result<Vec<SealCommitOutput>> {
+    info!("seal_commit_phase2_batch:start: {} sectors", sectors.len());
+    let _priority = enter_priority_class(PriorityClass::SealCommitPhase2);
+
+    let batch_sectors = tenant::settings().c2_batch_sectors.max(1);
+    let partitions = usize::from(PoRepProofPartitions::from(porep_config));
+    for sector in &sectors {
+        porep_config.adopt_porep_params(
+            sector.phase1_output.porep_params,
+            sector.phase1_output.vanilla_proofs.len(),
+        )?;
+    }
+
+    let groth_params = get_stacked_params::<Tree>(porep_config)?;
+    let compound_setup_params = compound_proof::SetupParams {
+        vanilla_params: setup_params(
+            PaddedBytesAmount::from(porep_config),
+            partitions,
+            porep_config.porep_id,
+            porep_config.api_version,
+            porep_config.api_features,
+        )?,
+        partitions: Some(partitions),
+        priority: false,
+    };
+    let compound_public_params = <StackedCompound<Tree, DefaultPieceHasher> as CompoundProof<
+        StackedDrg<'_, Tree, DefaultPieceHasher>,
+        _,
+    >>::setup(&compound_setup_params)?;
+
+    let mut outputs = Vec::with_capacity(sectors.len());
+    let mut sectors = sectors.into_iter().peekable();
+    while sectors.peek().is_some() {
+        let mut public_inputs = Vec::with_capacity(batch_sectors);
+        let mut vanilla_proofs = Vec::with_capacity(batch_sectors);
+        let mut seals = Vec::with_capacity(batch_sectors);
+        for sector in sectors.by_ref().take(batch_sectors) {
+            let SealCommitPhase2Input {
+                phase1_output,
+                prover_id,
+                sector_id,
+            } = sector;
+            let SealCommitPhase1Output {
+                vanilla_proofs: sector_proofs,
+                comm_d,
+                comm_r,
+                replica_id,
+                seed,
+                ticket,
+                ..
+            } = phase1_output;
+
+            ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+            ensure!(comm_r != [0; 32], "Invalid all zero commitment (comm_r)");
+
+            public_inputs.push(stacked::PublicInputs {
+                replica_id,
+                tau: Some(stacked::Tau {
+                    comm_d: DefaultPieceDomain::try_from_bytes(&comm_d)?,
+                    comm_r: as_safe_commitment(&comm_r, "comm_r")?,
+                }),
+                k: None,
+                seed,
+            });
+            vanilla_proofs.push(sector_proofs);
+            seals.push((comm_r, comm_d, prover_id, sector_id, ticket, seed));
+        }
+
+        trace!("snark_proof:start: {} sectors", seals.len());
+        let groth_proofs = StackedCompound::<Tree, DefaultPieceHasher>::circuit_proofs_batch(
+            &public_inputs,
+            vanilla_proofs,
+            &compound_public_params.vanilla_params,
+            &groth_params,
+            compound_public_params.priority,
+        )?;
+        trace!("snark_proof:finish");
+
+        for ((comm_r, comm_d, prover_id, sector_id, ticket, seed), sector_proofs) in
+            seals.into_iter().zip(groth_proofs)
+        {
+            let proof = MultiProof::new(sector_proofs, &groth_params.pvk);
+            let mut buf = Vec::with_capacity(SINGLE_PARTITION_PROOF_LEN * partitions);
+            proof.write(&mut buf)?;
+
+            verify_seal::<Tree>(
+                porep_config,
+                comm_r,
+                comm_d,
+                prover_id,
+                sector_id,
+                ticket,
+                seed,
+                &buf,
+            )
+            .context("post-seal verification sanity check failed")?;
+
+            info!("seal_commit_phase2:finish: {:?}", sector_id);
+            outputs.push(SealCommitOutput { proof: buf });
+        }
+    }
+
+    info!("seal_commit_phase2_batch:finish");
+    Ok(outputs)
+}
+
 /// Given the specified arguments, this method returns the inputs that were used to
 /// generate the seal proof.  This can be useful for proof aggregation, as verification
 /// requires these inputs.
@@ -601,6 +1455,7 @@ pub fn get_seal_inputs<Tree: 'static + MerkleTreeTrait>(
             usize::from(PoRepProofPartitions::from(porep_config)),
             porep_config.porep_id,
             porep_config.api_version,
+            porep_config.api_features,
         )?,
         partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
         priority: false,
@@ -633,8 +1488,71 @@ pub fn get_seal_inputs<Tree: 'static + MerkleTreeTrait>(
     Ok(inputs)
 }
 
+/// Derives the interactive PoRep challenges of a sector, i.e. the nodes proven by
+/// `seal_commit_phase1` for each partition, without access to its replica.
+///
+/// The challenges of partition `k` are derived from the replica id and the seed as described in
+/// `storage_proofs_porep::stacked::LayerChallenges::derive_internal`; node 0 is never challenged.
+///
+/// # Arguments
+///
+/// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
+/// * `comm_d` - a commitment to a sector's data.
+/// * `prover_id` - the prover_id used to seal this sector.
+/// * `sector_id` - the sector_id of this sector.
+/// * `ticket` - the ticket used to generate this sector's replica-id.
+/// * `seed` - the seed used to derive the porep challenges.
+pub fn generate_porep_challenges<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_d: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    seed: Ticket,
+) -> Result<Vec<Vec<u64>>> {
+    ensure!(comm_d != [0; 32], "Invalid all zero commitment (comm_d)");
+    ensure!(
+        !porep_config.feature_enabled(ApiFeature::SyntheticPoRep),
+        "the challenges of a synthetic PoRep also depend on comm_r"
+    );
+    ensure!(
+        !porep_config.feature_enabled(ApiFeature::NonInteractivePoRep),
+        "the challenges of a non-interactive PoRep depend on comm_r instead of the seed"
+    );
+
+    let replica_id = generate_replica_id::<Tree::Hasher, _>(
+        &prover_id,
+        sector_id.into(),
+        &ticket,
+        comm_d,
+        &porep_config.porep_id,
+    );
+
+    let partitions = usize::from(PoRepProofPartitions::from(porep_config));
+    let vanilla_params = setup_params(
+        PaddedBytesAmount::from(porep_config),
+        partitions,
+        porep_config.porep_id,
+        porep_config.api_version,
+        porep_config.api_features,
+    )?;
+
+    let challenges = (0..partitions)
+        .map(|k| {
+            vanilla_params
+                .layer_challenges
+                .derive(vanilla_params.nodes, &replica_id, &seed, k as u8)
+                .into_iter()
+                .map(|challenge| challenge as u64)
+                .collect()
+        })
+        .collect();
+
+    Ok(challenges)
+}
+
 /// Given a value, get one suitable for aggregation.
-fn get_aggregate_target_len(len: usize) -> usize {
+pub(crate) fn get_aggregate_target_len(len: usize) -> usize {
     if len == 1 {
         2
     } else {
@@ -643,7 +1561,10 @@ fn get_aggregate_target_len(len: usize) -> usize {
 }
 
 /// Given a list of proofs and a target_len, make sure that the proofs list is padded to the target_len size.
-fn pad_proofs_to_target(proofs: &mut Vec<groth16::Proof<Bls12>>, target_len: usize) -> Result<()> {
+pub(crate) fn pad_proofs_to_target(
+    proofs: &mut Vec<groth16::Proof<Bls12>>,
+    target_len: usize,
+) -> Result<()> {
     trace!(
         "pad_proofs_to_target target_len {}, proofs len {}",
         target_len,
@@ -680,7 +1601,7 @@ fn pad_proofs_to_target(proofs: &mut Vec<groth16::Proof<Bls12>>, target_len: usi
 }
 
 /// Given a list of public inputs and a target_len, make sure that the inputs list is padded to the target_len size.
-fn pad_inputs_to_target(
+pub(crate) fn pad_inputs_to_target(
     commit_inputs: &[Vec<Fr>],
     num_inputs_per_proof: usize,
     target_len: usize,
@@ -719,11 +1640,13 @@ fn pad_inputs_to_target(
 /// * `porep_config` - this sector's porep config that contains the number of bytes in the sector.
 /// * `seeds` - an ordered list of seeds used to derive the PoRep challenges.
 /// * `commit_outputs` - an ordered list of seal proof outputs returned from 'seal_commit_phase2'.
+/// * `aggregate_version` - the SnarkPack version of the aggregate proof.
 pub fn aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     comm_rs: &[[u8; 32]],
     seeds: &[[u8; 32]],
     commit_outputs: &[SealCommitOutput],
+    aggregate_version: groth16::aggregate::AggregateVersion,
 ) -> Result<AggregateSnarkProof> {
     info!("aggregate_seal_commit_proofs:start");
 
@@ -784,6 +1707,7 @@ pub fn aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
         &srs_prover_key,
         &hashed_seeds_and_comm_rs,
         proofs.as_slice(),
+        aggregate_version,
     )?;
     let mut aggregate_proof_bytes = Vec::new();
     aggregate_proof.write(&mut aggregate_proof_bytes)?;
@@ -803,12 +1727,14 @@ pub fn aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
 /// * `aggregate_proof_bytes` - the returned aggregate proof from 'aggreate_seal_commit_proofs'.
 /// * `commit_inputs` - a flattened/combined and ordered list of all public inputs, which must match
 ///    the ordering of the seal proofs when aggregated.
+/// * `aggregate_version` - the SnarkPack version the aggregate proof was generated with.
 pub fn verify_aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
     porep_config: PoRepConfig,
     aggregate_proof_bytes: AggregateSnarkProof,
     comm_rs: &[[u8; 32]],
     seeds: &[[u8; 32]],
     commit_inputs: Vec<Vec<Fr>>,
+    aggregate_version: groth16::aggregate::AggregateVersion,
 ) -> Result<bool> {
     info!("verify_aggregate_seal_commit_proofs:start");
 
@@ -878,6 +1804,7 @@ pub fn verify_aggregate_seal_commit_proofs<Tree: 'static + MerkleTreeTrait>(
         &hashed_seeds_and_comm_rs,
         commit_inputs.as_slice(),
         &aggregate_proof,
+        aggregate_version,
     )?;
     trace!("end verifying aggregate proof");
 
@@ -947,6 +1874,7 @@ pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
             usize::from(PoRepProofPartitions::from(porep_config)),
             porep_config.porep_id,
             porep_config.api_version,
+            porep_config.api_features,
         )?,
         partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
         priority: false,
@@ -985,11 +1913,7 @@ pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
             &public_inputs,
             &proof,
             &ChallengeRequirements {
-                minimum_challenges: *POREP_MINIMUM_CHALLENGES
-                    .read()
-                    .expect("POREP_MINIMUM_CHALLENGES poisoned")
-                    .get(&u64::from(SectorSize::from(porep_config)))
-                    .expect("unknown sector size") as usize,
+                minimum_challenges: porep_config.minimum_challenges(),
             },
         )
     };
@@ -998,6 +1922,33 @@ pub fn verify_seal<Tree: 'static + MerkleTreeTrait>(
     result
 }
 
+/// Like [`verify_seal`], for a proof of [`seal_commit_phase1_non_interactive`].
+pub fn verify_seal_non_interactive<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    comm_r_in: Commitment,
+    comm_d_in: Commitment,
+    prover_id: ProverId,
+    sector_id: SectorId,
+    ticket: Ticket,
+    proof_vec: &[u8],
+) -> Result<bool> {
+    ensure!(
+        porep_config.feature_enabled(ApiFeature::NonInteractivePoRep),
+        "porep_config is not that of a non-interactive PoRep"
+    );
+
+    verify_seal::<Tree>(
+        porep_config,
+        comm_r_in,
+        comm_d_in,
+        prover_id,
+        sector_id,
+        ticket,
+        NI_SEED,
+        proof_vec,
+    )
+}
+
 /// Verifies a batch of outputs of some previously-run seal operations.
 ///
 /// # Arguments
@@ -1062,6 +2013,7 @@ pub fn verify_batch_seal<Tree: 'static + MerkleTreeTrait>(
             usize::from(PoRepProofPartitions::from(porep_config)),
             porep_config.porep_id,
             porep_config.api_version,
+            porep_config.api_features,
         )?,
         partitions: Some(usize::from(PoRepProofPartitions::from(porep_config))),
         priority: false,
@@ -1108,11 +2060,7 @@ pub fn verify_batch_seal<Tree: 'static + MerkleTreeTrait>(
         &public_inputs,
         &proofs,
         &ChallengeRequirements {
-            minimum_challenges: *POREP_MINIMUM_CHALLENGES
-                .read()
-                .expect("POREP_MINIMUM_CHALLENGES poisoned")
-                .get(&u64::from(SectorSize::from(porep_config)))
-                .expect("unknown sector size") as usize,
+            minimum_challenges: porep_config.minimum_challenges(),
         },
     )
         .map_err(Into::into);
@@ -1120,3 +2068,29 @@ pub fn verify_batch_seal<Tree: 'static + MerkleTreeTrait>(
     info!("verify_batch_seal:finish");
     result
 }
+
+/// Like [`verify_batch_seal`], for the seal proofs of `seals`, which are verified by a single
+/// batched Groth16 verification. Returns false if any of them is invalid.
+pub fn verify_batch_seal_proofs<Tree: 'static + MerkleTreeTrait>(
+    porep_config: PoRepConfig,
+    seals: &[SealProofToVerify<'_>],
+) -> Result<bool> {
+    let comm_rs: Vec<_> = seals.iter().map(|seal| seal.comm_r).collect();
+    let comm_ds: Vec<_> = seals.iter().map(|seal| seal.comm_d).collect();
+    let prover_ids: Vec<_> = seals.iter().map(|seal| seal.prover_id).collect();
+    let sector_ids: Vec<_> = seals.iter().map(|seal| seal.sector_id).collect();
+    let tickets: Vec<_> = seals.iter().map(|seal| seal.ticket).collect();
+    let seeds: Vec<_> = seals.iter().map(|seal| seal.seed).collect();
+    let proofs: Vec<_> = seals.iter().map(|seal| seal.proof).collect();
+
+    verify_batch_seal::<Tree>(
+        porep_config,
+        &comm_rs,
+        &comm_ds,
+        &prover_ids,
+        &sector_ids,
+        &tickets,
+        &seeds,
+        &proofs,
+    )
+}
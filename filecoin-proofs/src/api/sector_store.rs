@@ -0,0 +1,291 @@
+//! Proving PoSts of sectors whose replicas are not on a local filesystem.
+//!
+//! The trees of a PoSt are read from files, so the sectors of a [`SectorStore`] are staged into a
+//! local directory first. Only what the PoSt reads is staged: the cache files (p_aux, t_aux and
+//! the cached rows of tree_r_last) and, for every challenge, the segment of the replica whose
+//! rows of tree_r_last are not cached. The staged replica is a sparse file of the length of the
+//! replica, so that a sector takes about the size of its cached tree on the local disk.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use log::info;
+use merkletree::store::StoreConfig;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    merkle::MerkleTreeTrait,
+    sector::SectorId,
+    tenant::{current_tenant, with_current_tenant},
+    util::NODE_SIZE,
+};
+
+use crate::{
+    api::{
+        generate_fallback_sector_challenges, generate_single_vanilla_proof,
+        generate_window_post_with_vanilla, generate_winning_post_with_vanilla,
+        post_util::replica_segment_leafs,
+    },
+    types::{
+        ChallengeSeed, Commitment, FallbackPoStSectorProof, PoStConfig, PoStType,
+        PrivateReplicaInfo, ProverId, SnarkProof,
+    },
+};
+
+/// Where the replicas and the cache files of sectors are read from, e.g. a network filesystem,
+/// an object store or a custom block store.
+pub trait SectorStore: Send + Sync {
+    /// The length of the replica of `sector_id`, in bytes.
+    fn replica_len(&self, sector_id: SectorId) -> Result<u64>;
+
+    /// Reads `buf.len()` bytes of the replica of `sector_id` at `offset`.
+    fn read_replica_range(&self, sector_id: SectorId, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Reads the file `name` of the cache directory of `sector_id`, or returns `None` if there is
+    /// no such file.
+    fn read_cache_file(&self, sector_id: SectorId, name: &str) -> Result<Option<Vec<u8>>>;
+}
+
+/// A [`SectorStore`] of replicas and cache directories on the local filesystem.
+#[derive(Clone, Debug, Default)]
+pub struct FsSectorStore {
+    sectors: BTreeMap<SectorId, (PathBuf, PathBuf)>,
+}
+
+impl FsSectorStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the sector `sector_id` with its replica at `replica_path` and its cache directory at
+    /// `cache_path`.
+    pub fn insert(&mut self, sector_id: SectorId, replica_path: PathBuf, cache_path: PathBuf) {
+        self.sectors.insert(sector_id, (replica_path, cache_path));
+    }
+
+    fn paths(&self, sector_id: SectorId) -> Result<&(PathBuf, PathBuf)> {
+        self.sectors
+            .get(&sector_id)
+            .ok_or_else(|| anyhow!("unknown sector {:?}", sector_id))
+    }
+}
+
+impl SectorStore for FsSectorStore {
+    fn replica_len(&self, sector_id: SectorId) -> Result<u64> {
+        let (replica_path, _) = self.paths(sector_id)?;
+
+        Ok(fs::metadata(replica_path)
+            .with_context(|| format!("could not stat path={:?}", replica_path))?
+            .len())
+    }
+
+    fn read_replica_range(&self, sector_id: SectorId, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let (replica_path, _) = self.paths(sector_id)?;
+        let mut file = File::open(replica_path)
+            .with_context(|| format!("could not open path={:?}", replica_path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+            .with_context(|| format!("could not read {:?} at {}", replica_path, offset))?;
+
+        Ok(())
+    }
+
+    fn read_cache_file(&self, sector_id: SectorId, name: &str) -> Result<Option<Vec<u8>>> {
+        let (_, cache_path) = self.paths(sector_id)?;
+        let path = cache_path.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        fs::read(&path)
+            .map(Some)
+            .with_context(|| format!("could not read path={:?}", path))
+    }
+}
+
+/// Stages what the PoSt `challenges` of `sector_id` read from `store` into `staging_dir`, see the
+/// [module documentation](self), and returns the staged sector. `staging_dir` may be removed once
+/// the sector is proven.
+pub fn stage_post_sector<Tree: 'static + MerkleTreeTrait, S: SectorStore>(
+    post_config: &PoStConfig,
+    store: &S,
+    sector_id: SectorId,
+    comm_r: Commitment,
+    challenges: &[u64],
+    staging_dir: &Path,
+) -> Result<PrivateReplicaInfo<Tree>> {
+    info!("stage_post_sector:start: {:?}", sector_id);
+
+    let cache_dir = staging_dir.join("cache");
+    fs::create_dir_all(&cache_dir)
+        .with_context(|| format!("could not create path={:?}", cache_dir))?;
+    let p_aux_name = CacheKey::PAux.to_string();
+    let p_aux = store
+        .read_cache_file(sector_id, &p_aux_name)?
+        .ok_or_else(|| anyhow!("{:?} has no p_aux", sector_id))?;
+    fs::write(cache_dir.join(&p_aux_name), p_aux)?;
+    let t_aux_name = CacheKey::TAux.to_string();
+    if let Some(t_aux) = store.read_cache_file(sector_id, &t_aux_name)? {
+        fs::write(cache_dir.join(&t_aux_name), t_aux)?;
+    }
+
+    let replica_path = staging_dir.join("replica");
+    let mut replica_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&replica_path)
+        .with_context(|| format!("could not create path={:?}", replica_path))?;
+    let replica_len = store.replica_len(sector_id)?;
+    ensure!(
+        replica_len == u64::from(post_config.sector_size),
+        "the replica of {:?} has {} bytes, expected {}",
+        sector_id,
+        replica_len,
+        u64::from(post_config.sector_size)
+    );
+    replica_file.set_len(replica_len)?;
+
+    let replica = PrivateReplicaInfo::<Tree>::new(replica_path, comm_r, cache_dir)?;
+
+    let (configs, _) = replica.tree_r_last_configs(post_config.sector_size)?;
+    for config in &configs {
+        let path = StoreConfig::data_path(&config.path, &config.id);
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("invalid tree_r_last path {:?}", path))?;
+        let tree = store
+            .read_cache_file(sector_id, name)?
+            .ok_or_else(|| anyhow!("{:?} has no {}", sector_id, name))?;
+        fs::write(&path, tree)?;
+    }
+
+    // Proving a leaf rebuilds the uncached rows of tree_r_last above its segment of the replica.
+    let segment_leafs = replica_segment_leafs(post_config, &replica)?;
+
+    let mut segment = vec![0u8; segment_leafs as usize * NODE_SIZE];
+    for challenge in challenges {
+        let offset = challenge / segment_leafs * segment_leafs * NODE_SIZE as u64;
+        store.read_replica_range(sector_id, offset, &mut segment)?;
+        replica_file.seek(SeekFrom::Start(offset))?;
+        replica_file.write_all(&segment)?;
+    }
+    replica_file.flush()?;
+
+    info!("stage_post_sector:finish: {:?}", sector_id);
+
+    Ok(replica)
+}
+
+// The vanilla proofs of `sectors` of the PoSt `post_config`, staged into subdirectories of
+// `staging_dir`, which are removed once the sectors are proven.
+fn generate_vanilla_proofs_from_store<Tree: 'static + MerkleTreeTrait, S: SectorStore>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    store: &S,
+    sectors: &BTreeMap<SectorId, Commitment>,
+    prover_id: ProverId,
+    staging_dir: &Path,
+) -> Result<Vec<FallbackPoStSectorProof<Tree>>> {
+    let sector_ids: Vec<SectorId> = sectors.keys().copied().collect();
+    let challenges = generate_fallback_sector_challenges::<Tree>(
+        post_config,
+        randomness,
+        &sector_ids,
+        prover_id,
+    )?;
+
+    let tenant = current_tenant();
+    sector_ids
+        .par_iter()
+        .map(|sector_id| {
+            with_current_tenant(tenant.clone(), || {
+                let sector_dir = staging_dir.join(format!("s-{}", u64::from(*sector_id)));
+                let proof = stage_post_sector::<Tree, S>(
+                    post_config,
+                    store,
+                    *sector_id,
+                    sectors[sector_id],
+                    &challenges[sector_id],
+                    &sector_dir,
+                )
+                .and_then(|replica| {
+                    generate_single_vanilla_proof(
+                        post_config,
+                        *sector_id,
+                        &replica,
+                        &challenges[sector_id],
+                    )
+                });
+                // The sector may have failed before anything was staged, and its error is the one
+                // worth returning.
+                let removed = match fs::remove_dir_all(&sector_dir) {
+                    Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+                    removed => removed,
+                };
+                let proof = proof?;
+                removed.with_context(|| format!("could not remove path={:?}", sector_dir))?;
+
+                Ok(proof)
+            })
+        })
+        .collect()
+}
+
+/// Like `generate_window_post`, reading the sectors, given with their comm_r, from `store`. They are
+/// staged into `staging_dir` while they are proven.
+pub fn generate_window_post_from_store<Tree: 'static + MerkleTreeTrait, S: SectorStore>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    store: &S,
+    sectors: &BTreeMap<SectorId, Commitment>,
+    prover_id: ProverId,
+    staging_dir: &Path,
+) -> Result<SnarkProof> {
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+
+    let vanilla_proofs = generate_vanilla_proofs_from_store::<Tree, S>(
+        post_config,
+        randomness,
+        store,
+        sectors,
+        prover_id,
+        staging_dir,
+    )?;
+
+    generate_window_post_with_vanilla(post_config, randomness, prover_id, vanilla_proofs)
+}
+
+/// Like `generate_winning_post`, reading the sectors, given with their comm_r, from `store`. They
+/// are staged into `staging_dir` while they are proven.
+pub fn generate_winning_post_from_store<Tree: 'static + MerkleTreeTrait, S: SectorStore>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    store: &S,
+    sectors: &BTreeMap<SectorId, Commitment>,
+    prover_id: ProverId,
+    staging_dir: &Path,
+) -> Result<SnarkProof> {
+    ensure!(
+        post_config.typ == PoStType::Winning,
+        "invalid post config type"
+    );
+
+    let vanilla_proofs = generate_vanilla_proofs_from_store::<Tree, S>(
+        post_config,
+        randomness,
+        store,
+        sectors,
+        prover_id,
+        staging_dir,
+    )?;
+
+    generate_winning_post_with_vanilla(post_config, randomness, prover_id, vanilla_proofs)
+}
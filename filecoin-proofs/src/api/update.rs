@@ -1,14 +1,18 @@
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::path::Path;
 
 use anyhow::{ensure, Context, Result};
+use bellperson::groth16;
 use bincode::{deserialize, serialize};
-use filecoin_hashers::{Domain, Hasher};
+use blstrs::Scalar as Fr;
+use filecoin_hashers::{Domain, HashFunction, Hasher};
+use fr32::fr_into_bytes;
 use generic_array::typenum::Unsigned;
 use log::{info, trace};
 use merkletree::merkle::get_merkle_tree_len;
 use merkletree::store::StoreConfig;
+use sha2::{Digest, Sha256};
 use storage_proofs_core::{
     cache_key::CacheKey,
     compound_proof::{self, CompoundProof},
@@ -23,12 +27,16 @@ use storage_proofs_update::{
 };
 
 use crate::{
-    caches::{get_empty_sector_update_params, get_empty_sector_update_verifying_key},
+    api::seal::{get_aggregate_target_len, pad_inputs_to_target, pad_proofs_to_target},
+    caches::{
+        get_empty_sector_update_params, get_empty_sector_update_srs_key,
+        get_empty_sector_update_srs_verifier_key, get_empty_sector_update_verifying_key,
+    },
     constants::{DefaultPieceDomain, DefaultPieceHasher},
     pieces::verify_pieces,
     types::{
-        Commitment, EmptySectorUpdateEncoded, EmptySectorUpdateProof, PieceInfo, PoRepConfig,
-        SectorUpdateConfig,
+        AggregateSnarkProof, Commitment, EmptySectorUpdateEncoded, EmptySectorUpdateProof,
+        PieceInfo, PoRepConfig, SectorUpdateConfig,
     },
 };
 
@@ -248,6 +256,37 @@ pub fn remove_encoded_data<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>
     data_path: &Path,
     comm_d_new: Commitment,
 ) -> Result<()> {
+    remove_encoded_data_with_sink::<Tree, _>(
+        config,
+        sector_key_path,
+        sector_key_cache_path,
+        replica_path,
+        replica_cache_path,
+        data_path,
+        comm_d_new,
+        io::sink(),
+    )?;
+
+    Ok(())
+}
+
+/// Like [`remove_encoded_data`], also writing the sector key to `sink` as it is written to
+/// `sector_key_path`. The replica and the data are read in windows, so that memory use doesn't
+/// grow with the sector size. Returns the flushed `sink`.
+#[allow(clippy::too_many_arguments)]
+pub fn remove_encoded_data_with_sink<
+    Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>,
+    W: Write + Send,
+>(
+    config: SectorUpdateConfig,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    replica_path: &Path,
+    replica_cache_path: &Path,
+    data_path: &Path,
+    comm_d_new: Commitment,
+    mut sink: W,
+) -> Result<W> {
     info!("remove_data:start");
 
     let p_aux = get_p_aux::<Tree>(replica_cache_path)?;
@@ -257,7 +296,7 @@ pub fn remove_encoded_data<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>
         get_new_configs_from_t_aux_old::<Tree>(&t_aux, sector_key_cache_path, config.nodes_count)?;
 
     let nodes_count = config.nodes_count;
-    let tree_r_last_new = EmptySectorUpdate::<Tree>::remove_encoded_data(
+    let tree_r_last_new = EmptySectorUpdate::<Tree>::remove_encoded_data_with_sink(
         nodes_count,
         sector_key_path,
         sector_key_cache_path,
@@ -269,6 +308,7 @@ pub fn remove_encoded_data<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>
         comm_d_new.into(),
         <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
         usize::from(config.h_select),
+        &mut sink,
     )?;
 
     // Persist p_aux and t_aux into the sector_key_cache_path here
@@ -278,7 +318,158 @@ pub fn remove_encoded_data<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>
     persist_t_aux::<Tree>(&t_aux, sector_key_cache_path)?;
 
     info!("remove_data:finish");
-    Ok(())
+    Ok(sink)
+}
+
+/// Regenerates the sector key (the CC replica) of the updated replica at `replica_path` from the
+/// deal data at `data_path`, e.g. to roll the sector back to CC, see `remove_encoded_data`.
+/// `replica_cache_path` is the cache of the sector as it was sealed. Returns the comm_r of the
+/// sector key, which is checked to be the one the sector was sealed with.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_sector_key_from_replica<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    porep_config: PoRepConfig,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    replica_path: &Path,
+    replica_cache_path: &Path,
+    data_path: &Path,
+    comm_d_new: Commitment,
+) -> Result<Commitment> {
+    remove_encoded_data::<Tree>(
+        SectorUpdateConfig::from_porep_config(porep_config),
+        sector_key_path,
+        sector_key_cache_path,
+        replica_path,
+        replica_cache_path,
+        data_path,
+        comm_d_new,
+    )?;
+
+    check_sector_key_comm_r::<Tree>(replica_cache_path, sector_key_cache_path)
+}
+
+/// Like [`generate_sector_key_from_replica`], with the deal data read from `data`, e.g. as it is
+/// unsealed, rather than from a file.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_sector_key_from_replica_with_reader<
+    Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>,
+    R: Read + Send,
+>(
+    porep_config: PoRepConfig,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    replica_path: &Path,
+    replica_cache_path: &Path,
+    data: R,
+    comm_d_new: Commitment,
+) -> Result<Commitment> {
+    info!("generate_sector_key_from_replica_with_reader:start");
+    let config = SectorUpdateConfig::from_porep_config(porep_config);
+
+    let p_aux = get_p_aux::<Tree>(replica_cache_path)?;
+    let t_aux = get_t_aux::<Tree>(replica_cache_path)?;
+
+    let (_, tree_r_last_new_config) =
+        get_new_configs_from_t_aux_old::<Tree>(&t_aux, sector_key_cache_path, config.nodes_count)?;
+
+    let tree_r_last_new = EmptySectorUpdate::<Tree>::remove_encoded_data_from_reader(
+        config.nodes_count,
+        sector_key_path,
+        sector_key_cache_path,
+        replica_path,
+        data,
+        tree_r_last_new_config,
+        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
+        comm_d_new.into(),
+        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
+        usize::from(config.h_select),
+    )?;
+
+    // Persist p_aux and t_aux into the sector_key_cache_path here
+    let mut p_aux = p_aux;
+    p_aux.comm_r_last = tree_r_last_new;
+    persist_p_aux::<Tree>(&p_aux, sector_key_cache_path)?;
+    persist_t_aux::<Tree>(&t_aux, sector_key_cache_path)?;
+
+    let comm_r = check_sector_key_comm_r::<Tree>(replica_cache_path, sector_key_cache_path)?;
+
+    info!("generate_sector_key_from_replica_with_reader:finish");
+    Ok(comm_r)
+}
+
+// The comm_r of the sector key regenerated into `sector_key_cache_path`, which must be the one
+// recorded in `replica_cache_path` when sealing.
+fn check_sector_key_comm_r<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    replica_cache_path: &Path,
+    sector_key_cache_path: &Path,
+) -> Result<Commitment> {
+    let sealed = get_p_aux::<Tree>(replica_cache_path)?;
+    let regenerated = get_p_aux::<Tree>(sector_key_cache_path)?;
+    ensure!(
+        regenerated.comm_r_last == sealed.comm_r_last,
+        "the regenerated sector key doesn't match the sealed one"
+    );
+
+    let comm_r =
+        <Tree::Hasher as Hasher>::Function::hash2(&regenerated.comm_c, &regenerated.comm_r_last);
+    let mut out = [0; 32];
+    comm_r.write_bytes(&mut out)?;
+
+    Ok(out)
+}
+
+/// Decodes the deal data of the updated replica at `replica_path` into `out_data_path`, see
+/// `decode_from`.
+pub fn decode_sector_data<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    porep_config: PoRepConfig,
+    out_data_path: &Path,
+    replica_path: &Path,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    comm_d_new: Commitment,
+) -> Result<()> {
+    decode_from::<Tree>(
+        SectorUpdateConfig::from_porep_config(porep_config),
+        out_data_path,
+        replica_path,
+        sector_key_path,
+        sector_key_cache_path,
+        comm_d_new,
+    )
+}
+
+/// Like [`decode_sector_data`], writing the deal data to `out` rather than to a file. The replica
+/// and the sector key are read in windows rather than mapped in memory, for large sectors. Returns
+/// the flushed `out`.
+pub fn decode_sector_data_to<
+    Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>,
+    W: Write + Send,
+>(
+    porep_config: PoRepConfig,
+    mut out: W,
+    replica_path: &Path,
+    sector_key_path: &Path,
+    sector_key_cache_path: &Path,
+    comm_d_new: Commitment,
+) -> Result<W> {
+    info!("decode_sector_data_to:start");
+    let config = SectorUpdateConfig::from_porep_config(porep_config);
+
+    let p_aux = get_p_aux::<Tree>(sector_key_cache_path)?;
+
+    EmptySectorUpdate::<Tree>::decode_into(
+        config.nodes_count,
+        &mut out,
+        replica_path,
+        sector_key_path,
+        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_c.into_bytes())?,
+        comm_d_new.into(),
+        <Tree::Hasher as Hasher>::Domain::try_from_bytes(&p_aux.comm_r_last.into_bytes())?,
+        usize::from(config.h_select),
+    )?;
+
+    info!("decode_sector_data_to:finish");
+    Ok(out)
 }
 
 /// Generate a single vanilla partition proof for a specified partition.
@@ -627,3 +818,194 @@ pub fn verify_empty_sector_update_proof<Tree: 'static + MerkleTreeTrait<Hasher =
 
     Ok(valid)
 }
+
+/// Returns the public inputs of the partition proofs of the update of a sector, e.g. to verify
+/// an aggregate of update proofs with `verify_aggregate_sector_update_proofs`.
+pub fn get_sector_update_inputs<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    porep_config: PoRepConfig,
+    comm_r_old: Commitment,
+    comm_r_new: Commitment,
+    comm_d_new: Commitment,
+) -> Result<Vec<Vec<Fr>>> {
+    let comm_r_old_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_old)?;
+    let comm_r_new_safe = <TreeRHasher as Hasher>::Domain::try_from_bytes(&comm_r_new)?;
+
+    let comm_d_new_safe = DefaultPieceDomain::try_from_bytes(&comm_d_new)?;
+
+    let config = SectorUpdateConfig::from_porep_config(porep_config);
+    let partitions = usize::from(config.update_partitions);
+    let public_inputs: storage_proofs_update::PublicInputs = PublicInputs {
+        k: partitions,
+        comm_r_old: comm_r_old_safe,
+        comm_d_new: comm_d_new_safe,
+        comm_r_new: comm_r_new_safe,
+        h: usize::from(config.h_select),
+    };
+    let public_params = PublicParams::from_sector_size(u64::from(config.sector_size));
+
+    (0..partitions)
+        .map(|k| {
+            EmptySectorUpdateCompound::<Tree>::generate_public_inputs(
+                &public_inputs,
+                &public_params,
+                Some(k),
+            )
+        })
+        .collect()
+}
+
+/// Given a porep_config and a list of empty sector update proofs, this method aggregates those
+/// proofs with SnarkPack (naively padding the count if necessary up to a power of 2) and returns
+/// the aggregate proof bytes, like `aggregate_seal_commit_proofs`.
+///
+/// # Arguments
+///
+/// * `porep_config` - the porep config of the updated sectors.
+/// * `proofs` - an ordered list of update proofs returned from `generate_empty_sector_update_proof`.
+/// * `sector_update_inputs` - a flattened and ordered list of the public inputs of the proofs, see
+///    `get_sector_update_inputs`.
+/// * `aggregate_version` - the SnarkPack version of the aggregate proof.
+pub fn aggregate_empty_sector_update_proofs<
+    Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>,
+>(
+    porep_config: PoRepConfig,
+    proofs: &[EmptySectorUpdateProof],
+    sector_update_inputs: &[Vec<Fr>],
+    aggregate_version: groth16::aggregate::AggregateVersion,
+) -> Result<AggregateSnarkProof> {
+    info!("aggregate_empty_sector_update_proofs:start");
+
+    ensure!(!proofs.is_empty(), "cannot aggregate with empty proofs");
+
+    let config = SectorUpdateConfig::from_porep_config(porep_config);
+    let partitions = usize::from(config.update_partitions);
+    ensure!(
+        sector_update_inputs.len() == proofs.len() * partitions,
+        "{} public inputs for the {} partitions of {} proofs",
+        sector_update_inputs.len(),
+        partitions,
+        proofs.len()
+    );
+
+    let verifying_key = get_empty_sector_update_verifying_key::<Tree>(porep_config)?;
+    let mut groth_proofs = proofs
+        .iter()
+        .try_fold(Vec::new(), |mut acc, proof| -> Result<_> {
+            acc.extend(
+                MultiProof::new_from_bytes(Some(partitions), &proof.0, &verifying_key)?
+                    .circuit_proofs,
+            );
+
+            Ok(acc)
+        })?;
+    trace!(
+        "aggregate_empty_sector_update_proofs called with {} proofs containing {} partition proofs",
+        proofs.len(),
+        groth_proofs.len(),
+    );
+
+    let target_proofs_len = get_aggregate_target_len(groth_proofs.len());
+    ensure!(
+        target_proofs_len > 1,
+        "cannot aggregate less than two proofs"
+    );
+
+    // If we're not at the pow2 target, duplicate the last proof until we are.
+    pad_proofs_to_target(&mut groth_proofs, target_proofs_len)?;
+
+    let srs_prover_key = get_empty_sector_update_srs_key::<Tree>(porep_config, groth_proofs.len())?;
+    let aggregate_proof = EmptySectorUpdateCompound::<Tree>::aggregate_proofs(
+        &srs_prover_key,
+        &hash_sector_update_inputs(sector_update_inputs),
+        groth_proofs.as_slice(),
+        aggregate_version,
+    )?;
+    let mut aggregate_proof_bytes = Vec::new();
+    aggregate_proof.write(&mut aggregate_proof_bytes)?;
+
+    info!("aggregate_empty_sector_update_proofs:finish");
+
+    Ok(aggregate_proof_bytes)
+}
+
+/// Given a porep_config, an aggregate proof and the flattened list of the public inputs of the
+/// aggregated proofs, this method verifies the aggregate empty sector update proof.
+///
+/// # Arguments
+///
+/// * `porep_config` - the porep config of the updated sectors.
+/// * `aggregate_proof_bytes` - the returned aggregate proof from
+///    `aggregate_empty_sector_update_proofs`.
+/// * `sector_update_inputs` - a flattened and ordered list of the public inputs, which must match
+///    the ordering of the update proofs when aggregated.
+/// * `aggregate_version` - the SnarkPack version the aggregate proof was generated with.
+pub fn verify_aggregate_sector_update_proofs<
+    Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>,
+>(
+    porep_config: PoRepConfig,
+    aggregate_proof_bytes: AggregateSnarkProof,
+    sector_update_inputs: Vec<Vec<Fr>>,
+    aggregate_version: groth16::aggregate::AggregateVersion,
+) -> Result<bool> {
+    info!("verify_aggregate_sector_update_proofs:start");
+
+    let aggregate_proof =
+        groth16::aggregate::AggregateProof::read(std::io::Cursor::new(&aggregate_proof_bytes))?;
+
+    let aggregated_proofs_len = aggregate_proof.tmipp.gipa.nproofs as usize;
+
+    ensure!(
+        !sector_update_inputs.is_empty(),
+        "cannot verify with empty inputs"
+    );
+    ensure!(
+        aggregated_proofs_len > 1,
+        "cannot verify less than two proofs"
+    );
+    ensure!(
+        aggregated_proofs_len == aggregated_proofs_len.next_power_of_two(),
+        "cannot verify non-pow2 aggregate sector update proofs"
+    );
+    ensure!(
+        sector_update_inputs.len() <= aggregated_proofs_len,
+        "{} public inputs for {} aggregated proofs",
+        sector_update_inputs.len(),
+        aggregated_proofs_len
+    );
+
+    let hashed_inputs = hash_sector_update_inputs(&sector_update_inputs);
+
+    // Each partition proof has one vector of public inputs, duplicated like the proofs.
+    let sector_update_inputs =
+        pad_inputs_to_target(&sector_update_inputs, 1, aggregated_proofs_len)?;
+
+    let verifying_key = get_empty_sector_update_verifying_key::<Tree>(porep_config)?;
+    let srs_verifier_key =
+        get_empty_sector_update_srs_verifier_key::<Tree>(porep_config, aggregated_proofs_len)?;
+
+    trace!("start verifying aggregate proof");
+    let result = EmptySectorUpdateCompound::<Tree>::verify_aggregate_proofs(
+        &srs_verifier_key,
+        &verifying_key,
+        &hashed_inputs,
+        sector_update_inputs.as_slice(),
+        &aggregate_proof,
+        aggregate_version,
+    )?;
+    trace!("end verifying aggregate proof");
+
+    info!("verify_aggregate_sector_update_proofs:finish");
+
+    Ok(result)
+}
+
+// Hashes the public inputs of the aggregated update proofs into the digest for the aggregate
+// proof method, the counterpart of the seeds and comm_rs of seal proofs.
+fn hash_sector_update_inputs(sector_update_inputs: &[Vec<Fr>]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for input in sector_update_inputs.iter().flatten() {
+        hasher.update(&fr_into_bytes(input));
+    }
+
+    hasher.finalize().into()
+}
@@ -4,11 +4,21 @@ use anyhow::{Context, Result};
 use blstrs::Scalar as Fr;
 use filecoin_hashers::{Domain, Hasher};
 use fr32::{bytes_into_fr, fr_into_bytes};
+use log::info;
 use merkletree::merkle::{get_merkle_tree_leafs, get_merkle_tree_len};
-use storage_proofs_core::merkle::{get_base_tree_count, MerkleTreeTrait};
+use storage_proofs_core::{
+    merkle::{get_base_tree_count, MerkleTreeTrait},
+    sector::SectorId,
+    tenant,
+    util::{auto_rows_to_discard, default_rows_to_discard, total_memory},
+};
+use storage_proofs_porep::stacked;
 use typenum::Unsigned;
 
-use crate::types::{Commitment, SectorSize};
+use crate::{
+    constants::{DefaultTreeHasher, WINDOW_POST_SECTOR_COUNT},
+    types::{Commitment, ProverId, SectorSize, Ticket},
+};
 
 pub fn as_safe_commitment<H: Domain, T: AsRef<str>>(
     comm: &[u8; 32],
@@ -27,6 +37,29 @@ pub fn commitment_from_fr(fr: Fr) -> Commitment {
     commitment
 }
 
+/// Derives the replica id of a sector, as used by PC1 to label the SDR graph.
+///
+/// `porep_seed` is the `porep_id` of the sector's `PoRepConfig`. The replica id is the SHA256 of
+/// the concatenated inputs (with the sector id in big endian), truncated to a valid field element,
+/// and returned in its little endian byte representation.
+pub fn generate_replica_id(
+    prover_id: &ProverId,
+    sector_id: SectorId,
+    ticket: &Ticket,
+    comm_d: Commitment,
+    porep_seed: &[u8; 32],
+) -> Commitment {
+    let replica_id = stacked::generate_replica_id::<DefaultTreeHasher, _>(
+        prover_id,
+        sector_id.into(),
+        ticket,
+        comm_d,
+        porep_seed,
+    );
+
+    commitment_from_fr(replica_id.into())
+}
+
 pub fn get_base_tree_size<Tree: MerkleTreeTrait>(sector_size: SectorSize) -> Result<usize> {
     let base_tree_leaves = u64::from(sector_size) as usize
         / size_of::<<Tree::Hasher as Hasher>::Domain>()
@@ -38,3 +71,67 @@ pub fn get_base_tree_size<Tree: MerkleTreeTrait>(sector_size: SectorSize) -> Res
 pub fn get_base_tree_leafs<Tree: MerkleTreeTrait>(base_tree_size: usize) -> Result<usize> {
     get_merkle_tree_leafs(base_tree_size, Tree::Arity::to_usize())
 }
+
+/// Returns the 'rows_to_discard' for the tree_r_last of a new sector. With
+/// `FIL_PROOFS_ROWS_TO_DISCARD_AUTO`, this is chosen such that the trees of all sectors of a
+/// Window PoSt partition fit into half of the memory of this machine.
+pub fn tree_r_last_rows_to_discard<Tree: MerkleTreeTrait>(
+    sector_size: SectorSize,
+) -> Result<usize> {
+    let base_tree_size = get_base_tree_size::<Tree>(sector_size)?;
+    let base_tree_leafs = get_base_tree_leafs::<Tree>(base_tree_size)?;
+    let arity = Tree::Arity::to_usize();
+
+    let memory = match total_memory() {
+        Some(memory) if tenant::settings().rows_to_discard_auto => memory,
+        _ => return Ok(default_rows_to_discard(base_tree_leafs, arity)),
+    };
+    let sectors = WINDOW_POST_SECTOR_COUNT
+        .read()
+        .expect("WINDOW_POST_SECTOR_COUNT poisoned")
+        .get(&u64::from(sector_size))
+        .copied()
+        .with_context(|| format!("unknown sector size {}", u64::from(sector_size)))?;
+
+    let rows_to_discard = auto_rows_to_discard(
+        base_tree_leafs,
+        arity,
+        get_base_tree_count::<Tree>(),
+        sectors,
+        memory / 2,
+    )?;
+    info!(
+        "tree_r_last rows_to_discard={} for {} sectors per partition and {} bytes of memory",
+        rows_to_discard, sectors, memory
+    );
+
+    Ok(rows_to_discard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_replica_id_vectors() {
+        let replica_id =
+            generate_replica_id(&[0; 32], SectorId::from(0), &[0; 32], [0; 32], &[0; 32]);
+        assert_eq!(
+            replica_id,
+            [
+                183, 7, 36, 21, 69, 163, 70, 38, 90, 171, 31, 251, 50, 255, 100, 181, 91, 248, 248,
+                220, 27, 86, 164, 110, 243, 60, 227, 209, 93, 177, 29, 51
+            ]
+        );
+
+        let replica_id =
+            generate_replica_id(&[1; 32], SectorId::from(42), &[2; 32], [3; 32], &[4; 32]);
+        assert_eq!(
+            replica_id,
+            [
+                103, 221, 179, 22, 244, 199, 142, 27, 40, 1, 67, 8, 252, 162, 128, 95, 39, 1, 165,
+                164, 171, 27, 220, 110, 76, 125, 91, 4, 234, 63, 32, 54
+            ]
+        );
+    }
+}
@@ -1,13 +1,16 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use anyhow::{ensure, Context, Result};
-use filecoin_hashers::Hasher;
-use log::info;
+use filecoin_hashers::{Domain, HashFunction, Hasher};
+use log::{info, warn};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use storage_proofs_core::{
     compound_proof::{self, CompoundProof},
     merkle::MerkleTreeTrait,
     multi_proof::MultiProof,
+    priority::{enter_priority_class, PriorityClass},
     sector::SectorId,
+    tenant::{current_tenant, with_current_tenant},
 };
 use storage_proofs_post::fallback::{
     self, FallbackPoSt, FallbackPoStCompound, PrivateSector, PublicSector,
@@ -15,16 +18,18 @@ use storage_proofs_post::fallback::{
 
 use crate::{
     api::{
-        as_safe_commitment, get_partitions_for_window_post, partition_vanilla_proofs,
-        single_partition_vanilla_proofs,
+        as_safe_commitment, generate_fallback_sector_challenges,
+        generate_partition_sector_challenges, generate_single_vanilla_proof,
+        get_num_partition_for_fallback_post, get_partitions_for_window_post,
+        partition_vanilla_proofs, single_partition_vanilla_proofs,
     },
     caches::{get_post_params, get_post_verifying_key},
     parameters::window_post_setup_params,
     types::{
         ChallengeSeed, FallbackPoStSectorProof, PoStConfig, PrivateReplicaInfo, ProverId,
-        PublicReplicaInfo, SnarkProof,
+        PublicReplicaInfo, SnarkProof, WindowPoStOutput, WindowPoStToVerify,
     },
-    PartitionSnarkProof, PoStType,
+    PartitionSnarkProof, PoStType, SINGLE_PARTITION_PROOF_LEN,
 };
 
 /// Generates a Window proof-of-spacetime with provided vanilla proofs.
@@ -35,6 +40,7 @@ pub fn generate_window_post_with_vanilla<Tree: 'static + MerkleTreeTrait>(
     vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
 ) -> Result<SnarkProof> {
     info!("generate_window_post_with_vanilla:start");
+    let _priority = enter_priority_class(PriorityClass::WindowPost);
     ensure!(
         post_config.typ == PoStType::Window,
         "invalid post config type"
@@ -103,6 +109,7 @@ pub fn generate_window_post<Tree: 'static + MerkleTreeTrait>(
     prover_id: ProverId,
 ) -> Result<SnarkProof> {
     info!("generate_window_post:start");
+    let _priority = enter_priority_class(PriorityClass::WindowPost);
     ensure!(
         post_config.typ == PoStType::Window,
         "invalid post config type"
@@ -154,6 +161,7 @@ pub fn generate_window_post<Tree: 'static + MerkleTreeTrait>(
             tree,
             comm_c,
             comm_r_last,
+            rows_to_discard: replica.rows_to_discard(),
         });
     }
 
@@ -175,6 +183,113 @@ pub fn generate_window_post<Tree: 'static + MerkleTreeTrait>(
     proof.to_vec()
 }
 
+/// Generates a Window proof-of-spacetime of the sectors of `replicas` that can be proven, rather
+/// than failing if any of them can't, e.g. because its replica can't be read. The sectors that
+/// can't be proven are left out of the proof and returned with it, to be declared faulty. The
+/// proof is that of the remaining sectors, as `generate_window_post` would generate it for them.
+///
+/// Fails if none of the sectors can be proven.
+pub fn generate_window_post_skipping_faults<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
+    prover_id: ProverId,
+) -> Result<WindowPoStOutput> {
+    info!("generate_window_post_skipping_faults:start");
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+
+    let mut faulty_sectors = BTreeSet::new();
+    // The challenges of a sector depend on its position among the proven sectors, so that the
+    // rest are proven again once a sector is left out.
+    let vanilla_proofs = loop {
+        let sector_ids: Vec<SectorId> = replicas
+            .keys()
+            .filter(|sector_id| !faulty_sectors.contains(*sector_id))
+            .copied()
+            .collect();
+        ensure!(
+            !sector_ids.is_empty(),
+            "all {} sectors are faulty",
+            faulty_sectors.len()
+        );
+
+        let challenges = generate_fallback_sector_challenges::<Tree>(
+            post_config,
+            randomness,
+            &sector_ids,
+            prover_id,
+        )?;
+        let tenant = current_tenant();
+        let proven: Vec<_> = sector_ids
+            .par_iter()
+            .map(|sector_id| {
+                with_current_tenant(tenant.clone(), || {
+                    let proof = prove_sector(
+                        post_config,
+                        *sector_id,
+                        &replicas[sector_id],
+                        &challenges[sector_id],
+                    );
+                    if let Err(err) = &proof {
+                        warn!("faulty sector {:?}: {:?}", sector_id, err);
+                    }
+
+                    (*sector_id, proof)
+                })
+            })
+            .collect();
+
+        let faults_before = faulty_sectors.len();
+        let mut vanilla_proofs = Vec::with_capacity(proven.len());
+        for (sector_id, proof) in proven {
+            match proof {
+                Ok(proof) => vanilla_proofs.push(proof),
+                Err(_) => {
+                    faulty_sectors.insert(sector_id);
+                }
+            }
+        }
+        if faulty_sectors.len() == faults_before {
+            break vanilla_proofs;
+        }
+    };
+
+    let proof =
+        generate_window_post_with_vanilla(post_config, randomness, prover_id, vanilla_proofs)?;
+
+    info!(
+        "generate_window_post_skipping_faults:finish: {} faulty sectors",
+        faulty_sectors.len()
+    );
+
+    Ok(WindowPoStOutput {
+        proof,
+        faulty_sectors,
+    })
+}
+
+// The vanilla proof of a sector, if its replica and tree_r_last match its comm_r.
+fn prove_sector<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    sector_id: SectorId,
+    replica: &PrivateReplicaInfo<Tree>,
+    challenges: &[u64],
+) -> Result<FallbackPoStSectorProof<Tree>> {
+    let comm_r = <Tree::Hasher as Hasher>::Function::hash2(
+        &replica.safe_comm_c(),
+        &replica.safe_comm_r_last(),
+    );
+    ensure!(
+        comm_r == replica.safe_comm_r()?,
+        "p_aux doesn't match comm_r"
+    );
+
+    generate_single_vanilla_proof(post_config, sector_id, replica, challenges)
+}
+
 /// Verifies a window proof-of-spacetime.
 pub fn verify_window_post<Tree: 'static + MerkleTreeTrait>(
     post_config: &PoStConfig,
@@ -204,23 +319,10 @@ pub fn verify_window_post<Tree: 'static + MerkleTreeTrait>(
     let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
         FallbackPoStCompound::setup(&setup_params)?;
 
-    let pub_sectors: Vec<_> = replicas
-        .iter()
-        .map(|(sector_id, replica)| {
-            let comm_r = replica.safe_comm_r().with_context(|| {
-                format!("verify_window_post: safe_comm_r failed: {:?}", sector_id)
-            })?;
-            Ok(PublicSector {
-                id: *sector_id,
-                comm_r,
-            })
-        })
-        .collect::<Result<_>>()?;
-
     let pub_inputs = fallback::PublicInputs {
         randomness: randomness_safe,
         prover_id: prover_id_safe,
-        sectors: pub_sectors,
+        sectors: public_sectors(replicas)?,
         k: None,
     };
 
@@ -246,6 +348,96 @@ pub fn verify_window_post<Tree: 'static + MerkleTreeTrait>(
     Ok(true)
 }
 
+fn public_sectors<D: Domain>(
+    replicas: &BTreeMap<SectorId, PublicReplicaInfo>,
+) -> Result<Vec<PublicSector<D>>> {
+    replicas
+        .iter()
+        .map(|(sector_id, replica)| {
+            let comm_r = replica.safe_comm_r().with_context(|| {
+                format!("verify_window_post: safe_comm_r failed: {:?}", sector_id)
+            })?;
+            Ok(PublicSector {
+                id: *sector_id,
+                comm_r,
+            })
+        })
+        .collect()
+}
+
+/// Verifies a batch of Window PoSts, e.g. of the PoSts of an epoch, which is considerably faster
+/// than verifying each of them with `verify_window_post`. The Groth proofs of the PoSts with the
+/// same number of partitions are verified together, sharing most of the pairings.
+///
+/// Returns false if any of the PoSts is invalid, without telling which.
+pub fn verify_window_post_batch<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    posts: &[WindowPoStToVerify<'_>],
+) -> Result<bool> {
+    info!("verify_window_post_batch:start: {} posts", posts.len());
+
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+    ensure!(!posts.is_empty(), "Cannot verify empty batch");
+
+    let mut batches: BTreeMap<Option<usize>, Vec<&WindowPoStToVerify<'_>>> = BTreeMap::new();
+    for post in posts {
+        ensure!(!post.proof.is_empty(), "Invalid proof (empty bytes) found");
+        batches
+            .entry(get_partitions_for_window_post(
+                post.replicas.len(),
+                post_config,
+            ))
+            .or_default()
+            .push(post);
+    }
+
+    let verifying_key = get_post_verifying_key::<Tree>(post_config)?;
+    let requirements = fallback::ChallengeRequirements {
+        minimum_challenge_count: post_config.challenge_count * post_config.sector_count,
+    };
+    for (partitions, batch) in batches {
+        let setup_params = compound_proof::SetupParams {
+            vanilla_params: window_post_setup_params(post_config),
+            partitions,
+            priority: false,
+        };
+        let pub_params: compound_proof::PublicParams<'_, FallbackPoSt<'_, Tree>> =
+            FallbackPoStCompound::setup(&setup_params)?;
+
+        let pub_inputs = batch
+            .iter()
+            .map(|post| {
+                Ok(fallback::PublicInputs {
+                    randomness: as_safe_commitment(&post.randomness, "randomness")?,
+                    prover_id: as_safe_commitment(&post.prover_id, "prover_id")?,
+                    sectors: public_sectors(post.replicas)?,
+                    k: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let multi_proofs = batch
+            .iter()
+            .map(|post| MultiProof::new_from_reader(partitions, post.proof, &verifying_key))
+            .collect::<Result<Vec<_>>>()?;
+
+        if !FallbackPoStCompound::batch_verify(
+            &pub_params,
+            &pub_inputs,
+            &multi_proofs,
+            &requirements,
+        )? {
+            return Ok(false);
+        }
+    }
+
+    info!("verify_window_post_batch:finish");
+
+    Ok(true)
+}
+
 /// Generates a Window proof-of-spacetime with provided vanilla proofs of a single partition.
 pub fn generate_single_window_post_with_vanilla<Tree: 'static + MerkleTreeTrait>(
     post_config: &PoStConfig,
@@ -255,6 +447,7 @@ pub fn generate_single_window_post_with_vanilla<Tree: 'static + MerkleTreeTrait>
     partition_index: usize,
 ) -> Result<PartitionSnarkProof> {
     info!("generate_single_window_post_with_vanilla:start");
+    let _priority = enter_priority_class(PriorityClass::WindowPost);
     ensure!(
         post_config.typ == PoStType::Window,
         "invalid post config type"
@@ -311,3 +504,178 @@ pub fn generate_single_window_post_with_vanilla<Tree: 'static + MerkleTreeTrait>
 
     proof.to_vec().map(PartitionSnarkProof)
 }
+
+/// Generates the proof of the partition `partition_index` of the Window proof-of-spacetime of
+/// `replicas`, all sectors of the deadline. Only the replicas of the sectors of the partition are
+/// read. The proofs of all partitions, merged with `merge_window_post_partition_proofs`, are a
+/// proof of `replicas`.
+pub fn generate_window_post_partition<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
+    prover_id: ProverId,
+    partition_index: usize,
+) -> Result<PartitionSnarkProof> {
+    info!("generate_window_post_partition:start: {}", partition_index);
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+    let partitions = get_num_partition_for_fallback_post(post_config, replicas.len());
+    ensure!(
+        partition_index < partitions,
+        "invalid partition index {} of {} partitions",
+        partition_index,
+        partitions
+    );
+
+    let partition_replicas: Vec<_> = replicas
+        .iter()
+        .skip(partition_index * post_config.sector_count)
+        .take(post_config.sector_count)
+        .collect();
+    let proof = prove_window_post_partition(
+        post_config,
+        randomness,
+        &partition_replicas,
+        prover_id,
+        partition_index,
+    )?;
+
+    info!("generate_window_post_partition:finish: {}", partition_index);
+
+    Ok(proof)
+}
+
+/// Generates the proof of the partition `partition_index` of a Window proof-of-spacetime given only
+/// `partition_replicas`, the sectors of the partition, e.g. to prove a failed partition again
+/// without the other sectors of the deadline. The proof is the one `generate_window_post_partition`
+/// generates given all sectors of the deadline, whose sectors of the partition must be
+/// `partition_replicas`.
+pub fn generate_window_post_partition_of_sectors<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    partition_replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
+    prover_id: ProverId,
+    partition_index: usize,
+) -> Result<PartitionSnarkProof> {
+    info!(
+        "generate_window_post_partition_of_sectors:start: {}",
+        partition_index
+    );
+    ensure!(
+        post_config.typ == PoStType::Window,
+        "invalid post config type"
+    );
+
+    let partition_replicas: Vec<_> = partition_replicas.iter().collect();
+    let proof = prove_window_post_partition(
+        post_config,
+        randomness,
+        &partition_replicas,
+        prover_id,
+        partition_index,
+    )?;
+
+    info!(
+        "generate_window_post_partition_of_sectors:finish: {}",
+        partition_index
+    );
+
+    Ok(proof)
+}
+
+fn prove_window_post_partition<Tree: 'static + MerkleTreeTrait>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    partition_replicas: &[(&SectorId, &PrivateReplicaInfo<Tree>)],
+    prover_id: ProverId,
+    partition_index: usize,
+) -> Result<PartitionSnarkProof> {
+    let sector_ids: Vec<SectorId> = partition_replicas
+        .iter()
+        .map(|(sector_id, _)| **sector_id)
+        .collect();
+    let challenges = generate_partition_sector_challenges::<Tree>(
+        post_config,
+        randomness,
+        &sector_ids,
+        partition_index,
+    )?;
+
+    let tenant = current_tenant();
+    let vanilla_proofs = partition_replicas
+        .par_iter()
+        .map(|(sector_id, replica)| {
+            with_current_tenant(tenant.clone(), || {
+                generate_single_vanilla_proof::<Tree>(
+                    post_config,
+                    **sector_id,
+                    replica,
+                    &challenges[*sector_id],
+                )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    generate_single_window_post_with_vanilla(
+        post_config,
+        randomness,
+        prover_id,
+        vanilla_proofs,
+        partition_index,
+    )
+}
+
+/// Generates the proofs of all partitions of the Window proof-of-spacetime of `replicas`, one at a
+/// time, see `generate_window_post_partition`. `proven` are the proofs of the first partitions,
+/// e.g. persisted by `on_partition` before a crash, which are not proven again. `on_partition` is
+/// called with the index and the proof of every partition when it is proven, and stops the proving
+/// if it fails.
+///
+/// Returns the proofs of all partitions, in order.
+pub fn generate_window_post_partitions<Tree, F>(
+    post_config: &PoStConfig,
+    randomness: &ChallengeSeed,
+    replicas: &BTreeMap<SectorId, PrivateReplicaInfo<Tree>>,
+    prover_id: ProverId,
+    mut proven: Vec<PartitionSnarkProof>,
+    mut on_partition: F,
+) -> Result<Vec<PartitionSnarkProof>>
+where
+    Tree: 'static + MerkleTreeTrait,
+    F: FnMut(usize, &PartitionSnarkProof) -> Result<()>,
+{
+    let partitions = get_num_partition_for_fallback_post(post_config, replicas.len());
+    ensure!(
+        proven.len() <= partitions,
+        "{} partitions are proven, but there are only {}",
+        proven.len(),
+        partitions
+    );
+    ensure!(
+        proven
+            .iter()
+            .all(|proof| proof.0.len() == SINGLE_PARTITION_PROOF_LEN),
+        "invalid proven partition proof length"
+    );
+    info!(
+        "generate_window_post_partitions: resuming at partition {} of {}",
+        proven.len(),
+        partitions
+    );
+
+    for partition_index in proven.len()..partitions {
+        let proof = generate_window_post_partition(
+            post_config,
+            randomness,
+            replicas,
+            prover_id,
+            partition_index,
+        )?;
+        on_partition(partition_index, &proof)?;
+        proven.push(proof);
+    }
+
+    Ok(proven)
+}
@@ -5,6 +5,7 @@ use storage_proofs_core::{
     compound_proof::{self, CompoundProof},
     merkle::MerkleTreeTrait,
     multi_proof::MultiProof,
+    priority::{enter_priority_class, PriorityClass},
     sector::SectorId,
 };
 use storage_proofs_post::fallback::{
@@ -31,6 +32,7 @@ pub fn generate_winning_post_with_vanilla<Tree: 'static + MerkleTreeTrait>(
     vanilla_proofs: Vec<FallbackPoStSectorProof<Tree>>,
 ) -> Result<SnarkProof> {
     info!("generate_winning_post_with_vanilla:start");
+    let _priority = enter_priority_class(PriorityClass::WinningPost);
     ensure!(
         post_config.typ == PoStType::Winning,
         "invalid post config type"
@@ -102,6 +104,7 @@ pub fn generate_winning_post<Tree: 'static + MerkleTreeTrait>(
     prover_id: ProverId,
 ) -> Result<SnarkProof> {
     info!("generate_winning_post:start");
+    let _priority = enter_priority_class(PriorityClass::WinningPost);
     ensure!(
         post_config.typ == PoStType::Winning,
         "invalid post config type"
@@ -159,6 +162,7 @@ pub fn generate_winning_post<Tree: 'static + MerkleTreeTrait>(
                 tree,
                 comm_c,
                 comm_r_last,
+                rows_to_discard: replica.rows_to_discard(),
             });
         }
     }
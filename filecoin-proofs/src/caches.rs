@@ -1,14 +1,17 @@
 use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
 use bellperson::groth16::{self, prepare_verifying_key};
-use blstrs::Bls12;
+use blstrs::{Bls12, G1Affine, G1Projective, G2Affine};
 use lazy_static::lazy_static;
 use log::{info, trace};
-use once_cell::sync::OnceCell;
 use rand::rngs::OsRng;
-use storage_proofs_core::{compound_proof::CompoundProof, merkle::MerkleTreeTrait};
+use storage_proofs_core::{
+    compound_proof::CompoundProof, merkle::MerkleTreeTrait, parameter_cache, parameter_shm, tenant,
+};
 use storage_proofs_porep::stacked::{StackedCompound, StackedDrg};
 use storage_proofs_post::fallback::{FallbackPoSt, FallbackPoStCircuit, FallbackPoStCompound};
 use storage_proofs_update::{
@@ -17,7 +20,7 @@ use storage_proofs_update::{
 };
 
 use crate::{
-    constants::{DefaultPieceHasher, PUBLISHED_SECTOR_SIZES},
+    constants::{DefaultPieceHasher, PUBLISHED_SECTOR_SIZES, UNPUBLISHED_SECTOR_SIZES},
     parameters::{public_params, window_post_public_params, winning_post_public_params},
     types::{PaddedBytesAmount, PoRepConfig, PoRepProofPartitions, PoStConfig, PoStType},
 };
@@ -27,9 +30,8 @@ pub type Bls12PreparedVerifyingKey = groth16::PreparedVerifyingKey<Bls12>;
 type Bls12ProverSRSKey = groth16::aggregate::ProverSRS<Bls12>;
 type Bls12VerifierSRSKey = groth16::aggregate::VerifierSRS<Bls12>;
 
-type Cache<G> = HashMap<String, Arc<G>>;
-type GrothMemCache = Cache<Bls12GrothParams>;
-type VerifyingKeyMemCache = Cache<Bls12PreparedVerifyingKey>;
+type GrothMemCache = MemCache<Bls12GrothParams>;
+type VerifyingKeyMemCache = MemCache<Bls12PreparedVerifyingKey>;
 
 const FIP0013_MIN_SNARKS: usize = 64;
 const FIP0013_MAX_SNARKS: usize = 8192;
@@ -51,12 +53,189 @@ lazy_static! {
         SRSCache::with_defaults(SRS_VERIFIER_IDENTIFIER);
 }
 
+/// Orders the uses of the entries of all memory caches.
+static CACHE_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct MemCacheEntry<G> {
+    value: Arc<G>,
+    size: usize,
+    last_used: u64,
+}
+
+/// A memory cache of parameters or verifying keys, whose entries know their approximate size in
+/// bytes and when they were last used, so that the least recently used ones can be evicted once
+/// the caches exceed `parameter_memory_cache_bytes`.
+#[derive(Debug)]
+pub struct MemCache<G> {
+    entries: HashMap<String, MemCacheEntry<G>>,
+}
+
+impl<G> Default for MemCache<G> {
+    fn default() -> Self {
+        MemCache {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<G> MemCache<G> {
+    fn get(&mut self, identifier: &str) -> Option<Arc<G>> {
+        self.entries.get_mut(identifier).map(|entry| {
+            entry.last_used = CACHE_CLOCK.fetch_add(1, Ordering::Relaxed);
+            entry.value.clone()
+        })
+    }
+
+    fn insert(&mut self, identifier: String, value: Arc<G>, size: usize) {
+        let last_used = CACHE_CLOCK.fetch_add(1, Ordering::Relaxed);
+        self.entries.insert(
+            identifier,
+            MemCacheEntry {
+                value,
+                size,
+                last_used,
+            },
+        );
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// The memory caches as seen by the eviction, where they are ranked together.
+trait LruCache {
+    fn len(&self) -> usize;
+    fn size(&self) -> usize;
+    /// When the least recently used entry was last used.
+    fn oldest_use(&self) -> Option<u64>;
+    fn evict_oldest(&mut self);
+}
+
+impl<G> LruCache for MemCache<G> {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn size(&self) -> usize {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    fn oldest_use(&self) -> Option<u64> {
+        self.entries.values().map(|entry| entry.last_used).min()
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(identifier, _)| identifier.clone());
+        if let Some(identifier) = oldest {
+            info!("evicting {} from the memory cache", identifier);
+            self.entries.remove(&identifier);
+        }
+    }
+}
+
+/// Evicts the least recently used entries of `caches` until they hold at most `budget` bytes, or
+/// only the most recently used entry is left. Entries which are in use stay alive until they are
+/// dropped.
+fn evict_lru(caches: &mut [&mut dyn LruCache], budget: usize) {
+    loop {
+        let size: usize = caches.iter().map(|cache| cache.size()).sum();
+        let len: usize = caches.iter().map(|cache| cache.len()).sum();
+        if size <= budget || len <= 1 {
+            return;
+        }
+
+        let oldest = caches
+            .iter_mut()
+            .filter_map(|cache| cache.oldest_use().map(|last_used| (last_used, cache)))
+            .min_by_key(|(last_used, _)| *last_used)
+            .map(|(_, cache)| cache);
+        match oldest {
+            Some(cache) => cache.evict_oldest(),
+            None => return,
+        }
+    }
+}
+
+/// SRS keys being generated are locked, and neither counted nor evicted until they are cached.
+impl<G> LruCache for &SRSCache<G> {
+    fn len(&self) -> usize {
+        self.cached().count()
+    }
+
+    fn size(&self) -> usize {
+        self.cached().map(|(_, size, _)| size).sum()
+    }
+
+    fn oldest_use(&self) -> Option<u64> {
+        self.cached().map(|(_, _, last_used)| last_used).min()
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .cached()
+            .min_by_key(|(_, _, last_used)| *last_used)
+            .map(|(key, _, _)| key);
+        if let Some(key) = oldest {
+            if let Ok(mut cell) = self.data[key].try_lock() {
+                info!("evicting {} from the memory cache", key);
+                *cell = None;
+            }
+        }
+    }
+}
+
+/// Evicts the least recently used parameters, verifying keys and SRS keys once the memory caches
+/// exceed `parameter_memory_cache_bytes`, 0 for no bound.
+fn evict_to_budget() {
+    let budget = tenant::settings().parameter_memory_cache_bytes;
+    if budget == 0 {
+        return;
+    }
+
+    let mut params = GROTH_PARAM_MEMORY_CACHE.lock().expect("poisoned cache");
+    let mut verifying_keys = VERIFYING_KEY_MEMORY_CACHE.lock().expect("poisoned cache");
+    let mut srs_keys = &*SRS_KEY_MEMORY_CACHE;
+    let mut srs_verifier_keys = &*SRS_VERIFIER_KEY_MEMORY_CACHE;
+    evict_lru(
+        &mut [
+            &mut *params,
+            &mut *verifying_keys,
+            &mut srs_keys,
+            &mut srs_verifier_keys,
+        ],
+        budget as usize,
+    );
+}
+
+/// Drops all parameters, verifying keys and SRS keys cached in memory, which are loaded again when
+/// they are used next. Proofs which are running keep theirs until they finish.
+pub fn purge_parameter_cache() {
+    info!("purging the parameters memory caches");
+    GROTH_PARAM_MEMORY_CACHE
+        .lock()
+        .expect("poisoned cache")
+        .clear();
+    VERIFYING_KEY_MEMORY_CACHE
+        .lock()
+        .expect("poisoned cache")
+        .clear();
+    SRS_KEY_MEMORY_CACHE.clear();
+    SRS_VERIFIER_KEY_MEMORY_CACHE.clear();
+    parameter_cache::purge_srs_cache();
+}
+
 /// We have a separate SRSCache type for srs keys since they are
 /// cached differently (as a hashmap per type, keyed by identifier
 /// consisting of sector size and pow2 num proofs to aggregate).
 #[derive(Debug, Default)]
 pub struct SRSCache<G> {
-    data: HashMap<String, OnceCell<Arc<G>>>,
+    data: HashMap<String, Mutex<Option<MemCacheEntry<G>>>>,
 }
 
 impl<G> SRSCache<G> {
@@ -67,13 +246,18 @@ impl<G> SRSCache<G> {
         let mut num_proofs_to_aggregate = PROOFS_TESTS_MIN_SNARKS;
 
         loop {
-            for sector_size in &PUBLISHED_SECTOR_SIZES {
-                let key = format!(
-                    "STACKED[{}-{}]-{}",
-                    sector_size, num_proofs_to_aggregate, identifier,
-                );
-                trace!("inserting placeholder srs key with hash key {}", key);
-                data.insert(key, OnceCell::new());
+            let sector_sizes = PUBLISHED_SECTOR_SIZES
+                .iter()
+                .chain(UNPUBLISHED_SECTOR_SIZES.iter());
+            for sector_size in sector_sizes {
+                for circuit in &["STACKED", "SECTOR-UPDATE"] {
+                    let key = format!(
+                        "{}[{}-{}]-{}",
+                        circuit, sector_size, num_proofs_to_aggregate, identifier,
+                    );
+                    trace!("inserting placeholder srs key with hash key {}", key);
+                    data.insert(key, Mutex::new(None));
+                }
             }
 
             num_proofs_to_aggregate <<= 1;
@@ -86,65 +270,111 @@ impl<G> SRSCache<G> {
     }
 
     /// Returns `None` for non existent entries, `Some(v)` for existing ones, where `v` is either
-    /// the result of running `generator` or already existing one.
-    pub fn get_or_init<F>(&self, key: &str, generator: F) -> Result<Option<&Arc<G>>>
+    /// the result of running `generator`, which returns it with its approximate size in bytes, or
+    /// already existing one.
+    pub fn get_or_init<F>(&self, key: &str, generator: F) -> Result<Option<Arc<G>>>
     where
-        F: FnOnce() -> Result<G>,
+        F: FnOnce() -> Result<(G, usize)>,
     {
         if let Some(cell) = self.data.get(key) {
             trace!("generating or waiting on specialize for {}", key);
-            let result =
-                cell.get_or_try_init(|| -> Result<Arc<G>> { Ok(Arc::new(generator()?)) })?;
-            return Ok(Some(result));
+            let mut cell = cell.lock().expect("poisoned cache");
+            let last_used = CACHE_CLOCK.fetch_add(1, Ordering::Relaxed);
+            if let Some(entry) = cell.as_mut() {
+                entry.last_used = last_used;
+                return Ok(Some(entry.value.clone()));
+            }
+
+            let (value, size) = generator()?;
+            let value = Arc::new(value);
+            *cell = Some(MemCacheEntry {
+                value: value.clone(),
+                size,
+                last_used,
+            });
+            return Ok(Some(value));
         }
 
         Ok(None)
     }
+
+    /// The keys of the cached entries with their size and last use, skipping those which are
+    /// locked.
+    fn cached(&self) -> impl Iterator<Item = (&String, usize, u64)> + '_ {
+        self.data.iter().filter_map(|(key, cell)| {
+            let cell = cell.try_lock().ok()?;
+            let entry = cell.as_ref()?;
+            Some((key, entry.size, entry.last_used))
+        })
+    }
+
+    /// Drops all entries, which are generated again when they are looked up next.
+    pub fn clear(&self) {
+        for cell in self.data.values() {
+            *cell.lock().expect("poisoned cache") = None;
+        }
+    }
 }
 
+/// Looks `identifier` up in `cache_ref`, running `generator` for the entry and its approximate
+/// size in bytes if it isn't cached.
 pub fn cache_lookup<F, G>(
-    cache_ref: &Mutex<Cache<G>>,
+    cache_ref: &Mutex<MemCache<G>>,
     identifier: String,
     generator: F,
 ) -> Result<Arc<G>>
 where
-    F: FnOnce() -> Result<G>,
+    F: FnOnce() -> Result<(G, usize)>,
     G: Send + Sync,
 {
+    // Tenants load their parameters from their own cache directory, so they get their own entries.
+    let identifier = tenant::scoped_key(identifier);
     info!("trying parameters memory cache for: {}", &identifier);
     {
-        let cache = (*cache_ref).lock().expect("poisoned cache");
+        let mut cache = (*cache_ref).lock().expect("poisoned cache");
 
         if let Some(entry) = cache.get(&identifier) {
             info!("found params in memory cache for {}", &identifier);
-            return Ok(entry.clone());
+            return Ok(entry);
         }
     }
 
     info!("no params in memory cache for {}", &identifier);
 
-    let new_entry = Arc::new(generator()?);
+    let (new_entry, size) = generator()?;
+    let new_entry = Arc::new(new_entry);
     let res = new_entry.clone();
     {
         let cache = &mut (*cache_ref).lock().expect("poisoned cache");
-        cache.insert(identifier, new_entry);
+        cache.insert(identifier, new_entry, size);
     }
+    evict_to_budget();
 
     Ok(res)
 }
 
+/// Purges the memory caches, see `purge_parameter_cache`, and detaches the process from the
+/// segments of the `parameter_cache_shm` directory, removing those no other process is attached
+/// to. Proofs which are running keep their parameters until they finish.
+pub fn release_shared_parameters() -> Result<()> {
+    purge_parameter_cache();
+
+    parameter_shm::detach_all()
+}
+
 pub fn srs_cache_lookup<F, G>(
     cache_ref: &SRSCache<G>,
     identifier: String,
     generator: F,
 ) -> Result<Arc<G>>
 where
-    F: FnOnce() -> Result<G>,
+    F: FnOnce() -> Result<(G, usize)>,
     G: Send + Sync,
 {
     trace!("srs_cache_lookup looking up {}", identifier);
     if let Some(entry) = cache_ref.get_or_init(&identifier, generator)? {
-        return Ok(entry.clone());
+        evict_to_budget();
+        return Ok(entry);
     }
 
     panic!("unknown identifier {}", identifier);
@@ -155,28 +385,53 @@ pub fn lookup_groth_params<F>(identifier: String, generator: F) -> Result<Arc<Bl
 where
     F: FnOnce() -> Result<Bls12GrothParams>,
 {
-    cache_lookup(&*GROTH_PARAM_MEMORY_CACHE, identifier, generator)
+    // The parameters are mapped, the mapping is what grows the memory of the process.
+    cache_lookup(&*GROTH_PARAM_MEMORY_CACHE, identifier, || {
+        let params = generator()?;
+        let size = params.params.len();
+        Ok((params, size))
+    })
 }
 
+/// Looks the prepared verifying key `identifier` up, preparing the one of `generator` if it isn't
+/// cached.
 #[inline]
 pub fn lookup_verifying_key<F>(
     identifier: String,
     generator: F,
 ) -> Result<Arc<Bls12PreparedVerifyingKey>>
 where
-    F: FnOnce() -> Result<Bls12PreparedVerifyingKey>,
+    F: FnOnce() -> Result<groth16::VerifyingKey<Bls12>>,
 {
     let vk_identifier = format!("{}-verifying-key", &identifier);
-    cache_lookup(&*VERIFYING_KEY_MEMORY_CACHE, vk_identifier, generator)
+    cache_lookup(&*VERIFYING_KEY_MEMORY_CACHE, vk_identifier, || {
+        let vk = generator()?;
+        // The prepared key holds the points of the public inputs twice, the G2 points it prepares
+        // are not counted.
+        let size = mem::size_of::<Bls12PreparedVerifyingKey>()
+            + vk.ic.len() * (mem::size_of::<G1Affine>() + mem::size_of::<G1Projective>());
+        Ok((prepare_verifying_key(&vk), size))
+    })
 }
 
+/// Looks the prover SRS key `identifier` for aggregating `num_proofs_to_aggregate` proofs up,
+/// running `generator` if it isn't cached.
 #[inline]
-pub fn lookup_srs_key<F>(identifier: String, generator: F) -> Result<Arc<Bls12ProverSRSKey>>
+pub fn lookup_srs_key<F>(
+    identifier: String,
+    num_proofs_to_aggregate: usize,
+    generator: F,
+) -> Result<Arc<Bls12ProverSRSKey>>
 where
     F: FnOnce() -> Result<Bls12ProverSRSKey>,
 {
     let srs_identifier = format!("{}-{}", &identifier, SRS_IDENTIFIER);
-    srs_cache_lookup::<_, Bls12ProverSRSKey>(&*SRS_KEY_MEMORY_CACHE, srs_identifier, generator)
+    srs_cache_lookup::<_, Bls12ProverSRSKey>(&*SRS_KEY_MEMORY_CACHE, srs_identifier, || {
+        // An estimate of the powers of both groups the key holds for the proofs, twice each.
+        let size =
+            4 * num_proofs_to_aggregate * (mem::size_of::<G1Affine>() + mem::size_of::<G2Affine>());
+        Ok((generator()?, size))
+    })
 }
 
 #[inline]
@@ -191,7 +446,7 @@ where
     srs_cache_lookup::<_, Bls12VerifierSRSKey>(
         &*SRS_VERIFIER_KEY_MEMORY_CACHE,
         srs_identifier,
-        generator,
+        || Ok((generator()?, mem::size_of::<Bls12VerifierSRSKey>())),
     )
 }
 
@@ -203,6 +458,7 @@ pub fn get_stacked_params<Tree: 'static + MerkleTreeTrait>(
         usize::from(PoRepProofPartitions::from(porep_config)),
         porep_config.porep_id,
         porep_config.api_version,
+        porep_config.api_features,
     )?;
 
     let parameters_generator = || {
@@ -214,10 +470,7 @@ pub fn get_stacked_params<Tree: 'static + MerkleTreeTrait>(
     };
 
     lookup_groth_params(
-        format!(
-            "STACKED[{}]",
-            usize::from(PaddedBytesAmount::from(porep_config))
-        ),
+        stacked_cache_key::<Tree>(porep_config)?,
         parameters_generator,
     )
 }
@@ -298,6 +551,7 @@ pub fn get_stacked_verifying_key<Tree: 'static + MerkleTreeTrait>(
         usize::from(PoRepProofPartitions::from(porep_config)),
         porep_config.porep_id,
         porep_config.api_version,
+        porep_config.api_features,
     )?;
 
     let vk_generator = || {
@@ -305,16 +559,20 @@ pub fn get_stacked_verifying_key<Tree: 'static + MerkleTreeTrait>(
             StackedDrg<'_, Tree, DefaultPieceHasher>,
             _,
         >>::verifying_key::<OsRng>(None, &public_params)?;
-        Ok(prepare_verifying_key(&vk))
+        Ok(vk)
     };
 
-    lookup_verifying_key(
-        format!(
-            "STACKED[{}]",
-            usize::from(PaddedBytesAmount::from(porep_config))
-        ),
-        vk_generator,
-    )
+    lookup_verifying_key(stacked_cache_key::<Tree>(porep_config)?, vk_generator)
+}
+
+/// The key of the PoRep params of `porep_config` in the memory caches. PoReps of the same sector
+/// size may have different circuits, see `PoRepConfigBuilder`.
+fn stacked_cache_key<Tree: 'static + MerkleTreeTrait>(porep_config: PoRepConfig) -> Result<String> {
+    Ok(format!(
+        "STACKED[{}-{}]",
+        usize::from(PaddedBytesAmount::from(porep_config)),
+        porep_config.get_cache_identifier::<Tree>()?
+    ))
 }
 
 pub fn get_post_verifying_key<Tree: 'static + MerkleTreeTrait>(
@@ -329,7 +587,7 @@ pub fn get_post_verifying_key<Tree: 'static + MerkleTreeTrait>(
                     FallbackPoSt<'_, Tree>,
                     FallbackPoStCircuit<Tree>,
                 >>::verifying_key::<OsRng>(None, &post_public_params)?;
-                Ok(prepare_verifying_key(&vk))
+                Ok(vk)
             };
 
             Ok(lookup_verifying_key(
@@ -348,7 +606,7 @@ pub fn get_post_verifying_key<Tree: 'static + MerkleTreeTrait>(
                     FallbackPoSt<'_, Tree>,
                     FallbackPoStCircuit<Tree>,
                 >>::verifying_key::<OsRng>(None, &post_public_params)?;
-                Ok(prepare_verifying_key(&vk))
+                Ok(vk)
             };
 
             Ok(lookup_verifying_key(
@@ -371,6 +629,7 @@ pub fn get_stacked_srs_key<Tree: 'static + MerkleTreeTrait>(
         usize::from(PoRepProofPartitions::from(porep_config)),
         porep_config.porep_id,
         porep_config.api_version,
+        porep_config.api_features,
     )?;
 
     let srs_generator = || {
@@ -391,6 +650,7 @@ pub fn get_stacked_srs_key<Tree: 'static + MerkleTreeTrait>(
             usize::from(PaddedBytesAmount::from(porep_config)),
             num_proofs_to_aggregate,
         ),
+        num_proofs_to_aggregate,
         srs_generator,
     )
 }
@@ -404,6 +664,7 @@ pub fn get_stacked_srs_verifier_key<Tree: 'static + MerkleTreeTrait>(
         usize::from(PoRepProofPartitions::from(porep_config)),
         porep_config.porep_id,
         porep_config.api_version,
+        porep_config.api_features,
     )?;
 
     let srs_verifier_generator = || {
@@ -443,7 +704,7 @@ pub fn get_empty_sector_update_verifying_key<
             EmptySectorUpdate<Tree>,
             EmptySectorUpdateCircuit<Tree>,
         >>::verifying_key::<OsRng>(None, &public_params)?;
-        Ok(prepare_verifying_key(&vk))
+        Ok(vk)
     };
 
     lookup_verifying_key(
@@ -454,3 +715,124 @@ pub fn get_empty_sector_update_verifying_key<
         vk_generator,
     )
 }
+
+pub fn get_empty_sector_update_srs_key<Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>>(
+    porep_config: PoRepConfig,
+    num_proofs_to_aggregate: usize,
+) -> Result<Arc<Bls12ProverSRSKey>> {
+    let public_params: storage_proofs_update::PublicParams =
+        PublicParams::from_sector_size(u64::from(porep_config.sector_size));
+
+    let srs_generator = || {
+        trace!(
+            "get_empty_sector_update_srs_key specializing SECTOR-UPDATE[{}-{}]",
+            usize::from(PaddedBytesAmount::from(porep_config)),
+            num_proofs_to_aggregate,
+        );
+        <EmptySectorUpdateCompound<Tree> as CompoundProof<
+            EmptySectorUpdate<Tree>,
+            EmptySectorUpdateCircuit<Tree>,
+        >>::srs_key::<rand::rngs::OsRng>(None, &public_params, num_proofs_to_aggregate)
+    };
+
+    lookup_srs_key(
+        format!(
+            "SECTOR-UPDATE[{}-{}]",
+            usize::from(PaddedBytesAmount::from(porep_config)),
+            num_proofs_to_aggregate,
+        ),
+        num_proofs_to_aggregate,
+        srs_generator,
+    )
+}
+
+pub fn get_empty_sector_update_srs_verifier_key<
+    Tree: 'static + MerkleTreeTrait<Hasher = TreeRHasher>,
+>(
+    porep_config: PoRepConfig,
+    num_proofs_to_aggregate: usize,
+) -> Result<Arc<Bls12VerifierSRSKey>> {
+    let public_params: storage_proofs_update::PublicParams =
+        PublicParams::from_sector_size(u64::from(porep_config.sector_size));
+
+    let srs_verifier_generator = || {
+        trace!(
+            "get_empty_sector_update_srs_verifier_key specializing SECTOR-UPDATE[{}-{}]",
+            usize::from(PaddedBytesAmount::from(porep_config)),
+            num_proofs_to_aggregate,
+        );
+        <EmptySectorUpdateCompound<Tree> as CompoundProof<
+            EmptySectorUpdate<Tree>,
+            EmptySectorUpdateCircuit<Tree>,
+        >>::srs_verifier_key::<rand::rngs::OsRng>(
+            None, &public_params, num_proofs_to_aggregate
+        )
+    };
+
+    lookup_srs_verifier_key(
+        format!(
+            "SECTOR-UPDATE[{}-{}]",
+            usize::from(PaddedBytesAmount::from(porep_config)),
+            num_proofs_to_aggregate,
+        ),
+        srs_verifier_generator,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_lru() {
+        let mut params = MemCache::default();
+        let mut verifying_keys = MemCache::default();
+        params.insert("a".to_string(), Arc::new(()), 100);
+        verifying_keys.insert("b".to_string(), Arc::new(()), 10);
+        params.insert("c".to_string(), Arc::new(()), 100);
+        // Using an entry makes it the most recently used one.
+        assert!(params.get("a").is_some());
+        verifying_keys.insert("d".to_string(), Arc::new(()), 10);
+
+        evict_lru(&mut [&mut params, &mut verifying_keys], 130);
+        assert!(params.entries.contains_key("a"));
+        assert!(!params.entries.contains_key("c"));
+        assert!(!verifying_keys.entries.contains_key("b"));
+        assert!(verifying_keys.entries.contains_key("d"));
+
+        // The most recently used entry is kept even if it exceeds the budget alone.
+        evict_lru(&mut [&mut params, &mut verifying_keys], 5);
+        assert_eq!(params.len() + verifying_keys.len(), 1);
+        assert!(verifying_keys.entries.contains_key("d"));
+    }
+
+    #[test]
+    fn test_evict_lru_srs_keys() {
+        let mut params = MemCache::default();
+        let srs_keys = SRSCache {
+            data: vec!["e".to_string(), "f".to_string()]
+                .into_iter()
+                .map(|key| (key, Mutex::new(None)))
+                .collect(),
+        };
+        srs_keys
+            .get_or_init("e", || Ok(((), 100)))
+            .expect("failed to generate");
+        params.insert("g".to_string(), Arc::new(()), 10);
+        srs_keys
+            .get_or_init("f", || Ok(((), 100)))
+            .expect("failed to generate");
+        assert_eq!(LruCache::size(&&srs_keys), 200);
+
+        evict_lru(&mut [&mut params, &mut &srs_keys], 150);
+        assert_eq!(LruCache::len(&&srs_keys), 1);
+        assert!(srs_keys.data["e"].lock().expect("poisoned cache").is_none());
+        assert!(params.entries.contains_key("g"));
+
+        // An evicted SRS key is generated again when it is looked up next.
+        let generated = srs_keys
+            .get_or_init("e", || Ok(((), 100)))
+            .expect("failed to generate");
+        assert!(generated.is_some());
+    }
+}
@@ -0,0 +1,112 @@
+use std::env;
+
+use serde::Serialize;
+use storage_proofs_core::{gpu_framework::usable_gpu_frameworks, tenant};
+
+/// The accelerated code paths available to this binary, as detected at runtime.
+#[derive(Clone, Debug, Serialize)]
+pub struct Capabilities {
+    /// Version of the `filecoin-proofs` crate.
+    pub version: &'static str,
+    /// Operating system the binary was built for, e.g. `linux` or `windows`.
+    pub os: &'static str,
+    /// SHA-256 implementation used for SDR labeling: `sha-ni`, `avx2`, `asm`, `arm-sha2`
+    /// or `portable`.
+    pub sha256: &'static str,
+    pub sha_ni: bool,
+    pub avx2: bool,
+    pub avx512f: bool,
+    pub neon: bool,
+    /// GPU backends compiled in (`cuda`, `opencl`).
+    pub gpu_backends: Vec<&'static str>,
+    /// GPU backends compiled in which found a device.
+    pub usable_gpu_backends: Vec<&'static str>,
+    /// Whether GPU usage is disabled through `BELLMAN_NO_GPU`.
+    pub gpu_disabled: bool,
+    /// Whether the multicore SDR, and with it hwloc, is compiled in.
+    pub multicore_sdr: bool,
+    /// Whether the multicore SDR is enabled through `FIL_PROOFS_USE_MULTICORE_SDR`.
+    pub use_multicore_sdr: bool,
+    /// Physical cores reported by hwloc, 0 if hwloc is not available.
+    pub hwloc_cores: usize,
+    /// Core groups the multicore SDR can bind to, 0 if hwloc is not available.
+    pub core_groups: usize,
+}
+
+/// Reports which accelerated paths this binary was built with and which of them are usable on
+/// the current machine.
+pub fn capabilities() -> Capabilities {
+    let mut gpu_backends = Vec::new();
+    if cfg!(feature = "cuda") {
+        gpu_backends.push("cuda");
+    }
+    if cfg!(feature = "opencl") {
+        gpu_backends.push("opencl");
+    }
+
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+    let (hwloc_cores, core_groups) = (
+        storage_proofs_porep::stacked::topology_core_count(),
+        storage_proofs_porep::stacked::core_group_count(),
+    );
+    #[cfg(not(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity")))]
+    let (hwloc_cores, core_groups) = (0, 0);
+
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        os: env::consts::OS,
+        sha256: sha2raw::implementation_name(),
+        sha_ni: x86_feature("sha"),
+        avx2: x86_feature("avx2"),
+        avx512f: x86_feature("avx512f"),
+        // NEON is part of the baseline of all aarch64 targets.
+        neon: cfg!(target_arch = "aarch64"),
+        gpu_backends,
+        usable_gpu_backends: usable_gpu_frameworks()
+            .into_iter()
+            .map(|framework| framework.name())
+            .collect(),
+        gpu_disabled: env::var("BELLMAN_NO_GPU").is_ok(),
+        multicore_sdr: cfg!(any(
+            feature = "multicore-sdr",
+            feature = "multicore-sdr-os-affinity"
+        )),
+        use_multicore_sdr: tenant::settings().use_multicore_sdr,
+        hwloc_cores,
+        core_groups,
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn x86_feature(feature: &str) -> bool {
+    match feature {
+        "sha" => is_x86_feature_detected!("sha"),
+        "avx2" => is_x86_feature_detected!("avx2"),
+        "avx512f" => is_x86_feature_detected!("avx512f"),
+        _ => false,
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn x86_feature(_feature: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let caps = capabilities();
+
+        assert!(["sha-ni", "avx2", "asm", "arm-sha2", "portable"].contains(&caps.sha256));
+        if cfg!(target_arch = "x86_64") {
+            assert_eq!(caps.sha256 == "sha-ni", caps.sha_ni);
+        }
+        if !caps.multicore_sdr {
+            assert_eq!(caps.hwloc_cores, 0);
+        }
+        assert!(serde_json::to_string(&caps).is_ok());
+    }
+}
@@ -0,0 +1,616 @@
+//! Self-describing CBOR encodings of proofs and commitments.
+//!
+//! The raw byte blobs returned by the proving APIs are only meaningful together with out-of-band
+//! knowledge (proof type, partition count, ...). The encodings in this module wrap them into CBOR
+//! maps with stable, documented keys, so that services written in other languages can parse and
+//! route them without linking this crate. All maps carry a schema version `v`, currently
+//! [`CBOR_SCHEMA_VERSION`]. Decoders reject unknown versions.
+//!
+//! The schema, in CDDL:
+//!
+//! ```text
+//! commitment      = bstr .size 32
+//!
+//! seal-pre-commit = {
+//!     v: 1,
+//!     comm_r: commitment,
+//!     comm_d: commitment,
+//! }
+//!
+//! seal-commit-phase1 = {
+//!     v: 1,
+//!     sector_size: uint,
+//!     comm_r: commitment,
+//!     comm_d: commitment,
+//!     output: bstr,                 ; CBOR encoding of the SealCommitPhase1Output
+//! }
+//!
+//! remote-c2-request = {
+//!     v: 1,
+//!     sector_size: uint,
+//!     partitions: uint,
+//!     porep_id: bstr .size 32,
+//!     api_version: tstr,            ; e.g. "1.1.0"
+//!     api_features: [* "synthetic-porep" / "non-interactive-porep"],
+//!     layers: uint,                 ; the PoRep parameters the sector was sealed with
+//!     minimum_challenges: uint,
+//!     prover_id: bstr .size 32,
+//!     sector_id: uint,
+//!     phase1_output: bstr,          ; seal-commit-phase1 map
+//! }
+//!
+//! seal-proof = {
+//!     v: 1,
+//!     partitions: uint,             ; number of concatenated 192 byte groth16 proofs
+//!     proof: bstr,
+//! }
+//!
+//! post-proof = {
+//!     v: 1,
+//!     post_type: "winning" / "window",
+//!     proof: bstr,
+//! }
+//!
+//! post-vanilla-proof = {
+//!     v: 1,
+//!     sector_size: uint,
+//!     sector_id: uint,
+//!     comm_r: commitment,
+//!     proof: bstr,                  ; CBOR encoding of the FallbackPoStSectorProof
+//! }
+//! ```
+//!
+//! The `output` of a `seal-commit-phase1` map holds the vanilla proofs of C1, which are only
+//! decoded by this crate, e.g. on a GPU prover running `seal_commit_phase2` for the sealing box.
+//! Likewise, the `proof` of a `post-vanilla-proof` map is generated by
+//! `generate_single_vanilla_proof` on the storage node holding the replica, and decoded by the
+//! node running `generate_window_post_with_vanilla`. The remaining keys let services route the
+//! maps without decoding them.
+
+use anyhow::{bail, ensure, Result};
+use filecoin_hashers::{Domain, Hasher};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use storage_proofs_core::{api_version::ApiFeature, sector::SectorId};
+
+use crate::{
+    constants::{PoRepParams, SINGLE_PARTITION_PROOF_LEN},
+    types::{
+        Commitment, FallbackPoStSectorProof, MerkleTreeTrait, PoRepConfig, PoRepProofPartitions,
+        PoStType, ProverId, SealCommitOutput, SealCommitPhase1Output, SealPreCommitOutput,
+        SectorSize, SnarkProof,
+    },
+};
+
+/// Version of the CBOR schema produced by this module.
+pub const CBOR_SCHEMA_VERSION: u64 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SealPreCommitCbor {
+    v: u64,
+    #[serde(with = "serde_bytes")]
+    comm_r: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    comm_d: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SealCommitPhase1Cbor {
+    v: u64,
+    sector_size: u64,
+    #[serde(with = "serde_bytes")]
+    comm_r: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    comm_d: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    output: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RemoteC2RequestCbor {
+    v: u64,
+    sector_size: u64,
+    partitions: u64,
+    #[serde(with = "serde_bytes")]
+    porep_id: Vec<u8>,
+    api_version: String,
+    api_features: Vec<String>,
+    layers: u64,
+    minimum_challenges: u64,
+    #[serde(with = "serde_bytes")]
+    prover_id: Vec<u8>,
+    sector_id: u64,
+    #[serde(with = "serde_bytes")]
+    phase1_output: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SealProofCbor {
+    v: u64,
+    partitions: u64,
+    #[serde(with = "serde_bytes")]
+    proof: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PoStProofCbor {
+    v: u64,
+    post_type: String,
+    #[serde(with = "serde_bytes")]
+    proof: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VanillaPoStProofCbor {
+    v: u64,
+    sector_size: u64,
+    sector_id: u64,
+    #[serde(with = "serde_bytes")]
+    comm_r: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    proof: Vec<u8>,
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(serde_cbor::from_slice(bytes)?)
+}
+
+fn ensure_version(v: u64) -> Result<()> {
+    ensure!(
+        v == CBOR_SCHEMA_VERSION,
+        "unsupported cbor schema version {} (expected {})",
+        v,
+        CBOR_SCHEMA_VERSION
+    );
+
+    Ok(())
+}
+
+fn to_commitment(bytes: &[u8], name: &str) -> Result<Commitment> {
+    ensure!(
+        bytes.len() == 32,
+        "invalid {} length {}, expected 32",
+        name,
+        bytes.len()
+    );
+    let mut commitment = [0u8; 32];
+    commitment.copy_from_slice(bytes);
+
+    Ok(commitment)
+}
+
+/// Encodes the commitments produced by `seal_pre_commit_phase2` as a `seal-pre-commit` map.
+pub fn seal_pre_commit_output_to_cbor(output: &SealPreCommitOutput) -> Result<Vec<u8>> {
+    Ok(serde_cbor::to_vec(&SealPreCommitCbor {
+        v: CBOR_SCHEMA_VERSION,
+        comm_r: output.comm_r.to_vec(),
+        comm_d: output.comm_d.to_vec(),
+    })?)
+}
+
+/// Decodes a `seal-pre-commit` map.
+pub fn seal_pre_commit_output_from_cbor(bytes: &[u8]) -> Result<SealPreCommitOutput> {
+    let decoded: SealPreCommitCbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+
+    Ok(SealPreCommitOutput {
+        comm_r: to_commitment(&decoded.comm_r, "comm_r")?,
+        comm_d: to_commitment(&decoded.comm_d, "comm_d")?,
+    })
+}
+
+/// Encodes the output of `seal_commit_phase1` of a sector of `sector_size` as a
+/// `seal-commit-phase1` map.
+pub fn seal_commit_phase1_output_to_cbor<Tree: MerkleTreeTrait>(
+    sector_size: u64,
+    output: &SealCommitPhase1Output<Tree>,
+) -> Result<Vec<u8>>
+where
+    SealCommitPhase1Output<Tree>: Serialize,
+{
+    Ok(serde_cbor::to_vec(&SealCommitPhase1Cbor {
+        v: CBOR_SCHEMA_VERSION,
+        sector_size,
+        comm_r: output.comm_r.to_vec(),
+        comm_d: output.comm_d.to_vec(),
+        output: serde_cbor::to_vec(output)?,
+    })?)
+}
+
+/// Returns the sector size of a `seal-commit-phase1` map, which selects the tree shape to decode
+/// it with, e.g. with `with_shape!`.
+pub fn seal_commit_phase1_sector_size(bytes: &[u8]) -> Result<u64> {
+    let decoded: SealCommitPhase1Cbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+
+    Ok(decoded.sector_size)
+}
+
+/// Decodes a `seal-commit-phase1` map of a sector with the tree shape `Tree`.
+pub fn seal_commit_phase1_output_from_cbor<Tree: MerkleTreeTrait>(
+    bytes: &[u8],
+) -> Result<SealCommitPhase1Output<Tree>>
+where
+    SealCommitPhase1Output<Tree>: DeserializeOwned,
+{
+    let decoded: SealCommitPhase1Cbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+
+    let output: SealCommitPhase1Output<Tree> = decode(&decoded.output)?;
+    ensure!(
+        output.comm_r[..] == decoded.comm_r[..] && output.comm_d[..] == decoded.comm_d[..],
+        "seal-commit-phase1 commitments do not match its output"
+    );
+
+    Ok(output)
+}
+
+/// A request to prove the commit of a sector on another machine, see `prove_remote_c2_request`.
+#[derive(Clone, Debug)]
+pub struct RemoteC2Request {
+    pub porep_config: PoRepConfig,
+    /// The PoRep parameters the sector was sealed with, which the proving service must resolve for
+    /// `porep_config` too, see `PoRepConfig::resolved_porep_params`.
+    pub porep_params: PoRepParams,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    /// The output of `seal_commit_phase1`, as a `seal-commit-phase1` map.
+    pub phase1_output: Vec<u8>,
+}
+
+fn api_feature_name(feature: &ApiFeature) -> &'static str {
+    match feature {
+        ApiFeature::SyntheticPoRep => "synthetic-porep",
+        ApiFeature::NonInteractivePoRep => "non-interactive-porep",
+    }
+}
+
+/// The features of a PoRep by their names, out of the combinations a `PoRepConfig` may have.
+fn api_features(names: &[String]) -> Result<&'static [ApiFeature]> {
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let features: &'static [ApiFeature] = match names.as_slice() {
+        [] => &[],
+        ["synthetic-porep"] => &[ApiFeature::SyntheticPoRep],
+        ["non-interactive-porep"] => &[ApiFeature::NonInteractivePoRep],
+        _ => bail!("unsupported api features {:?}", names),
+    };
+
+    Ok(features)
+}
+
+/// Encodes a request to prove a commit as a `remote-c2-request` map.
+pub fn remote_c2_request_to_cbor(request: &RemoteC2Request) -> Result<Vec<u8>> {
+    let porep_config = &request.porep_config;
+
+    Ok(serde_cbor::to_vec(&RemoteC2RequestCbor {
+        v: CBOR_SCHEMA_VERSION,
+        sector_size: u64::from(porep_config.sector_size),
+        partitions: usize::from(porep_config.partitions) as u64,
+        porep_id: porep_config.porep_id.to_vec(),
+        api_version: porep_config.api_version.to_string(),
+        api_features: porep_config
+            .api_features
+            .iter()
+            .map(|feature| api_feature_name(feature).to_string())
+            .collect(),
+        layers: request.porep_params.layers as u64,
+        minimum_challenges: request.porep_params.minimum_challenges,
+        prover_id: request.prover_id.to_vec(),
+        sector_id: u64::from(request.sector_id),
+        phase1_output: request.phase1_output.clone(),
+    })?)
+}
+
+/// Decodes a `remote-c2-request` map.
+pub fn remote_c2_request_from_cbor(bytes: &[u8]) -> Result<RemoteC2Request> {
+    let decoded: RemoteC2RequestCbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+    ensure!(
+        decoded.partitions > 0 && decoded.partitions <= u64::from(u8::MAX),
+        "invalid number of partitions {}",
+        decoded.partitions
+    );
+
+    Ok(RemoteC2Request {
+        porep_config: PoRepConfig {
+            sector_size: SectorSize(decoded.sector_size),
+            partitions: PoRepProofPartitions(decoded.partitions as u8),
+            porep_id: to_commitment(&decoded.porep_id, "porep_id")?,
+            api_version: decoded.api_version.parse()?,
+            api_features: api_features(&decoded.api_features)?,
+        },
+        porep_params: PoRepParams {
+            layers: decoded.layers as usize,
+            minimum_challenges: decoded.minimum_challenges,
+        },
+        prover_id: to_commitment(&decoded.prover_id, "prover_id")?,
+        sector_id: SectorId::from(decoded.sector_id),
+        phase1_output: decoded.phase1_output,
+    })
+}
+
+/// Encodes the output of `seal_commit_phase2` as a `seal-proof` map.
+pub fn seal_commit_output_to_cbor(output: &SealCommitOutput) -> Result<Vec<u8>> {
+    ensure!(
+        !output.proof.is_empty() && output.proof.len() % SINGLE_PARTITION_PROOF_LEN == 0,
+        "invalid seal proof length {}",
+        output.proof.len()
+    );
+
+    Ok(serde_cbor::to_vec(&SealProofCbor {
+        v: CBOR_SCHEMA_VERSION,
+        partitions: (output.proof.len() / SINGLE_PARTITION_PROOF_LEN) as u64,
+        proof: output.proof.clone(),
+    })?)
+}
+
+/// Decodes a `seal-proof` map.
+pub fn seal_commit_output_from_cbor(bytes: &[u8]) -> Result<SealCommitOutput> {
+    let decoded: SealProofCbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+    ensure!(decoded.partitions > 0, "seal proof has no partitions");
+    let expected_len = decoded
+        .partitions
+        .checked_mul(SINGLE_PARTITION_PROOF_LEN as u64);
+    ensure!(
+        expected_len == Some(decoded.proof.len() as u64),
+        "seal proof length {} does not match {} partitions",
+        decoded.proof.len(),
+        decoded.partitions
+    );
+
+    Ok(SealCommitOutput {
+        proof: decoded.proof,
+    })
+}
+
+fn post_type_name(post_type: &PoStType) -> &'static str {
+    match post_type {
+        PoStType::Winning => "winning",
+        PoStType::Window => "window",
+    }
+}
+
+/// Encodes a Winning or Window PoSt proof as a `post-proof` map.
+pub fn post_proof_to_cbor(post_type: &PoStType, proof: &[u8]) -> Result<Vec<u8>> {
+    ensure!(!proof.is_empty(), "invalid empty post proof");
+
+    Ok(serde_cbor::to_vec(&PoStProofCbor {
+        v: CBOR_SCHEMA_VERSION,
+        post_type: post_type_name(post_type).to_string(),
+        proof: proof.to_vec(),
+    })?)
+}
+
+/// Decodes a `post-proof` map.
+pub fn post_proof_from_cbor(bytes: &[u8]) -> Result<(PoStType, SnarkProof)> {
+    let decoded: PoStProofCbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+
+    let post_type = match decoded.post_type.as_str() {
+        "winning" => PoStType::Winning,
+        "window" => PoStType::Window,
+        other => bail!("unknown post type {:?}", other),
+    };
+
+    Ok((post_type, decoded.proof))
+}
+
+/// Encodes the vanilla PoSt proof of a sector of `sector_size`, as returned by
+/// `generate_single_vanilla_proof`, as a `post-vanilla-proof` map.
+pub fn vanilla_post_proof_to_cbor<Tree: MerkleTreeTrait>(
+    sector_size: u64,
+    proof: &FallbackPoStSectorProof<Tree>,
+) -> Result<Vec<u8>>
+where
+    FallbackPoStSectorProof<Tree>: Serialize,
+{
+    Ok(serde_cbor::to_vec(&VanillaPoStProofCbor {
+        v: CBOR_SCHEMA_VERSION,
+        sector_size,
+        sector_id: u64::from(proof.sector_id),
+        comm_r: proof.comm_r.into_bytes(),
+        proof: serde_cbor::to_vec(proof)?,
+    })?)
+}
+
+/// Returns the sector size of a `post-vanilla-proof` map, which selects the tree shape to decode
+/// it with, e.g. with `with_shape!`.
+pub fn vanilla_post_proof_sector_size(bytes: &[u8]) -> Result<u64> {
+    let decoded: VanillaPoStProofCbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+
+    Ok(decoded.sector_size)
+}
+
+/// Decodes a `post-vanilla-proof` map of a sector with the tree shape `Tree`.
+pub fn vanilla_post_proof_from_cbor<Tree: MerkleTreeTrait>(
+    bytes: &[u8],
+) -> Result<FallbackPoStSectorProof<Tree>>
+where
+    FallbackPoStSectorProof<Tree>: DeserializeOwned,
+{
+    let decoded: VanillaPoStProofCbor = decode(bytes)?;
+    ensure_version(decoded.v)?;
+
+    let proof: FallbackPoStSectorProof<Tree> = decode(&decoded.proof)?;
+    ensure!(
+        u64::from(proof.sector_id) == decoded.sector_id
+            && proof.comm_r == <Tree::Hasher as Hasher>::Domain::try_from_bytes(&decoded.comm_r)?,
+        "post-vanilla-proof sector does not match its proof"
+    );
+
+    Ok(proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use storage_proofs_core::api_version::ApiVersion;
+    use storage_proofs_post::fallback;
+
+    use crate::constants::{SectorShape2KiB, SECTOR_SIZE_2_KIB};
+
+    #[test]
+    fn test_seal_pre_commit_roundtrip() {
+        let output = SealPreCommitOutput {
+            comm_r: [1; 32],
+            comm_d: [2; 32],
+        };
+        let bytes = seal_pre_commit_output_to_cbor(&output).expect("encode failed");
+        let decoded = seal_pre_commit_output_from_cbor(&bytes).expect("decode failed");
+
+        assert_eq!(decoded.comm_r, output.comm_r);
+        assert_eq!(decoded.comm_d, output.comm_d);
+    }
+
+    #[test]
+    fn test_seal_commit_phase1_roundtrip() {
+        let output = SealCommitPhase1Output::<SectorShape2KiB> {
+            vanilla_proofs: vec![Vec::new()],
+            comm_r: [1; 32],
+            comm_d: [2; 32],
+            replica_id: Default::default(),
+            seed: [3; 32],
+            ticket: [4; 32],
+            porep_params: None,
+        };
+        let bytes =
+            seal_commit_phase1_output_to_cbor(SECTOR_SIZE_2_KIB, &output).expect("encode failed");
+        assert_eq!(
+            seal_commit_phase1_sector_size(&bytes).expect("decode failed"),
+            SECTOR_SIZE_2_KIB
+        );
+
+        let decoded: SealCommitPhase1Output<SectorShape2KiB> =
+            seal_commit_phase1_output_from_cbor(&bytes).expect("decode failed");
+        assert_eq!(decoded.vanilla_proofs.len(), 1);
+        assert_eq!(decoded.comm_r, output.comm_r);
+        assert_eq!(decoded.comm_d, output.comm_d);
+        assert_eq!(decoded.replica_id, output.replica_id);
+        assert_eq!(decoded.seed, output.seed);
+        assert_eq!(decoded.ticket, output.ticket);
+
+        let mut mismatched: SealCommitPhase1Cbor = decode(&bytes).expect("decode failed");
+        mismatched.comm_r = vec![5; 32];
+        let bytes = serde_cbor::to_vec(&mismatched).expect("encode failed");
+        assert!(seal_commit_phase1_output_from_cbor::<SectorShape2KiB>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_remote_c2_request_roundtrip() {
+        let request = RemoteC2Request {
+            porep_config: PoRepConfig {
+                sector_size: SectorSize(SECTOR_SIZE_2_KIB),
+                partitions: PoRepProofPartitions(1),
+                porep_id: [1; 32],
+                api_version: ApiVersion::V1_1_0,
+                api_features: &[ApiFeature::SyntheticPoRep],
+            },
+            porep_params: PoRepParams {
+                layers: 2,
+                minimum_challenges: 2,
+            },
+            prover_id: [2; 32],
+            sector_id: SectorId::from(3),
+            phase1_output: vec![4; 8],
+        };
+        let bytes = remote_c2_request_to_cbor(&request).expect("encode failed");
+        let decoded = remote_c2_request_from_cbor(&bytes).expect("decode failed");
+
+        let (config, expected) = (decoded.porep_config, request.porep_config);
+        assert_eq!(u64::from(config.sector_size), SECTOR_SIZE_2_KIB);
+        assert_eq!(usize::from(config.partitions), 1);
+        assert_eq!(config.porep_id, expected.porep_id);
+        assert_eq!(config.api_version, expected.api_version);
+        assert_eq!(config.api_features, expected.api_features);
+        assert_eq!(decoded.porep_params, request.porep_params);
+        assert_eq!(decoded.prover_id, request.prover_id);
+        assert_eq!(decoded.sector_id, request.sector_id);
+        assert_eq!(decoded.phase1_output, request.phase1_output);
+
+        let mut unsupported: RemoteC2RequestCbor = decode(&bytes).expect("decode failed");
+        unsupported.api_features = vec!["synthetic-porep".to_string(); 2];
+        let bytes = serde_cbor::to_vec(&unsupported).expect("encode failed");
+        assert!(remote_c2_request_from_cbor(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_seal_proof_roundtrip() {
+        let output = SealCommitOutput {
+            proof: vec![7; SINGLE_PARTITION_PROOF_LEN * 10],
+        };
+        let bytes = seal_commit_output_to_cbor(&output).expect("encode failed");
+        let decoded = seal_commit_output_from_cbor(&bytes).expect("decode failed");
+        assert_eq!(decoded.proof, output.proof);
+
+        let invalid = SealCommitOutput { proof: vec![7; 10] };
+        assert!(seal_commit_output_to_cbor(&invalid).is_err());
+
+        let empty = serde_cbor::to_vec(&SealProofCbor {
+            v: CBOR_SCHEMA_VERSION,
+            partitions: 0,
+            proof: Vec::new(),
+        })
+        .expect("encode failed");
+        assert!(seal_commit_output_from_cbor(&empty).is_err());
+
+        // 2^58 + 1 partitions of 192 bytes wrap around to the length of a single partition.
+        let overflowing = serde_cbor::to_vec(&SealProofCbor {
+            v: CBOR_SCHEMA_VERSION,
+            partitions: (1 << 58) + 1,
+            proof: vec![7; SINGLE_PARTITION_PROOF_LEN],
+        })
+        .expect("encode failed");
+        assert!(seal_commit_output_from_cbor(&overflowing).is_err());
+    }
+
+    #[test]
+    fn test_post_proof_roundtrip() {
+        let proof = vec![3; SINGLE_PARTITION_PROOF_LEN];
+        let bytes = post_proof_to_cbor(&PoStType::Window, &proof).expect("encode failed");
+        let (post_type, decoded) = post_proof_from_cbor(&bytes).expect("decode failed");
+
+        assert_eq!(post_type, PoStType::Window);
+        assert_eq!(decoded, proof);
+    }
+
+    #[test]
+    fn test_vanilla_post_proof_roundtrip() {
+        let proof = FallbackPoStSectorProof::<SectorShape2KiB> {
+            sector_id: SectorId::from(3),
+            comm_r: Default::default(),
+            vanilla_proof: fallback::Proof {
+                sectors: Vec::new(),
+            },
+        };
+        let bytes = vanilla_post_proof_to_cbor(SECTOR_SIZE_2_KIB, &proof).expect("encode failed");
+        assert_eq!(
+            vanilla_post_proof_sector_size(&bytes).expect("decode failed"),
+            SECTOR_SIZE_2_KIB
+        );
+
+        let decoded: FallbackPoStSectorProof<SectorShape2KiB> =
+            vanilla_post_proof_from_cbor(&bytes).expect("decode failed");
+        assert_eq!(decoded.sector_id, proof.sector_id);
+        assert_eq!(decoded.comm_r, proof.comm_r);
+        assert!(decoded.vanilla_proof.sectors.is_empty());
+
+        let mut mismatched: VanillaPoStProofCbor = decode(&bytes).expect("decode failed");
+        mismatched.sector_id = 4;
+        let bytes = serde_cbor::to_vec(&mismatched).expect("encode failed");
+        assert!(vanilla_post_proof_from_cbor::<SectorShape2KiB>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_reject_unknown_version() {
+        let bytes = serde_cbor::to_vec(&PoStProofCbor {
+            v: CBOR_SCHEMA_VERSION + 1,
+            post_type: "winning".to_string(),
+            proof: vec![1],
+        })
+        .expect("encode failed");
+
+        assert!(post_proof_from_cbor(&bytes).is_err());
+    }
+}
@@ -4,14 +4,17 @@ use std::sync::RwLock;
 pub use storage_proofs_core::drgraph::BASE_DEGREE as DRG_DEGREE;
 pub use storage_proofs_porep::stacked::EXP_DEGREE;
 
+use anyhow::{ensure, Result};
 use filecoin_hashers::{poseidon::PoseidonHasher, sha256::Sha256Hasher, Hasher};
 use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
 use storage_proofs_core::{
     merkle::{BinaryMerkleTree, LCTree, OctLCMerkleTree, OctMerkleTree},
     util::NODE_SIZE,
     MAX_LEGACY_POREP_REGISTERED_PROOF_ID,
 };
-use typenum::{U0, U2, U8};
+use typenum::{U0, U2, U4, U8};
 
 use crate::types::UnpaddedBytesAmount;
 
@@ -23,6 +26,7 @@ pub const SECTOR_SIZE_8_MIB: u64 = 1 << 23;
 pub const SECTOR_SIZE_16_MIB: u64 = 1 << 24;
 pub const SECTOR_SIZE_512_MIB: u64 = 1 << 29;
 pub const SECTOR_SIZE_1_GIB: u64 = 1 << 30;
+pub const SECTOR_SIZE_16_GIB: u64 = 1 << 34;
 pub const SECTOR_SIZE_32_GIB: u64 = 1 << 35;
 pub const SECTOR_SIZE_64_GIB: u64 = 1 << 36;
 
@@ -47,6 +51,11 @@ pub const PUBLISHED_SECTOR_SIZES: [u64; 10] = [
     SECTOR_SIZE_64_GIB,
 ];
 
+/// Sector sizes which are built in, but for which no parameters have been published, e.g. for
+/// devnets and research deployments. Their parameters come from a local (insecure) parameter
+/// generation, see `paramcache`.
+pub const UNPUBLISHED_SECTOR_SIZES: [u64; 1] = [SECTOR_SIZE_16_GIB];
+
 lazy_static! {
     pub static ref POREP_MINIMUM_CHALLENGES: RwLock<HashMap<u64, u64>> = RwLock::new(
         [
@@ -58,6 +67,7 @@ lazy_static! {
             (SECTOR_SIZE_16_MIB, 2),
             (SECTOR_SIZE_512_MIB, 2),
             (SECTOR_SIZE_1_GIB, 2),
+            (SECTOR_SIZE_16_GIB, 176),
             (SECTOR_SIZE_32_GIB, 176),
             (SECTOR_SIZE_64_GIB, 176),
         ]
@@ -75,6 +85,7 @@ lazy_static! {
             (SECTOR_SIZE_16_MIB, 1),
             (SECTOR_SIZE_512_MIB, 1),
             (SECTOR_SIZE_1_GIB, 1),
+            (SECTOR_SIZE_16_GIB, 10),
             (SECTOR_SIZE_32_GIB, 10),
             (SECTOR_SIZE_64_GIB, 10),
         ]
@@ -92,6 +103,7 @@ lazy_static! {
             (SECTOR_SIZE_16_MIB, 2),
             (SECTOR_SIZE_512_MIB, 2),
             (SECTOR_SIZE_1_GIB, 2),
+            (SECTOR_SIZE_16_GIB, 11),
             (SECTOR_SIZE_32_GIB, 11),
             (SECTOR_SIZE_64_GIB, 11),
         ]
@@ -112,6 +124,7 @@ lazy_static! {
             (SECTOR_SIZE_16_MIB, 2),
             (SECTOR_SIZE_512_MIB, 2),
             (SECTOR_SIZE_1_GIB, 2),
+            (SECTOR_SIZE_16_GIB, 2349), // fewer constraints per sector than 32GiB, fitting in a single partition
             (SECTOR_SIZE_32_GIB, 2349), // this gives 125,279,217 constraints, fitting in a single partition
             (SECTOR_SIZE_64_GIB, 2300), // this gives 129,887,900 constraints, fitting in a single partition
         ]
@@ -119,6 +132,55 @@ lazy_static! {
         .copied()
         .collect()
     );
+    /// The minimum number of challenges of a non-interactive PoRep, which makes up for the
+    /// challenges being known to the prover as soon as the sector is replicated.
+    pub static ref NI_POREP_MINIMUM_CHALLENGES: RwLock<HashMap<u64, u64>> = RwLock::new(
+        [
+            (SECTOR_SIZE_2_KIB, 2),
+            (SECTOR_SIZE_4_KIB, 2),
+            (SECTOR_SIZE_16_KIB, 2),
+            (SECTOR_SIZE_32_KIB, 2),
+            (SECTOR_SIZE_8_MIB, 2),
+            (SECTOR_SIZE_16_MIB, 2),
+            (SECTOR_SIZE_512_MIB, 2),
+            (SECTOR_SIZE_1_GIB, 2),
+            (SECTOR_SIZE_16_GIB, 2253),
+            (SECTOR_SIZE_32_GIB, 2253),
+            (SECTOR_SIZE_64_GIB, 2253),
+        ]
+        .iter()
+        .copied()
+        .collect()
+    );
+}
+
+/// The number of challenges proven by a partition of a non-interactive PoRep.
+pub const NI_POREP_CHALLENGES_PER_PARTITION: u64 = 18;
+
+/// The minimum number of challenges of a non-interactive PoRep of `sector_size`. Registered custom
+/// sector sizes use their interactive minimum.
+pub fn ni_porep_minimum_challenges(sector_size: u64) -> u64 {
+    let minimum = NI_POREP_MINIMUM_CHALLENGES
+        .read()
+        .expect("NI_POREP_MINIMUM_CHALLENGES poisoned")
+        .get(&sector_size)
+        .copied();
+    minimum.unwrap_or_else(|| {
+        *POREP_MINIMUM_CHALLENGES
+            .read()
+            .expect("POREP_MINIMUM_CHALLENGES poisoned")
+            .get(&sector_size)
+            .expect("unknown sector size")
+    })
+}
+
+/// The number of partitions of a non-interactive PoRep of `sector_size`.
+pub fn ni_porep_partitions(sector_size: u64) -> u8 {
+    let minimum = ni_porep_minimum_challenges(sector_size);
+    let partitions =
+        (minimum + NI_POREP_CHALLENGES_PER_PARTITION - 1) / NI_POREP_CHALLENGES_PER_PARTITION;
+
+    partitions as u8
 }
 
 /// The size of a single snark proof.
@@ -148,6 +210,7 @@ pub type DefaultOctLCTree = OctLCMerkleTree<DefaultTreeHasher>;
 // Generic shapes
 pub type SectorShapeBase = LCTree<DefaultTreeHasher, U8, U0, U0>;
 pub type SectorShapeSub2 = LCTree<DefaultTreeHasher, U8, U2, U0>;
+pub type SectorShapeSub4 = LCTree<DefaultTreeHasher, U8, U4, U0>;
 pub type SectorShapeSub8 = LCTree<DefaultTreeHasher, U8, U8, U0>;
 pub type SectorShapeTop2 = LCTree<DefaultTreeHasher, U8, U8, U2>;
 
@@ -160,6 +223,8 @@ pub type SectorShape4KiB = SectorShapeSub2;
 pub type SectorShape16MiB = SectorShapeSub2;
 pub type SectorShape1GiB = SectorShapeSub2;
 
+pub type SectorShape16GiB = SectorShapeSub4;
+
 pub type SectorShape16KiB = SectorShapeSub8;
 pub type SectorShape32GiB = SectorShapeSub8;
 
@@ -170,26 +235,222 @@ pub fn is_sector_shape_base(sector_size: u64) -> bool {
     matches!(
         sector_size,
         SECTOR_SIZE_2_KIB | SECTOR_SIZE_8_MIB | SECTOR_SIZE_512_MIB
-    )
+    ) || custom_sector_shape(sector_size) == Some(SectorShape::Base)
 }
 
 pub fn is_sector_shape_sub2(sector_size: u64) -> bool {
     matches!(
         sector_size,
         SECTOR_SIZE_4_KIB | SECTOR_SIZE_16_MIB | SECTOR_SIZE_1_GIB
-    )
+    ) || custom_sector_shape(sector_size) == Some(SectorShape::Sub2)
+}
+
+pub fn is_sector_shape_sub4(sector_size: u64) -> bool {
+    sector_size == SECTOR_SIZE_16_GIB || custom_sector_shape(sector_size) == Some(SectorShape::Sub4)
 }
 
 pub fn is_sector_shape_sub8(sector_size: u64) -> bool {
     matches!(sector_size, SECTOR_SIZE_16_KIB | SECTOR_SIZE_32_GIB)
+        || custom_sector_shape(sector_size) == Some(SectorShape::Sub8)
 }
 
 pub fn is_sector_shape_top2(sector_size: u64) -> bool {
     matches!(sector_size, SECTOR_SIZE_32_KIB | SECTOR_SIZE_64_GIB)
+        || custom_sector_shape(sector_size) == Some(SectorShape::Top2)
+}
+
+/// The generic sector shapes, see [`SectorShapeBase`] and the following.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SectorShape {
+    Base,
+    Sub2,
+    Sub4,
+    Sub8,
+    Top2,
+}
+
+impl SectorShape {
+    /// Number of base trees of tree_r_last.
+    pub fn base_tree_count(self) -> u64 {
+        match self {
+            SectorShape::Base => 1,
+            SectorShape::Sub2 => 2,
+            SectorShape::Sub4 => 4,
+            SectorShape::Sub8 => 8,
+            SectorShape::Top2 => 16,
+        }
+    }
 }
 
-/// Calls a function with the type hint of the sector shape matching the provided sector.
-/// Panics if provided with an unknown sector size.
+/// A sector size which is not published, e.g. for a devnet, with everything needed to seal and
+/// prove sectors of that size. The parameters of custom sizes come from a local (insecure)
+/// parameter generation, see `paramcache --custom`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CustomSectorSize {
+    pub sector_size: u64,
+    pub shape: SectorShape,
+    pub porep_partitions: u8,
+    pub porep_minimum_challenges: u64,
+    pub layers: usize,
+    pub window_post_sector_count: usize,
+}
+
+lazy_static! {
+    static ref CUSTOM_SECTOR_SHAPES: RwLock<HashMap<u64, SectorShape>> = Default::default();
+}
+
+/// Returns the shape of a registered custom sector size.
+pub fn custom_sector_shape(sector_size: u64) -> Option<SectorShape> {
+    CUSTOM_SECTOR_SHAPES
+        .read()
+        .expect("CUSTOM_SECTOR_SHAPES poisoned")
+        .get(&sector_size)
+        .copied()
+}
+
+/// Registers a custom sector size within this process. Registering the same size again with the
+/// same settings is a no-op. Published sector sizes can't be changed.
+pub fn register_sector_size(custom: &CustomSectorSize) -> Result<()> {
+    let sector_size = custom.sector_size;
+    ensure!(
+        !PUBLISHED_SECTOR_SIZES.contains(&sector_size)
+            && !UNPUBLISHED_SECTOR_SIZES.contains(&sector_size),
+        "sector size {} is built in and can't be registered",
+        sector_size
+    );
+
+    // Each base tree of tree_r_last is a full oct tree of at least one row above the leafs.
+    let nodes = sector_size / NODE_SIZE as u64;
+    let base_tree_count = custom.shape.base_tree_count();
+    let base_tree_nodes = nodes / base_tree_count;
+    ensure!(
+        sector_size % NODE_SIZE as u64 == 0
+            && nodes % base_tree_count == 0
+            && base_tree_nodes >= 8
+            && base_tree_nodes.is_power_of_two()
+            && base_tree_nodes.trailing_zeros() % 3 == 0,
+        "sector size {} does not fit the {:?} shape",
+        sector_size,
+        custom.shape
+    );
+    ensure!(
+        custom.porep_partitions > 0,
+        "porep_partitions must be positive"
+    );
+    ensure!(custom.layers > 0, "layers must be positive");
+    ensure!(
+        custom.porep_minimum_challenges > 0,
+        "porep_minimum_challenges must be positive"
+    );
+    ensure!(
+        custom.window_post_sector_count > 0,
+        "window_post_sector_count must be positive"
+    );
+
+    let mut shapes = CUSTOM_SECTOR_SHAPES
+        .write()
+        .expect("CUSTOM_SECTOR_SHAPES poisoned");
+    if let Some(shape) = shapes.get(&sector_size) {
+        let registered = CustomSectorSize {
+            sector_size,
+            shape: *shape,
+            porep_partitions: POREP_PARTITIONS.read().expect("POREP_PARTITIONS poisoned")
+                [&sector_size],
+            porep_minimum_challenges: POREP_MINIMUM_CHALLENGES
+                .read()
+                .expect("POREP_MINIMUM_CHALLENGES poisoned")[&sector_size],
+            layers: LAYERS.read().expect("LAYERS poisoned")[&sector_size],
+            window_post_sector_count: WINDOW_POST_SECTOR_COUNT
+                .read()
+                .expect("WINDOW_POST_SECTOR_COUNT poisoned")[&sector_size],
+        };
+        ensure!(
+            &registered == custom,
+            "sector size {} is already registered differently",
+            sector_size
+        );
+        return Ok(());
+    }
+
+    warn!(
+        "registering custom sector size {}, its parameters are not from a trusted setup",
+        sector_size
+    );
+    POREP_PARTITIONS
+        .write()
+        .expect("POREP_PARTITIONS poisoned")
+        .insert(sector_size, custom.porep_partitions);
+    POREP_MINIMUM_CHALLENGES
+        .write()
+        .expect("POREP_MINIMUM_CHALLENGES poisoned")
+        .insert(sector_size, custom.porep_minimum_challenges);
+    LAYERS
+        .write()
+        .expect("LAYERS poisoned")
+        .insert(sector_size, custom.layers);
+    WINDOW_POST_SECTOR_COUNT
+        .write()
+        .expect("WINDOW_POST_SECTOR_COUNT poisoned")
+        .insert(sector_size, custom.window_post_sector_count);
+    shapes.insert(sector_size, custom.shape);
+
+    Ok(())
+}
+
+/// The PoRep parameters of a custom network, which take precedence over the ones of its sector
+/// size for the sectors sealed with its porep id, see `PoRepConfigBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoRepParams {
+    pub layers: usize,
+    pub minimum_challenges: u64,
+}
+
+lazy_static! {
+    static ref NETWORK_POREP_PARAMS: RwLock<HashMap<(u64, [u8; 32]), PoRepParams>> =
+        Default::default();
+}
+
+/// Returns the PoRep parameters registered for sectors of `sector_size` sealed with `porep_id`.
+pub fn network_porep_params(sector_size: u64, porep_id: [u8; 32]) -> Option<PoRepParams> {
+    NETWORK_POREP_PARAMS
+        .read()
+        .expect("NETWORK_POREP_PARAMS poisoned")
+        .get(&(sector_size, porep_id))
+        .copied()
+}
+
+/// Registers the PoRep parameters of sectors of `sector_size` sealed with `porep_id` within this
+/// process. Registering the same parameters again is a no-op.
+pub(crate) fn register_porep_params(
+    sector_size: u64,
+    porep_id: [u8; 32],
+    params: PoRepParams,
+) -> Result<()> {
+    let mut registered = NETWORK_POREP_PARAMS
+        .write()
+        .expect("NETWORK_POREP_PARAMS poisoned");
+    if let Some(current) = registered.get(&(sector_size, porep_id)) {
+        ensure!(
+            *current == params,
+            "the PoRep of sector size {} is already registered differently for this porep id",
+            sector_size
+        );
+        return Ok(());
+    }
+
+    warn!(
+        "registering PoRep parameters {:?} for sector size {}, their parameters are not from a \
+         trusted setup",
+        params, sector_size
+    );
+    registered.insert((sector_size, porep_id), params);
+
+    Ok(())
+}
+
+/// Calls a function with the type hint of the sector shape matching the provided sector, which
+/// may be a registered custom sector size. Panics if provided with an unknown sector size.
 #[macro_export]
 macro_rules! with_shape {
     ($size:expr, $f:ident) => {
@@ -221,13 +482,33 @@ macro_rules! with_shape {
             _x if $size == $crate::constants::SECTOR_SIZE_1_GIB => {
               $f::<$crate::constants::SectorShape1GiB>($($args),*)
             },
+            _x if $size == $crate::constants::SECTOR_SIZE_16_GIB => {
+              $f::<$crate::constants::SectorShape16GiB>($($args),*)
+            },
             _x if $size == $crate::constants::SECTOR_SIZE_32_GIB => {
               $f::<$crate::constants::SectorShape32GiB>($($args),*)
             },
             _x if $size == $crate::constants::SECTOR_SIZE_64_GIB => {
               $f::<$crate::constants::SectorShape64GiB>($($args),*)
             },
-            _ => panic!("unsupported sector size: {}", $size),
+            _ => match $crate::constants::custom_sector_shape($size) {
+                Some($crate::constants::SectorShape::Base) => {
+                  $f::<$crate::constants::SectorShapeBase>($($args),*)
+                },
+                Some($crate::constants::SectorShape::Sub2) => {
+                  $f::<$crate::constants::SectorShapeSub2>($($args),*)
+                },
+                Some($crate::constants::SectorShape::Sub4) => {
+                  $f::<$crate::constants::SectorShapeSub4>($($args),*)
+                },
+                Some($crate::constants::SectorShape::Sub8) => {
+                  $f::<$crate::constants::SectorShapeSub8>($($args),*)
+                },
+                Some($crate::constants::SectorShape::Top2) => {
+                  $f::<$crate::constants::SectorShapeTop2>($($args),*)
+                },
+                None => panic!("unsupported sector size: {}", $size),
+            },
         }
     };
     ($size:expr, $f:ident, $($args:expr),*) => {
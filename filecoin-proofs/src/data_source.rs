@@ -0,0 +1,122 @@
+use std::fs::{self, metadata, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+
+/// Staged (unsealed) sector data, as consumed by
+/// [`seal_pre_commit_phase1_from_source`](crate::seal_pre_commit_phase1_from_source).
+///
+/// The data is written to the replica file once, which is then sealed in place, so sources that
+/// are not backed by a file don't need to be materialized to disk beforehand.
+pub trait DataSource {
+    /// Writes the data to `out_path`, replacing its contents, and returns the number of bytes
+    /// written.
+    fn write_to(&mut self, out_path: &Path) -> Result<u64>;
+}
+
+/// Data staged in a file.
+#[derive(Debug, Clone)]
+pub struct FileDataSource {
+    path: PathBuf,
+}
+
+impl FileDataSource {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileDataSource {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl DataSource for FileDataSource {
+    fn write_to(&mut self, out_path: &Path) -> Result<u64> {
+        ensure!(metadata(&self.path)?.is_file(), "in_path must be a file");
+
+        fs::copy(&self.path, out_path).with_context(|| {
+            format!(
+                "could not copy in_path={:?} to out_path={:?}",
+                self.path.display(),
+                out_path.display()
+            )
+        })
+    }
+}
+
+/// Data held in memory, e.g. a committed capacity template.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDataSource<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MemoryDataSource<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        MemoryDataSource { data }
+    }
+}
+
+impl DataSource for MemoryDataSource<'_> {
+    fn write_to(&mut self, out_path: &Path) -> Result<u64> {
+        fs::write(out_path, self.data)
+            .with_context(|| format!("could not write out_path={:?}", out_path.display()))?;
+
+        Ok(self.data.len() as u64)
+    }
+}
+
+/// Data read from a stream, e.g. spooled from the network.
+#[derive(Debug)]
+pub struct ReaderDataSource<R> {
+    reader: R,
+}
+
+impl<R: Read> ReaderDataSource<R> {
+    pub fn new(reader: R) -> Self {
+        ReaderDataSource { reader }
+    }
+}
+
+impl<R: Read> DataSource for ReaderDataSource<R> {
+    fn write_to(&mut self, out_path: &Path) -> Result<u64> {
+        let file = File::create(out_path)
+            .with_context(|| format!("could not create out_path={:?}", out_path.display()))?;
+        let mut writer = BufWriter::new(file);
+
+        let written = io::copy(&mut self.reader, &mut writer)
+            .with_context(|| format!("could not write out_path={:?}", out_path.display()))?;
+        writer.flush()?;
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_data_sources() {
+        let dir = tempdir().expect("tempdir failure");
+        let in_path = dir.path().join("in");
+        let out_path = dir.path().join("out");
+        let data = vec![7u8; 1024];
+        fs::write(&in_path, &data).expect("failed to write in_path");
+
+        let mut sources: Vec<Box<dyn DataSource + '_>> = vec![
+            Box::new(FileDataSource::new(&in_path)),
+            Box::new(MemoryDataSource::new(&data)),
+            Box::new(ReaderDataSource::new(&data[..])),
+        ];
+        for source in sources.iter_mut() {
+            fs::write(&out_path, &[1u8; 2048]).expect("failed to write out_path");
+
+            let written = source.write_to(&out_path).expect("write_to failure");
+            assert_eq!(written, data.len() as u64);
+            assert_eq!(fs::read(&out_path).expect("failed to read out_path"), data);
+        }
+
+        assert!(FileDataSource::new(dir.path()).write_to(&out_path).is_err());
+    }
+}
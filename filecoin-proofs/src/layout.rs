@@ -0,0 +1,309 @@
+//! Helpers for the conventional lotus sector storage layout.
+//!
+//! A storage root holds one directory per sector file type, and every file is named after the
+//! sector (`s-t0<miner>-<number>`):
+//!
+//! ```text
+//! <root>/sealed/s-t01000-1           replica (`out_path` / `replica_path`)
+//! <root>/cache/s-t01000-1/           cache directory (`cache_path`), incl. p_aux and t_aux
+//! <root>/unsealed/s-t01000-1         unsealed sector data (`in_path` / `out_path` of unseal)
+//! <root>/update/s-t01000-1           SnapDeals updated replica
+//! <root>/update-cache/s-t01000-1/    SnapDeals updated cache directory
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use storage_proofs_core::{cache_key::CacheKey, sector::SectorId};
+
+/// The kinds of sector files in a lotus storage root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectorFileType {
+    Sealed,
+    Cache,
+    Unsealed,
+    Update,
+    UpdateCache,
+}
+
+impl SectorFileType {
+    pub const ALL: [SectorFileType; 5] = [
+        SectorFileType::Sealed,
+        SectorFileType::Cache,
+        SectorFileType::Unsealed,
+        SectorFileType::Update,
+        SectorFileType::UpdateCache,
+    ];
+
+    /// The name of the directory (below the storage root) holding files of this type.
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            SectorFileType::Sealed => "sealed",
+            SectorFileType::Cache => "cache",
+            SectorFileType::Unsealed => "unsealed",
+            SectorFileType::Update => "update",
+            SectorFileType::UpdateCache => "update-cache",
+        }
+    }
+
+    /// Whether sectors of this type are stored as a directory rather than a single file.
+    pub fn is_dir(self) -> bool {
+        matches!(self, SectorFileType::Cache | SectorFileType::UpdateCache)
+    }
+}
+
+impl fmt::Display for SectorFileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.dir_name())
+    }
+}
+
+/// Returns the lotus file name of a sector, e.g. `s-t01000-1`.
+pub fn sector_name(miner_id: u64, sector_id: SectorId) -> String {
+    format!("s-t0{}-{}", miner_id, u64::from(sector_id))
+}
+
+/// Parses a lotus sector file name into the miner actor id and the sector number.
+pub fn parse_sector_name(name: &str) -> Result<(u64, SectorId)> {
+    let rest = name
+        .strip_prefix("s-t0")
+        .with_context(|| format!("invalid sector name {:?}", name))?;
+    let mut parts = rest.splitn(2, '-');
+    let miner_id = parts
+        .next()
+        .and_then(|miner| miner.parse::<u64>().ok())
+        .with_context(|| format!("invalid miner id in sector name {:?}", name))?;
+    let sector_id = parts
+        .next()
+        .and_then(|number| number.parse::<u64>().ok())
+        .with_context(|| format!("invalid sector number in sector name {:?}", name))?;
+
+    Ok((miner_id, SectorId::from(sector_id)))
+}
+
+/// The paths of a single sector, as passed to the sealing, unsealing and proving APIs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorPaths {
+    pub sealed: PathBuf,
+    pub cache: PathBuf,
+    pub unsealed: PathBuf,
+    pub update: PathBuf,
+    pub update_cache: PathBuf,
+}
+
+impl SectorPaths {
+    pub fn get(&self, typ: SectorFileType) -> &Path {
+        match typ {
+            SectorFileType::Sealed => &self.sealed,
+            SectorFileType::Cache => &self.cache,
+            SectorFileType::Unsealed => &self.unsealed,
+            SectorFileType::Update => &self.update,
+            SectorFileType::UpdateCache => &self.update_cache,
+        }
+    }
+
+    /// Location of the persistent aux data (`p_aux`) in the cache directory.
+    pub fn p_aux(&self) -> PathBuf {
+        self.cache.join(CacheKey::PAux.to_string())
+    }
+
+    /// Location of the temporary aux data (`t_aux`) in the cache directory.
+    pub fn t_aux(&self) -> PathBuf {
+        self.cache.join(CacheKey::TAux.to_string())
+    }
+}
+
+/// A lotus storage root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorLayout {
+    root: PathBuf,
+}
+
+impl SectorLayout {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        SectorLayout {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn dir(&self, typ: SectorFileType) -> PathBuf {
+        self.root.join(typ.dir_name())
+    }
+
+    pub fn path(&self, typ: SectorFileType, miner_id: u64, sector_id: SectorId) -> PathBuf {
+        self.dir(typ).join(sector_name(miner_id, sector_id))
+    }
+
+    pub fn sector_paths(&self, miner_id: u64, sector_id: SectorId) -> SectorPaths {
+        SectorPaths {
+            sealed: self.path(SectorFileType::Sealed, miner_id, sector_id),
+            cache: self.path(SectorFileType::Cache, miner_id, sector_id),
+            unsealed: self.path(SectorFileType::Unsealed, miner_id, sector_id),
+            update: self.path(SectorFileType::Update, miner_id, sector_id),
+            update_cache: self.path(SectorFileType::UpdateCache, miner_id, sector_id),
+        }
+    }
+
+    /// Creates the per-type directories below the storage root.
+    pub fn create_dirs(&self) -> Result<()> {
+        for typ in SectorFileType::ALL.iter() {
+            let dir = self.dir(*typ);
+            fs::create_dir_all(&dir).with_context(|| format!("could not create {:?}", dir))?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the storage directories and the sector's cache directories, returning its paths.
+    pub fn prepare_sector(&self, miner_id: u64, sector_id: SectorId) -> Result<SectorPaths> {
+        self.create_dirs()?;
+        let paths = self.sector_paths(miner_id, sector_id);
+        fs::create_dir_all(&paths.cache)
+            .with_context(|| format!("could not create {:?}", paths.cache))?;
+
+        Ok(paths)
+    }
+
+    /// Lists the sectors that have a file of the given type, sorted by miner and sector number.
+    ///
+    /// Entries which do not follow the lotus naming scheme (e.g. temporary files) are ignored.
+    pub fn list_sectors(&self, typ: SectorFileType) -> Result<Vec<(u64, SectorId)>> {
+        let dir = self.dir(typ);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut sectors = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("could not read {:?}", dir))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() != typ.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if let Ok(sector) = parse_sector_name(name) {
+                    sectors.push(sector);
+                }
+            }
+        }
+        sectors.sort();
+
+        Ok(sectors)
+    }
+}
+
+/// Moves all existing files of a sector from one set of paths to another, e.g. to import a
+/// sector sealed with custom paths into a lotus storage root. Files are renamed when possible
+/// and copied otherwise (e.g. across file systems). Existing destinations are never overwritten.
+pub fn move_sector(from: &SectorPaths, to: &SectorPaths) -> Result<()> {
+    for typ in SectorFileType::ALL.iter() {
+        let src = from.get(*typ);
+        let dst = to.get(*typ);
+        if !src.exists() || src == dst {
+            continue;
+        }
+        ensure!(!dst.exists(), "refusing to overwrite {:?}", dst);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("could not create {:?}", parent))?;
+        }
+
+        if fs::rename(src, dst).is_err() {
+            copy_recursive(src, dst)
+                .with_context(|| format!("could not copy {:?} to {:?}", src, dst))?;
+            if src.is_dir() {
+                fs::remove_dir_all(src)?;
+            } else {
+                fs::remove_file(src)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_recursive(src: &Path, dst: &Path) -> Result<()> {
+    if src.is_dir() {
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(src, dst)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sector_name_roundtrip() {
+        let name = sector_name(1000, SectorId::from(42));
+        assert_eq!(name, "s-t01000-42");
+
+        let (miner_id, sector_id) = parse_sector_name(&name).expect("parse failed");
+        assert_eq!(miner_id, 1000);
+        assert_eq!(sector_id, SectorId::from(42));
+
+        assert!(parse_sector_name("s-t01000").is_err());
+        assert!(parse_sector_name("s-t01000-x").is_err());
+        assert!(parse_sector_name("t01000-1").is_err());
+    }
+
+    #[test]
+    fn test_layout_paths_and_listing() {
+        let dir = tempfile::tempdir().expect("tempdir failed");
+        let layout = SectorLayout::new(dir.path());
+        let paths = layout
+            .prepare_sector(1000, SectorId::from(7))
+            .expect("prepare failed");
+
+        assert_eq!(paths.sealed, dir.path().join("sealed/s-t01000-7"));
+        assert_eq!(paths.t_aux(), dir.path().join("cache/s-t01000-7/t_aux"));
+        assert!(paths.cache.is_dir());
+
+        fs::write(&paths.sealed, b"replica").expect("write failed");
+        let stray = layout.dir(SectorFileType::Sealed).join("fetching");
+        fs::write(stray, b"").expect("write failed");
+        assert_eq!(
+            layout
+                .list_sectors(SectorFileType::Sealed)
+                .expect("list failed"),
+            vec![(1000, SectorId::from(7))]
+        );
+        assert_eq!(
+            layout
+                .list_sectors(SectorFileType::Cache)
+                .expect("list failed"),
+            vec![(1000, SectorId::from(7))]
+        );
+    }
+
+    #[test]
+    fn test_move_sector() {
+        let src_dir = tempfile::tempdir().expect("tempdir failed");
+        let dst_dir = tempfile::tempdir().expect("tempdir failed");
+
+        let src = SectorLayout::new(src_dir.path())
+            .prepare_sector(1000, SectorId::from(1))
+            .expect("prepare failed");
+        fs::write(&src.sealed, b"replica").expect("write failed");
+        fs::write(src.p_aux(), b"p_aux").expect("write failed");
+
+        let dst = SectorLayout::new(dst_dir.path()).sector_paths(1000, SectorId::from(1));
+        move_sector(&src, &dst).expect("move failed");
+
+        assert!(!src.sealed.exists());
+        assert_eq!(fs::read(&dst.sealed).expect("read failed"), b"replica");
+        assert_eq!(fs::read(dst.p_aux()).expect("read failed"), b"p_aux");
+        assert!(!dst.unsealed.exists());
+    }
+}
@@ -4,16 +4,22 @@
 #![allow(clippy::upper_case_acronyms)]
 
 pub mod caches;
+pub mod cbor;
 pub mod constants;
+pub mod layout;
 pub mod param;
 pub mod parameters;
 pub mod pieces;
 pub mod types;
 
 mod api;
+mod capabilities;
 mod commitment_reader;
+mod data_source;
 
 pub use api::*;
+pub use capabilities::*;
 pub use commitment_reader::*;
 pub use constants::*;
+pub use data_source::*;
 pub use types::*;
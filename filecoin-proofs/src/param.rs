@@ -7,14 +7,12 @@ use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use blake2b_simd::State as Blake2b;
 use storage_proofs_core::parameter_cache::{
-    parameter_cache_dir, CacheEntryMetadata, PARAMETER_METADATA_EXT,
+    parameter_cache_file_path, CacheEntryMetadata, PARAMETER_METADATA_EXT,
 };
 
-// Produces an absolute path to a file within the cache
+// Produces an absolute path to a file within the cache, searching its directories in order
 pub fn get_full_path_for_file_within_cache(filename: &str) -> PathBuf {
-    let mut path = parameter_cache_dir();
-    path.push(filename);
-    path
+    parameter_cache_file_path(filename)
 }
 
 // Produces a BLAKE2b checksum for a file within the cache
@@ -1,10 +1,16 @@
 use anyhow::{ensure, Result};
-use storage_proofs_core::{api_version::ApiVersion, proof::ProofScheme};
-use storage_proofs_porep::stacked::{self, LayerChallenges, StackedDrg};
+use storage_proofs_core::{
+    api_version::{ApiFeature, ApiVersion},
+    proof::ProofScheme,
+};
+use storage_proofs_porep::stacked::{self, LayerChallenges, StackedDrg, SYNTHETIC_CHALLENGE_COUNT};
 use storage_proofs_post::fallback::{self, FallbackPoSt};
 
 use crate::{
-    constants::{DefaultPieceHasher, DRG_DEGREE, EXP_DEGREE, LAYERS, POREP_MINIMUM_CHALLENGES},
+    constants::{
+        network_porep_params, ni_porep_minimum_challenges, DefaultPieceHasher, DRG_DEGREE,
+        EXP_DEGREE, LAYERS, POREP_MINIMUM_CHALLENGES,
+    },
     types::{MerkleTreeTrait, PaddedBytesAmount, PoStConfig},
 };
 
@@ -19,12 +25,14 @@ pub fn public_params<Tree: 'static + MerkleTreeTrait>(
     partitions: usize,
     porep_id: [u8; 32],
     api_version: ApiVersion,
+    api_features: &[ApiFeature],
 ) -> Result<stacked::PublicParams<Tree>> {
     StackedDrg::<Tree, DefaultPieceHasher>::setup(&setup_params(
         sector_bytes,
         partitions,
         porep_id,
         api_version,
+        api_features,
     )?)
 }
 
@@ -79,20 +87,33 @@ pub fn setup_params(
     partitions: usize,
     porep_id: [u8; 32],
     api_version: ApiVersion,
+    api_features: &[ApiFeature],
 ) -> Result<stacked::SetupParams> {
-    let layer_challenges = select_challenges(
-        partitions,
-        *POREP_MINIMUM_CHALLENGES
+    let non_interactive = api_features.contains(&ApiFeature::NonInteractivePoRep);
+    ensure!(
+        !(non_interactive && api_features.contains(&ApiFeature::SyntheticPoRep)),
+        "a non-interactive PoRep can't be synthetic"
+    );
+    // The parameters of a custom network take precedence over the ones of the sector size.
+    let network_params = network_porep_params(u64::from(sector_bytes), porep_id);
+    let minimum_challenges = match network_params {
+        Some(params) => params.minimum_challenges,
+        None if non_interactive => ni_porep_minimum_challenges(u64::from(sector_bytes)),
+        None => *POREP_MINIMUM_CHALLENGES
             .read()
             .expect("POREP_MINIMUM_CHALLENGES poisoned")
             .get(&u64::from(sector_bytes))
-            .expect("unknown sector size") as usize,
-        *LAYERS
+            .expect("unknown sector size"),
+    };
+    let layers = match network_params {
+        Some(params) => params.layers,
+        None => *LAYERS
             .read()
             .expect("LAYERS poisoned")
             .get(&u64::from(sector_bytes))
             .expect("unknown sector size"),
-    );
+    };
+    let layer_challenges = select_challenges(partitions, minimum_challenges as usize, layers);
     let sector_bytes = u64::from(sector_bytes);
 
     ensure!(
@@ -105,6 +126,24 @@ pub fn setup_params(
     let degree = DRG_DEGREE;
     let expansion_degree = EXP_DEGREE;
 
+    // A synthetic PoRep is proven by the same circuit, only the challenges are selected out of the
+    // synthetic ones. Sectors of the test sizes have fewer nodes than there are synthetic
+    // challenges, they get one per node.
+    let layer_challenges = if api_features.contains(&ApiFeature::SyntheticPoRep) {
+        LayerChallenges::new_synthetic(
+            layer_challenges.layers(),
+            layer_challenges.challenges_count_all(),
+            SYNTHETIC_CHALLENGE_COUNT.min(nodes),
+        )
+    } else if non_interactive {
+        LayerChallenges::new_non_interactive(
+            layer_challenges.layers(),
+            layer_challenges.challenges_count_all(),
+        )
+    } else {
+        layer_challenges
+    };
+
     Ok(stacked::SetupParams {
         nodes,
         degree,
@@ -134,7 +173,10 @@ fn select_challenges(
 mod tests {
     use super::*;
 
-    use crate::{DefaultOctLCTree, PoRepProofPartitions, PoStType};
+    use crate::{
+        constants::{ni_porep_partitions, SECTOR_SIZE_32_GIB},
+        DefaultOctLCTree, PoRepProofPartitions, PoStType,
+    };
 
     #[test]
     fn partition_layer_challenges_test() {
@@ -147,6 +189,33 @@ mod tests {
         assert_eq!(3, f(4));
     }
 
+    #[test]
+    fn test_non_interactive_porep_params() {
+        let sector_bytes = PaddedBytesAmount(SECTOR_SIZE_32_GIB);
+        let partitions = usize::from(ni_porep_partitions(SECTOR_SIZE_32_GIB));
+        assert_eq!(partitions, 126);
+
+        let params = setup_params(
+            sector_bytes,
+            partitions,
+            [0; 32],
+            ApiVersion::V1_1_0,
+            &[ApiFeature::NonInteractivePoRep],
+        )
+        .expect("failed to get params");
+        assert!(params.layer_challenges.is_non_interactive());
+        assert_eq!(params.layer_challenges.challenges_count_all(), 18);
+
+        assert!(setup_params(
+            sector_bytes,
+            partitions,
+            [0; 32],
+            ApiVersion::V1_1_0,
+            &[ApiFeature::NonInteractivePoRep, ApiFeature::SyntheticPoRep],
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_winning_post_params() {
         let config = PoStConfig {
@@ -1,6 +1,11 @@
 pub use merkletree::store::StoreConfig;
 pub use storage_proofs_core::merkle::{MerkleProof, MerkleTreeTrait};
-pub use storage_proofs_porep::stacked::{Labels, PersistentAux, TemporaryAux};
+pub use storage_proofs_porep::stacked::{
+    LabelingProgress, LabelingStrategy, Labels, PersistentAux, TemporaryAux,
+};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
 
 use filecoin_hashers::Hasher;
 use serde::{Deserialize, Serialize};
@@ -8,7 +13,7 @@ use storage_proofs_core::{merkle::BinaryMerkleTree, sector::SectorId};
 use storage_proofs_porep::stacked;
 use storage_proofs_post::fallback;
 
-use crate::constants::DefaultPieceHasher;
+use crate::constants::{DefaultPieceHasher, PoRepParams};
 
 mod bytes_amount;
 mod hselect;
@@ -70,6 +75,18 @@ pub struct SealCommitPhase1Output<Tree: MerkleTreeTrait> {
     pub replica_id: <Tree::Hasher as Hasher>::Domain,
     pub seed: Ticket,
     pub ticket: Ticket,
+    /// The custom PoRep parameters the sector was sealed with, if any, which C2 registers in its
+    /// own process, see `PoRepConfigBuilder`.
+    #[serde(default)]
+    pub porep_params: Option<PoRepParams>,
+}
+
+/// A sector to prove the commit of, see `seal_commit_phase2_batch`.
+#[derive(Clone, Debug)]
+pub struct SealCommitPhase2Input<Tree: MerkleTreeTrait> {
+    pub phase1_output: SealCommitPhase1Output<Tree>,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
 }
 
 #[derive(Clone, Debug)]
@@ -77,6 +94,18 @@ pub struct SealCommitOutput {
     pub proof: Vec<u8>,
 }
 
+/// A seal proof with what it is verified against, see `verify_batch_seal_proofs`.
+#[derive(Clone, Copy, Debug)]
+pub struct SealProofToVerify<'a> {
+    pub comm_r: Commitment,
+    pub comm_d: Commitment,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    pub ticket: Ticket,
+    pub seed: Ticket,
+    pub proof: &'a [u8],
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SealPreCommitPhase1Output<Tree: MerkleTreeTrait> {
     #[serde(bound(
@@ -88,6 +117,68 @@ pub struct SealPreCommitPhase1Output<Tree: MerkleTreeTrait> {
     pub comm_d: Commitment,
 }
 
+impl<Tree: MerkleTreeTrait> Clone for SealPreCommitPhase1Output<Tree> {
+    fn clone(&self) -> Self {
+        SealPreCommitPhase1Output {
+            labels: self.labels.clone(),
+            config: self.config.clone(),
+            comm_d: self.comm_d,
+        }
+    }
+}
+
+/// The result of an operation run with a deadline: either its output, or the state needed to
+/// resume it if the deadline was reached first.
+#[derive(Debug)]
+pub enum SealProgress<T, S> {
+    Done(T),
+    Paused(S),
+}
+
+/// The state of a `seal_pre_commit_phase1` paused at its deadline.
+///
+/// Labels of completed layers are kept in the cache directory, resuming only generates the
+/// remaining ones.
+#[derive(Clone, Debug)]
+pub struct SealPreCommitPhase1State {
+    pub porep_config: PoRepConfig,
+    pub cache_path: PathBuf,
+    pub in_path: PathBuf,
+    pub out_path: PathBuf,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    pub ticket: Ticket,
+    pub piece_infos: Vec<PieceInfo>,
+    /// The checkpoint at which the operation stopped, e.g. `layer 5`.
+    pub checkpoint: String,
+}
+
+/// The state of a `seal_pre_commit_phase2` paused at its deadline.
+///
+/// Tree building restarts from the beginning on resume, the deadline only prevents starting a
+/// tree build that would overrun it.
+#[derive(Debug)]
+pub struct SealPreCommitPhase2State<Tree: MerkleTreeTrait> {
+    pub porep_config: PoRepConfig,
+    pub phase1_output: SealPreCommitPhase1Output<Tree>,
+    pub cache_path: PathBuf,
+    pub replica_path: PathBuf,
+    /// The checkpoint at which the operation stopped, e.g. `tree_r_last`.
+    pub checkpoint: String,
+}
+
+impl<Tree: MerkleTreeTrait> Clone for SealPreCommitPhase2State<Tree> {
+    fn clone(&self) -> Self {
+        SealPreCommitPhase2State {
+            porep_config: self.porep_config,
+            phase1_output: self.phase1_output.clone(),
+            cache_path: self.cache_path.clone(),
+            replica_path: self.replica_path.clone(),
+            checkpoint: self.checkpoint.clone(),
+        }
+    }
+}
+
 #[repr(transparent)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PartitionSnarkProof(pub Vec<u8>);
@@ -115,6 +206,24 @@ pub struct FallbackPoStSectorProof<Tree: MerkleTreeTrait> {
     pub vanilla_proof: VanillaProof<Tree>, // Has comm_c, comm_r_last, inclusion_proofs
 }
 
+/// A Window PoSt of the sectors that could be proven, see `generate_window_post_skipping_faults`.
+#[derive(Clone, Debug)]
+pub struct WindowPoStOutput {
+    pub proof: SnarkProof,
+    /// The sectors that couldn't be proven, which are not proven by `proof` and are to be declared
+    /// faulty.
+    pub faulty_sectors: BTreeSet<SectorId>,
+}
+
+/// A Window PoSt with what it is verified against, see `verify_window_post_batch`.
+#[derive(Clone, Copy, Debug)]
+pub struct WindowPoStToVerify<'a> {
+    pub randomness: ChallengeSeed,
+    pub prover_id: ProverId,
+    pub replicas: &'a BTreeMap<SectorId, PublicReplicaInfo>,
+    pub proof: &'a [u8],
+}
+
 pub struct EmptySectorUpdateEncoded {
     pub comm_r_new: Commitment,
     pub comm_r_last_new: Commitment,
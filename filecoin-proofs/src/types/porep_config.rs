@@ -1,8 +1,8 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use storage_proofs_core::{
-    api_version::ApiVersion,
+    api_version::{ApiFeature, ApiVersion},
     merkle::MerkleTreeTrait,
     parameter_cache::{
         parameter_cache_metadata_path, parameter_cache_params_path,
@@ -12,8 +12,12 @@ use storage_proofs_core::{
 use storage_proofs_porep::stacked::{StackedCircuit, StackedCompound};
 
 use crate::{
-    constants::DefaultPieceHasher,
-    parameters::public_params,
+    constants::{
+        network_porep_params, ni_porep_minimum_challenges, ni_porep_partitions,
+        register_porep_params, DefaultPieceHasher, PoRepParams, LAYERS,
+        NI_POREP_CHALLENGES_PER_PARTITION, POREP_MINIMUM_CHALLENGES, POREP_PARTITIONS,
+    },
+    parameters::{public_params, setup_params},
     types::{PaddedBytesAmount, PoRepProofPartitions, SectorSize, UnpaddedBytesAmount},
 };
 
@@ -23,6 +27,8 @@ pub struct PoRepConfig {
     pub partitions: PoRepProofPartitions,
     pub porep_id: [u8; 32],
     pub api_version: ApiVersion,
+    /// Features of the PoRep enabled on top of `api_version`, e.g. the synthetic PoRep.
+    pub api_features: &'static [ApiFeature],
 }
 
 impl From<PoRepConfig> for PaddedBytesAmount {
@@ -54,13 +60,108 @@ impl From<PoRepConfig> for SectorSize {
 }
 
 impl PoRepConfig {
-    /// Returns the cache identifier as used by `storage-proofs::parameter_cache`.
+    /// The config of the non-interactive PoRep of sectors of `sector_size`, whose challenges don't
+    /// depend on a seed. It has more partitions than the interactive PoRep.
+    pub fn new_non_interactive(
+        sector_size: u64,
+        porep_id: [u8; 32],
+        api_version: ApiVersion,
+    ) -> Self {
+        PoRepConfig {
+            sector_size: SectorSize(sector_size),
+            partitions: PoRepProofPartitions(ni_porep_partitions(sector_size)),
+            porep_id,
+            api_version,
+            api_features: &[ApiFeature::NonInteractivePoRep],
+        }
+    }
+
+    /// Whether `feature` is enabled for the PoRep.
+    pub fn feature_enabled(&self, feature: ApiFeature) -> bool {
+        self.api_features.contains(&feature)
+    }
+
+    /// The minimum number of challenges of the PoRep over all partitions.
+    pub fn minimum_challenges(&self) -> usize {
+        let sector_size = u64::from(self.sector_size);
+        let minimum = if let Some(params) = network_porep_params(sector_size, self.porep_id) {
+            params.minimum_challenges
+        } else if self.feature_enabled(ApiFeature::NonInteractivePoRep) {
+            ni_porep_minimum_challenges(sector_size)
+        } else {
+            *POREP_MINIMUM_CHALLENGES
+                .read()
+                .expect("POREP_MINIMUM_CHALLENGES poisoned")
+                .get(&sector_size)
+                .expect("unknown sector size")
+        };
+
+        minimum as usize
+    }
+
+    /// The custom PoRep parameters registered for the sector size and porep id of the config in
+    /// this process, if any, see [`PoRepConfigBuilder`].
+    pub fn porep_params(&self) -> Option<PoRepParams> {
+        network_porep_params(u64::from(self.sector_size), self.porep_id)
+    }
+
+    /// The PoRep parameters the sectors of the config are sealed and proven with, custom or not.
+    pub fn resolved_porep_params(&self) -> PoRepParams {
+        PoRepParams {
+            layers: self.layers(),
+            minimum_challenges: self.minimum_challenges() as u64,
+        }
+    }
+
+    /// Checks that the output of C1 with `porep_params` and proofs of `partitions` partitions was
+    /// sealed with this config, and registers its custom parameters if this process hasn't, e.g.
+    /// when C2 runs in another process than C1.
+    pub(crate) fn adopt_porep_params(
+        &self,
+        porep_params: Option<PoRepParams>,
+        partitions: usize,
+    ) -> Result<()> {
+        ensure!(
+            partitions == usize::from(self.partitions),
+            "the phase1 output has proofs of {} partitions, the config {}",
+            partitions,
+            usize::from(self.partitions)
+        );
+        match (porep_params, self.porep_params()) {
+            (Some(params), _) => {
+                register_porep_params(u64::from(self.sector_size), self.porep_id, params)
+            }
+            (None, Some(params)) => bail!(
+                "the phase1 output was sealed without the PoRep parameters {:?} of its porep id",
+                params
+            ),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// The number of layers of the PoRep.
+    pub fn layers(&self) -> usize {
+        let sector_size = u64::from(self.sector_size);
+        match network_porep_params(sector_size, self.porep_id) {
+            Some(params) => params.layers,
+            None => *LAYERS
+                .read()
+                .expect("LAYERS poisoned")
+                .get(&sector_size)
+                .expect("unknown sector size"),
+        }
+    }
+
+    /// Returns the cache identifier as used by `storage-proofs::parameter_cache`. It is derived
+    /// from the public params of a single partition (the sector size, the tree shape, the layers
+    /// and the challenges of each partition), not from the porep id.
     pub fn get_cache_identifier<Tree: 'static + MerkleTreeTrait>(&self) -> Result<String> {
         let params = public_params::<Tree>(
             self.sector_size.into(),
             self.partitions.into(),
             self.porep_id,
             self.api_version,
+            self.api_features,
         )?;
 
         Ok(
@@ -86,3 +187,239 @@ impl PoRepConfig {
         Ok(parameter_cache_params_path(&id))
     }
 }
+
+/// Builds the `PoRepConfig` of a custom network, whose PoRep may have its own layers, minimum
+/// number of challenges and partitions rather than the ones of its sector size.
+///
+/// Custom parameters are registered for the sector size and porep id of the config within this
+/// process, and honored by the vanilla proofs and circuits of every sector sealed, proven or
+/// verified with that porep id, so a network must use a porep id of its own. They are carried to
+/// C2 by `SealCommitPhase1Output` (and the remote C2 request), which registers them in its process,
+/// but processes verifying the proofs must build the config themselves. Networks whose
+/// PoReps have the same layers and challenges per partition share their Groth parameters (see
+/// `get_cache_identifier`), which must be generated locally, e.g. with `paramcache`.
+#[derive(Clone, Debug)]
+pub struct PoRepConfigBuilder {
+    sector_size: u64,
+    porep_id: [u8; 32],
+    api_version: ApiVersion,
+    api_features: &'static [ApiFeature],
+    partitions: Option<u8>,
+    layers: Option<usize>,
+    minimum_challenges: Option<u64>,
+}
+
+impl PoRepConfigBuilder {
+    /// A builder of the config of sectors of `sector_size`, a built-in or registered sector size.
+    pub fn new(sector_size: u64, porep_id: [u8; 32], api_version: ApiVersion) -> Self {
+        PoRepConfigBuilder {
+            sector_size,
+            porep_id,
+            api_version,
+            api_features: &[],
+            partitions: None,
+            layers: None,
+            minimum_challenges: None,
+        }
+    }
+
+    pub fn api_features(mut self, api_features: &'static [ApiFeature]) -> Self {
+        self.api_features = api_features;
+        self
+    }
+
+    /// The number of partitions, by default those needed to prove the minimum number of
+    /// challenges.
+    pub fn partitions(mut self, partitions: u8) -> Self {
+        self.partitions = Some(partitions);
+        self
+    }
+
+    pub fn layers(mut self, layers: usize) -> Self {
+        self.layers = Some(layers);
+        self
+    }
+
+    /// The minimum number of challenges over all partitions.
+    pub fn minimum_challenges(mut self, minimum_challenges: u64) -> Self {
+        self.minimum_challenges = Some(minimum_challenges);
+        self
+    }
+
+    /// Builds the config, registering its parameters if they are custom. Fails if the porep id
+    /// is already registered with different parameters for the sector size.
+    pub fn build(self) -> Result<PoRepConfig> {
+        let sector_size = self.sector_size;
+        let layers = LAYERS
+            .read()
+            .expect("LAYERS poisoned")
+            .get(&sector_size)
+            .copied();
+        let layers = match layers {
+            Some(layers) => layers,
+            None => bail!("unknown sector size {}", sector_size),
+        };
+        let non_interactive = self.api_features.contains(&ApiFeature::NonInteractivePoRep);
+
+        let registered = network_porep_params(sector_size, self.porep_id);
+        let params = PoRepParams {
+            layers: self
+                .layers
+                .or_else(|| registered.map(|params| params.layers))
+                .unwrap_or(layers),
+            minimum_challenges: self
+                .minimum_challenges
+                .or_else(|| registered.map(|params| params.minimum_challenges))
+                .unwrap_or_else(|| {
+                    if non_interactive {
+                        ni_porep_minimum_challenges(sector_size)
+                    } else {
+                        POREP_MINIMUM_CHALLENGES
+                            .read()
+                            .expect("POREP_MINIMUM_CHALLENGES poisoned")[&sector_size]
+                    }
+                }),
+        };
+        ensure!(params.layers > 0, "layers must be positive");
+        ensure!(
+            params.minimum_challenges > 0,
+            "minimum_challenges must be positive"
+        );
+
+        let partitions = match self.partitions {
+            Some(partitions) => u64::from(partitions),
+            None if non_interactive => {
+                (params.minimum_challenges + NI_POREP_CHALLENGES_PER_PARTITION - 1)
+                    / NI_POREP_CHALLENGES_PER_PARTITION
+            }
+            None => {
+                u64::from(POREP_PARTITIONS.read().expect("POREP_PARTITIONS poisoned")[&sector_size])
+            }
+        };
+        ensure!(
+            partitions > 0 && partitions <= u64::from(u8::MAX),
+            "invalid number of partitions {}",
+            partitions
+        );
+
+        let config = PoRepConfig {
+            sector_size: SectorSize(sector_size),
+            partitions: PoRepProofPartitions(partitions as u8),
+            porep_id: self.porep_id,
+            api_version: self.api_version,
+            api_features: self.api_features,
+        };
+        // Rejects invalid combinations of features before anything is registered.
+        setup_params(
+            config.sector_size.into(),
+            partitions as usize,
+            config.porep_id,
+            config.api_version,
+            config.api_features,
+        )?;
+        if self.layers.is_some() || self.minimum_challenges.is_some() {
+            register_porep_params(sector_size, self.porep_id, params)?;
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::constants::{SectorShape2KiB, SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_GIB};
+
+    #[test]
+    fn test_porep_config_builder() {
+        let builtin = PoRepConfigBuilder::new(SECTOR_SIZE_2_KIB, [1; 32], ApiVersion::V1_1_0)
+            .build()
+            .expect("build failed");
+        assert_eq!(usize::from(builtin.partitions), 1);
+        assert_eq!(builtin.layers(), 2);
+        assert_eq!(builtin.minimum_challenges(), 2);
+
+        let custom = PoRepConfigBuilder::new(SECTOR_SIZE_2_KIB, [2; 32], ApiVersion::V1_1_0)
+            .layers(4)
+            .minimum_challenges(6)
+            .partitions(2)
+            .build()
+            .expect("build failed");
+        assert_eq!(usize::from(custom.partitions), 2);
+        assert_eq!(custom.layers(), 4);
+        assert_eq!(custom.minimum_challenges(), 6);
+
+        let params = setup_params(
+            custom.sector_size.into(),
+            usize::from(custom.partitions),
+            custom.porep_id,
+            custom.api_version,
+            custom.api_features,
+        )
+        .expect("setup_params failed");
+        assert_eq!(params.layer_challenges.layers(), 4);
+        assert_eq!(params.layer_challenges.challenges_count_all(), 3);
+
+        // The parameters of a porep id are registered once, and rebuilding picks them up.
+        let rebuilt = PoRepConfigBuilder::new(SECTOR_SIZE_2_KIB, [2; 32], ApiVersion::V1_1_0)
+            .partitions(2)
+            .build()
+            .expect("build failed");
+        assert_eq!(rebuilt.layers(), 4);
+        assert!(
+            PoRepConfigBuilder::new(SECTOR_SIZE_2_KIB, [2; 32], ApiVersion::V1_1_0)
+                .layers(3)
+                .build()
+                .is_err()
+        );
+
+        // The parameter id depends on the layers and challenges, not on the porep id.
+        let id = |config: PoRepConfig| {
+            config
+                .get_cache_identifier::<SectorShape2KiB>()
+                .expect("get_cache_identifier failed")
+        };
+        let same = PoRepConfigBuilder::new(SECTOR_SIZE_2_KIB, [3; 32], ApiVersion::V1_1_0)
+            .layers(4)
+            .minimum_challenges(6)
+            .partitions(2)
+            .build()
+            .expect("build failed");
+        assert_eq!(id(custom), id(same));
+        assert_ne!(id(custom), id(builtin));
+
+        // C2 checks the parameters C1 sealed with, and registers them in its own process.
+        let params = custom.porep_params();
+        assert_eq!(
+            params,
+            Some(PoRepParams {
+                layers: 4,
+                minimum_challenges: 6,
+            })
+        );
+        assert_eq!(custom.resolved_porep_params(), params.expect("no params"));
+        custom.adopt_porep_params(params, 2).expect("adopt failed");
+        assert!(custom.adopt_porep_params(params, 1).is_err());
+        assert!(custom.adopt_porep_params(None, 2).is_err());
+        let unregistered = PoRepConfig {
+            porep_id: [5; 32],
+            ..custom
+        };
+        assert_eq!(unregistered.layers(), 2);
+        unregistered
+            .adopt_porep_params(params, 2)
+            .expect("adopt failed");
+        assert_eq!(unregistered.layers(), 4);
+
+        assert!(PoRepConfigBuilder::new(12345, [4; 32], ApiVersion::V1_1_0)
+            .build()
+            .is_err());
+        assert!(
+            PoRepConfigBuilder::new(SECTOR_SIZE_32_GIB, [4; 32], ApiVersion::V1_1_0)
+                .api_features(&[ApiFeature::NonInteractivePoRep, ApiFeature::SyntheticPoRep])
+                .build()
+                .is_err()
+        );
+    }
+}
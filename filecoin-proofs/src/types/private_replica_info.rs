@@ -8,8 +8,8 @@ use anyhow::{ensure, Context, Result};
 use bincode::deserialize;
 use filecoin_hashers::Hasher;
 use generic_array::typenum::Unsigned;
-use log::trace;
-use merkletree::store::StoreConfig;
+use log::{trace, warn};
+use merkletree::store::{ReplicaConfig, StoreConfig};
 use storage_proofs_core::{
     cache_key::CacheKey,
     merkle::{
@@ -21,7 +21,8 @@ use storage_proofs_core::{
 
 use crate::{
     api::{as_safe_commitment, get_base_tree_leafs, get_base_tree_size},
-    types::{Commitment, PersistentAux, SectorSize},
+    constants::DefaultPieceHasher,
+    types::{Commitment, PersistentAux, SectorSize, TemporaryAux},
 };
 
 /// The minimal information required about a replica, in order to be able to generate
@@ -34,6 +35,8 @@ pub struct PrivateReplicaInfo<Tree: MerkleTreeTrait> {
     comm_r: Commitment,
     /// Persistent Aux.
     aux: PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+    /// The 'rows_to_discard' of tree_r_last recorded in t_aux when sealing, if there is a t_aux.
+    rows_to_discard: Option<usize>,
     /// Contains sector-specific (e.g. merkle trees) assets
     pub cache_dir: PathBuf,
 
@@ -46,6 +49,7 @@ impl<Tree: MerkleTreeTrait> Clone for PrivateReplicaInfo<Tree> {
             replica: self.replica.clone(),
             comm_r: self.comm_r,
             aux: self.aux.clone(),
+            rows_to_discard: self.rows_to_discard,
             cache_dir: self.cache_dir.clone(),
             _t: Default::default(),
         }
@@ -96,12 +100,33 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
             deserialize(&aux_bytes)
         }?;
 
+        // The trees must be read with the value they were built with, which may not be the
+        // current default, e.g. with `FIL_PROOFS_ROWS_TO_DISCARD_AUTO`. The PoSts don't need
+        // t_aux otherwise, so without a readable one the default is used, as before it was
+        // recorded.
+        let t_aux_path = cache_dir.join(CacheKey::TAux.to_string());
+        let rows_to_discard = if t_aux_path.exists() {
+            match read_rows_to_discard::<Tree>(&t_aux_path) {
+                Ok(rows_to_discard) => Some(rows_to_discard),
+                Err(err) => {
+                    warn!(
+                        "using the default rows_to_discard, could not read {:?}: {:?}",
+                        t_aux_path, err
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
         ensure!(replica.exists(), "Sealed replica does not exist");
 
         Ok(PrivateReplicaInfo {
             replica,
             comm_r,
             aux,
+            rows_to_discard,
             cache_dir,
             _t: Default::default(),
         })
@@ -127,6 +152,11 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
         self.aux.comm_r_last
     }
 
+    /// The 'rows_to_discard' of tree_r_last, if it was recorded when sealing.
+    pub fn rows_to_discard(&self) -> Option<usize> {
+        self.rows_to_discard
+    }
+
     /// Generate the merkle tree of this particular replica.
     pub fn merkle_tree(
         &self,
@@ -140,13 +170,27 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
             Tree::TopTreeArity,
         >,
     > {
+        let base_tree_size = get_base_tree_size::<Tree>(sector_size)?;
+        let (configs, replica_config) = self.tree_r_last_configs(sector_size)?;
+
+        create_tree::<Tree>(base_tree_size, &configs, Some(&replica_config))
+    }
+
+    /// The configs of the stores of the base trees of tree_r_last, and of the replica.
+    pub(crate) fn tree_r_last_configs(
+        &self,
+        sector_size: SectorSize,
+    ) -> Result<(Vec<StoreConfig>, ReplicaConfig)> {
         let base_tree_size = get_base_tree_size::<Tree>(sector_size)?;
         let base_tree_leafs = get_base_tree_leafs::<Tree>(base_tree_size)?;
+        let rows_to_discard = self
+            .rows_to_discard
+            .unwrap_or_else(|| default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()));
         trace!(
             "post: base tree size {}, base tree leafs {}, rows_to_discard {}, arities [{}, {}, {}]",
             base_tree_size,
             base_tree_leafs,
-            default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()),
+            rows_to_discard,
             Tree::Arity::to_usize(),
             Tree::SubTreeArity::to_usize(),
             Tree::TopTreeArity::to_usize(),
@@ -155,18 +199,25 @@ impl<Tree: 'static + MerkleTreeTrait> PrivateReplicaInfo<Tree> {
         let mut config = StoreConfig::new(
             self.cache_dir_path(),
             CacheKey::CommRLastTree.to_string(),
-            default_rows_to_discard(base_tree_leafs, Tree::Arity::to_usize()),
+            rows_to_discard,
         );
         config.size = Some(base_tree_size);
 
         let tree_count = get_base_tree_count::<Tree>();
-        let (configs, replica_config) = split_config_and_replica(
+        split_config_and_replica(
             config,
             self.replica_path().to_path_buf(),
             base_tree_leafs,
             tree_count,
-        )?;
-
-        create_tree::<Tree>(base_tree_size, &configs, Some(&replica_config))
+        )
     }
 }
+
+/// Reads the 'rows_to_discard' of tree_r_last recorded in the t_aux at `t_aux_path`.
+fn read_rows_to_discard<Tree: 'static + MerkleTreeTrait>(t_aux_path: &Path) -> Result<usize> {
+    let t_aux_bytes = fs::read(t_aux_path)
+        .with_context(|| format!("could not read from path={:?}", t_aux_path))?;
+    let t_aux: TemporaryAux<Tree, DefaultPieceHasher> = deserialize(&t_aux_bytes)?;
+
+    Ok(t_aux.tree_r_last_config.rows_to_discard)
+}
@@ -1,8 +1,9 @@
 use std::collections::BTreeMap;
-use std::fs::{metadata, read_dir, remove_file, OpenOptions};
+use std::fs::{self, metadata, read_dir, remove_file, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Once;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{ensure, Context, Error, Result};
 use bellperson::groth16;
@@ -10,34 +11,56 @@ use bincode::serialize;
 use blstrs::{Bls12, Scalar as Fr};
 use ff::Field;
 use filecoin_hashers::Hasher;
+use filecoin_proofs::caches::get_stacked_params;
 use filecoin_proofs::{
-    add_piece, aggregate_seal_commit_proofs, clear_cache, compute_comm_d, decode_from, encode_into,
-    fauxrep_aux, generate_empty_sector_update_proof,
+    add_piece, aggregate_empty_sector_update_proofs, aggregate_seal_commit_proofs,
+    check_post_challenges, check_replica, clear_cache, clear_layer_data, compute_comm_d,
+    decode_from, decode_sector_data_to, encode_into, estimate_gpu_memory, fauxrep_aux,
+    generate_data_range_proof_from_data, generate_empty_sector_update_proof,
     generate_empty_sector_update_proof_with_vanilla, generate_fallback_sector_challenges,
-    generate_partition_proofs, generate_piece_commitment, generate_single_partition_proof,
-    generate_single_vanilla_proof, generate_single_window_post_with_vanilla, generate_window_post,
-    generate_window_post_with_vanilla, generate_winning_post,
+    generate_params, generate_partition_proofs, generate_piece_commitment,
+    generate_piece_inclusion_proof, generate_piece_inclusion_proof_from_data,
+    generate_porep_challenges, generate_post_challenges,
+    generate_sector_key_from_replica_with_reader, generate_single_partition_proof,
+    generate_single_vanilla_proof, generate_single_window_post_with_vanilla, generate_synth_proofs,
+    generate_window_post, generate_window_post_from_store, generate_window_post_partition,
+    generate_window_post_partition_of_sectors, generate_window_post_partitions,
+    generate_window_post_skipping_faults, generate_window_post_with_vanilla, generate_winning_post,
     generate_winning_post_sector_challenge, generate_winning_post_with_vanilla,
-    get_num_partition_for_fallback_post, get_seal_inputs, merge_window_post_partition_proofs,
-    remove_encoded_data, seal_commit_phase1, seal_commit_phase2, seal_pre_commit_phase1,
-    seal_pre_commit_phase2, unseal_range, validate_cache_for_commit,
+    get_num_partition_for_fallback_post, get_seal_inputs, get_sector_update_inputs,
+    merge_window_post_partition_proofs, remove_encoded_data, remove_encoded_data_with_sink,
+    resume_seal_pre_commit_phase1, resume_seal_pre_commit_phase2, seal_commit_phase1,
+    seal_commit_phase1_non_interactive, seal_commit_phase2, seal_commit_phase2_batch,
+    seal_pre_commit_phase1, seal_pre_commit_phase1_with_deadline,
+    seal_pre_commit_phase1_with_progress, seal_pre_commit_phase2,
+    seal_pre_commit_phase2_with_deadline, test_circuit, unseal_range, validate_cache_for_commit,
     validate_cache_for_precommit_phase2, verify_aggregate_seal_commit_proofs,
-    verify_empty_sector_update_proof, verify_partition_proofs, verify_seal,
-    verify_single_partition_proof, verify_window_post, verify_winning_post, Commitment,
-    DefaultTreeDomain, MerkleTreeTrait, PaddedBytesAmount, PieceInfo, PoRepConfig,
-    PoRepProofPartitions, PoStConfig, PoStType, PrivateReplicaInfo, ProverId, PublicReplicaInfo,
-    SealCommitOutput, SealPreCommitOutput, SealPreCommitPhase1Output, SectorShape16KiB,
-    SectorShape2KiB, SectorShape32KiB, SectorShape4KiB, SectorSize, SectorUpdateConfig,
-    UnpaddedByteIndex, UnpaddedBytesAmount, POREP_PARTITIONS, SECTOR_SIZE_16_KIB,
-    SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_KIB, SECTOR_SIZE_4_KIB, WINDOW_POST_CHALLENGE_COUNT,
-    WINDOW_POST_SECTOR_COUNT, WINNING_POST_CHALLENGE_COUNT, WINNING_POST_SECTOR_COUNT,
+    verify_aggregate_sector_update_proofs, verify_batch_seal_proofs, verify_data_range_proof,
+    verify_empty_sector_update_proof, verify_partition_proofs, verify_piece_inclusion_proof,
+    verify_seal, verify_seal_non_interactive, verify_single_partition_proof, verify_window_post,
+    verify_window_post_batch, verify_winning_post, warm_post_cache, CircuitInputs, CircuitProof,
+    Commitment, DefaultTreeDomain, FsSectorStore, GpuPhase, MerkleTreeTrait, PaddedBytesAmount,
+    ParameterSet, PieceInfo, PoRepConfig, PoRepProofPartitions, PoStConfig, PoStType,
+    PrivateReplicaInfo, ProverId, PublicReplicaInfo, ReplicaCheck, SealCommitOutput,
+    SealCommitPhase2Input, SealPreCommitOutput, SealPreCommitPhase1Output, SealProgress,
+    SealProofToVerify, SectorShape16KiB, SectorShape2KiB, SectorShape32KiB, SectorShape4KiB,
+    SectorSize, SectorUpdateConfig, UnpaddedByteIndex, UnpaddedBytesAmount, WindowPoStToVerify,
+    POREP_PARTITIONS, SECTOR_SIZE_16_KIB, SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_KIB, SECTOR_SIZE_4_KIB,
+    WINDOW_POST_CHALLENGE_COUNT, WINDOW_POST_SECTOR_COUNT, WINNING_POST_CHALLENGE_COUNT,
+    WINNING_POST_SECTOR_COUNT,
 };
 use fr32::bytes_into_fr;
 use log::info;
 use memmap::MmapOptions;
 use rand::{random, Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
-use storage_proofs_core::{api_version::ApiVersion, is_legacy_porep_id, sector::SectorId};
+use storage_proofs_core::{
+    api_version::{ApiFeature, ApiVersion},
+    cache_key::CacheKey,
+    is_legacy_porep_id,
+    merkle::MerkleProofTrait,
+    sector::SectorId,
+};
 use storage_proofs_update::constants::TreeRHasher;
 use tempfile::{tempdir, NamedTempFile, TempDir};
 
@@ -65,6 +88,94 @@ const TEST_SEED: [u8; 16] = [
     0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06, 0xbc, 0xe5,
 ];
 
+#[test]
+fn test_blank_circuits_2kib() -> Result<()> {
+    let porep_config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let report = test_circuit::<SectorShape2KiB>(
+        CircuitProof::SealCommit(porep_config),
+        CircuitInputs::Blank,
+    )?;
+    assert!(report.num_constraints > 0);
+    assert!(report.partitions.is_empty());
+    assert!(report.is_satisfied());
+
+    let post_config = PoStConfig {
+        sector_size: SECTOR_SIZE_2_KIB.into(),
+        sector_count: WINNING_POST_SECTOR_COUNT,
+        challenge_count: WINNING_POST_CHALLENGE_COUNT,
+        typ: PoStType::Winning,
+        priority: false,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let report = test_circuit::<SectorShape2KiB>(
+        CircuitProof::WinningPoSt(post_config.clone()),
+        CircuitInputs::Blank,
+    )?;
+    assert!(report.num_constraints > 0);
+
+    // The proof kind must match the config.
+    assert!(test_circuit::<SectorShape2KiB>(
+        CircuitProof::WindowPoSt(post_config),
+        CircuitInputs::Blank
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_estimate_gpu_memory_2kib() -> Result<()> {
+    let porep_config = porep_config(
+        SECTOR_SIZE_2_KIB,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+
+    let tree_c = estimate_gpu_memory(&porep_config, GpuPhase::TreeC)?;
+    let tree_r_last = estimate_gpu_memory(&porep_config, GpuPhase::TreeRLast)?;
+    let seal_commit_phase2 = estimate_gpu_memory(&porep_config, GpuPhase::SealCommitPhase2)?;
+    // tree_c builds trees like tree_r_last, besides hashing the columns.
+    assert!(tree_c > tree_r_last);
+    assert!(tree_r_last > 0);
+
+    // The H query of the Groth parameters has a point for all but one of the elements of the
+    // domain counted from the circuit.
+    let groth_params = get_stacked_params::<SectorShape2KiB>(porep_config)?;
+    let domain_size = groth_params.h.len() as u64 + 1;
+    assert_eq!(
+        seal_commit_phase2,
+        2 * domain_size * std::mem::size_of::<Fr>() as u64
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_generate_params_2kib() -> Result<()> {
+    let output_dir = tempdir()?;
+    let post_config = PoStConfig {
+        sector_size: SECTOR_SIZE_2_KIB.into(),
+        sector_count: WINNING_POST_SECTOR_COUNT,
+        challenge_count: WINNING_POST_CHALLENGE_COUNT,
+        typ: PoStType::Winning,
+        priority: false,
+        api_version: ApiVersion::V1_1_0,
+    };
+
+    let paths = generate_params(&ParameterSet::PoSt(post_config), output_dir.path())?;
+    assert_eq!(paths.len(), 3);
+    for path in &paths {
+        assert!(path.starts_with(output_dir.path()));
+        assert!(path.exists(), "{} was not generated", path.display());
+    }
+
+    Ok(())
+}
+
 #[test]
 #[ignore]
 fn test_seal_lifecycle_2kib_porep_id_v1_base_8() -> Result<()> {
@@ -596,28 +707,813 @@ fn test_resumable_seal_v1() {
     run_resumable_seal::<SectorShape2KiB>(false, 1, &porep_id, ApiVersion::V1_0_0);
 }
 
-#[test]
-#[ignore]
-fn test_resumable_seal_v1_1() {
-    let porep_id_v1_1: u64 = 5; // This is a RegisteredSealProof value
+#[test]
+#[ignore]
+fn test_resumable_seal_v1_1() {
+    let porep_id_v1_1: u64 = 5; // This is a RegisteredSealProof value
+
+    let mut porep_id = [0u8; 32];
+    porep_id[..8].copy_from_slice(&porep_id_v1_1.to_le_bytes());
+    assert!(!is_legacy_porep_id(porep_id));
+    run_resumable_seal::<SectorShape2KiB>(false, 0, &porep_id, ApiVersion::V1_1_0);
+    run_resumable_seal::<SectorShape2KiB>(false, 1, &porep_id, ApiVersion::V1_1_0);
+}
+
+/// Create a seal, delete a layer and resume
+///
+/// The current code works on two layers only. The `layer_to_delete` specifies (zero-based) which
+/// layer should be deleted.
+fn run_resumable_seal<Tree: 'static + MerkleTreeTrait>(
+    skip_proofs: bool,
+    layer_to_delete: usize,
+    porep_id: &[u8; 32],
+    api_version: ApiVersion,
+) {
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let (mut piece_file, piece_bytes) =
+        generate_piece_file(sector_size).expect("failed to generate piece file");
+    let sealed_sector_file = NamedTempFile::new().expect("failed to created sealed sector file");
+    let cache_dir = tempdir().expect("failed to create temp dir");
+
+    let config = porep_config(sector_size, *porep_id, api_version);
+    let ticket = rng.gen();
+    let sector_id = rng.gen::<u64>().into();
+
+    // First create seals as expected
+    run_seal_pre_commit_phase1::<Tree>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )
+    .expect("failed to run seal pre commit phase1");
+    let layers = get_layer_file_paths(&cache_dir);
+    assert_eq!(layers.len(), 2, "not all expected layers were created");
+
+    // Delete one layer, keep the other
+    clear_cache_dir_keep_data_layer(&cache_dir);
+    remove_file(&layers[layer_to_delete]).expect("failed to remove layer");
+    let layers_remaining = get_layer_file_paths(&cache_dir);
+    assert_eq!(layers_remaining.len(), 1, "expected one layer only");
+    if layer_to_delete == 0 {
+        assert_eq!(layers_remaining[0], layers[1], "wrong layer was removed");
+    } else {
+        assert_eq!(layers_remaining[0], layers[0], "wrong layer was removed");
+    }
+
+    // Resume the seal
+    piece_file
+        .seek(SeekFrom::Start(0))
+        .expect("failed to seek piece file to start");
+    let (piece_infos, phase1_output) = run_seal_pre_commit_phase1::<Tree>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )
+    .expect("failed to run seal pre commit phase1");
+
+    // Running proofs clears the cache, hence we can only check for existence of files if we don't
+    // run them
+    if skip_proofs {
+        let layers_recreated = get_layer_file_paths(&cache_dir);
+        assert_eq!(
+            layers_recreated.len(),
+            2,
+            "not all expected layers were recreated"
+        );
+        assert_eq!(
+            layers_recreated, layers,
+            "recreated layers don't match original ones"
+        );
+    } else {
+        let pre_commit_output = seal_pre_commit_phase2(
+            config,
+            phase1_output,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+        )
+        .expect("failed to run seal pre commit phase2");
+
+        validate_cache_for_commit::<_, _, Tree>(cache_dir.path(), sealed_sector_file.path())
+            .expect("failed to validate cache for commit");
+
+        let seed = rng.gen();
+        proof_and_unseal::<Tree>(
+            config,
+            cache_dir.path(),
+            &sealed_sector_file,
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            pre_commit_output,
+            &piece_infos,
+            &piece_bytes,
+        )
+        .expect("failed to proof");
+    }
+}
+
+#[test]
+fn test_seal_pre_commit_with_deadline_2kib() -> Result<()> {
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let number_of_bytes_in_piece = UnpaddedBytesAmount::from(PaddedBytesAmount(sector_size));
+    let piece_info = generate_piece_commitment(piece_file.as_file_mut(), number_of_bytes_in_piece)?;
+    piece_file.as_file_mut().seek(SeekFrom::Start(0))?;
+    let mut staged_sector_file = NamedTempFile::new()?;
+    add_piece(
+        &mut piece_file,
+        &mut staged_sector_file,
+        number_of_bytes_in_piece,
+        &[],
+    )?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let future = SystemTime::now() + Duration::from_secs(3600);
+
+    // A deadline in the past stops before the first layer.
+    let state = match seal_pre_commit_phase1_with_deadline::<_, _, _, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        staged_sector_file.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        rng.gen::<u64>().into(),
+        rng.gen(),
+        &[piece_info],
+        past,
+    )? {
+        SealProgress::Paused(state) => state,
+        SealProgress::Done(_) => panic!("deadline was ignored"),
+    };
+    assert_eq!(state.checkpoint, "layer 1");
+    assert!(get_layer_file_paths(&cache_dir).is_empty());
+
+    let phase1_output = match resume_seal_pre_commit_phase1::<SectorShape2KiB>(state, future)? {
+        SealProgress::Done(output) => output,
+        SealProgress::Paused(state) => panic!("paused at {}", state.checkpoint),
+    };
+    assert_eq!(get_layer_file_paths(&cache_dir).len(), 2);
+
+    let state = match seal_pre_commit_phase2_with_deadline(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        past,
+    )? {
+        SealProgress::Paused(state) => state,
+        SealProgress::Done(_) => panic!("deadline was ignored"),
+    };
+    assert_eq!(state.checkpoint, "tree_c");
+
+    match resume_seal_pre_commit_phase2(state, future)? {
+        SealProgress::Done(_) => {}
+        SealProgress::Paused(state) => panic!("paused at {}", state.checkpoint),
+    }
+    validate_cache_for_commit::<_, _, SectorShape2KiB>(
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn test_seal_pre_commit_with_progress_2kib() -> Result<()> {
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let number_of_bytes_in_piece = UnpaddedBytesAmount::from(PaddedBytesAmount(sector_size));
+    let piece_info = generate_piece_commitment(piece_file.as_file_mut(), number_of_bytes_in_piece)?;
+    piece_file.as_file_mut().seek(SeekFrom::Start(0))?;
+    let mut staged_sector_file = NamedTempFile::new()?;
+    add_piece(
+        &mut piece_file,
+        &mut staged_sector_file,
+        number_of_bytes_in_piece,
+        &[],
+    )?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    let sector_id: SectorId = rng.gen::<u64>().into();
+    let ticket = rng.gen();
+    let nodes = sector_size / 32;
+
+    let seal = || -> Result<Vec<(usize, u64)>> {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorded = reports.clone();
+        seal_pre_commit_phase1_with_progress::<_, _, _, _, SectorShape2KiB>(
+            config,
+            cache_dir.path(),
+            staged_sector_file.path(),
+            sealed_sector_file.path(),
+            prover_id,
+            sector_id,
+            ticket,
+            &[piece_info.clone()],
+            move |progress| {
+                assert_eq!(progress.nodes_total, nodes);
+                recorded
+                    .lock()
+                    .expect("reports poisoned")
+                    .push((progress.layer, progress.nodes_done));
+            },
+        )?;
+        let reports = reports.lock().expect("reports poisoned").clone();
+        Ok(reports)
+    };
+
+    // Every layer is reported when it starts and when it is complete.
+    assert_eq!(seal()?, vec![(1, 0), (1, nodes), (2, 0), (2, nodes)]);
+    // The layers are kept in the cache, and only reported as complete.
+    assert_eq!(seal()?, vec![(1, nodes), (2, nodes)]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_seal_synthetic_2kib() -> Result<()> {
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+
+    let config = PoRepConfig {
+        api_features: &[ApiFeature::SyntheticPoRep],
+        ..porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0)
+    };
+    let sector_id: SectorId = rng.gen::<u64>().into();
+    let ticket = rng.gen();
+    let seed = rng.gen();
+
+    let (piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    // The layers are discarded as soon as the synthetic proofs are stored.
+    generate_synth_proofs::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        pre_commit_output.clone(),
+        &piece_infos,
+    )?;
+    clear_layer_data::<SectorShape2KiB>(cache_dir.path())?;
+    assert!(get_layer_file_paths(&cache_dir).is_empty());
+    validate_cache_for_commit::<_, _, SectorShape2KiB>(
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    let phase1_output = seal_commit_phase1::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        pre_commit_output.clone(),
+        &piece_infos,
+    )?;
+    clear_cache::<SectorShape2KiB>(cache_dir.path())?;
+
+    let comm_r = phase1_output.comm_r;
+    let comm_d = phase1_output.comm_d;
+    let SealCommitOutput { proof } =
+        seal_commit_phase2(config, phase1_output, prover_id, sector_id)?;
+    assert!(verify_seal::<SectorShape2KiB>(
+        config, comm_r, comm_d, prover_id, sector_id, ticket, seed, &proof,
+    )?);
+
+    // Without the synthetic PoRep, the same proof proves other challenges.
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    assert!(!verify_seal::<SectorShape2KiB>(
+        config, comm_r, comm_d, prover_id, sector_id, ticket, seed, &proof,
+    )?);
+
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_seal_non_interactive_2kib() -> Result<()> {
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+
+    let config = PoRepConfig::new_non_interactive(
+        sector_size,
+        ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    );
+    let sector_id: SectorId = rng.gen::<u64>().into();
+    let ticket = rng.gen();
+
+    let (piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+
+    // The sector is committed right away, without a seed.
+    let phase1_output = seal_commit_phase1_non_interactive::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        pre_commit_output.clone(),
+        &piece_infos,
+    )?;
+    clear_cache::<SectorShape2KiB>(cache_dir.path())?;
+
+    let comm_r = phase1_output.comm_r;
+    let comm_d = phase1_output.comm_d;
+    let SealCommitOutput { proof } =
+        seal_commit_phase2(config, phase1_output, prover_id, sector_id)?;
+    assert!(verify_seal_non_interactive::<SectorShape2KiB>(
+        config, comm_r, comm_d, prover_id, sector_id, ticket, &proof,
+    )?);
+
+    // The proof doesn't verify with the interactive PoRep, whatever the seed.
+    let interactive = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    assert!(verify_seal_non_interactive::<SectorShape2KiB>(
+        interactive,
+        comm_r,
+        comm_d,
+        prover_id,
+        sector_id,
+        ticket,
+        &proof,
+    )
+    .is_err());
+    assert!(!verify_seal::<SectorShape2KiB>(
+        interactive,
+        comm_r,
+        comm_d,
+        prover_id,
+        sector_id,
+        ticket,
+        [0; 32],
+        &proof,
+    )?);
+
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_seal_commit_phase2_batch_2kib() -> Result<()> {
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+
+    // More sectors than fit in a batch, so that the last batch is partial.
+    let mut sectors = Vec::new();
+    let mut seals = Vec::new();
+    for _ in 0..3 {
+        let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+        let sealed_sector_file = NamedTempFile::new()?;
+        let cache_dir = tempdir()?;
+        let sector_id: SectorId = rng.gen::<u64>().into();
+        let ticket = rng.gen();
+        let seed = rng.gen();
+
+        let (piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+            config,
+            prover_id,
+            sector_id,
+            ticket,
+            &cache_dir,
+            &mut piece_file,
+            &sealed_sector_file,
+        )?;
+        let pre_commit_output = seal_pre_commit_phase2(
+            config,
+            phase1_output,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+        )?;
+        let phase1_output = seal_commit_phase1::<_, SectorShape2KiB>(
+            config,
+            cache_dir.path(),
+            sealed_sector_file.path(),
+            prover_id,
+            sector_id,
+            ticket,
+            seed,
+            pre_commit_output.clone(),
+            &piece_infos,
+        )?;
+
+        seals.push((pre_commit_output, sector_id, ticket, seed));
+        sectors.push(SealCommitPhase2Input {
+            phase1_output,
+            prover_id,
+            sector_id,
+        });
+    }
+
+    let outputs = seal_commit_phase2_batch(config, sectors)?;
+    assert_eq!(outputs.len(), seals.len());
+    for (output, (pre_commit_output, sector_id, ticket, seed)) in outputs.iter().zip(&seals) {
+        assert!(verify_seal::<SectorShape2KiB>(
+            config,
+            pre_commit_output.comm_r,
+            pre_commit_output.comm_d,
+            prover_id,
+            *sector_id,
+            *ticket,
+            *seed,
+            &output.proof,
+        )?);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "remote-c2")]
+#[test]
+#[ignore]
+fn test_seal_commit_phase2_remote_2kib() -> Result<()> {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use filecoin_proofs::prove_remote_c2_request;
+    use storage_proofs_core::{settings::Settings, tenant};
+
+    init_logger();
+
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    let (mut piece_file, _piece_bytes) = generate_piece_file(sector_size)?;
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+    let sector_id: SectorId = rng.gen::<u64>().into();
+    let ticket = rng.gen();
+    let seed = rng.gen();
+
+    let (piece_infos, phase1_output) = run_seal_pre_commit_phase1::<SectorShape2KiB>(
+        config,
+        prover_id,
+        sector_id,
+        ticket,
+        &cache_dir,
+        &mut piece_file,
+        &sealed_sector_file,
+    )?;
+    let pre_commit_output = seal_pre_commit_phase2(
+        config,
+        phase1_output,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+    )?;
+    let phase1_output = seal_commit_phase1::<_, SectorShape2KiB>(
+        config,
+        cache_dir.path(),
+        sealed_sector_file.path(),
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        pre_commit_output.clone(),
+        &piece_infos,
+    )?;
+
+    // A proving service answering a single request with `prove_remote_c2_request`.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}/c2", listener.local_addr()?);
+    let service = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("content-length:") {
+                content_length = value.trim().parse()?;
+            }
+        }
+        let mut request = vec![0; content_length];
+        reader.read_exact(&mut request)?;
+
+        let response = prove_remote_c2_request(&request)?;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/cbor\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            response.len()
+        )?;
+        stream.write_all(&response)?;
+
+        Ok(())
+    });
+
+    let settings = Settings {
+        remote_c2_url: url,
+        ..(*tenant::settings()).clone()
+    };
+    let output = tenant::with_settings(settings, || {
+        seal_commit_phase2(config, phase1_output, prover_id, sector_id)
+    })?;
+    service.join().expect("the proving service panicked")?;
+
+    assert!(verify_seal::<SectorShape2KiB>(
+        config,
+        pre_commit_output.comm_r,
+        pre_commit_output.comm_d,
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        &output.proof,
+    )?);
+
+    Ok(())
+}
+
+#[test]
+fn test_check_replica_2kib() -> Result<()> {
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let (_, replica, comm_r, cache_dir) = create_seal::<_, SectorShape2KiB>(
+        &mut rng,
+        SECTOR_SIZE_2_KIB,
+        prover_id,
+        true,
+        &ARBITRARY_POREP_ID_V1_1_0,
+        ApiVersion::V1_1_0,
+    )?;
+    let sector_size = SectorSize(SECTOR_SIZE_2_KIB);
+
+    for check in &[ReplicaCheck::Sample(4), ReplicaCheck::Full] {
+        assert!(check_replica::<SectorShape2KiB>(
+            sector_size,
+            replica.path(),
+            cache_dir.path(),
+            comm_r,
+            *check,
+        )?);
+    }
+
+    // Rot the lowest byte of the last node, which a full check reads.
+    let mut data = fs::read(replica.path())?;
+    let last = data.len() - 32;
+    data[last] ^= 1;
+    fs::write(replica.path(), &data)?;
+    assert!(!check_replica::<SectorShape2KiB>(
+        sector_size,
+        replica.path(),
+        cache_dir.path(),
+        comm_r,
+        ReplicaCheck::Full,
+    )?);
+
+    // The replica doesn't match another commitment.
+    let mut other_comm_r = comm_r;
+    other_comm_r[0] ^= 1;
+    assert!(!check_replica::<SectorShape2KiB>(
+        sector_size,
+        replica.path(),
+        cache_dir.path(),
+        other_comm_r,
+        ReplicaCheck::Sample(1),
+    )?);
+
+    Ok(())
+}
+
+#[test]
+fn test_window_post_skipping_faults_2kib() -> Result<()> {
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let mut sectors = Vec::new();
+    let mut priv_replicas = BTreeMap::new();
+    let mut pub_replicas = BTreeMap::new();
+    for _ in 0..3 {
+        let (sector_id, replica, comm_r, cache_dir) = create_seal::<_, SectorShape2KiB>(
+            &mut rng,
+            sector_size,
+            prover_id,
+            true,
+            &ARBITRARY_POREP_ID_V1_1_0,
+            ApiVersion::V1_1_0,
+        )?;
+        priv_replicas.insert(
+            sector_id,
+            PrivateReplicaInfo::new(replica.path().into(), comm_r, cache_dir.path().into())?,
+        );
+        pub_replicas.insert(sector_id, PublicReplicaInfo::new(comm_r)?);
+        sectors.push((sector_id, replica, cache_dir));
+    }
+
+    // The replica of the first sector can't be read anymore.
+    let (faulty_id, faulty_replica, _) = &sectors[0];
+    fs::write(faulty_replica.path(), b"")?;
+
+    let config = PoStConfig {
+        sector_size: sector_size.into(),
+        sector_count: *WINDOW_POST_SECTOR_COUNT
+            .read()
+            .expect("WINDOW_POST_SECTOR_COUNT poisoned")
+            .get(&sector_size)
+            .expect("unknown sector size"),
+        challenge_count: WINDOW_POST_CHALLENGE_COUNT,
+        typ: PoStType::Window,
+        priority: false,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let randomness = [7u8; 32];
+
+    let output = generate_window_post_skipping_faults::<SectorShape2KiB>(
+        &config,
+        &randomness,
+        &priv_replicas,
+        prover_id,
+    )?;
+    assert_eq!(
+        output.faulty_sectors,
+        vec![*faulty_id].into_iter().collect()
+    );
+
+    // The proof is that of the remaining sectors.
+    pub_replicas.remove(faulty_id);
+    assert!(verify_window_post::<SectorShape2KiB>(
+        &config,
+        &randomness,
+        &pub_replicas,
+        prover_id,
+        &output.proof,
+    )?);
+
+    Ok(())
+}
+
+#[test]
+fn test_window_post_from_store_2kib() -> Result<()> {
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let prover_fr: DefaultTreeDomain = Fr::random(&mut rng).into();
+    let mut prover_id = [0u8; 32];
+    prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
+
+    let mut sectors = Vec::new();
+    let mut store = FsSectorStore::new();
+    let mut comm_rs = BTreeMap::new();
+    let mut pub_replicas = BTreeMap::new();
+    for _ in 0..2 {
+        let (sector_id, replica, comm_r, cache_dir) = create_seal::<_, SectorShape2KiB>(
+            &mut rng,
+            sector_size,
+            prover_id,
+            true,
+            &ARBITRARY_POREP_ID_V1_1_0,
+            ApiVersion::V1_1_0,
+        )?;
+        store.insert(sector_id, replica.path().into(), cache_dir.path().into());
+        comm_rs.insert(sector_id, comm_r);
+        pub_replicas.insert(sector_id, PublicReplicaInfo::new(comm_r)?);
+        sectors.push((replica, cache_dir));
+    }
+
+    let config = PoStConfig {
+        sector_size: sector_size.into(),
+        sector_count: *WINDOW_POST_SECTOR_COUNT
+            .read()
+            .expect("WINDOW_POST_SECTOR_COUNT poisoned")
+            .get(&sector_size)
+            .expect("unknown sector size"),
+        challenge_count: WINDOW_POST_CHALLENGE_COUNT,
+        typ: PoStType::Window,
+        priority: false,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let randomness = [9u8; 32];
+    let staging_dir = tempdir()?;
+
+    let proof = generate_window_post_from_store::<SectorShape2KiB, _>(
+        &config,
+        &randomness,
+        &store,
+        &comm_rs,
+        prover_id,
+        staging_dir.path(),
+    )?;
+    assert!(verify_window_post::<SectorShape2KiB>(
+        &config,
+        &randomness,
+        &pub_replicas,
+        prover_id,
+        &proof,
+    )?);
+    // The staged sectors are removed once they are proven.
+    assert_eq!(read_dir(staging_dir.path())?.count(), 0);
 
-    let mut porep_id = [0u8; 32];
-    porep_id[..8].copy_from_slice(&porep_id_v1_1.to_le_bytes());
-    assert!(!is_legacy_porep_id(porep_id));
-    run_resumable_seal::<SectorShape2KiB>(false, 0, &porep_id, ApiVersion::V1_1_0);
-    run_resumable_seal::<SectorShape2KiB>(false, 1, &porep_id, ApiVersion::V1_1_0);
+    Ok(())
 }
 
-/// Create a seal, delete a layer and resume
-///
-/// The current code works on two layers only. The `layer_to_delete` specifies (zero-based) which
-/// layer should be deleted.
-fn run_resumable_seal<Tree: 'static + MerkleTreeTrait>(
-    skip_proofs: bool,
-    layer_to_delete: usize,
-    porep_id: &[u8; 32],
-    api_version: ApiVersion,
-) {
+#[test]
+fn test_piece_inclusion_proof_2kib() -> Result<()> {
     init_logger();
 
     let sector_size = SECTOR_SIZE_2_KIB;
@@ -626,95 +1522,145 @@ fn run_resumable_seal<Tree: 'static + MerkleTreeTrait>(
     let mut prover_id = [0u8; 32];
     prover_id.copy_from_slice(AsRef::<[u8]>::as_ref(&prover_fr));
 
-    let (mut piece_file, piece_bytes) =
-        generate_piece_file(sector_size).expect("failed to generate piece file");
-    let sealed_sector_file = NamedTempFile::new().expect("failed to created sealed sector file");
-    let cache_dir = tempdir().expect("failed to create temp dir");
+    // Two pieces, each filling half of the sector.
+    let piece_size = UnpaddedBytesAmount::from(PaddedBytesAmount(sector_size / 2));
+    let mut staged_sector_file = NamedTempFile::new()?;
+    let mut piece_infos = Vec::new();
+    let mut piece_lengths = Vec::new();
+    for _ in 0..2 {
+        let piece_bytes: Vec<u8> = (0..piece_size.0).map(|_| random::<u8>()).collect();
+        let (piece_info, _) = add_piece(
+            &piece_bytes[..],
+            &mut staged_sector_file,
+            piece_size,
+            &piece_lengths,
+        )?;
+        piece_infos.push(piece_info);
+        piece_lengths.push(piece_size);
+    }
+    let offsets = [UnpaddedByteIndex(0), UnpaddedByteIndex::from(piece_size)];
+    let comm_d = compute_comm_d(SectorSize(sector_size), &piece_infos)?;
+
+    for (piece_info, offset) in piece_infos.iter().zip(offsets.iter()) {
+        let proof = generate_piece_inclusion_proof_from_data(
+            SectorSize(sector_size),
+            staged_sector_file.path(),
+            piece_info,
+            *offset,
+        )?;
+        assert!(verify_piece_inclusion_proof(
+            SectorSize(sector_size),
+            &comm_d,
+            piece_info,
+            *offset,
+            &proof
+        )?);
+    }
 
-    let config = porep_config(sector_size, *porep_id, api_version);
-    let ticket = rng.gen();
-    let sector_id = rng.gen::<u64>().into();
+    // A piece is only included at its own offset.
+    let proof = generate_piece_inclusion_proof_from_data(
+        SectorSize(sector_size),
+        staged_sector_file.path(),
+        &piece_infos[0],
+        offsets[0],
+    )?;
+    assert!(!verify_piece_inclusion_proof(
+        SectorSize(sector_size),
+        &comm_d,
+        &piece_infos[0],
+        offsets[1],
+        &proof
+    )?);
+    assert!(generate_piece_inclusion_proof_from_data(
+        SectorSize(sector_size),
+        staged_sector_file.path(),
+        &piece_infos[0],
+        offsets[1],
+    )
+    .is_err());
 
-    // First create seals as expected
-    run_seal_pre_commit_phase1::<Tree>(
+    // The data tree stored by sealing yields the same proofs.
+    let sealed_sector_file = NamedTempFile::new()?;
+    let cache_dir = tempdir()?;
+    let config = porep_config(sector_size, ARBITRARY_POREP_ID_V1_1_0, ApiVersion::V1_1_0);
+    let phase1_output = seal_pre_commit_phase1::<_, _, _, SectorShape2KiB>(
         config,
+        cache_dir.path(),
+        staged_sector_file.path(),
+        sealed_sector_file.path(),
         prover_id,
-        sector_id,
-        ticket,
-        &cache_dir,
-        &mut piece_file,
-        &sealed_sector_file,
-    )
-    .expect("failed to run seal pre commit phase1");
-    let layers = get_layer_file_paths(&cache_dir);
-    assert_eq!(layers.len(), 2, "not all expected layers were created");
+        rng.gen::<u64>().into(),
+        rng.gen(),
+        &piece_infos,
+    )?;
+    assert_eq!(phase1_output.comm_d, comm_d);
 
-    // Delete one layer, keep the other
-    clear_cache_dir_keep_data_layer(&cache_dir);
-    remove_file(&layers[layer_to_delete]).expect("failed to remove layer");
-    let layers_remaining = get_layer_file_paths(&cache_dir);
-    assert_eq!(layers_remaining.len(), 1, "expected one layer only");
-    if layer_to_delete == 0 {
-        assert_eq!(layers_remaining[0], layers[1], "wrong layer was removed");
-    } else {
-        assert_eq!(layers_remaining[0], layers[0], "wrong layer was removed");
-    }
+    let proof = generate_piece_inclusion_proof(
+        SectorSize(sector_size),
+        cache_dir.path(),
+        &piece_infos[1],
+        offsets[1],
+    )?;
+    assert_eq!(
+        proof,
+        generate_piece_inclusion_proof_from_data(
+            SectorSize(sector_size),
+            staged_sector_file.path(),
+            &piece_infos[1],
+            offsets[1],
+        )?
+    );
 
-    // Resume the seal
-    piece_file
-        .seek(SeekFrom::Start(0))
-        .expect("failed to seek piece file to start");
-    let (piece_infos, phase1_output) = run_seal_pre_commit_phase1::<Tree>(
-        config,
-        prover_id,
-        sector_id,
-        ticket,
-        &cache_dir,
-        &mut piece_file,
-        &sealed_sector_file,
-    )
-    .expect("failed to run seal pre commit phase1");
+    Ok(())
+}
 
-    // Running proofs clears the cache, hence we can only check for existence of files if we don't
-    // run them
-    if skip_proofs {
-        let layers_recreated = get_layer_file_paths(&cache_dir);
-        assert_eq!(
-            layers_recreated.len(),
-            2,
-            "not all expected layers were recreated"
-        );
-        assert_eq!(
-            layers_recreated, layers,
-            "recreated layers don't match original ones"
-        );
-    } else {
-        let pre_commit_output = seal_pre_commit_phase2(
-            config,
-            phase1_output,
-            cache_dir.path(),
-            sealed_sector_file.path(),
-        )
-        .expect("failed to run seal pre commit phase2");
+#[test]
+fn test_data_range_proof_2kib() -> Result<()> {
+    init_logger();
 
-        validate_cache_for_commit::<_, _, Tree>(cache_dir.path(), sealed_sector_file.path())
-            .expect("failed to validate cache for commit");
+    let sector_size = SECTOR_SIZE_2_KIB;
+    let piece_size = UnpaddedBytesAmount::from(PaddedBytesAmount(sector_size));
+    let piece_bytes: Vec<u8> = (0..piece_size.0).map(|_| random::<u8>()).collect();
+    let mut staged_sector_file = NamedTempFile::new()?;
+    let (piece_info, _) = add_piece(&piece_bytes[..], &mut staged_sector_file, piece_size, &[])?;
+    let comm_d = compute_comm_d(SectorSize(sector_size), &[piece_info])?;
+
+    for &(offset, len) in &[(0, 1), (100, 300), (127, 127), (1000, 1032), (2031, 1)] {
+        let data = &piece_bytes[offset..offset + len];
+        let proof = generate_data_range_proof_from_data(
+            SectorSize(sector_size),
+            staged_sector_file.path(),
+            UnpaddedByteIndex(offset as u64),
+            UnpaddedBytesAmount(len as u64),
+        )?;
+        assert!(verify_data_range_proof(
+            SectorSize(sector_size),
+            &comm_d,
+            UnpaddedByteIndex(offset as u64),
+            data,
+            &proof
+        )?);
 
-        let seed = rng.gen();
-        proof_and_unseal::<Tree>(
-            config,
-            cache_dir.path(),
-            &sealed_sector_file,
-            prover_id,
-            sector_id,
-            ticket,
-            seed,
-            pre_commit_output,
-            &piece_infos,
-            &piece_bytes,
-        )
-        .expect("failed to proof");
+        let mut tampered = data.to_vec();
+        tampered[len / 2] ^= 1;
+        assert!(!verify_data_range_proof(
+            SectorSize(sector_size),
+            &comm_d,
+            UnpaddedByteIndex(offset as u64),
+            &tampered,
+            &proof
+        )?);
     }
+
+    assert!(generate_data_range_proof_from_data(
+        SectorSize(sector_size),
+        staged_sector_file.path(),
+        UnpaddedByteIndex(2000),
+        UnpaddedBytesAmount(100),
+    )
+    .is_err());
+
+    Ok(())
 }
 
 #[test]
@@ -861,7 +1807,10 @@ fn winning_post<Tree: 'static + MerkleTreeTrait>(
     // 1)
     //
     let priv_replicas = vec![(sector_id, private_replica_info.clone())];
+    let warm_cache = warm_post_cache::<Tree>(&config, &priv_replicas)?;
+    assert!(warm_cache.warmed_bytes() > 0);
     let proof = generate_winning_post::<Tree>(&config, &randomness, &priv_replicas[..], prover_id)?;
+    drop(warm_cache);
 
     let valid =
         verify_winning_post::<Tree>(&config, &randomness, &pub_replicas[..], prover_id, &proof)?;
@@ -894,6 +1843,20 @@ fn winning_post<Tree: 'static + MerkleTreeTrait>(
         verify_winning_post::<Tree>(&config, &randomness, &pub_replicas[..], prover_id, &proof)?;
     assert!(valid, "proof did not verify");
 
+    // A corrupt t_aux doesn't keep the sector from being proven with the default rows_to_discard.
+    fs::write(
+        cache_dir.path().join(CacheKey::TAux.to_string()),
+        b"corrupt",
+    )?;
+    let private_replica_info =
+        PrivateReplicaInfo::<Tree>::new(replica.path().into(), comm_r, cache_dir.path().into())?;
+    assert_eq!(private_replica_info.rows_to_discard(), None);
+    let priv_replicas = vec![(sector_id, private_replica_info)];
+    let proof = generate_winning_post::<Tree>(&config, &randomness, &priv_replicas[..], prover_id)?;
+    let valid =
+        verify_winning_post::<Tree>(&config, &randomness, &pub_replicas[..], prover_id, &proof)?;
+    assert!(valid, "proof did not verify");
+
     replica.close()?;
 
     Ok(())
@@ -1183,6 +2146,38 @@ fn partition_window_post<Tree: 'static + MerkleTreeTrait>(
         prover_id,
     )?;
 
+    // The standalone challenges are those proven, and all sectors can be proven.
+    let post_challenges = generate_post_challenges::<Tree>(
+        &config,
+        &randomness,
+        prover_id,
+        &replica_sectors.iter().copied().collect(),
+    )?;
+    for (i, sector_id) in replica_sectors.iter().enumerate() {
+        assert_eq!(post_challenges[sector_id].leaves, challenges[sector_id]);
+        assert_eq!(
+            post_challenges[sector_id].partition,
+            i / config.sector_count
+        );
+
+        // The prefetched ranges hold every challenged node.
+        let ranges =
+            post_challenges[sector_id].replica_ranges(&config, &priv_replicas[sector_id])?;
+        for leaf in &challenges[sector_id] {
+            let offset = leaf * 32;
+            assert!(ranges
+                .iter()
+                .any(|range| range.start <= offset && offset + 32 <= range.end));
+        }
+    }
+    assert!(check_post_challenges(&config, &priv_replicas, &post_challenges).is_empty());
+    let mut missing_replicas = priv_replicas.clone();
+    missing_replicas.remove(&replica_sectors[0]);
+    assert_eq!(
+        check_post_challenges(&config, &missing_replicas, &post_challenges),
+        vec![replica_sectors[0]].into_iter().collect()
+    );
+
     let num_sectors_per_chunk = config.sector_count;
     let mut proofs = Vec::new();
 
@@ -1234,6 +2229,46 @@ fn partition_window_post<Tree: 'static + MerkleTreeTrait>(
         verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &final_proof)?;
     assert!(valid, "proofs did not verify");
 
+    // Proving resumes after the partitions proven before.
+    let first = generate_window_post_partition(&config, &randomness, &priv_replicas, prover_id, 0)?;
+    let mut resumed = Vec::new();
+    let proofs = generate_window_post_partitions(
+        &config,
+        &randomness,
+        &priv_replicas,
+        prover_id,
+        vec![first],
+        |partition_index, _| {
+            resumed.push(partition_index);
+            Ok(())
+        },
+    )?;
+    assert_eq!(resumed, (1..partitions).collect::<Vec<_>>());
+    let mut proofs = proofs;
+    let final_proof = merge_window_post_partition_proofs(proofs.clone())?;
+    let valid =
+        verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &final_proof)?;
+    assert!(valid, "resumed proofs did not verify");
+
+    // The last partition is proven again given only its sectors.
+    let last = partitions - 1;
+    let last_replicas: BTreeMap<_, _> = priv_replicas
+        .iter()
+        .skip(last * config.sector_count)
+        .map(|(sector_id, replica)| (*sector_id, replica.clone()))
+        .collect();
+    proofs[last] = generate_window_post_partition_of_sectors(
+        &config,
+        &randomness,
+        &last_replicas,
+        prover_id,
+        last,
+    )?;
+    let final_proof = merge_window_post_partition_proofs(proofs)?;
+    let valid =
+        verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &final_proof)?;
+    assert!(valid, "recomputed partition did not verify");
+
     Ok(())
 }
 
@@ -1299,9 +2334,11 @@ fn window_post<Tree: 'static + MerkleTreeTrait>(
     /////////////////////////////////////////////
     // The following methods of proof generation are functionally equivalent:
     // 1)
-    let proof = generate_window_post::<Tree>(&config, &randomness, &priv_replicas, prover_id)?;
+    let first_proof =
+        generate_window_post::<Tree>(&config, &randomness, &priv_replicas, prover_id)?;
 
-    let valid = verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &proof)?;
+    let valid =
+        verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &first_proof)?;
     assert!(valid, "proof did not verify");
 
     // 2)
@@ -1334,6 +2371,33 @@ fn window_post<Tree: 'static + MerkleTreeTrait>(
     let valid = verify_window_post::<Tree>(&config, &randomness, &pub_replicas, prover_id, &proof)?;
     assert!(valid, "proof did not verify");
 
+    let posts = [
+        WindowPoStToVerify {
+            randomness,
+            prover_id,
+            replicas: &pub_replicas,
+            proof: &first_proof,
+        },
+        WindowPoStToVerify {
+            randomness,
+            prover_id,
+            replicas: &pub_replicas,
+            proof: &proof,
+        },
+    ];
+    let valid = verify_window_post_batch::<Tree>(&config, &posts)?;
+    assert!(valid, "batch did not verify");
+
+    let wrong_posts = [
+        posts[0],
+        WindowPoStToVerify {
+            randomness: [0u8; 32],
+            ..posts[1]
+        },
+    ];
+    let valid = verify_window_post_batch::<Tree>(&config, &wrong_posts)?;
+    assert!(!valid, "batch with the wrong randomness verified");
+
     Ok(())
 }
 
@@ -1364,6 +2428,7 @@ fn porep_config(sector_size: u64, porep_id: [u8; 32], api_version: ApiVersion) -
         ),
         porep_id,
         api_version,
+        api_features: &[],
     }
 }
 
@@ -1447,6 +2512,29 @@ fn generate_proof<Tree: 'static + MerkleTreeTrait>(
         "seed and phase1 output ticket do not match"
     );
 
+    let challenges = generate_porep_challenges::<Tree>(
+        config,
+        phase1_output.comm_d,
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+    )?;
+    let proven: Vec<Vec<u64>> = phase1_output
+        .vanilla_proofs
+        .iter()
+        .map(|partition_proofs| {
+            partition_proofs
+                .iter()
+                .map(|proof| proof.comm_d_proofs.path_index() as u64)
+                .collect()
+        })
+        .collect();
+    ensure!(
+        challenges == proven,
+        "derived challenges do not match the proven challenges"
+    );
+
     let comm_r = phase1_output.comm_r;
     let inputs = get_seal_inputs::<Tree>(
         config,
@@ -1521,6 +2609,29 @@ fn unseal<Tree: 'static + MerkleTreeTrait>(
         &commit_output.proof,
     )?;
     assert!(verified, "failed to verify valid seal");
+
+    let seal = SealProofToVerify {
+        comm_r,
+        comm_d,
+        prover_id,
+        sector_id,
+        ticket,
+        seed,
+        proof: &commit_output.proof,
+    };
+    assert!(
+        verify_batch_seal_proofs::<Tree>(config, &[seal, seal])?,
+        "failed to batch verify valid seals"
+    );
+    let invalid = SealProofToVerify {
+        seed: [seed[0].wrapping_add(1); 32],
+        ..seal
+    };
+    assert!(
+        !verify_batch_seal_proofs::<Tree>(config, &[seal, invalid])?,
+        "batch verified an invalid seal"
+    );
+
     Ok(())
 }
 
@@ -1878,6 +2989,40 @@ fn create_seal_for_upgrade<R: Rng, Tree: 'static + MerkleTreeTrait<Hasher = Tree
     )?;
     ensure!(valid, "Compound proof failed to verify");
 
+    // The proofs of a batch of updates can be aggregated.
+    let inputs = get_sector_update_inputs::<Tree>(
+        porep_config,
+        comm_r,
+        encoded.comm_r_new,
+        encoded.comm_d_new,
+    )?;
+    let batch_inputs: Vec<Vec<Fr>> = inputs.iter().chain(inputs.iter()).cloned().collect();
+    let aggregate_proof = aggregate_empty_sector_update_proofs::<Tree>(
+        porep_config,
+        &[proof.clone(), proof.clone()],
+        &batch_inputs,
+        groth16::aggregate::AggregateVersion::V2,
+    )?;
+    let valid = verify_aggregate_sector_update_proofs::<Tree>(
+        porep_config,
+        aggregate_proof.clone(),
+        batch_inputs,
+        groth16::aggregate::AggregateVersion::V2,
+    )?;
+    ensure!(valid, "Aggregate update proof failed to verify");
+    let other_inputs =
+        get_sector_update_inputs::<Tree>(porep_config, comm_r, encoded.comm_r_new, comm_r)?;
+    let valid = verify_aggregate_sector_update_proofs::<Tree>(
+        porep_config,
+        aggregate_proof,
+        inputs.into_iter().chain(other_inputs).collect(),
+        groth16::aggregate::AggregateVersion::V2,
+    )?;
+    ensure!(
+        !valid,
+        "Aggregate update proof verified with the wrong inputs"
+    );
+
     let decoded_sector_file = NamedTempFile::new()?;
     // New replica (new_sealed_sector_file) is currently 0 bytes --
     // set a length here to ensure proper mmap later.  Lotus will
@@ -1935,8 +3080,50 @@ fn create_seal_for_upgrade<R: Rng, Tree: 'static + MerkleTreeTrait<Hasher = Tree
     // When the data is removed, it MUST match the original sealed data.
     compare_elements(remove_encoded_file.path(), sealed_sector_file.path())?;
 
+    // The sector key is also streamed to the sink as it is written.
+    let sector_key = remove_encoded_data_with_sink::<Tree, _>(
+        config,
+        remove_encoded_file.path(),
+        tempdir().expect("failed to create temp dir").path(),
+        new_sealed_sector_file.path(),
+        cache_dir.path(),
+        new_staged_sector_file.path(),
+        encoded.comm_d_new,
+        Vec::new(),
+    )?;
+    assert_eq!(sector_key, fs::read(sealed_sector_file.path())?);
+
     remove_encoded_file.close()?;
 
+    // The streaming variants decode the same data and regenerate the same sector key.
+    let decoded = decode_sector_data_to::<Tree, _>(
+        porep_config,
+        Vec::new(),
+        new_sealed_sector_file.path(),
+        sealed_sector_file.path(),
+        cache_dir.path(),
+        encoded.comm_d_new,
+    )?;
+    let staged_len = decoded.len();
+    assert_eq!(
+        decoded,
+        &fs::read(new_staged_sector_file.path())?[..staged_len]
+    );
+
+    let sector_key_file = NamedTempFile::new()?;
+    let sector_key_cache_dir = tempdir().expect("failed to create temp dir");
+    let sector_key_comm_r = generate_sector_key_from_replica_with_reader::<Tree, _>(
+        porep_config,
+        sector_key_file.path(),
+        sector_key_cache_dir.path(),
+        new_sealed_sector_file.path(),
+        cache_dir.path(),
+        fs::File::open(new_staged_sector_file.path())?,
+        encoded.comm_d_new,
+    )?;
+    assert_eq!(sector_key_comm_r, comm_r);
+    compare_elements(sector_key_file.path(), sealed_sector_file.path())?;
+
     clear_cache::<Tree>(cache_dir.path())?;
     clear_cache::<Tree>(new_cache_dir.path())?;
 
@@ -1,6 +1,10 @@
 use filecoin_proofs::{
-    with_shape, SECTOR_SIZE_16_MIB, SECTOR_SIZE_1_GIB, SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_GIB,
+    is_sector_shape_base, is_sector_shape_sub2, is_sector_shape_sub4, is_sector_shape_sub8,
+    is_sector_shape_top2, register_sector_size, with_shape, CustomSectorSize, SectorShape, LAYERS,
+    POREP_MINIMUM_CHALLENGES, POREP_PARTITIONS, PUBLISHED_SECTOR_SIZES, SECTOR_SIZE_16_GIB,
+    SECTOR_SIZE_16_MIB, SECTOR_SIZE_1_GIB, SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_GIB,
     SECTOR_SIZE_4_KIB, SECTOR_SIZE_512_MIB, SECTOR_SIZE_64_GIB, SECTOR_SIZE_8_MIB,
+    UNPUBLISHED_SECTOR_SIZES, WINDOW_POST_SECTOR_COUNT,
 };
 use generic_array::typenum::Unsigned;
 use storage_proofs_core::merkle::MerkleTreeTrait;
@@ -70,6 +74,7 @@ fn test_with_shape_macro() {
     test_with_shape_macro_aux(SECTOR_SIZE_16_MIB);
     test_with_shape_macro_aux(SECTOR_SIZE_512_MIB);
     test_with_shape_macro_aux(SECTOR_SIZE_1_GIB);
+    test_with_shape_macro_aux(SECTOR_SIZE_16_GIB);
     test_with_shape_macro_aux(SECTOR_SIZE_32_GIB);
     test_with_shape_macro_aux(SECTOR_SIZE_64_GIB);
 }
@@ -83,3 +88,89 @@ fn test_with_shape_macro_aux(sector_size: u64) {
         sector_size, arities, expected
     );
 }
+
+#[test]
+fn test_published_sector_sizes_are_registered() {
+    for sector_size in PUBLISHED_SECTOR_SIZES
+        .iter()
+        .chain(UNPUBLISHED_SECTOR_SIZES.iter())
+    {
+        let shapes = [
+            is_sector_shape_base(*sector_size),
+            is_sector_shape_sub2(*sector_size),
+            is_sector_shape_sub4(*sector_size),
+            is_sector_shape_sub8(*sector_size),
+            is_sector_shape_top2(*sector_size),
+        ];
+        assert_eq!(
+            shapes.iter().filter(|matches| **matches).count(),
+            1,
+            "sector size {} must have exactly one shape",
+            sector_size
+        );
+
+        assert!(POREP_MINIMUM_CHALLENGES
+            .read()
+            .expect("POREP_MINIMUM_CHALLENGES poisoned")
+            .contains_key(sector_size));
+        assert!(POREP_PARTITIONS
+            .read()
+            .expect("POREP_PARTITIONS poisoned")
+            .contains_key(sector_size));
+        assert!(LAYERS
+            .read()
+            .expect("LAYERS poisoned")
+            .contains_key(sector_size));
+        assert!(WINDOW_POST_SECTOR_COUNT
+            .read()
+            .expect("WINDOW_POST_SECTOR_COUNT poisoned")
+            .contains_key(sector_size));
+    }
+}
+
+#[test]
+fn test_register_custom_sector_size() {
+    // 8192 nodes, two base trees of 8^4 nodes.
+    let custom = CustomSectorSize {
+        sector_size: 1 << 18,
+        shape: SectorShape::Sub2,
+        porep_partitions: 1,
+        porep_minimum_challenges: 2,
+        layers: 2,
+        window_post_sector_count: 2,
+    };
+
+    assert!(register_sector_size(&CustomSectorSize {
+        shape: SectorShape::Base,
+        ..custom.clone()
+    })
+    .is_err());
+    assert!(register_sector_size(&CustomSectorSize {
+        sector_size: SECTOR_SIZE_2_KIB,
+        shape: SectorShape::Base,
+        ..custom.clone()
+    })
+    .is_err());
+    assert!(register_sector_size(&CustomSectorSize {
+        sector_size: SECTOR_SIZE_16_GIB,
+        shape: SectorShape::Sub4,
+        ..custom.clone()
+    })
+    .is_err());
+
+    register_sector_size(&custom).expect("register_sector_size failed");
+    register_sector_size(&custom).expect("registering again failed");
+    assert!(register_sector_size(&CustomSectorSize {
+        layers: 3,
+        ..custom.clone()
+    })
+    .is_err());
+
+    assert!(is_sector_shape_sub2(custom.sector_size));
+    assert!(!is_sector_shape_base(custom.sector_size));
+    assert_eq!(with_shape!(custom.sector_size, arities_to_usize), (8, 2, 0));
+    assert_eq!(
+        LAYERS.read().expect("LAYERS poisoned")[&custom.sector_size],
+        2
+    );
+}
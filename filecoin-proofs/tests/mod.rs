@@ -37,6 +37,7 @@ fn test_verify_seal_fr32_validation() {
                 ),
                 porep_id: arbitrary_porep_id,
                 api_version: ApiVersion::V1_1_0,
+                api_features: &[],
             },
             not_convertible_to_fr_bytes,
             convertible_to_fr_bytes,
@@ -76,6 +77,7 @@ fn test_verify_seal_fr32_validation() {
                 ),
                 porep_id: arbitrary_porep_id,
                 api_version: ApiVersion::V1_1_0,
+                api_features: &[],
             },
             convertible_to_fr_bytes,
             not_convertible_to_fr_bytes,
@@ -119,6 +121,7 @@ fn test_verify_seal_fr32_validation() {
                 ),
                 porep_id: arbitrary_porep_id,
                 api_version: ApiVersion::V1_1_0,
+                api_features: &[],
             },
             non_zero_commitment_fr_bytes,
             non_zero_commitment_fr_bytes,
@@ -11,7 +11,9 @@ mod consts;
 mod platform;
 mod sha256;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sha256_avx2;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod sha256_intrinsics;
 mod sha256_utils;
 
-pub use sha256::Sha256;
+pub use sha256::{implementation_name, Sha256};
@@ -1,14 +1,16 @@
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-use crate::sha256_intrinsics;
 use crate::sha256_utils;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::{sha256_avx2, sha256_intrinsics};
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum Platform {
     Portable,
-    #[cfg(feature = "asm")]
+    #[cfg(all(feature = "asm", not(target_env = "msvc")))]
     Asm,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Avx2,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     Sha,
 }
 
@@ -16,15 +18,29 @@ enum Platform {
 pub struct Implementation(Platform);
 
 impl Implementation {
+    /// Selects the fastest implementation the CPU supports, and logs it together with the
+    /// relevant CPU features.
+    ///
+    /// CPUs with AVX-512 but without the SHA extensions (Skylake-SP, Cascade Lake) use the AVX2
+    /// implementation, as the AVX-512 intrinsics are not available on the stable toolchain.
     pub fn detect() -> Self {
+        let implementation = Self::fastest();
+        implementation.log_selection();
+        implementation
+    }
+
+    fn fastest() -> Self {
         // Try the different implementations in order of how fast/modern they are.
         #[cfg(target_arch = "x86_64")]
         {
             if let Some(sha_impl) = Self::sha_if_supported() {
                 return sha_impl;
             }
+            if let Some(avx2_impl) = Self::avx2_if_supported() {
+                return avx2_impl;
+            }
         }
-        #[cfg(feature = "asm")]
+        #[cfg(all(feature = "asm", not(target_env = "msvc")))]
         {
             if let Some(asm_impl) = Self::asm_if_supported() {
                 return asm_impl;
@@ -43,12 +59,12 @@ impl Implementation {
     pub fn sha_if_supported() -> Option<Self> {
         // Use raw_cpuid instead of is_x86_feature_detected, to ensure the check
         // never happens at compile time.
-        let is_runtime_ok = cpuid_bool::cpuid_bool!("sha");
+        let is_runtime_ok = cpuid_bool::cpuid_bool!("sha", "sse2", "ssse3", "sse4.1");
 
         #[cfg(target_feature = "sha")]
         {
             if !is_runtime_ok {
-                println!("WARN: sha-ni not available, falling back");
+                log::warn!("sha-ni not available, falling back");
             }
         }
 
@@ -60,11 +76,44 @@ impl Implementation {
         None
     }
 
-    #[cfg(feature = "asm")]
+    #[cfg(target_arch = "x86_64")]
+    pub fn avx2_if_supported() -> Option<Self> {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("bmi2") {
+            return Some(Implementation(Platform::Avx2));
+        }
+
+        None
+    }
+
+    #[cfg(all(feature = "asm", not(target_env = "msvc")))]
     pub fn asm_if_supported() -> Option<Self> {
+        // On aarch64 the assembly uses the SHA2 crypto extensions.
+        #[cfg(target_arch = "aarch64")]
+        {
+            if !aarch64_sha2_detected() {
+                return None;
+            }
+        }
+
         Some(Implementation(Platform::Asm))
     }
 
+    /// Name of the implementation: `sha-ni`, `avx2`, `asm`, `arm-sha2` (the assembly on aarch64)
+    /// or `portable`.
+    pub fn name(self) -> &'static str {
+        match self.0 {
+            Platform::Portable => "portable",
+            #[cfg(all(feature = "asm", not(target_env = "msvc")))]
+            Platform::Asm if cfg!(target_arch = "aarch64") => "arm-sha2",
+            #[cfg(all(feature = "asm", not(target_env = "msvc")))]
+            Platform::Asm => "asm",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::Avx2 => "avx2",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::Sha => "sha-ni",
+        }
+    }
+
     #[inline]
     pub fn compress256(self, state: &mut [u32; 8], blocks: &[&[u8]]) {
         match self.0 {
@@ -75,7 +124,11 @@ impl Implementation {
             Platform::Sha => {
                 unsafe { sha256_intrinsics::compress256(state, blocks) };
             }
-            #[cfg(feature = "asm")]
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::Avx2 => {
+                unsafe { sha256_avx2::compress256(state, blocks) };
+            }
+            #[cfg(all(feature = "asm", not(target_env = "msvc")))]
             Platform::Asm => {
                 let mut buffer = [0u8; 64];
                 for block in blocks.chunks(2) {
@@ -86,4 +139,89 @@ impl Implementation {
             }
         }
     }
+
+    #[cfg(target_arch = "x86_64")]
+    fn log_selection(self) {
+        log::info!(
+            "sha256: using {} (sha: {}, avx2: {}, bmi2: {}, avx512f: {})",
+            self.name(),
+            is_x86_feature_detected!("sha"),
+            is_x86_feature_detected!("avx2"),
+            is_x86_feature_detected!("bmi2"),
+            is_x86_feature_detected!("avx512f"),
+        );
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn log_selection(self) {
+        log::info!(
+            "sha256: using {} (sha2: {})",
+            self.name(),
+            aarch64_sha2_detected()
+        );
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn log_selection(self) {
+        log::info!("sha256: using {}", self.name());
+    }
+}
+
+/// Whether the CPU has the SHA2 crypto extensions, which all Apple processors have.
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+fn aarch64_sha2_detected() -> bool {
+    unsafe { libc::getauxval(libc::AT_HWCAP) & libc::HWCAP_SHA2 != 0 }
+}
+
+#[cfg(all(target_arch = "aarch64", target_os = "macos"))]
+fn aarch64_sha2_detected() -> bool {
+    true
+}
+
+#[cfg(all(
+    target_arch = "aarch64",
+    not(any(target_os = "linux", target_os = "macos"))
+))]
+fn aarch64_sha2_detected() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{RngCore, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    #[test]
+    fn test_implementations_match_portable() {
+        let rng = &mut XorShiftRng::from_seed([
+            0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06,
+            0xbc, 0xe5,
+        ]);
+
+        let mut implementations: Vec<Implementation> = Vec::new();
+        #[cfg(target_arch = "x86_64")]
+        {
+            implementations.extend(Implementation::sha_if_supported());
+            implementations.extend(Implementation::avx2_if_supported());
+        }
+        #[cfg(all(feature = "asm", not(target_env = "msvc")))]
+        implementations.extend(Implementation::asm_if_supported());
+
+        // Odd and even numbers of blocks, as the AVX2 implementation handles them in pairs.
+        for halves in (2..=12).step_by(2) {
+            let mut input = vec![0u8; 32 * halves];
+            rng.fill_bytes(&mut input);
+            let blocks = input.chunks(32).collect::<Vec<_>>();
+
+            let mut expected = [0u32; 8];
+            Implementation::portable().compress256(&mut expected, &blocks);
+            for implementation in &implementations {
+                let mut state = [0u32; 8];
+                implementation.compress256(&mut state, &blocks);
+                assert_eq!(state, expected, "{} differs", implementation.name());
+            }
+        }
+    }
 }
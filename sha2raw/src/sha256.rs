@@ -7,6 +7,12 @@ lazy_static! {
     static ref IMPL: Implementation = Implementation::detect();
 }
 
+/// Name of the compression function implementation detected at runtime, see
+/// `Implementation::name`.
+pub fn implementation_name() -> &'static str {
+    IMPL.name()
+}
+
 #[derive(Clone)]
 pub struct Sha256 {
     len: u64,
@@ -0,0 +1,175 @@
+//! SHA-256 for CPUs with AVX2 and BMI2, but without the SHA extensions.
+//!
+//! The message schedules of two blocks are computed at once with AVX2, one block in each 128-bit
+//! lane, and the rounds are computed with the `rorx` rotations of BMI2, following Intel's
+//! "Fast SHA-256 Implementations on Intel Architecture Processors".
+
+#![allow(clippy::many_single_char_names)]
+#![allow(clippy::cast_ptr_alignment)] // Safe to cast without alignment checks as the loads and stores do not require alignment.
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64 as x86;
+
+use x86::{
+    __m128i, __m256i, _mm256_add_epi32, _mm256_alignr_epi8, _mm256_blend_epi32,
+    _mm256_broadcastsi128_si256, _mm256_castsi128_si256, _mm256_castsi256_si128,
+    _mm256_extracti128_si256, _mm256_inserti128_si256, _mm256_or_si256, _mm256_set_epi64x,
+    _mm256_setzero_si256, _mm256_shuffle_epi32, _mm256_shuffle_epi8, _mm256_slli_epi32,
+    _mm256_srli_epi32, _mm256_xor_si256, _mm_loadu_si128, _mm_storeu_si128,
+};
+
+use crate::consts::K32;
+
+/// Process a block with the SHA-256 algorithm.
+#[inline]
+#[target_feature(enable = "avx2,bmi2")]
+pub unsafe fn compress256(state: &mut [u32; 8], blocks: &[&[u8]]) {
+    assert_eq!(blocks.len() % 2, 0);
+
+    // Swaps the bytes of every word, SHA-256 is big endian.
+    let mask = _mm256_set_epi64x(
+        0x0c0d_0e0f_0809_0a0bu64 as i64,
+        0x0405_0607_0001_0203u64 as i64,
+        0x0c0d_0e0f_0809_0a0bu64 as i64,
+        0x0405_0607_0001_0203u64 as i64,
+    );
+    // The words of the schedules of both blocks, with the round constants added.
+    let mut wk = [[0u32; 64]; 2];
+
+    for pair in blocks.chunks(4) {
+        // Without a second block, the schedule of the first one is computed twice.
+        let second = if pair.len() == 4 { &pair[2..] } else { pair };
+        for half in pair.iter().chain(second) {
+            assert_eq!(half.len(), 32);
+        }
+
+        let mut w = [
+            load(&pair[0][..16], &second[0][..16], mask),
+            load(&pair[0][16..], &second[0][16..], mask),
+            load(&pair[1][..16], &second[1][..16], mask),
+            load(&pair[1][16..], &second[1][16..], mask),
+        ];
+        for t in 0..16 {
+            let k = _mm256_broadcastsi128_si256(_mm_loadu_si128(
+                K32.as_ptr().add(4 * t) as *const __m128i
+            ));
+            store(&mut wk, t, _mm256_add_epi32(w[t % 4], k));
+            if t < 12 {
+                w[t % 4] = schedule(w[t % 4], w[(t + 1) % 4], w[(t + 2) % 4], w[(t + 3) % 4]);
+            }
+        }
+
+        rounds(state, &wk[0]);
+        if pair.len() == 4 {
+            rounds(state, &wk[1]);
+        }
+    }
+}
+
+/// Loads four words of a block into the low lane and four words of the other into the high one.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn load(low: &[u8], high: &[u8], mask: __m256i) -> __m256i {
+    let low = _mm_loadu_si128(low.as_ptr() as *const __m128i);
+    let high = _mm_loadu_si128(high.as_ptr() as *const __m128i);
+    _mm256_shuffle_epi8(
+        _mm256_inserti128_si256(_mm256_castsi128_si256(low), high, 1),
+        mask,
+    )
+}
+
+/// Stores the words `4 * t..4 * t + 4` of the schedules of both blocks.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn store(wk: &mut [[u32; 64]; 2], t: usize, words: __m256i) {
+    _mm_storeu_si128(
+        wk[0].as_mut_ptr().add(4 * t) as *mut __m128i,
+        _mm256_castsi256_si128(words),
+    );
+    _mm_storeu_si128(
+        wk[1].as_mut_ptr().add(4 * t) as *mut __m128i,
+        _mm256_extracti128_si256(words, 1),
+    );
+}
+
+/// Computes the next four words of the schedules from the last sixteen, `w0` being the oldest.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn schedule(w0: __m256i, w1: __m256i, w2: __m256i, w3: __m256i) -> __m256i {
+    // w[t - 16] + sigma0(w[t - 15]) + w[t - 7]
+    let words = _mm256_add_epi32(
+        _mm256_add_epi32(w0, sigma0(_mm256_alignr_epi8(w1, w0, 4))),
+        _mm256_alignr_epi8(w3, w2, 4),
+    );
+
+    // sigma1(w[t - 2]) depends on the first two of the new words for the last two of them.
+    let first = sigma1(_mm256_shuffle_epi32(w3, 0b01_00_11_10));
+    let words = _mm256_add_epi32(
+        words,
+        _mm256_blend_epi32(_mm256_setzero_si256(), first, 0b0011_0011),
+    );
+    let last = sigma1(_mm256_shuffle_epi32(words, 0b01_00_01_00));
+    _mm256_add_epi32(
+        words,
+        _mm256_blend_epi32(_mm256_setzero_si256(), last, 0b1100_1100),
+    )
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn sigma0(x: __m256i) -> __m256i {
+    _mm256_xor_si256(
+        _mm256_xor_si256(
+            _mm256_or_si256(_mm256_srli_epi32(x, 7), _mm256_slli_epi32(x, 25)),
+            _mm256_or_si256(_mm256_srli_epi32(x, 18), _mm256_slli_epi32(x, 14)),
+        ),
+        _mm256_srli_epi32(x, 3),
+    )
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn sigma1(x: __m256i) -> __m256i {
+    _mm256_xor_si256(
+        _mm256_xor_si256(
+            _mm256_or_si256(_mm256_srli_epi32(x, 17), _mm256_slli_epi32(x, 15)),
+            _mm256_or_si256(_mm256_srli_epi32(x, 19), _mm256_slli_epi32(x, 13)),
+        ),
+        _mm256_srli_epi32(x, 10),
+    )
+}
+
+/// The 64 rounds of a block, `wk` being its schedule with the round constants added. Inlined into
+/// `compress256`, so that the rotations are compiled to `rorx`.
+#[inline(always)]
+fn rounds(state: &mut [u32; 8], wk: &[u32; 64]) {
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+    for &x in wk.iter() {
+        let t1 = h
+            .wrapping_add(e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25))
+            .wrapping_add((e & f) ^ (!e & g))
+            .wrapping_add(x);
+        let t2 = (a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22))
+            .wrapping_add((a & b) ^ (a & c) ^ (b & c));
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+    state[4] = state[4].wrapping_add(e);
+    state[5] = state[5].wrapping_add(f);
+    state[6] = state[6].wrapping_add(g);
+    state[7] = state[7].wrapping_add(h);
+}
@@ -14,7 +14,11 @@ use x86::{
 
 /// Process a block with the SHA-256 algorithm.
 /// Based on https://github.com/noloader/SHA-Intrinsics/blob/master/sha256-x86.c
-#[inline(always)]
+///
+/// Compiled with the SHA extensions enabled, whatever the build target, so the intrinsics are
+/// inlined. Callers must check that the CPU supports them.
+#[inline]
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
 pub unsafe fn compress256(state: &mut [u32; 8], blocks: &[&[u8]]) {
     assert_eq!(blocks.len() % 2, 0);
 
@@ -53,6 +53,18 @@ impl FromStr for ApiVersion {
     }
 }
 
+/// Protocol features of a proof on top of those of its [`ApiVersion`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ApiFeature {
+    /// The challenges of the PoRep are selected out of synthetic challenges, which are proven
+    /// right after PC2, so that the layers don't have to be kept until the commit.
+    SyntheticPoRep,
+    /// The challenges of the PoRep only depend on its commitments, not on a seed, so that a sector
+    /// can be committed without waiting for chain randomness. They are proven by circuits of their
+    /// own, with more challenges than the interactive PoRep.
+    NonInteractivePoRep,
+}
+
 #[test]
 fn test_fmt() {
     assert_eq!(format!("{}", ApiVersion::V1_0_0), "1.0.0");
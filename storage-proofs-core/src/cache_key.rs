@@ -7,6 +7,8 @@ pub enum CacheKey {
     CommDTree,
     CommCTree,
     CommRLastTree,
+    ReplicaEncoding,
+    SynthProofs,
 }
 
 impl Display for CacheKey {
@@ -17,6 +19,8 @@ impl Display for CacheKey {
             CacheKey::CommDTree => write!(f, "tree-d"),
             CacheKey::CommCTree => write!(f, "tree-c"),
             CacheKey::CommRLastTree => write!(f, "tree-r-last"),
+            CacheKey::ReplicaEncoding => write!(f, "replica-encoding"),
+            CacheKey::SynthProofs => write!(f, "syn-porep-vanilla-proofs.dat"),
         }
     }
 }
@@ -1,3 +1,6 @@
+use std::mem;
+use std::sync::Arc;
+
 use anyhow::{ensure, Context};
 use bellperson::{
     groth16::{
@@ -8,21 +11,26 @@ use bellperson::{
         create_random_proof_batch, create_random_proof_batch_in_priority, verify_proofs_batch,
         PreparedVerifyingKey,
     },
-    Circuit,
+    Circuit, ConstraintSystem, SynthesisError,
 };
 use blstrs::{Bls12, Scalar as Fr};
-use log::info;
+use log::{debug, info};
 use rand::{rngs::OsRng, RngCore};
 use rayon::prelude::{
     IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
 };
 
 use crate::{
+    control,
     error::Result,
+    gpu_framework::{enter_gpu_framework, gpu_framework},
+    gpu_scheduler::{gpu_scheduler, GpuRequest, GpuWork},
     multi_proof::MultiProof,
     parameter_cache::{CacheableParameters, ParameterSetMetadata},
     partitions::partition_count,
+    priority::current_priority_class,
     proof::ProofScheme,
+    tenant::{current_tenant, with_current_tenant, Tenant},
 };
 
 #[derive(Clone)]
@@ -205,16 +213,18 @@ where
         let inputs: Vec<_> = multi_proofs
             .par_iter()
             .zip(public_inputs.par_iter())
-            .flat_map(|(multi_proof, pub_inputs)| {
+            .map(|(multi_proof, pub_inputs)| {
                 (0..multi_proof.circuit_proofs.len())
                     .into_par_iter()
                     .map(|k| {
                         Self::generate_public_inputs(pub_inputs, vanilla_public_params, Some(k))
                     })
                     .collect::<Result<Vec<_>>>()
-                    .expect("Invalid public inputs") // TODO: improve error handling
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
         let circuit_proofs: Vec<_> = multi_proofs
             .iter()
             .flat_map(|m| m.circuit_proofs.iter())
@@ -236,7 +246,6 @@ where
         groth_params: &groth16::MappedParameters<Bls12>,
         priority: bool,
     ) -> Result<Vec<groth16::Proof<Bls12>>> {
-        let mut rng = OsRng;
         ensure!(
             !vanilla_proofs.is_empty(),
             "cannot create a circuit proof over missing vanilla proofs"
@@ -256,11 +265,7 @@ where
             })
             .collect::<Result<Vec<_>>>()?;
 
-        let groth_proofs = if priority {
-            create_random_proof_batch_in_priority(circuits, groth_params, &mut rng)?
-        } else {
-            create_random_proof_batch(circuits, groth_params, &mut rng)?
-        };
+        let groth_proofs = prove_circuits(circuits, groth_params, priority)?;
 
         groth_proofs
             .into_iter()
@@ -273,6 +278,68 @@ where
             .collect()
     }
 
+    /// circuit_proofs_batch is like circuit_proofs, for the vanilla proofs of several public
+    /// inputs, e.g. of several sectors. The circuits of all of them are proven together, so that
+    /// they share the FFT and multiexp passes on the GPU. It returns the groth proofs of each
+    /// public input, in order.
+    fn circuit_proofs_batch(
+        pub_ins: &[S::PublicInputs],
+        vanilla_proofs: Vec<Vec<S::Proof>>,
+        pub_params: &S::PublicParams,
+        groth_params: &groth16::MappedParameters<Bls12>,
+        priority: bool,
+    ) -> Result<Vec<Vec<groth16::Proof<Bls12>>>> {
+        ensure!(
+            pub_ins.len() == vanilla_proofs.len(),
+            "{} public inputs for the vanilla proofs of {}",
+            pub_ins.len(),
+            vanilla_proofs.len()
+        );
+        ensure!(
+            vanilla_proofs.iter().all(|proofs| !proofs.is_empty()),
+            "cannot create a circuit proof over missing vanilla proofs"
+        );
+
+        let counts: Vec<usize> = vanilla_proofs.iter().map(Vec::len).collect();
+        let circuits = vanilla_proofs
+            .into_par_iter()
+            .zip(pub_ins.par_iter())
+            .flat_map(|(proofs, pub_in)| {
+                proofs
+                    .into_par_iter()
+                    .enumerate()
+                    .map(move |(k, vanilla_proof)| {
+                        Self::circuit(
+                            pub_in,
+                            C::ComponentPrivateInputs::default(),
+                            &vanilla_proof,
+                            pub_params,
+                            Some(k),
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let groth_proofs = prove_circuits(circuits, groth_params, priority)?;
+
+        let mut groth_proofs = groth_proofs.into_iter();
+        counts
+            .into_iter()
+            .map(|count| {
+                groth_proofs
+                    .by_ref()
+                    .take(count)
+                    .map(|groth_proof| {
+                        let mut proof_vec = Vec::new();
+                        groth_proof.write(&mut proof_vec)?;
+                        let gp = groth16::Proof::<Bls12>::read(&proof_vec[..])?;
+                        Ok(gp)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect()
+    }
+
     /// Given a prover_srs key, a list of groth16 proofs, and an ordered list of seeds
     /// (used to derive the PoRep challenges) hashed pair-wise with the comm_rs using sha256, aggregate them all into
     /// an AggregateProof type.
@@ -497,3 +564,86 @@ where
         Ok(res)
     }
 }
+
+/// A circuit synthesized on behalf of the tenant it is proven for, as bellperson synthesizes the
+/// circuits of a batch on the threads of its rayon pool.
+struct TenantCircuit<C> {
+    circuit: C,
+    tenant: Option<Arc<Tenant>>,
+}
+
+impl<C: Circuit<Fr>> Circuit<Fr> for TenantCircuit<C> {
+    fn synthesize<CS: ConstraintSystem<Fr>>(
+        self,
+        cs: &mut CS,
+    ) -> std::result::Result<(), SynthesisError> {
+        let TenantCircuit { circuit, tenant } = self;
+        with_current_tenant(tenant, || circuit.synthesize(cs))
+    }
+}
+
+fn create_random_proofs<C: Circuit<Fr> + Send>(
+    circuits: Vec<C>,
+    groth_params: &groth16::MappedParameters<Bls12>,
+    priority: bool,
+) -> Result<Vec<groth16::Proof<Bls12>>> {
+    let tenant = current_tenant();
+    let circuits: Vec<_> = circuits
+        .into_iter()
+        .map(|circuit| TenantCircuit {
+            circuit,
+            tenant: tenant.clone(),
+        })
+        .collect();
+    let mut rng = OsRng;
+    let groth_proofs = if priority {
+        create_random_proof_batch_in_priority(circuits, groth_params, &mut rng)?
+    } else {
+        create_random_proof_batch(circuits, groth_params, &mut rng)?
+    };
+
+    Ok(groth_proofs)
+}
+
+/// Proves `circuits` on the GPU framework of the process, see `enter_gpu_framework`, once the
+/// GPU scheduler of the calling thread grants them the GPU, see `gpu_scheduler`.
+fn prove_circuits<C: Circuit<Fr> + Send>(
+    mut circuits: Vec<C>,
+    groth_params: &groth16::MappedParameters<Bls12>,
+    priority: bool,
+) -> Result<Vec<groth16::Proof<Bls12>>> {
+    enter_gpu_framework(gpu_framework());
+    let class = current_priority_class();
+    let request = GpuRequest {
+        work: GpuWork::Proof,
+        class,
+        priority,
+        circuits: circuits.len(),
+        job: control::current_job(),
+    };
+    let mut grant = gpu_scheduler().acquire(&request)?;
+    let priority = grant.priority();
+    let chunk_len = match grant.chunk_len() {
+        Some(chunk_len) if chunk_len > 0 => chunk_len,
+        _ => return create_random_proofs(circuits, groth_params, priority),
+    };
+
+    let mut groth_proofs = Vec::with_capacity(circuits.len());
+    loop {
+        let rest = circuits.split_off(chunk_len.min(circuits.len()));
+        let chunk = mem::replace(&mut circuits, rest);
+        groth_proofs.extend(create_random_proofs(chunk, groth_params, priority)?);
+        if circuits.is_empty() {
+            break;
+        }
+        if grant.yield_to_urgent() {
+            debug!(
+                "resuming the proofs of {:?}, {} circuits left",
+                class,
+                circuits.len()
+            );
+        }
+    }
+
+    Ok(groth_proofs)
+}
@@ -0,0 +1,446 @@
+//! Cooperative pause/resume control for long running sealing jobs.
+//!
+//! Sealing phases register themselves as a job and call [`checkpoint`] at points where stopping
+//! is safe (between SDR layers, between tree builds, ...). When a job has been asked to pause,
+//! the next checkpoint blocks the calling thread until the job is resumed. No progress is lost,
+//! which allows maintenance windows without having to restart hours of work.
+//!
+//! Jobs registered within [`with_deadline`] instead stop at the first checkpoint reached after
+//! their deadline, by returning [`Error::DeadlineReached`] from [`checkpoint`].
+//!
+//! With the `control-socket` feature enabled (on unix), pause, resume and status requests can be
+//! issued over a Unix domain socket, see [`serve_control_socket`].
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
+
+use lazy_static::lazy_static;
+use log::info;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::events::{self, Event};
+use crate::scoped::with_local;
+
+pub type JobId = u64;
+
+/// A snapshot of the state of a registered job.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct JobStatus {
+    pub id: JobId,
+    pub name: String,
+    /// The label of the last checkpoint reached, if any.
+    pub checkpoint: Option<String>,
+    /// Whether a pause has been requested for this job.
+    pub pause_requested: bool,
+    /// Whether the job is currently blocked in a checkpoint.
+    pub paused: bool,
+    /// Wall-clock time after which the job stops at its next checkpoint.
+    pub deadline: Option<SystemTime>,
+}
+
+#[derive(Default)]
+struct Registry {
+    jobs: BTreeMap<JobId, JobStatus>,
+    /// Set by a global pause, so that jobs started while paused stop at their first checkpoint.
+    pause_all: bool,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+    static ref RESUMED: Condvar = Condvar::new();
+}
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT_JOB: Cell<Option<JobId>> = Cell::new(None);
+    static DEADLINE: RefCell<Option<SystemTime>> = RefCell::new(None);
+}
+
+fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().expect("control registry poisoned")
+}
+
+/// Registration of a job with the controller. The job is unregistered when the handle is dropped.
+///
+/// Checkpoints reached on the thread that registered the job are attributed to it.
+#[derive(Debug)]
+pub struct JobHandle {
+    id: JobId,
+    previous: Option<JobId>,
+    name: String,
+    started: Instant,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        registry().jobs.remove(&self.id);
+        CURRENT_JOB.with(|current| current.set(self.previous));
+        events::emit(Event::JobFinish {
+            job: self.id,
+            name: std::mem::take(&mut self.name),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+        });
+    }
+}
+
+/// Registers a new job for the current thread, e.g. `register_job("pc1 SectorId(1)")`.
+pub fn register_job<S: Into<String>>(name: S) -> JobHandle {
+    #[cfg(all(unix, feature = "control-socket"))]
+    start_configured_control_socket();
+
+    let id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+    let name = name.into();
+    {
+        let mut registry = registry();
+        let pause_requested = registry.pause_all;
+        registry.jobs.insert(
+            id,
+            JobStatus {
+                id,
+                name: name.clone(),
+                checkpoint: None,
+                pause_requested,
+                paused: false,
+                deadline: DEADLINE.with(|deadline| *deadline.borrow()),
+            },
+        );
+    }
+    let previous = CURRENT_JOB.with(|current| current.replace(Some(id)));
+    events::emit(Event::JobStart {
+        job: id,
+        name: name.clone(),
+    });
+
+    JobHandle {
+        id,
+        previous,
+        name,
+        started: Instant::now(),
+    }
+}
+
+/// Runs `f` with a deadline for all jobs it registers on the current thread. Their checkpoints
+/// return [`Error::DeadlineReached`] once `deadline` has passed.
+pub fn with_deadline<T, F: FnOnce() -> T>(deadline: SystemTime, f: F) -> T {
+    with_local(&DEADLINE, Some(deadline), f)
+}
+
+/// Whether the jobs registered on the current thread have a deadline, see [`with_deadline`].
+pub fn has_deadline() -> bool {
+    DEADLINE.with(|current| current.borrow().is_some())
+}
+
+/// Returns the id of the job registered on the current thread, if any.
+pub fn current_job() -> Option<JobId> {
+    CURRENT_JOB.with(|current| current.get())
+}
+
+/// Marks a safe stopping point of the current job. Blocks while the job is paused, and fails
+/// with [`Error::DeadlineReached`] if the deadline of the job has passed.
+///
+/// This is a no-op when no job is registered on the current thread.
+pub fn checkpoint(label: &str) -> Result<()> {
+    let id = match current_job() {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    events::emit(Event::Checkpoint {
+        job: id,
+        label: label.to_string(),
+    });
+
+    let mut registry = registry();
+    let paused = match registry.jobs.get_mut(&id) {
+        Some(job) => {
+            job.checkpoint = Some(label.to_string());
+            if let Some(deadline) = job.deadline {
+                if SystemTime::now() >= deadline {
+                    info!("job {} reached its deadline at checkpoint {}", id, label);
+                    return Err(Error::DeadlineReached(label.to_string()).into());
+                }
+            }
+            job.pause_requested
+        }
+        None => return Ok(()),
+    };
+    if !paused {
+        return Ok(());
+    }
+
+    info!("job {} paused at checkpoint {}", id, label);
+    events::emit(Event::Paused {
+        job: id,
+        checkpoint: label.to_string(),
+    });
+    let paused_at = Instant::now();
+    loop {
+        match registry.jobs.get_mut(&id) {
+            Some(job) if job.pause_requested => job.paused = true,
+            Some(job) => {
+                job.paused = false;
+                break;
+            }
+            None => break,
+        }
+        registry = RESUMED.wait(registry).expect("control registry poisoned");
+    }
+    drop(registry);
+    info!("job {} resumed at checkpoint {}", id, label);
+    events::emit(Event::Resumed {
+        job: id,
+        checkpoint: label.to_string(),
+        paused_ms: paused_at.elapsed().as_millis() as u64,
+    });
+
+    Ok(())
+}
+
+/// Requests a pause of the given job, or of all jobs (including future ones) if `id` is `None`.
+///
+/// Returns `false` if the requested job is unknown.
+pub fn pause(id: Option<JobId>) -> bool {
+    let mut registry = registry();
+    match id {
+        Some(id) => match registry.jobs.get_mut(&id) {
+            Some(job) => {
+                job.pause_requested = true;
+                true
+            }
+            None => false,
+        },
+        None => {
+            registry.pause_all = true;
+            registry
+                .jobs
+                .values_mut()
+                .for_each(|job| job.pause_requested = true);
+            true
+        }
+    }
+}
+
+/// Resumes the given job, or all jobs if `id` is `None`.
+///
+/// Returns `false` if the requested job is unknown.
+pub fn resume(id: Option<JobId>) -> bool {
+    let mut registry = registry();
+    let found = match id {
+        Some(id) => match registry.jobs.get_mut(&id) {
+            Some(job) => {
+                job.pause_requested = false;
+                true
+            }
+            None => false,
+        },
+        None => {
+            registry.pause_all = false;
+            registry
+                .jobs
+                .values_mut()
+                .for_each(|job| job.pause_requested = false);
+            true
+        }
+    };
+    RESUMED.notify_all();
+
+    found
+}
+
+/// Returns the status of all registered jobs, ordered by id.
+pub fn status() -> Vec<JobStatus> {
+    registry().jobs.values().cloned().collect()
+}
+
+#[cfg(all(unix, feature = "control-socket"))]
+pub use socket::serve_control_socket;
+
+#[cfg(all(unix, feature = "control-socket"))]
+fn start_configured_control_socket() {
+    use std::sync::Once;
+
+    use log::warn;
+
+    use crate::settings::SETTINGS;
+
+    static START: Once = Once::new();
+
+    START.call_once(|| {
+        let path = &SETTINGS.control_socket;
+        if path.is_empty() {
+            return;
+        }
+        if let Err(err) = serve_control_socket(path) {
+            warn!("failed to start control socket at {}: {:?}", path, err);
+        }
+    });
+}
+
+#[cfg(all(unix, feature = "control-socket"))]
+mod socket {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::thread;
+
+    use anyhow::{ensure, Context, Result};
+    use log::{info, warn};
+    use serde_json::json;
+
+    use super::{pause, resume, status, JobId};
+
+    /// Starts serving control requests on a Unix domain socket at `path`, in a background thread.
+    ///
+    /// The protocol is line based. Each request line is one of `pause [<job id>]`,
+    /// `resume [<job id>]` or `status`, and is answered with a single line of JSON.
+    /// Without a job id, `pause` and `resume` apply to all jobs.
+    pub fn serve_control_socket<P: AsRef<Path>>(path: P) -> Result<thread::JoinHandle<()>> {
+        let path = path.as_ref();
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            // Only remove a stale socket left behind by a previous process of this user.
+            ensure!(
+                metadata.file_type().is_socket(),
+                "{:?} exists and is not a socket",
+                path
+            );
+            ensure!(
+                metadata.uid() == unsafe { libc::geteuid() },
+                "control socket {:?} is owned by another user",
+                path
+            );
+            ensure!(
+                UnixStream::connect(path).is_err(),
+                "control socket {:?} is served by another process",
+                path
+            );
+            fs::remove_file(path)
+                .with_context(|| format!("could not remove stale socket {:?}", path))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("could not bind control socket {:?}", path))?;
+        info!("serving control socket at {:?}", path);
+
+        let handle = thread::Builder::new()
+            .name("fil-proofs-control".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(err) = handle_connection(stream) {
+                                warn!("control socket connection failed: {:?}", err);
+                            }
+                        }
+                        Err(err) => warn!("control socket accept failed: {:?}", err),
+                    }
+                }
+            })?;
+
+        Ok(handle)
+    }
+
+    fn handle_connection(stream: UnixStream) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let response = handle_request(&line?);
+            writeln!(writer, "{}", response)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_request(line: &str) -> serde_json::Value {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let id = match parts.next().map(str::parse::<JobId>).transpose() {
+            Ok(id) => id,
+            Err(err) => return json!({ "ok": false, "error": format!("invalid job id: {}", err) }),
+        };
+
+        match command {
+            "pause" => json!({ "ok": pause(id) }),
+            "resume" => json!({ "ok": resume(id) }),
+            "status" => json!({ "ok": true, "jobs": status() }),
+            _ => json!({ "ok": false, "error": format!("unknown command: {:?}", command) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pause_resume_job() {
+        let (tx, rx) = channel();
+        let worker = thread::spawn(move || {
+            let job = register_job("test job");
+            tx.send(job.id()).expect("send failed");
+            // Give the test thread time to request the pause.
+            thread::sleep(Duration::from_millis(100));
+            checkpoint("first").expect("checkpoint failed");
+            checkpoint("second").expect("checkpoint failed");
+        });
+
+        let id = rx.recv().expect("recv failed");
+        assert!(pause(Some(id)));
+
+        // Wait until the job blocks in its first checkpoint.
+        let job = loop {
+            if let Some(job) = status().into_iter().find(|job| job.id == id && job.paused) {
+                break job;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(job.checkpoint.as_deref(), Some("first"));
+
+        assert!(resume(Some(id)));
+        worker.join().expect("worker failed");
+
+        assert!(status().iter().all(|job| job.id != id));
+        assert!(!pause(Some(id)));
+    }
+
+    #[test]
+    fn test_checkpoint_without_job() {
+        assert_eq!(current_job(), None);
+        checkpoint("noop").expect("checkpoint failed");
+    }
+
+    #[test]
+    fn test_checkpoint_after_deadline() {
+        let past = SystemTime::now() - Duration::from_secs(1);
+        let err = with_deadline(past, || {
+            let _job = register_job("deadline job");
+            checkpoint("layer 1").expect_err("deadline not reached")
+        });
+        match err.downcast_ref::<Error>() {
+            Some(Error::DeadlineReached(label)) => assert_eq!(label, "layer 1"),
+            _ => panic!("unexpected error {:?}", err),
+        }
+
+        // The deadline is reset when `f` panics, too.
+        let panicked = std::panic::catch_unwind(|| with_deadline(past, || panic!("job failed")));
+        assert!(panicked.is_err());
+        assert_eq!(DEADLINE.with(|deadline| *deadline.borrow()), None);
+
+        // Jobs registered outside of the deadline scope are not affected.
+        let _job = register_job("unbounded job");
+        checkpoint("layer 1").expect("checkpoint failed");
+    }
+}
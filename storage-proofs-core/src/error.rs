@@ -41,6 +41,10 @@ pub enum Error {
     FaultySectors(Vec<SectorId>),
     #[error("Invalid parameters file: {}", _0)]
     InvalidParameters(String),
+    #[error("deadline reached at checkpoint {}", _0)]
+    DeadlineReached(String),
+    #[error("scratch quota of tenant {} exceeded, {} bytes requested", _0, _1)]
+    ScratchQuotaExceeded(String, u64),
 }
 
 impl From<Box<dyn Any + Send>> for Error {
@@ -0,0 +1,183 @@
+//! Opt-in structured operational events.
+//!
+//! When `FIL_PROOFS_EVENTS_JSON` is set to a file path (or to `stderr`), operational events are
+//! appended to it as JSON lines, independently of the regular log output. Every line is an object
+//! with the following fields, followed by the fields of the event itself:
+//!
+//! ```text
+//! v       schema version, currently EVENT_SCHEMA_VERSION (1)
+//! ts_ms   unix timestamp in milliseconds
+//! pid     process id
+//! event   one of the event names below
+//! ```
+//!
+//! | event           | fields                                           |
+//! |-----------------|--------------------------------------------------|
+//! | `job_start`     | `job`, `name`                                    |
+//! | `job_finish`    | `job`, `name`, `duration_ms`                     |
+//! | `checkpoint`    | `job`, `label`                                   |
+//! | `paused`        | `job`, `checkpoint`                              |
+//! | `resumed`       | `job`, `checkpoint`, `paused_ms`                 |
+//! | `device_choice` | `job` (or null), `operation`, `device`           |
+//! | `fallback`      | `job` (or null), `operation`, `reason`           |
+//!
+//! Jobs are the phases registered with [`control::register_job`](crate::control::register_job).
+//! Fields are only ever added within a schema version; removing or changing a field bumps it.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::warn;
+use serde::Serialize;
+
+use crate::control::JobId;
+use crate::settings::SETTINGS;
+
+/// Version of the event schema, see the module documentation.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    JobStart {
+        job: JobId,
+        name: String,
+    },
+    JobFinish {
+        job: JobId,
+        name: String,
+        duration_ms: u64,
+    },
+    Checkpoint {
+        job: JobId,
+        label: String,
+    },
+    Paused {
+        job: JobId,
+        checkpoint: String,
+    },
+    Resumed {
+        job: JobId,
+        checkpoint: String,
+        paused_ms: u64,
+    },
+    /// The device (`cpu` or `gpu`) chosen for an operation, e.g. `tree_c`.
+    DeviceChoice {
+        job: Option<JobId>,
+        operation: String,
+        device: String,
+    },
+    /// An operation was retried along a different path, e.g. a local instead of a shared cache.
+    Fallback {
+        job: Option<JobId>,
+        operation: String,
+        reason: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    v: u32,
+    ts_ms: u64,
+    pid: u32,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+lazy_static! {
+    static ref SINK: Option<Mutex<Box<dyn Write + Send>>> = open_sink(&SETTINGS.events_json);
+}
+
+fn open_sink(target: &str) -> Option<Mutex<Box<dyn Write + Send>>> {
+    let sink: Box<dyn Write + Send> = match target {
+        "" => return None,
+        "stderr" => Box::new(io::stderr()),
+        path => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Box::new(file),
+            Err(err) => {
+                warn!("could not open events file {}: {:?}", path, err);
+                return None;
+            }
+        },
+    };
+
+    Some(Mutex::new(sink))
+}
+
+fn to_line(event: &Event, ts_ms: u64, pid: u32) -> serde_json::Result<String> {
+    serde_json::to_string(&Line {
+        v: EVENT_SCHEMA_VERSION,
+        ts_ms,
+        pid,
+        event,
+    })
+}
+
+/// Returns true if events are being recorded.
+pub fn enabled() -> bool {
+    SINK.is_some()
+}
+
+/// Records an event, if enabled. Failures to write are logged and otherwise ignored.
+pub fn emit(event: Event) {
+    let sink = match &*SINK {
+        Some(sink) => sink,
+        None => return,
+    };
+
+    let ts_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|ts| ts.as_millis() as u64)
+        .unwrap_or(0);
+    let result = to_line(&event, ts_ms, process::id())
+        .map_err(io::Error::from)
+        .and_then(|line| {
+            let mut sink = sink.lock().expect("events sink poisoned");
+            writeln!(sink, "{}", line)?;
+            sink.flush()
+        });
+    if let Err(err) = result {
+        warn!("failed to record event {:?}: {:?}", event, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_format() {
+        let line = to_line(
+            &Event::JobFinish {
+                job: 3,
+                name: "seal_pre_commit_phase1 SectorId(1)".to_string(),
+                duration_ms: 1500,
+            },
+            1_600_000_000_000,
+            42,
+        )
+        .expect("to_line failed");
+
+        assert_eq!(
+            line,
+            "{\"v\":1,\"ts_ms\":1600000000000,\"pid\":42,\"event\":\"job_finish\",\"job\":3,\
+             \"name\":\"seal_pre_commit_phase1 SectorId(1)\",\"duration_ms\":1500}"
+        );
+
+        let line = to_line(
+            &Event::DeviceChoice {
+                job: None,
+                operation: "tree_c".to_string(),
+                device: "gpu".to_string(),
+            },
+            0,
+            1,
+        )
+        .expect("to_line failed");
+        assert!(line.contains("\"event\":\"device_choice\",\"job\":null,\"operation\":\"tree_c\""));
+    }
+}
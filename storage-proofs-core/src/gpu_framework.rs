@@ -0,0 +1,274 @@
+//! The GPU framework, CUDA or OpenCL, the GPU work of the proofs runs on.
+//!
+//! bellperson and neptune pick the framework of their kernels from the `BELLMAN_GPU_FRAMEWORK`
+//! and `NEPTUNE_GPU_FRAMEWORK` environment variables whenever they create them, preferring CUDA.
+//! When both are compiled in, the `gpu_framework` setting selects one for the process (or tenant),
+//! and [`with_gpu_framework`] for the GPU work run on the calling thread. If the selected framework
+//! has no usable device, e.g. CUDA without the NVIDIA driver, the other one is used instead, and a
+//! `fallback` event for the `gpu_framework` operation is emitted.
+//!
+//! The environment is shared by the whole process, and changing it races with the threads reading
+//! it, e.g. those of the GPU drivers. The variables are therefore set once, by
+//! [`init_gpu_framework`] on startup or else by the first GPU work, and all GPU work of the process
+//! runs on that framework. GPU work selecting another one runs on it as well, and emits a
+//! `fallback` event.
+
+use std::cell::RefCell;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{bail, Error, Result};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+
+use crate::{
+    control,
+    events::{self, Event},
+    scoped::with_local,
+    settings::SETTINGS,
+    tenant,
+};
+
+/// The environment variables the dependencies read their framework from.
+const FRAMEWORK_ENV_VARS: [&str; 2] = ["BELLMAN_GPU_FRAMEWORK", "NEPTUNE_GPU_FRAMEWORK"];
+
+lazy_static! {
+    /// The compiled in frameworks which found a device, in order of preference.
+    static ref USABLE_FRAMEWORKS: Vec<GpuFramework> = usable_frameworks();
+    /// The framework set in the environment, once it is initialized.
+    static ref PROCESS_FRAMEWORK: Mutex<Option<Option<GpuFramework>>> = Mutex::new(None);
+}
+
+thread_local! {
+    static GPU_FRAMEWORK: RefCell<Option<GpuFramework>> = RefCell::new(None);
+}
+
+/// A GPU framework.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GpuFramework {
+    Cuda,
+    Opencl,
+}
+
+impl GpuFramework {
+    /// The name of the framework, as it is set in the settings.
+    pub fn name(self) -> &'static str {
+        match self {
+            GpuFramework::Cuda => "cuda",
+            GpuFramework::Opencl => "opencl",
+        }
+    }
+}
+
+impl fmt::Display for GpuFramework {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for GpuFramework {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "cuda" => Ok(GpuFramework::Cuda),
+            "opencl" => Ok(GpuFramework::Opencl),
+            _ => bail!("unknown GPU framework {:?}, expected cuda or opencl", s),
+        }
+    }
+}
+
+/// Runs `f`, running the GPU work of the proofs on this thread on `framework` if it has a usable
+/// device, and the GPU work of the process runs on it, see [`init_gpu_framework`].
+pub fn with_gpu_framework<T, F: FnOnce() -> T>(framework: GpuFramework, f: F) -> T {
+    with_local(&GPU_FRAMEWORK, Some(framework), f)
+}
+
+/// The framework selected for the GPU work on this thread, with [`with_gpu_framework`] or the
+/// `gpu_framework` setting, if any.
+pub fn preferred_gpu_framework() -> Option<GpuFramework> {
+    if let Some(framework) = GPU_FRAMEWORK.with(|current| *current.borrow()) {
+        return Some(framework);
+    }
+
+    let setting = &tenant::settings().gpu_framework;
+    if setting.is_empty() {
+        return None;
+    }
+    match setting.parse() {
+        Ok(framework) => Some(framework),
+        Err(err) => {
+            warn!("ignoring the gpu_framework setting: {}", err);
+            None
+        }
+    }
+}
+
+/// The framework the GPU work on this thread runs on: the preferred one if it has a usable device,
+/// the other one if only that does. `None` if no framework is preferred, which leaves the choice
+/// to the dependencies, or no framework has a usable device.
+pub fn gpu_framework() -> Option<GpuFramework> {
+    usable_gpu_framework(preferred_gpu_framework()?)
+}
+
+/// `preferred` if it has a usable device, the other framework if only that does.
+fn usable_gpu_framework(preferred: GpuFramework) -> Option<GpuFramework> {
+    if USABLE_FRAMEWORKS.contains(&preferred) {
+        return Some(preferred);
+    }
+
+    let fallback = *USABLE_FRAMEWORKS.first()?;
+    warn!(
+        "GPU framework {} has no usable device, falling back to {}",
+        preferred, fallback
+    );
+    events::emit(Event::Fallback {
+        job: control::current_job(),
+        operation: "gpu_framework".to_string(),
+        reason: format!("{} has no usable device, using {}", preferred, fallback),
+    });
+
+    Some(fallback)
+}
+
+/// The compiled in frameworks which found a device, CUDA first.
+pub fn usable_gpu_frameworks() -> Vec<GpuFramework> {
+    USABLE_FRAMEWORKS.clone()
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+fn usable_frameworks() -> Vec<GpuFramework> {
+    let devices = rust_gpu_tools::Device::all();
+    let mut usable = Vec::new();
+    #[cfg(feature = "cuda")]
+    if devices.iter().any(|device| device.cuda_device().is_some()) {
+        usable.push(GpuFramework::Cuda);
+    }
+    #[cfg(feature = "opencl")]
+    if devices
+        .iter()
+        .any(|device| device.opencl_device().is_some())
+    {
+        usable.push(GpuFramework::Opencl);
+    }
+    debug!("usable GPU frameworks: {:?}", usable);
+
+    usable
+}
+
+#[cfg(not(any(feature = "cuda", feature = "opencl")))]
+fn usable_frameworks() -> Vec<GpuFramework> {
+    debug!("no GPU framework compiled in");
+    Vec::new()
+}
+
+/// Sets the framework variables of the environment once for the process, to `framework` or with
+/// `None` to the `gpu_framework` setting of the process, falling back to the other framework if it
+/// has no usable device like [`gpu_framework`]. Returns the framework set, `None` if the choice is
+/// left to the dependencies. Later calls return it and leave the environment as it is.
+///
+/// Call it on startup before any threads are started, as changing the environment races with
+/// threads reading it. Otherwise the first GPU work of the process calls it.
+pub fn init_gpu_framework(framework: Option<GpuFramework>) -> Option<GpuFramework> {
+    let mut process_framework = PROCESS_FRAMEWORK.lock().expect("poisoned lock");
+    if let Some(process_framework) = *process_framework {
+        return process_framework;
+    }
+
+    let preferred = framework.or_else(|| match SETTINGS.gpu_framework.as_str() {
+        "" => None,
+        setting => setting.parse().ok(),
+    });
+    let framework = preferred.and_then(usable_gpu_framework);
+    if let Some(framework) = framework {
+        info!("running the GPU work of the process on {}", framework);
+        for var in &FRAMEWORK_ENV_VARS {
+            env::set_var(var, framework.name());
+        }
+    }
+    *process_framework = Some(framework);
+
+    framework
+}
+
+/// Enters GPU work on `framework`, returning the framework it runs on: the framework of the
+/// process, see [`init_gpu_framework`], which is initialized to `framework` if it isn't yet.
+pub fn enter_gpu_framework(framework: Option<GpuFramework>) -> Option<GpuFramework> {
+    let process_framework = init_gpu_framework(framework);
+    if let (Some(framework), Some(process_framework)) = (framework, process_framework) {
+        if framework != process_framework {
+            warn!(
+                "GPU work on {} runs on {}, the GPU framework of the process",
+                framework, process_framework
+            );
+            events::emit(Event::Fallback {
+                job: control::current_job(),
+                operation: "gpu_framework".to_string(),
+                reason: format!(
+                    "the process runs its GPU work on {}, not {}",
+                    process_framework, framework
+                ),
+            });
+        }
+    }
+
+    process_framework
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic;
+
+    #[test]
+    fn test_with_gpu_framework() {
+        assert_eq!(
+            "CUDA".parse::<GpuFramework>().ok(),
+            Some(GpuFramework::Cuda)
+        );
+        assert_eq!(
+            "opencl".parse::<GpuFramework>().ok(),
+            Some(GpuFramework::Opencl)
+        );
+        assert!("vulkan".parse::<GpuFramework>().is_err());
+
+        let frameworks = with_gpu_framework(GpuFramework::Opencl, || {
+            let outer = preferred_gpu_framework();
+            let inner = with_gpu_framework(GpuFramework::Cuda, preferred_gpu_framework);
+            (outer, inner, preferred_gpu_framework())
+        });
+        assert_eq!(
+            frameworks,
+            (
+                Some(GpuFramework::Opencl),
+                Some(GpuFramework::Cuda),
+                Some(GpuFramework::Opencl)
+            )
+        );
+
+        // The framework is restored when `f` panics.
+        let panicked = panic::catch_unwind(|| {
+            with_gpu_framework(GpuFramework::Cuda, || panic!("proof failed"))
+        });
+        assert!(panicked.is_err());
+        assert_eq!(GPU_FRAMEWORK.with(|current| *current.borrow()), None);
+    }
+
+    #[test]
+    fn test_init_gpu_framework() {
+        let framework = init_gpu_framework(Some(GpuFramework::Opencl));
+        if let Some(framework) = framework {
+            assert_eq!(
+                env::var(FRAMEWORK_ENV_VARS[0]).ok().as_deref(),
+                Some(framework.name())
+            );
+        }
+
+        // The environment is only set once.
+        assert_eq!(init_gpu_framework(Some(GpuFramework::Cuda)), framework);
+        assert_eq!(enter_gpu_framework(Some(GpuFramework::Cuda)), framework);
+    }
+}
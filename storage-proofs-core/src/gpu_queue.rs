@@ -0,0 +1,178 @@
+//! The order in which the SNARK proofs of the process are run on the GPU.
+//!
+//! A Winning PoSt has to be proven within seconds of its randomness, so it must not wait for a
+//! Window PoSt or a C2 holding the GPU for minutes. When `gpu_preemption_circuits` is set, the
+//! SNARK proofs of the process take turns on the GPU. A waiting Winning PoSt goes first, followed by
+//! the Window PoSt and the other classes in the order of [`PriorityClass`]. Proofs of the other
+//! classes prove their circuits in chunks of `gpu_preemption_circuits`, and hand their turn over
+//! between two chunks when a proof of a more urgent class is waiting, so that a Winning PoSt waits
+//! for one chunk at most. Winning PoSts are then also proven with bellperson's priority lock, which
+//! makes the GPU kernels of other processes yield to them.
+//!
+//! There is a single turn for all the GPUs of the host, since bellperson spreads the multiexps of
+//! every proof over all of them. On a host with several GPUs, proofs which would otherwise run side
+//! by side then wait for each other; a scheduler assigning the devices can be set with
+//! `with_gpu_scheduler` instead.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use lazy_static::lazy_static;
+use log::debug;
+
+use crate::priority::PriorityClass;
+
+lazy_static! {
+    static ref GPU_QUEUE: Mutex<GpuQueue> = Mutex::new(GpuQueue::default());
+    static ref GPU_TURN_RELEASED: Condvar = Condvar::new();
+}
+
+/// The tickets of the proofs waiting for their turn with their urgency, in arrival order.
+#[derive(Default)]
+struct GpuQueue {
+    next_ticket: u64,
+    waiting: Vec<(u64, u8)>,
+    taken: bool,
+}
+
+impl GpuQueue {
+    /// The ticket of the most urgent proof which has waited the longest.
+    fn next_in_line(&self) -> Option<u64> {
+        self.waiting
+            .iter()
+            .min_by(|(ticket_a, urgency_a), (ticket_b, urgency_b)| {
+                urgency_a
+                    .cmp(urgency_b)
+                    .then_with(|| ticket_a.cmp(ticket_b))
+            })
+            .map(|(ticket, _)| *ticket)
+    }
+}
+
+/// How urgent the SNARK proofs of `class` are on the GPU, lower values first. Unlike the OS
+/// priorities, which favour the Window PoSt, the Winning PoSt goes first as its deadline is the
+/// tightest. Proofs outside of a class go last.
+fn urgency(class: Option<PriorityClass>) -> u8 {
+    match class {
+        Some(PriorityClass::WinningPost) => 0,
+        Some(PriorityClass::WindowPost) => 1,
+        Some(PriorityClass::SealCommitPhase2) => 2,
+        Some(PriorityClass::SealPreCommitPhase2) => 3,
+        Some(PriorityClass::SealPreCommitPhase1) => 4,
+        Some(PriorityClass::Unseal) => 5,
+        None => 6,
+    }
+}
+
+fn wait_in_line(
+    mut queue: MutexGuard<'static, GpuQueue>,
+    urgency: u8,
+) -> MutexGuard<'static, GpuQueue> {
+    let ticket = queue.next_ticket;
+    queue.next_ticket += 1;
+    queue.waiting.push((ticket, urgency));
+    while queue.taken || queue.next_in_line() != Some(ticket) {
+        queue = GPU_TURN_RELEASED.wait(queue).expect("poisoned lock");
+    }
+    queue.waiting.retain(|(waiting, _)| *waiting != ticket);
+    queue.taken = true;
+
+    queue
+}
+
+/// The turn of a SNARK proof on the GPU, which is passed on when it is dropped.
+#[derive(Debug)]
+pub struct GpuTurn {
+    urgency: u8,
+}
+
+impl GpuTurn {
+    /// Waits for the turn of a proof of `class`, `None` for proofs outside of a class.
+    pub fn wait(class: Option<PriorityClass>) -> Self {
+        let urgency = urgency(class);
+        let _queue = wait_in_line(GPU_QUEUE.lock().expect("poisoned lock"), urgency);
+
+        GpuTurn { urgency }
+    }
+
+    /// Hands the turn over if a proof of a more urgent class is waiting, and waits for the next
+    /// turn. Returns whether the turn was handed over.
+    pub fn yield_to_urgent(&mut self) -> bool {
+        let mut queue = GPU_QUEUE.lock().expect("poisoned lock");
+        if !queue
+            .waiting
+            .iter()
+            .any(|(_, urgency)| *urgency < self.urgency)
+        {
+            return false;
+        }
+
+        debug!("handing the GPU over to more urgent proofs");
+        queue.taken = false;
+        GPU_TURN_RELEASED.notify_all();
+        let _queue = wait_in_line(queue, self.urgency);
+
+        true
+    }
+}
+
+impl Drop for GpuTurn {
+    fn drop(&mut self) {
+        let mut queue = GPU_QUEUE.lock().expect("poisoned lock");
+        queue.taken = false;
+        GPU_TURN_RELEASED.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_winning_post_goes_first() {
+        let mut turn = GpuTurn::wait(Some(PriorityClass::SealCommitPhase2));
+        assert!(!turn.yield_to_urgent());
+
+        let (sender, receiver) = mpsc::channel();
+        let spawn_waiting = |class: PriorityClass| {
+            let sender = sender.clone();
+            let handle = thread::spawn(move || {
+                let _turn = GpuTurn::wait(Some(class));
+                sender.send(class).expect("failed to send");
+            });
+            // Waits until the thread is in line.
+            while GPU_QUEUE
+                .lock()
+                .expect("poisoned lock")
+                .waiting
+                .iter()
+                .all(|(_, waiting)| *waiting != urgency(Some(class)))
+            {
+                thread::sleep(Duration::from_millis(1));
+            }
+            handle
+        };
+        let handles = vec![
+            spawn_waiting(PriorityClass::Unseal),
+            spawn_waiting(PriorityClass::WindowPost),
+            spawn_waiting(PriorityClass::WinningPost),
+        ];
+
+        // The more urgent proofs are served before the turn returns.
+        assert!(turn.yield_to_urgent());
+        let served: Vec<PriorityClass> = receiver.try_iter().collect();
+        assert_eq!(
+            served,
+            vec![PriorityClass::WinningPost, PriorityClass::WindowPost]
+        );
+
+        drop(turn);
+        for handle in handles {
+            handle.join().expect("failed to join");
+        }
+        assert_eq!(receiver.try_iter().next(), Some(PriorityClass::Unseal));
+    }
+}
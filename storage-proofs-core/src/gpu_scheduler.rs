@@ -0,0 +1,257 @@
+//! Arbitration of the GPU between the SNARK proofs and the tree builders.
+//!
+//! Before it proves its circuits, a SNARK proof acquires the GPU from the scheduler of the calling
+//! thread, and releases it by dropping the returned [`GpuGrant`]. The grant decides whether the
+//! circuits are proven with bellperson's priority lock, and whether they are proven in chunks
+//! between which the GPU may be handed over to more urgent proofs. The GPU tree builders of PC2
+//! which aren't assigned a device acquire it the same way while they build their trees.
+//!
+//! The [`DefaultGpuScheduler`] grants the GPU to the proofs right away and leaves the arbitration
+//! to the lock files of bellperson, Winning PoSts taking its priority lock, unless
+//! `gpu_preemption_circuits` is set, in which case the proofs of the process take turns, see
+//! `gpu_queue`. It grants the GPU to one tree builder of the process at a time. Within
+//! [`with_gpu_scheduler`], the proofs and tree builders on the calling thread acquire the GPU from
+//! the given scheduler instead, e.g. a cluster scheduler arbitrating the GPUs of a host between
+//! processes. bellperson still takes its lock files while its kernels run, which stay uncontended
+//! if the scheduler grants the GPU to one proof at a time.
+
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    control::JobId, error::Result, gpu_queue::GpuTurn, priority::PriorityClass, scoped::with_local,
+    settings::SETTINGS,
+};
+
+lazy_static! {
+    /// Held by the tree builder the default scheduler granted the GPU to.
+    static ref TREE_BUILDER_LOCK: Mutex<()> = Mutex::new(());
+}
+
+thread_local! {
+    static SCHEDULER: RefCell<Option<Arc<dyn GpuScheduler>>> = RefCell::new(None);
+}
+
+/// The work asking for the GPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuWork {
+    /// A SNARK proof.
+    Proof,
+    /// Building trees of PC2 (or tuning the batch size of their builder), which has no circuits.
+    TreeBuilding,
+}
+
+/// A SNARK proof or a tree builder asking for the GPU.
+#[derive(Clone, Debug)]
+pub struct GpuRequest {
+    /// The work to run on the GPU.
+    pub work: GpuWork,
+    /// The priority class the proof runs in, see `priority`.
+    pub class: Option<PriorityClass>,
+    /// Whether the proof asks for priority, as Winning PoSts do.
+    pub priority: bool,
+    /// The number of circuits to prove.
+    pub circuits: usize,
+    /// The job the proof runs in, see `control`.
+    pub job: Option<JobId>,
+}
+
+/// Grants the GPU to SNARK proofs and tree builders.
+pub trait GpuScheduler: Send + Sync {
+    /// Waits until the work of `request` may use the GPU, and returns its grant, which releases
+    /// the GPU when it is dropped.
+    fn acquire(&self, request: &GpuRequest) -> Result<Box<dyn GpuGrant>>;
+}
+
+/// The GPU granted to a SNARK proof or a tree builder, until it is dropped.
+pub trait GpuGrant {
+    /// Whether the circuits are proven with bellperson's priority lock, which makes the GPU
+    /// kernels of other processes yield to them.
+    fn priority(&self) -> bool;
+
+    /// The number of circuits proven at once, between which the GPU may be handed over, `None` to
+    /// prove all of them at once.
+    fn chunk_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Called between two chunks of circuits. Hands the GPU over if more urgent proofs are
+    /// waiting, and waits for it again. Returns whether the GPU was handed over.
+    fn yield_to_urgent(&mut self) -> bool {
+        false
+    }
+}
+
+/// The scheduler of the GPU unless another one is set with [`with_gpu_scheduler`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultGpuScheduler;
+
+impl GpuScheduler for DefaultGpuScheduler {
+    fn acquire(&self, request: &GpuRequest) -> Result<Box<dyn GpuGrant>> {
+        if request.work == GpuWork::TreeBuilding {
+            return Ok(Box::new(DefaultGpuGrant {
+                turn: None,
+                priority: false,
+                chunk_len: None,
+                _tree_builder_lock: Some(TREE_BUILDER_LOCK.lock().expect("poisoned lock")),
+            }));
+        }
+
+        let chunk_len = SETTINGS.gpu_preemption_circuits;
+        if chunk_len == 0 {
+            return Ok(Box::new(DefaultGpuGrant {
+                turn: None,
+                priority: request.priority,
+                chunk_len: None,
+                _tree_builder_lock: None,
+            }));
+        }
+
+        let turn = GpuTurn::wait(request.class);
+        // Winning PoSts are never handed over, and make other processes yield.
+        let grant = if request.class == Some(PriorityClass::WinningPost) {
+            DefaultGpuGrant {
+                turn: Some(turn),
+                priority: true,
+                chunk_len: None,
+                _tree_builder_lock: None,
+            }
+        } else {
+            DefaultGpuGrant {
+                turn: Some(turn),
+                priority: request.priority,
+                chunk_len: Some(chunk_len),
+                _tree_builder_lock: None,
+            }
+        };
+
+        Ok(Box::new(grant))
+    }
+}
+
+struct DefaultGpuGrant {
+    turn: Option<GpuTurn>,
+    priority: bool,
+    chunk_len: Option<usize>,
+    _tree_builder_lock: Option<MutexGuard<'static, ()>>,
+}
+
+impl GpuGrant for DefaultGpuGrant {
+    fn priority(&self) -> bool {
+        self.priority
+    }
+
+    fn chunk_len(&self) -> Option<usize> {
+        self.chunk_len
+    }
+
+    fn yield_to_urgent(&mut self) -> bool {
+        match self.turn.as_mut() {
+            Some(turn) => turn.yield_to_urgent(),
+            None => false,
+        }
+    }
+}
+
+/// Runs `f`, acquiring the GPU for the SNARK proofs and tree builders on this thread from
+/// `scheduler`.
+pub fn with_gpu_scheduler<T, F: FnOnce() -> T>(scheduler: Arc<dyn GpuScheduler>, f: F) -> T {
+    with_local(&SCHEDULER, Some(scheduler), f)
+}
+
+/// The scheduler of the current thread.
+pub fn gpu_scheduler() -> Arc<dyn GpuScheduler> {
+    SCHEDULER
+        .with(|cell| cell.borrow().clone())
+        .unwrap_or_else(|| Arc::new(DefaultGpuScheduler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingScheduler {
+        granted: Arc<AtomicUsize>,
+    }
+
+    struct CountingGrant(Arc<AtomicUsize>);
+
+    impl GpuScheduler for CountingScheduler {
+        fn acquire(&self, _request: &GpuRequest) -> Result<Box<dyn GpuGrant>> {
+            self.granted.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(CountingGrant(self.granted.clone())))
+        }
+    }
+
+    impl GpuGrant for CountingGrant {
+        fn priority(&self) -> bool {
+            false
+        }
+    }
+
+    impl Drop for CountingGrant {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_with_gpu_scheduler() {
+        let request = GpuRequest {
+            work: GpuWork::Proof,
+            class: Some(PriorityClass::WinningPost),
+            priority: true,
+            circuits: 1,
+            job: None,
+        };
+        if SETTINGS.gpu_preemption_circuits == 0 {
+            let grant = gpu_scheduler().acquire(&request).expect("acquire failed");
+            assert!(grant.priority());
+            assert_eq!(grant.chunk_len(), None);
+        }
+
+        let scheduler = CountingScheduler::default();
+        let granted = scheduler.granted.clone();
+        let grant = with_gpu_scheduler(Arc::new(scheduler), || {
+            gpu_scheduler().acquire(&request).expect("acquire failed")
+        });
+        assert_eq!(granted.load(Ordering::SeqCst), 1);
+        assert!(!grant.priority());
+        drop(grant);
+        assert_eq!(granted.load(Ordering::SeqCst), 0);
+
+        // The scheduler is restored when `f` panics.
+        let panicked = panic::catch_unwind(|| {
+            with_gpu_scheduler(Arc::new(CountingScheduler::default()), || {
+                panic!("proof failed")
+            })
+        });
+        assert!(panicked.is_err());
+        assert!(SCHEDULER.with(|cell| cell.borrow().is_none()));
+    }
+
+    #[test]
+    fn test_default_gpu_scheduler_tree_building() {
+        let request = GpuRequest {
+            work: GpuWork::TreeBuilding,
+            class: None,
+            priority: false,
+            circuits: 0,
+            job: None,
+        };
+        let grant = DefaultGpuScheduler
+            .acquire(&request)
+            .expect("acquire failed");
+        assert!(!grant.priority());
+        // One tree builder holds the GPU at a time.
+        assert!(TREE_BUILDER_LOCK.try_lock().is_err());
+        drop(grant);
+        assert!(TREE_BUILDER_LOCK.try_lock().is_ok());
+    }
+}
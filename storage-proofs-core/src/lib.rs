@@ -14,22 +14,34 @@ use std::convert::TryInto;
 pub mod api_version;
 pub mod cache_key;
 pub mod compound_proof;
+pub mod control;
 pub mod crypto;
 pub mod data;
 pub mod drgraph;
 pub mod error;
+pub mod events;
 pub mod gadgets;
+pub mod gpu_framework;
+pub mod gpu_queue;
+pub mod gpu_scheduler;
 pub mod measurements;
 pub mod merkle;
 pub mod multi_proof;
 pub mod parameter_cache;
+pub mod parameter_shm;
 pub mod partitions;
+#[cfg(feature = "pasta")]
+pub mod pasta;
 pub mod pieces;
 pub mod por;
+pub mod priority;
 pub mod proof;
+pub mod scoped;
 pub mod sector;
 pub mod settings;
+pub mod tenant;
 pub mod test_helper;
+pub mod thread_pool;
 pub mod util;
 
 pub use data::Data;
@@ -1,31 +1,37 @@
-use std::collections::{BTreeMap, HashSet};
-use std::fs::{create_dir_all, File, OpenOptions};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use anyhow::bail;
+use anyhow::{bail, ensure, Context};
 use bellperson::{groth16, Circuit};
 use blake2b_simd::Params as Blake2bParams;
 use blstrs::{Bls12, Scalar as Fr};
 use fs2::FileExt;
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use log::{info, trace};
+use log::{info, trace, warn};
 use memmap::MmapOptions;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
+    control,
     error::{Error, Result},
-    settings::SETTINGS,
+    events::{self, Event},
+    parameter_shm, tenant,
 };
 
 /// Bump this when circuits change to invalidate the cache.
 pub const VERSION: usize = 28;
 pub const SRS_MAX_PROOFS_TO_AGGREGATE: usize = 65536; // FIXME: placeholder value
+/// The most elements of the SRS which are read. We do not currently support lengths higher than
+/// this, even though the SRS file can handle up to (2 << 19) + 1 elements.
+pub const SRS_MAX_LEN: usize = (2 << 14) + 1;
 
 pub const GROTH_PARAMETER_EXT: &str = "params";
 pub const PARAMETER_METADATA_EXT: &str = "meta";
@@ -38,6 +44,8 @@ pub struct LockedFile(File);
 
 pub type ParameterMap = BTreeMap<String, ParameterData>;
 
+type SrsMemCache = HashMap<PathBuf, Arc<groth16::aggregate::GenericSRS<Bls12>>>;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ParameterData {
     pub cid: String,
@@ -53,6 +61,9 @@ lazy_static! {
         serde_json::from_str(PARAMETERS_DATA).expect("Invalid parameters.json");
     pub static ref SRS_PARAMETERS: ParameterMap =
         serde_json::from_str(SRS_PARAMETERS_DATA).expect("Invalid srs-inner-product.json");
+    /// The SRS read by this process, by path, each truncated to the most elements any
+    /// aggregation needed so far.
+    static ref SRS_MEMORY_CACHE: Mutex<SrsMemCache> = Mutex::new(HashMap::new());
     /// Contains the parameters that were previously verified. This way the parameter files are
     /// only hashed once and not on every usage.
     static ref VERIFIED_PARAMETERS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
@@ -156,32 +167,62 @@ impl Drop for LockedFile {
 }
 
 pub fn parameter_cache_dir_name() -> String {
-    SETTINGS.parameter_cache.clone()
+    tenant::settings().parameter_cache.clone()
+}
+
+/// The directories of the parameter cache, in search order. The `parameter_cache` setting lists
+/// them separated like `PATH` is, by `:` (`;` on Windows), e.g. a read-only shared volume followed
+/// by a local directory.
+pub fn parameter_cache_dirs() -> Vec<PathBuf> {
+    env::split_paths(&parameter_cache_dir_name())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect()
 }
 
+/// The directory of the parameter cache which new files are written to, the first of
+/// `parameter_cache_dirs` which is writable.
 pub fn parameter_cache_dir() -> PathBuf {
-    Path::new(&parameter_cache_dir_name()).to_path_buf()
+    let dirs = parameter_cache_dirs();
+    if dirs.len() <= 1 {
+        return Path::new(&parameter_cache_dir_name()).to_path_buf();
+    }
+
+    dirs.iter()
+        .find(|dir| is_writable(dir))
+        .unwrap_or(&dirs[0])
+        .clone()
+}
+
+fn is_writable(dir: &Path) -> bool {
+    create_dir_all(dir).is_ok() && tempfile::tempfile_in(dir).is_ok()
+}
+
+/// The path of the file `file_name` of the parameter cache, in the first of `parameter_cache_dirs`
+/// which holds it, or in `parameter_cache_dir` if none does.
+pub fn parameter_cache_file_path(file_name: &str) -> PathBuf {
+    parameter_cache_dirs()
+        .into_iter()
+        .map(|dir| dir.join(file_name))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| parameter_cache_dir().join(file_name))
 }
 
 pub fn parameter_cache_params_path(parameter_set_identifier: &str) -> PathBuf {
-    let dir = Path::new(&parameter_cache_dir_name()).to_path_buf();
-    dir.join(format!(
+    parameter_cache_file_path(&format!(
         "v{}-{}.{}",
         VERSION, parameter_set_identifier, GROTH_PARAMETER_EXT
     ))
 }
 
 pub fn parameter_cache_metadata_path(parameter_set_identifier: &str) -> PathBuf {
-    let dir = Path::new(&parameter_cache_dir_name()).to_path_buf();
-    dir.join(format!(
+    parameter_cache_file_path(&format!(
         "v{}-{}.{}",
         VERSION, parameter_set_identifier, PARAMETER_METADATA_EXT
     ))
 }
 
 pub fn parameter_cache_verifying_key_path(parameter_set_identifier: &str) -> PathBuf {
-    let dir = Path::new(&parameter_cache_dir_name()).to_path_buf();
-    dir.join(format!(
+    parameter_cache_file_path(&format!(
         "v{}-{}.{}",
         VERSION, parameter_set_identifier, VERIFYING_KEY_EXT
     ))
@@ -191,8 +232,7 @@ pub fn parameter_cache_srs_key_path(
     _parameter_set_identifier: &str,
     _num_proofs_to_aggregate: usize,
 ) -> PathBuf {
-    let dir = Path::new(&parameter_cache_dir_name()).to_path_buf();
-    dir.join(format!(
+    parameter_cache_file_path(&format!(
         "v{}-{}.{}",
         VERSION, SRS_SHARED_KEY_NAME, SRS_KEY_EXT
     ))
@@ -326,7 +366,7 @@ where
         _circuit: C,
         pub_params: &P,
         num_proofs_to_aggregate: usize,
-    ) -> Result<groth16::aggregate::GenericSRS<Bls12>> {
+    ) -> Result<Arc<groth16::aggregate::GenericSRS<Bls12>>> {
         let id = Self::cache_identifier(pub_params);
         let cache_path =
             ensure_ancestor_dirs_exist(parameter_cache_srs_key_path(&id, num_proofs_to_aggregate))?;
@@ -351,9 +391,11 @@ where
         };
 
         // generate (or load) srs key
-        match read_cached_srs_key(&cache_path) {
+        match cached_srs_key(&cache_path, srs_len(num_proofs_to_aggregate)) {
             Ok(key) => Ok(key),
-            Err(_) => write_cached_srs_key(&cache_path, generate()?).map_err(Into::into),
+            Err(_) => write_cached_srs_key(&cache_path, generate()?)
+                .map(Arc::new)
+                .map_err(Into::into),
         }
     }
 
@@ -394,12 +436,191 @@ fn ensure_parent(path: &Path) -> io::Result<()> {
     }
 }
 
+/// What a parameter file was found to match its digest with, see `matches_digest`. Besides its
+/// length and modification time, the file is identified by its inode and its change time, which
+/// is updated by any write to the file and can't be set back.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct VerifiedStamp {
+    digest: String,
+    len: u64,
+    modified: Duration,
+    device: u64,
+    inode: u64,
+    changed: Duration,
+}
+
+impl VerifiedStamp {
+    fn of(file: &File, digest: &str) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let (device, inode, changed) = file_identity(&metadata);
+
+        Ok(VerifiedStamp {
+            digest: digest.to_string(),
+            len: metadata.len(),
+            modified,
+            device,
+            inode,
+            changed,
+        })
+    }
+
+    fn path(cache_entry_path: &Path) -> PathBuf {
+        let mut path = cache_entry_path.as_os_str().to_os_string();
+        path.push(".verified");
+        PathBuf::from(path)
+    }
+}
+
+/// The device, the inode and the change time of a file.
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> (u64, u64, Duration) {
+    use std::os::unix::fs::MetadataExt;
+
+    let changed = Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+    (metadata.dev(), metadata.ino(), changed)
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &fs::Metadata) -> (u64, u64, Duration) {
+    (0, 0, Duration::default())
+}
+
+/// Whether the file at `cache_entry_path` matches `digest`, a BLAKE2b hash truncated to 256 bits.
+///
+/// The file is hashed through a memory mapping rather than read into a buffer. With
+/// `verified_params_stamps`, a match is recorded in a stamp next to the file identifying it (see
+/// [`VerifiedStamp`]), so that other processes (or restarts) skip hashing the unchanged file again.
+/// Failing to write the stamp, e.g. in a read-only cache, is logged and otherwise ignored. Without
+/// `trust_stamp`, the file is hashed even if its stamp matches, and only stamped.
+fn matches_digest(cache_entry_path: &Path, digest: &str, trust_stamp: bool) -> Result<bool> {
+    let use_stamps = tenant::settings().verified_params_stamps;
+    let stamp_path = VerifiedStamp::path(cache_entry_path);
+    let hash = with_exclusive_read_lock::<_, io::Error, _>(cache_entry_path, |file| {
+        let stamp = VerifiedStamp::of(file.as_ref(), digest)?;
+        let stamped = if use_stamps && trust_stamp {
+            fs::read(&stamp_path)
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<VerifiedStamp>(&bytes).ok())
+        } else {
+            None
+        };
+        if stamped.as_ref() == Some(&stamp) {
+            info!("{:?} matched its digest before", cache_entry_path);
+            return Ok(None);
+        }
+
+        info!("generating consistency digest for {:?}", cache_entry_path);
+        let map = unsafe { MmapOptions::new().map(file.as_ref())? };
+        let mut hasher = Blake2bParams::new().to_state();
+        hasher.update(&map);
+        info!("generated consistency digest for {:?}", cache_entry_path);
+
+        Ok(Some((hasher.finalize(), stamp)))
+    })?;
+
+    let (hash, stamp) = match hash {
+        Some(hash) => hash,
+        None => return Ok(true),
+    };
+    if &hash.to_hex()[..32] != digest {
+        return Ok(false);
+    }
+    if !use_stamps {
+        return Ok(true);
+    }
+    if let Err(err) = serde_json::to_vec(&stamp)
+        .map_err(io::Error::from)
+        .and_then(|bytes| fs::write(&stamp_path, bytes))
+    {
+        warn!("failed to write {:?}: {}", stamp_path, err);
+    }
+
+    Ok(true)
+}
+
+/// The outcome of checking a parameter file against the digests of `parameters.json` and
+/// `srs-inner-product.json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParameterFileStatus {
+    /// The file matches its digest.
+    Valid,
+    /// The file does not match its digest, it is corrupt or truncated.
+    Mismatch { expected: String },
+    /// The file is not listed, e.g. parameters generated for a custom sector size.
+    Unknown,
+}
+
+/// The status of a file of the parameter cache, see `verify_all_cached_parameters`.
+#[derive(Debug, Clone)]
+pub struct ParameterFileReport {
+    pub file_name: String,
+    pub path: PathBuf,
+    pub status: ParameterFileStatus,
+}
+
+/// Checks the parameter file at `path` against its digest, independently of the
+/// `verify_production_params` setting. The file is always hashed, as a `.verified` stamp could be
+/// forged, and a valid file is not hashed again when it is read with `verify_production_params`
+/// later on.
+pub fn verify_parameter_file(path: &Path) -> Result<ParameterFileStatus> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("invalid parameter path {:?}", path))?
+        .to_string();
+    let data = match get_parameter_data_from_id(&file_name)
+        .or_else(|| get_srs_parameter_data_from_id(&file_name))
+    {
+        Some(data) => data,
+        None => return Ok(ParameterFileStatus::Unknown),
+    };
+    ensure!(path.exists(), "parameter file {:?} does not exist", path);
+
+    if !matches_digest(path, &data.digest, false)? {
+        warn!("{:?} does not match its digest {}", path, data.digest);
+        return Ok(ParameterFileStatus::Mismatch {
+            expected: data.digest.clone(),
+        });
+    }
+    VERIFIED_PARAMETERS
+        .lock()
+        .expect("verified parameters lock failed")
+        .insert(file_name);
+
+    Ok(ParameterFileStatus::Valid)
+}
+
+/// Checks every file of `parameters.json` and `srs-inner-product.json` found in the parameter
+/// cache with `verify_parameter_file`, e.g. as a health check on startup. Files which aren't
+/// cached are skipped, as a node only fetches the parameters of the sector sizes it uses.
+pub fn verify_all_cached_parameters() -> Result<Vec<ParameterFileReport>> {
+    let mut reports = Vec::new();
+    for file_name in PARAMETERS.keys().chain(SRS_PARAMETERS.keys()) {
+        let path = parameter_cache_file_path(file_name);
+        if !path.exists() {
+            continue;
+        }
+        let status = verify_parameter_file(&path)?;
+        reports.push(ParameterFileReport {
+            file_name: file_name.clone(),
+            path,
+            status,
+        });
+    }
+
+    Ok(reports)
+}
+
 // Reads parameter mappings using mmap so that they can be lazily
 // loaded later.
 pub fn read_cached_params(cache_entry_path: &Path) -> Result<groth16::MappedParameters<Bls12>> {
     info!("checking cache_path: {:?} for parameters", cache_entry_path);
 
-    let verify_production_params = SETTINGS.verify_production_params;
+    let verify_production_params = tenant::settings().verify_production_params;
     info!(
         "Verify production parameters is {}",
         verify_production_params
@@ -425,29 +646,14 @@ pub fn read_cached_params(cache_entry_path: &Path) -> Result<groth16::MappedPara
                     .get(&cache_key)
                     .is_none();
                 if not_yet_verified {
-                    info!("generating consistency digest for parameters");
-                    let hash = with_exclusive_read_lock::<_, io::Error, _>(
-                        cache_entry_path,
-                        |mut file| {
-                            let mut hasher = Blake2bParams::new().to_state();
-                            io::copy(&mut file, &mut hasher)
-                                .expect("copying file into hasher failed");
-                            Ok(hasher.finalize())
-                        },
-                    )?;
-                    info!("generated consistency digest for parameters");
-
-                    // The hash in the parameters file is truncated to 256 bits.
-                    let digest_hex = &hash.to_hex()[..32];
-
-                    if digest_hex != data.digest {
+                    if !matches_digest(cache_entry_path, &data.digest, true)? {
                         return Err(Error::InvalidParameters(
                             cache_entry_path.display().to_string(),
                         )
                         .into());
                     }
 
-                    trace!("parameter data is valid [{}]", digest_hex);
+                    trace!("parameter data is valid [{}]", data.digest);
                     VERIFIED_PARAMETERS
                         .lock()
                         .expect("verified parameters lock failed")
@@ -461,8 +667,10 @@ pub fn read_cached_params(cache_entry_path: &Path) -> Result<groth16::MappedPara
     }
 
     with_exclusive_read_lock::<_, io::Error, _>(cache_entry_path, |_file| {
-        let mapped_params =
-            groth16::Parameters::build_mapped_parameters(cache_entry_path.to_path_buf(), false)?;
+        let mapped_params = groth16::Parameters::build_mapped_parameters(
+            mapped_params_path(cache_entry_path),
+            false,
+        )?;
         info!("read parameters from cache {:?} ", cache_entry_path);
 
         Ok(mapped_params)
@@ -470,6 +678,31 @@ pub fn read_cached_params(cache_entry_path: &Path) -> Result<groth16::MappedPara
     .map_err(Into::into)
 }
 
+/// The path the parameters at `cache_entry_path` are mapped from, their segment in the
+/// parameter_cache_shm directory if it is set, see `parameter_shm`.
+fn mapped_params_path(cache_entry_path: &Path) -> PathBuf {
+    let shm_dir = tenant::settings().parameter_cache_shm.clone();
+    if shm_dir.is_empty() {
+        return cache_entry_path.to_path_buf();
+    }
+
+    match parameter_shm::attach(cache_entry_path, Path::new(&shm_dir)) {
+        Ok(path) => path,
+        Err(err) => {
+            warn!(
+                "parameters: not using shared memory {}, falling back to {:?}: {:?}",
+                shm_dir, cache_entry_path, err
+            );
+            events::emit(Event::Fallback {
+                job: control::current_job(),
+                operation: "parameter_cache".to_string(),
+                reason: format!("shared memory {}: {}", shm_dir, err),
+            });
+            cache_entry_path.to_path_buf()
+        }
+    }
+}
+
 fn read_cached_verifying_key(cache_entry_path: &Path) -> io::Result<groth16::VerifyingKey<Bls12>> {
     info!(
         "checking cache_path: {:?} for verifying key",
@@ -483,10 +716,62 @@ fn read_cached_verifying_key(cache_entry_path: &Path) -> io::Result<groth16::Ver
     })
 }
 
-fn read_cached_srs_key(cache_entry_path: &Path) -> Result<groth16::aggregate::GenericSRS<Bls12>> {
+/// The number of elements of the SRS specializing it for an aggregation of
+/// `num_proofs_to_aggregate` proofs needs, the KZG openings use all powers up to 2n - 1, n being
+/// the number of proofs padded to a power of two.
+pub fn srs_len(num_proofs_to_aggregate: usize) -> usize {
+    2 * num_proofs_to_aggregate.next_power_of_two()
+}
+
+/// The SRS at `cache_entry_path` with at least `len` elements, from the memory cache if an
+/// aggregation read as many of them before.
+fn cached_srs_key(
+    cache_entry_path: &Path,
+    len: usize,
+) -> Result<Arc<groth16::aggregate::GenericSRS<Bls12>>> {
+    if let Some(key) = SRS_MEMORY_CACHE
+        .lock()
+        .expect("srs memory cache lock failed")
+        .get(cache_entry_path)
+    {
+        if key.g_alpha_powers.len() >= len {
+            trace!("found srs in memory cache for {:?}", cache_entry_path);
+            return Ok(key.clone());
+        }
+    }
+
+    let key = Arc::new(read_cached_srs_key(cache_entry_path, len)?);
+    SRS_MEMORY_CACHE
+        .lock()
+        .expect("srs memory cache lock failed")
+        .insert(cache_entry_path.to_path_buf(), key.clone());
+
+    Ok(key)
+}
+
+/// Drops the SRS cached in memory, which are read again when they are used next.
+pub fn purge_srs_cache() {
+    SRS_MEMORY_CACHE
+        .lock()
+        .expect("srs memory cache lock failed")
+        .clear();
+}
+
+/// Reads the first `len` elements of the SRS at `cache_entry_path`, verifying its digest first with
+/// `verify_production_params`.
+fn read_cached_srs_key(
+    cache_entry_path: &Path,
+    len: usize,
+) -> Result<groth16::aggregate::GenericSRS<Bls12>> {
     info!("checking cache_path: {:?} for srs", cache_entry_path);
+    ensure!(
+        len <= SRS_MAX_LEN,
+        "aggregations needing {} elements of the srs are not supported, the maximum is {}",
+        len,
+        SRS_MAX_LEN
+    );
 
-    let verify_production_params = SETTINGS.verify_production_params;
+    let verify_production_params = tenant::settings().verify_production_params;
     info!(
         "Verify production parameters is {}",
         verify_production_params
@@ -512,29 +797,14 @@ fn read_cached_srs_key(cache_entry_path: &Path) -> Result<groth16::aggregate::Ge
                     .get(&cache_key)
                     .is_none();
                 if not_yet_verified {
-                    info!("generating consistency digest for srs");
-                    let hash = with_exclusive_read_lock::<_, io::Error, _>(
-                        cache_entry_path,
-                        |mut file| {
-                            let mut hasher = Blake2bParams::new().to_state();
-                            io::copy(&mut file, &mut hasher)
-                                .expect("copying file into hasher failed");
-                            Ok(hasher.finalize())
-                        },
-                    )?;
-                    info!("generated consistency digest for srs");
-
-                    // The hash in the parameters file is truncated to 256 bits.
-                    let digest_hex = &hash.to_hex()[..32];
-
-                    if digest_hex != data.digest {
+                    if !matches_digest(cache_entry_path, &data.digest, true)? {
                         return Err(Error::InvalidParameters(
                             cache_entry_path.display().to_string(),
                         )
                         .into());
                     }
 
-                    trace!("srs data is valid [{}]", digest_hex);
+                    trace!("srs data is valid [{}]", data.digest);
                     VERIFIED_PARAMETERS
                         .lock()
                         .expect("verified parameters lock failed")
@@ -549,13 +819,12 @@ fn read_cached_srs_key(cache_entry_path: &Path) -> Result<groth16::aggregate::Ge
 
     with_exclusive_read_lock(cache_entry_path, |file| {
         let srs_map = unsafe { MmapOptions::new().map(file.as_ref())? };
-        // NOTE: We do not currently support lengths higher than this,
-        // even though the SRS file can handle up to (2 << 19) + 1
-        // elements.  Specifying under that limit speeds up
-        // performance quite a bit.
-        let max_len = (2 << 14) + 1;
-        let key = groth16::aggregate::GenericSRS::read_mmap(&srs_map, max_len)?;
-        info!("read srs key from cache {:?} ", cache_entry_path);
+        // Reading only the elements the aggregation needs speeds up performance quite a bit.
+        let key = groth16::aggregate::GenericSRS::read_mmap(&srs_map, len)?;
+        info!(
+            "read {} elements of srs key from cache {:?} ",
+            len, cache_entry_path
+        );
 
         Ok(key)
     })
@@ -651,3 +920,203 @@ where
     ensure_parent(file_path)?;
     f(&mut open_file(file_path)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::{
+        settings::{Settings, SETTINGS},
+        TEST_SEED,
+    };
+
+    #[test]
+    fn test_cached_srs_key() {
+        let dir = tempfile::tempdir().expect("tempdir failed");
+        let path = dir.path().join("test.srs");
+        let mut rng = XorShiftRng::from_seed(TEST_SEED);
+        write_cached_srs_key(&path, groth16::aggregate::setup_fake_srs(&mut rng, 64))
+            .expect("write_cached_srs_key failed");
+
+        let full = read_cached_srs_key(&path, SRS_MAX_LEN).expect("read_cached_srs_key failed");
+        let small = cached_srs_key(&path, srs_len(5)).expect("cached_srs_key failed");
+        assert!(small.g_alpha_powers.len() >= 16);
+        assert!(small.g_alpha_powers.len() < full.g_alpha_powers.len());
+        assert_eq!(
+            small.g_alpha_powers[..],
+            full.g_alpha_powers[..small.g_alpha_powers.len()]
+        );
+
+        // The cached SRS serves smaller aggregations, larger ones read more of it.
+        let smaller = cached_srs_key(&path, srs_len(2)).expect("cached_srs_key failed");
+        assert!(Arc::ptr_eq(&small, &smaller));
+        let larger = cached_srs_key(&path, srs_len(32)).expect("cached_srs_key failed");
+        assert!(larger.g_alpha_powers.len() >= 64);
+        assert!(cached_srs_key(&path, SRS_MAX_LEN + 1).is_err());
+
+        purge_srs_cache();
+        assert!(!Arc::ptr_eq(
+            &cached_srs_key(&path, srs_len(2)).expect("cached_srs_key failed"),
+            &larger
+        ));
+    }
+
+    #[test]
+    fn test_parameter_cache_dirs() {
+        let dir = tempfile::tempdir().expect("tempdir failed");
+        // Nothing can be created below a file.
+        let blocker = dir.path().join("blocker");
+        fs::write(&blocker, b"").expect("write failed");
+        let unwritable = blocker.join("params");
+        let shared = dir.path().join("shared");
+        let local = dir.path().join("local");
+        create_dir_all(&shared).expect("create_dir_all failed");
+        let vk_name = format!("v{}-a.{}", VERSION, VERIFYING_KEY_EXT);
+        fs::write(shared.join(&vk_name), b"").expect("write failed");
+
+        let parameter_cache = env::join_paths(&[&unwritable, &shared, &local])
+            .expect("join_paths failed")
+            .into_string()
+            .expect("invalid path");
+        let settings = Settings {
+            parameter_cache,
+            ..SETTINGS.clone()
+        };
+        tenant::register_tenant("test-tenant-parameter-dirs", settings, None)
+            .expect("register_tenant failed");
+        tenant::with_tenant("test-tenant-parameter-dirs", || {
+            assert_eq!(
+                parameter_cache_dirs(),
+                vec![unwritable, shared.clone(), local]
+            );
+            assert_eq!(parameter_cache_dir(), shared);
+            assert_eq!(
+                parameter_cache_verifying_key_path("a"),
+                shared.join(&vk_name)
+            );
+            assert_eq!(
+                parameter_cache_params_path("a"),
+                shared.join(format!("v{}-a.{}", VERSION, GROTH_PARAMETER_EXT))
+            );
+        })
+        .expect("with_tenant failed");
+    }
+
+    #[test]
+    fn test_matches_digest() {
+        let dir = tempfile::tempdir().expect("tempdir failed");
+        let path = dir.path().join("v28-test.params");
+        fs::write(&path, b"parameters").expect("write failed");
+        let digest = Blake2bParams::new()
+            .to_state()
+            .update(b"parameters")
+            .finalize();
+        let digest = &digest.to_hex()[..32];
+
+        // Matches are only stamped with `verified_params_stamps`.
+        assert!(matches_digest(&path, digest, true).expect("matches_digest failed"));
+        assert!(!VerifiedStamp::path(&path).exists());
+
+        let settings = Settings {
+            verified_params_stamps: true,
+            ..SETTINGS.clone()
+        };
+        tenant::with_settings(settings, || {
+            assert!(
+                !matches_digest(&path, "00000000000000000000000000000000", true)
+                    .expect("matches_digest failed")
+            );
+            assert!(!VerifiedStamp::path(&path).exists());
+
+            assert!(matches_digest(&path, digest, true).expect("matches_digest failed"));
+            let stamp: VerifiedStamp =
+                serde_json::from_slice(&fs::read(VerifiedStamp::path(&path)).expect("read failed"))
+                    .expect("invalid stamp");
+            assert_eq!(stamp.digest, digest);
+            assert_eq!(stamp.len, 10);
+
+            // Changing the file invalidates the stamp.
+            fs::write(&path, b"corrupted!").expect("write failed");
+            let mut stale = stamp;
+            stale.modified = Duration::default();
+            fs::write(
+                VerifiedStamp::path(&path),
+                serde_json::to_vec(&stale).expect("serialize failed"),
+            )
+            .expect("write failed");
+            assert!(!matches_digest(&path, digest, true).expect("matches_digest failed"));
+        });
+    }
+
+    #[test]
+    fn test_verify_parameter_file_ignores_stamp() {
+        let dir = tempfile::tempdir().expect("tempdir failed");
+        let file_name = PARAMETERS.keys().next().expect("no parameters").clone();
+        let path = dir.path().join(&file_name);
+        fs::write(&path, b"corrupted!").expect("write failed");
+
+        let settings = Settings {
+            verified_params_stamps: true,
+            ..SETTINGS.clone()
+        };
+        tenant::with_settings(settings, || {
+            // A forged stamp matching the corrupt file is trusted when parameters are read, but
+            // not when the file is verified.
+            let file = File::open(&path).expect("open failed");
+            let stamp =
+                VerifiedStamp::of(&file, &PARAMETERS[&file_name].digest).expect("failed to stamp");
+            fs::write(
+                VerifiedStamp::path(&path),
+                serde_json::to_vec(&stamp).expect("serialize failed"),
+            )
+            .expect("write failed");
+            assert!(matches_digest(&path, &PARAMETERS[&file_name].digest, true)
+                .expect("matches_digest failed"));
+
+            assert_eq!(
+                verify_parameter_file(&path).expect("verify_parameter_file failed"),
+                ParameterFileStatus::Mismatch {
+                    expected: PARAMETERS[&file_name].digest.clone()
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_verify_all_cached_parameters() {
+        let dir = tempfile::tempdir().expect("tempdir failed");
+        let file_name = PARAMETERS.keys().next().expect("no parameters").clone();
+        fs::write(dir.path().join(&file_name), b"corrupted!").expect("write failed");
+        let unknown = dir.path().join(format!("v{}-custom.params", VERSION));
+        fs::write(&unknown, b"parameters").expect("write failed");
+
+        assert_eq!(
+            verify_parameter_file(&unknown).expect("verify_parameter_file failed"),
+            ParameterFileStatus::Unknown
+        );
+
+        let settings = Settings {
+            parameter_cache: dir.path().to_str().expect("invalid path").to_string(),
+            ..SETTINGS.clone()
+        };
+        tenant::register_tenant("test-tenant-verify-parameters", settings, None)
+            .expect("register_tenant failed");
+        let reports = tenant::with_tenant("test-tenant-verify-parameters", || {
+            verify_all_cached_parameters()
+        })
+        .expect("with_tenant failed")
+        .expect("verify_all_cached_parameters failed");
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].file_name, file_name);
+        assert_eq!(reports[0].path, dir.path().join(&file_name));
+        assert_eq!(
+            reports[0].status,
+            ParameterFileStatus::Mismatch {
+                expected: PARAMETERS[&file_name].digest.clone()
+            }
+        );
+    }
+}
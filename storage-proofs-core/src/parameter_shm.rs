@@ -0,0 +1,245 @@
+//! Groth parameters loaded into shared memory.
+//!
+//! With the `parameter_cache_shm` setting pointing to a tmpfs directory (e.g. `/dev/shm`), the
+//! first process of the host which uses a parameter file copies it into a segment there, and the
+//! prover processes attach to that segment instead of mapping the file of the parameter cache.
+//! Unlike the pages of the cache file, the segment is not evicted when sealing streams through
+//! the page cache, so the parameters are neither read from disk again nor held twice.
+//!
+//! Every attached process holds a shared lock on its segment, which counts its references; the
+//! kernel drops the lock of a process which exits. A process detaching from a segment removes it
+//! if no other process holds a lock on it. Loading and removing segments is coordinated through a
+//! lock file next to each of them. A segment is valid while the source file it was copied from
+//! keeps its length and modification time, which are recorded next to the segment once it is
+//! copied. An invalid segment is replaced; processes still attached to it keep reading the old
+//! one, as it is unlinked rather than overwritten. Partial copies of processes which died while
+//! loading a segment are removed by the next process loading it.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use fs2::FileExt;
+use lazy_static::lazy_static;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+lazy_static! {
+    /// The segments this process is attached to, with the file it holds its shared lock with.
+    static ref ATTACHED: Mutex<HashMap<PathBuf, File>> = Mutex::new(HashMap::new());
+}
+
+/// The source file a segment was copied from.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Source {
+    len: u64,
+    modified: Duration,
+}
+
+impl Source {
+    fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Ok(Source {
+            len: metadata.len(),
+            modified,
+        })
+    }
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Takes the lock coordinating the loading and removal of the segment at `path`, which is
+/// released when the returned file is dropped.
+fn coordinate(path: &Path) -> Result<File> {
+    let lock_path = sibling(path, ".lock");
+    let lock = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&lock_path)
+        .with_context(|| format!("could not open {}", lock_path.display()))?;
+    lock.lock_exclusive()?;
+
+    Ok(lock)
+}
+
+/// Attaches this process to the segment of the parameter file `source` in `dir`, loading it first
+/// unless it is loaded already, and returns the path of the segment.
+pub(crate) fn attach(source: &Path, dir: &Path) -> Result<PathBuf> {
+    let file_name = source
+        .file_name()
+        .context("parameter path has no file name")?;
+    let path = dir.join(file_name);
+
+    let mut attached = ATTACHED.lock().expect("attached segments lock failed");
+    if attached.contains_key(&path) {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+    let _lock = coordinate(&path)?;
+    let source_path = sibling(&path, ".source");
+    let expected =
+        Source::of(source).with_context(|| format!("could not stat {}", source.display()))?;
+    let loaded = fs::read(&source_path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<Source>(&bytes).ok());
+    if path.exists() && loaded.as_ref() == Some(&expected) {
+        debug!("parameters: {} is loaded", path.display());
+    } else {
+        info!(
+            "parameters: loading {} into {}",
+            source.display(),
+            path.display()
+        );
+        match fs::remove_file(&source_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        remove_stale_copies(&path)?;
+        // The copy is renamed into place, so that no process attaches to a partial segment.
+        let tmp_path = sibling(&path, &format!(".tmp-{}", process::id()));
+        if let Err(err) = fs::copy(source, &tmp_path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err).with_context(|| {
+                format!(
+                    "could not copy {} to {}",
+                    source.display(),
+                    tmp_path.display()
+                )
+            });
+        }
+        fs::rename(&tmp_path, &path)?;
+        fs::write(&source_path, serde_json::to_vec(&expected)?)?;
+        info!("parameters: loaded {}", path.display());
+    }
+
+    let segment =
+        File::open(&path).with_context(|| format!("could not open {}", path.display()))?;
+    segment.lock_shared()?;
+    attached.insert(path.clone(), segment);
+
+    Ok(path)
+}
+
+/// Removes the copies of the segment at `path` left behind by processes which died while loading
+/// it. Copies are only made while holding the lock of `coordinate`, so all of them are stale.
+fn remove_stale_copies(path: &Path) -> Result<()> {
+    let (dir, file_name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(file_name)) => (dir, file_name),
+        _ => return Ok(()),
+    };
+    let mut prefix = file_name.to_os_string();
+    prefix.push(".tmp-");
+    let prefix = prefix.to_string_lossy().into_owned();
+
+    for entry in fs::read_dir(dir).with_context(|| format!("could not list {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            info!("parameters: removing stale {}", entry.path().display());
+            match fs::remove_file(entry.path()) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Detaches this process from all segments it is attached to, removing those no other process is
+/// attached to. Parameters read from a segment before remain readable until they are dropped.
+pub fn detach_all() -> Result<()> {
+    let mut attached = ATTACHED.lock().expect("attached segments lock failed");
+    for (path, segment) in attached.drain() {
+        let _lock = coordinate(&path)?;
+        // Succeeds if this process holds the only shared lock, which it upgrades.
+        if segment.try_lock_exclusive().is_err() {
+            debug!("parameters: {} is still attached", path.display());
+            continue;
+        }
+        // The segment may have been replaced since it was attached to.
+        if !same_file(&segment, &path)? {
+            continue;
+        }
+
+        info!("parameters: removing {}", path.display());
+        fs::remove_file(sibling(&path, ".source"))?;
+        fs::remove_file(&path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    let attached = file.metadata()?;
+
+    Ok(metadata.dev() == attached.dev() && metadata.ino() == attached.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_file: &File, _path: &Path) -> Result<bool> {
+    // Segments are only removed on Unix, files which are open can't be removed elsewhere.
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attach_detach() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let shm_dir = tempfile::tempdir().expect("tempdir failure");
+        let source = dir.path().join("v28-test.params");
+        fs::write(&source, b"parameters").expect("write failure");
+        // The copy of a process which died while loading the segment.
+        let stale = shm_dir.path().join("v28-test.params.tmp-1");
+        fs::write(&stale, b"param").expect("write failure");
+
+        let path = attach(&source, shm_dir.path()).expect("attach failure");
+        assert_eq!(path, shm_dir.path().join("v28-test.params"));
+        assert!(!stale.exists());
+        assert_eq!(fs::read(&path).expect("read failure"), b"parameters");
+        assert_eq!(
+            attach(&source, shm_dir.path()).expect("attach failure"),
+            path
+        );
+
+        // Another attached process keeps the segment from being removed.
+        let other = File::open(&path).expect("open failure");
+        other.lock_shared().expect("lock failure");
+        detach_all().expect("detach failure");
+        assert!(path.exists());
+
+        drop(other);
+        attach(&source, shm_dir.path()).expect("attach failure");
+        detach_all().expect("detach failure");
+        assert!(!path.exists());
+    }
+}
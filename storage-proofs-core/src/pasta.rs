@@ -0,0 +1,312 @@
+//! Experimental proof backend over the Pasta curves.
+//!
+//! The production [`Hasher`](filecoin_hashers::Hasher) machinery is tied to the BLS12-381 scalar
+//! field. This module provides the field-generic counterparts needed to build the same Poseidon
+//! merkle trees over the scalar fields of Pallas ([`Fq`]) and Vesta ([`Fp`]), as groundwork for
+//! proof systems other than Groth16. Trees are built with the regular `merkletree` machinery.
+//!
+//! Nothing in here is used by the sealing or PoSt APIs, and the hash outputs are not compatible
+//! with any production proof.
+
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Formatter};
+use std::hash::{Hash, Hasher as StdHasher};
+use std::marker::PhantomData;
+use std::panic::panic_any;
+
+use anyhow::ensure;
+use ff::{Field, PrimeField};
+use generic_array::typenum::{U2, U4, U8};
+use lazy_static::lazy_static;
+use merkletree::{
+    hash::Algorithm as LightAlgorithm,
+    merkle::{Element, MerkleTree},
+    store::VecStore,
+};
+use neptune::poseidon::{Poseidon, PoseidonConstants};
+pub use pasta_curves::{Fp, Fq};
+
+use crate::error::Result;
+
+lazy_static! {
+    static ref FP_POSEIDON_CONSTANTS_2: PoseidonConstants<Fp, U2> = PoseidonConstants::new();
+    static ref FP_POSEIDON_CONSTANTS_4: PoseidonConstants<Fp, U4> = PoseidonConstants::new();
+    static ref FP_POSEIDON_CONSTANTS_8: PoseidonConstants<Fp, U8> = PoseidonConstants::new();
+    static ref FQ_POSEIDON_CONSTANTS_2: PoseidonConstants<Fq, U2> = PoseidonConstants::new();
+    static ref FQ_POSEIDON_CONSTANTS_4: PoseidonConstants<Fq, U4> = PoseidonConstants::new();
+    static ref FQ_POSEIDON_CONSTANTS_8: PoseidonConstants<Fq, U8> = PoseidonConstants::new();
+}
+
+/// A field supported by the Pasta backend, together with its Poseidon constants.
+pub trait PastaField: PrimeField<Repr = [u8; 32]> + Send + Sync + 'static {
+    fn poseidon_constants_2() -> &'static PoseidonConstants<Self, U2>;
+    fn poseidon_constants_4() -> &'static PoseidonConstants<Self, U4>;
+    fn poseidon_constants_8() -> &'static PoseidonConstants<Self, U8>;
+}
+
+impl PastaField for Fp {
+    fn poseidon_constants_2() -> &'static PoseidonConstants<Self, U2> {
+        &*FP_POSEIDON_CONSTANTS_2
+    }
+
+    fn poseidon_constants_4() -> &'static PoseidonConstants<Self, U4> {
+        &*FP_POSEIDON_CONSTANTS_4
+    }
+
+    fn poseidon_constants_8() -> &'static PoseidonConstants<Self, U8> {
+        &*FP_POSEIDON_CONSTANTS_8
+    }
+}
+
+impl PastaField for Fq {
+    fn poseidon_constants_2() -> &'static PoseidonConstants<Self, U2> {
+        &*FQ_POSEIDON_CONSTANTS_2
+    }
+
+    fn poseidon_constants_4() -> &'static PoseidonConstants<Self, U4> {
+        &*FQ_POSEIDON_CONSTANTS_4
+    }
+
+    fn poseidon_constants_8() -> &'static PoseidonConstants<Self, U8> {
+        &*FQ_POSEIDON_CONSTANTS_8
+    }
+}
+
+/// A merkle tree node: the little endian representation of an element of `F`.
+pub struct PastaDomain<F> {
+    repr: [u8; 32],
+    _field: PhantomData<F>,
+}
+
+impl<F: PastaField> PastaDomain<F> {
+    pub fn try_from_bytes(raw: &[u8]) -> Result<Self> {
+        ensure!(raw.len() == Self::byte_len(), "invalid amount of bytes");
+        let mut repr = [0u8; 32];
+        repr.copy_from_slice(raw);
+        ensure!(
+            F::from_repr_vartime(repr).is_some(),
+            "bytes are not a valid field element"
+        );
+
+        Ok(PastaDomain {
+            repr,
+            _field: PhantomData,
+        })
+    }
+
+    pub fn to_field(&self) -> F {
+        F::from_repr_vartime(self.repr).expect("from_repr failure")
+    }
+}
+
+impl<F: PastaField> From<F> for PastaDomain<F> {
+    fn from(val: F) -> Self {
+        PastaDomain {
+            repr: val.to_repr(),
+            _field: PhantomData,
+        }
+    }
+}
+
+impl<F> Clone for PastaDomain<F> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<F> Copy for PastaDomain<F> {}
+
+impl<F> Default for PastaDomain<F> {
+    fn default() -> Self {
+        PastaDomain {
+            repr: [0u8; 32],
+            _field: PhantomData,
+        }
+    }
+}
+
+impl<F> Debug for PastaDomain<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "PastaDomain({})", hex::encode(&self.repr))
+    }
+}
+
+impl<F> PartialEq for PastaDomain<F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.repr == other.repr
+    }
+}
+
+impl<F> Eq for PastaDomain<F> {}
+
+impl<F> Ord for PastaDomain<F> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.repr.cmp(&other.repr)
+    }
+}
+
+impl<F> PartialOrd for PastaDomain<F> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<F> Hash for PastaDomain<F> {
+    fn hash<H: StdHasher>(&self, state: &mut H) {
+        self.repr.hash(state);
+    }
+}
+
+impl<F> AsRef<[u8]> for PastaDomain<F> {
+    fn as_ref(&self) -> &[u8] {
+        &self.repr
+    }
+}
+
+impl<F: PastaField> Element for PastaDomain<F> {
+    fn byte_len() -> usize {
+        32
+    }
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        match PastaDomain::try_from_bytes(bytes) {
+            Ok(res) => res,
+            Err(err) => panic_any(err),
+        }
+    }
+
+    fn copy_to_slice(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.repr);
+    }
+}
+
+/// Hashes 2, 4 or 8 field elements with Poseidon.
+pub fn poseidon_hash<F: PastaField>(preimage: &[F]) -> F {
+    match preimage.len() {
+        2 => Poseidon::new_with_preimage(preimage, F::poseidon_constants_2()).hash(),
+        4 => Poseidon::new_with_preimage(preimage, F::poseidon_constants_4()).hash(),
+        8 => Poseidon::new_with_preimage(preimage, F::poseidon_constants_8()).hash(),
+        arity => panic_any(format!("unsupported arity {}", arity)),
+    }
+}
+
+/// The Poseidon `merkletree` algorithm over `F`.
+pub struct PastaPoseidonFunction<F>(F);
+
+impl<F: PastaField> Default for PastaPoseidonFunction<F> {
+    fn default() -> Self {
+        PastaPoseidonFunction(F::zero())
+    }
+}
+
+impl<F: PastaField> StdHasher for PastaPoseidonFunction<F> {
+    fn write(&mut self, msg: &[u8]) {
+        let preimage = msg
+            .chunks(32)
+            .map(|chunk| PastaDomain::<F>::from_slice(chunk).to_field())
+            .collect::<Vec<_>>();
+        self.0 = poseidon_hash(&preimage);
+    }
+
+    fn finish(&self) -> u64 {
+        unimplemented!()
+    }
+}
+
+impl<F: PastaField> LightAlgorithm<PastaDomain<F>> for PastaPoseidonFunction<F> {
+    fn hash(&mut self) -> PastaDomain<F> {
+        self.0.into()
+    }
+
+    fn reset(&mut self) {
+        self.0 = F::zero();
+    }
+
+    fn leaf(&mut self, leaf: PastaDomain<F>) -> PastaDomain<F> {
+        leaf
+    }
+
+    fn node(
+        &mut self,
+        left: PastaDomain<F>,
+        right: PastaDomain<F>,
+        _height: usize,
+    ) -> PastaDomain<F> {
+        poseidon_hash(&[left.to_field(), right.to_field()]).into()
+    }
+
+    fn multi_node(&mut self, parts: &[PastaDomain<F>], _height: usize) -> PastaDomain<F> {
+        poseidon_hash(&parts.iter().map(PastaDomain::to_field).collect::<Vec<_>>()).into()
+    }
+}
+
+/// An in-memory Poseidon merkle tree over `F` with the given arity (2, 4 or 8).
+pub type PastaMerkleTree<F, U> =
+    MerkleTree<PastaDomain<F>, PastaPoseidonFunction<F>, VecStore<PastaDomain<F>>, U>;
+pub type PastaBinaryMerkleTree<F> = PastaMerkleTree<F, U2>;
+pub type PastaOctMerkleTree<F> = PastaMerkleTree<F, U8>;
+
+/// Builds an in-memory Poseidon merkle tree over `F` from its leaves.
+pub fn build_pasta_tree<F, U>(leaves: &[F]) -> Result<PastaMerkleTree<F, U>>
+where
+    F: PastaField,
+    U: generic_array::typenum::Unsigned,
+{
+    MerkleTree::new(leaves.iter().copied().map(PastaDomain::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::TEST_SEED;
+
+    fn test_binary_tree<F: PastaField>() {
+        let mut rng = XorShiftRng::from_seed(TEST_SEED);
+        let leaves: Vec<F> = (0..4).map(|_| F::random(&mut rng)).collect();
+
+        let tree = build_pasta_tree::<F, U2>(&leaves).expect("build_pasta_tree failure");
+        let expected = poseidon_hash(&[
+            poseidon_hash(&[leaves[0], leaves[1]]),
+            poseidon_hash(&[leaves[2], leaves[3]]),
+        ]);
+
+        assert_eq!(tree.root(), PastaDomain::from(expected));
+        assert_eq!(
+            tree.read_at(2).expect("read_at failure").to_field(),
+            leaves[2]
+        );
+    }
+
+    #[test]
+    fn test_binary_tree_fp() {
+        test_binary_tree::<Fp>();
+    }
+
+    #[test]
+    fn test_binary_tree_fq() {
+        test_binary_tree::<Fq>();
+    }
+
+    #[test]
+    fn test_oct_tree_root() {
+        let leaves: Vec<Fp> = (0..8u64).map(Fp::from).collect();
+        let tree = build_pasta_tree::<Fp, U8>(&leaves).expect("build_pasta_tree failure");
+
+        assert_eq!(tree.root(), PastaDomain::from(poseidon_hash(&leaves)));
+    }
+
+    #[test]
+    fn test_domain_bytes() {
+        let domain = PastaDomain::from(Fq::from(42u64));
+        let parsed =
+            PastaDomain::<Fq>::try_from_bytes(domain.as_ref()).expect("try_from_bytes failure");
+        assert_eq!(parsed, domain);
+
+        assert!(PastaDomain::<Fq>::try_from_bytes(&[0xff; 32]).is_err());
+        assert!(PastaDomain::<Fq>::try_from_bytes(&[0; 31]).is_err());
+    }
+}
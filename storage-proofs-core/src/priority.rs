@@ -0,0 +1,283 @@
+//! Operation priority classes, enforced through OS scheduling knobs.
+//!
+//! Each proving operation runs in a priority class. When `use_os_priorities` is enabled, entering
+//! a class adjusts the calling thread's nice value and I/O priority (and optionally moves it into
+//! a per-class cgroup), so that deadline critical work wins on contended hosts. Threads spawned
+//! afterwards inherit the adjustments, which covers the SDR producer threads bound by `cores.rs`.
+//!
+//! Only the calling thread and the threads it spawns are affected. The work an operation hands to
+//! the shared pools, i.e. rayon, the yastl `THREAD_POOL` and bellperson's workers, runs on threads
+//! which were started before and keep their priority, as they also run the work of other classes.
+//!
+//! Raising a priority above the default (and restoring a prior priority after lowering it)
+//! requires `CAP_SYS_NICE` or a suitable `RLIMIT_NICE`. Failures are logged, never fatal.
+//!
+//! The class of the calling thread is also recorded regardless of `use_os_priorities`, see
+//! [`current_priority_class`], e.g. to order the proofs of the process on the GPU.
+
+use std::cell::Cell;
+use std::fmt;
+
+use log::{debug, warn};
+
+use crate::settings::SETTINGS;
+
+thread_local! {
+    static CURRENT_CLASS: Cell<Option<PriorityClass>> = Cell::new(None);
+}
+
+/// Priority classes, ordered from most to least urgent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PriorityClass {
+    WindowPost,
+    WinningPost,
+    SealCommitPhase2,
+    SealPreCommitPhase2,
+    SealPreCommitPhase1,
+    Unseal,
+}
+
+impl PriorityClass {
+    /// The nice value threads of this class run with.
+    pub fn nice(self) -> i32 {
+        match self {
+            PriorityClass::WindowPost => -10,
+            PriorityClass::WinningPost => -5,
+            PriorityClass::SealCommitPhase2 => 0,
+            PriorityClass::SealPreCommitPhase2 => 5,
+            PriorityClass::SealPreCommitPhase1 => 10,
+            PriorityClass::Unseal => 15,
+        }
+    }
+
+    /// The best-effort I/O priority level (0 is highest, 7 lowest) of this class.
+    pub fn io_level(self) -> u32 {
+        match self {
+            PriorityClass::WindowPost => 0,
+            PriorityClass::WinningPost => 1,
+            PriorityClass::SealCommitPhase2 => 2,
+            PriorityClass::SealPreCommitPhase2 => 4,
+            PriorityClass::SealPreCommitPhase1 => 5,
+            PriorityClass::Unseal => 7,
+        }
+    }
+
+    /// The name of the cgroup (below `priority_cgroup_root`) used for this class.
+    pub fn cgroup_name(self) -> &'static str {
+        match self {
+            PriorityClass::WindowPost => "window-post",
+            PriorityClass::WinningPost => "winning-post",
+            PriorityClass::SealCommitPhase2 => "c2",
+            PriorityClass::SealPreCommitPhase2 => "pc2",
+            PriorityClass::SealPreCommitPhase1 => "pc1",
+            PriorityClass::Unseal => "unseal",
+        }
+    }
+}
+
+impl fmt::Display for PriorityClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.cgroup_name())
+    }
+}
+
+/// Restores the thread's previous scheduling state and priority class when dropped.
+#[derive(Debug)]
+pub struct PriorityGuard {
+    prior_class: Option<PriorityClass>,
+    #[cfg(target_os = "linux")]
+    prior: Option<linux::ThreadState>,
+}
+
+impl PriorityGuard {
+    fn new(prior_class: Option<PriorityClass>) -> Self {
+        PriorityGuard {
+            prior_class,
+            #[cfg(target_os = "linux")]
+            prior: None,
+        }
+    }
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        CURRENT_CLASS.with(|current| current.set(self.prior_class));
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(prior) = self.prior.take() {
+                linux::restore(prior);
+            }
+        }
+    }
+}
+
+/// Runs the current thread in the given priority class until the returned guard is dropped.
+///
+/// This is a no-op unless `use_os_priorities` is enabled. The threads of the shared pools keep
+/// their priority, see the module documentation.
+pub fn enter_priority_class(class: PriorityClass) -> PriorityGuard {
+    #[allow(unused_mut)]
+    let mut guard = PriorityGuard::new(CURRENT_CLASS.with(|current| current.replace(Some(class))));
+    if !SETTINGS.use_os_priorities {
+        return guard;
+    }
+    debug!("entering priority class {}", class);
+
+    #[cfg(target_os = "linux")]
+    {
+        guard.prior = Some(linux::apply(class, &SETTINGS.priority_cgroup_root));
+        guard
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        warn!("os priorities are only supported on linux, ignoring");
+        guard
+    }
+}
+
+/// The priority class the current thread runs in, if any.
+pub fn current_priority_class() -> Option<PriorityClass> {
+    CURRENT_CLASS.with(Cell::get)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{debug, warn, PriorityClass};
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+    const IOPRIO_CLASS_BE: u32 = 2;
+
+    #[derive(Debug)]
+    pub(super) struct ThreadState {
+        tid: libc::pid_t,
+        nice: libc::c_int,
+        ioprio: libc::c_long,
+        cgroup: Option<PathBuf>,
+    }
+
+    fn gettid() -> libc::pid_t {
+        unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
+    }
+
+    fn set_nice(tid: libc::pid_t, nice: libc::c_int) {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } != 0 {
+            warn!(
+                "failed to set nice {} for thread {}: {}",
+                nice,
+                tid,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    fn set_ioprio(tid: libc::pid_t, ioprio: libc::c_long) {
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, ioprio) } != 0 {
+            warn!(
+                "failed to set io priority {} for thread {}: {}",
+                ioprio,
+                tid,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Returns the cgroup v2 directory the thread currently belongs to.
+    fn current_cgroup() -> Option<PathBuf> {
+        let cgroups = fs::read_to_string("/proc/thread-self/cgroup").ok()?;
+        cgroups
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(|path| Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+    }
+
+    fn move_to_cgroup(tid: libc::pid_t, cgroup: &Path) -> bool {
+        match fs::write(cgroup.join("cgroup.threads"), tid.to_string()) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("failed to move thread {} to {:?}: {}", tid, cgroup, err);
+                false
+            }
+        }
+    }
+
+    pub(super) fn apply(class: PriorityClass, cgroup_root: &str) -> ThreadState {
+        let tid = gettid();
+        let prior = ThreadState {
+            tid,
+            nice: unsafe { libc::getpriority(libc::PRIO_PROCESS, tid as libc::id_t) },
+            ioprio: unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, tid) },
+            cgroup: if cgroup_root.is_empty() {
+                None
+            } else {
+                current_cgroup()
+            },
+        };
+
+        set_nice(tid, class.nice());
+        set_ioprio(
+            tid,
+            ((IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | class.io_level()) as libc::c_long,
+        );
+        if prior.cgroup.is_some() {
+            move_to_cgroup(tid, &Path::new(cgroup_root).join(class.cgroup_name()));
+        }
+        debug!("thread {} now in priority class {}", tid, class);
+
+        prior
+    }
+
+    pub(super) fn restore(prior: ThreadState) {
+        set_nice(prior.tid, prior.nice);
+        if prior.ioprio >= 0 {
+            set_ioprio(prior.tid, prior.ioprio);
+        }
+        if let Some(cgroup) = prior.cgroup {
+            move_to_cgroup(prior.tid, &cgroup);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_class_ordering() {
+        let classes = [
+            PriorityClass::WindowPost,
+            PriorityClass::WinningPost,
+            PriorityClass::SealCommitPhase2,
+            PriorityClass::SealPreCommitPhase2,
+            PriorityClass::SealPreCommitPhase1,
+            PriorityClass::Unseal,
+        ];
+        for pair in classes.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!(pair[0].nice() < pair[1].nice());
+            assert!(pair[0].io_level() < pair[1].io_level());
+            assert!(pair[1].io_level() <= 7);
+        }
+    }
+
+    #[test]
+    fn test_current_priority_class() {
+        assert_eq!(current_priority_class(), None);
+        {
+            let _outer = enter_priority_class(PriorityClass::SealCommitPhase2);
+            {
+                let _inner = enter_priority_class(PriorityClass::WinningPost);
+                assert_eq!(current_priority_class(), Some(PriorityClass::WinningPost));
+            }
+            assert_eq!(
+                current_priority_class(),
+                Some(PriorityClass::SealCommitPhase2)
+            );
+        }
+        assert_eq!(current_priority_class(), None);
+    }
+}
@@ -0,0 +1,63 @@
+//! Scoped overrides of thread-local values, e.g. the tenant or the GPU scheduler of the calling
+//! thread.
+
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+/// Runs `f` with the thread-local `key` set to `value`, and restores its previous value once `f`
+/// returns, also when it unwinds.
+pub fn with_local<V, T, F>(key: &'static LocalKey<RefCell<V>>, value: V, f: F) -> T
+where
+    V: 'static,
+    F: FnOnce() -> T,
+{
+    let _restore = Restore {
+        key,
+        previous: Some(key.with(|cell| cell.replace(value))),
+    };
+
+    f()
+}
+
+struct Restore<V: 'static> {
+    key: &'static LocalKey<RefCell<V>>,
+    previous: Option<V>,
+}
+
+impl<V: 'static> Drop for Restore<V> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            self.key.with(|cell| *cell.borrow_mut() = previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        static VALUE: RefCell<Option<u32>> = RefCell::new(None);
+    }
+
+    fn value() -> Option<u32> {
+        VALUE.with(|cell| *cell.borrow())
+    }
+
+    #[test]
+    fn test_with_local() {
+        let values = with_local(&VALUE, Some(1), || {
+            let outer = value();
+            let inner = with_local(&VALUE, Some(2), value);
+            (outer, inner, value())
+        });
+        assert_eq!(values, (Some(1), Some(2), Some(1)));
+        assert_eq!(value(), None);
+
+        // The previous value is restored when `f` panics, too.
+        let panicked =
+            std::panic::catch_unwind(|| with_local(&VALUE, Some(3), || panic!("failed")));
+        assert!(panicked.is_err());
+        assert_eq!(value(), None);
+    }
+}
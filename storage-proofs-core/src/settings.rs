@@ -11,28 +11,59 @@ lazy_static! {
 const SETTINGS_PATH: &str = "./rust-fil-proofs.config.toml";
 const PREFIX: &str = "FIL_PROOFS";
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Settings {
     pub verify_cache: bool,
     pub verify_production_params: bool,
+    pub verified_params_stamps: bool,
     pub use_gpu_column_builder: bool,
     pub max_gpu_column_batch_size: u32,
     pub column_write_batch_size: u32,
+    pub tree_c_memory_budget: u64,
     pub use_gpu_tree_builder: bool,
     pub max_gpu_tree_batch_size: u32,
+    pub gpu_tree_batch_size_auto: bool,
     pub rows_to_discard: u32,
+    pub rows_to_discard_auto: bool,
     pub sdr_parents_cache_size: u32,
     pub window_post_synthesis_num_cpus: u32,
     pub parameter_cache: String,
+    pub parameter_cache_shm: String,
+    pub parameter_memory_cache_bytes: u64,
     pub parent_cache: String,
+    pub parent_cache_shared: String,
+    pub parent_cache_shm: String,
+    pub parent_cache_verify_interval_secs: u64,
     pub use_multicore_sdr: bool,
+    pub use_gpu_sdr: bool,
     pub multicore_sdr_producers: usize,
     pub multicore_sdr_producer_stride: u64,
     pub multicore_sdr_lookahead: usize,
+    pub multicore_sdr_cache_window_nodes: u32,
     pub multicore_sdr_skip_cores: String,
+    pub skip_cores: String,
     pub multicore_sdr_core_plan: String,
+    pub multicore_sdr_core_plan_file: String,
+    pub multicore_sdr_core_grouping: String,
+    pub multicore_sdr_checkout_timeout_secs: u64,
+    pub multicore_sdr_core_lock_dir: String,
+    pub multicore_sdr_pc1_reserved_groups: usize,
+    pub multicore_sdr_checkpoint_nodes: u64,
     pub merkle_tree_cache: String,
+    pub control_socket: String,
+    pub use_os_priorities: bool,
+    pub priority_cgroup_root: String,
+    pub events_json: String,
+    pub encode_replica_in_pc1: bool,
+    pub pc2_tree_records: bool,
+    pub batch_sdr_interleave: usize,
+    pub layer_write_mode: String,
+    pub remote_c2_url: String,
+    pub remote_c2_timeout_secs: u64,
+    pub c2_batch_sectors: usize,
+    pub gpu_preemption_circuits: usize,
+    pub gpu_framework: String,
 }
 
 impl Default for Settings {
@@ -40,40 +71,89 @@ impl Default for Settings {
         Settings {
             verify_cache: false,
             verify_production_params: false,
+            verified_params_stamps: false,
             use_gpu_column_builder: false,
             max_gpu_column_batch_size: 400_000,
             column_write_batch_size: 262_144,
+            tree_c_memory_budget: 0,
             use_gpu_tree_builder: false,
             max_gpu_tree_batch_size: 700_000,
+            gpu_tree_batch_size_auto: false,
             rows_to_discard: 2,
+            rows_to_discard_auto: false,
             sdr_parents_cache_size: 2_048,
             window_post_synthesis_num_cpus: num_cpus::get() as u32,
             // `parameter_cache` does not use the cache() mechanism because it is now used
             // for durable, canonical Groth parameters and verifying keys.
             // The name is retained for backwards compatibility.
-            parameter_cache: "/var/tmp/filecoin-proof-parameters/".to_string(),
+            parameter_cache: format!("{}filecoin-proof-parameters/", default_tmp_dir()),
+            parameter_cache_shm: "".to_string(),
+            parameter_memory_cache_bytes: 0,
             parent_cache: cache("filecoin-parents"),
+            parent_cache_shared: "".to_string(),
+            parent_cache_shm: "".to_string(),
+            parent_cache_verify_interval_secs: 0,
             use_multicore_sdr: false,
+            use_gpu_sdr: false,
             multicore_sdr_producers: 3,
             multicore_sdr_producer_stride: 128,
             multicore_sdr_lookahead: 800,
+            multicore_sdr_cache_window_nodes: 0,
             multicore_sdr_skip_cores: "".to_string(),
-            multicore_sdr_core_plan: "DELL7525".to_string(),
+            skip_cores: "".to_string(),
+            multicore_sdr_core_plan: "".to_string(),
+            multicore_sdr_core_plan_file: "".to_string(),
+            multicore_sdr_core_grouping: "cache".to_string(),
+            multicore_sdr_checkout_timeout_secs: 0,
+            multicore_sdr_core_lock_dir: "".to_string(),
+            multicore_sdr_pc1_reserved_groups: 0,
+            multicore_sdr_checkpoint_nodes: 0,
             merkle_tree_cache: "/mnt/sealing/tree".to_string(),
+            control_socket: "".to_string(),
+            use_os_priorities: false,
+            priority_cgroup_root: "".to_string(),
+            events_json: "".to_string(),
+            encode_replica_in_pc1: false,
+            pc2_tree_records: false,
+            batch_sdr_interleave: 0,
+            layer_write_mode: "".to_string(),
+            remote_c2_url: "".to_string(),
+            remote_c2_timeout_secs: 0,
+            c2_batch_sectors: 2,
+            gpu_preemption_circuits: 0,
+            gpu_framework: "".to_string(),
         }
     }
 }
 
 /// All cache files and directories paths should be constructed using this function,
-/// which its base directory from the FIL_PROOFS_CACHE_DIR env var, and defaults to /var/tmp.
+/// which its base directory from the FIL_PROOFS_CACHE_DIR env var, and defaults to /var/tmp (or
+/// the temporary directory of the user on Windows).
 /// Note that FIL_PROOFS_CACHE_DIR is not a first class setting and can only be set by env var.
 fn cache(s: &str) -> String {
     let cache_var = format!("{}_CACHE_DIR", PREFIX);
-    let mut cache_name = env::var(cache_var).unwrap_or_else(|_| "/var/tmp/".to_string());
+    let mut cache_name = env::var(cache_var).unwrap_or_else(|_| default_tmp_dir());
     cache_name.push_str(s);
     cache_name
 }
 
+/// The base directory of the default cache paths, with a trailing separator.
+#[cfg(not(target_os = "windows"))]
+fn default_tmp_dir() -> String {
+    "/var/tmp/".to_string()
+}
+
+/// The base directory of the default cache paths, with a trailing separator. There is no
+/// equivalent of /var/tmp on Windows, the temporary directory of the user is used instead.
+#[cfg(target_os = "windows")]
+fn default_tmp_dir() -> String {
+    let mut dir = env::temp_dir().display().to_string();
+    if !dir.ends_with('\\') {
+        dir.push('\\');
+    }
+    dir
+}
+
 /// Sets an environment variable to a value if it isn't properly set yet.
 fn set_env_var_if_unset(env_var: &str, value: &str) {
     if env::var(env_var).is_err() {
@@ -0,0 +1,248 @@
+//! Tenant namespaces for proving services shared by several tenants within one process.
+//!
+//! A tenant has its own [`Settings`], which replace the global [`SETTINGS`] for all work run
+//! within [`with_tenant`] on the calling thread. This keeps parameter caches, parent caches and
+//! tuning settings of the tenants apart. Settings which are process wide by nature (control
+//! socket, events, OS priorities and the multicore SDR core plan) are always taken from
+//! [`SETTINGS`].
+//!
+//! The tenant is carried over to the threads the proofs hand their work to: the threads of the
+//! PC2 tree builders, the producers of the multicore SDR, the synthesis of the circuits and the
+//! per-sector work of the PoSts. Threads started by the caller run outside of a tenant unless
+//! they enter it with [`with_current_tenant`].
+//!
+//! A tenant may also limit the scratch space used by its running jobs. Jobs reserve the space
+//! they are going to need with [`reserve_scratch`] and release it once they are done.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, ensure};
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::error::{Error, Result};
+use crate::scoped::with_local;
+use crate::settings::{Settings, SETTINGS};
+
+#[derive(Debug)]
+pub struct Tenant {
+    name: String,
+    settings: Arc<Settings>,
+    scratch_quota: Option<u64>,
+    scratch_used: AtomicU64,
+}
+
+impl Tenant {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn settings(&self) -> &Arc<Settings> {
+        &self.settings
+    }
+
+    /// Maximum number of scratch bytes reserved at any time, if limited.
+    pub fn scratch_quota(&self) -> Option<u64> {
+        self.scratch_quota
+    }
+
+    /// Number of scratch bytes currently reserved by running jobs.
+    pub fn scratch_used(&self) -> u64 {
+        self.scratch_used.load(Ordering::SeqCst)
+    }
+}
+
+lazy_static! {
+    static ref TENANTS: RwLock<HashMap<String, Arc<Tenant>>> = Default::default();
+    static ref GLOBAL_SETTINGS: Arc<Settings> = Arc::new(SETTINGS.clone());
+}
+
+thread_local! {
+    static CURRENT_TENANT: RefCell<Option<Arc<Tenant>>> = RefCell::new(None);
+}
+
+/// Registers a tenant with its own settings and an optional scratch quota in bytes.
+pub fn register_tenant(name: &str, settings: Settings, scratch_quota: Option<u64>) -> Result<()> {
+    ensure!(!name.is_empty(), "tenant name must not be empty");
+
+    let mut tenants = TENANTS.write().expect("tenants poisoned");
+    ensure!(
+        !tenants.contains_key(name),
+        "tenant {} is already registered",
+        name
+    );
+    info!("registering tenant {}", name);
+    tenants.insert(
+        name.to_string(),
+        Arc::new(Tenant {
+            name: name.to_string(),
+            settings: Arc::new(settings),
+            scratch_quota,
+            scratch_used: AtomicU64::new(0),
+        }),
+    );
+
+    Ok(())
+}
+
+/// Removes a tenant. Work already running for it is not affected. Returns false if there was no
+/// such tenant.
+pub fn remove_tenant(name: &str) -> bool {
+    TENANTS
+        .write()
+        .expect("tenants poisoned")
+        .remove(name)
+        .is_some()
+}
+
+/// Returns the registered tenant called `name`.
+pub fn tenant(name: &str) -> Option<Arc<Tenant>> {
+    TENANTS.read().expect("tenants poisoned").get(name).cloned()
+}
+
+/// Runs `f` on behalf of the tenant called `name`, which must be registered.
+pub fn with_tenant<T, F: FnOnce() -> T>(name: &str, f: F) -> Result<T> {
+    let tenant = match tenant(name) {
+        Some(tenant) => tenant,
+        None => bail!("unknown tenant {}", name),
+    };
+
+    Ok(with_local(&CURRENT_TENANT, Some(tenant), f))
+}
+
+/// Runs `f` on behalf of `tenant`, or outside of a tenant if it is `None`, e.g. to carry the
+/// [`current_tenant`] of a thread over to another one.
+pub fn with_current_tenant<T, F: FnOnce() -> T>(tenant: Option<Arc<Tenant>>, f: F) -> T {
+    with_local(&CURRENT_TENANT, tenant, f)
+}
+
+/// Runs `f` with `settings` in place of those of the current tenant, e.g. to write parameters
+/// into another cache directory. `f` keeps the name of the current tenant, but runs outside of its
+/// scratch quota.
+pub fn with_settings<T, F: FnOnce() -> T>(settings: Settings, f: F) -> T {
+    let name = current_tenant()
+        .map(|tenant| tenant.name.clone())
+        .unwrap_or_default();
+    let tenant = Arc::new(Tenant {
+        name,
+        settings: Arc::new(settings),
+        scratch_quota: None,
+        scratch_used: AtomicU64::new(0),
+    });
+
+    with_current_tenant(Some(tenant), f)
+}
+
+/// Returns the tenant the current thread works for, if any.
+pub fn current_tenant() -> Option<Arc<Tenant>> {
+    CURRENT_TENANT.with(|current| current.borrow().clone())
+}
+
+/// Returns the settings of the current tenant, or the global settings outside of a tenant.
+pub fn settings() -> Arc<Settings> {
+    match current_tenant() {
+        Some(tenant) => tenant.settings.clone(),
+        None => GLOBAL_SETTINGS.clone(),
+    }
+}
+
+/// Prefixes `key` with the current tenant, for in-memory caches which must not be shared.
+pub fn scoped_key(key: String) -> String {
+    match current_tenant() {
+        Some(tenant) => format!("{}/{}", tenant.name, key),
+        None => key,
+    }
+}
+
+/// Scratch space reserved with [`reserve_scratch`], released when dropped.
+#[derive(Debug)]
+pub struct ScratchReservation {
+    tenant: Option<Arc<Tenant>>,
+    bytes: u64,
+}
+
+impl Drop for ScratchReservation {
+    fn drop(&mut self) {
+        if let Some(tenant) = &self.tenant {
+            tenant.scratch_used.fetch_sub(self.bytes, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Reserves `bytes` of scratch space for the current tenant, failing with
+/// [`Error::ScratchQuotaExceeded`] if that would exceed its quota. Outside of a tenant, or for
+/// tenants without a quota, this always succeeds.
+pub fn reserve_scratch(bytes: u64) -> Result<ScratchReservation> {
+    let tenant = match current_tenant() {
+        Some(tenant) => tenant,
+        None => {
+            return Ok(ScratchReservation {
+                tenant: None,
+                bytes,
+            })
+        }
+    };
+
+    let quota = tenant.scratch_quota.unwrap_or(u64::MAX);
+    let reserved = tenant
+        .scratch_used
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+            used.checked_add(bytes).filter(|total| *total <= quota)
+        });
+    if reserved.is_err() {
+        return Err(Error::ScratchQuotaExceeded(tenant.name.clone(), bytes).into());
+    }
+
+    Ok(ScratchReservation {
+        tenant: Some(tenant),
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_settings_and_quota() {
+        let tenant_settings = Settings {
+            parameter_cache: "/tmp/tenant-a-params/".to_string(),
+            ..SETTINGS.clone()
+        };
+        register_tenant("test-tenant-a", tenant_settings, Some(100))
+            .expect("register_tenant failed");
+        assert!(register_tenant("test-tenant-a", SETTINGS.clone(), None).is_err());
+        assert!(with_tenant("test-tenant-unknown", || ()).is_err());
+
+        assert_eq!(settings().parameter_cache, SETTINGS.parameter_cache);
+        with_tenant("test-tenant-a", || {
+            assert_eq!(settings().parameter_cache, "/tmp/tenant-a-params/");
+            assert_eq!(scoped_key("key".to_string()), "test-tenant-a/key");
+
+            let first = reserve_scratch(60).expect("reserve_scratch failed");
+            let err = reserve_scratch(60).expect_err("quota was ignored");
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::ScratchQuotaExceeded(_, 60))
+            ));
+            drop(first);
+            let _second = reserve_scratch(60).expect("reserve_scratch failed");
+            assert_eq!(current_tenant().expect("no tenant").scratch_used(), 60);
+        })
+        .expect("with_tenant failed");
+
+        assert!(current_tenant().is_none());
+        assert_eq!(scoped_key("key".to_string()), "key");
+        assert_eq!(
+            tenant("test-tenant-a")
+                .expect("tenant missing")
+                .scratch_used(),
+            0
+        );
+        assert!(remove_tenant("test-tenant-a"));
+        assert!(!remove_tenant("test-tenant-a"));
+    }
+}
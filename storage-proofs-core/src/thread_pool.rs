@@ -0,0 +1,95 @@
+//! Running the parallel work of a call on a thread pool of its own.
+//!
+//! The parallel iterators of the proofs run on the rayon pool of the calling thread, which is the
+//! global pool of the process unless the call runs within [`with_thread_pool`]. Giving each stage
+//! of a pipeline (e.g. PC1, PC2, C2 and the PoSts) its own pool bounds the number of threads its
+//! parallel work runs on, so that concurrent calls don't take the cores of each other.
+//!
+//! Only the work of rayon is bounded. The threads of the multicore SDR, the threads feeding the
+//! GPU tree builders and the worker pool of bellperson, on which the SNARK proofs of C2 and the
+//! PoSts synthesize and prove their circuits, are not: a stage proving SNARKs is bounded for its
+//! vanilla proofs only.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::error::Result;
+use crate::tenant::{current_tenant, with_current_tenant};
+
+/// Builds a thread pool of `num_threads` threads to run calls on with [`with_thread_pool`]. The
+/// threads are named after `name`.
+pub fn build_thread_pool(name: &str, num_threads: usize) -> Result<Arc<ThreadPool>> {
+    let name = name.to_string();
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(move |index| format!("{}-{}", name, index))
+        .build()
+        .with_context(|| format!("failed to build a thread pool of {} threads", num_threads))?;
+
+    Ok(Arc::new(pool))
+}
+
+/// Runs `f` on `pool`, so that all parallel work of `f` runs on the threads of `pool`. `f` runs on
+/// one of those threads, on behalf of the tenant of the calling thread. Other thread local
+/// overrides, such as `with_tree_builder_backend`, have to be entered within `f`.
+pub fn with_thread_pool<T: Send, F: FnOnce() -> T + Send>(pool: &ThreadPool, f: F) -> T {
+    let tenant = current_tenant();
+    pool.install(move || with_current_tenant(tenant, f))
+}
+
+/// Runs `f` on a thread pool of `num_threads` threads built for the call, see
+/// [`with_thread_pool`].
+pub fn with_thread_pool_size<T: Send, F: FnOnce() -> T + Send>(
+    num_threads: usize,
+    f: F,
+) -> Result<T> {
+    let pool = build_thread_pool("fil-proofs", num_threads)?;
+
+    Ok(with_thread_pool(&pool, f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+    use crate::settings::SETTINGS;
+    use crate::tenant::{register_tenant, with_tenant};
+
+    #[test]
+    fn test_with_thread_pool() {
+        let pool = build_thread_pool("test-pool", 2).expect("build_thread_pool failed");
+        let thread_names: Vec<String> = with_thread_pool(&pool, || {
+            assert_eq!(rayon::current_num_threads(), 2);
+            (0..64)
+                .into_par_iter()
+                .map(|_| {
+                    std::thread::current()
+                        .name()
+                        .expect("unnamed thread")
+                        .to_string()
+                })
+                .collect()
+        });
+        assert!(thread_names
+            .iter()
+            .all(|name| name.starts_with("test-pool-")));
+
+        register_tenant("test-tenant-pool", SETTINGS.clone(), None)
+            .expect("register_tenant failed");
+        let tenant = with_tenant("test-tenant-pool", || {
+            with_thread_pool(&pool, || {
+                current_tenant().map(|tenant| tenant.name().to_string())
+            })
+        })
+        .expect("with_tenant failed");
+        assert_eq!(tenant.as_deref(), Some("test-tenant-pool"));
+
+        let num_threads = with_thread_pool_size(3, rayon::current_num_threads)
+            .expect("with_thread_pool_size failed");
+        assert_eq!(num_threads, 3);
+    }
+}
@@ -6,9 +6,9 @@ use bellperson::{
     ConstraintSystem, SynthesisError,
 };
 use ff::PrimeField;
-use merkletree::merkle::get_merkle_tree_row_count;
+use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_row_count};
 
-use crate::{error::Error, settings::SETTINGS};
+use crate::{error::Error, tenant};
 
 pub const NODE_SIZE: usize = 32;
 
@@ -166,7 +166,7 @@ pub fn default_rows_to_discard(leafs: usize, arity: usize) -> usize {
 
     // This configurable setting is for a default oct-tree
     // rows_to_discard value, which defaults to 2.
-    let rows_to_discard = SETTINGS.rows_to_discard as usize;
+    let rows_to_discard = tenant::settings().rows_to_discard as usize;
 
     // Discard at most 'constant value' rows (coded below,
     // differing by arity) while respecting the max number that
@@ -178,6 +178,53 @@ pub fn default_rows_to_discard(leafs: usize, arity: usize) -> usize {
     }
 }
 
+/// Chooses 'rows_to_discard' for the `tree_count` base trees of `leafs` leafs of a sector, such
+/// that the cached rows of `sectors` sectors (the sectors proven in one PoSt partition) fit into
+/// `memory_budget` bytes. The smallest such value is used, as every discarded row has to be
+/// rebuilt from the replica when proving. If even discarding as many rows as possible doesn't fit,
+/// that maximum is used.
+pub fn auto_rows_to_discard(
+    leafs: usize,
+    arity: usize,
+    tree_count: usize,
+    sectors: usize,
+    memory_budget: u64,
+) -> anyhow::Result<usize> {
+    let row_count = get_merkle_tree_row_count(leafs, arity);
+    if row_count <= 2 {
+        return Ok(0);
+    }
+
+    let max_rows_to_discard = row_count - 2;
+    for rows_to_discard in 0..max_rows_to_discard {
+        let cached_bytes = (get_merkle_tree_cache_size(leafs, arity, rows_to_discard)?
+            * NODE_SIZE
+            * tree_count
+            * sectors) as u64;
+        if cached_bytes <= memory_budget {
+            return Ok(rows_to_discard);
+        }
+    }
+
+    Ok(max_rows_to_discard)
+}
+
+/// Returns the physical memory of this machine in bytes, if it can be determined.
+#[cfg(target_os = "linux")]
+pub fn total_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kib * 1024)
+}
+
+/// Returns the physical memory of this machine in bytes, if it can be determined.
+#[cfg(not(target_os = "linux"))]
+pub fn total_memory() -> Option<u64> {
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -193,6 +240,39 @@ mod tests {
 
     use crate::TEST_SEED;
 
+    #[test]
+    fn test_auto_rows_to_discard() {
+        // 2^24 leafs, an arity of 8 gives 8 base tree rows above the leafs.
+        let leafs = 1 << 24;
+        let cache_size = |rows| {
+            (get_merkle_tree_cache_size(leafs, 8, rows).expect("cache size failure") * NODE_SIZE)
+                as u64
+        };
+
+        assert_eq!(
+            auto_rows_to_discard(leafs, 8, 1, 1, u64::MAX).expect("auto failure"),
+            0
+        );
+        // Two sectors need twice the memory of one.
+        let budget = 2 * cache_size(3);
+        assert_eq!(
+            auto_rows_to_discard(leafs, 8, 1, 2, budget).expect("auto failure"),
+            3
+        );
+        assert_eq!(
+            auto_rows_to_discard(leafs, 8, 1, 2, budget - 1).expect("auto failure"),
+            4
+        );
+        assert_eq!(
+            auto_rows_to_discard(leafs, 8, 1, 1, 0).expect("auto failure"),
+            get_merkle_tree_row_count(leafs, 8) - 2
+        );
+        assert_eq!(
+            auto_rows_to_discard(8, 8, 1, 1, 0).expect("auto failure"),
+            0
+        );
+    }
+
     #[test]
     fn test_bytes_into_boolean_vec() {
         let mut cs = TestConstraintSystem::<Fr>::new();
@@ -0,0 +1,27 @@
+//! Binding of threads to cores, as the multicore SDR does, for schedulers pinning their own
+//! workers (e.g. the tree builders of PC2) consistently with the core groups of the crate.
+//!
+//! Cores are identified by their index among the cores reported by hwloc, or among the logical
+//! processors if hwloc reports no cores, which are the indexes used by core plans and
+//! `FIL_PROOFS_SKIP_CORES`.
+//!
+//! ```no_run
+//! use storage_proofs_porep::affinity::{bind_core, checkout_core_group, CoreIndex};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! // Pin the current thread to core 2, until `_cleanup` is dropped.
+//! let _cleanup = bind_core(CoreIndex::new(2)?)?;
+//!
+//! // Or use a core group, which no other sector is labeled on while it is checked out.
+//! if let Some(group) = checkout_core_group() {
+//!     let _cleanup = group.bind_unit(0);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::stacked::vanilla::cores::{
+    bind_core, checkout_core_group, checkout_core_group_near, checkout_core_group_priority,
+    checkout_core_group_timeout, core_group_count, topology_core_count, CheckedOutCoreGroup,
+    CheckoutPriority, Cleanup, CoreIndex, CoreLocality,
+};
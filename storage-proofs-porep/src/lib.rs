@@ -9,6 +9,8 @@ use filecoin_hashers::Hasher;
 use merkletree::store::StoreConfig;
 use storage_proofs_core::{error::Result, merkle::BinaryMerkleTree, proof::ProofScheme, Data};
 
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+pub mod affinity;
 pub mod drg;
 pub mod stacked;
 
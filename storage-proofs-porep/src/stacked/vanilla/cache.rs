@@ -1,31 +1,39 @@
-use std::collections::{BTreeMap, HashSet};
-use std::fs::{remove_file, File};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, remove_file, File};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, ensure, Context};
 use byteorder::{ByteOrder, LittleEndian};
 use filecoin_hashers::Hasher;
 use lazy_static::lazy_static;
-use log::{info, trace};
+use log::{debug, info, trace, warn};
 use mapr::{Mmap, MmapOptions};
 use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSliceMut};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use storage_proofs_core::{
+    control,
     drgraph::{Graph, BASE_DEGREE},
     error::Result,
+    events::{self, Event},
     parameter_cache::{with_exclusive_lock, LockedFile, ParameterSetMetadata, VERSION},
-    settings::SETTINGS,
+    tenant,
     util::NODE_SIZE,
 };
 
 use crate::stacked::vanilla::graph::{StackedGraph, DEGREE};
+#[cfg(target_os = "linux")]
+use crate::stacked::vanilla::shm;
 
 /// u32 = 4 bytes
 const NODE_BYTES: usize = 4;
 
+/// Format version of the header file written next to each parent cache.
+pub const PARENT_CACHE_HEADER_VERSION: u32 = 1;
+
 pub const PARENT_CACHE_DATA: &str = include_str!("../../../parent_cache.json");
 
 pub type ParentCacheDataMap = BTreeMap<String, ParentCacheData>;
@@ -40,6 +48,130 @@ lazy_static! {
     pub static ref PARENT_CACHE: ParentCacheDataMap =
         serde_json::from_str(PARENT_CACHE_DATA).expect("Invalid parent_cache.json");
     static ref PARENT_CACHE_ACCESS_LOCK: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    /// When the digest of each cache file was last verified (or the file generated).
+    static ref LAST_VERIFIED: Mutex<HashMap<PathBuf, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Describes the graph a parent cache file was generated for, and the digest of its contents.
+///
+/// The header is stored as json next to the cache file (`<cache stem>.header`). It allows a
+/// cache generated once to be checked and shared read-only across machines, e.g. through
+/// `FIL_PROOFS_PARENT_CACHE_SHARED`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ParentCacheHeader {
+    pub header_version: u32,
+    pub cache_version: usize,
+    pub hasher: String,
+    /// Hex encoded seed of the base (DRG) graph.
+    pub graph_seed: String,
+    pub feistel_keys: [u64; 4],
+    pub sector_size: u64,
+    pub degree: usize,
+    pub cache_entries: u32,
+    /// Hex encoded sha256 of the cache file.
+    pub digest: String,
+}
+
+impl ParentCacheHeader {
+    pub fn new<H, G>(graph: &StackedGraph<H, G>, cache_entries: u32, digest: String) -> Self
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        ParentCacheHeader {
+            header_version: PARENT_CACHE_HEADER_VERSION,
+            cache_version: VERSION,
+            hasher: H::name(),
+            graph_seed: hex::encode(graph.base_graph().seed()),
+            feistel_keys: graph.feistel_keys,
+            sector_size: (graph.size() * NODE_SIZE) as u64,
+            degree: DEGREE,
+            cache_entries,
+            digest,
+        }
+    }
+
+    /// The location of the header belonging to the cache file at `cache_path`.
+    pub fn path(cache_path: &Path) -> PathBuf {
+        cache_path.with_extension("header")
+    }
+
+    /// Reads the header belonging to the cache file at `cache_path`.
+    pub fn read(cache_path: &Path) -> Result<Self> {
+        let path = Self::path(cache_path);
+        let data = fs::read(&path)
+            .with_context(|| format!("could not read parent cache header {}", path.display()))?;
+        let header: Self = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid parent cache header {}", path.display()))?;
+        ensure!(
+            header.header_version == PARENT_CACHE_HEADER_VERSION,
+            "unsupported parent cache header version {} in {}",
+            header.header_version,
+            path.display()
+        );
+
+        Ok(header)
+    }
+
+    /// Writes the header belonging to the cache file at `cache_path`.
+    pub fn write(&self, cache_path: &Path) -> Result<()> {
+        let path = Self::path(cache_path);
+        let tmp_path = path.with_extension("header.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("could not write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("could not write {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Checks that the cache file at `path` matches its header, by length and by digest, and that
+/// the digest matches the manifest for production caches. Returns the verified header.
+pub fn verify_parent_cache(path: &Path) -> Result<ParentCacheHeader> {
+    let header = ParentCacheHeader::read(path)?;
+
+    let file = LockedFile::open_shared_read(path)
+        .with_context(|| format!("could not open path={}", path.display()))?;
+    let expected_len = header.cache_entries as u64 * (DEGREE * NODE_BYTES) as u64;
+    let actual_len = file.as_ref().metadata()?.len();
+    ensure!(
+        actual_len == expected_len,
+        "corrupted cache: {}, expected {}, got {} bytes",
+        path.display(),
+        expected_len,
+        actual_len
+    );
+
+    let data = unsafe {
+        MmapOptions::new()
+            .map(file.as_ref())
+            .with_context(|| format!("could not mmap path={}", path.display()))?
+    };
+    let digest = digest_hex(&data);
+    ensure!(
+        digest == header.digest,
+        "parent cache digest mismatch: {}, expected {}, got {}",
+        path.display(),
+        header.digest,
+        digest
+    );
+    if let Some(pcd) = get_parent_cache_data(path) {
+        ensure!(
+            pcd.digest == header.digest,
+            "parent cache {} does not match the manifest",
+            path.display()
+        );
+    }
+
+    Ok(header)
+}
+
+fn digest_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    let hash = hasher.finalize();
+    hash.iter().map(|x| format!("{:01$x}", x, 2)).collect()
 }
 
 // StackedGraph will hold two different (but related) `ParentCache`,
@@ -52,6 +184,8 @@ pub struct ParentCache {
     cache: CacheData,
     pub sector_size: usize,
     pub digest: String,
+    /// Whether the cache is read from a shared memory segment.
+    in_shm: bool,
 }
 
 #[derive(Debug)]
@@ -148,15 +282,117 @@ impl CacheData {
             offset,
         })
     }
+
+    /// Maps all `len` nodes of the shared memory segment at `path`.
+    #[cfg(target_os = "linux")]
+    fn open_shm(len: u32, path: &Path) -> Result<Self> {
+        let file = LockedFile::open_shared_read(path)
+            .with_context(|| format!("could not open path={}", path.display()))?;
+        let data = shm::map(file.as_ref(), len as usize * DEGREE * NODE_BYTES)?;
+
+        Ok(Self {
+            data,
+            file,
+            len,
+            offset: 0,
+        })
+    }
 }
 
 impl ParentCache {
     pub fn new<H, G>(len: u32, cache_entries: u32, graph: &StackedGraph<H, G>) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        Self::new_with(len, cache_entries, graph, false)
+    }
+
+    /// Like `new`, but always verifies the digest of an existing cache, as if the verify_cache
+    /// option was enabled.
+    pub fn new_verified<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+    ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        Self::new_with(len, cache_entries, graph, true)
+    }
+
+    fn new_with<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        force_verify: bool,
+    ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        let cache = Self::open_or_generate(len, cache_entries, graph, force_verify)?;
+        let shm_dir = tenant::settings().parent_cache_shm.clone();
+        if shm_dir.is_empty() {
+            return Ok(cache);
+        }
+
+        match cache.load_into_shm(Path::new(&shm_dir)) {
+            Ok(shm_cache) => Ok(shm_cache),
+            Err(err) => {
+                warn!(
+                    "parent cache: not using shared memory {}, falling back to {}: {:?}",
+                    shm_dir,
+                    cache.path.display(),
+                    err
+                );
+                events::emit(Event::Fallback {
+                    job: control::current_job(),
+                    operation: "parent_cache".to_string(),
+                    reason: format!("shared memory {}: {}", shm_dir, err),
+                });
+                Ok(cache)
+            }
+        }
+    }
+
+    fn open_or_generate<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        force_verify: bool,
+    ) -> Result<Self>
     where
         H: Hasher,
         G: Graph<H> + ParameterSetMetadata + Send + Sync,
     {
         let path = cache_path(cache_entries, graph);
+        let settings = tenant::settings();
+        if !settings.parent_cache_shared.is_empty() {
+            let shared_path = Path::new(&settings.parent_cache_shared)
+                .join(path.file_name().expect("cache_path file_name failure"));
+            if shared_path.exists() {
+                let verify = force_verify || verification_required(&shared_path);
+                match Self::open_shared_with(len, cache_entries, graph, &shared_path, verify) {
+                    Ok(cache) => return Ok(cache),
+                    Err(err) => {
+                        warn!(
+                            "parent cache: not using shared {}, falling back to {}: {:?}",
+                            shared_path.display(),
+                            path.display(),
+                            err
+                        );
+                        events::emit(Event::Fallback {
+                            job: control::current_job(),
+                            operation: "parent_cache".to_string(),
+                            reason: format!("shared {}: {}", shared_path.display(), err),
+                        });
+                    }
+                }
+            }
+        }
+
         let generation_key = path.display().to_string();
         let mut generated = PARENT_CACHE_ACCESS_LOCK
             .lock()
@@ -168,7 +404,8 @@ impl ParentCache {
             if generated.get(&generation_key).is_none() {
                 generated.insert(generation_key);
             }
-            Self::open(len, cache_entries, graph, &path)
+            let verify = force_verify || verification_required(&path);
+            Self::open_with(len, cache_entries, graph, &path, verify)
         } else {
             match Self::generate(len, cache_entries, graph, &path) {
                 Ok(c) => {
@@ -180,7 +417,8 @@ impl ParentCache {
                     match err.downcast::<io::Error>() {
                         Ok(error) if error.kind() == io::ErrorKind::AlreadyExists => {
                             // cache was written from another process, just read it
-                            Self::open(len, cache_entries, graph, &path)
+                            let verify = force_verify || verification_required(&path);
+                            Self::open_with(len, cache_entries, graph, &path, verify)
                         }
                         Ok(error) => Err(error.into()),
                         Err(error) => Err(error),
@@ -191,15 +429,44 @@ impl ParentCache {
     }
 
     /// Opens an existing cache from disk.  If the verify_cache option
-    /// is enabled, we rehash the data and compare with the persisted
-    /// hash file.  If the persisted hash file does not exist, we
-    /// re-generate the cache file, which will create it.
+    /// is enabled, or the periodic verification of the cache is due,
+    /// we rehash the data and compare with the manifest, or the header
+    /// for caches which are not in it.  If they don't match, we
+    /// re-generate the cache file.
     pub fn open<H, G>(
         len: u32,
         cache_entries: u32,
         graph: &StackedGraph<H, G>,
         path: &Path,
     ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        Self::open_with(len, cache_entries, graph, path, verification_required(path))
+    }
+
+    /// Like `open`, but always verifies the digest of the cache.
+    pub fn open_verified<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        path: &Path,
+    ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        Self::open_with(len, cache_entries, graph, path, true)
+    }
+
+    fn open_with<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        path: &Path,
+        verify_cache: bool,
+    ) -> Result<Self>
     where
         H: Hasher,
         G: Graph<H> + ParameterSetMetadata + Send + Sync,
@@ -207,27 +474,25 @@ impl ParentCache {
         // Check if current entry is part of the official parent cache manifest.  If not, we're
         // dealing with some kind of test sector.  If verify has been requested but it's not a
         // production entry in the manifest, we'll calculate the digest so that it can be returned,
-        // although we don't attempt to match it up to anything.  This is useful for the case of
-        // generating new additions to the parent cache manifest since a valid digest is required.
-        let (parent_cache_data, verify_cache, is_production, mut digest_hex) =
-            match get_parent_cache_data(path) {
-                None => {
-                    info!("[open] Parent cache data is not supported in production");
-
-                    (
-                        None,
-                        SETTINGS.verify_cache,
-                        false, // not production since not in manifest
-                        "".to_string(),
-                    )
-                }
-                Some(pcd) => (
-                    Some(pcd),
-                    SETTINGS.verify_cache,
-                    true, // is_production since it exists in the manifest
-                    pcd.digest.clone(),
-                ),
-            };
+        // and only match it up to the header written when the cache was generated, if any.  This
+        // is useful for the case of generating new additions to the parent cache manifest since a
+        // valid digest is required.
+        let (parent_cache_data, is_production, mut digest_hex) = match get_parent_cache_data(path) {
+            None => {
+                info!("[open] Parent cache data is not supported in production");
+
+                (
+                    None,
+                    false, // not production since not in manifest
+                    "".to_string(),
+                )
+            }
+            Some(pcd) => (
+                Some(pcd),
+                true, // is_production since it exists in the manifest
+                pcd.digest.clone(),
+            ),
+        };
 
         info!(
             "parent cache: opening {}, verify enabled: {}",
@@ -238,7 +503,6 @@ impl ParentCache {
         if verify_cache {
             // Always check all of the data for integrity checks, even
             // if we're only opening a portion of it.
-            let mut hasher = Sha256::new();
             info!("[open] parent cache: calculating consistency digest");
             let file = File::open(&path)?;
             let data = unsafe {
@@ -246,43 +510,62 @@ impl ParentCache {
                     .map(&file)
                     .with_context(|| format!("could not mmap path={}", path.display()))?
             };
-            hasher.update(&data);
+            digest_hex = self::digest_hex(&data);
             drop(data);
 
-            let hash = hasher.finalize();
-            digest_hex = hash.iter().map(|x| format!("{:01$x}", x, 2)).collect();
-
             info!(
                 "[open] parent cache: calculated consistency digest: {:?}",
                 digest_hex
             );
 
-            if is_production {
-                let parent_cache_data = parent_cache_data.expect("parent_cache_data failure");
-
+            // Caches which are not in the manifest are compared to their header, if they have one.
+            let expected_digest = match parent_cache_data {
+                Some(pcd) => Some(pcd.digest.clone()),
+                None => ParentCacheHeader::read(path)
+                    .ok()
+                    .map(|header| header.digest),
+            };
+            if let Some(expected_digest) = expected_digest {
                 trace!(
-                    "[{}] Comparing {:?} to {:?}",
+                    "[{}] Comparing {:?} to {:?} (production: {})",
                     graph.size() * NODE_SIZE,
                     digest_hex,
-                    parent_cache_data.digest
+                    expected_digest,
+                    is_production
                 );
 
-                if digest_hex == parent_cache_data.digest {
+                if digest_hex == expected_digest {
                     info!("[open] parent cache: cache is verified!");
                 } else {
-                    info!(
+                    warn!(
                         "[!!!] Parent cache digest mismatch detected.  Regenerating {}",
                         path.display()
                     );
+                    events::emit(Event::Fallback {
+                        job: control::current_job(),
+                        operation: "parent_cache".to_string(),
+                        reason: format!("digest mismatch, regenerating {}", path.display()),
+                    });
                     // delete invalid cache
                     remove_file(path)?;
-                    ensure!(
-                        Self::generate(len, graph.size() as u32, graph, path).is_ok(),
-                        "Failed to generate parent cache"
+                    return Self::generate(len, cache_entries, graph, path)
+                        .context("Failed to generate parent cache");
+                }
+            }
+            record_verified(path);
+
+            // Caches generated before headers were introduced get one, once verified, unless their
+            // directory is read-only, in which case the verified digest is all there is to check.
+            if !ParentCacheHeader::path(path).exists() {
+                let header = ParentCacheHeader::new(graph, cache_entries, digest_hex.clone());
+                if let Err(err) = header.write(path) {
+                    if !is_read_only(&err) {
+                        return Err(err);
+                    }
+                    debug!(
+                        "parent cache: not writing a header for read-only {}",
+                        path.display()
                     );
-
-                    // Note that if we wanted the user to manually terminate after repeated
-                    // generation attemps, we could recursively return Self::open(...) here.
                 }
             }
         }
@@ -293,6 +576,7 @@ impl ParentCache {
             num_cache_entries: cache_entries,
             sector_size: graph.size() * NODE_SIZE,
             digest: digest_hex,
+            in_shm: false,
         })
     }
 
@@ -340,10 +624,7 @@ impl ParentCache {
             data.flush().context("failed to flush parent cache")?;
 
             info!("[generate] parent cache: generating consistency digest");
-            let mut hasher = Sha256::new();
-            hasher.update(&data);
-            let hash = hasher.finalize();
-            digest_hex = hash.iter().map(|x| format!("{:01$x}", x, 2)).collect();
+            digest_hex = self::digest_hex(&data);
             info!(
                 "[generate] parent cache: generated consistency digest: {:?}",
                 digest_hex
@@ -366,9 +647,12 @@ impl ParentCache {
 
             drop(data);
 
+            ParentCacheHeader::new(graph, cache_entries, digest_hex.clone()).write(path)?;
+
             info!("parent cache: written to disk");
             Ok(())
         })?;
+        record_verified(path);
 
         Ok(ParentCache {
             cache: CacheData::open(0, len, path)?,
@@ -376,6 +660,65 @@ impl ParentCache {
             num_cache_entries: cache_entries,
             sector_size,
             digest: digest_hex,
+            in_shm: false,
+        })
+    }
+
+    /// Opens a cache generated elsewhere, e.g. on a read-only network share. The cache must have
+    /// a header matching the graph, and is fully verified if the verify_cache option is enabled.
+    /// Shared caches are never regenerated.
+    pub fn open_shared<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        path: &Path,
+    ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        Self::open_shared_with(len, cache_entries, graph, path, verification_required(path))
+    }
+
+    fn open_shared_with<H, G>(
+        len: u32,
+        cache_entries: u32,
+        graph: &StackedGraph<H, G>,
+        path: &Path,
+        verify_cache: bool,
+    ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Send + Sync,
+    {
+        info!("parent cache: opening shared {}", path.display());
+        let header = if verify_cache {
+            let header = verify_parent_cache(path)?;
+            record_verified(path);
+            header
+        } else {
+            ParentCacheHeader::read(path)?
+        };
+        ensure!(
+            header == ParentCacheHeader::new(graph, cache_entries, header.digest.clone()),
+            "parent cache header of {} does not match the graph",
+            path.display()
+        );
+        if let Some(pcd) = get_parent_cache_data(path) {
+            ensure!(
+                pcd.digest == header.digest,
+                "parent cache {} does not match the manifest",
+                path.display()
+            );
+        }
+
+        Ok(ParentCache {
+            cache: CacheData::open(0, len, path)?,
+            path: path.to_path_buf(),
+            num_cache_entries: cache_entries,
+            sector_size: header.sector_size as usize,
+            digest: header.digest,
+            in_shm: false,
         })
     }
 
@@ -406,10 +749,91 @@ impl ParentCache {
     pub fn reset(&mut self) -> Result<()> {
         self.cache.reset()
     }
+
+    /// Whether the cache is read from a shared memory segment in the parent_cache_shm directory,
+    /// which is mapped as a whole.
+    pub fn in_shm(&self) -> bool {
+        self.in_shm
+    }
+
+    /// Loads the cache into a segment of the shared memory directory `dir`, unless another
+    /// process did already, and maps all of it from there.
+    #[cfg(target_os = "linux")]
+    fn load_into_shm(&self, dir: &Path) -> Result<Self> {
+        // Caches which are neither verified nor in the manifest have the digest of their header.
+        let digest = if self.digest.is_empty() {
+            ParentCacheHeader::read(&self.path)?.digest
+        } else {
+            self.digest.clone()
+        };
+        let len = self.num_cache_entries as usize * DEGREE * NODE_BYTES;
+        let path = shm::load(&self.path, len, &digest, dir)?;
+
+        Ok(ParentCache {
+            cache: CacheData::open_shm(self.num_cache_entries, &path)?,
+            path,
+            num_cache_entries: self.num_cache_entries,
+            sector_size: self.sector_size,
+            digest,
+            in_shm: true,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn load_into_shm(&self, _dir: &Path) -> Result<Self> {
+        bail!("parent caches can only be loaded into shared memory on Linux")
+    }
+}
+
+/// Whether the cache file at `path` has to be verified when it is opened: always with the
+/// verify_cache option, otherwise if it wasn't verified by this process within the last
+/// parent_cache_verify_interval_secs.
+fn verification_required(path: &Path) -> bool {
+    let settings = tenant::settings();
+    settings.verify_cache
+        || verification_due(
+            path,
+            Duration::from_secs(settings.parent_cache_verify_interval_secs),
+        )
+}
+
+/// Whether the periodic verification of the cache at `path` is due, a zero `interval` disables
+/// it.
+fn verification_due(path: &Path, interval: Duration) -> bool {
+    if interval == Duration::ZERO {
+        return false;
+    }
+    LAST_VERIFIED
+        .lock()
+        .expect("LAST_VERIFIED poisoned")
+        .get(path)
+        .map(|verified| verified.elapsed() >= interval)
+        .unwrap_or(true)
+}
+
+/// Whether `err` failed to write into a directory which is read-only, or not writable by us.
+fn is_read_only(err: &anyhow::Error) -> bool {
+    let err = match err.root_cause().downcast_ref::<io::Error>() {
+        Some(err) => err,
+        None => return false,
+    };
+    #[cfg(unix)]
+    let read_only_fs = err.raw_os_error() == Some(libc::EROFS);
+    #[cfg(not(unix))]
+    let read_only_fs = false;
+
+    read_only_fs || err.kind() == io::ErrorKind::PermissionDenied
+}
+
+fn record_verified(path: &Path) {
+    LAST_VERIFIED
+        .lock()
+        .expect("LAST_VERIFIED poisoned")
+        .insert(path.to_path_buf(), Instant::now());
 }
 
 fn parent_cache_dir_name() -> String {
-    SETTINGS.parent_cache.clone()
+    tenant::settings().parent_cache.clone()
 }
 
 fn parent_cache_id(path: &Path) -> String {
@@ -491,6 +915,161 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_header_verify_and_open_shared() {
+        init_logger();
+        let nodes = 32u32;
+        let new_graph = |porep_id| {
+            StackedBucketGraph::<PoseidonHasher>::new_stacked(
+                nodes as usize,
+                BASE_DEGREE,
+                EXP_DEGREE,
+                porep_id,
+                ApiVersion::V1_1_0,
+            )
+            .expect("new_stacked failure")
+        };
+        let graph = new_graph([2u8; 32]);
+
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("shared.cache");
+        let generated =
+            ParentCache::generate(nodes, nodes, &graph, &path).expect("generate failure");
+
+        let header = verify_parent_cache(&path).expect("verify failure");
+        assert_eq!(
+            header,
+            ParentCacheHeader::new(&graph, nodes, generated.digest.clone())
+        );
+
+        let mut shared =
+            ParentCache::open_shared(nodes / 2, nodes, &graph, &path).expect("open_shared failure");
+        for node in 0..nodes {
+            let mut expected_parents = [0; DEGREE];
+            graph
+                .parents(node as usize, &mut expected_parents)
+                .expect("graph parents failure");
+            assert_eq!(
+                expected_parents,
+                shared.read(node).expect("shared cache read failure")
+            );
+        }
+
+        let other_graph = new_graph([3u8; 32]);
+        assert!(ParentCache::open_shared(nodes, nodes, &other_graph, &path).is_err());
+    }
+
+    #[test]
+    fn test_open_verified_regenerates_corrupted_cache() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        init_logger();
+        let nodes = 32u32;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new_stacked(
+            nodes as usize,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [4u8; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("new_stacked failure");
+
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("corrupted.cache");
+        let generated =
+            ParentCache::generate(nodes, nodes, &graph, &path).expect("generate failure");
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .expect("open failure");
+        file.seek(SeekFrom::Start(4)).expect("seek failure");
+        file.write_all(&[0xff; 4]).expect("write failure");
+        drop(file);
+        assert!(verify_parent_cache(&path).is_err());
+
+        let mut cache =
+            ParentCache::open_verified(nodes, nodes, &graph, &path).expect("open_verified failure");
+        assert_eq!(cache.digest, generated.digest);
+        verify_parent_cache(&path).expect("verify failure");
+        for node in 0..nodes {
+            let mut expected_parents = [0; DEGREE];
+            graph
+                .parents(node as usize, &mut expected_parents)
+                .expect("graph parents failure");
+            assert_eq!(
+                expected_parents,
+                cache.read(node).expect("cache read failure")
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_load_into_shm() {
+        init_logger();
+        let nodes = 32u32;
+        let graph = StackedBucketGraph::<PoseidonHasher>::new_stacked(
+            nodes as usize,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [5u8; 32],
+            ApiVersion::V1_1_0,
+        )
+        .expect("new_stacked failure");
+
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let shm_dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("shm.cache");
+        let generated =
+            ParentCache::generate(nodes / 4, nodes, &graph, &path).expect("generate failure");
+
+        let mut cache = generated
+            .load_into_shm(shm_dir.path())
+            .expect("load_into_shm failure");
+        assert!(cache.in_shm());
+        assert_eq!(cache.path, shm_dir.path().join("shm.cache"));
+        assert_eq!(cache.digest, generated.digest);
+        for node in 0..nodes {
+            let mut expected_parents = [0; DEGREE];
+            graph
+                .parents(node as usize, &mut expected_parents)
+                .expect("graph parents failure");
+            assert_eq!(
+                expected_parents,
+                cache.read(node).expect("cache read failure")
+            );
+        }
+        // The whole cache is mapped.
+        assert_eq!(cache.cache.len, nodes);
+    }
+
+    #[test]
+    fn test_verification_due() {
+        let path = Path::new("/nonexistent/verification-due.cache");
+        let interval = Duration::from_secs(3600);
+
+        assert!(!verification_due(path, Duration::ZERO));
+        assert!(verification_due(path, interval));
+        record_verified(path);
+        assert!(!verification_due(path, interval));
+        assert!(verification_due(path, Duration::from_nanos(1)));
+    }
+
+    #[test]
+    fn test_is_read_only() {
+        let denied = anyhow::Error::from(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("could not write header");
+        assert!(is_read_only(&denied));
+        #[cfg(unix)]
+        assert!(is_read_only(&anyhow::Error::from(
+            io::Error::from_raw_os_error(libc::EROFS)
+        )));
+        assert!(!is_read_only(&anyhow::Error::from(io::Error::from(
+            io::ErrorKind::Other
+        ))));
+    }
+
     #[test]
     #[cfg(feature = "isolated-testing")]
     fn test_parallel_generation_and_read_partial_range_v1_0() {
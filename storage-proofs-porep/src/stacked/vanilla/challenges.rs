@@ -1,20 +1,77 @@
+use std::fmt::{self, Debug, Formatter};
+
 use filecoin_hashers::Domain;
 use num_bigint::BigUint;
 use num_traits::cast::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The number of synthetic challenges of a sector, out of which the challenges of a synthetic
+/// PoRep are selected.
+pub const SYNTHETIC_CHALLENGE_COUNT: usize = 1 << 18;
+
+/// The domain separation tag of the challenges of a non-interactive PoRep.
+const NI_CHALLENGE_TAG: &[u8] = b"filecoin.io|PoRep|1|NonInteractive|1";
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct LayerChallenges {
     /// How many layers we are generating challenges for.
     layers: usize,
     /// The maximum count of challenges
     max_count: usize,
+    /// The number of synthetic challenges the challenges are selected from, for a synthetic PoRep.
+    #[serde(default)]
+    synth_count: Option<usize>,
+    /// Whether the challenges are those of a non-interactive PoRep.
+    #[serde(default)]
+    non_interactive: bool,
+}
+
+// The parameter identifier of the circuit includes the challenges. Synthetic challenges are proven
+// by the same circuit, so they are left out. Non-interactive challenges get circuits of their own.
+impl Debug for LayerChallenges {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("LayerChallenges");
+        debug
+            .field("layers", &self.layers)
+            .field("max_count", &self.max_count);
+        if self.non_interactive {
+            debug.field("non_interactive", &self.non_interactive);
+        }
+        debug.finish()
+    }
 }
 
 impl LayerChallenges {
     pub const fn new(layers: usize, max_count: usize) -> Self {
-        LayerChallenges { layers, max_count }
+        LayerChallenges {
+            layers,
+            max_count,
+            synth_count: None,
+            non_interactive: false,
+        }
+    }
+
+    /// Challenges of a synthetic PoRep, which are selected out of `synth_count` synthetic
+    /// challenges, see [`LayerChallenges::derive_synthetic`].
+    pub const fn new_synthetic(layers: usize, max_count: usize, synth_count: usize) -> Self {
+        LayerChallenges {
+            layers,
+            max_count,
+            synth_count: Some(synth_count),
+            non_interactive: false,
+        }
+    }
+
+    /// Challenges of a non-interactive PoRep, which don't depend on a seed, see
+    /// [`LayerChallenges::derive_non_interactive`].
+    pub const fn new_non_interactive(layers: usize, max_count: usize) -> Self {
+        LayerChallenges {
+            layers,
+            max_count,
+            synth_count: None,
+            non_interactive: true,
+        }
     }
 
     pub fn layers(&self) -> usize {
@@ -25,7 +82,17 @@ impl LayerChallenges {
         self.max_count
     }
 
-    /// Derive all challenges.
+    /// The number of synthetic challenges, if the challenges are those of a synthetic PoRep.
+    pub fn synth_count(&self) -> Option<usize> {
+        self.synth_count
+    }
+
+    /// Whether the challenges are those of a non-interactive PoRep.
+    pub fn is_non_interactive(&self) -> bool {
+        self.non_interactive
+    }
+
+    /// Derives the challenges of partition `k`, see [`LayerChallenges::derive_internal`].
     pub fn derive<D: Domain>(
         &self,
         leaves: usize,
@@ -36,6 +103,18 @@ impl LayerChallenges {
         self.derive_internal(self.challenges_count_all(), leaves, replica_id, seed, k)
     }
 
+    /// Derives `challenges_count` challenged nodes of partition `k`, out of `leaves` nodes.
+    ///
+    /// Challenge `i` is derived as
+    ///
+    /// ```text
+    /// j = challenges_count * k + i                          (u32, little endian)
+    /// h = SHA256(replica_id.into_bytes() || seed || j)
+    /// challenge = (h as little endian integer) mod (leaves - 1) + 1
+    /// ```
+    ///
+    /// so that node 0 is never challenged. Deriving the challenges of several partitions yields
+    /// the same challenges as deriving all of them at once for partition 0.
     pub fn derive_internal<D: Domain>(
         &self,
         challenges_count: usize,
@@ -48,26 +127,182 @@ impl LayerChallenges {
 
         (0..challenges_count)
             .map(|i| {
-                let j: u32 = ((challenges_count * k as usize) + i) as u32;
+                let hash = seed_hash(replica_id, seed, challenges_count, k, i);
+
+                // We cannot try to prove the first node, so make sure the challenge
+                // can never be 0.
+                reduce(&hash, leaves - 1) + 1
+            })
+            .collect()
+    }
+
+    /// Derives the challenges of partition `k` of a synthetic PoRep, which are the synthetic
+    /// challenges (see [`SynthChallenges`]) at the indexes of
+    /// [`LayerChallenges::derive_synth_indexes`].
+    pub fn derive_synthetic<D: Domain>(
+        &self,
+        leaves: usize,
+        replica_id: &D,
+        comm_r: &D,
+        seed: &[u8; 32],
+        k: u8,
+    ) -> Vec<usize> {
+        let synth = SynthChallenges::new(leaves, replica_id, comm_r, self.synth_count_or_panic());
+
+        self.derive_synth_indexes(replica_id, seed, k)
+            .into_iter()
+            .map(|index| synth.challenge(index))
+            .collect()
+    }
+
+    /// Derives the indexes of the synthetic challenges which are the challenges of partition `k`
+    /// of a synthetic PoRep. Index `i` is derived as
+    ///
+    /// ```text
+    /// j = challenges_count * k + i                          (u32, little endian)
+    /// h = SHA256(replica_id.into_bytes() || seed || j)
+    /// index = (h as little endian integer) mod synth_count
+    /// ```
+    pub fn derive_synth_indexes<D: Domain>(
+        &self,
+        replica_id: &D,
+        seed: &[u8; 32],
+        k: u8,
+    ) -> Vec<usize> {
+        let synth_count = self.synth_count_or_panic();
+        let challenges_count = self.challenges_count_all();
+
+        (0..challenges_count)
+            .map(|i| {
+                let hash = seed_hash(replica_id, seed, challenges_count, k, i);
+                reduce(&hash, synth_count)
+            })
+            .collect()
+    }
+
+    /// Derives the challenges of partition `k` of a non-interactive PoRep, out of `leaves` nodes.
+    /// They only depend on the replica id and `comm_r`, and challenge `i` is derived as
+    ///
+    /// ```text
+    /// j = challenges_count * k + i                          (u32, little endian)
+    /// h = SHA256(tag || replica_id.into_bytes() || comm_r.into_bytes() || j)
+    /// challenge = (h as little endian integer) mod (leaves - 1) + 1
+    /// ```
+    ///
+    /// with the tag `filecoin.io|PoRep|1|NonInteractive|1`.
+    pub fn derive_non_interactive<D: Domain>(
+        &self,
+        leaves: usize,
+        replica_id: &D,
+        comm_r: &D,
+        k: u8,
+    ) -> Vec<usize> {
+        assert!(leaves > 2, "Too few leaves: {}", leaves);
+        let challenges_count = self.challenges_count_all();
 
+        (0..challenges_count)
+            .map(|i| {
+                let j: u32 = ((challenges_count * k as usize) + i) as u32;
                 let hash = Sha256::new()
+                    .chain(NI_CHALLENGE_TAG)
                     .chain(replica_id.into_bytes())
-                    .chain(seed)
+                    .chain(comm_r.into_bytes())
                     .chain(&j.to_le_bytes())
                     .finalize();
 
-                let big_challenge = BigUint::from_bytes_le(hash.as_ref());
-
-                // We cannot try to prove the first node, so make sure the challenge
-                // can never be 0.
-                let big_mod_challenge = big_challenge % (leaves - 1);
-                let big_mod_challenge = big_mod_challenge
-                    .to_usize()
-                    .expect("`big_mod_challenge` exceeds size of `usize`");
-                big_mod_challenge + 1
+                reduce(hash.as_ref(), leaves - 1) + 1
             })
             .collect()
     }
+
+    fn synth_count_or_panic(&self) -> usize {
+        self.synth_count
+            .expect("the challenges are not those of a synthetic PoRep")
+    }
+}
+
+/// The synthetic challenges of a sector, which only depend on its replica id and `comm_r`. They
+/// are known, and can be proven, as soon as the sector is replicated, before the seed of its
+/// commit is.
+///
+/// Synthetic challenge `i` is derived as
+///
+/// ```text
+/// h = SHA256(replica_id.into_bytes() || comm_r.into_bytes() || i)      (i: u64, little endian)
+/// challenge = (h as little endian integer) mod (leaves - 1) + 1
+/// ```
+#[derive(Clone, Debug)]
+pub struct SynthChallenges {
+    leaves: usize,
+    /// The hasher with the replica id and `comm_r` already input.
+    prefix: Sha256,
+    count: usize,
+}
+
+impl SynthChallenges {
+    pub fn new<D: Domain>(leaves: usize, replica_id: &D, comm_r: &D, count: usize) -> Self {
+        assert!(leaves > 2, "Too few leaves: {}", leaves);
+        assert!(count > 0, "no synthetic challenges");
+
+        SynthChallenges {
+            leaves,
+            prefix: Sha256::new()
+                .chain(replica_id.into_bytes())
+                .chain(comm_r.into_bytes()),
+            count,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Synthetic challenge `index`, which is less than [`SynthChallenges::len`].
+    pub fn challenge(&self, index: usize) -> usize {
+        assert!(index < self.count, "invalid synthetic challenge {}", index);
+        let hash = self
+            .prefix
+            .clone()
+            .chain(&(index as u64).to_le_bytes())
+            .finalize();
+
+        reduce(hash.as_ref(), self.leaves - 1) + 1
+    }
+
+    /// All synthetic challenges, in the order of their indexes.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.count).map(move |index| self.challenge(index))
+    }
+}
+
+/// The hash of [`LayerChallenges::derive_internal`] for challenge `i` of partition `k`.
+fn seed_hash<D: Domain>(
+    replica_id: &D,
+    seed: &[u8; 32],
+    challenges_count: usize,
+    k: u8,
+    i: usize,
+) -> Vec<u8> {
+    let j: u32 = ((challenges_count * k as usize) + i) as u32;
+
+    Sha256::new()
+        .chain(replica_id.into_bytes())
+        .chain(seed)
+        .chain(&j.to_le_bytes())
+        .finalize()
+        .to_vec()
+}
+
+/// `hash` as a little endian integer, modulo `modulus`.
+fn reduce(hash: &[u8], modulus: usize) -> usize {
+    let big_mod = BigUint::from_bytes_le(hash) % modulus;
+    big_mod
+        .to_usize()
+        .expect("`big_mod_challenge` exceeds size of `usize`")
 }
 
 #[derive(Debug, Default)]
@@ -93,6 +328,23 @@ mod test {
         assert_eq!(expected as usize, calculated_count);
     }
 
+    #[test]
+    fn challenge_derivation_test_vectors() {
+        let challenges = LayerChallenges::new(2, 4);
+        let replica_id = Sha256Domain::from([1u8; 32]);
+        let seed = [2u8; 32];
+        let leaves = 1 << 20;
+
+        assert_eq!(
+            challenges.derive(leaves, &replica_id, &seed, 0),
+            vec![617095, 479923, 487168, 27147]
+        );
+        assert_eq!(
+            challenges.derive(leaves, &replica_id, &seed, 1),
+            vec![575428, 460598, 951334, 445503]
+        );
+    }
+
     #[test]
     fn challenge_derivation() {
         let n = 200;
@@ -160,4 +412,81 @@ mod test {
             assert_eq!(one_partition_challenges, many_partition_challenges);
         }
     }
+
+    #[test]
+    fn synthetic_challenge_derivation() {
+        let n = 20;
+        let synth_count = 64;
+        let leaves = 1 << 10;
+        let rng = &mut thread_rng();
+        let replica_id: Sha256Domain = Sha256Domain::random(rng);
+        let comm_r: Sha256Domain = Sha256Domain::random(rng);
+        let seed: [u8; 32] = rng.gen();
+
+        let challenges = LayerChallenges::new_synthetic(2, n, synth_count);
+        let synth = SynthChallenges::new(leaves, &replica_id, &comm_r, synth_count);
+        assert_eq!(synth.len(), synth_count);
+        assert!(synth
+            .iter()
+            .all(|challenge| challenge > 0 && challenge < leaves));
+
+        for k in 0..3 {
+            let indexes = challenges.derive_synth_indexes(&replica_id, &seed, k);
+            assert_eq!(indexes.len(), n);
+            assert!(indexes.iter().all(|index| *index < synth_count));
+
+            let expected: Vec<usize> = indexes.iter().map(|i| synth.challenge(*i)).collect();
+            assert_eq!(
+                challenges.derive_synthetic(leaves, &replica_id, &comm_r, &seed, k),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn synthetic_challenges_keep_identifier() {
+        assert_eq!(
+            format!("{:?}", LayerChallenges::new_synthetic(2, 4, 64)),
+            format!("{:?}", LayerChallenges::new(2, 4))
+        );
+    }
+
+    #[test]
+    fn non_interactive_challenge_derivation() {
+        let n = 20;
+        let leaves = 1 << 10;
+        let rng = &mut thread_rng();
+        let replica_id: Sha256Domain = Sha256Domain::random(rng);
+        let comm_r: Sha256Domain = Sha256Domain::random(rng);
+
+        let challenges = LayerChallenges::new_non_interactive(2, n);
+        let one_partition = LayerChallenges::new_non_interactive(2, 3 * n).derive_non_interactive(
+            leaves,
+            &replica_id,
+            &comm_r,
+            0,
+        );
+        let partitions = (0..3)
+            .flat_map(|k| challenges.derive_non_interactive(leaves, &replica_id, &comm_r, k))
+            .collect::<Vec<_>>();
+        assert_eq!(one_partition, partitions);
+        assert!(partitions
+            .iter()
+            .all(|challenge| *challenge > 0 && *challenge < leaves));
+
+        // Unlike the interactive challenges, they depend on comm_r.
+        let other_comm_r: Sha256Domain = Sha256Domain::random(rng);
+        assert_ne!(
+            challenges.derive_non_interactive(leaves, &replica_id, &other_comm_r, 0),
+            partitions[..n]
+        );
+    }
+
+    #[test]
+    fn non_interactive_challenges_change_identifier() {
+        assert_ne!(
+            format!("{:?}", LayerChallenges::new_non_interactive(2, 4)),
+            format!("{:?}", LayerChallenges::new(2, 4))
+        );
+    }
 }
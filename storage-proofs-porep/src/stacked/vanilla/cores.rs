@@ -1,22 +1,492 @@
-use std::sync::{Mutex, MutexGuard};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "windows")]
+use std::ptr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Condvar, Mutex, MutexGuard, RwLock,
+};
+use std::time::{Duration, Instant};
 
-use anyhow::{format_err, Result};
-use hwloc::{Bitmap, ObjectType, Topology, TopologyObject, CPUBIND_THREAD};
+use anyhow::{ensure, format_err, Context, Result};
+use fs2::FileExt;
+#[cfg(feature = "multicore-sdr")]
+use hwloc::{
+    Bitmap, ObjectType, Topology, TopologyObject, CPUBIND_PROCESS, CPUBIND_THREAD, MEMBIND_BIND,
+    MEMBIND_DEFAULT, MEMBIND_THREAD,
+};
 use lazy_static::lazy_static;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::from_str;
-use storage_proofs_core::settings::SETTINGS;
+use storage_proofs_core::settings::{Settings, SETTINGS};
+
+#[cfg(not(feature = "multicore-sdr"))]
+use crate::stacked::vanilla::os_topology::{
+    Bitmap, ObjectType, Topology, TopologyObject, CPUBIND_PROCESS, CPUBIND_THREAD, MEMBIND_BIND,
+    MEMBIND_DEFAULT, MEMBIND_THREAD,
+};
 
 type CoreGroup = Vec<CoreIndex>;
 lazy_static! {
     pub static ref TOPOLOGY: Mutex<Topology> = Mutex::new(Topology::new());
-    pub static ref CORE_GROUPS: Option<Vec<Mutex<CoreGroup>>> = {
-        // let num_producers = &SETTINGS.multicore_sdr_producers;
-        // let cores_per_unit = num_producers + 1;
-        let core_plan = SETTINGS.multicore_sdr_core_plan.clone();
+    static ref CORE_GROUPS: RwLock<Option<&'static [CoreGroupSlot]>> =
+        RwLock::new(build_core_groups(&SETTINGS).map(leak_core_groups));
+    static ref CHECKOUT_QUEUE: Mutex<CheckoutQueue> = Mutex::new(CheckoutQueue::default());
+    static ref CORE_GROUP_RELEASED: Condvar = Condvar::new();
+    static ref REGISTERED_CORE_PLANS: RwLock<HashMap<String, Vec<CoreGroup>>> = Default::default();
+}
+
+/// How often waiting callers check for core groups released by other processes.
+const CROSS_PROCESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The tickets of the callers waiting for a core group with their priority, in arrival order.
+/// Only the next caller in line may check out a group.
+#[derive(Default)]
+struct CheckoutQueue {
+    next_ticket: u64,
+    waiting: VecDeque<(u64, CheckoutPriority)>,
+    failed_checkouts: u64,
+    wait_time: Duration,
+}
+
+impl CheckoutQueue {
+    /// The ticket of the caller with the highest priority which has waited the longest.
+    fn next_in_line(&self) -> Option<u64> {
+        self.waiting
+            .iter()
+            .max_by(|(ticket_a, priority_a), (ticket_b, priority_b)| {
+                priority_a
+                    .cmp(priority_b)
+                    .then_with(|| ticket_b.cmp(ticket_a))
+            })
+            .map(|(ticket, _)| *ticket)
+    }
+}
+
+/// The priority class of a core group checkout. Waiting callers of a higher class are served
+/// first, and only PC1 labeling may check out the groups reserved by
+/// `FIL_PROOFS_MULTICORE_SDR_PC1_RESERVED_GROUPS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckoutPriority {
+    Misc,
+    /// Threads feeding the GPU while building the trees of PC2.
+    Pc2Feeder,
+    /// The labeling of PC1.
+    Pc1,
+}
+
+/// The core groups future checkouts are served from.
+fn current_core_groups() -> Option<&'static [CoreGroupSlot]> {
+    *CORE_GROUPS.read().expect("poisoned lock")
+}
+
+// Checked out groups borrow their slot for as long as they are held, which may outlive a reload,
+// so replaced groups are never freed.
+fn leak_core_groups(groups: Vec<CoreGroupSlot>) -> &'static [CoreGroupSlot] {
+    Box::leak(groups.into_boxed_slice())
+}
+
+/// Rebuilds the core groups from the core plan settings of `settings` (core plan, core plan
+/// file, skipped cores and producers) and swaps them in, without restarting the process.
+/// Groups which are checked out stay bound until they are returned, only later checkouts use the
+/// new groups. Returns the number of new groups, 0 if no core plan could be built, in which case
+/// no cores are bound from now on.
+///
+/// The replaced groups are kept in memory, so this is meant for occasional retuning.
+pub fn reload_core_plan(settings: &Settings) -> usize {
+    let groups = build_core_groups(settings).map(leak_core_groups);
+    let count = groups.map(<[CoreGroupSlot]>::len).unwrap_or(0);
+    info!("reloaded the core plan, {} core groups", count);
+    *CORE_GROUPS.write().expect("poisoned lock") = groups;
+
+    // Waiting callers retry with the new groups.
+    let _queue = CHECKOUT_QUEUE.lock().expect("poisoned lock");
+    CORE_GROUP_RELEASED.notify_all();
+
+    count
+}
+
+/// Builds the core groups from the settings: from the core plan file, the named core plan or the
+/// topology, without the skipped cores and the cores the process is not allowed to run on.
+fn build_core_groups(settings: &Settings) -> Option<Vec<CoreGroupSlot>> {
+    let cores_per_unit = settings.multicore_sdr_producers + 1;
+    let mut skip_cores = parse_skip_cores(&settings.skip_cores);
+    skip_cores.extend(parse_skip_cores(&settings.multicore_sdr_skip_cores));
+    let core_plan = settings.multicore_sdr_core_plan.clone();
+    let core_plan_file = settings.multicore_sdr_core_plan_file.clone();
+    let grouping = CoreGrouping::parse(&settings.multicore_sdr_core_grouping);
+    let lock_dir = core_lock_dir(&settings.multicore_sdr_core_lock_dir);
+
+    let allowed = allowed_cores(&TOPOLOGY.lock().expect("poisoned lock"));
+    if let Some(allowed) = &allowed {
+        let disallowed = allowed
+            .iter()
+            .enumerate()
+            .filter(|(_, allowed)| !**allowed)
+            .map(|(core, _)| core)
+            .collect::<Vec<_>>();
+        if !disallowed.is_empty() {
+            info!("cores {:?} are outside of the allowed cpuset", disallowed);
+            skip_cores.extend(disallowed);
+        }
+    }
+
+    let plan = core_groups(
+        core_plan,
+        &core_plan_file,
+        grouping,
+        cores_per_unit,
+        &skip_cores,
+    )?;
+    let mut groups = remove_skipped_cores(plan.groups, &skip_cores);
+    if let Some(allowed) = &allowed {
+        groups = remove_unknown_cores(groups, allowed.len());
+    }
+    if groups.is_empty() {
+        warn!("no cores left for the multicore SDR, not binding cores");
+        return None;
+    }
+
+    let topo = TOPOLOGY.lock().expect("poisoned lock");
+    Some(
+        groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, cores)| {
+                let numa_node = numa_node_of_cores(&topo, &cores);
+                debug!("core group {} is on NUMA node {:?}", i, numa_node);
+                let units = match grouping {
+                    CoreGrouping::SharedCache => None,
+                    CoreGrouping::SmtSiblings => Some(logical_processors(&topo, &cores)),
+                };
+                let lock_files = lock_dir
+                    .as_ref()
+                    .map(|dir| core_lock_files(dir, &cores))
+                    .unwrap_or_default();
+                CoreGroupSlot {
+                    core_indexes: cores.iter().map(|core| core.0).collect(),
+                    lock_files,
+                    cores: Mutex::new(cores),
+                    numa_node,
+                    units,
+                    producers: plan.producers,
+                    reserved: i < settings.multicore_sdr_pc1_reserved_groups,
+                    stats: SlotStats::default(),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Whether the process may run on each core. Under a cgroup cpuset (e.g. in a container) or a
+/// CPU affinity mask, hwloc still reports all cores, but binding to the others fails.
+fn allowed_cores(topo: &Topology) -> Option<Vec<bool>> {
+    let allowed = topo.get_cpubind(CPUBIND_PROCESS)?;
+    let cores = core_objects(topo);
+    if cores.is_empty() {
+        return None;
+    }
+
+    Some(
+        cores
+            .iter()
+            .map(|core| {
+                core.allowed_cpuset()
+                    .map(|set| set.into_iter().any(|pu| allowed.is_set(pu)))
+                    .unwrap_or(false)
+            })
+            .collect(),
+    )
+}
+
+/// Removes the cores with an index not below `core_count` from all groups, dropping the groups
+/// left empty.
+fn remove_unknown_cores(groups: Vec<CoreGroup>, core_count: usize) -> Vec<CoreGroup> {
+    let unknown: Vec<usize> = groups
+        .iter()
+        .flatten()
+        .map(|core| core.0)
+        .filter(|core| *core >= core_count)
+        .collect();
+    if unknown.is_empty() {
+        return groups;
+    }
+
+    warn!(
+        "ignoring cores {:?} of the core plan, only {} cores are available",
+        unknown, core_count
+    );
+    remove_skipped_cores(groups, &unknown)
+}
 
-        core_groups(core_plan)
+/// A core group which can be checked out, with the NUMA node local to its cores.
+pub struct CoreGroupSlot {
+    cores: Mutex<CoreGroup>,
+    core_indexes: Vec<usize>,
+    numa_node: Option<u32>,
+    // The logical processors threads are bound to when grouping by SMT siblings, as the core and
+    // the position of the processor within the core.
+    units: Option<Vec<(CoreIndex, usize)>>,
+    // The files locked while the group is checked out, one per core, shared with other processes.
+    lock_files: Vec<PathBuf>,
+    // The number of producers set by the core plan.
+    producers: Option<usize>,
+    // Only PC1 labeling may check out the group.
+    reserved: bool,
+    stats: SlotStats,
+}
+
+/// The directory of the core lock files, created if needed. `None` if the cores aren't locked
+/// across processes.
+fn core_lock_dir(dir: &str) -> Option<PathBuf> {
+    if dir.is_empty() {
+        return None;
+    }
+    match fs::create_dir_all(dir) {
+        Ok(()) => Some(PathBuf::from(dir)),
+        Err(err) => {
+            warn!(
+                "not locking cores across processes, can't create {}: {}",
+                dir, err
+            );
+            None
+        }
+    }
+}
+
+fn core_lock_files(dir: &Path, cores: &[CoreIndex]) -> Vec<PathBuf> {
+    cores
+        .iter()
+        .map(|core| dir.join(format!("core-{}.lock", core.0)))
+        .collect()
+}
+
+/// Takes an exclusive lock on all `files`, without waiting. Nothing stays locked on error.
+fn lock_core_files(files: &[PathBuf]) -> io::Result<Vec<File>> {
+    files
+        .iter()
+        .map(|path| {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)?;
+            file.try_lock_exclusive()?;
+            Ok(file)
+        })
+        .collect()
+}
+
+/// How the threads of a sector are placed on the cores of its group, from
+/// `FIL_PROOFS_MULTICORE_SDR_CORE_GROUPING`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CoreGrouping {
+    /// Every thread gets a physical core of the group, cores sharing a cache form a group.
+    SharedCache,
+    /// The producers run on the SMT siblings of the consumer, every core forms a group.
+    SmtSiblings,
+}
+
+impl CoreGrouping {
+    fn parse(grouping: &str) -> Self {
+        match grouping {
+            "" | "cache" => CoreGrouping::SharedCache,
+            "smt" => CoreGrouping::SmtSiblings,
+            _ => {
+                warn!("unknown core grouping {}, grouping by cache", grouping);
+                CoreGrouping::SharedCache
+            }
+        }
+    }
+}
+
+/// The logical processors of `cores`, in the order of the cores.
+fn logical_processors(topo: &Topology, cores: &[CoreIndex]) -> Vec<(CoreIndex, usize)> {
+    cores
+        .iter()
+        .flat_map(|core| {
+            let count = get_core_by_index(topo, *core)
+                .ok()
+                .and_then(TopologyObject::allowed_cpuset)
+                .map(|set| set.into_iter().count())
+                .unwrap_or(1);
+            (0..count.max(1)).map(move |sibling| (*core, sibling))
+        })
+        .collect()
+}
+
+/// The logical processor `unit` is bound to out of `count`: the consumer (unit 0) gets the first
+/// one, the producers take turns on the others. `None` if there are no others for the producers.
+fn smt_unit(unit: usize, count: usize) -> Option<usize> {
+    match (unit, count) {
+        (_, 0) => None,
+        (0, _) => Some(0),
+        (_, 1) => None,
+        _ => Some(1 + (unit - 1) % (count - 1)),
+    }
+}
+
+/// The number of producers which fit a group of `cores` cores besides the consumer, or
+/// `producers` if the group has no core to spare, in which case they are not bound.
+fn group_producers(producers: usize, cores: usize) -> usize {
+    if cores < 2 {
+        producers
+    } else {
+        cores - 1
+    }
+}
+
+#[derive(Default)]
+struct SlotStats {
+    checkouts: AtomicU64,
+    contended: AtomicU64,
+    held_nanos: AtomicU64,
+    checked_out: AtomicBool,
+}
+
+/// Utilization of a core group since the start of the process.
+#[derive(Clone, Debug, Serialize)]
+pub struct CoreGroupStats {
+    pub cores: Vec<usize>,
+    pub numa_node: Option<u32>,
+    /// Number of times the group was checked out.
+    pub checkouts: u64,
+    /// Number of times the group was busy when a checkout tried it.
+    pub contended: u64,
+    /// Total time the group was checked out, not counting a current checkout.
+    pub hold_time: Duration,
+    pub checked_out: bool,
+}
+
+/// Utilization of all core groups, see [`core_stats`].
+#[derive(Clone, Debug, Serialize)]
+pub struct CoreStats {
+    pub groups: Vec<CoreGroupStats>,
+    /// Number of checkouts which found no free group, immediately or within their timeout.
+    pub failed_checkouts: u64,
+    /// Total time the successful checkouts waited for a group.
+    pub wait_time: Duration,
+}
+
+/// Reports how much every core group has been used, to tune the number of PC1 workers.
+pub fn core_stats() -> CoreStats {
+    let (failed_checkouts, wait_time) = {
+        let queue = CHECKOUT_QUEUE.lock().expect("poisoned lock");
+        (queue.failed_checkouts, queue.wait_time)
     };
+    let groups = current_core_groups()
+        .map(|groups| {
+            groups
+                .iter()
+                .map(|slot| CoreGroupStats {
+                    cores: slot.core_indexes.clone(),
+                    numa_node: slot.numa_node,
+                    checkouts: slot.stats.checkouts.load(Ordering::SeqCst),
+                    contended: slot.stats.contended.load(Ordering::SeqCst),
+                    hold_time: Duration::from_nanos(slot.stats.held_nanos.load(Ordering::SeqCst)),
+                    checked_out: slot.stats.checked_out.load(Ordering::SeqCst),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    CoreStats {
+        groups,
+        failed_checkouts,
+        wait_time,
+    }
+}
+
+/// A checked out core group, which is returned when dropped.
+pub struct CheckedOutCoreGroup {
+    // Only `None` while dropping, it is released before the waiters are notified.
+    cores: Option<MutexGuard<'static, CoreGroup>>,
+    core_locks: Vec<File>,
+    slot: &'static CoreGroupSlot,
+    checked_out_at: Instant,
+}
+
+impl Drop for CheckedOutCoreGroup {
+    fn drop(&mut self) {
+        let held = self.checked_out_at.elapsed();
+        let stats = &self.slot.stats;
+        stats
+            .held_nanos
+            .fetch_add(held.as_nanos() as u64, Ordering::SeqCst);
+        stats.checked_out.store(false, Ordering::SeqCst);
+        // Closing the files releases their locks.
+        self.core_locks.clear();
+        self.cores.take();
+
+        // Taking the queue lock makes sure that no waiter misses the notification between
+        // failing to check out a group and waiting.
+        let _queue = CHECKOUT_QUEUE.lock().expect("poisoned lock");
+        CORE_GROUP_RELEASED.notify_all();
+    }
+}
+
+impl CheckedOutCoreGroup {
+    /// The NUMA node all cores of the group belong to, `None` if it is unknown or the cores are
+    /// spread over several nodes. Memory used by the cores should be bound to this node.
+    pub fn numa_node(&self) -> Option<u32> {
+        self.slot.numa_node
+    }
+
+    /// The number of producers to label a sector with on this group: one for every core of the
+    /// group but the consumer's. The number set by the core plan, or `configured`, is only used
+    /// if the group has a single core, or the producers run on SMT siblings.
+    pub fn producers(&self, configured: usize) -> usize {
+        let producers = self.slot.producers.unwrap_or(configured);
+        // With SMT siblings, the producers take turns on the logical processors of the group.
+        if self.slot.units.is_some() {
+            return producers;
+        }
+        let clamped = group_producers(producers, self.len());
+        if clamped != producers {
+            warn!(
+                "core group {:?} has {} cores, labeling with {} producers instead of {}",
+                self.slot.core_indexes,
+                self.len(),
+                clamped,
+                producers
+            );
+        }
+        clamped
+    }
+
+    /// Binds the calling thread for `unit` of the group, 0 for the consumer and `i + 1` for
+    /// producer `i`. Returns `None` if the group has no place for the unit.
+    pub fn bind_unit(&self, unit: usize) -> Option<Result<Cleanup>> {
+        match &self.slot.units {
+            None => self.get(unit).map(|core| bind_core(*core)),
+            Some(units) => smt_unit(unit, units.len()).map(|i| {
+                let (core, sibling) = units[i];
+                bind_logical_processor(core, sibling)
+            }),
+        }
+    }
+}
+
+impl Deref for CheckedOutCoreGroup {
+    type Target = CoreGroup;
+
+    fn deref(&self) -> &CoreGroup {
+        self.cores.as_ref().expect("core group already released")
+    }
+}
+
+impl fmt::Debug for CheckedOutCoreGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CheckedOutCoreGroup")
+            .field("cores", &self.cores)
+            .field("numa_node", &self.slot.numa_node)
+            .finish()
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -24,22 +494,252 @@ lazy_static! {
 /// created with a value known to be less than the number of visible cores.
 pub struct CoreIndex(usize);
 
-pub fn checkout_core_group() -> Option<MutexGuard<'static, CoreGroup>> {
-    match &*CORE_GROUPS {
-        Some(groups) => {
-            for (i, group) in groups.iter().enumerate() {
-                match group.try_lock() {
-                    Ok(guard) => {
-                        debug!("checked out core group {}", i);
-                        return Some(guard);
-                    }
-                    Err(_) => debug!("core group {} locked, could not checkout", i),
+impl CoreIndex {
+    /// The core at `index` among the cores reported by hwloc, as used by the core plans.
+    pub fn new(index: usize) -> Result<Self> {
+        let core_count = topology_core_count();
+        ensure!(
+            index < core_count,
+            "core index {} out of range for {} cores",
+            index,
+            core_count
+        );
+        Ok(CoreIndex(index))
+    }
+
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// Checks out a free core group, without waiting. Returns `None` if all groups are busy or
+/// other callers are waiting for one.
+pub fn checkout_core_group() -> Option<CheckedOutCoreGroup> {
+    checkout_core_group_timeout(Duration::from_secs(0))
+}
+
+/// Checks out a core group for PC1 labeling, waiting up to `timeout` for one to be released if
+/// all are busy. Callers are served in the order they started waiting. Returns `None` on timeout.
+pub fn checkout_core_group_timeout(timeout: Duration) -> Option<CheckedOutCoreGroup> {
+    checkout_core_group_on(CheckoutPriority::Pc1, timeout, None)
+}
+
+/// Checks out a core group like [`checkout_core_group_timeout`] with the given priority. Callers
+/// are served by priority first and in the order they started waiting next.
+pub fn checkout_core_group_priority(
+    priority: CheckoutPriority,
+    timeout: Duration,
+) -> Option<CheckedOutCoreGroup> {
+    checkout_core_group_on(priority, timeout, None)
+}
+
+/// Where the cores of a checked out group should be.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CoreLocality {
+    /// A NUMA node, as reported by hwloc.
+    NumaNode(u32),
+    /// The PCI address of a device, e.g. `0000:3b:00.0` or `3b:00.0`, such as a GPU which is fed
+    /// by the threads bound to the group.
+    PciDevice(String),
+}
+
+/// Checks out a free core group on the NUMA node of `locality`, or any free group if there is
+/// none, without waiting. Threads feeding a GPU use it to get cores next to the device, so
+/// their buffers are allocated on its node. The checkout has the `Pc2Feeder` priority.
+pub fn checkout_core_group_near(locality: &CoreLocality) -> Option<CheckedOutCoreGroup> {
+    let numa_node = match locality {
+        CoreLocality::NumaNode(node) => Some(*node),
+        CoreLocality::PciDevice(address) => {
+            let node = pci_numa_node(address);
+            if node.is_none() {
+                debug!("no NUMA node known for PCI device {}", address);
+            }
+            node
+        }
+    };
+
+    checkout_core_group_on(
+        CheckoutPriority::Pc2Feeder,
+        Duration::from_secs(0),
+        numa_node,
+    )
+}
+
+/// A core group checked out near a device, see [`bind_near`].
+pub(crate) struct BoundCoreGroup {
+    // The binding is restored before the group is returned.
+    _binding: Option<Cleanup>,
+    _group: CheckedOutCoreGroup,
+}
+
+/// Checks out a core group near `locality` like [`checkout_core_group_near`], and binds the
+/// calling thread to its first core until the returned group is dropped, on the calling thread.
+pub(crate) fn bind_near(locality: &CoreLocality) -> Option<BoundCoreGroup> {
+    let group = checkout_core_group_near(locality)?;
+    debug!("binding a feeder thread to core group {:?}", group);
+    // This could fail, but we will ignore the error if so. It will be logged as a warning by
+    // `bind_core`.
+    let binding = group.bind_unit(0).and_then(|binding| binding.ok());
+
+    Some(BoundCoreGroup {
+        _binding: binding,
+        _group: group,
+    })
+}
+
+/// The NUMA node of a PCI device, from sysfs.
+#[cfg(target_os = "linux")]
+fn pci_numa_node(address: &str) -> Option<u32> {
+    let path = format!(
+        "/sys/bus/pci/devices/{}/numa_node",
+        normalize_pci_address(address)
+    );
+    // The kernel reports -1 if the node is unknown.
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pci_numa_node(_address: &str) -> Option<u32> {
+    None
+}
+
+/// Lower cases a PCI address and adds the domain if it is missing.
+#[cfg(any(target_os = "linux", test))]
+fn normalize_pci_address(address: &str) -> String {
+    let address = address.trim().to_lowercase();
+    if address.matches(':').count() == 1 {
+        format!("0000:{}", address)
+    } else {
+        address
+    }
+}
+
+fn checkout_core_group_on(
+    priority: CheckoutPriority,
+    timeout: Duration,
+    numa_node: Option<u32>,
+) -> Option<CheckedOutCoreGroup> {
+    current_core_groups()?;
+    let start = Instant::now();
+    let deadline = start + timeout;
+
+    let mut queue = CHECKOUT_QUEUE.lock().expect("poisoned lock");
+    let ticket = queue.next_ticket;
+    queue.next_ticket += 1;
+    queue.waiting.push_back((ticket, priority));
+
+    loop {
+        // The groups may have been replaced by `reload_core_plan` while waiting.
+        let groups = current_core_groups();
+        if let Some(groups) = groups.filter(|_| queue.next_in_line() == Some(ticket)) {
+            if let Some(group) = try_checkout_core_group(groups, numa_node, priority) {
+                queue.waiting.retain(|(waiting, _)| *waiting != ticket);
+                queue.wait_time += start.elapsed();
+                // The next waiter may find another free group.
+                CORE_GROUP_RELEASED.notify_all();
+                return Some(group);
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline || groups.is_none() {
+            queue.waiting.retain(|(waiting, _)| *waiting != ticket);
+            queue.failed_checkouts += 1;
+            CORE_GROUP_RELEASED.notify_all();
+            if !timeout.is_zero() {
+                debug!("timed out waiting for a core group after {:?}", timeout);
+            }
+            return None;
+        }
+
+        // Groups released by other processes are not notified, so check again from time to time.
+        queue = CORE_GROUP_RELEASED
+            .wait_timeout(queue, (deadline - now).min(CROSS_PROCESS_POLL_INTERVAL))
+            .expect("poisoned lock")
+            .0;
+    }
+}
+
+/// The order in which groups are tried: the groups on `numa_node` first, then all others.
+fn checkout_order(group_nodes: &[Option<u32>], numa_node: Option<u32>) -> Vec<usize> {
+    let (mut near, far): (Vec<usize>, Vec<usize>) =
+        (0..group_nodes.len()).partition(|i| numa_node.is_some() && group_nodes[*i] == numa_node);
+    near.extend(far);
+    near
+}
+
+fn try_checkout_core_group(
+    groups: &'static [CoreGroupSlot],
+    numa_node: Option<u32>,
+    priority: CheckoutPriority,
+) -> Option<CheckedOutCoreGroup> {
+    let group_nodes: Vec<Option<u32>> = groups.iter().map(|group| group.numa_node).collect();
+    for i in checkout_order(&group_nodes, numa_node) {
+        let group = &groups[i];
+        if group.reserved && priority != CheckoutPriority::Pc1 {
+            continue;
+        }
+        let guard = match group.cores.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                debug!("core group {} locked, could not checkout", i);
+                group.stats.contended.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+        };
+        match lock_core_files(&group.lock_files) {
+            Ok(core_locks) => {
+                debug!("checked out core group {}", i);
+                group.stats.checkouts.fetch_add(1, Ordering::SeqCst);
+                group.stats.checked_out.store(true, Ordering::SeqCst);
+                return Some(CheckedOutCoreGroup {
+                    cores: Some(guard),
+                    core_locks,
+                    slot: group,
+                    checked_out_at: Instant::now(),
+                });
+            }
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+                {
+                    debug!("core group {} is used by another process", i);
+                } else {
+                    warn!("failed to lock the cores of core group {}: {}", i, err);
                 }
+                group.stats.contended.fetch_add(1, Ordering::SeqCst);
             }
-            None
         }
-        None => None,
     }
+    None
+}
+
+/// Number of physical cores reported by hwloc, or of logical processors if it reports no cores.
+pub fn topology_core_count() -> usize {
+    let topo = TOPOLOGY.lock().expect("poisoned lock");
+    core_objects(&topo).len()
+}
+
+/// The hwloc objects core indexes refer to. These are the physical cores, or the logical
+/// processors if hwloc finds no cores, as on ARM servers whose firmware doesn't describe the CPU
+/// topology.
+fn core_object_type(topo: &Topology) -> ObjectType {
+    match topo.objects_with_type(&ObjectType::Core) {
+        Ok(cores) if !cores.is_empty() => ObjectType::Core,
+        _ => ObjectType::PU,
+    }
+}
+
+fn core_objects(topo: &Topology) -> Vec<&TopologyObject> {
+    topo.objects_with_type(&core_object_type(topo))
+        .unwrap_or_default()
+}
+
+/// Number of core groups the multicore SDR can check out, 0 if no core plan could be built.
+pub fn core_group_count() -> usize {
+    current_core_groups()
+        .map(<[CoreGroupSlot]>::len)
+        .unwrap_or(0)
 }
 
 #[cfg(not(target_os = "windows"))]
@@ -55,28 +755,81 @@ fn get_thread_id() -> ThreadId {
     unsafe { libc::pthread_self() }
 }
 
+/// Returns the pseudo handle of the current thread. It is only valid on the calling thread, which
+/// is fine as the [`Cleanup`] of a binding is always dropped on the thread it was created on.
 #[cfg(target_os = "windows")]
 fn get_thread_id() -> ThreadId {
     unsafe { kernel32::GetCurrentThread() }
 }
 
+/// Restores the binding a thread had before [`bind_core`] when dropped. It must be dropped on the
+/// bound thread.
 pub struct Cleanup {
     tid: ThreadId,
     prior_state: Option<Bitmap>,
+    #[cfg(target_os = "windows")]
+    prior_affinity: Option<winapi::winnt::GROUP_AFFINITY>,
 }
 
 impl Drop for Cleanup {
     fn drop(&mut self) {
+        #[cfg(target_os = "windows")]
+        if let Some(prior) = self.prior_affinity.take() {
+            let _ = unsafe { kernel32::SetThreadGroupAffinity(self.tid, &prior, ptr::null_mut()) };
+        }
+
         if let Some(prior) = self.prior_state.take() {
             let child_topo = &TOPOLOGY;
             let mut locked_topo = child_topo.lock().expect("poisded lock");
+            #[cfg(not(target_os = "windows"))]
             let _ = locked_topo.set_cpubind_for_thread(self.tid, prior.clone(), CPUBIND_THREAD);
-            let _ = locked_topo.set_membind(prior, hwloc::MEMBIND_DEFAULT, hwloc::MEMBIND_THREAD);
+            let _ = locked_topo.set_membind(prior, MEMBIND_DEFAULT, MEMBIND_THREAD);
         }
     }
 }
 
+/// The processor group and the affinity mask within the group of the logical processor with
+/// the OS index `pu`. hwloc numbers the logical processors of Windows processor group `g` from
+/// `64 * g` on.
+#[cfg(any(target_os = "windows", test))]
+fn processor_group_affinity(pu: u32) -> (u16, u64) {
+    ((pu / 64) as u16, 1 << (pu % 64))
+}
+
+/// Binds the current thread to the logical processor with the OS index `pu`, returning the
+/// previous affinity. Windows only schedules a thread within one processor group of up to 64
+/// logical processors, and hwloc only binds within the current group of the thread, so the group
+/// affinity is set directly.
+#[cfg(target_os = "windows")]
+fn set_thread_group_affinity(tid: ThreadId, pu: i32) -> Result<winapi::winnt::GROUP_AFFINITY> {
+    ensure!(pu >= 0, "no logical processor to bind to");
+
+    let (group, mask) = processor_group_affinity(pu as u32);
+    let affinity = winapi::winnt::GROUP_AFFINITY {
+        Mask: mask as winapi::basetsd::KAFFINITY,
+        Group: group,
+        Reserved: [0; 3],
+    };
+    let mut prior: winapi::winnt::GROUP_AFFINITY = unsafe { std::mem::zeroed() };
+    let ok = unsafe { kernel32::SetThreadGroupAffinity(tid, &affinity, &mut prior) };
+    ensure!(
+        ok != 0,
+        "failed to set group affinity: {}",
+        std::io::Error::last_os_error()
+    );
+
+    Ok(prior)
+}
+
+/// Binds the calling thread to a logical processor of a core, and its memory allocations to the
+/// NUMA node of the core, until the returned [`Cleanup`] is dropped.
 pub fn bind_core(core_index: CoreIndex) -> Result<Cleanup> {
+    bind_logical_processor(core_index, 0)
+}
+
+/// Binds the calling thread to the `sibling`th logical processor of a core, or to its first one
+/// if it has no such processor.
+fn bind_logical_processor(core_index: CoreIndex, sibling: usize) -> Result<Cleanup> {
     let child_topo = &TOPOLOGY;
     let tid = get_thread_id();
     let mut locked_topo = child_topo.lock().expect("poisoned lock");
@@ -87,37 +840,87 @@ pub fn bind_core(core_index: CoreIndex) -> Result<Cleanup> {
         .allowed_cpuset()
         .ok_or_else(|| format_err!("no allowed cpuset for core at index {}", core_index.0,))?;
     debug!("allowed cpuset: {:?}", cpuset);
-    let mut bind_to = cpuset;
 
     // Get only one logical processor (in case the core is SMT/hyper-threaded).
-    bind_to.singlify();
+    let bind_to = match cpuset.clone().into_iter().nth(sibling) {
+        Some(pu) if sibling > 0 => {
+            let mut bind_to = Bitmap::new();
+            bind_to.set(pu);
+            bind_to
+        }
+        _ => {
+            let mut bind_to = cpuset;
+            bind_to.singlify();
+            bind_to
+        }
+    };
 
     // Thread binding before explicit set.
     let before = locked_topo.get_cpubind_for_thread(tid, CPUBIND_THREAD);
 
     debug!("binding to {:?}", bind_to);
     // Set the binding.
+    #[cfg(not(target_os = "windows"))]
     let result = locked_topo
         // .set_cpubind_for_thread(tid, bind_to, CPUBIND_THREAD)
         .set_cpubind_for_thread(tid, bind_to.clone(), CPUBIND_THREAD)
         .map_err(|err| format_err!("failed to bind CPU: {:?}", err));
+    #[cfg(target_os = "windows")]
+    let (result, prior_affinity) = match set_thread_group_affinity(tid, bind_to.first()) {
+        Ok(prior) => (Ok(()), Some(prior)),
+        Err(err) => (Err(err), None),
+    };
 
     if result.is_err() {
         warn!("error in bind_core, {:?}", result);
     }
 
-    let _ = locked_topo.set_membind(bind_to, hwloc::MEMBIND_BIND, hwloc::MEMBIND_THREAD);
+    let _ = locked_topo.set_membind(bind_to, MEMBIND_BIND, MEMBIND_THREAD);
 
     Ok(Cleanup {
         tid,
         prior_state: before,
+        #[cfg(target_os = "windows")]
+        prior_affinity,
     })
 }
 
+/// The OS index of the NUMA node containing all `cores`, `None` if there is no such node.
+fn numa_node_of_cores(topo: &Topology, cores: &[CoreIndex]) -> Option<u32> {
+    let nodes = topo.objects_with_type(&ObjectType::NUMANode).ok()?;
+
+    let mut numa_node = None;
+    for core_index in cores {
+        let first_pu = get_core_by_index(topo, *core_index)
+            .ok()?
+            .cpuset()
+            .map(|set| set.first())?;
+        if first_pu < 0 {
+            return None;
+        }
+        let node = nodes.iter().find(|node| {
+            node.cpuset()
+                .map(|set| set.is_set(first_pu as u32))
+                .unwrap_or(false)
+        })?;
+
+        match numa_node {
+            None => numa_node = Some(node.os_index()),
+            Some(other) if other != node.os_index() => {
+                warn!("core group {:?} spans several NUMA nodes", cores);
+                return None;
+            }
+            Some(_) => {}
+        }
+    }
+
+    numa_node
+}
+
 fn get_core_by_index(topo: &Topology, index: CoreIndex) -> Result<&TopologyObject> {
     let idx = index.0;
 
-    match topo.objects_with_type(&ObjectType::Core) {
+    match topo.objects_with_type(&core_object_type(topo)) {
         Ok(all_cores) if idx < all_cores.len() => Ok(all_cores[idx]),
         Ok(all_cores) => Err(format_err!(
             "idx ({}) out of range for {} cores",
@@ -128,84 +931,168 @@ fn get_core_by_index(topo: &Topology, index: CoreIndex) -> Result<&TopologyObjec
     }
 }
 
-fn core_groups(core_plan: String) -> Option<Vec<Mutex<Vec<CoreIndex>>>> {
-    // let topo = TOPOLOGY.lock().expect("poisoned lock");
-    //
-    // let a = topo.objects_with_type(&ObjectType::NUMANode)
-    //     .expect("objects_with_type failed");
-    //
-    // for b in a {
-    //     println!("{:?}", b.cpuset().unwrap());
-    //     // println!("{:?}",b.cpuset())
-    // }
-    //
-    // let core_depth = match topo.depth_or_below_for_type(&ObjectType::Core) {
-    //     Ok(depth) => depth,
-    //     Err(_) => return None,
-    // };
-    // let all_cores = topo
-    //     .objects_with_type(&ObjectType::Core)
-    //     .expect("objects_with_type failed");
-    // let core_count = all_cores.len();
-    //
-    // let mut cache_depth = core_depth;
-    // let mut cache_count = 1;
-    //
-    // while cache_depth > 0 {
-    //     let objs = topo.objects_at_depth(cache_depth);
-    //     let obj_count = objs.len();
-    //     if obj_count < core_count {
-    //         cache_count = obj_count;
-    //         break;
-    //     }
-    //
-    //     cache_depth -= 1;
-    // }
-    //
-    // assert_eq!(0, core_count % cache_count);
-    // let mut group_size = core_count / cache_count;
-    // let mut group_count = cache_count;
-    //
-    // if cache_count <= 1 {
-    //     // If there are not more than one shared caches, there is no benefit in trying to group cores by cache.
-    //     // In that case, prefer more groups so we can still bind cores and also get some parallelism.
-    //     // Create as many full groups as possible. The last group may not be full.
-    //     group_count = core_count / cores_per_unit;
-    //     group_size = cores_per_unit;
-    //
-    //     info!(
-    //         "found only {} shared cache(s), heuristically grouping cores into {} groups",
-    //         cache_count, group_count
-    //     );
-    // } else {
-    //     debug!(
-    //         "Cores: {}, Shared Caches: {}, cores per cache (group_size): {}",
-    //         core_count, cache_count, group_size
-    //     );
-    // }
-    //
-    // let skips: Vec<CoreIndex>;
-    // if skip_cores.eq("") {
-    //     skips = Vec::new();
-    // } else {
-    //     skips = skip_cores.split(",")
-    //         .into_iter()
-    //         .map(|core|CoreIndex(from_str::<usize>(core).unwrap()))
-    //         .collect::<Vec<_>>();
-    //     println!("{:?}",skips);
-    // }
-    //
-    // let core_groups = (0..group_count)
-    //     .map(|i| {
-    //         (0..group_size)
-    //             .map(|j| {
-    //                 let core_index = i * group_size + j;
-    //                 assert!(core_index < core_count);
-    //                 CoreIndex(core_index)
-    //             })
-    //             .collect::<Vec<_>>()
-    //     })
-    //     .collect::<Vec<_>>();
+/// A core plan read from `FIL_PROOFS_MULTICORE_SDR_CORE_PLAN_FILE`, listing the indexes of the
+/// cores of every group. Files ending in `.json` are read as JSON, all others as TOML.
+///
+/// The plan may set the number of producers of the sectors labeled on its groups, which
+/// overrides `FIL_PROOFS_MULTICORE_SDR_PRODUCERS`.
+#[derive(Debug, Deserialize)]
+struct CorePlanFile {
+    groups: Vec<Vec<usize>>,
+    #[serde(default)]
+    producers: Option<usize>,
+}
+
+/// The core groups of a core plan, with the number of producers they are sized for if the plan
+/// sets it.
+#[derive(Debug, PartialEq)]
+struct CorePlan {
+    groups: Vec<CoreGroup>,
+    producers: Option<usize>,
+}
+
+impl CorePlan {
+    fn new(groups: Vec<CoreGroup>) -> Self {
+        CorePlan {
+            groups,
+            producers: None,
+        }
+    }
+}
+
+fn load_core_plan_file(path: &str, core_count: usize) -> Result<CorePlan> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("could not read core plan {}", path))?;
+    let is_json = Path::new(path)
+        .extension()
+        .map(|ext| ext == "json")
+        .unwrap_or(false);
+    let plan: CorePlanFile = if is_json {
+        from_str(&contents).with_context(|| format!("invalid core plan {}", path))?
+    } else {
+        toml::from_str(&contents).with_context(|| format!("invalid core plan {}", path))?
+    };
+
+    ensure!(
+        plan.producers != Some(0),
+        "core plan {} needs at least one producer",
+        path
+    );
+
+    Ok(CorePlan {
+        groups: core_plan_groups(plan.groups, core_count)?,
+        producers: plan.producers,
+    })
+}
+
+/// Checks that the `groups` of a plan are not empty and don't share cores. The indexes are only
+/// checked against `core_count` if hwloc reported any cores.
+fn core_plan_groups(groups: Vec<Vec<usize>>, core_count: usize) -> Result<Vec<CoreGroup>> {
+    ensure!(!groups.is_empty(), "core plan has no groups");
+
+    let mut seen = HashSet::new();
+    for (i, group) in groups.iter().enumerate() {
+        ensure!(!group.is_empty(), "core group {} is empty", i);
+        for &core in group {
+            ensure!(
+                core_count == 0 || core < core_count,
+                "core {} of group {} out of range for {} cores",
+                core,
+                i,
+                core_count
+            );
+            ensure!(seen.insert(core), "core {} is in more than one group", core);
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|group| group.into_iter().map(CoreIndex).collect())
+        .collect())
+}
+
+/// Registers a core plan called `name`, listing the hwloc core indexes of every group, which can
+/// then be selected by `FIL_PROOFS_MULTICORE_SDR_CORE_PLAN` or [`select_core_plan`]. Registering
+/// a name again replaces its plan, a registered plan takes precedence over a built-in one.
+pub fn register_core_plan(name: &str, groups: Vec<Vec<usize>>) -> Result<()> {
+    ensure!(!name.is_empty(), "core plan name must not be empty");
+    let groups = core_plan_groups(groups, topology_core_count())
+        .with_context(|| format!("invalid core plan {}", name))?;
+
+    info!(
+        "registering core plan {} with {} groups",
+        name,
+        groups.len()
+    );
+    REGISTERED_CORE_PLANS
+        .write()
+        .expect("poisoned lock")
+        .insert(name.to_string(), groups);
+
+    Ok(())
+}
+
+/// Makes the core plan called `name` the active one, as [`reload_core_plan`] does with
+/// `FIL_PROOFS_MULTICORE_SDR_CORE_PLAN` set to `name` and no core plan file. An empty name
+/// groups the cores by topology. Returns the number of core groups.
+pub fn select_core_plan(name: &str) -> Result<usize> {
+    ensure!(
+        name.is_empty() || is_builtin_core_plan(name) || registered_core_plan(name).is_some(),
+        "unknown core plan {}",
+        name
+    );
+
+    let mut settings = SETTINGS.clone();
+    settings.multicore_sdr_core_plan = name.to_string();
+    settings.multicore_sdr_core_plan_file = String::new();
+
+    Ok(reload_core_plan(&settings))
+}
+
+fn registered_core_plan(name: &str) -> Option<Vec<CoreGroup>> {
+    REGISTERED_CORE_PLANS
+        .read()
+        .expect("poisoned lock")
+        .get(name)
+        .cloned()
+}
+
+fn is_builtin_core_plan(name: &str) -> bool {
+    name == "DELL7525"
+}
+
+fn core_groups(
+    core_plan: String,
+    core_plan_file: &str,
+    grouping: CoreGrouping,
+    cores_per_unit: usize,
+    skip_cores: &[usize],
+) -> Option<CorePlan> {
+    if !core_plan_file.is_empty() {
+        return match load_core_plan_file(core_plan_file, topology_core_count()) {
+            Ok(plan) => {
+                info!(
+                    "using {} core groups from core plan {}",
+                    plan.groups.len(),
+                    core_plan_file
+                );
+                Some(plan)
+            }
+            Err(err) => {
+                error!("not binding cores, failed to load core plan: {:?}", err);
+                None
+            }
+        };
+    }
+
+    if let Some(groups) = registered_core_plan(&core_plan) {
+        info!(
+            "using {} core groups from registered core plan {}",
+            groups.len(),
+            core_plan
+        );
+        return Some(CorePlan::new(groups));
+    }
 
     let custom_groups = match core_plan.as_str() {
         "DELL7525" => {
@@ -246,16 +1133,200 @@ fn core_groups(core_plan: String) -> Option<Vec<Mutex<Vec<CoreIndex>>>> {
                 vec![CoreIndex(124),CoreIndex(125),CoreIndex(126),CoreIndex(127)],
             ]
         },
-        _=> vec![vec![CoreIndex(0),CoreIndex(1),CoreIndex(2),CoreIndex(3)]]
+        "" => return topology_core_groups(grouping, cores_per_unit, skip_cores).map(CorePlan::new),
+        _ => {
+            warn!(
+                "unknown core plan {}, grouping cores by topology",
+                core_plan
+            );
+            return topology_core_groups(grouping, cores_per_unit, skip_cores).map(CorePlan::new);
+        }
     };
 
-    Some(
-        custom_groups
-            .iter()
-            // .filter(|group| !skips.contains(group.split_first().unwrap().0))
-            .map(|group| Mutex::new(group.clone()))
-            .collect::<Vec<_>>(),
-    )
+    Some(CorePlan::new(custom_groups))
+}
+
+/// Parses a comma separated list of core indexes and inclusive ranges of core indexes, e.g.
+/// `0-3,16,32-35`, as used by `FIL_PROOFS_SKIP_CORES`.
+fn parse_skip_cores(skip_cores: &str) -> Vec<usize> {
+    let parse_range = |entry: &str| -> Option<Vec<usize>> {
+        let mut bounds = entry.splitn(2, '-').map(str::trim);
+        let start: usize = bounds.next()?.parse().ok()?;
+        match bounds.next() {
+            Some(end) => {
+                let end: usize = end.parse().ok()?;
+                if end < start {
+                    return None;
+                }
+                Some((start..=end).collect())
+            }
+            None => Some(vec![start]),
+        }
+    };
+
+    skip_cores
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .flat_map(|entry| match parse_range(entry) {
+            Some(cores) => cores,
+            None => {
+                warn!("ignoring invalid cores to skip: {}", entry);
+                Vec::new()
+            }
+        })
+        .collect()
+}
+
+/// Removes the skipped cores from all groups, dropping the groups left empty.
+fn remove_skipped_cores(groups: Vec<CoreGroup>, skip_cores: &[usize]) -> Vec<CoreGroup> {
+    if skip_cores.is_empty() {
+        return groups;
+    }
+
+    let groups: Vec<CoreGroup> = groups
+        .into_iter()
+        .map(|group| {
+            group
+                .into_iter()
+                .filter(|core| !skip_cores.contains(&core.0))
+                .collect::<CoreGroup>()
+        })
+        .filter(|group| !group.is_empty())
+        .collect();
+    info!(
+        "skipping cores {:?}, {} core groups left",
+        skip_cores,
+        groups.len()
+    );
+
+    groups
+}
+
+/// Groups the cores of the topology reported by hwloc by the first cache shared by more than one
+/// of them. The L1 and L2 caches are private to a core, so on Zen this is the L3 cache of a core
+/// complex (CCX). On ARM servers this is a cluster of cores (a DSU, reported as an hwloc group),
+/// or the system level cache or NUMA node if the clusters are too small or missing, which is then
+/// split into groups.
+fn topology_core_groups(
+    grouping: CoreGrouping,
+    cores_per_unit: usize,
+    skip_cores: &[usize],
+) -> Option<Vec<CoreGroup>> {
+    let topo = TOPOLOGY.lock().expect("poisoned lock");
+
+    let core_depth = match topo.depth_or_below_for_type(&core_object_type(&topo)) {
+        Ok(depth) => depth,
+        Err(_) => return None,
+    };
+    let all_cores = core_objects(&topo);
+    let core_count = all_cores.len();
+    if core_count == 0 {
+        return None;
+    }
+    if grouping == CoreGrouping::SmtSiblings {
+        info!("found {} cores, one core group per core", core_count);
+        return Some(
+            (0..core_count)
+                .filter(|core| !skip_cores.contains(core))
+                .map(|core| vec![CoreIndex(core)])
+                .collect(),
+        );
+    }
+
+    // The levels above the cores, closest first.
+    let (depths, levels): (Vec<u32>, Vec<(ObjectType, usize)>) = (0..core_depth)
+        .rev()
+        .filter_map(|depth| {
+            let objects = topo.objects_at_depth(depth);
+            objects
+                .first()
+                .map(|object| (depth, (object.object_type(), objects.len())))
+        })
+        .unzip();
+    let depth = depths[shared_level(&levels, core_count, cores_per_unit)?];
+    let shared = topo.objects_at_depth(depth);
+
+    // Index of the shared object containing the first logical processor of every core. Cores
+    // which can't be matched (no cpuset) get a group of their own at the end.
+    let shared_cache: Vec<usize> = all_cores
+        .iter()
+        .map(|core| {
+            let first_pu = core.cpuset().map(|set| set.first()).unwrap_or(-1);
+            shared
+                .iter()
+                .position(|obj| {
+                    first_pu >= 0
+                        && obj
+                            .cpuset()
+                            .map(|set| set.is_set(first_pu as u32))
+                            .unwrap_or(false)
+                })
+                .unwrap_or(shared.len())
+        })
+        .collect();
+
+    let groups = group_cores(&shared_cache, cores_per_unit, skip_cores);
+    info!(
+        "found {} cores sharing {} cache(s) at depth {}, grouped into {} core groups",
+        core_count,
+        shared.len(),
+        depth,
+        groups.len()
+    );
+    debug!("core groups: {:?}", groups);
+
+    Some(groups)
+}
+
+/// The index of the level the cores are grouped by, out of the `levels` above the cores (closest
+/// first) with their object type and number of objects: the first one with fewer objects than
+/// there are cores. Groups of fewer than `cores_per_unit` cores are skipped, as they are clusters
+/// which share no cache, like the core pairs of Ampere Altra. Falls back to the topmost level.
+fn shared_level(
+    levels: &[(ObjectType, usize)],
+    core_count: usize,
+    cores_per_unit: usize,
+) -> Option<usize> {
+    levels
+        .iter()
+        .position(|(object_type, count)| {
+            *count < core_count
+                && (*object_type != ObjectType::Group || core_count / count >= cores_per_unit)
+        })
+        .or_else(|| levels.len().checked_sub(1))
+}
+
+/// Groups the cores sharing a cache, `shared_cache[i]` being the cache of core `i`. Caches shared
+/// by at least twice `cores_per_unit` cores, and the only cache if all cores share one, are split
+/// into groups of `cores_per_unit` cores so that more sectors can be labeled in parallel.
+fn group_cores(
+    shared_cache: &[usize],
+    cores_per_unit: usize,
+    skip_cores: &[usize],
+) -> Vec<CoreGroup> {
+    let mut by_cache: BTreeMap<usize, CoreGroup> = BTreeMap::new();
+    for (core, cache) in shared_cache.iter().enumerate() {
+        if !skip_cores.contains(&core) {
+            by_cache.entry(*cache).or_default().push(CoreIndex(core));
+        }
+    }
+
+    let cores_per_unit = cores_per_unit.max(1);
+    let single_cache = by_cache.len() == 1;
+    by_cache
+        .into_iter()
+        .flat_map(|(_, cores)| {
+            if single_cache || cores.len() >= 2 * cores_per_unit {
+                cores
+                    .chunks(cores_per_unit)
+                    .map(<[CoreIndex]>::to_vec)
+                    .collect()
+            } else {
+                vec![cores]
+            }
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -265,10 +1336,256 @@ mod tests {
     #[test]
     fn test_cores() {
         println!("test_cores");
-        let cores = core_groups(String::from("PLAN_X"));
+        let cores = core_groups(String::from("PLAN_X"), "", CoreGrouping::SharedCache, 4, &[]);
         println!("{:?}", cores);
     }
 
+    fn indexes(groups: Vec<CoreGroup>) -> Vec<Vec<usize>> {
+        groups
+            .into_iter()
+            .map(|group| group.into_iter().map(|core| core.0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_level() {
+        use ObjectType::{Cache, Group, Machine, Package};
+
+        // Zen 2: private L2, L3 per core complex of 4 cores.
+        let zen = [(Cache, 64), (Cache, 16), (Package, 1), (Machine, 1)];
+        assert_eq!(shared_level(&zen, 64, 4), Some(1));
+
+        // Ampere Altra: private L2, pairs of cores without a shared cache, one SLC.
+        let altra = [(Cache, 80), (Group, 40), (Package, 1), (Machine, 1)];
+        assert_eq!(shared_level(&altra, 80, 4), Some(2));
+        assert_eq!(shared_level(&altra, 80, 2), Some(1));
+
+        // Clusters of 4 cores sharing their L3.
+        let clusters = [(Cache, 64), (Group, 16), (Package, 1), (Machine, 1)];
+        assert_eq!(shared_level(&clusters, 64, 4), Some(1));
+
+        // Only private levels.
+        assert_eq!(shared_level(&[(Cache, 4), (Machine, 4)], 4, 4), Some(1));
+        assert_eq!(shared_level(&[], 4, 4), None);
+    }
+
+    #[test]
+    fn test_group_cores() {
+        // Two CCXs of four cores each.
+        let shared_cache = [0, 0, 0, 0, 1, 1, 1, 1];
+        assert_eq!(
+            indexes(group_cores(&shared_cache, 4, &[])),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]
+        );
+        assert_eq!(
+            indexes(group_cores(&shared_cache, 4, &[1, 4])),
+            vec![vec![0, 2, 3], vec![5, 6, 7]]
+        );
+
+        // Two CCDs of eight cores each, split into groups of four.
+        let shared_cache = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1];
+        assert_eq!(
+            indexes(group_cores(&shared_cache, 4, &[])),
+            vec![
+                vec![0, 1, 2, 3],
+                vec![4, 5, 6, 7],
+                vec![8, 9, 10, 11],
+                vec![12, 13, 14, 15]
+            ]
+        );
+
+        // A single cache shared by all cores.
+        assert_eq!(
+            indexes(group_cores(&[0; 6], 4, &[])),
+            vec![vec![0, 1, 2, 3], vec![4, 5]]
+        );
+    }
+
+    #[test]
+    fn test_processor_group_affinity() {
+        assert_eq!(processor_group_affinity(0), (0, 1));
+        assert_eq!(processor_group_affinity(63), (0, 1 << 63));
+        assert_eq!(processor_group_affinity(64), (1, 1));
+        assert_eq!(processor_group_affinity(130), (2, 1 << 2));
+    }
+
+    #[test]
+    fn test_parse_skip_cores() {
+        assert!(parse_skip_cores("").is_empty());
+        assert_eq!(parse_skip_cores("1, 3,x,5"), vec![1, 3, 5]);
+        assert_eq!(
+            parse_skip_cores("0-2, 8 - 9,7-6,12"),
+            vec![0, 1, 2, 8, 9, 12]
+        );
+    }
+
+    #[test]
+    fn test_remove_skipped_cores() {
+        let groups = vec![
+            vec![CoreIndex(0), CoreIndex(1)],
+            vec![CoreIndex(2), CoreIndex(3)],
+        ];
+        assert_eq!(
+            indexes(remove_skipped_cores(groups.clone(), &[1, 2, 3])),
+            vec![vec![0]]
+        );
+        assert_eq!(remove_skipped_cores(groups.clone(), &[]), groups);
+    }
+
+    #[test]
+    fn test_remove_unknown_cores() {
+        let groups = vec![
+            vec![CoreIndex(0), CoreIndex(1)],
+            vec![CoreIndex(2), CoreIndex(3)],
+        ];
+        assert_eq!(
+            indexes(remove_unknown_cores(groups.clone(), 3)),
+            vec![vec![0, 1], vec![2]]
+        );
+        assert_eq!(indexes(remove_unknown_cores(groups, 2)), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn test_core_plan_file() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+
+        let toml_path = dir.path().join("plan.toml");
+        fs::write(&toml_path, "groups = [[0, 1], [2, 3]]\n").expect("write failure");
+        let plan = load_core_plan_file(&toml_path.to_string_lossy(), 4).expect("invalid plan");
+        assert_eq!(
+            plan,
+            CorePlan::new(vec![
+                vec![CoreIndex(0), CoreIndex(1)],
+                vec![CoreIndex(2), CoreIndex(3)]
+            ])
+        );
+
+        let json_path = dir.path().join("plan.json");
+        fs::write(&json_path, r#"{"groups": [[4, 5, 6]], "producers": 2}"#).expect("write failure");
+        let plan = load_core_plan_file(&json_path.to_string_lossy(), 0).expect("invalid plan");
+        assert_eq!(
+            plan,
+            CorePlan {
+                groups: vec![vec![CoreIndex(4), CoreIndex(5), CoreIndex(6)]],
+                producers: Some(2),
+            }
+        );
+
+        fs::write(&toml_path, "producers = 0\ngroups = [[0, 1]]\n").expect("write failure");
+        assert!(load_core_plan_file(&toml_path.to_string_lossy(), 4).is_err());
+
+        assert!(core_plan_groups(vec![], 4).is_err());
+        assert!(core_plan_groups(vec![vec![0], vec![]], 4).is_err());
+        assert!(core_plan_groups(vec![vec![0, 1], vec![1, 2]], 4).is_err());
+        assert!(core_plan_groups(vec![vec![0, 4]], 4).is_err());
+        assert!(core_plan_groups(vec![vec![0, 4]], 0).is_ok());
+    }
+
+    #[test]
+    fn test_core_index() {
+        assert!(CoreIndex::new(usize::MAX).is_err());
+        if topology_core_count() > 0 {
+            assert_eq!(CoreIndex::new(0).expect("no core 0").index(), 0);
+        }
+    }
+
+    #[test]
+    fn test_lock_core_files() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let files = core_lock_files(dir.path(), &[CoreIndex(0), CoreIndex(1)]);
+        assert_eq!(files[1], dir.path().join("core-1.lock"));
+
+        let locks = lock_core_files(&files).expect("failed to lock");
+        assert!(lock_core_files(&files).is_err());
+        assert!(lock_core_files(&files[1..]).is_err());
+        drop(locks);
+        assert!(lock_core_files(&files).is_ok());
+        assert!(lock_core_files(&[]).expect("failed to lock").is_empty());
+    }
+
+    #[test]
+    fn test_checkout_queue_priority() {
+        let mut queue = CheckoutQueue::default();
+        assert_eq!(queue.next_in_line(), None);
+
+        queue.waiting.push_back((0, CheckoutPriority::Misc));
+        queue.waiting.push_back((1, CheckoutPriority::Pc2Feeder));
+        assert_eq!(queue.next_in_line(), Some(1));
+        queue.waiting.push_back((2, CheckoutPriority::Pc1));
+        queue.waiting.push_back((3, CheckoutPriority::Pc1));
+        assert_eq!(queue.next_in_line(), Some(2));
+
+        queue.waiting.retain(|(ticket, _)| *ticket != 2);
+        assert_eq!(queue.next_in_line(), Some(3));
+        queue.waiting.retain(|(ticket, _)| *ticket != 3);
+        assert_eq!(queue.next_in_line(), Some(1));
+    }
+
+    #[test]
+    fn test_checkout_order() {
+        let nodes = [Some(0), Some(1), None, Some(1)];
+        assert_eq!(checkout_order(&nodes, None), vec![0, 1, 2, 3]);
+        assert_eq!(checkout_order(&nodes, Some(1)), vec![1, 3, 0, 2]);
+        assert_eq!(checkout_order(&nodes, Some(2)), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_normalize_pci_address() {
+        assert_eq!(normalize_pci_address("0000:3B:00.0"), "0000:3b:00.0");
+        assert_eq!(normalize_pci_address("3b:00.0"), "0000:3b:00.0");
+    }
+
+    #[test]
+    fn test_smt_unit() {
+        assert_eq!(smt_unit(0, 0), None);
+        assert_eq!(smt_unit(0, 1), Some(0));
+        assert_eq!(smt_unit(1, 1), None);
+        assert_eq!(
+            (0..5).map(|unit| smt_unit(unit, 2)).collect::<Vec<_>>(),
+            vec![Some(0), Some(1), Some(1), Some(1), Some(1)]
+        );
+        assert_eq!(
+            (0..5).map(|unit| smt_unit(unit, 4)).collect::<Vec<_>>(),
+            vec![Some(0), Some(1), Some(2), Some(3), Some(1)]
+        );
+    }
+
+    #[test]
+    fn test_group_producers() {
+        assert_eq!(group_producers(3, 4), 3);
+        assert_eq!(group_producers(3, 2), 1);
+        assert_eq!(group_producers(3, 6), 5);
+        assert_eq!(group_producers(3, 1), 3);
+    }
+
+    #[test]
+    fn test_core_grouping() {
+        assert_eq!(CoreGrouping::parse(""), CoreGrouping::SharedCache);
+        assert_eq!(CoreGrouping::parse("cache"), CoreGrouping::SharedCache);
+        assert_eq!(CoreGrouping::parse("smt"), CoreGrouping::SmtSiblings);
+        assert_eq!(CoreGrouping::parse("other"), CoreGrouping::SharedCache);
+    }
+
+    #[test]
+    fn test_register_core_plan() {
+        assert!(register_core_plan("", vec![vec![0]]).is_err());
+        assert!(register_core_plan("test-empty", vec![]).is_err());
+        assert!(register_core_plan("test-shared", vec![vec![0], vec![0]]).is_err());
+        assert!(select_core_plan("test-unknown").is_err());
+
+        register_core_plan("test-plan", vec![vec![0]]).expect("failed to register");
+        assert_eq!(
+            core_groups(
+                "test-plan".to_string(),
+                "",
+                CoreGrouping::SharedCache,
+                1,
+                &[]
+            ),
+            Some(CorePlan::new(vec![vec![CoreIndex(0)]]))
+        );
+    }
+
     #[test]
     #[cfg(feature = "isolated-testing")]
     // This test should not be run while other tests are running, as
@@ -286,4 +1603,71 @@ mod tests {
             _ => panic!("failed to get two checkouts"),
         }
     }
+
+    #[test]
+    #[cfg(feature = "isolated-testing")]
+    fn test_checkout_core_group_timeout() {
+        let mut checkouts = Vec::new();
+        while let Some(group) = checkout_core_group() {
+            checkouts.push(group);
+        }
+        assert_eq!(checkouts.len(), core_group_count());
+        assert!(checkout_core_group_timeout(Duration::from_millis(10)).is_none());
+
+        let waiter = std::thread::spawn(|| {
+            checkout_core_group_timeout(Duration::from_secs(10)).map(|group| group.clone())
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        let released = checkouts.pop().expect("no core group checked out");
+        let released_cores = released.clone();
+        drop(released);
+
+        assert_eq!(
+            waiter.join().expect("waiter panicked"),
+            Some(released_cores)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "isolated-testing")]
+    fn test_core_stats() {
+        let before = core_stats();
+        let checkout = checkout_core_group().expect("no core group available");
+        let cores: Vec<usize> = checkout.iter().map(|core| core.0).collect();
+
+        let group = |stats: &CoreStats| {
+            stats
+                .groups
+                .iter()
+                .find(|group| group.cores == cores)
+                .cloned()
+                .expect("checked out group not in stats")
+        };
+        assert!(group(&core_stats()).checked_out);
+        drop(checkout);
+
+        let (before, after) = (group(&before), group(&core_stats()));
+        assert!(!after.checked_out);
+        assert_eq!(after.checkouts, before.checkouts + 1);
+        assert!(after.hold_time > before.hold_time);
+    }
+
+    #[test]
+    #[cfg(feature = "isolated-testing")]
+    fn test_reload_core_plan() {
+        let held = checkout_core_group().expect("no core group available");
+        let held_cores = held.clone();
+
+        let count = reload_core_plan(&SETTINGS);
+        assert_eq!(count, core_group_count());
+        assert_eq!(*held, held_cores);
+
+        // The held group belongs to the replaced groups, so all new groups are free.
+        let mut checkouts = Vec::new();
+        while let Some(group) = checkout_core_group() {
+            checkouts.push(group);
+        }
+        assert_eq!(checkouts.len(), count);
+        assert!(checkouts.iter().any(|group| **group == held_cores));
+    }
 }
@@ -0,0 +1,188 @@
+//! Labeling of a batch of sectors in lockstep.
+//!
+//! All sectors of a batch share the same graph, so they are labeled node by node in the same
+//! order: the parents of a chunk of nodes are read from the parent cache once for the whole batch,
+//! and the chunk is then labeled for all sectors in parallel. Each sector keeps two layers in
+//! memory, like the single core labeling does, and layers are written to disk as regular files.
+
+use std::marker::PhantomData;
+use std::mem;
+
+use anyhow::{ensure, Context, Result};
+use filecoin_hashers::Hasher;
+use log::info;
+use merkletree::store::StoreConfig;
+use rayon::prelude::{IntoParallelRefMutIterator, ParallelIterator};
+use sha2raw::Sha256;
+use storage_proofs_core::{
+    control,
+    drgraph::Graph,
+    merkle::MerkleTreeTrait,
+    util::{data_at_node_offset, NODE_SIZE},
+};
+
+use crate::stacked::vanilla::{
+    cache::ParentCache,
+    create_label::{
+        prepare_layers, progress::LayerProgress, read_layer, write_layer, LabelingOptions,
+    },
+    graph::DEGREE,
+    proof::LayerState,
+    Labels, StackedBucketGraph,
+};
+
+/// Number of nodes whose parents are read at once, for all sectors of the batch.
+const PARENTS_CHUNK_NODES: usize = 1 << 14;
+
+struct SectorLabels<'a, T> {
+    replica_id: &'a T,
+    layer_states: Vec<LayerState>,
+    layer_labels: Vec<u8>,
+    exp_labels: Vec<u8>,
+}
+
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]> + Sync>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &mut ParentCache,
+    layers: usize,
+    replica_ids: &[T],
+    configs: Vec<StoreConfig>,
+    options: &LabelingOptions,
+) -> Result<Vec<(Labels<Tree>, Vec<LayerState>)>> {
+    ensure!(
+        replica_ids.len() == configs.len(),
+        "a config is required for every replica id"
+    );
+    info!(
+        "generate labels for a batch of {} sectors",
+        replica_ids.len()
+    );
+
+    let layer_size = graph.size() * NODE_SIZE;
+    let mut sectors: Vec<SectorLabels<'_, T>> = replica_ids
+        .iter()
+        .zip(configs.iter())
+        .map(|(replica_id, config)| SectorLabels {
+            replica_id,
+            layer_states: prepare_layers::<Tree>(graph, config, layers),
+            layer_labels: vec![0u8; layer_size],
+            exp_labels: vec![0u8; layer_size],
+        })
+        .collect();
+
+    let mut parents = vec![[0u32; DEGREE]; PARENTS_CHUNK_NODES.min(graph.size())];
+    for layer in 1..=layers {
+        info!("generating layer: {}", layer);
+        control::checkpoint(&format!("layer {}", layer))?;
+
+        // Sectors which already have this layer on disk load it, as the next layer needs it.
+        let generated = |sector: &SectorLabels<'_, T>| sector.layer_states[layer - 1].generated;
+        sectors
+            .par_iter_mut()
+            .filter(|sector| generated(sector))
+            .try_for_each(|sector| {
+                read_layer(
+                    &sector.layer_states[layer - 1].config,
+                    &mut sector.exp_labels,
+                )
+            })?;
+        if sectors.iter().all(generated) {
+            info!("skipping layer {}, already generated", layer);
+            LayerProgress::skip(options, layer, layers, graph.size() as u64);
+            continue;
+        }
+
+        parents_cache.reset()?;
+        let mut progress = LayerProgress::start(options, layer, layers, 0, graph.size() as u64);
+        for chunk_start in (0..graph.size()).step_by(parents.len()) {
+            let chunk_len = parents.len().min(graph.size() - chunk_start);
+            for (i, node_parents) in parents[..chunk_len].iter_mut().enumerate() {
+                let node = chunk_start + i;
+                // The first node has no parents.
+                if node > 0 {
+                    *node_parents = parents_cache.read(node as u32)?;
+                }
+            }
+
+            let chunk_parents = &parents[..chunk_len];
+            sectors
+                .par_iter_mut()
+                .filter(|sector| !generated(sector))
+                .for_each(|sector| {
+                    for (i, node_parents) in chunk_parents.iter().enumerate() {
+                        create_label(graph, sector, layer, chunk_start + i, node_parents);
+                    }
+                });
+            progress.update((chunk_start + chunk_len) as u64);
+        }
+
+        sectors
+            .par_iter_mut()
+            .filter(|sector| !generated(sector))
+            .try_for_each(|sector| -> Result<()> {
+                let layer_config = &sector.layer_states[layer - 1].config;
+                write_layer(&sector.layer_labels, layer_config)
+                    .context("failed to store labels")?;
+                info!(
+                    "  generated layer {} store with id {}",
+                    layer, layer_config.id
+                );
+
+                mem::swap(&mut sector.layer_labels, &mut sector.exp_labels);
+                Ok(())
+            })?;
+        progress.finish();
+    }
+
+    Ok(sectors
+        .into_iter()
+        .map(|sector| {
+            (
+                Labels::<Tree> {
+                    labels: sector
+                        .layer_states
+                        .iter()
+                        .map(|s| s.config.clone())
+                        .collect(),
+                    _h: PhantomData,
+                },
+                sector.layer_states,
+            )
+        })
+        .collect())
+}
+
+/// Labels `node` of a sector, the same way `single::create_label(_exp)` does, with its parents
+/// already read from the cache.
+fn create_label<H: Hasher, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<H>,
+    sector: &mut SectorLabels<'_, T>,
+    layer_index: usize,
+    node: usize,
+    parents: &[u32; DEGREE],
+) {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 32];
+
+    buffer[..4].copy_from_slice(&(layer_index as u32).to_be_bytes());
+    buffer[4..12].copy_from_slice(&(node as u64).to_be_bytes());
+    hasher.input(&[sector.replica_id.as_ref(), &buffer[..]][..]);
+
+    // hash parents for all non 0 nodes
+    let hash = if node == 0 {
+        hasher.finish()
+    } else if layer_index == 1 {
+        graph.copy_parents_data_inner(parents, &sector.layer_labels, hasher)
+    } else {
+        graph.copy_parents_data_inner_exp(parents, &sector.layer_labels, &sector.exp_labels, hasher)
+    };
+
+    // store the newly generated key
+    let start = data_at_node_offset(node);
+    let end = start + NODE_SIZE;
+    sector.layer_labels[start..end].copy_from_slice(&hash[..]);
+
+    // strip last two bits, to ensure result is in Fr.
+    sector.layer_labels[end - 1] &= 0b0011_1111;
+}
@@ -0,0 +1,234 @@
+//! Checkpoints of the multicore labeling within a layer.
+//!
+//! Every `FIL_PROOFS_MULTICORE_SDR_CHECKPOINT_NODES` nodes, the labels computed since the last
+//! checkpoint are written to `sdr-checkpoint.dat` in the cache directory, and the progress is
+//! recorded in `sdr-checkpoint.json`. When the labeling is restarted after a crash, the layer is
+//! resumed from the last checkpoint instead of its first node. Completed layers are found on disk
+//! as before, see `prepare_layers`.
+//!
+//! Checkpoints are written and synced off the labeling thread. They are best effort: failures are
+//! logged, the checkpoint is removed, and the labeling continues without them.
+
+use std::fs::{self, remove_file, rename, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use storage_proofs_core::util::NODE_SIZE;
+
+const DATA_FILE: &str = "sdr-checkpoint.dat";
+const PROGRESS_FILE: &str = "sdr-checkpoint.json";
+
+/// The progress recorded by the last checkpoint.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct LabelProgress {
+    /// The hex encoded replica id of the labeling.
+    replica_id: String,
+    /// The layer being labeled, starting at 1.
+    layer: usize,
+    /// The number of nodes of the layer whose labels are in the data file.
+    nodes: u64,
+}
+
+/// The checkpoints of the labeling of a replica in a cache directory.
+#[derive(Debug)]
+pub struct LabelCheckpoint {
+    data_path: PathBuf,
+    progress_path: PathBuf,
+    replica_id: String,
+    interval: u64,
+}
+
+impl LabelCheckpoint {
+    /// Checkpoints taken every `interval` nodes in `cache_dir`.
+    pub fn new(cache_dir: &Path, replica_id: &[u8], interval: u64) -> Self {
+        LabelCheckpoint {
+            data_path: cache_dir.join(DATA_FILE),
+            progress_path: cache_dir.join(PROGRESS_FILE),
+            replica_id: hex::encode(replica_id),
+            interval,
+        }
+    }
+
+    /// The number of nodes labeled between two checkpoints.
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Loads the labels of `layer` saved by an interrupted labeling of the same replica into
+    /// `labels`, and returns the number of nodes loaded. Returns `0` (and removes the checkpoint)
+    /// if there is none for the layer, or it can't be used.
+    pub fn resume(&self, layer: usize, labels: &mut [u8]) -> u64 {
+        match self.try_resume(layer, labels) {
+            Ok(nodes) => {
+                if nodes > 0 {
+                    info!("resuming layer {} from checkpoint at node {}", layer, nodes);
+                } else {
+                    self.clear();
+                }
+                nodes
+            }
+            Err(err) => {
+                warn!("failed to resume from checkpoint: {:?}", err);
+                self.clear();
+                0
+            }
+        }
+    }
+
+    fn try_resume(&self, layer: usize, labels: &mut [u8]) -> Result<u64> {
+        if !self.progress_path.exists() {
+            return Ok(0);
+        }
+
+        let progress = fs::read(&self.progress_path).context("failed to read progress")?;
+        let progress: LabelProgress =
+            serde_json::from_slice(&progress).context("failed to parse progress")?;
+        if progress.replica_id != self.replica_id || progress.layer != layer {
+            return Ok(0);
+        }
+
+        let len = progress.nodes as usize * NODE_SIZE;
+        ensure!(
+            len < labels.len(),
+            "checkpoint of {} nodes exceeds the layer",
+            progress.nodes
+        );
+        let mut file = File::open(&self.data_path).context("failed to open checkpoint data")?;
+        file.read_exact(&mut labels[..len])
+            .context("failed to read checkpoint data")?;
+
+        Ok(progress.nodes)
+    }
+
+    /// Saves the labels of `layer` up to `nodes`. `labels` holds the labels of the layer, of which
+    /// those from `saved_nodes`, the nodes of the previous checkpoint of the layer, are written.
+    /// If saving fails, the previous checkpoint is removed as well, as its data may be overwritten.
+    pub fn save(&self, layer: usize, labels: &[u8], saved_nodes: u64, nodes: u64) -> Result<()> {
+        let result = self.try_save(layer, labels, saved_nodes, nodes);
+        if result.is_err() {
+            self.clear();
+        }
+        result
+    }
+
+    fn try_save(&self, layer: usize, labels: &[u8], saved_nodes: u64, nodes: u64) -> Result<()> {
+        let start = saved_nodes as usize * NODE_SIZE;
+        let end = nodes as usize * NODE_SIZE;
+        ensure!(
+            start <= end && end <= labels.len(),
+            "invalid checkpoint range"
+        );
+
+        // The data file may be longer than the progress recorded, if a save was interrupted, so
+        // it is written at the position of the previous checkpoint and truncated.
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&self.data_path)
+            .context("failed to open checkpoint data")?;
+        file.seek(SeekFrom::Start(start as u64))?;
+        file.write_all(&labels[start..end])
+            .context("failed to write checkpoint data")?;
+        file.set_len(end as u64)?;
+        file.sync_data()?;
+
+        let progress = LabelProgress {
+            replica_id: self.replica_id.clone(),
+            layer,
+            nodes,
+        };
+        let tmp_path = self.progress_path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&progress)?).context("failed to write progress")?;
+        rename(tmp_path, &self.progress_path).context("failed to rename progress")?;
+
+        Ok(())
+    }
+
+    /// Removes the checkpoint, once the layer it belongs to is stored.
+    pub fn clear(&self) {
+        for path in &[&self.progress_path, &self.data_path] {
+            if path.exists() {
+                if let Err(err) = remove_file(path) {
+                    warn!("failed to delete checkpoint file {:?}: {}", path, err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let cache_dir = tempdir().expect("tempdir failure");
+        let checkpoint = LabelCheckpoint::new(cache_dir.path(), &[1u8; 32], 2);
+        let labels: Vec<u8> = (0..8 * NODE_SIZE).map(|i| i as u8).collect();
+        let mut resumed = vec![0u8; labels.len()];
+
+        assert_eq!(checkpoint.resume(2, &mut resumed), 0);
+
+        checkpoint
+            .save(2, &labels, 0, 2)
+            .expect("checkpoint save failure");
+        checkpoint
+            .save(2, &labels, 2, 5)
+            .expect("checkpoint save failure");
+        assert_eq!(checkpoint.resume(2, &mut resumed), 5);
+        assert_eq!(&resumed[..5 * NODE_SIZE], &labels[..5 * NODE_SIZE]);
+        assert!(resumed[5 * NODE_SIZE..].iter().all(|byte| *byte == 0));
+
+        // Another replica does not resume from it, and removes it.
+        let other = LabelCheckpoint::new(cache_dir.path(), &[2u8; 32], 2);
+        assert_eq!(other.resume(2, &mut resumed), 0);
+        assert_eq!(checkpoint.resume(2, &mut resumed), 0);
+    }
+
+    #[test]
+    fn test_checkpoint_failed_save() {
+        let cache_dir = tempdir().expect("tempdir failure");
+        let checkpoint = LabelCheckpoint::new(cache_dir.path(), &[1u8; 32], 2);
+        let labels: Vec<u8> = (0..8 * NODE_SIZE).map(|i| i as u8).collect();
+
+        checkpoint
+            .save(1, &labels, 0, 2)
+            .expect("checkpoint save failure");
+        assert!(checkpoint.save(1, &labels, 2, 9).is_err());
+
+        // The previous checkpoint is not resumed from.
+        assert!(!checkpoint.progress_path.exists());
+        let mut resumed = vec![0u8; labels.len()];
+        assert_eq!(checkpoint.resume(1, &mut resumed), 0);
+    }
+
+    #[test]
+    fn test_checkpoint_interrupted_save() {
+        let cache_dir = tempdir().expect("tempdir failure");
+        let checkpoint = LabelCheckpoint::new(cache_dir.path(), &[1u8; 32], 2);
+        let labels: Vec<u8> = (0..8 * NODE_SIZE).map(|i| i as u8).collect();
+
+        checkpoint
+            .save(1, &labels, 0, 2)
+            .expect("checkpoint save failure");
+        // Data written past the recorded progress, as by a save interrupted before its progress.
+        fs::write(&checkpoint.data_path, &labels[..6 * NODE_SIZE]).expect("write failure");
+        let mut resumed = vec![0u8; labels.len()];
+        assert_eq!(checkpoint.resume(1, &mut resumed), 2);
+
+        let mut labels = labels;
+        labels[2 * NODE_SIZE..]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xff);
+        checkpoint
+            .save(1, &labels, 2, 4)
+            .expect("checkpoint save failure");
+        let data = fs::read(&checkpoint.data_path).expect("read failure");
+        assert_eq!(&data[..], &labels[..4 * NODE_SIZE]);
+    }
+}
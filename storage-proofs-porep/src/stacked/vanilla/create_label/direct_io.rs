@@ -0,0 +1,215 @@
+//! Layer writes with direct I/O.
+//!
+//! A layer written through the page cache fills it with data which is only read again by PC2, and
+//! evicts the parent cache and the trees of other sectors on the way. With the `direct` layer
+//! write mode, layers are written with `O_DIRECT` instead, bypassing the page cache. The
+//! `io_uring` mode (with the `io-uring` feature) also submits the writes through io_uring, keeping
+//! several of them in flight while the next chunk is copied.
+//!
+//! Direct I/O requires buffers, offsets and lengths aligned to the logical block size of the
+//! device, so the data is copied through aligned buffers (unless it is aligned already), and a tail
+//! which isn't a whole number of blocks is written through the page cache.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use mapr::{MmapMut, MmapOptions};
+
+/// The alignment of direct I/O, which covers devices with blocks of up to 4KiB.
+const ALIGNMENT: usize = 4096;
+
+/// Bytes written at once.
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Writes in flight at once through io_uring.
+#[cfg(feature = "io-uring")]
+const QUEUE_DEPTH: usize = 4;
+
+/// Writes `data` to the file at `path` with direct I/O, submitting the writes through io_uring if
+/// `use_io_uring` is set.
+pub fn write(path: &Path, data: &[u8], use_io_uring: bool) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+        .with_context(|| format!("could not open {} for direct I/O", path.display()))?;
+
+    let aligned_len = data.len() / ALIGNMENT * ALIGNMENT;
+    if use_io_uring {
+        write_io_uring(&file, &data[..aligned_len])?;
+    } else {
+        write_aligned(&file, &data[..aligned_len])?;
+    }
+
+    if aligned_len < data.len() {
+        clear_direct(&file)?;
+        file.write_all_at(&data[aligned_len..], aligned_len as u64)?;
+    }
+
+    Ok(())
+}
+
+/// Writes `data`, a whole number of blocks, from the start of `file`.
+fn write_aligned(file: &File, data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // Data in aligned memory, e.g. an mmapped layer, is written as is.
+    let mut buffer = if data.as_ptr() as usize % ALIGNMENT == 0 {
+        None
+    } else {
+        Some(aligned_buffer(CHUNK_SIZE.min(data.len()))?)
+    };
+    for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        let offset = (i * CHUNK_SIZE) as u64;
+        match buffer {
+            Some(ref mut buffer) => {
+                buffer[..chunk.len()].copy_from_slice(chunk);
+                file.write_all_at(&buffer[..chunk.len()], offset)?;
+            }
+            None => file.write_all_at(chunk, offset)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `data`, a whole number of blocks, from the start of `file` through io_uring.
+#[cfg(feature = "io-uring")]
+fn write_io_uring(file: &File, data: &[u8]) -> Result<()> {
+    use anyhow::anyhow;
+    use io_uring::{opcode, types, IoUring};
+
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // The ring is dropped before the buffers, which cancels the writes still in flight on errors.
+    let mut buffers = (0..QUEUE_DEPTH)
+        .map(|_| aligned_buffer(CHUNK_SIZE))
+        .collect::<Result<Vec<_>>>()?;
+    let mut lens = [0usize; QUEUE_DEPTH];
+    let mut free: Vec<usize> = (0..QUEUE_DEPTH).collect();
+    let mut ring = IoUring::new(QUEUE_DEPTH as u32)?;
+
+    // Waits for a write to complete, returning the buffer it used.
+    let complete = |ring: &mut IoUring, lens: &[usize]| -> Result<usize> {
+        ring.submit_and_wait(1)?;
+        let entry = ring.completion().next().context("no io_uring completion")?;
+        let slot = entry.user_data() as usize;
+        ensure!(
+            entry.result() >= 0,
+            "io_uring write failed: {}",
+            io::Error::from_raw_os_error(-entry.result())
+        );
+        ensure!(
+            entry.result() as usize == lens[slot],
+            "short io_uring write of {} bytes instead of {}",
+            entry.result(),
+            lens[slot]
+        );
+        Ok(slot)
+    };
+
+    for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        if free.is_empty() {
+            free.push(complete(&mut ring, &lens)?);
+        }
+        let slot = free.pop().expect("no free buffer");
+        buffers[slot][..chunk.len()].copy_from_slice(chunk);
+        lens[slot] = chunk.len();
+
+        let entry = opcode::Write::new(
+            types::Fd(file.as_raw_fd()),
+            buffers[slot].as_ptr(),
+            chunk.len() as u32,
+        )
+        .offset((i * CHUNK_SIZE) as i64)
+        .build()
+        .user_data(slot as u64);
+        unsafe {
+            ring.submission()
+                .push(&entry)
+                .map_err(|_| anyhow!("io_uring submission queue is full"))?;
+        }
+        ring.submit()?;
+    }
+    while free.len() < QUEUE_DEPTH {
+        free.push(complete(&mut ring, &lens)?);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn write_io_uring(_file: &File, _data: &[u8]) -> Result<()> {
+    anyhow::bail!("built without the io-uring feature")
+}
+
+/// Page aligned memory of `len` bytes.
+fn aligned_buffer(len: usize) -> Result<MmapMut> {
+    Ok(MmapOptions::new().len(len).map_anon()?)
+}
+
+/// Clears `O_DIRECT` of `file`, so that a tail which isn't a whole number of blocks can be written.
+fn clear_direct(file: &File) -> Result<()> {
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    ensure!(flags >= 0, "fcntl failed: {}", io::Error::last_os_error());
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_DIRECT) };
+    ensure!(result == 0, "fcntl failed: {}", io::Error::last_os_error());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let path = dir.path().join("layer");
+
+        // e.g. tmpfs doesn't support direct I/O, there is nothing to test on it.
+        let supported = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&path)
+            .is_ok();
+        if !supported {
+            return;
+        }
+
+        let lens = [
+            0,
+            1000,
+            ALIGNMENT,
+            3 * ALIGNMENT + 32,
+            CHUNK_SIZE + ALIGNMENT,
+        ];
+        for len in lens.iter() {
+            // Unaligned data, which is copied through aligned buffers.
+            let buffer: Vec<u8> = (0..len + 1).map(|x| x as u8).collect();
+            let data = &buffer[1..];
+
+            write(&path, data, false).expect("direct write failed");
+            assert_eq!(fs::read(&path).expect("read failure"), data);
+
+            #[cfg(feature = "io-uring")]
+            {
+                write(&path, data, true).expect("io_uring write failed");
+                assert_eq!(fs::read(&path).expect("read failure"), data);
+            }
+        }
+    }
+}
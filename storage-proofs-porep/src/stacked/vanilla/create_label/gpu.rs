@@ -0,0 +1,193 @@
+//! Labeling of a batch of sectors on the GPU.
+//!
+//! The label of a node depends on the label of the node before it, so a single sector can't be
+//! labeled in parallel. Like the CPU batch labeling, the sectors of a batch are labeled in
+//! lockstep instead, by one work item per sector running the kernel in `sdr.cl`. Both layers of all
+//! sectors are kept in GPU memory, and the parents of a chunk of nodes are read from the parent
+//! cache once and uploaded for the whole batch. Every layer is read back and written to disk as a
+//! regular file, so the CPU labeling continues from the completed layers if the GPU fails.
+//!
+//! As the base parents of a node are spread over its layer and the expander parents over the
+//! previous one, the layers can't be streamed through the GPU in windows. A batch therefore needs
+//! twice its layer size of GPU memory, 64GiB per 32GiB sector, which limits this backend to small
+//! sectors (e.g. for tests) on current GPUs.
+
+use std::marker::PhantomData;
+use std::mem;
+
+use anyhow::{ensure, Context, Result};
+use log::info;
+use merkletree::store::StoreConfig;
+use rust_gpu_tools::{opencl, Device};
+use storage_proofs_core::{control, drgraph::Graph, merkle::MerkleTreeTrait, util::NODE_SIZE};
+
+use crate::stacked::vanilla::{
+    cache::ParentCache,
+    create_label::{
+        prepare_layers, progress::LayerProgress, read_layer, write_layer, LabelingOptions,
+    },
+    graph::DEGREE,
+    proof::LayerState,
+    Labels, StackedBucketGraph,
+};
+
+const KERNEL_SOURCE: &str = include_str!("sdr.cl");
+
+/// Number of nodes labeled by a kernel launch, whose parents are uploaded at once.
+const PARENTS_CHUNK_NODES: usize = 1 << 16;
+
+const LOCAL_WORK_SIZE: usize = 64;
+
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]>>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &mut ParentCache,
+    layers: usize,
+    replica_ids: &[T],
+    configs: Vec<StoreConfig>,
+    options: &LabelingOptions,
+) -> Result<Vec<(Labels<Tree>, Vec<LayerState>)>> {
+    ensure!(
+        replica_ids.len() == configs.len(),
+        "a config is required for every replica id"
+    );
+    ensure!(
+        replica_ids.iter().all(|id| id.as_ref().len() == 32),
+        "replica ids must be 32 bytes"
+    );
+
+    let sectors = replica_ids.len();
+    let nodes = graph.size();
+    let layer_size = nodes * NODE_SIZE;
+    let batch_size = sectors * layer_size;
+    let chunk_nodes = PARENTS_CHUNK_NODES.min(nodes);
+
+    let device = *Device::all().first().context("no GPU found")?;
+    let required = 2 * batch_size + chunk_nodes * DEGREE * 4 + sectors * 32;
+    ensure!(
+        device.memory() >= required as u64,
+        "labeling {} sectors needs {} bytes of GPU memory, {} has {}",
+        sectors,
+        required,
+        device.name(),
+        device.memory()
+    );
+    // The parents are gathered and the layers written on cores next to the GPU.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+    let _cores = crate::stacked::vanilla::cores::bind_near(
+        &crate::stacked::vanilla::cores::CoreLocality::PciDevice(format!("{}.0", device.pci_id())),
+    );
+    let program = opencl::Program::from_opencl(
+        device
+            .opencl_device()
+            .context("GPU does not support OpenCL")?,
+        KERNEL_SOURCE,
+    )?;
+    info!(
+        "generate labels for a batch of {} sectors on {}",
+        sectors,
+        device.name()
+    );
+
+    let layer_states: Vec<Vec<LayerState>> = configs
+        .iter()
+        .map(|config| prepare_layers::<Tree>(graph, config, layers))
+        .collect();
+
+    let replica_bytes: Vec<u8> = replica_ids
+        .iter()
+        .flat_map(|id| id.as_ref().iter().copied())
+        .collect();
+    let replica_buffer = program.create_buffer_from_slice(&replica_bytes)?;
+    let mut layer_buffer = unsafe { program.create_buffer::<u8>(batch_size)? };
+    let mut exp_buffer = unsafe { program.create_buffer::<u8>(batch_size)? };
+    let mut parents_buffer = unsafe { program.create_buffer::<u32>(chunk_nodes * DEGREE)? };
+
+    // The labels of all sectors, as read back from or to be uploaded to the GPU.
+    let mut labels = vec![0u8; batch_size];
+    let mut parents = vec![0u32; chunk_nodes * DEGREE];
+    // Whether the previous layer was loaded from disk and has yet to be uploaded.
+    let mut upload_exp_labels = false;
+    let work_groups = (sectors + LOCAL_WORK_SIZE - 1) / LOCAL_WORK_SIZE;
+
+    for layer in 1..=layers {
+        info!("generating layer: {}", layer);
+        control::checkpoint(&format!("layer {}", layer))?;
+
+        // Sectors which already have this layer on disk are labeled again with the others, which
+        // yields the same labels, unless all of them have it.
+        if layer_states
+            .iter()
+            .all(|states| states[layer - 1].generated)
+        {
+            info!("skipping layer {}, already generated", layer);
+            LayerProgress::skip(options, layer, layers, nodes as u64);
+            for (states, sector_labels) in layer_states.iter().zip(labels.chunks_mut(layer_size)) {
+                read_layer(&states[layer - 1].config, sector_labels)?;
+            }
+            upload_exp_labels = true;
+            continue;
+        }
+        if upload_exp_labels {
+            program.write_from_buffer(&mut exp_buffer, &labels)?;
+            upload_exp_labels = false;
+        }
+
+        parents_cache.reset()?;
+        let mut progress = LayerProgress::start(options, layer, layers, 0, nodes as u64);
+        for chunk_start in (0..nodes).step_by(chunk_nodes) {
+            let chunk_len = chunk_nodes.min(nodes - chunk_start);
+            for (i, node_parents) in parents.chunks_mut(DEGREE).take(chunk_len).enumerate() {
+                let node = chunk_start + i;
+                // The first node has no parents.
+                if node > 0 {
+                    node_parents.copy_from_slice(&parents_cache.read(node as u32)?);
+                }
+            }
+            program.write_from_buffer(&mut parents_buffer, &parents)?;
+
+            program
+                .create_kernel("label_nodes", work_groups, LOCAL_WORK_SIZE)?
+                .arg(&layer_buffer)
+                .arg(&exp_buffer)
+                .arg(&parents_buffer)
+                .arg(&replica_buffer)
+                .arg(&(sectors as u32))
+                .arg(&(nodes as u64))
+                .arg(&(layer as u32))
+                .arg(&(chunk_start as u64))
+                .arg(&(chunk_len as u32))
+                .run()?;
+            progress.update((chunk_start + chunk_len) as u64);
+        }
+
+        program.read_into_buffer(&layer_buffer, &mut labels)?;
+        for (states, sector_labels) in layer_states.iter().zip(labels.chunks(layer_size)) {
+            if states[layer - 1].generated {
+                continue;
+            }
+            let layer_config = &states[layer - 1].config;
+            write_layer(sector_labels, layer_config).context("failed to store labels")?;
+            info!(
+                "  generated layer {} store with id {}",
+                layer, layer_config.id
+            );
+        }
+
+        mem::swap(&mut layer_buffer, &mut exp_buffer);
+        progress.finish();
+    }
+
+    Ok(layer_states
+        .into_iter()
+        .map(|states| {
+            (
+                Labels::<Tree> {
+                    labels: states.iter().map(|s| s.config.clone()).collect(),
+                    _h: PhantomData,
+                },
+                states,
+            )
+        })
+        .collect())
+}
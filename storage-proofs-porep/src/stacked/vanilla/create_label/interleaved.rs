@@ -0,0 +1,276 @@
+//! Labeling of a batch of sectors in lockstep, with the labels of the sectors interleaved.
+//!
+//! Like in `batch`, the parents of a chunk of nodes are read from the parent cache once for the
+//! whole batch. In addition, the sectors are labeled in groups of `interleave` sectors, whose
+//! layers are laid out node by node in memory: the labels of a node for all sectors of a group are
+//! next to each other. The parents of a node are spread over the whole layer, and reading the
+//! labels of a parent then reads them for all sectors of the group at once, so the cache and TLB
+//! misses of these random reads are shared by the group. Each group is labeled on its own core, so
+//! larger groups trade parallelism for fewer misses per sector. Layers are written to disk as
+//! regular files, one per sector.
+
+use std::marker::PhantomData;
+use std::mem;
+
+use anyhow::{ensure, Context, Result};
+use log::info;
+use merkletree::store::StoreConfig;
+use rayon::prelude::{IntoParallelRefMutIterator, ParallelIterator};
+use sha2raw::Sha256;
+use storage_proofs_core::{
+    control,
+    drgraph::{Graph, BASE_DEGREE},
+    merkle::MerkleTreeTrait,
+    util::NODE_SIZE,
+};
+
+use crate::stacked::vanilla::{
+    cache::ParentCache,
+    create_label::{
+        prepare_layers, progress::LayerProgress, read_layer, write_layer, LabelingOptions,
+    },
+    graph::{hash_base_parents, hash_exp_parents, DEGREE},
+    proof::LayerState,
+    Labels, StackedBucketGraph,
+};
+
+/// Number of nodes whose parents are read at once, for all sectors of the batch.
+const PARENTS_CHUNK_NODES: usize = 1 << 14;
+
+/// Bytes prefetched at once, a cache line.
+const PREFETCH_BYTES: usize = 64;
+
+/// Sectors labeled together, with their labels interleaved.
+struct SectorGroup<'a, T> {
+    replica_ids: &'a [T],
+    layer_states: Vec<Vec<LayerState>>,
+    /// The label of node `n` of the `s`th sector is at `(n * replica_ids.len() + s) * NODE_SIZE`.
+    layer_labels: Vec<u8>,
+    exp_labels: Vec<u8>,
+    /// A layer of a single sector, as it is stored on disk.
+    sector_labels: Vec<u8>,
+}
+
+impl<'a, T: AsRef<[u8]>> SectorGroup<'a, T> {
+    /// Whether all sectors of the group have `layer` on disk.
+    fn generated(&self, layer: usize) -> bool {
+        self.layer_states
+            .iter()
+            .all(|states| states[layer - 1].generated)
+    }
+
+    /// Loads `layer` of all sectors from disk, as the next layer needs it.
+    fn read_layer(&mut self, layer: usize) -> Result<()> {
+        let sectors = self.replica_ids.len();
+        for (sector, states) in self.layer_states.iter().enumerate() {
+            read_layer(&states[layer - 1].config, &mut self.sector_labels)?;
+            for (node, label) in self.sector_labels.chunks_exact(NODE_SIZE).enumerate() {
+                let start = (node * sectors + sector) * NODE_SIZE;
+                self.exp_labels[start..start + NODE_SIZE].copy_from_slice(label);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores `layer` of the sectors which don't have it on disk yet.
+    fn write_layer(&mut self, layer: usize) -> Result<()> {
+        let sectors = self.replica_ids.len();
+        for (sector, states) in self.layer_states.iter().enumerate() {
+            let layer_state = &states[layer - 1];
+            if layer_state.generated {
+                continue;
+            }
+            for (node, label) in self.sector_labels.chunks_exact_mut(NODE_SIZE).enumerate() {
+                let start = (node * sectors + sector) * NODE_SIZE;
+                label.copy_from_slice(&self.layer_labels[start..start + NODE_SIZE]);
+            }
+            write_layer(&self.sector_labels, &layer_state.config)
+                .context("failed to store labels")?;
+            info!(
+                "  generated layer {} store with id {}",
+                layer, layer_state.config.id
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Labels `node` of all sectors, the same way `single::create_label(_exp)` does, with its
+    /// parents already read from the cache. Sectors which have the layer on disk yield the same
+    /// labels again.
+    fn create_labels(&mut self, layer_index: usize, node: usize, parents: &[u32; DEGREE]) {
+        let sectors = self.replica_ids.len();
+        let degree = if layer_index == 1 {
+            BASE_DEGREE
+        } else {
+            DEGREE
+        };
+
+        // The labels of a parent for all sectors are read right after each other.
+        if node > 0 {
+            for (i, parent) in parents[..degree].iter().enumerate() {
+                let data = if i < BASE_DEGREE {
+                    &self.layer_labels
+                } else {
+                    &self.exp_labels
+                };
+                let start = *parent as usize * sectors * NODE_SIZE;
+                for line in data[start..start + sectors * NODE_SIZE].chunks(PREFETCH_BYTES) {
+                    prefetch!(line.as_ptr() as *const i8);
+                }
+            }
+        }
+
+        let mut buffer = [0u8; 32];
+        buffer[..4].copy_from_slice(&(layer_index as u32).to_be_bytes());
+        buffer[4..12].copy_from_slice(&(node as u64).to_be_bytes());
+
+        for (sector, replica_id) in self.replica_ids.iter().enumerate() {
+            let mut hasher = Sha256::new();
+            hasher.input(&[replica_id.as_ref(), &buffer[..]][..]);
+
+            // hash parents for all non 0 nodes
+            let hash = if node == 0 {
+                hasher.finish()
+            } else if layer_index == 1 {
+                let mut labels: [&[u8]; BASE_DEGREE] = [&[]; BASE_DEGREE];
+                for (label, parent) in labels.iter_mut().zip(parents.iter()) {
+                    *label = read_label(&self.layer_labels, sectors, sector, *parent);
+                }
+                hash_base_parents(&labels, hasher)
+            } else {
+                let mut labels: [&[u8]; DEGREE] = [&[]; DEGREE];
+                for (i, (label, parent)) in labels.iter_mut().zip(parents.iter()).enumerate() {
+                    let data = if i < BASE_DEGREE {
+                        &self.layer_labels
+                    } else {
+                        &self.exp_labels
+                    };
+                    *label = read_label(data, sectors, sector, *parent);
+                }
+                hash_exp_parents(&labels, hasher)
+            };
+
+            // store the newly generated key
+            let start = (node * sectors + sector) * NODE_SIZE;
+            let end = start + NODE_SIZE;
+            self.layer_labels[start..end].copy_from_slice(&hash[..]);
+
+            // strip last two bits, to ensure result is in Fr.
+            self.layer_labels[end - 1] &= 0b0011_1111;
+        }
+    }
+}
+
+/// The label of `node` of the `sector`th of `sectors` interleaved sectors.
+#[inline]
+fn read_label(data: &[u8], sectors: usize, sector: usize, node: u32) -> &[u8] {
+    let start = (node as usize * sectors + sector) * NODE_SIZE;
+    &data[start..start + NODE_SIZE]
+}
+
+#[allow(clippy::type_complexity)]
+pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]> + Sync>(
+    graph: &StackedBucketGraph<Tree::Hasher>,
+    parents_cache: &mut ParentCache,
+    layers: usize,
+    replica_ids: &[T],
+    configs: Vec<StoreConfig>,
+    interleave: usize,
+    options: &LabelingOptions,
+) -> Result<Vec<(Labels<Tree>, Vec<LayerState>)>> {
+    ensure!(
+        replica_ids.len() == configs.len(),
+        "a config is required for every replica id"
+    );
+    ensure!(interleave > 0, "at least one sector must be interleaved");
+    info!(
+        "generate labels for a batch of {} sectors, interleaving {}",
+        replica_ids.len(),
+        interleave
+    );
+
+    let nodes = graph.size();
+    let layer_size = nodes * NODE_SIZE;
+    let mut groups: Vec<SectorGroup<'_, T>> = replica_ids
+        .chunks(interleave)
+        .zip(configs.chunks(interleave))
+        .map(|(replica_ids, configs)| SectorGroup {
+            replica_ids,
+            layer_states: configs
+                .iter()
+                .map(|config| prepare_layers::<Tree>(graph, config, layers))
+                .collect(),
+            layer_labels: vec![0u8; replica_ids.len() * layer_size],
+            exp_labels: vec![0u8; replica_ids.len() * layer_size],
+            sector_labels: vec![0u8; layer_size],
+        })
+        .collect();
+
+    let mut parents = vec![[0u32; DEGREE]; PARENTS_CHUNK_NODES.min(nodes)];
+    for layer in 1..=layers {
+        info!("generating layer: {}", layer);
+        control::checkpoint(&format!("layer {}", layer))?;
+
+        // Groups which already have this layer on disk load it, as the next layer needs it. The
+        // other groups label all of their sectors.
+        groups
+            .par_iter_mut()
+            .filter(|group| group.generated(layer))
+            .try_for_each(|group| group.read_layer(layer))?;
+        if groups.iter().all(|group| group.generated(layer)) {
+            info!("skipping layer {}, already generated", layer);
+            LayerProgress::skip(options, layer, layers, nodes as u64);
+            continue;
+        }
+
+        parents_cache.reset()?;
+        let mut progress = LayerProgress::start(options, layer, layers, 0, nodes as u64);
+        for chunk_start in (0..nodes).step_by(parents.len()) {
+            let chunk_len = parents.len().min(nodes - chunk_start);
+            for (i, node_parents) in parents[..chunk_len].iter_mut().enumerate() {
+                let node = chunk_start + i;
+                // The first node has no parents.
+                if node > 0 {
+                    *node_parents = parents_cache.read(node as u32)?;
+                }
+            }
+
+            let chunk_parents = &parents[..chunk_len];
+            groups
+                .par_iter_mut()
+                .filter(|group| !group.generated(layer))
+                .for_each(|group| {
+                    for (i, node_parents) in chunk_parents.iter().enumerate() {
+                        group.create_labels(layer, chunk_start + i, node_parents);
+                    }
+                });
+            progress.update((chunk_start + chunk_len) as u64);
+        }
+
+        groups
+            .par_iter_mut()
+            .filter(|group| !group.generated(layer))
+            .try_for_each(|group| -> Result<()> {
+                group.write_layer(layer)?;
+                mem::swap(&mut group.layer_labels, &mut group.exp_labels);
+                Ok(())
+            })?;
+        progress.finish();
+    }
+
+    Ok(groups
+        .into_iter()
+        .flat_map(|group| group.layer_states)
+        .map(|layer_states| {
+            (
+                Labels::<Tree> {
+                    labels: layer_states.iter().map(|s| s.config.clone()).collect(),
+                    _h: PhantomData,
+                },
+                layer_states,
+            )
+        })
+        .collect())
+}
@@ -1,19 +1,68 @@
+use std::fmt;
 use std::fs::{self, create_dir_all, remove_file, rename, File};
 use std::io::{self, BufReader};
+use std::path::Path;
 
-use anyhow::Context;
-use filecoin_hashers::Hasher;
+use anyhow::{ensure, Context};
+use filecoin_hashers::{Domain, Hasher};
 use log::{info, warn};
 use merkletree::{merkle::Element, store::StoreConfig};
+use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSlice, ParallelSliceMut};
 use storage_proofs_core::{
-    cache_key::CacheKey, drgraph::Graph, error::Result, merkle::MerkleTreeTrait,
+    cache_key::CacheKey,
+    control,
+    drgraph::Graph,
+    error::Result,
+    events::{self, Event},
+    merkle::MerkleTreeTrait,
+    tenant,
+    util::NODE_SIZE,
 };
 
-use crate::stacked::vanilla::{proof::LayerState, StackedBucketGraph};
+use crate::{
+    encode::encode,
+    stacked::vanilla::{proof::LayerState, StackedBucketGraph},
+};
 
-#[cfg(feature = "multicore-sdr")]
+pub mod batch;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+pub mod checkpoint;
+#[cfg(target_os = "linux")]
+mod direct_io;
+#[cfg(feature = "gpu-sdr")]
+pub mod gpu;
+pub mod interleaved;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
 pub mod multi;
+pub mod progress;
 pub mod single;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+pub mod sizing;
+pub mod strategy;
+
+/// Options of the labeling of sectors, see `StackedDrg::replicate_phase1_with_options`.
+#[derive(Clone, Default)]
+pub struct LabelingOptions {
+    /// Called with the progress of the labeling, see `progress`.
+    pub progress: Option<progress::ProgressCallback>,
+    /// Whether the sector is labeled by the multicore SDR, see `strategy`.
+    pub strategy: strategy::LabelingStrategy,
+    /// Overrides of the buffer sizes of the multicore SDR, see `sizing`.
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+    pub sizing: sizing::MulticoreSdrSizing,
+}
+
+impl fmt::Debug for LabelingOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("LabelingOptions");
+        debug
+            .field("progress", &self.progress.is_some())
+            .field("strategy", &self.strategy);
+        #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+        debug.field("sizing", &self.sizing);
+        debug.finish()
+    }
+}
 
 /// Prepares the necessary `StoreConfig`s with which the layers are stored.
 /// Also checks for already existing layers and marks them as such.
@@ -55,12 +104,84 @@ pub fn write_layer(data: &[u8], config: &StoreConfig) -> Result<()> {
     if let Some(parent) = data_path.parent() {
         create_dir_all(parent).context("failed to create parent directories")?;
     }
-    fs::write(&tmp_data_path, data).context("failed to write layer data")?;
+    write_layer_data(&tmp_data_path, data)?;
     rename(tmp_data_path, data_path).context("failed to rename tmp data")?;
 
     Ok(())
 }
 
+/// How layers are written to disk, from the `layer_write_mode` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LayerWriteMode {
+    /// Through the page cache.
+    Buffered,
+    /// With `O_DIRECT`, bypassing the page cache.
+    Direct,
+    /// With `O_DIRECT`, submitting the writes through io_uring.
+    IoUring,
+}
+
+impl LayerWriteMode {
+    fn parse(mode: &str) -> Self {
+        let mode = match mode {
+            "" | "buffered" => LayerWriteMode::Buffered,
+            "direct" => LayerWriteMode::Direct,
+            "io_uring" if cfg!(feature = "io-uring") => LayerWriteMode::IoUring,
+            "io_uring" => {
+                warn!("built without the io-uring feature, writing layers with direct I/O");
+                LayerWriteMode::Direct
+            }
+            _ => {
+                warn!("unknown layer write mode {}, writing layers buffered", mode);
+                LayerWriteMode::Buffered
+            }
+        };
+        if mode != LayerWriteMode::Buffered && cfg!(not(target_os = "linux")) {
+            warn!("direct I/O is only supported on Linux, writing layers buffered");
+            return LayerWriteMode::Buffered;
+        }
+
+        mode
+    }
+}
+
+/// Writes the layer `data` to `path` in the configured layer write mode. Direct I/O which fails,
+/// e.g. on a filesystem without support for it, falls back to writing through the page cache.
+fn write_layer_data(path: &Path, data: &[u8]) -> Result<()> {
+    let mode = LayerWriteMode::parse(&tenant::settings().layer_write_mode);
+    if mode != LayerWriteMode::Buffered {
+        match write_direct(path, data, mode == LayerWriteMode::IoUring) {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!(
+                    "failed to write {} with direct I/O, writing it buffered: {:?}",
+                    path.display(),
+                    err
+                );
+                events::emit(Event::Fallback {
+                    job: control::current_job(),
+                    operation: "layer_write".to_string(),
+                    reason: format!("{:?} {}: {}", mode, path.display(), err),
+                });
+            }
+        }
+    }
+
+    fs::write(path, data).context("failed to write layer data")?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn write_direct(path: &Path, data: &[u8], use_io_uring: bool) -> Result<()> {
+    direct_io::write(path, data, use_io_uring)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_direct(_path: &Path, _data: &[u8], _use_io_uring: bool) -> Result<()> {
+    anyhow::bail!("direct I/O is only supported on Linux")
+}
+
 /// Reads a layer from disk, into the provided slice.
 pub fn read_layer(config: &StoreConfig, mut data: &mut [u8]) -> Result<()> {
     let data_path = StoreConfig::data_path(&config.path, &config.id);
@@ -101,3 +222,25 @@ pub fn is_layer_written<Tree: 'static + MerkleTreeTrait>(
 
     Ok(true)
 }
+
+/// Encodes `data` in place with the labels of the last layer, the same way `tree_r_last` does.
+pub fn encode_with_labels<Tree: 'static + MerkleTreeTrait>(
+    last_layer_labels: &[u8],
+    data: &mut [u8],
+) -> Result<()> {
+    ensure!(
+        last_layer_labels.len() == data.len(),
+        "labels and data must be of the same length"
+    );
+
+    data.par_chunks_mut(NODE_SIZE)
+        .zip(last_layer_labels.par_chunks(NODE_SIZE))
+        .try_for_each(|(data_node_bytes, key_bytes)| {
+            let key = <Tree::Hasher as Hasher>::Domain::try_from_bytes(key_bytes)?;
+            let data_node = <Tree::Hasher as Hasher>::Domain::try_from_bytes(data_node_bytes)?;
+            let encoded_node = encode(key, data_node);
+            data_node_bytes.copy_from_slice(AsRef::<[u8]>::as_ref(&encoded_node));
+
+            Ok(())
+        })
+}
@@ -3,33 +3,38 @@ use std::marker::PhantomData;
 use std::mem::{self, size_of};
 use std::sync::{
     atomic::{AtomicU64, Ordering::SeqCst},
-    Arc, MutexGuard,
+    Arc,
 };
 use std::thread;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use byte_slice_cast::{AsByteSlice, AsMutSliceOf};
+use crossbeam::channel::TrySendError;
 use filecoin_hashers::Hasher;
 use generic_array::{
     typenum::{Unsigned, U64},
     GenericArray,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use mapr::MmapMut;
 use merkletree::store::{DiskStore, Store, StoreConfig};
 use storage_proofs_core::{
     cache_key::CacheKey,
+    control,
     drgraph::{Graph, BASE_DEGREE},
     merkle::MerkleTreeTrait,
-    settings::SETTINGS,
+    tenant::{self, current_tenant, with_current_tenant},
     util::NODE_SIZE,
 };
 
 use crate::stacked::vanilla::{
     cache::ParentCache,
-    cores::{bind_core, checkout_core_group, CoreIndex},
-    create_label::{prepare_layers, read_layer, write_layer},
+    cores::{checkout_core_group, checkout_core_group_timeout, CheckedOutCoreGroup},
+    create_label::{
+        checkpoint::LabelCheckpoint, encode_with_labels, prepare_layers, progress::LayerProgress,
+        read_layer, sizing::RingSizing, write_layer, LabelingOptions,
+    },
     graph::{StackedBucketGraph, DEGREE, EXP_DEGREE},
     memory_handling::{setup_create_label_memory, CacheReader},
     params::{Labels, LabelsCache},
@@ -200,6 +205,9 @@ fn create_label_runner(
     }
 }
 
+// Labels the nodes of a layer from `start_node` on. If it isn't the first node, the labels of the
+// nodes before it must be in `layer_labels` and the parents cache must have been moved to it.
+#[allow(clippy::too_many_arguments)]
 fn create_layer_labels(
     parents_cache: &CacheReader<u32>,
     replica_id: &[u8],
@@ -207,22 +215,20 @@ fn create_layer_labels(
     exp_labels: Option<&mut MmapMut>,
     num_nodes: u64,
     cur_layer: u32,
-    core_group: Arc<Option<MutexGuard<'_, Vec<CoreIndex>>>>,
+    core_group: Arc<Option<CheckedOutCoreGroup>>,
+    num_producers: usize,
+    sizing: &RingSizing,
+    start_node: u64,
+    checkpoint: Option<&LabelCheckpoint>,
+    progress: &mut LayerProgress,
 ) {
     info!("Creating labels for layer {}", cur_layer);
-    // num_producers is the number of producer threads
-    let (lookahead, num_producers, producer_stride) = {
-        let settings = &SETTINGS;
-        let lookahead = settings.multicore_sdr_lookahead;
-        let num_producers = settings.multicore_sdr_producers;
-        // NOTE: Stride must not exceed the number of nodes in parents_cache's window. If it does, the process will deadlock
-        // with producers and consumers waiting for each other.
-        let producer_stride = settings
-            .multicore_sdr_producer_stride
-            .min(parents_cache.window_nodes() as u64);
-
-        (lookahead, num_producers, producer_stride)
-    };
+    let lookahead = sizing.lookahead;
+    // NOTE: Stride must not exceed the number of nodes in parents_cache's window. If it does, the process will deadlock
+    // with producers and consumers waiting for each other.
+    let producer_stride = sizing
+        .producer_stride
+        .min(parents_cache.window_nodes() as u64);
 
     const BYTES_PER_NODE: usize = (NODE_SIZE * DEGREE) + SHA_BLOCK_SIZE;
 
@@ -234,10 +240,12 @@ fn create_layer_labels(
         prepare_block(replica_id, cur_layer, buf);
     }
 
+    // Node 0 has no parents, so the producers start at the node after it.
+    let first_node = start_node.max(1);
     // Highest node that is ready from the producer
-    let cur_producer = AtomicU64::new(0);
+    let cur_producer = AtomicU64::new(first_node - 1);
     // Next node to be filled
-    let cur_awaiting = AtomicU64::new(1);
+    let cur_awaiting = AtomicU64::new(first_node);
 
     // These UnsafeSlices are managed through the 3 Atomics above, to minimize any locking overhead.
     let layer_labels = UnsafeSlice::from_slice(
@@ -250,6 +258,7 @@ fn create_layer_labels(
     });
     let base_parent_missing = UnsafeSlice::from_slice(&mut base_parent_missing);
 
+    let tenant = current_tenant();
     crossbeam::thread::scope(|s| {
         let mut runners = Vec::with_capacity(num_producers);
 
@@ -261,60 +270,93 @@ fn create_layer_labels(
             let ring_buf = &ring_buf;
             let base_parent_missing = &base_parent_missing;
 
-            let core_index = if let Some(cg) = &*core_group {
-                cg.get(i + 1)
-            } else {
-                None
-            };
+            let core_group = &*core_group;
+            let worker_tenant = tenant.clone();
             runners.push(s.spawn(move |_| {
-                // This could fail, but we will ignore the error if so.
-                // It will be logged as a warning by `bind_core`.
-                debug!("binding core in producer thread {}", i);
-                // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
-                let _cleanup_handle = core_index.map(|c| bind_core(*c));
-
-                create_label_runner(
-                    parents_cache,
-                    layer_labels,
-                    exp_labels,
-                    num_nodes,
-                    cur_producer,
-                    cur_awaiting,
-                    producer_stride,
-                    lookahead as u64,
-                    ring_buf,
-                    base_parent_missing,
-                )
+                with_current_tenant(worker_tenant, || {
+                    // This could fail, but we will ignore the error if so.
+                    // It will be logged as a warning by `bind_core`.
+                    debug!("binding core in producer thread {}", i);
+                    // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
+                    let _cleanup_handle = core_group.as_ref().and_then(|cg| cg.bind_unit(i + 1));
+
+                    create_label_runner(
+                        parents_cache,
+                        layer_labels,
+                        exp_labels,
+                        num_nodes,
+                        cur_producer,
+                        cur_awaiting,
+                        producer_stride,
+                        lookahead as u64,
+                        ring_buf,
+                        base_parent_missing,
+                    )
+                })
             }));
         }
 
-        let mut cur_node_ptr = unsafe { layer_labels.as_mut_slice() };
-        let mut cur_parent_ptr = unsafe { parents_cache.consumer_slice_at(DEGREE) };
-        let mut cur_parent_ptr_offset = DEGREE;
+        // Points to the node before the one being labeled.
+        let mut cur_node_ptr =
+            unsafe { &mut layer_labels.as_mut_slice()[(first_node as usize - 1) * NODE_WORDS..] };
+        let mut cur_parent_ptr_offset = first_node as usize * DEGREE;
+        let mut cur_parent_ptr = unsafe { parents_cache.consumer_slice_at(cur_parent_ptr_offset) };
 
-        // Calculate node 0 (special case with no parents)
-        // Which is replica_id || cur_layer || 0
-        // TODO - Hash and save intermediate result: replica_id || cur_layer
-        let mut buf = [0u8; (NODE_SIZE * DEGREE) + 64];
-        prepare_block(replica_id, cur_layer, &mut buf);
+        if start_node == 0 {
+            // Calculate node 0 (special case with no parents)
+            // Which is replica_id || cur_layer || 0
+            // TODO - Hash and save intermediate result: replica_id || cur_layer
+            let mut buf = [0u8; (NODE_SIZE * DEGREE) + 64];
+            prepare_block(replica_id, cur_layer, &mut buf);
 
-        cur_node_ptr[..8].copy_from_slice(&SHA256_INITIAL_DIGEST);
-        compress256!(cur_node_ptr, buf, 2);
+            cur_node_ptr[..8].copy_from_slice(&SHA256_INITIAL_DIGEST);
+            compress256!(cur_node_ptr, buf, 2);
 
-        // Fix endianess
-        cur_node_ptr[..8].iter_mut().for_each(|x| *x = x.to_be());
+            // Fix endianess
+            cur_node_ptr[..8].iter_mut().for_each(|x| *x = x.to_be());
 
-        cur_node_ptr[7] &= 0x3FFF_FFFF; // Strip last two bits to ensure in Fr
+            cur_node_ptr[7] &= 0x3FFF_FFFF; // Strip last two bits to ensure in Fr
+        }
 
         // Keep track of which node slot in the ring_buffer to use
-        let mut cur_slot = 0;
+        let mut cur_slot = (first_node as usize - 1) % lookahead;
         let mut count_not_ready = 0;
 
+        // Checkpoints are written by their own thread, the consumer only hands over the node it
+        // reached. A checkpoint is skipped while the previous one is still being written, and no
+        // more are taken once one fails.
+        let (checkpoint_tx, checkpoint_rx) = crossbeam::channel::bounded::<u64>(1);
+        let checkpoint_writer = checkpoint.map(|cp| {
+            let layer_labels = &layer_labels;
+            s.spawn(move |_| {
+                let mut saved_nodes = start_node;
+                for nodes in checkpoint_rx {
+                    // Safety: the labels of the nodes before `nodes` are no longer written.
+                    let labels = unsafe { &layer_labels.as_slice()[..nodes as usize * NODE_WORDS] };
+                    match cp.save(
+                        cur_layer as usize,
+                        labels.as_byte_slice(),
+                        saved_nodes,
+                        nodes,
+                    ) {
+                        Ok(()) => saved_nodes = nodes,
+                        Err(err) => {
+                            warn!("failed to checkpoint layer {}: {:?}", cur_layer, err);
+                            break;
+                        }
+                    }
+                }
+            })
+        });
+        let interval = checkpoint.map(|cp| cp.interval());
+        let mut checkpoint_tx = interval.map(|_| checkpoint_tx);
+        let mut checkpoint_nodes = start_node;
+
         // Calculate nodes 1 to n
 
         // Skip first node.
-        parents_cache.store_consumer(1);
-        let mut i = 1;
+        parents_cache.store_consumer(first_node);
+        let mut i = first_node;
         while i < num_nodes {
             // Ensure next buffer is ready
             let mut counted = false;
@@ -423,10 +465,27 @@ fn create_layer_labels(
                 i += 1;
                 cur_slot = (cur_slot + 1) % lookahead;
             }
+            progress.update(i);
+
+            let due = interval.map_or(false, |interval| {
+                i < num_nodes && i - checkpoint_nodes >= interval
+            });
+            if let Some(tx) = checkpoint_tx.as_ref().filter(|_| due) {
+                match tx.try_send(i) {
+                    Ok(()) => checkpoint_nodes = i,
+                    Err(TrySendError::Full(_)) => {}
+                    Err(TrySendError::Disconnected(_)) => checkpoint_tx = None,
+                }
+            }
         }
 
         debug!("PRODUCER NOT READY: {} times", count_not_ready);
 
+        drop(checkpoint_tx);
+        if let Some(writer) = checkpoint_writer {
+            writer.join().expect("join failed");
+        }
+
         for runner in runners {
             runner.join().expect("join failed");
         }
@@ -441,6 +500,8 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     layers: usize,
     replica_id: T,
     config: StoreConfig,
+    encode_data: Option<&mut [u8]>,
+    options: &LabelingOptions,
 ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
     info!("create labels");
 
@@ -448,18 +509,41 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
     let sector_size = graph.size() * NODE_SIZE;
     let node_count = graph.size() as u64;
-    let cache_window_nodes = SETTINGS.sdr_parents_cache_size as usize;
+    let (sizing, checkout_timeout, num_producers, checkpoint_nodes) = {
+        let settings = tenant::settings();
+        (
+            RingSizing::new(&settings, options.sizing)?,
+            Duration::from_secs(settings.multicore_sdr_checkout_timeout_secs),
+            settings.multicore_sdr_producers,
+            settings.multicore_sdr_checkpoint_nodes,
+        )
+    };
+    let checkpoint = if checkpoint_nodes > 0 {
+        Some(LabelCheckpoint::new(
+            &config.path,
+            replica_id.as_ref(),
+            checkpoint_nodes,
+        ))
+    } else {
+        None
+    };
 
-    let default_cache_size = DEGREE * 4 * cache_window_nodes;
+    debug!("multicore sdr sizing: {:?}", sizing);
+    let default_cache_size = DEGREE * 4 * sizing.cache_window_nodes;
 
-    let core_group = Arc::new(checkout_core_group());
+    let core_group = Arc::new(checkout_core_group_timeout(checkout_timeout));
+    // num_producers is the number of producer threads
+    let num_producers = match &*core_group {
+        Some(group) => group.producers(num_producers),
+        None => num_producers,
+    };
 
     // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
     let _cleanup_handle = (*core_group).as_ref().map(|group| {
         // This could fail, but we will ignore the error if so.
         // It will be logged as a warning by `bind_core`.
         debug!("binding core in main thread");
-        group.get(0).map(|core_index| bind_core(*core_index))
+        group.bind_unit(0)
     });
 
     // NOTE: this means we currently keep 2x sector size around, to improve speed
@@ -468,13 +552,19 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         DEGREE,
         Some(default_cache_size as usize),
         &parents_cache.path,
+        parents_cache.in_shm(),
+        (*core_group)
+            .as_ref()
+            .and_then(CheckedOutCoreGroup::numa_node),
     )?;
 
     for (layer, layer_state) in (1..=layers).zip(layer_states.iter()) {
         info!("Layer {}", layer);
+        control::checkpoint(&format!("layer {}", layer))?;
 
         if layer_state.generated {
             info!("skipping layer {}, already generated", layer);
+            LayerProgress::skip(options, layer, layers, node_count);
 
             // load the already generated layer into exp_labels
             read_layer(&layer_state.config, &mut exp_labels)?;
@@ -487,6 +577,16 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             parents_cache.finish_reset()?;
         }
 
+        // Resume from the checkpoint of an interrupted labeling of this layer, if there is one.
+        let start_node = match &checkpoint {
+            Some(checkpoint) => checkpoint.resume(layer, &mut layer_labels),
+            None => 0,
+        };
+        if start_node > 0 {
+            parents_cache.seek(start_node)?;
+        }
+        let mut progress = LayerProgress::start(options, layer, layers, start_node, node_count);
+
         create_layer_labels(
             &parents_cache,
             replica_id.as_ref(),
@@ -499,6 +599,11 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             node_count,
             layer as u32,
             core_group.clone(),
+            num_producers,
+            &sizing,
+            start_node,
+            checkpoint.as_ref(),
+            &mut progress,
         );
 
         // Cache reset happens in two parts.
@@ -513,6 +618,10 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
             info!("  storing labels on disk");
             write_layer(&exp_labels, layer_config).context("failed to store labels")?;
+            if let Some(checkpoint) = &checkpoint {
+                checkpoint.clear();
+            }
+            progress.finish();
 
             info!(
                 "  generated layer {} store with id {}",
@@ -521,6 +630,12 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         }
     }
 
+    // The labels of the last layer are still in memory, so encode while we have them.
+    if let Some(data) = encode_data {
+        info!("  encoding replica with the last layer");
+        encode_with_labels::<Tree>(&exp_labels, data).context("failed to encode replica")?;
+    }
+
     Ok((
         Labels::<Tree> {
             labels: layer_states.iter().map(|s| s.config.clone()).collect(),
@@ -537,6 +652,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     layers: usize,
     replica_id: T,
     config: StoreConfig,
+    options: &LabelingOptions,
 ) -> Result<LabelsCache<Tree>> {
     info!("create labels");
 
@@ -546,18 +662,30 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
     let sector_size = graph.size() * NODE_SIZE;
     let node_count = graph.size() as u64;
-    let cache_window_nodes = (&SETTINGS.sdr_parents_cache_size / 2) as usize;
+    let (sizing, num_producers) = {
+        let settings = tenant::settings();
+        (
+            RingSizing::new(&settings, options.sizing)?,
+            settings.multicore_sdr_producers,
+        )
+    };
+    let cache_window_nodes = (sizing.cache_window_nodes / 2).max(1);
 
     let default_cache_size = DEGREE * 4 * cache_window_nodes;
 
     let core_group = Arc::new(checkout_core_group());
+    // num_producers is the number of producer threads
+    let num_producers = match &*core_group {
+        Some(group) => group.producers(num_producers),
+        None => num_producers,
+    };
 
     // When `_cleanup_handle` is dropped, the previous binding of thread will be restored.
     let _cleanup_handle = (*core_group).as_ref().map(|group| {
         // This could fail, but we will ignore the error if so.
         // It will be logged as a warning by `bind_core`.
         debug!("binding core in main thread");
-        group.get(0).map(|core_index| bind_core(*core_index))
+        group.bind_unit(0)
     });
 
     // NOTE: this means we currently keep 2x sector size around, to improve speed
@@ -566,6 +694,10 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         DEGREE,
         Some(default_cache_size as usize),
         &parents_cache.path,
+        parents_cache.in_shm(),
+        (*core_group)
+            .as_ref()
+            .and_then(CheckedOutCoreGroup::numa_node),
     )?;
 
     for layer in 1..=layers {
@@ -577,6 +709,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             parents_cache.finish_reset()?;
         }
 
+        let mut progress = LayerProgress::start(options, layer, layers, 0, node_count);
         create_layer_labels(
             &parents_cache,
             replica_id.as_ref(),
@@ -589,6 +722,11 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             node_count,
             layer as u32,
             core_group.clone(),
+            num_producers,
+            &sizing,
+            0,
+            None,
+            &mut progress,
         );
 
         // Cache reset happens in two parts.
@@ -621,6 +759,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             labels.push(layer_store);
             label_configs.push(layer_config);
         }
+        progress.finish();
     }
     assert_eq!(
         labels.len(),
@@ -742,7 +881,12 @@ mod tests {
         let cache = graph.parent_cache().expect("parent_cache failed");
 
         let labels = create_labels_for_decoding::<LCTree<PoseidonHasher, U8, U0, U2>, _>(
-            &graph, &cache, layers, replica_id, config,
+            &graph,
+            &cache,
+            layers,
+            replica_id,
+            config,
+            &LabelingOptions::default(),
         )
         .expect("create_labels_for_decoding failed");
 
@@ -0,0 +1,124 @@
+//! Progress reporting of the SDR labeling.
+//!
+//! A labeling with a `progress` callback in its `LabelingOptions` reports its progress to it:
+//! once when a layer starts, every [`REPORT_INTERVAL_NODES`] nodes and once when the layer is
+//! complete. Layers found on disk are reported as complete right away. A callback which isn't
+//! called for a long time therefore means the labeling stalls.
+
+use std::sync::Arc;
+
+use crate::stacked::vanilla::create_label::LabelingOptions;
+
+/// The number of nodes labeled between two reports within a layer.
+pub const REPORT_INTERVAL_NODES: u64 = 1 << 20;
+
+/// The progress of the labeling of a sector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LabelingProgress {
+    /// The layer being labeled, starting at 1.
+    pub layer: usize,
+    /// The number of layers of the sector.
+    pub layers: usize,
+    /// The number of nodes of the layer labeled so far.
+    pub nodes_done: u64,
+    /// The number of nodes of a layer.
+    pub nodes_total: u64,
+}
+
+/// A callback the progress of the labeling is reported to. It runs on the labeling thread.
+pub type ProgressCallback = Arc<dyn Fn(LabelingProgress) + Send + Sync>;
+
+/// Reports the progress of the labeling of a layer to a callback, if any.
+pub(crate) struct LayerProgress {
+    callback: Option<ProgressCallback>,
+    progress: LabelingProgress,
+}
+
+impl LayerProgress {
+    /// Reports the start of the labeling of `layer`, resumed after `nodes_done` nodes.
+    pub(crate) fn start(
+        options: &LabelingOptions,
+        layer: usize,
+        layers: usize,
+        nodes_done: u64,
+        nodes_total: u64,
+    ) -> Self {
+        let progress = LayerProgress {
+            callback: options.progress.clone(),
+            progress: LabelingProgress {
+                layer,
+                layers,
+                nodes_done,
+                nodes_total,
+            },
+        };
+        progress.report();
+        progress
+    }
+
+    /// Reports a layer found on disk, which needs no labeling.
+    pub(crate) fn skip(options: &LabelingOptions, layer: usize, layers: usize, nodes_total: u64) {
+        LayerProgress::start(options, layer, layers, nodes_total, nodes_total);
+    }
+
+    /// Records that `nodes_done` nodes are labeled, which is reported if the last report was
+    /// at least `REPORT_INTERVAL_NODES` nodes ago.
+    pub(crate) fn update(&mut self, nodes_done: u64) {
+        if self.callback.is_some() && nodes_done >= self.progress.nodes_done + REPORT_INTERVAL_NODES
+        {
+            self.progress.nodes_done = nodes_done;
+            self.report();
+        }
+    }
+
+    /// Reports the layer as complete.
+    pub(crate) fn finish(&mut self) {
+        self.progress.nodes_done = self.progress.nodes_total;
+        self.report();
+    }
+
+    fn report(&self) {
+        if let Some(callback) = &self.callback {
+            callback(self.progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_layer_progress() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let nodes_total = 3 * REPORT_INTERVAL_NODES;
+
+        let recorded = reports.clone();
+        let options = LabelingOptions {
+            progress: Some(Arc::new(move |progress: LabelingProgress| {
+                recorded
+                    .lock()
+                    .expect("reports poisoned")
+                    .push((progress.layer, progress.nodes_done))
+            })),
+            ..LabelingOptions::default()
+        };
+        LayerProgress::skip(&options, 1, 2, nodes_total);
+        let mut progress = LayerProgress::start(&options, 2, 2, 0, nodes_total);
+        progress.update(REPORT_INTERVAL_NODES - 1);
+        progress.update(REPORT_INTERVAL_NODES);
+        progress.update(REPORT_INTERVAL_NODES + 1);
+        progress.finish();
+        assert_eq!(
+            *reports.lock().expect("reports poisoned"),
+            vec![
+                (1, nodes_total),
+                (2, 0),
+                (2, REPORT_INTERVAL_NODES),
+                (2, nodes_total)
+            ]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// SDR labeling of a batch of sectors, one work item per sector.
+//
+// The label of a node is the SHA-256 digest of
+//
+//   replica_id || layer (u32 BE) || node (u64 BE) || zero padding to 64 bytes || parents
+//
+// with the last two bits stripped. The parents are the labels of the 37 parents (1184 bytes) in
+// the order of `copy_parents_data_inner(_exp)`: the 6 base parents six times and the first one
+// again in the first layer, all 14 parents twice and the first 9 again in the other layers. Node
+// 0 has no parents. Labels are stored as bytes, the way the CPU labeling lays them out.
+
+#define NODE_WORDS 8
+#define BASE_DEGREE 6
+#define DEGREE 14
+#define PARENT_COUNT 37
+// The length of the message of a node with parents, in bits.
+#define MESSAGE_BITS ((64 + PARENT_COUNT * 32) * 8)
+
+__constant uint K[64] = {
+  0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+  0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+  0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+  0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+  0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+  0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+  0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+  0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+};
+
+__constant uint IV[8] = {
+  0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+};
+
+// Converts between the words of SHA-256 (big endian) and of the little endian device.
+inline uint swap_bytes(uint x) {
+  return (x >> 24) | ((x >> 8) & 0x0000ff00) | ((x << 8) & 0x00ff0000) | (x << 24);
+}
+
+#define ROTR(x, n) rotate((x), (uint)(32 - (n)))
+#define CH(x, y, z) bitselect((z), (y), (x))
+#define MAJ(x, y, z) bitselect((x), (y), ((x) ^ (z)))
+#define EP0(x) (ROTR(x, 2) ^ ROTR(x, 13) ^ ROTR(x, 22))
+#define EP1(x) (ROTR(x, 6) ^ ROTR(x, 11) ^ ROTR(x, 25))
+#define SIG0(x) (ROTR(x, 7) ^ ROTR(x, 18) ^ ((x) >> 3))
+#define SIG1(x) (ROTR(x, 17) ^ ROTR(x, 19) ^ ((x) >> 10))
+
+void compress(uint *state, const uint *block) {
+  uint w[64];
+  for (int i = 0; i < 16; i++) {
+    w[i] = block[i];
+  }
+  for (int i = 16; i < 64; i++) {
+    w[i] = SIG1(w[i - 2]) + w[i - 7] + SIG0(w[i - 15]) + w[i - 16];
+  }
+
+  uint a = state[0], b = state[1], c = state[2], d = state[3];
+  uint e = state[4], f = state[5], g = state[6], h = state[7];
+  for (int i = 0; i < 64; i++) {
+    uint t1 = h + EP1(e) + CH(e, f, g) + K[i] + w[i];
+    uint t2 = EP0(a) + MAJ(a, b, c);
+    h = g;
+    g = f;
+    f = e;
+    e = d + t1;
+    d = c;
+    c = b;
+    b = a;
+    a = t1 + t2;
+  }
+
+  state[0] += a;
+  state[1] += b;
+  state[2] += c;
+  state[3] += d;
+  state[4] += e;
+  state[5] += f;
+  state[6] += g;
+  state[7] += h;
+}
+
+// Loads the label of parent `k` of the 37 into `words`.
+void load_parent(
+    uint *words,
+    uint k,
+    uint layer,
+    __global const uint *parents,
+    __global const uint *labels,
+    __global const uint *exp_labels) {
+  uint index = layer == 1 ? k % BASE_DEGREE : k % DEGREE;
+  __global const uint *source = index < BASE_DEGREE ? labels : exp_labels;
+  __global const uint *label = source + (ulong)parents[index] * NODE_WORDS;
+  for (int i = 0; i < NODE_WORDS; i++) {
+    words[i] = swap_bytes(label[i]);
+  }
+}
+
+// Labels the nodes `chunk_start..chunk_start + chunk_len` of `layer` for every sector. `parents`
+// holds the DEGREE parents of each node of the chunk, `layer_labels` and `exp_labels` the labels
+// of the layer and of the previous one for all sectors, one after another.
+__kernel void label_nodes(
+    __global uint *layer_labels,
+    __global const uint *exp_labels,
+    __global const uint *parents,
+    __global const uint *replica_ids,
+    uint sectors,
+    ulong nodes,
+    uint layer,
+    ulong chunk_start,
+    uint chunk_len) {
+  uint sector = get_global_id(0);
+  if (sector >= sectors) {
+    return;
+  }
+
+  __global uint *labels = layer_labels + sector * nodes * NODE_WORDS;
+  __global const uint *exp = exp_labels + sector * nodes * NODE_WORDS;
+
+  uint header[16];
+  for (int i = 0; i < NODE_WORDS; i++) {
+    header[i] = swap_bytes(replica_ids[sector * NODE_WORDS + i]);
+  }
+  header[8] = layer;
+  for (int i = 11; i < 16; i++) {
+    header[i] = 0;
+  }
+
+  for (uint i = 0; i < chunk_len; i++) {
+    ulong node = chunk_start + i;
+    __global const uint *node_parents = parents + (ulong)i * DEGREE;
+
+    uint state[8];
+    for (int j = 0; j < 8; j++) {
+      state[j] = IV[j];
+    }
+    header[9] = (uint)(node >> 32);
+    header[10] = (uint)node;
+    compress(state, header);
+
+    uint block[16];
+    if (node == 0) {
+      block[0] = 0x80000000;
+      for (int j = 1; j < 15; j++) {
+        block[j] = 0;
+      }
+      block[15] = 64 * 8;
+      compress(state, block);
+    } else {
+      // Two parents per block, the last one is padded.
+      for (uint k = 0; k + 1 < PARENT_COUNT; k += 2) {
+        load_parent(block, k, layer, node_parents, labels, exp);
+        load_parent(block + NODE_WORDS, k + 1, layer, node_parents, labels, exp);
+        compress(state, block);
+      }
+      load_parent(block, PARENT_COUNT - 1, layer, node_parents, labels, exp);
+      block[8] = 0x80000000;
+      for (int j = 9; j < 15; j++) {
+        block[j] = 0;
+      }
+      block[15] = MESSAGE_BITS;
+      compress(state, block);
+    }
+
+    // Strip the last two bits, to ensure the label is in Fr.
+    state[7] &= 0xffffff3f;
+
+    __global uint *label = labels + node * NODE_WORDS;
+    for (int j = 0; j < NODE_WORDS; j++) {
+      label[j] = swap_bytes(state[j]);
+    }
+  }
+}
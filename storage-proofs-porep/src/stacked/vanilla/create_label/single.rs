@@ -8,6 +8,7 @@ use log::info;
 use merkletree::store::{DiskStore, Store, StoreConfig};
 use sha2raw::Sha256;
 use storage_proofs_core::{
+    control,
     drgraph::Graph,
     merkle::MerkleTreeTrait,
     util::{data_at_node_offset, NODE_SIZE},
@@ -15,7 +16,10 @@ use storage_proofs_core::{
 
 use crate::stacked::vanilla::{
     cache::ParentCache,
-    create_label::{prepare_layers, read_layer, write_layer},
+    create_label::{
+        encode_with_labels, prepare_layers, progress::LayerProgress, read_layer, write_layer,
+        LabelingOptions,
+    },
     proof::LayerState,
     Labels, LabelsCache, StackedBucketGraph,
 };
@@ -27,6 +31,8 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     layers: usize,
     replica_id: T,
     config: StoreConfig,
+    encode_data: Option<&mut [u8]>,
+    options: &LabelingOptions,
 ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
     info!("generate labels");
 
@@ -39,8 +45,10 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
     for (layer, layer_state) in (1..=layers).zip(layer_states.iter()) {
         info!("generating layer: {}", layer);
+        control::checkpoint(&format!("layer {}", layer))?;
         if layer_state.generated {
             info!("skipping layer {}, already generated", layer);
+            LayerProgress::skip(options, layer, layers, graph.size() as u64);
 
             // load the already generated layer into exp_labels
             read_layer(&layer_state.config, &mut exp_labels)?;
@@ -49,6 +57,8 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
         parents_cache.reset()?;
 
+        let node_count = graph.size() as u64;
+        let mut progress = LayerProgress::start(options, layer, layers, 0, node_count);
         if layer == 1 {
             for node in 0..graph.size() {
                 create_label(
@@ -59,6 +69,7 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
                     layer,
                     node,
                 )?;
+                progress.update(node as u64 + 1);
             }
         } else {
             for node in 0..graph.size() {
@@ -71,6 +82,7 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
                     layer,
                     node,
                 )?;
+                progress.update(node as u64 + 1);
             }
         }
 
@@ -84,11 +96,18 @@ pub fn create_labels_for_encoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
             "  generated layer {} store with id {}",
             layer, layer_config.id
         );
+        progress.finish();
 
         info!("  setting exp parents");
         mem::swap(&mut layer_labels, &mut exp_labels);
     }
 
+    // The labels of the last layer are still in memory, so encode while we have them.
+    if let Some(data) = encode_data {
+        info!("  encoding replica with the last layer");
+        encode_with_labels::<Tree>(&exp_labels, data).context("failed to encode replica")?;
+    }
+
     Ok((
         Labels::<Tree> {
             labels: layer_states.iter().map(|s| s.config.clone()).collect(),
@@ -105,6 +124,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
     layers: usize,
     replica_id: T,
     config: StoreConfig,
+    options: &LabelingOptions,
 ) -> Result<LabelsCache<Tree>> {
     info!("generate labels");
 
@@ -121,6 +141,8 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
 
         parents_cache.reset()?;
 
+        let node_count = graph.size() as u64;
+        let mut progress = LayerProgress::start(options, layer, layers, 0, node_count);
         if layer == 1 {
             for node in 0..graph.size() {
                 create_label(
@@ -131,6 +153,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
                     layer,
                     node,
                 )?;
+                progress.update(node as u64 + 1);
             }
         } else {
             for node in 0..graph.size() {
@@ -143,6 +166,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
                     layer,
                     node,
                 )?;
+                progress.update(node as u64 + 1);
             }
         }
 
@@ -153,6 +177,7 @@ pub fn create_labels_for_decoding<Tree: 'static + MerkleTreeTrait, T: AsRef<[u8]
         let layer_store: DiskStore<<Tree::Hasher as Hasher>::Domain> =
             DiskStore::new_from_disk(graph.size(), Tree::Arity::to_usize(), &config)?;
         info!("  generated layer {} store with id {}", layer, config.id);
+        progress.finish();
 
         info!("  setting exp parents");
         mem::swap(&mut layer_labels, &mut exp_labels);
@@ -0,0 +1,107 @@
+//! Sizing of the buffers of the multicore SDR labeling.
+//!
+//! The producer threads load the parents of the nodes ahead of the consumer into a ring buffer of
+//! `lookahead` nodes (512 bytes each), `producer_stride` nodes at a time, out of the window of the
+//! parents cache mapped in memory (56 bytes a node, two windows are mapped). More lookahead and a
+//! larger window let the producers run further ahead, which can avoid stalls of the consumer on
+//! machines with a large L3 cache, at the cost of memory.
+//!
+//! The sizes come from the settings, and can be overridden for a sector by the `sizing` of its
+//! `LabelingOptions`.
+
+use anyhow::{ensure, Result};
+use storage_proofs_core::settings::Settings;
+
+/// Overrides of the multicore SDR sizing settings, unset sizes are taken from the settings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MulticoreSdrSizing {
+    /// Nodes of the ring buffer, instead of `FIL_PROOFS_MULTICORE_SDR_LOOKAHEAD`.
+    pub lookahead: Option<usize>,
+    /// Nodes loaded by a producer at a time, instead of `FIL_PROOFS_MULTICORE_SDR_PRODUCER_STRIDE`.
+    pub producer_stride: Option<u64>,
+    /// Nodes of a parents cache window, instead of `FIL_PROOFS_MULTICORE_SDR_CACHE_WINDOW_NODES`.
+    pub cache_window_nodes: Option<usize>,
+}
+
+/// The sizes the multicore labeling runs with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RingSizing {
+    pub lookahead: usize,
+    pub producer_stride: u64,
+    pub cache_window_nodes: usize,
+}
+
+impl RingSizing {
+    /// The sizes of `settings`, with the overrides of `sizing` applied.
+    pub(crate) fn new(settings: &Settings, sizing: MulticoreSdrSizing) -> Result<Self> {
+        let cache_window_nodes = match settings.multicore_sdr_cache_window_nodes {
+            0 => settings.sdr_parents_cache_size,
+            nodes => nodes,
+        };
+        let ring = RingSizing {
+            lookahead: sizing.lookahead.unwrap_or(settings.multicore_sdr_lookahead),
+            producer_stride: sizing
+                .producer_stride
+                .unwrap_or(settings.multicore_sdr_producer_stride),
+            cache_window_nodes: sizing
+                .cache_window_nodes
+                .unwrap_or(cache_window_nodes as usize),
+        };
+        ensure!(ring.lookahead > 0, "multicore SDR lookahead must not be 0");
+        ensure!(
+            ring.producer_stride > 0,
+            "multicore SDR producer stride must not be 0"
+        );
+        ensure!(
+            ring.cache_window_nodes > 0,
+            "multicore SDR cache window must not be 0"
+        );
+
+        Ok(ring)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_sizing() {
+        let settings = Settings {
+            multicore_sdr_lookahead: 800,
+            multicore_sdr_producer_stride: 128,
+            multicore_sdr_cache_window_nodes: 0,
+            sdr_parents_cache_size: 2048,
+            ..Default::default()
+        };
+        let ring =
+            RingSizing::new(&settings, MulticoreSdrSizing::default()).expect("invalid sizing");
+        assert_eq!(
+            ring,
+            RingSizing {
+                lookahead: 800,
+                producer_stride: 128,
+                cache_window_nodes: 2048,
+            }
+        );
+
+        let settings = Settings {
+            multicore_sdr_cache_window_nodes: 8192,
+            ..settings
+        };
+        let sizing = MulticoreSdrSizing {
+            lookahead: Some(4000),
+            ..Default::default()
+        };
+        let ring = RingSizing::new(&settings, sizing).expect("invalid sizing");
+        assert_eq!(ring.lookahead, 4000);
+        assert_eq!(ring.producer_stride, 128);
+        assert_eq!(ring.cache_window_nodes, 8192);
+
+        let sizing = MulticoreSdrSizing {
+            producer_stride: Some(0),
+            ..Default::default()
+        };
+        assert!(RingSizing::new(&settings, sizing).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Selection of the SDR labeling of a sector.
+//!
+//! Sectors are labeled by the multicore SDR if `FIL_PROOFS_USE_MULTICORE_SDR` is set (and the
+//! multicore SDR is built), and on a single core otherwise. The `strategy` of the
+//! `LabelingOptions` of a sector can select either instead, e.g. so that a scheduler can seal a
+//! sector on a single core while all core groups are checked out.
+
+use anyhow::{ensure, Result};
+use storage_proofs_core::settings::Settings;
+
+/// How the layers of a sector are labeled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LabelingStrategy {
+    /// As configured by `FIL_PROOFS_USE_MULTICORE_SDR`.
+    Settings,
+    /// On the calling thread.
+    SingleCore,
+    /// By the multicore SDR, on a group of cores sharing a cache.
+    MultiCore,
+}
+
+impl Default for LabelingStrategy {
+    fn default() -> Self {
+        LabelingStrategy::Settings
+    }
+}
+
+/// Whether a sector is labeled by the multicore SDR with `strategy` and `settings`.
+pub(crate) fn use_multicore_sdr(strategy: LabelingStrategy, settings: &Settings) -> Result<bool> {
+    let multicore_built = cfg!(any(
+        feature = "multicore-sdr",
+        feature = "multicore-sdr-os-affinity"
+    ));
+    match strategy {
+        LabelingStrategy::Settings => Ok(multicore_built && settings.use_multicore_sdr),
+        LabelingStrategy::SingleCore => Ok(false),
+        LabelingStrategy::MultiCore => {
+            ensure!(
+                multicore_built,
+                "multicore labeling requested, but built without the multicore SDR"
+            );
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labeling_strategy() {
+        let settings = Settings {
+            use_multicore_sdr: true,
+            ..Default::default()
+        };
+        let multicore_built = cfg!(any(
+            feature = "multicore-sdr",
+            feature = "multicore-sdr-os-affinity"
+        ));
+
+        assert_eq!(
+            use_multicore_sdr(LabelingStrategy::Settings, &settings).expect("invalid strategy"),
+            multicore_built
+        );
+        assert!(
+            !use_multicore_sdr(LabelingStrategy::Settings, &Settings::default())
+                .expect("invalid strategy")
+        );
+        assert!(
+            !use_multicore_sdr(LabelingStrategy::SingleCore, &settings).expect("invalid strategy")
+        );
+        assert_eq!(
+            use_multicore_sdr(LabelingStrategy::MultiCore, &Settings::default()).is_ok(),
+            multicore_built
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Batch sizes of the GPU tree builder, tuned to the device.
+//!
+//! With `FIL_PROOFS_GPU_TREE_BATCH_SIZE_AUTO`, the batch size of tree_r_last is chosen from the
+//! memory of the GPU instead of `FIL_PROOFS_MAX_GPU_TREE_BATCH_SIZE`: a batch estimated to use half
+//! of it (the other half is left to the other users of the device) is hashed on it, halving the
+//! batch until that succeeds. The probed size is cached per device and arity for the lifetime of
+//! the process.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use blstrs::Scalar as Fr;
+use filecoin_hashers::PoseidonArity;
+use generic_array::GenericArray;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use neptune::{batch_hasher::Batcher, BatchHasher};
+use rust_gpu_tools::Device;
+
+use super::tree_builder::{acquire_tree_gpu, gpu_batch_bytes};
+
+/// The smallest batch size probed.
+const MIN_BATCH_SIZE: usize = 1 << 14;
+
+/// The largest batch size chosen, which bounds the memory of the batches on the host.
+const MAX_BATCH_SIZE: usize = 1 << 22;
+
+lazy_static! {
+    /// The probed batch sizes by device index and arity.
+    static ref BATCH_SIZES: Mutex<HashMap<(usize, usize), usize>> = Mutex::new(HashMap::new());
+}
+
+/// The batch size of the tree builder of arity `A` on the GPU `device_index` (an index into the
+/// GPUs found), probed with the GPU acquired from the GPU scheduler like a tree builder if it isn't
+/// cached.
+pub(crate) fn tree_batch_size<A: PoseidonArity>(device_index: usize) -> Result<usize> {
+    let key = (device_index, A::to_usize());
+    if let Some(batch_size) = BATCH_SIZES.lock().expect("BATCH_SIZES poisoned").get(&key) {
+        return Ok(*batch_size);
+    }
+
+    let _gpu_grant = acquire_tree_gpu()?;
+    let devices = Device::all();
+    let device = *devices.get(device_index).with_context(|| {
+        format!(
+            "GPU {} not found, there are {}",
+            device_index,
+            devices.len()
+        )
+    })?;
+
+    let mut batch_size = initial_batch_size(device.memory(), A::to_usize());
+    while let Err(err) = probe::<A>(device, batch_size) {
+        ensure!(
+            batch_size > MIN_BATCH_SIZE,
+            "hashing a batch of {} on GPU {} failed: {:?}",
+            batch_size,
+            device_index,
+            err
+        );
+        warn!(
+            "hashing a batch of {} on GPU {} failed, halving it: {:?}",
+            batch_size, device_index, err
+        );
+        batch_size /= 2;
+    }
+    info!(
+        "GPU {} ({}, {} bytes): tree batch size {} for arity {}",
+        device_index,
+        device.name(),
+        device.memory(),
+        batch_size,
+        A::to_usize()
+    );
+
+    BATCH_SIZES
+        .lock()
+        .expect("BATCH_SIZES poisoned")
+        .insert(key, batch_size);
+    Ok(batch_size)
+}
+
+/// The batch size of arity `arity` estimated to use half of `memory` bytes, see
+/// `gpu_batch_bytes`.
+fn initial_batch_size(memory: u64, arity: usize) -> usize {
+    let batch_size = (memory / 2 / gpu_batch_bytes(arity, 1)) as usize;
+
+    batch_size.clamp(MIN_BATCH_SIZE, MAX_BATCH_SIZE)
+}
+
+/// Hashes a batch of `batch_size` preimages on `device`.
+fn probe<A: PoseidonArity>(device: &Device, batch_size: usize) -> Result<()> {
+    let mut batcher = Batcher::<A>::new(device, batch_size)?;
+    let preimages = vec![GenericArray::<Fr, A>::default(); batch_size];
+    let digests = batcher.hash(&preimages)?;
+    ensure!(
+        digests.len() == batch_size,
+        "{} digests of {} preimages",
+        digests.len(),
+        batch_size
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_initial_batch_size() {
+        // 1GiB of memory, half of it for preimages of 18 elements.
+        assert_eq!(initial_batch_size(1 << 30, 8), (1 << 29) / (18 * 32));
+        assert_eq!(initial_batch_size(0, 8), MIN_BATCH_SIZE);
+        assert_eq!(initial_batch_size(1 << 40, 8), MAX_BATCH_SIZE);
+    }
+}
@@ -16,7 +16,7 @@ use storage_proofs_core::{
     drgraph::{BucketGraph, Graph, BASE_DEGREE},
     error::Result,
     parameter_cache::ParameterSetMetadata,
-    settings::SETTINGS,
+    tenant,
     util::NODE_SIZE,
     PoRepID,
 };
@@ -135,7 +135,7 @@ where
     /// Returns a reference to the parent cache.
     pub fn parent_cache(&self) -> Result<ParentCache> {
         // Number of nodes to be cached in memory
-        let default_cache_size = SETTINGS.sdr_parents_cache_size;
+        let default_cache_size = tenant::settings().sdr_parents_cache_size;
         let cache_entries = self.size() as u32;
         let cache_size = cache_entries.min(default_cache_size);
 
@@ -143,6 +143,15 @@ where
 
         ParentCache::new(cache_size, cache_entries, self)
     }
+
+    /// Like `parent_cache`, but verifies the digest of an existing cache, which is regenerated if
+    /// it doesn't match.
+    pub fn verified_parent_cache(&self) -> Result<ParentCache> {
+        let cache_entries = self.size() as u32;
+        let cache_size = cache_entries.min(tenant::settings().sdr_parents_cache_size);
+
+        ParentCache::new_verified(cache_size, cache_entries, self)
+    }
     pub fn copy_parents_data_exp(
         &self,
         node: u32,
@@ -182,12 +191,12 @@ where
         }
     }
 
-    fn copy_parents_data_inner_exp(
+    pub(crate) fn copy_parents_data_inner_exp(
         &self,
         cache_parents: &[u32],
         base_data: &[u8],
         exp_data: &[u8],
-        mut hasher: Sha256,
+        hasher: Sha256,
     ) -> [u8; 32] {
         prefetch(&cache_parents[..BASE_DEGREE], base_data);
         prefetch(&cache_parents[BASE_DEGREE..], exp_data);
@@ -210,22 +219,14 @@ where
             read_node(13, cache_parents, exp_data),
         ];
 
-        // round 1 (14)
-        hasher.input(&parents);
-
-        // round 2 (14)
-        hasher.input(&parents);
-
-        // round 3 (9)
-        hasher.input(&parents[..8]);
-        hasher.finish_with(parents[8])
+        hash_exp_parents(&parents, hasher)
     }
 
-    fn copy_parents_data_inner(
+    pub(crate) fn copy_parents_data_inner(
         &self,
         cache_parents: &[u32],
         base_data: &[u8],
-        mut hasher: Sha256,
+        hasher: Sha256,
     ) -> [u8; 32] {
         prefetch(&cache_parents[..BASE_DEGREE], base_data);
 
@@ -239,27 +240,48 @@ where
             read_node(5, cache_parents, base_data),
         ];
 
-        // round 1 (0..6)
-        hasher.input(&parents);
+        hash_base_parents(&parents, hasher)
+    }
+}
+
+/// Finishes the label of a node of the first layer, from the labels of its base parents.
+#[inline]
+pub(crate) fn hash_base_parents(parents: &[&[u8]; BASE_DEGREE], mut hasher: Sha256) -> [u8; 32] {
+    // round 1 (0..6)
+    hasher.input(parents);
 
-        // round 2 (6..12)
-        hasher.input(&parents);
+    // round 2 (6..12)
+    hasher.input(parents);
 
-        // round 3 (12..18)
-        hasher.input(&parents);
+    // round 3 (12..18)
+    hasher.input(parents);
 
-        // round 4 (18..24)
-        hasher.input(&parents);
+    // round 4 (18..24)
+    hasher.input(parents);
 
-        // round 5 (24..30)
-        hasher.input(&parents);
+    // round 5 (24..30)
+    hasher.input(parents);
 
-        // round 6 (30..36)
-        hasher.input(&parents);
+    // round 6 (30..36)
+    hasher.input(parents);
 
-        // round 7 (37)
-        hasher.finish_with(parents[0])
-    }
+    // round 7 (37)
+    hasher.finish_with(parents[0])
+}
+
+/// Finishes the label of a node of the other layers, from the labels of its base parents and of
+/// its expander parents in the previous layer.
+#[inline]
+pub(crate) fn hash_exp_parents(parents: &[&[u8]; DEGREE], mut hasher: Sha256) -> [u8; 32] {
+    // round 1 (14)
+    hasher.input(parents);
+
+    // round 2 (14)
+    hasher.input(parents);
+
+    // round 3 (9)
+    hasher.input(&parents[..8]);
+    hasher.finish_with(parents[8])
 }
 
 impl<H, G> ParameterSetMetadata for StackedGraph<H, G>
@@ -1,19 +1,29 @@
 use std::cell::UnsafeCell;
 use std::fs::File;
 use std::hint::spin_loop;
+use std::io;
 use std::marker::{PhantomData, Sync};
 use std::mem::size_of;
 use std::path::Path;
 use std::slice;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use byte_slice_cast::{AsSliceOf, FromByteSlice};
-use log::{info, warn};
+use log::{debug, info, warn};
 use mapr::{Mmap, MmapMut, MmapOptions};
+#[cfg(target_os = "linux")]
+use storage_proofs_core::util::NODE_SIZE;
+
+#[cfg(target_os = "linux")]
+use crate::stacked::vanilla::shm;
 
 pub struct CacheReader<T> {
     file: File,
+    numa_node: Option<u32>,
+    /// Whether the cache is a shared memory segment, which is mapped as a whole rather than in
+    /// windows.
+    shm: bool,
     bufs: UnsafeCell<[Mmap; 2]>,
     size: usize,
     degree: usize,
@@ -93,7 +103,12 @@ impl IncrementingCursor {
 }
 
 impl<T: FromByteSlice> CacheReader<T> {
-    pub fn new(filename: &Path, window_size: Option<usize>, degree: usize) -> Result<Self> {
+    pub fn new(
+        filename: &Path,
+        window_size: Option<usize>,
+        degree: usize,
+        numa_node: Option<u32>,
+    ) -> Result<Self> {
         info!("initializing cache");
         let file = File::open(filename)?;
         let size = File::metadata(&file)?.len() as usize;
@@ -115,10 +130,12 @@ impl<T: FromByteSlice> CacheReader<T> {
             }
         };
 
-        let buf0 = Self::map_buf(0, window_size, &file)?;
-        let buf1 = Self::map_buf(window_size as u64, window_size, &file)?;
+        let buf0 = Self::map_buf(0, window_size, &file, numa_node)?;
+        let buf1 = Self::map_buf(window_size as u64, window_size, &file, numa_node)?;
         Ok(Self {
             file,
+            numa_node,
+            shm: false,
             bufs: UnsafeCell::new([buf0, buf1]),
             size,
             degree,
@@ -130,6 +147,29 @@ impl<T: FromByteSlice> CacheReader<T> {
         })
     }
 
+    /// Opens a cache loaded into the shared memory segment at `path`, see `shm`, of which the
+    /// first `size` bytes are the cache. It is mapped as a single window, which is never remapped.
+    #[cfg(target_os = "linux")]
+    pub fn new_shm(path: &Path, size: usize, degree: usize) -> Result<Self> {
+        info!("initializing cache from shared memory");
+        let file = File::open(path)?;
+        let buf0 = shm::map(&file, size)?;
+        // The second window is never used.
+        let buf1 = MmapOptions::new().len(1).map_anon()?.make_read_only()?;
+        Ok(Self {
+            file,
+            numa_node: None,
+            shm: true,
+            bufs: UnsafeCell::new([buf0, buf1]),
+            size,
+            degree,
+            window_size: size,
+            cursor: IncrementingCursor::new(0),
+            consumer: AtomicU64::new(0),
+            _t: PhantomData::<T>,
+        })
+    }
+
     pub fn size(&self) -> usize {
         self.size
     }
@@ -172,30 +212,86 @@ impl<T: FromByteSlice> CacheReader<T> {
     }
 
     pub fn start_reset(&self) -> Result<()> {
-        let buf0 = Self::map_buf(0, self.window_size, &self.file)?;
+        if self.shm {
+            return Ok(());
+        }
+        let buf0 = Self::map_buf(0, self.window_size, &self.file, self.numa_node)?;
         let bufs = unsafe { self.get_mut_bufs() };
         bufs[0] = buf0;
         Ok(())
     }
 
     pub fn finish_reset(&self) -> Result<()> {
-        let buf1 = Self::map_buf(self.window_size as u64, self.window_size, &self.file)?;
-        let bufs = unsafe { self.get_mut_bufs() };
-        bufs[1] = buf1;
+        if !self.shm {
+            let buf1 = Self::map_buf(
+                self.window_size as u64,
+                self.window_size,
+                &self.file,
+                self.numa_node,
+            )?;
+            let bufs = unsafe { self.get_mut_bufs() };
+            bufs[1] = buf1;
+        }
         self.cursor.store(0);
         self.store_consumer(0);
         Ok(())
     }
 
-    fn map_buf(offset: u64, len: usize, file: &File) -> Result<Mmap> {
-        unsafe {
+    /// Moves the reader to `node`, as if the nodes before it had been consumed, so that the
+    /// labeling of a layer can be resumed at that node. Must not be called while producers run.
+    pub fn seek(&self, node: u64) -> Result<()> {
+        let window_nodes = self.window_element_count() / self.degree;
+        let window = node as usize / window_nodes;
+        let num_windows = self.size / self.window_size;
+        ensure!(window < num_windows, "node {} is out of range", node);
+        if self.shm {
+            // The whole cache is mapped.
+            self.store_consumer(node);
+            return Ok(());
+        }
+
+        let bufs = unsafe { self.get_mut_bufs() };
+        bufs[window % 2] = Self::map_buf(
+            (window * self.window_size) as u64,
+            self.window_size,
+            &self.file,
+            self.numa_node,
+        )?;
+        // The cursor is the furthest window mapped.
+        let cursor = if window + 1 < num_windows {
+            bufs[(window + 1) % 2] = Self::map_buf(
+                ((window + 1) * self.window_size) as u64,
+                self.window_size,
+                &self.file,
+                self.numa_node,
+            )?;
+            window + 1
+        } else {
+            window
+        };
+        self.cursor.store(cursor);
+        self.store_consumer(node);
+        Ok(())
+    }
+
+    fn map_buf(offset: u64, len: usize, file: &File, numa_node: Option<u32>) -> Result<Mmap> {
+        let buf = unsafe {
             MmapOptions::new()
                 .offset(offset)
                 .len(len)
                 .private()
-                .map(file)
-                .map_err(|e| e.into())
+                .map(file)?
+        };
+        if let Some(numa_node) = numa_node {
+            if let Err(err) = bind_to_numa_node(&buf, numa_node) {
+                debug!(
+                    "failed to bind parents cache window to NUMA node: {:?}",
+                    err
+                );
+            }
         }
+
+        Ok(buf)
     }
 
     #[inline]
@@ -266,6 +362,7 @@ impl<T: FromByteSlice> CacheReader<T> {
             (new_window * self.window_size) as u64,
             self.window_size as usize,
             &self.file,
+            self.numa_node,
         )
         .expect("map_buf failed");
 
@@ -275,36 +372,163 @@ impl<T: FromByteSlice> CacheReader<T> {
     }
 }
 
-fn allocate_layer(sector_size: usize) -> Result<MmapMut> {
-    match MmapOptions::new()
-        .len(sector_size)
-        .private()
-        .clone()
-        .lock()
-        .map_anon()
-        .and_then(|mut layer| {
-            layer.mlock()?;
-            Ok(layer)
-        }) {
-        Ok(layer) => Ok(layer),
-        Err(err) => {
-            // fallback to not locked if permissions are not available
-            warn!("failed to lock map {:?}, falling back", err);
-            let layer = MmapOptions::new().len(sector_size).private().map_anon()?;
-            Ok(layer)
+/// Allocates a layer buffer. If `numa_node` is given, the memory is bound to that node before it
+/// is locked (and with that faulted in), so it is local to the cores labeling the layer.
+pub fn allocate_layer(sector_size: usize, numa_node: Option<u32>) -> Result<MmapMut> {
+    let mut layer = MmapOptions::new().len(sector_size).private().map_anon()?;
+
+    if let Some(numa_node) = numa_node {
+        if let Err(err) = bind_to_numa_node(&layer, numa_node) {
+            warn!("failed to bind layer to NUMA node {}: {:?}", numa_node, err);
         }
     }
+
+    // fallback to not locked if permissions are not available
+    if let Err(err) = layer.mlock() {
+        warn!("failed to lock map {:?}, falling back", err);
+    }
+
+    Ok(layer)
 }
 
+#[cfg(target_os = "linux")]
+const MPOL_BIND: libc::c_long = 2;
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: libc::c_ulong = 1 << 1;
+
+/// The node mask selecting only `numa_node`, in the layout of `mbind(2)`.
+#[cfg(target_os = "linux")]
+fn node_mask(numa_node: u32) -> Vec<libc::c_ulong> {
+    let bits = 8 * size_of::<libc::c_ulong>();
+    let mut mask = vec![0; numa_node as usize / bits + 1];
+    mask[numa_node as usize / bits] = 1 << (numa_node as usize % bits);
+    mask
+}
+
+/// Binds the pages of `buf` to `numa_node`, moving the pages already allocated on other nodes.
+#[cfg(target_os = "linux")]
+pub fn bind_to_numa_node(buf: &[u8], numa_node: u32) -> Result<()> {
+    let mask = node_mask(numa_node);
+    let max_node = (mask.len() * 8 * size_of::<libc::c_ulong>() + 1) as libc::c_ulong;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            buf.as_ptr(),
+            buf.len() as libc::c_ulong,
+            MPOL_BIND,
+            mask.as_ptr(),
+            max_node,
+            MPOL_MF_MOVE,
+        )
+    };
+    ensure!(result == 0, "mbind failed: {}", io::Error::last_os_error());
+
+    Ok(())
+}
+
+/// NUMA binding is only supported on Linux, elsewhere the memory is left where the OS puts it.
+#[cfg(not(target_os = "linux"))]
+pub fn bind_to_numa_node(_buf: &[u8], _numa_node: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Sets up the parents cache reader and the two layer buffers of the labeling, bound to
+/// `numa_node` if given. A cache in shared memory (`cache_in_shm`) is mapped as a whole.
 pub fn setup_create_label_memory(
     sector_size: usize,
     degree: usize,
     window_size: Option<usize>,
     cache_path: &Path,
+    cache_in_shm: bool,
+    numa_node: Option<u32>,
 ) -> Result<(CacheReader<u32>, MmapMut, MmapMut)> {
-    let parents_cache = CacheReader::new(cache_path, window_size, degree)?;
-    let layer_labels = allocate_layer(sector_size)?;
-    let exp_labels = allocate_layer(sector_size)?;
+    let parents_cache = open_cache_reader(
+        sector_size,
+        degree,
+        window_size,
+        cache_path,
+        cache_in_shm,
+        numa_node,
+    )?;
+    let layer_labels = allocate_layer(sector_size, numa_node)?;
+    let exp_labels = allocate_layer(sector_size, numa_node)?;
 
     Ok((parents_cache, layer_labels, exp_labels))
 }
+
+#[cfg(target_os = "linux")]
+fn open_cache_reader(
+    sector_size: usize,
+    degree: usize,
+    window_size: Option<usize>,
+    cache_path: &Path,
+    cache_in_shm: bool,
+    numa_node: Option<u32>,
+) -> Result<CacheReader<u32>> {
+    if cache_in_shm {
+        let nodes = sector_size / NODE_SIZE;
+        CacheReader::new_shm(cache_path, nodes * degree * size_of::<u32>(), degree)
+    } else {
+        CacheReader::new(cache_path, window_size, degree, numa_node)
+    }
+}
+
+/// Parent caches are only loaded into shared memory on Linux.
+#[cfg(not(target_os = "linux"))]
+fn open_cache_reader(
+    _sector_size: usize,
+    degree: usize,
+    window_size: Option<usize>,
+    cache_path: &Path,
+    _cache_in_shm: bool,
+    numa_node: Option<u32>,
+) -> Result<CacheReader<u32>> {
+    CacheReader::new(cache_path, window_size, degree, numa_node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use byte_slice_cast::AsByteSlice;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_node_mask() {
+        let bits = 8 * size_of::<libc::c_ulong>() as u32;
+        assert_eq!(node_mask(0), vec![1]);
+        assert_eq!(node_mask(3), vec![8]);
+        assert_eq!(node_mask(bits), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_cache_reader_seek() {
+        let degree = 2;
+        let nodes = 64;
+        let cache_dir = tempfile::tempdir().expect("tempdir failure");
+        let path = cache_dir.path().join("parents");
+        let parents: Vec<u32> = (0..(nodes * degree) as u32).collect();
+        std::fs::write(&path, parents.as_byte_slice()).expect("write failure");
+
+        // Windows of 8 nodes.
+        let window_size = 8 * degree * size_of::<u32>();
+        let reader = CacheReader::<u32>::new(&path, Some(window_size), degree, None)
+            .expect("cache reader failure");
+        for node in &[20, 63] {
+            reader.seek(*node).expect("seek failure");
+            assert_eq!(reader.get_consumer(), *node);
+            let pos = *node as usize * degree;
+            assert_eq!(unsafe { reader.consumer_slice_at(pos)[0] }, pos as u32);
+            assert_eq!(unsafe { reader.slice_at(pos)[0] }, pos as u32);
+        }
+        assert!(reader.seek(nodes as u64).is_err());
+    }
+
+    #[test]
+    fn test_allocate_layer() {
+        // Binding failures (e.g. a kernel without NUMA support) only log a warning.
+        let layer = allocate_layer(1 << 16, Some(0)).expect("allocate_layer failure");
+        assert_eq!(layer.len(), 1 << 16);
+        assert!(layer.iter().all(|byte| *byte == 0));
+    }
+}
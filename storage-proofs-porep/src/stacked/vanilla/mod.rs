@@ -8,25 +8,59 @@ mod cache;
 mod challenges;
 mod column;
 mod column_proof;
-#[cfg(feature = "multicore-sdr")]
-mod cores;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+pub(crate) mod cores;
 mod encoding_proof;
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+mod gpu_tuning;
 mod graph;
 mod labeling_proof;
-#[cfg(feature = "multicore-sdr")]
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
 mod memory_handling;
+#[cfg(all(feature = "multicore-sdr-os-affinity", not(feature = "multicore-sdr")))]
+mod os_topology;
 mod params;
 mod porep;
 mod proof;
 mod proof_scheme;
-#[cfg(feature = "multicore-sdr")]
+mod replica_sink;
+#[cfg(target_os = "linux")]
+mod shm;
+mod synth_proofs;
+mod tree_builder;
+mod tree_records;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
 mod utils;
 
-pub use challenges::{ChallengeRequirements, LayerChallenges};
+pub use cache::{verify_parent_cache, ParentCache, ParentCacheHeader, PARENT_CACHE_HEADER_VERSION};
+pub use challenges::{
+    ChallengeRequirements, LayerChallenges, SynthChallenges, SYNTHETIC_CHALLENGE_COUNT,
+};
 pub use column::Column;
 pub use column_proof::ColumnProof;
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+pub use cores::{
+    checkout_core_group_near, core_group_count, core_stats, register_core_plan, reload_core_plan,
+    select_core_plan, topology_core_count, CheckedOutCoreGroup, CheckoutPriority, CoreGroupStats,
+    CoreLocality, CoreStats,
+};
+pub use create_label::progress::{LabelingProgress, ProgressCallback};
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+pub use create_label::sizing::MulticoreSdrSizing;
+pub use create_label::strategy::LabelingStrategy;
+pub use create_label::LabelingOptions;
 pub use encoding_proof::EncodingProof;
 pub use graph::{StackedBucketGraph, StackedGraph, EXP_DEGREE};
 pub use labeling_proof::LabelingProof;
 pub use params::*;
-pub use proof::{StackedDrg, TreeRElementData, TOTAL_PARENTS};
+pub use proof::{
+    clear_replica_encoding, read_replica_encoding, tree_r_last_gpu_batch_size, ReplicaEncoding,
+    StackedDrg, TreeRElementData, TOTAL_PARENTS,
+};
+pub use replica_sink::{unwrap_replica_sink, ReplicaSink};
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub use tree_builder::NeptuneBackend;
+pub use tree_builder::{
+    gpu_batch_bytes, with_tree_builder_backend, BaseColumnTreeBuilder, BaseTreeBuilder,
+    TreeBuilderBackend,
+};
@@ -0,0 +1,558 @@
+//! The parts of the hwloc API used by `cores`, for builds of the multicore SDR without hwloc (the
+//! `multicore-sdr-os-affinity` feature).
+//!
+//! On Linux the packages, cores and logical processors are read from `/proc/cpuinfo`, or from
+//! sysfs if it doesn't report them (as on ARM), the NUMA nodes from sysfs, and threads are bound
+//! with `pthread_setaffinity_np`. The caches are not
+//! known, so the automatic grouping splits the cores of every package into groups. Memory is not
+//! bound by thread, the layer buffers are still bound to the NUMA node of their core group. On
+//! other systems no cores are found, so no cores are bound.
+
+use std::collections::{btree_set, BTreeMap, BTreeSet};
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(any(target_os = "linux", test))]
+use std::{fs, path::Path};
+
+use super::cores::ThreadId;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectType {
+    Machine,
+    Package,
+    NUMANode,
+    Cache,
+    Group,
+    Core,
+    PU,
+}
+
+pub type CpuBindFlags = i32;
+pub const CPUBIND_PROCESS: CpuBindFlags = 1 << 0;
+pub const CPUBIND_THREAD: CpuBindFlags = 1 << 1;
+
+pub type MemBindPolicy = i32;
+pub const MEMBIND_DEFAULT: MemBindPolicy = 0;
+pub const MEMBIND_BIND: MemBindPolicy = 2;
+
+pub type MemBindFlags = i32;
+pub const MEMBIND_THREAD: MemBindFlags = 1 << 1;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A set of logical processors, by their OS index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitmap(BTreeSet<u32>);
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Bitmap::default()
+    }
+
+    pub fn set(&mut self, id: u32) {
+        self.0.insert(id);
+    }
+
+    pub fn is_set(&self, id: u32) -> bool {
+        self.0.contains(&id)
+    }
+
+    /// The lowest index in the set, -1 if it is empty.
+    pub fn first(&self) -> i32 {
+        self.0.iter().next().map(|id| *id as i32).unwrap_or(-1)
+    }
+
+    /// Keeps only the lowest index.
+    pub fn singlify(&mut self) {
+        let first = self.0.iter().next().copied();
+        self.0.retain(|id| Some(*id) == first);
+    }
+}
+
+impl IntoIterator for Bitmap {
+    type Item = u32;
+    type IntoIter = btree_set::IntoIter<u32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+pub struct TopologyObject {
+    object_type: ObjectType,
+    os_index: u32,
+    cpuset: Bitmap,
+}
+
+impl TopologyObject {
+    pub fn object_type(&self) -> ObjectType {
+        self.object_type
+    }
+
+    pub fn os_index(&self) -> u32 {
+        self.os_index
+    }
+
+    pub fn cpuset(&self) -> Option<Bitmap> {
+        Some(self.cpuset.clone())
+    }
+
+    pub fn allowed_cpuset(&self) -> Option<Bitmap> {
+        Some(self.cpuset.clone())
+    }
+}
+
+pub struct Topology {
+    // From the machine down to the logical processors.
+    levels: Vec<Vec<TopologyObject>>,
+    numa_nodes: Vec<TopologyObject>,
+}
+
+impl Topology {
+    pub fn new() -> Self {
+        #[cfg(target_os = "linux")]
+        let (processors, numa_nodes) = {
+            let mut processors = fs::read_to_string("/proc/cpuinfo")
+                .map(|cpuinfo| parse_cpuinfo(&cpuinfo))
+                .unwrap_or_default();
+            read_sysfs_topology(Path::new("/sys/devices/system/cpu"), &mut processors);
+            (processors, read_numa_nodes())
+        };
+        #[cfg(not(target_os = "linux"))]
+        let (processors, numa_nodes) = (Vec::new(), Vec::new());
+
+        Topology {
+            levels: build_levels(&processors),
+            numa_nodes,
+        }
+    }
+
+    pub fn objects_with_type(
+        &self,
+        object_type: &ObjectType,
+    ) -> Result<Vec<&TopologyObject>, Error> {
+        if *object_type == ObjectType::NUMANode {
+            return Ok(self.numa_nodes.iter().collect());
+        }
+        self.levels
+            .iter()
+            .find(|level| level.first().map(TopologyObject::object_type) == Some(*object_type))
+            .map(|level| level.iter().collect())
+            .ok_or_else(|| Error(format!("no {:?} objects", object_type)))
+    }
+
+    /// The depth of `object_type`, or of the level below it if there is no such level.
+    pub fn depth_or_below_for_type(&self, object_type: &ObjectType) -> Result<u32, Error> {
+        let rank = |object_type: ObjectType| match object_type {
+            ObjectType::Machine => 0,
+            ObjectType::Package => 1,
+            ObjectType::Core => 2,
+            _ => 3,
+        };
+        self.levels
+            .iter()
+            .position(|level| {
+                level
+                    .first()
+                    .map(|object| rank(object.object_type) >= rank(*object_type))
+                    .unwrap_or(false)
+            })
+            .map(|depth| depth as u32)
+            .ok_or_else(|| Error(format!("no {:?} objects", object_type)))
+    }
+
+    pub fn objects_at_depth(&self, depth: u32) -> Vec<&TopologyObject> {
+        self.levels
+            .get(depth as usize)
+            .map(|level| level.iter().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_cpubind(&self, _flags: CpuBindFlags) -> Option<Bitmap> {
+        process_affinity()
+    }
+
+    pub fn get_cpubind_for_thread(&self, tid: ThreadId, _flags: CpuBindFlags) -> Option<Bitmap> {
+        thread_affinity(tid)
+    }
+
+    pub fn set_cpubind_for_thread(
+        &mut self,
+        tid: ThreadId,
+        set: Bitmap,
+        _flags: CpuBindFlags,
+    ) -> Result<(), Error> {
+        set_thread_affinity(tid, &set)
+    }
+
+    /// Memory is not bound by thread without hwloc.
+    pub fn set_membind(
+        &mut self,
+        _set: Bitmap,
+        _policy: MemBindPolicy,
+        _flags: MemBindFlags,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_affinity() -> Option<Bitmap> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret == 0 {
+        Some(from_cpu_set(&set))
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn thread_affinity(tid: ThreadId) -> Option<Bitmap> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let ret =
+        unsafe { libc::pthread_getaffinity_np(tid, mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret == 0 {
+        Some(from_cpu_set(&set))
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_thread_affinity(tid: ThreadId, cpuset: &Bitmap) -> Result<(), Error> {
+    let set = to_cpu_set(cpuset);
+    let ret = unsafe { libc::pthread_setaffinity_np(tid, mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(Error(format!("pthread_setaffinity_np failed: {}", ret)))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_affinity() -> Option<Bitmap> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_affinity(_tid: ThreadId) -> Option<Bitmap> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_thread_affinity(_tid: ThreadId, _cpuset: &Bitmap) -> Result<(), Error> {
+    Err(Error(
+        "binding threads is only supported on Linux".to_string(),
+    ))
+}
+
+/// A logical processor of `/proc/cpuinfo`, with its package and core if known.
+#[derive(Debug, PartialEq)]
+struct Processor {
+    index: u32,
+    package: Option<u32>,
+    core: Option<u32>,
+}
+
+/// Reads the `processor`, `physical id` and `core id` of every entry of `/proc/cpuinfo`. ARM
+/// kernels only report the processor, see `read_sysfs_topology`.
+#[cfg(any(target_os = "linux", test))]
+fn parse_cpuinfo(cpuinfo: &str) -> Vec<Processor> {
+    let mut processors: Vec<Processor> = Vec::new();
+    for line in cpuinfo.lines() {
+        let mut parts = line.splitn(2, ':').map(str::trim);
+        let (key, value) = match (parts.next(), parts.next().and_then(|v| v.parse().ok())) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue,
+        };
+        match (key, processors.last_mut()) {
+            ("processor", _) => processors.push(Processor {
+                index: value,
+                package: None,
+                core: None,
+            }),
+            ("physical id", Some(processor)) => processor.package = Some(value),
+            ("core id", Some(processor)) => processor.core = Some(value),
+            _ => {}
+        }
+    }
+    processors
+}
+
+/// Fills in the package and core of the processors `/proc/cpuinfo` has none for from `cpu_dir`,
+/// the sysfs directory of the processors. Packages unknown to the firmware are reported as -1.
+#[cfg(any(target_os = "linux", test))]
+fn read_sysfs_topology(cpu_dir: &Path, processors: &mut [Processor]) {
+    for processor in processors {
+        let topology = cpu_dir
+            .join(format!("cpu{}", processor.index))
+            .join("topology");
+        let read_id = |name: &str| {
+            fs::read_to_string(topology.join(name))
+                .ok()
+                .and_then(|id| id.trim().parse::<i64>().ok())
+                .filter(|id| *id >= 0)
+                .map(|id| id as u32)
+        };
+        if processor.package.is_none() {
+            processor.package = read_id("physical_package_id");
+        }
+        if processor.core.is_none() {
+            processor.core = read_id("core_id");
+        }
+    }
+}
+
+/// The machine, packages, cores (if all processors have a core id) and logical processors.
+fn build_levels(processors: &[Processor]) -> Vec<Vec<TopologyObject>> {
+    let object = |object_type, os_index, cpuset: &Bitmap| TopologyObject {
+        object_type,
+        os_index,
+        cpuset: cpuset.clone(),
+    };
+
+    let mut machine = Bitmap::new();
+    let mut packages: BTreeMap<u32, Bitmap> = BTreeMap::new();
+    let mut cores: BTreeMap<(u32, u32), Bitmap> = BTreeMap::new();
+    for processor in processors {
+        let package = processor.package.unwrap_or(0);
+        machine.set(processor.index);
+        packages.entry(package).or_default().set(processor.index);
+        if let Some(core) = processor.core {
+            cores
+                .entry((package, core))
+                .or_default()
+                .set(processor.index);
+        }
+    }
+
+    let mut levels = vec![vec![object(ObjectType::Machine, 0, &machine)]];
+    if processors.is_empty() {
+        return levels;
+    }
+    levels.push(
+        packages
+            .iter()
+            .map(|(package, cpuset)| object(ObjectType::Package, *package, cpuset))
+            .collect(),
+    );
+    if processors.iter().all(|processor| processor.core.is_some()) {
+        levels.push(
+            cores
+                .iter()
+                .map(|((_, core), cpuset)| object(ObjectType::Core, *core, cpuset))
+                .collect(),
+        );
+    }
+    // Like hwloc, the logical processors of a core are next to each other.
+    let mut pus: Vec<&Processor> = processors.iter().collect();
+    pus.sort_by_key(|processor| (processor.package, processor.core, processor.index));
+    levels.push(
+        pus.into_iter()
+            .map(|processor| {
+                let mut cpuset = Bitmap::new();
+                cpuset.set(processor.index);
+                object(ObjectType::PU, processor.index, &cpuset)
+            })
+            .collect(),
+    );
+
+    levels
+}
+
+/// Parses a sysfs CPU list like `0-3,8-11`.
+#[cfg(any(target_os = "linux", test))]
+fn parse_cpu_list(list: &str) -> Bitmap {
+    let mut cpuset = Bitmap::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range
+            .splitn(2, '-')
+            .map(|bound| bound.trim().parse::<u32>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(start)), Some(Ok(end))) => (start..=end).for_each(|cpu| cpuset.set(cpu)),
+            (Some(Ok(cpu)), None) => cpuset.set(cpu),
+            _ => {}
+        }
+    }
+    cpuset
+}
+
+#[cfg(target_os = "linux")]
+fn read_numa_nodes() -> Vec<TopologyObject> {
+    let entries = match fs::read_dir("/sys/devices/system/node") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut nodes: Vec<TopologyObject> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name();
+            let os_index = name.to_str()?.strip_prefix("node")?.parse().ok()?;
+            let cpulist = fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            Some(TopologyObject {
+                object_type: ObjectType::NUMANode,
+                os_index,
+                cpuset: parse_cpu_list(&cpulist),
+            })
+        })
+        .collect();
+    nodes.sort_by_key(TopologyObject::os_index);
+    nodes
+}
+
+#[cfg(target_os = "linux")]
+fn from_cpu_set(set: &libc::cpu_set_t) -> Bitmap {
+    let mut cpuset = Bitmap::new();
+    for cpu in 0..libc::CPU_SETSIZE as usize {
+        if unsafe { libc::CPU_ISSET(cpu, set) } {
+            cpuset.set(cpu as u32);
+        }
+    }
+    cpuset
+}
+
+#[cfg(target_os = "linux")]
+fn to_cpu_set(cpuset: &Bitmap) -> libc::cpu_set_t {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for cpu in cpuset.clone() {
+        if (cpu as usize) < libc::CPU_SETSIZE as usize {
+            unsafe { libc::CPU_SET(cpu as usize, &mut set) };
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPUINFO: &str = "processor\t: 0\nphysical id\t: 0\ncore id\t\t: 0\n\n\
+                           processor\t: 1\nphysical id\t: 0\ncore id\t\t: 1\n\n\
+                           processor\t: 2\nphysical id\t: 0\ncore id\t\t: 0\n\n\
+                           processor\t: 3\nphysical id\t: 0\ncore id\t\t: 1\n";
+
+    #[test]
+    fn test_parse_cpuinfo() {
+        let processors = parse_cpuinfo(CPUINFO);
+        assert_eq!(processors.len(), 4);
+        assert_eq!(
+            processors[2],
+            Processor {
+                index: 2,
+                package: Some(0),
+                core: Some(0),
+            }
+        );
+
+        let arm = parse_cpuinfo("processor\t: 0\nBogoMIPS\t: 50.00\n\nprocessor\t: 1\n");
+        assert_eq!(arm.len(), 2);
+        assert_eq!(arm[1].core, None);
+    }
+
+    #[test]
+    fn test_read_sysfs_topology() {
+        let cpu_dir = tempfile::tempdir().expect("tempdir failure");
+        for (cpu, package, core) in &[(0, "-1", "0"), (1, "-1", "1")] {
+            let topology = cpu_dir.path().join(format!("cpu{}", cpu)).join("topology");
+            fs::create_dir_all(&topology).expect("create_dir_all failure");
+            fs::write(
+                topology.join("physical_package_id"),
+                format!("{}\n", package),
+            )
+            .expect("write failure");
+            fs::write(topology.join("core_id"), format!("{}\n", core)).expect("write failure");
+        }
+
+        let mut processors = parse_cpuinfo("processor\t: 0\n\nprocessor\t: 1\n\nprocessor\t: 2\n");
+        read_sysfs_topology(cpu_dir.path(), &mut processors);
+        assert_eq!(
+            processors[1],
+            Processor {
+                index: 1,
+                package: None,
+                core: Some(1),
+            }
+        );
+        // Without sysfs entries, nothing is known.
+        assert_eq!(processors[2].core, None);
+
+        // What `/proc/cpuinfo` reports is kept.
+        let mut processors = parse_cpuinfo(CPUINFO);
+        read_sysfs_topology(cpu_dir.path(), &mut processors);
+        assert_eq!(processors[1].core, Some(1));
+        assert_eq!(processors[1].package, Some(0));
+    }
+
+    #[test]
+    fn test_topology_levels() {
+        let topo = Topology {
+            levels: build_levels(&parse_cpuinfo(CPUINFO)),
+            numa_nodes: Vec::new(),
+        };
+        let cores = topo.objects_with_type(&ObjectType::Core).expect("no cores");
+        assert_eq!(cores.len(), 2);
+        assert_eq!(
+            cores[1]
+                .cpuset()
+                .expect("no cpuset")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(
+            topo.depth_or_below_for_type(&ObjectType::Core).ok(),
+            Some(2)
+        );
+        assert_eq!(topo.objects_at_depth(1).len(), 1);
+
+        // Siblings are next to each other.
+        let pus: Vec<u32> = topo
+            .objects_with_type(&ObjectType::PU)
+            .expect("no processors")
+            .iter()
+            .map(|pu| pu.os_index())
+            .collect();
+        assert_eq!(pus, vec![0, 2, 1, 3]);
+
+        let arm = Topology {
+            levels: build_levels(&parse_cpuinfo("processor\t: 0\n\nprocessor\t: 1\n")),
+            numa_nodes: Vec::new(),
+        };
+        assert!(arm.objects_with_type(&ObjectType::Core).is_err());
+        assert_eq!(arm.depth_or_below_for_type(&ObjectType::Core).ok(), Some(2));
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        let cpuset = parse_cpu_list("0-2,8\n");
+        assert_eq!(cpuset.into_iter().collect::<Vec<_>>(), vec![0, 1, 2, 8]);
+        assert_eq!(parse_cpu_list("").first(), -1);
+    }
+
+    #[test]
+    fn test_bitmap() {
+        let mut bitmap = Bitmap::new();
+        bitmap.set(5);
+        bitmap.set(3);
+        assert_eq!(bitmap.first(), 3);
+        assert!(bitmap.is_set(5));
+        bitmap.singlify();
+        assert!(!bitmap.is_set(5));
+        assert_eq!(bitmap.into_iter().collect::<Vec<_>>(), vec![3]);
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::fs::remove_file;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
@@ -27,7 +28,8 @@ use storage_proofs_core::{
 };
 
 use crate::stacked::vanilla::{
-    Column, ColumnProof, EncodingProof, LabelingProof, LayerChallenges, StackedBucketGraph,
+    Column, ColumnProof, EncodingProof, LabelingProof, LayerChallenges, ReplicaSink,
+    StackedBucketGraph,
 };
 
 pub const BINARY_ARITY: usize = 2;
@@ -132,7 +134,29 @@ impl<T: Domain, S: Domain> PublicInputs<T, S> {
     ) -> Vec<usize> {
         let k = partition_k.unwrap_or(0);
 
-        layer_challenges.derive::<T>(leaves, &self.replica_id, &self.seed, k as u8)
+        if layer_challenges.synth_count().is_some() {
+            let comm_r = &self
+                .tau
+                .as_ref()
+                .expect("synthetic challenges require comm_r")
+                .comm_r;
+            layer_challenges.derive_synthetic::<T>(
+                leaves,
+                &self.replica_id,
+                comm_r,
+                &self.seed,
+                k as u8,
+            )
+        } else if layer_challenges.is_non_interactive() {
+            let comm_r = &self
+                .tau
+                .as_ref()
+                .expect("non-interactive challenges require comm_r")
+                .comm_r;
+            layer_challenges.derive_non_interactive::<T>(leaves, &self.replica_id, comm_r, k as u8)
+        } else {
+            layer_challenges.derive::<T>(leaves, &self.replica_id, &self.seed, k as u8)
+        }
     }
 }
 
@@ -328,6 +352,34 @@ pub type TransformedLayers<Tree, G> = (
     TemporaryAux<Tree, G>,
 );
 
+/// Options of phase2 of replication, see `StackedDrg::replicate_phase2_with_options`.
+#[derive(Clone, Default)]
+pub struct Phase2Options {
+    /// The rows of tree_r_last to discard instead of the default. The value is recorded in t_aux.
+    pub tree_r_last_rows_to_discard: Option<usize>,
+    /// The GPUs (indexes into the GPUs found by `rust-gpu-tools`) to split the base trees of tree_c
+    /// across, e.g. so that PC2 of a sector uses both GPUs of a dual-GPU machine. Assigning devices
+    /// builds tree_c on the GPU (with Poseidon), and builders on assigned devices don't wait for
+    /// other builders on the GPU, the caller schedules the sectors on them. With no devices, tree_c
+    /// is built as configured.
+    pub tree_c_devices: Vec<usize>,
+    /// The sink the encoded replica is also streamed to, see `replica_sink`.
+    pub replica_sink: Option<ReplicaSink>,
+}
+
+impl fmt::Debug for Phase2Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Phase2Options")
+            .field(
+                "tree_r_last_rows_to_discard",
+                &self.tree_r_last_rows_to_discard,
+            )
+            .field("tree_c_devices", &self.tree_c_devices)
+            .field("replica_sink", &self.replica_sink.is_some())
+            .finish()
+    }
+}
+
 /// Tau for a single parition.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Tau<D: Domain, E: Domain> {
@@ -470,10 +522,15 @@ impl<Tree: MerkleTreeTrait, G: Hasher> TemporaryAux<Tree, G> {
         }
         trace!("tree c deleted");
 
-        for i in 0..t_aux.labels.labels.len() {
-            let cur_config = t_aux.labels.labels[i].clone();
-            if cached(&cur_config) {
-                DiskStore::<<Tree::Hasher as Hasher>::Domain>::delete(cur_config)
+        Self::clear_labels(&t_aux)
+    }
+
+    /// Discards the persisted layers, e.g. of a synthetic PoRep once its synthetic proofs are
+    /// stored, which is all it needs the layers for.
+    pub fn clear_labels(t_aux: &TemporaryAux<Tree, G>) -> Result<()> {
+        for (i, config) in t_aux.labels.labels.iter().enumerate() {
+            if Path::new(&StoreConfig::data_path(&config.path, &config.id)).exists() {
+                DiskStore::<<Tree::Hasher as Hasher>::Domain>::delete(config.clone())
                     .with_context(|| format!("labels {}", i))?;
                 trace!("layer {} deleted", i);
             }
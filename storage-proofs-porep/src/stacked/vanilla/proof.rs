@@ -1,11 +1,11 @@
 use std::any::TypeId;
-use std::fs;
+use std::fs::{self, OpenOptions};
 use std::marker::PhantomData;
 use std::panic::panic_any;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::Arc;
 
-use anyhow::Context;
+use anyhow::{bail, ensure, Context};
 use bincode::deserialize;
 use blstrs::Scalar as Fr;
 use fdlimit::raise_fd_limit;
@@ -14,42 +14,51 @@ use filecoin_hashers::{poseidon::PoseidonHasher, Domain, HashFunction, Hasher, P
 use generic_array::typenum::{Unsigned, U0, U11, U2, U8};
 use lazy_static::lazy_static;
 use log::{error, info, trace, warn};
+use mapr::MmapOptions;
 use merkletree::{
     merkle::{get_merkle_tree_len, is_merkle_tree_size_valid},
     store::{DiskStore, Store, StoreConfig},
 };
 use rayon::prelude::{
-    IndexedParallelIterator, IntoParallelIterator, ParallelIterator, ParallelSliceMut,
+    IndexedParallelIterator, IntoParallelIterator, ParallelIterator, ParallelSlice,
+    ParallelSliceMut,
 };
 use storage_proofs_core::{
     cache_key::CacheKey,
+    control,
     data::Data,
     drgraph::Graph,
     error::Result,
+    events::{self, Event},
     measurements::{measure_op, Operation},
     merkle::{
         create_disk_tree, create_lc_tree, get_base_tree_count, split_config,
         split_config_and_replica, BinaryMerkleTree, DiskTree, LCTree, MerkleProofTrait, MerkleTree,
         MerkleTreeTrait,
     },
-    settings::SETTINGS,
+    tenant::{self, current_tenant, with_current_tenant},
     util::{default_rows_to_discard, NODE_SIZE},
 };
 use yastl::Pool;
 
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+use crate::stacked::vanilla::tree_builder::{tree_c_backends, NeptuneBackend};
 use crate::{
     encode::{decode, encode, encode_fr},
     stacked::vanilla::{
         challenges::LayerChallenges,
         column::Column,
-        create_label,
+        create_label::{self, LabelingOptions},
         graph::StackedBucketGraph,
         hash::hash_single_column,
         params::{
-            get_node, Labels, LabelsCache, PersistentAux, Proof, PublicInputs, PublicParams,
-            ReplicaColumnProof, Tau, TemporaryAux, TemporaryAuxCache, TransformedLayers,
-            BINARY_ARITY,
+            get_node, Labels, LabelsCache, PersistentAux, Phase2Options, Proof, PublicInputs,
+            PublicParams, ReplicaColumnProof, Tau, TemporaryAux, TemporaryAuxCache,
+            TransformedLayers, BINARY_ARITY,
         },
+        replica_sink::ReplicaStream,
+        tree_builder::{tree_builder_backend, Backend},
+        tree_records::{clear_records, PendingTrees},
         EncodingProof, LabelingProof,
     },
     PoRep,
@@ -58,12 +67,6 @@ use crate::{
 pub const TOTAL_PARENTS: usize = 37;
 
 lazy_static! {
-    /// Ensure that only one `TreeBuilder` or `ColumnTreeBuilder` uses the GPU at a time.
-    /// Curently, this is accomplished by only instantiating at most one at a time.
-    /// It might be possible to relax this constraint, but in that case, only one builder
-    /// should actually be active at any given time, so the mutex should still be used.
-    static ref GPU_LOCK: Mutex<()> = Mutex::new(());
-
     static ref THREAD_POOL: Pool = Pool::new(num_cpus::get());
 }
 
@@ -104,11 +107,33 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         layers: usize,
         partition_count: usize,
     ) -> Result<Vec<Vec<Proof<Tree, G>>>> {
+        let graph_size = graph.size();
+
+        (0..partition_count)
+            .map(|k| {
+                trace!("proving partition {}/{}", k + 1, partition_count);
+
+                // Derive the set of challenges we are proving over.
+                let challenges = pub_inputs.challenges(layer_challenges, graph_size, Some(k));
+
+                Self::prove_challenges(graph, pub_inputs, p_aux, t_aux, layers, &challenges)
+            })
+            .collect()
+    }
+
+    /// Proves the labeling and encoding of the nodes `challenges`, from the layers and trees of
+    /// `t_aux`.
+    pub(crate) fn prove_challenges(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        pub_inputs: &PublicInputs<<Tree::Hasher as Hasher>::Domain, <G as Hasher>::Domain>,
+        p_aux: &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+        t_aux: &TemporaryAuxCache<Tree, G>,
+        layers: usize,
+        challenges: &[usize],
+    ) -> Result<Vec<Proof<Tree, G>>> {
         assert!(layers > 0);
         assert_eq!(t_aux.labels.len(), layers);
 
-        let graph_size = graph.size();
-
         // Sanity checks on restored trees.
         assert!(pub_inputs.tau.is_some());
         assert_eq!(
@@ -146,146 +171,134 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 .collect()
         };
 
-        (0..partition_count)
-            .map(|k| {
-                trace!("proving partition {}/{}", k + 1, partition_count);
-
-                // Derive the set of challenges we are proving over.
-                let challenges = pub_inputs.challenges(layer_challenges, graph_size, Some(k));
+        // Stacked commitment specifics
+        challenges
+            .into_par_iter()
+            .map(|&challenge| {
+                trace!(" challenge {}", challenge);
+                assert!(challenge < graph.size(), "Invalid challenge");
+                assert!(challenge > 0, "Invalid challenge");
+
+                // Initial data layer openings (c_X in Comm_D)
+                let comm_d_proof = t_aux.tree_d.gen_proof(challenge)?;
+                assert!(comm_d_proof.validate(challenge));
+
+                // Stacked replica column openings
+                let rcp = {
+                    let (c_x, drg_parents, exp_parents) = {
+                        assert_eq!(p_aux.comm_c, t_aux.tree_c.root());
+                        let tree_c = &t_aux.tree_c;
+
+                        // All labels in C_X
+                        trace!("  c_x");
+                        let c_x = t_aux.column(challenge as u32)?.into_proof(tree_c)?;
+
+                        // All labels in the DRG parents.
+                        trace!("  drg_parents");
+                        let drg_parents = get_drg_parents_columns(challenge)?
+                            .into_iter()
+                            .map(|column| column.into_proof(tree_c))
+                            .collect::<Result<_>>()?;
+
+                        // Labels for the expander parents
+                        trace!("  exp_parents");
+                        let exp_parents = get_exp_parents_columns(challenge)?
+                            .into_iter()
+                            .map(|column| column.into_proof(tree_c))
+                            .collect::<Result<_>>()?;
+
+                        (c_x, drg_parents, exp_parents)
+                    };
 
-                // Stacked commitment specifics
-                challenges
-                    .into_par_iter()
-                    .enumerate()
-                    .map(|(challenge_index, challenge)| {
-                        trace!(" challenge {} ({})", challenge, challenge_index);
-                        assert!(challenge < graph.size(), "Invalid challenge");
-                        assert!(challenge > 0, "Invalid challenge");
-
-                        // Initial data layer openings (c_X in Comm_D)
-                        let comm_d_proof = t_aux.tree_d.gen_proof(challenge)?;
-                        assert!(comm_d_proof.validate(challenge));
-
-                        // Stacked replica column openings
-                        let rcp = {
-                            let (c_x, drg_parents, exp_parents) = {
-                                assert_eq!(p_aux.comm_c, t_aux.tree_c.root());
-                                let tree_c = &t_aux.tree_c;
-
-                                // All labels in C_X
-                                trace!("  c_x");
-                                let c_x = t_aux.column(challenge as u32)?.into_proof(tree_c)?;
-
-                                // All labels in the DRG parents.
-                                trace!("  drg_parents");
-                                let drg_parents = get_drg_parents_columns(challenge)?
-                                    .into_iter()
-                                    .map(|column| column.into_proof(tree_c))
-                                    .collect::<Result<_>>()?;
-
-                                // Labels for the expander parents
-                                trace!("  exp_parents");
-                                let exp_parents = get_exp_parents_columns(challenge)?
-                                    .into_iter()
-                                    .map(|column| column.into_proof(tree_c))
-                                    .collect::<Result<_>>()?;
-
-                                (c_x, drg_parents, exp_parents)
-                            };
-
-                            ReplicaColumnProof {
-                                c_x,
-                                drg_parents,
-                                exp_parents,
-                            }
-                        };
+                    ReplicaColumnProof {
+                        c_x,
+                        drg_parents,
+                        exp_parents,
+                    }
+                };
 
-                        // Final replica layer openings
-                        trace!("final replica layer openings");
-                        let comm_r_last_proof = t_aux.tree_r_last.gen_cached_proof(
-                            challenge,
-                            Some(t_aux.tree_r_last_config_rows_to_discard),
-                        )?;
+                // Final replica layer openings
+                trace!("final replica layer openings");
+                let comm_r_last_proof = t_aux
+                    .tree_r_last
+                    .gen_cached_proof(challenge, Some(t_aux.tree_r_last_config_rows_to_discard))?;
+
+                debug_assert!(comm_r_last_proof.validate(challenge));
+
+                // Labeling Proofs Layer 1..l
+                let mut labeling_proofs = Vec::with_capacity(layers);
+                let mut encoding_proof = None;
+
+                for layer in 1..=layers {
+                    trace!("  encoding proof layer {}", layer,);
+                    let parents_data: Vec<<Tree::Hasher as Hasher>::Domain> = if layer == 1 {
+                        let mut parents = vec![0; graph.base_graph().degree()];
+                        graph.base_parents(challenge, &mut parents)?;
+
+                        parents
+                            .into_par_iter()
+                            .map(|parent| t_aux.domain_node_at_layer(layer, parent))
+                            .collect::<Result<_>>()?
+                    } else {
+                        let mut parents = vec![0; graph.degree()];
+                        graph.parents(challenge, &mut parents)?;
+                        let base_parents_count = graph.base_graph().degree();
+
+                        parents
+                            .into_par_iter()
+                            .enumerate()
+                            .map(|(i, parent)| {
+                                if i < base_parents_count {
+                                    // parents data for base parents is from the current layer
+                                    t_aux.domain_node_at_layer(layer, parent)
+                                } else {
+                                    // parents data for exp parents is from the previous layer
+                                    t_aux.domain_node_at_layer(layer - 1, parent)
+                                }
+                            })
+                            .collect::<Result<_>>()?
+                    };
 
-                        debug_assert!(comm_r_last_proof.validate(challenge));
-
-                        // Labeling Proofs Layer 1..l
-                        let mut labeling_proofs = Vec::with_capacity(layers);
-                        let mut encoding_proof = None;
-
-                        for layer in 1..=layers {
-                            trace!("  encoding proof layer {}", layer,);
-                            let parents_data: Vec<<Tree::Hasher as Hasher>::Domain> = if layer == 1
-                            {
-                                let mut parents = vec![0; graph.base_graph().degree()];
-                                graph.base_parents(challenge, &mut parents)?;
-
-                                parents
-                                    .into_par_iter()
-                                    .map(|parent| t_aux.domain_node_at_layer(layer, parent))
-                                    .collect::<Result<_>>()?
-                            } else {
-                                let mut parents = vec![0; graph.degree()];
-                                graph.parents(challenge, &mut parents)?;
-                                let base_parents_count = graph.base_graph().degree();
-
-                                parents
-                                    .into_par_iter()
-                                    .enumerate()
-                                    .map(|(i, parent)| {
-                                        if i < base_parents_count {
-                                            // parents data for base parents is from the current layer
-                                            t_aux.domain_node_at_layer(layer, parent)
-                                        } else {
-                                            // parents data for exp parents is from the previous layer
-                                            t_aux.domain_node_at_layer(layer - 1, parent)
-                                        }
-                                    })
-                                    .collect::<Result<_>>()?
-                            };
+                    // repeat parents
+                    let mut parents_data_full = vec![Default::default(); TOTAL_PARENTS];
+                    for chunk in parents_data_full.chunks_mut(parents_data.len()) {
+                        chunk.copy_from_slice(&parents_data[..chunk.len()]);
+                    }
 
-                            // repeat parents
-                            let mut parents_data_full = vec![Default::default(); TOTAL_PARENTS];
-                            for chunk in parents_data_full.chunks_mut(parents_data.len()) {
-                                chunk.copy_from_slice(&parents_data[..chunk.len()]);
-                            }
+                    let proof = LabelingProof::<Tree::Hasher>::new(
+                        layer as u32,
+                        challenge as u64,
+                        parents_data_full.clone(),
+                    );
 
-                            let proof = LabelingProof::<Tree::Hasher>::new(
-                                layer as u32,
-                                challenge as u64,
-                                parents_data_full.clone(),
-                            );
-
-                            {
-                                let labeled_node = rcp.c_x.get_node_at_layer(layer)?;
-                                assert!(
-                                    proof.verify(&pub_inputs.replica_id, labeled_node),
-                                    "Invalid encoding proof generated at layer {}",
-                                    layer,
-                                );
-                                trace!("Valid encoding proof generated at layer {}", layer);
-                            }
+                    {
+                        let labeled_node = rcp.c_x.get_node_at_layer(layer)?;
+                        assert!(
+                            proof.verify(&pub_inputs.replica_id, labeled_node),
+                            "Invalid encoding proof generated at layer {}",
+                            layer,
+                        );
+                        trace!("Valid encoding proof generated at layer {}", layer);
+                    }
 
-                            labeling_proofs.push(proof);
+                    labeling_proofs.push(proof);
 
-                            if layer == layers {
-                                encoding_proof = Some(EncodingProof::new(
-                                    layer as u32,
-                                    challenge as u64,
-                                    parents_data_full,
-                                ));
-                            }
-                        }
+                    if layer == layers {
+                        encoding_proof = Some(EncodingProof::new(
+                            layer as u32,
+                            challenge as u64,
+                            parents_data_full,
+                        ));
+                    }
+                }
 
-                        Ok(Proof {
-                            comm_d_proofs: comm_d_proof,
-                            replica_column_proofs: rcp,
-                            comm_r_last_proof,
-                            labeling_proofs,
-                            encoding_proof: encoding_proof.expect("invalid tapering"),
-                        })
-                    })
-                    .collect()
+                Ok(Proof {
+                    comm_d_proofs: comm_d_proof,
+                    replica_column_proofs: rcp,
+                    comm_r_last_proof,
+                    labeling_proofs,
+                    encoding_proof: encoding_proof.expect("invalid tapering"),
+                })
             })
             .collect()
     }
@@ -331,11 +344,33 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         replica_id: &<Tree::Hasher as Hasher>::Domain,
         config: StoreConfig,
     ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+        Self::generate_labels_and_encode(
+            graph,
+            layer_challenges,
+            replica_id,
+            config,
+            None,
+            &LabelingOptions::default(),
+        )
+    }
+
+    /// Generates the layers as needed for encoding with `options` and, if `encode_data` is given,
+    /// encodes it in place with the last layer.
+    fn generate_labels_and_encode(
+        graph: &StackedBucketGraph<Tree::Hasher>,
+        layer_challenges: &LayerChallenges,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        encode_data: Option<&mut [u8]>,
+        options: &LabelingOptions,
+    ) -> Result<(Labels<Tree>, Vec<LayerState>)> {
+        // The trees of previous layers must not be reused by PC2.
+        clear_records(&config.path)?;
         let mut parent_cache = graph.parent_cache()?;
 
-        #[cfg(feature = "multicore-sdr")]
+        #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
         {
-            if SETTINGS.use_multicore_sdr {
+            if create_label::strategy::use_multicore_sdr(options.strategy, &tenant::settings())? {
                 info!("multi core replication");
                 create_label::multi::create_labels_for_encoding(
                     graph,
@@ -343,6 +378,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     layer_challenges.layers(),
                     replica_id,
                     config,
+                    encode_data,
+                    options,
                 )
             } else {
                 info!("single core replication");
@@ -352,12 +389,16 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     layer_challenges.layers(),
                     replica_id,
                     config,
+                    encode_data,
+                    options,
                 )
             }
         }
 
-        #[cfg(not(feature = "multicore-sdr"))]
+        #[cfg(not(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity")))]
         {
+            // Fails if the multicore SDR is requested for the sector.
+            create_label::strategy::use_multicore_sdr(options.strategy, &tenant::settings())?;
             info!("single core replication");
             create_label::single::create_labels_for_encoding(
                 graph,
@@ -365,6 +406,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 layer_challenges.layers(),
                 replica_id,
                 config,
+                encode_data,
+                options,
             )
         }
     }
@@ -377,10 +420,11 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         config: StoreConfig,
     ) -> Result<LabelsCache<Tree>> {
         let mut parent_cache = graph.parent_cache()?;
+        let options = LabelingOptions::default();
 
-        #[cfg(feature = "multicore-sdr")]
+        #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
         {
-            if SETTINGS.use_multicore_sdr {
+            if create_label::strategy::use_multicore_sdr(options.strategy, &tenant::settings())? {
                 info!("multi core replication");
                 create_label::multi::create_labels_for_decoding(
                     graph,
@@ -388,6 +432,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     layer_challenges.layers(),
                     replica_id,
                     config,
+                    &options,
                 )
             } else {
                 info!("single core replication");
@@ -397,12 +442,15 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     layer_challenges.layers(),
                     replica_id,
                     config,
+                    &options,
                 )
             }
         }
 
-        #[cfg(not(feature = "multicore-sdr"))]
+        #[cfg(not(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity")))]
         {
+            // Fails if the multicore SDR is requested for the sector.
+            create_label::strategy::use_multicore_sdr(options.strategy, &tenant::settings())?;
             info!("single core replication");
             create_label::single::create_labels_for_decoding(
                 graph,
@@ -410,6 +458,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 layer_challenges.layers(),
                 replica_id,
                 config,
+                &options,
             )
         }
     }
@@ -437,39 +486,77 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         Ok(tree)
     }
 
-    // Even if the column builder is enabled, the GPU column builder
-    // only supports Poseidon hashes.
+    // Even if the column builder is enabled (in the settings, or by assigning devices to
+    // tree_c), the GPU column builder only supports Poseidon hashes.
     pub fn use_gpu_column_builder() -> bool {
-        SETTINGS.use_gpu_column_builder
+        Self::use_gpu_column_builder_on(&[])
+    }
+
+    fn use_gpu_column_builder_on(devices: &[usize]) -> bool {
+        (tenant::settings().use_gpu_column_builder || !devices.is_empty())
             && TypeId::of::<Tree::Hasher>() == TypeId::of::<PoseidonHasher>()
     }
 
     // Even if the tree builder is enabled, the GPU tree builder
     // only supports Poseidon hashes.
     pub fn use_gpu_tree_builder() -> bool {
-        SETTINGS.use_gpu_tree_builder
+        tenant::settings().use_gpu_tree_builder
             && TypeId::of::<Tree::Hasher>() == TypeId::of::<PoseidonHasher>()
     }
 
+    /// The tree builder backend of the current thread, see [`with_tree_builder_backend`], if the
+    /// trees are Poseidon trees.
+    ///
+    /// [`with_tree_builder_backend`]: crate::stacked::with_tree_builder_backend
+    fn custom_tree_builder() -> Option<Backend> {
+        tree_builder_backend()
+            .filter(|_| TypeId::of::<Tree::Hasher>() == TypeId::of::<PoseidonHasher>())
+    }
+
     #[cfg(any(feature = "cuda", feature = "opencl"))]
     fn generate_tree_c<ColumnArity, TreeArity>(
         layers: usize,
         nodes_count: usize,
         tree_count: usize,
         configs: Vec<StoreConfig>,
+        pending: &PendingTrees,
         labels: &LabelsCache<Tree>,
+        devices: &[usize],
     ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
         TreeArity: PoseidonArity,
     {
-        if Self::use_gpu_column_builder() {
-            Self::generate_tree_c_gpu::<ColumnArity, TreeArity>(
+        let memory_budget = tenant::settings().tree_c_memory_budget as usize;
+        if let Some(backend) = Self::custom_tree_builder() {
+            Self::generate_tree_c_batched::<ColumnArity, TreeArity>(
+                layers,
+                nodes_count,
+                tree_count,
+                configs,
+                pending,
+                labels,
+                vec![backend],
+            )
+        } else if memory_budget != 0 {
+            Self::generate_tree_c_windowed::<ColumnArity, TreeArity>(
+                layers,
+                nodes_count,
+                tree_count,
+                configs,
+                pending,
+                labels,
+                memory_budget,
+            )
+        } else if Self::use_gpu_column_builder_on(devices) {
+            Self::generate_tree_c_batched::<ColumnArity, TreeArity>(
                 layers,
                 nodes_count,
                 tree_count,
                 configs,
+                pending,
                 labels,
+                tree_c_backends(devices)?,
             )
         } else {
             Self::generate_tree_c_cpu::<ColumnArity, TreeArity>(
@@ -477,6 +564,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 nodes_count,
                 tree_count,
                 configs,
+                pending,
                 labels,
             )
         }
@@ -488,29 +576,58 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         nodes_count: usize,
         tree_count: usize,
         configs: Vec<StoreConfig>,
+        pending: &PendingTrees,
         labels: &LabelsCache<Tree>,
+        _devices: &[usize],
     ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
         TreeArity: PoseidonArity,
     {
-        Self::generate_tree_c_cpu::<ColumnArity, TreeArity>(
-            layers,
-            nodes_count,
-            tree_count,
-            configs,
-            labels,
-        )
+        let memory_budget = tenant::settings().tree_c_memory_budget as usize;
+        if let Some(backend) = Self::custom_tree_builder() {
+            Self::generate_tree_c_batched::<ColumnArity, TreeArity>(
+                layers,
+                nodes_count,
+                tree_count,
+                configs,
+                pending,
+                labels,
+                vec![backend],
+            )
+        } else if memory_budget != 0 {
+            Self::generate_tree_c_windowed::<ColumnArity, TreeArity>(
+                layers,
+                nodes_count,
+                tree_count,
+                configs,
+                pending,
+                labels,
+                memory_budget,
+            )
+        } else {
+            Self::generate_tree_c_cpu::<ColumnArity, TreeArity>(
+                layers,
+                nodes_count,
+                tree_count,
+                configs,
+                pending,
+                labels,
+            )
+        }
     }
 
     #[allow(clippy::needless_range_loop)]
-    #[cfg(any(feature = "cuda", feature = "opencl"))]
-    fn generate_tree_c_gpu<ColumnArity, TreeArity>(
+    /// Builds tree_c with tree builder backends, the `g`th backend building every `groups`th
+    /// base tree from the `g`th one.
+    fn generate_tree_c_batched<ColumnArity, TreeArity>(
         layers: usize,
         nodes_count: usize,
         tree_count: usize,
         configs: Vec<StoreConfig>,
+        pending: &PendingTrees,
         labels: &LabelsCache<Tree>,
+        backends: Vec<Backend>,
     ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         ColumnArity: 'static + PoseidonArity,
@@ -518,16 +635,14 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
     {
         use std::cmp::min;
         use std::sync::mpsc::sync_channel as channel;
-        use std::sync::{Arc, RwLock};
+        use std::sync::RwLock;
 
         use fr32::fr_into_bytes;
-        use generic_array::GenericArray;
-        use neptune::{
-            batch_hasher::Batcher,
-            column_tree_builder::{ColumnTreeBuilder, ColumnTreeBuilderTrait},
-        };
 
-        info!("generating tree c using the GPU");
+        info!(
+            "generating tree c using the {} tree builder",
+            backends[0].name()
+        );
         // Build the tree for CommC
         measure_op(Operation::GenerateTreeC, || {
             info!("Building column hashes");
@@ -541,154 +656,155 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             // Override these values with care using environment variables:
             // FIL_PROOFS_MAX_GPU_COLUMN_BATCH_SIZE, FIL_PROOFS_MAX_GPU_TREE_BATCH_SIZE, and
             // FIL_PROOFS_COLUMN_WRITE_BATCH_SIZE respectively.
-            let max_gpu_column_batch_size = SETTINGS.max_gpu_column_batch_size as usize;
-            let max_gpu_tree_batch_size = SETTINGS.max_gpu_tree_batch_size as usize;
-            let column_write_batch_size = SETTINGS.column_write_batch_size as usize;
-
-            // This channel will receive batches of columns and add them to the ColumnTreeBuilder.
-            let (builder_tx, builder_rx) = channel(0);
+            let max_gpu_column_batch_size = tenant::settings().max_gpu_column_batch_size as usize;
+            let column_write_batch_size = tenant::settings().column_write_batch_size as usize;
 
-            let config_count = configs.len(); // Don't move config into closure below.
+            // Only the pending base trees are built, see `tree_records`.
+            let pending_count = pending.indexes().len();
+            let groups = backends.len().min(pending_count);
+            let tenant = current_tenant();
             THREAD_POOL.scoped(|s| {
-                // This channel will receive the finished tree data to be written to disk.
-                let (writer_tx, writer_rx) = channel::<(Vec<Fr>, Vec<Fr>)>(0);
-
-                s.execute(move || {
-                    for i in 0..config_count {
-                        let mut node_index = 0;
-                        let builder_tx = builder_tx.clone();
-                        while node_index != nodes_count {
-                            let chunked_nodes_count =
-                                min(nodes_count - node_index, max_gpu_column_batch_size);
-                            trace!(
-                                "processing config {}/{} with column nodes {}",
-                                i + 1,
-                                tree_count,
-                                chunked_nodes_count,
-                            );
-
-                            let columns: Vec<GenericArray<Fr, ColumnArity>> = {
-                                use fr32::bytes_into_fr;
-
-                                // Allocate layer data array and insert a placeholder for each layer.
-                                let mut layer_data: Vec<Vec<u8>> =
-                                    vec![
-                                        vec![0u8; chunked_nodes_count * std::mem::size_of::<Fr>()];
-                                        layers
-                                    ];
-
-                                // gather all layer data.
-                                for (layer_index, mut layer_bytes) in
-                                    layer_data.iter_mut().enumerate()
-                                {
-                                    let store = labels.labels_for_layer(layer_index + 1);
-                                    let start = (i * nodes_count) + node_index;
-                                    let end = start + chunked_nodes_count;
-
-                                    store
-                                        .read_range_into(start, end, &mut layer_bytes)
-                                        .expect("failed to read store range");
-                                }
+                // This channel will receive the finished tree data of each config to be written to
+                // disk.
+                let (writer_tx, writer_rx) = channel::<(usize, Vec<Fr>, Vec<Fr>)>(0);
+
+                for (group, backend) in backends.into_iter().take(groups).enumerate() {
+                    let config_indexes: Vec<usize> = pending
+                        .indexes()
+                        .iter()
+                        .copied()
+                        .skip(group)
+                        .step_by(groups)
+                        .collect();
+                    let builder_indexes = config_indexes.clone();
+                    // This channel will receive batches of columns and add them to the column tree
+                    // builder.
+                    let (builder_tx, builder_rx) = channel(0);
+                    let worker_tenant = tenant.clone();
+                    let feeder_address = backend.pci_address();
+                    s.execute(move || with_current_tenant(worker_tenant, || {
+                        // The columns are gathered on cores next to the device they are fed to.
+                        let _cores = bind_feeder(feeder_address);
+                        for i in config_indexes {
+                            let mut node_index = 0;
+                            let builder_tx = builder_tx.clone();
+                            while node_index != nodes_count {
+                                let chunked_nodes_count =
+                                    min(nodes_count - node_index, max_gpu_column_batch_size);
+                                trace!(
+                                    "processing config {}/{} with column nodes {}",
+                                    i + 1,
+                                    tree_count,
+                                    chunked_nodes_count,
+                                );
 
-                                (0..chunked_nodes_count)
-                                    .into_par_iter()
-                                    .map(|index| {
-                                        (0..layers)
-                                            .map(|layer_index| {
-                                                bytes_into_fr(
+                                // The labels of each column one after the other.
+                                let columns: Vec<Fr> = {
+                                    use fr32::bytes_into_fr;
+
+                                    // Allocate layer data array and insert a placeholder for each layer.
+                                    let mut layer_data: Vec<Vec<u8>> =
+                                        vec![
+                                            vec![0u8; chunked_nodes_count * std::mem::size_of::<Fr>()];
+                                            layers
+                                        ];
+
+                                    // gather all layer data.
+                                    for (layer_index, mut layer_bytes) in
+                                        layer_data.iter_mut().enumerate()
+                                    {
+                                        let store = labels.labels_for_layer(layer_index + 1);
+                                        let start = (i * nodes_count) + node_index;
+                                        let end = start + chunked_nodes_count;
+
+                                        store
+                                            .read_range_into(start, end, &mut layer_bytes)
+                                            .expect("failed to read store range");
+                                    }
+
+                                    (0..chunked_nodes_count * layers)
+                                        .into_par_iter()
+                                        .map(|element| {
+                                            let (index, layer_index) =
+                                                (element / layers, element % layers);
+                                            bytes_into_fr(
                                                 &layer_data[layer_index][std::mem::size_of::<Fr>()
                                                     * index
                                                     ..std::mem::size_of::<Fr>() * (index + 1)],
                                             )
                                             .expect("Could not create Fr from bytes.")
-                                            })
-                                            .collect::<GenericArray<Fr, ColumnArity>>()
-                                    })
-                                    .collect()
-                            };
-
-                            node_index += chunked_nodes_count;
-                            trace!(
-                                "node index {}/{}/{}",
-                                node_index,
-                                chunked_nodes_count,
-                                nodes_count,
-                            );
+                                        })
+                                        .collect()
+                                };
+
+                                node_index += chunked_nodes_count;
+                                trace!(
+                                    "node index {}/{}/{}",
+                                    node_index,
+                                    chunked_nodes_count,
+                                    nodes_count,
+                                );
 
-                            let is_final = node_index == nodes_count;
-                            builder_tx
-                                .send((columns, is_final))
-                                .expect("failed to send columns");
-                        }
-                    }
-                });
-                s.execute(move || {
-                    let _gpu_lock = GPU_LOCK.lock().expect("failed to get gpu lock");
-                    let tree_batcher = match Batcher::pick_gpu(max_gpu_tree_batch_size) {
-                        Ok(b) => Some(b),
-                        Err(err) => {
-                            warn!("no GPU found, falling back to CPU tree builder: {}", err);
-                            None
-                        }
-                    };
-                    let column_batcher = match Batcher::pick_gpu(max_gpu_column_batch_size) {
-                        Ok(b) => Some(b),
-                        Err(err) => {
-                            warn!("no GPU found, falling back to CPU tree builder: {}", err);
-                            None
+                                let is_final = node_index == nodes_count;
+                                builder_tx
+                                    .send((columns, is_final))
+                                    .expect("failed to send columns");
+                            }
                         }
-                    };
-                    let mut column_tree_builder = ColumnTreeBuilder::<ColumnArity, TreeArity>::new(
-                        column_batcher,
-                        tree_batcher,
-                        nodes_count,
-                    )
-                    .expect("failed to create ColumnTreeBuilder");
-
-                    // Loop until all trees for all configs have been built.
-                    for i in 0..config_count {
-                        loop {
-                            let (columns, is_final): (Vec<GenericArray<Fr, ColumnArity>>, bool) =
-                                builder_rx.recv().expect("failed to recv columns");
-
-                            // Just add non-final column batches.
-                            if !is_final {
-                                column_tree_builder
-                                    .add_columns(&columns)
-                                    .expect("failed to add columns");
-                                continue;
-                            };
-
-                            // If we get here, this is a final column: build a sub-tree.
-                            let (base_data, tree_data) = column_tree_builder
-                                .add_final_columns(&columns)
-                                .expect("failed to add final columns");
-                            trace!(
-                                "base data len {}, tree data len {}",
-                                base_data.len(),
-                                tree_data.len()
-                            );
-
-                            let tree_len = base_data.len() + tree_data.len();
-                            info!(
-                                "persisting base tree_c {}/{} of length {}",
-                                i + 1,
-                                tree_count,
-                                tree_len,
-                            );
-
-                            writer_tx
-                                .send((base_data, tree_data))
-                                .expect("failed to send base_data, tree_data");
-                            break;
+                    }));
+                    let writer_tx = writer_tx.clone();
+                    let worker_tenant = tenant.clone();
+                    s.execute(move || with_current_tenant(worker_tenant, || {
+                        let mut column_tree_builder = backend
+                            .column_tree_builder(layers, Tree::Arity::to_usize(), nodes_count)
+                            .expect("failed to create column tree builder");
+
+                        // Loop until all trees for the configs of this backend have been built.
+                        for i in builder_indexes {
+                            loop {
+                                let (columns, is_final): (Vec<Fr>, bool) =
+                                    builder_rx.recv().expect("failed to recv columns");
+
+                                // Just add non-final column batches.
+                                if !is_final {
+                                    column_tree_builder
+                                        .add_columns(&columns)
+                                        .expect("failed to add columns");
+                                    continue;
+                                };
+
+                                // If we get here, this is a final column: build a sub-tree.
+                                let (base_data, tree_data) = column_tree_builder
+                                    .add_final_columns(&columns)
+                                    .expect("failed to add final columns");
+                                trace!(
+                                    "base data len {}, tree data len {}",
+                                    base_data.len(),
+                                    tree_data.len()
+                                );
+
+                                let tree_len = base_data.len() + tree_data.len();
+                                info!(
+                                    "persisting base tree_c {}/{} of length {}",
+                                    i + 1,
+                                    tree_count,
+                                    tree_len,
+                                );
+
+                                writer_tx
+                                    .send((i, base_data, tree_data))
+                                    .expect("failed to send base_data, tree_data");
+                                break;
+                            }
                         }
-                    }
-                });
+                    }));
+                }
 
-                for config in &configs {
-                    let (base_data, tree_data) = writer_rx
+                for _ in 0..pending_count {
+                    let (i, base_data, tree_data) = writer_rx
                         .recv()
                         .expect("failed to receive base_data, tree_data for tree_c");
+                    let config = &configs[i];
                     let tree_len = base_data.len() + tree_data.len();
 
                     assert_eq!(base_data.len(), nodes_count);
@@ -756,6 +872,11 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                         .sync()
                         .expect("store sync failure");
                     trace!("done writing tree_c store data");
+                    drop(store);
+
+                    pending
+                        .complete(config)
+                        .expect("failed to record base tree_c");
                 }
             });
 
@@ -770,6 +891,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         nodes_count: usize,
         tree_count: usize,
         configs: Vec<StoreConfig>,
+        pending: &PendingTrees,
         labels: &LabelsCache<Tree>,
     ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
@@ -780,25 +902,30 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         measure_op(Operation::GenerateTreeC, || {
             info!("Building column hashes");
 
-            let mut trees = Vec::with_capacity(tree_count);
-            for (i, config) in configs.iter().enumerate() {
+            // Only the pending base trees are built, see `tree_records`.
+            let mut trees = Vec::with_capacity(pending.indexes().len());
+            for &i in pending.indexes() {
+                let config = &configs[i];
                 let mut hashes: Vec<<Tree::Hasher as Hasher>::Domain> =
                     vec![<Tree::Hasher as Hasher>::Domain::default(); nodes_count];
 
-                THREAD_POOL.scoped(|s| {
-                    let n = num_cpus::get();
+                let tenant = current_tenant();
+                // The columns are hashed on the rayon pool of the call, see `thread_pool`, in as
+                // many chunks as it has threads.
+                let n = rayon::current_num_threads();
 
-                    // only split if we have at least two elements per thread
-                    let num_chunks = if n > nodes_count * 2 { 1 } else { n };
+                // only split if we have at least two elements per thread
+                let num_chunks = if n > nodes_count * 2 { 1 } else { n };
 
-                    // chunk into n chunks
-                    let chunk_size = (nodes_count as f64 / num_chunks as f64).ceil() as usize;
+                // chunk into n chunks
+                let chunk_size = (nodes_count as f64 / num_chunks as f64).ceil() as usize;
 
-                    // calculate all n chunks in parallel
-                    for (chunk, hashes_chunk) in hashes.chunks_mut(chunk_size).enumerate() {
-                        let labels = &labels;
-
-                        s.execute(move || {
+                // calculate all n chunks in parallel
+                hashes
+                    .par_chunks_mut(chunk_size)
+                    .enumerate()
+                    .for_each(|(chunk, hashes_chunk)| {
+                        with_current_tenant(tenant.clone(), || {
                             for (j, hash) in hashes_chunk.iter_mut().enumerate() {
                                 let data: Vec<_> = (1..=layers)
                                     .map(|layer| {
@@ -812,20 +939,109 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
                                 *hash = hash_single_column(&data).into();
                             }
-                        });
-                    }
-                });
+                        })
+                    });
 
                 info!("building base tree_c {}/{}", i + 1, tree_count);
                 trees.push(
                     DiskTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_par_iter_with_config(
                         hashes.into_par_iter(),
                         config.clone(),
-                    ),
+                    )?,
                 );
+                pending.complete(config)?;
             }
 
-            assert_eq!(tree_count, trees.len());
+            assert_eq!(pending.indexes().len(), trees.len());
+
+            create_disk_tree::<
+                DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
+            >(configs[0].size.expect("config size failure"), &configs)
+        })
+    }
+
+    /// Builds tree_c on the CPU within about `memory_budget` bytes: the columns of a window of
+    /// nodes are read from the layers and their hashes written to the store of the base tree, which
+    /// is then built on disk. This is slower than holding all the column hashes of a base tree in
+    /// memory, which takes 4GiB for 32GiB sectors.
+    fn generate_tree_c_windowed<ColumnArity, TreeArity>(
+        layers: usize,
+        nodes_count: usize,
+        tree_count: usize,
+        configs: Vec<StoreConfig>,
+        pending: &PendingTrees,
+        labels: &LabelsCache<Tree>,
+        memory_budget: usize,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    where
+        ColumnArity: PoseidonArity,
+        TreeArity: PoseidonArity,
+    {
+        use fr32::{bytes_into_fr, fr_into_bytes};
+        use merkletree::merkle::get_merkle_tree_row_count;
+
+        // A window holds the labels of its columns and their hashes.
+        let window = (memory_budget / ((layers + 1) * NODE_SIZE)).max(1);
+        info!(
+            "generating tree c using the CPU in windows of {} nodes",
+            window
+        );
+        measure_op(Operation::GenerateTreeC, || {
+            // Only the pending base trees are built, see `tree_records`.
+            for &i in pending.indexes() {
+                let config = &configs[i];
+                let tree_c_store_path = StoreConfig::data_path(&config.path, &config.id);
+                if tree_c_store_path.exists() {
+                    fs::remove_file(&tree_c_store_path)
+                        .with_context(|| format!("could not remove {:?}", tree_c_store_path))?;
+                }
+                let mut store = DiskStore::<<Tree::Hasher as Hasher>::Domain>::new_with_config(
+                    config.size.expect("config size failure"),
+                    Tree::Arity::to_usize(),
+                    config.clone(),
+                )?;
+
+                let mut layer_data = vec![Vec::new(); layers];
+                let mut node_index = 0;
+                while node_index < nodes_count {
+                    let chunked_nodes_count = window.min(nodes_count - node_index);
+                    let start = (i * nodes_count) + node_index;
+                    for (layer_index, layer_bytes) in layer_data.iter_mut().enumerate() {
+                        layer_bytes.resize(chunked_nodes_count * NODE_SIZE, 0);
+                        labels.labels_for_layer(layer_index + 1).read_range_into(
+                            start,
+                            start + chunked_nodes_count,
+                            layer_bytes,
+                        )?;
+                    }
+
+                    let hashes = (0..chunked_nodes_count)
+                        .into_par_iter()
+                        .map(|j| -> Result<Vec<u8>> {
+                            let column = layer_data
+                                .iter()
+                                .map(|layer_bytes| {
+                                    bytes_into_fr(&layer_bytes[j * NODE_SIZE..(j + 1) * NODE_SIZE])
+                                })
+                                .collect::<std::result::Result<Vec<Fr>, _>>()?;
+                            Ok(fr_into_bytes(&hash_single_column(&column)))
+                        })
+                        .collect::<Result<Vec<Vec<u8>>>>()?
+                        .concat();
+                    store.copy_from_slice(&hashes, node_index)?;
+                    node_index += chunked_nodes_count;
+                }
+
+                info!("building base tree_c {}/{}", i + 1, tree_count);
+                store.build::<<Tree::Hasher as Hasher>::Function, Tree::Arity>(
+                    nodes_count,
+                    get_merkle_tree_row_count(nodes_count, Tree::Arity::to_usize()),
+                    Some(config.clone()),
+                )?;
+                store.sync()?;
+                drop(store);
+                pending.complete(config)?;
+            }
 
             create_disk_tree::<
                 DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>,
@@ -918,7 +1134,55 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         Self::prepare_tree_r_data_cpu(source, data, start, end)
     }
 
+    /// Prepares the `tree_r_last` leaves of a replica which was already encoded during phase1,
+    /// see [`replicate_phase1_and_encode`](Self::replicate_phase1_and_encode).
     #[cfg(any(feature = "cuda", feature = "opencl"))]
+    fn prepare_tree_r_data_encoded(
+        _source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
+        data: Option<&mut Data<'_>>,
+        start: usize,
+        end: usize,
+    ) -> Result<TreeRElementData<Tree>> {
+        let encoded_bytes =
+            &data.expect("failed to unwrap data").as_ref()[(start * NODE_SIZE)..(end * NODE_SIZE)];
+
+        if Self::use_gpu_tree_builder() {
+            use fr32::bytes_into_fr;
+
+            let encoded_data = encoded_bytes
+                .par_chunks(NODE_SIZE)
+                .map(bytes_into_fr)
+                .collect::<std::result::Result<Vec<Fr>, _>>()?;
+
+            Ok(TreeRElementData::FrList(encoded_data))
+        } else {
+            let encoded_data = encoded_bytes
+                .par_chunks(NODE_SIZE)
+                .map(<Tree::Hasher as Hasher>::Domain::try_from_bytes)
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(TreeRElementData::ElementList(encoded_data))
+        }
+    }
+
+    /// Prepares the `tree_r_last` leaves of a replica which was already encoded during phase1,
+    /// see [`replicate_phase1_and_encode`](Self::replicate_phase1_and_encode).
+    #[cfg(not(any(feature = "cuda", feature = "opencl")))]
+    fn prepare_tree_r_data_encoded(
+        _source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
+        data: Option<&mut Data<'_>>,
+        start: usize,
+        end: usize,
+    ) -> Result<TreeRElementData<Tree>> {
+        let encoded_data = data.expect("failed to unwrap data").as_ref()
+            [(start * NODE_SIZE)..(end * NODE_SIZE)]
+            .par_chunks(NODE_SIZE)
+            .map(<Tree::Hasher as Hasher>::Domain::try_from_bytes)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(TreeRElementData::ElementList(encoded_data))
+    }
+
     pub fn generate_tree_r_last<TreeArity>(
         data: &mut Data<'_>,
         nodes_count: usize,
@@ -928,6 +1192,35 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         callback: Option<PrepareTreeRDataCallback<Tree>>,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    where
+        TreeArity: PoseidonArity,
+    {
+        Self::generate_tree_r_last_pending::<TreeArity>(
+            data,
+            nodes_count,
+            tree_count,
+            tree_r_last_config,
+            replica_path,
+            source,
+            callback,
+            &PendingTrees::all(tree_count),
+            &ReplicaStream::default(),
+        )
+    }
+
+    #[cfg(any(feature = "cuda", feature = "opencl"))]
+    #[allow(clippy::too_many_arguments)]
+    fn generate_tree_r_last_pending<TreeArity>(
+        data: &mut Data<'_>,
+        nodes_count: usize,
+        tree_count: usize,
+        tree_r_last_config: StoreConfig,
+        replica_path: PathBuf,
+        source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
+        callback: Option<PrepareTreeRDataCallback<Tree>>,
+        pending: &PendingTrees,
+        stream: &ReplicaStream,
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
     {
@@ -936,8 +1229,22 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             None => Self::prepare_tree_r_data,
         };
 
-        if Self::use_gpu_tree_builder() {
-            Self::generate_tree_r_last_gpu::<TreeArity>(
+        if let Some(backend) = Self::custom_tree_builder() {
+            Self::generate_tree_r_last_batched::<TreeArity>(
+                data,
+                nodes_count,
+                tree_count,
+                tree_r_last_config,
+                replica_path,
+                source,
+                encode_data,
+                pending,
+                stream,
+                backend,
+                tenant::settings().max_gpu_tree_batch_size as usize,
+            )
+        } else if Self::use_gpu_tree_builder() {
+            Self::generate_tree_r_last_batched::<TreeArity>(
                 data,
                 nodes_count,
                 tree_count,
@@ -945,6 +1252,10 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 replica_path,
                 source,
                 encode_data,
+                pending,
+                stream,
+                Arc::new(NeptuneBackend::default()),
+                gpu_tree_batch_size::<Tree::Arity>(),
             )
         } else {
             Self::generate_tree_r_last_cpu::<TreeArity>(
@@ -955,12 +1266,15 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 replica_path,
                 source,
                 encode_data,
+                pending,
+                stream,
             )
         }
     }
 
     #[cfg(not(any(feature = "cuda", feature = "opencl")))]
-    pub fn generate_tree_r_last<TreeArity>(
+    #[allow(clippy::too_many_arguments)]
+    fn generate_tree_r_last_pending<TreeArity>(
         data: &mut Data<'_>,
         nodes_count: usize,
         tree_count: usize,
@@ -968,6 +1282,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         replica_path: PathBuf,
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         callback: Option<PrepareTreeRDataCallback<Tree>>,
+        pending: &PendingTrees,
+        stream: &ReplicaStream,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -977,41 +1293,59 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             None => Self::prepare_tree_r_data,
         };
 
-        Self::generate_tree_r_last_cpu::<TreeArity>(
-            data,
-            nodes_count,
-            tree_count,
-            tree_r_last_config,
-            replica_path,
-            source,
-            encode_data,
-        )
-    }
-
-    #[cfg(any(feature = "cuda", feature = "opencl"))]
-    fn generate_tree_r_last_gpu<TreeArity>(
-        data: &mut Data<'_>,
-        nodes_count: usize,
-        tree_count: usize,
-        tree_r_last_config: StoreConfig,
-        replica_path: PathBuf,
-        source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
-        callback: PrepareTreeRDataCallback<Tree>,
-    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
-    where
-        TreeArity: PoseidonArity,
-    {
-        use std::cmp::min;
-        use std::fs::OpenOptions;
-        use std::io::Write;
+        if let Some(backend) = Self::custom_tree_builder() {
+            Self::generate_tree_r_last_batched::<TreeArity>(
+                data,
+                nodes_count,
+                tree_count,
+                tree_r_last_config,
+                replica_path,
+                source,
+                encode_data,
+                pending,
+                stream,
+                backend,
+                tenant::settings().max_gpu_tree_batch_size as usize,
+            )
+        } else {
+            Self::generate_tree_r_last_cpu::<TreeArity>(
+                data,
+                nodes_count,
+                tree_count,
+                tree_r_last_config,
+                replica_path,
+                source,
+                encode_data,
+                pending,
+                stream,
+            )
+        }
+    }
+
+    /// Builds tree_r_last with a tree builder backend, fed batches of `max_batch_size` leaves.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_tree_r_last_batched<TreeArity>(
+        data: &mut Data<'_>,
+        nodes_count: usize,
+        tree_count: usize,
+        tree_r_last_config: StoreConfig,
+        replica_path: PathBuf,
+        source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
+        callback: PrepareTreeRDataCallback<Tree>,
+        pending: &PendingTrees,
+        stream: &ReplicaStream,
+        backend: Backend,
+        max_batch_size: usize,
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    where
+        TreeArity: PoseidonArity,
+    {
+        use std::cmp::min;
+        use std::io::Write;
         use std::sync::mpsc::sync_channel as channel;
 
         use fr32::fr_into_bytes;
         use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_leafs};
-        use neptune::{
-            batch_hasher::Batcher,
-            tree_builder::{TreeBuilder, TreeBuilderTrait},
-        };
 
         let (configs, replica_config) = split_config_and_replica(
             tree_r_last_config.clone(),
@@ -1020,110 +1354,147 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             tree_count,
         )?;
 
-        info!("generating tree r last using the GPU");
-        let max_gpu_tree_batch_size = SETTINGS.max_gpu_tree_batch_size as usize;
+        info!(
+            "generating tree r last using the {} tree builder",
+            backend.name()
+        );
 
-        // This channel will receive batches of leaf nodes and add them to the TreeBuilder.
-        let (builder_tx, builder_rx) = channel::<(Vec<Fr>, bool)>(0);
-        let config_count = configs.len(); // Don't move config into closure below.
+        // This channel will receive batches of leaf nodes and add them to the TreeBuilder. Errors
+        // of the producer and the builder are sent on, then both stop.
+        let (builder_tx, builder_rx) = channel::<Result<(Vec<Fr>, bool)>>(0);
         let configs = &configs;
         let tree_r_last_config = &tree_r_last_config;
 
-        THREAD_POOL.scoped(|s| {
+        let tenant = current_tenant();
+        THREAD_POOL.scoped(|s| -> Result<()> {
             // This channel will receive the finished tree data to be written to disk.
-            let (writer_tx, writer_rx) = channel::<Vec<Fr>>(0);
+            let (writer_tx, writer_rx) = channel::<Result<Vec<Fr>>>(0);
 
+            let worker_tenant = tenant.clone();
+            let feeder_address = backend.pci_address();
             s.execute(move || {
-                for i in 0..config_count {
-                    let mut node_index = 0;
-                    while node_index != nodes_count {
-                        let chunked_nodes_count =
-                            min(nodes_count - node_index, max_gpu_tree_batch_size);
-                        let start = (i * nodes_count) + node_index;
-                        let end = start + chunked_nodes_count;
-                        trace!(
-                            "processing config {}/{} with leaf nodes {} [{}, {}, {}-{}]",
-                            i + 1,
-                            tree_count,
-                            chunked_nodes_count,
-                            node_index,
-                            nodes_count,
-                            start,
-                            end,
-                        );
+                with_current_tenant(worker_tenant, || {
+                    let _cores = bind_feeder(feeder_address);
+                    let produce = || -> Result<()> {
+                        // Only the pending base trees are built, see `tree_records`, but the whole
+                        // replica is streamed.
+                        for i in 0..tree_count {
+                            if !pending.indexes().contains(&i) {
+                                let start = i * nodes_count * NODE_SIZE;
+                                stream.write(
+                                    &data.as_ref()[start..start + nodes_count * NODE_SIZE],
+                                )?;
+                                continue;
+                            }
+                            pending
+                                .start(&configs[i])
+                                .context("failed to record base tree_r_last")?;
+                            let mut node_index = 0;
+                            while node_index != nodes_count {
+                                let chunked_nodes_count =
+                                    min(nodes_count - node_index, max_batch_size);
+                                let start = (i * nodes_count) + node_index;
+                                let end = start + chunked_nodes_count;
+                                trace!(
+                                    "processing config {}/{} with leaf nodes {} [{}, {}, {}-{}]",
+                                    i + 1,
+                                    tree_count,
+                                    chunked_nodes_count,
+                                    node_index,
+                                    nodes_count,
+                                    start,
+                                    end,
+                                );
 
-                        let prepared_data = match callback(source, Some(data), start, end)
-                            .expect("failed to prepare tree_r_last data")
-                        {
-                            TreeRElementData::FrList(x) => x,
-                            _ => panic!("fr_list is required"),
-                        };
-                        node_index += chunked_nodes_count;
-
-                        trace!(
-                            "node index {}/{}/{}",
-                            node_index,
-                            chunked_nodes_count,
-                            nodes_count,
-                        );
+                                let prepared_data = match callback(source, Some(data), start, end)
+                                    .context(
+                                    "failed to prepare tree_r_last data",
+                                )? {
+                                    TreeRElementData::FrList(x) => x,
+                                    TreeRElementData::ElementList(x) => {
+                                        x.into_iter().map(Into::into).collect()
+                                    }
+                                };
+                                stream.write(&data.as_ref()[start * NODE_SIZE..end * NODE_SIZE])?;
+                                node_index += chunked_nodes_count;
+
+                                trace!(
+                                    "node index {}/{}/{}",
+                                    node_index,
+                                    chunked_nodes_count,
+                                    nodes_count,
+                                );
+
+                                let is_final = node_index == nodes_count;
+                                if builder_tx.send(Ok((prepared_data, is_final))).is_err() {
+                                    // The builder failed, and sent on its error.
+                                    return Ok(());
+                                }
+                            }
+                        }
 
-                        let is_final = node_index == nodes_count;
-                        builder_tx
-                            .send((prepared_data, is_final))
-                            .expect("failed to send prepared data");
+                        Ok(())
+                    };
+                    if let Err(err) = produce() {
+                        let _ = builder_tx.send(Err(err));
                     }
-                }
+                })
             });
+            let worker_tenant = tenant.clone();
             s.execute(move || {
-                let _gpu_lock = GPU_LOCK.lock().expect("failed to get gpu lock");
-                let batcher = match Batcher::pick_gpu(max_gpu_tree_batch_size) {
-                    Ok(b) => Some(b),
-                    Err(err) => {
-                        warn!("no GPU found, falling back to CPU tree builder: {}", err);
-                        None
-                    }
-                };
-                let mut tree_builder = TreeBuilder::<Tree::Arity>::new(
-                    batcher,
-                    nodes_count,
-                    tree_r_last_config.rows_to_discard,
-                )
-                .expect("failed to create TreeBuilder");
-
-                // Loop until all trees for all configs have been built.
-                for i in 0..config_count {
-                    loop {
-                        let (prepared_data, is_final) =
-                            builder_rx.recv().expect("failed to recv prepared data");
-
-                        // Just add non-final leaf batches.
-                        if !is_final {
-                            tree_builder
-                                .add_leaves(&prepared_data)
-                                .expect("failed to add leaves");
-                            continue;
-                        };
-
-                        // If we get here, this is a final leaf batch: build a sub-tree.
-                        info!(
-                            "building base tree_r_last with GPU {}/{}",
-                            i + 1,
-                            tree_count
-                        );
-                        let (_, tree_data) = tree_builder
-                            .add_final_leaves(&prepared_data)
-                            .expect("failed to add final leaves");
+                with_current_tenant(worker_tenant, || {
+                    let build = || -> Result<()> {
+                        let mut tree_builder = backend
+                            .tree_builder(
+                                Tree::Arity::to_usize(),
+                                nodes_count,
+                                tree_r_last_config.rows_to_discard,
+                            )
+                            .context("failed to create tree builder")?;
+
+                        // Loop until all pending trees have been built.
+                        for &i in pending.indexes() {
+                            loop {
+                                let (prepared_data, is_final) = builder_rx
+                                    .recv()
+                                    .context("failed to recv prepared data")??;
+
+                                // Just add non-final leaf batches.
+                                if !is_final {
+                                    tree_builder
+                                        .add_leaves(&prepared_data)
+                                        .context("failed to add leaves")?;
+                                    continue;
+                                };
+
+                                // If we get here, this is a final leaf batch: build a sub-tree.
+                                info!("building base tree_r_last {}/{}", i + 1, tree_count);
+                                let tree_data = tree_builder
+                                    .add_final_leaves(&prepared_data)
+                                    .context("failed to add final leaves")?;
+
+                                if writer_tx.send(Ok(tree_data)).is_err() {
+                                    // Writing the trees failed.
+                                    return Ok(());
+                                }
+                                break;
+                            }
+                        }
 
-                        writer_tx.send(tree_data).expect("failed to send tree_data");
-                        break;
+                        Ok(())
+                    };
+                    if let Err(err) = build() {
+                        let _ = writer_tx.send(Err(err));
                     }
-                }
+                })
             });
 
-            for config in configs.iter() {
+            // Returning early drops the receiver, which stops the builder and with it the producer.
+            for &i in pending.indexes() {
+                let config = &configs[i];
                 let tree_data = writer_rx
                     .recv()
-                    .expect("failed to receive tree_data for tree_r_last");
+                    .context("failed to receive tree_data for tree_r_last")??;
 
                 let tree_data_len = tree_data.len();
                 let cache_size = get_merkle_tree_cache_size(
@@ -1155,11 +1526,19 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     .create(true)
                     .write(true)
                     .open(&tree_r_last_path)
-                    .expect("failed to open file for tree_r_last");
+                    .context("failed to open file for tree_r_last")?;
                 f.write_all(&flat_tree_data)
-                    .expect("failed to wrote tree_r_last data");
+                    .context("failed to wrote tree_r_last data")?;
+                drop(f);
+
+                pending
+                    .complete(config)
+                    .context("failed to record base tree_r_last")?;
             }
-        });
+
+            Ok(())
+        })?;
+        stream.flush()?;
 
         create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
             tree_r_last_config.size.expect("config size failure"),
@@ -1168,6 +1547,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn generate_tree_r_last_cpu<TreeArity>(
         data: &mut Data<'_>,
         nodes_count: usize,
@@ -1176,6 +1556,8 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         replica_path: PathBuf,
         source: &DiskStore<<Tree::Hasher as Hasher>::Domain>,
         callback: PrepareTreeRDataCallback<Tree>,
+        pending: &PendingTrees,
+        stream: &ReplicaStream,
     ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
     where
         TreeArity: PoseidonArity,
@@ -1189,11 +1571,18 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
         info!("generating tree r last using the CPU");
 
-        // Note that nodes_count is the count of nodes in each base tree
-        let mut start = 0;
-        let mut end = nodes_count;
-
+        // Only the pending base trees are built, see `tree_records`, but the whole replica is
+        // streamed.
         for (i, config) in configs.iter().enumerate() {
+            // Note that nodes_count is the count of nodes in each base tree
+            let start = i * nodes_count;
+            let end = start + nodes_count;
+            if !pending.indexes().contains(&i) {
+                stream.write(&data.as_ref()[start * NODE_SIZE..end * NODE_SIZE])?;
+                continue;
+            }
+
+            pending.start(config)?;
             let encoded_data: Vec<<Tree::Hasher as Hasher>::Domain> =
                 match callback(source, Some(data), start, end)
                     .expect("failed to prepare tree_r_last data")
@@ -1201,6 +1590,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     TreeRElementData::ElementList(x) => x,
                     _ => panic!("element list required"),
                 };
+            stream.write(&data.as_ref()[start * NODE_SIZE..end * NODE_SIZE])?;
 
             info!(
                 "building base tree_r_last with CPU {}/{}",
@@ -1226,10 +1616,9 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                 config.clone(),
             )
             .with_context(|| format!("failed tree_r_last CPU {}/{}", i + 1, tree_count))?;
-
-            start += nodes_count;
-            end += nodes_count;
+            pending.complete(config)?;
         }
+        stream.flush()?;
 
         create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
             tree_r_last_config.size.expect("config size failure"),
@@ -1262,10 +1651,12 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             config,
             replica_path,
             labels,
+            Phase2Options::default(),
         )
         .context("failed to transform")
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn transform_and_replicate_layers_inner(
         graph: &StackedBucketGraph<Tree::Hasher>,
         layer_challenges: &LayerChallenges,
@@ -1274,6 +1665,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         config: StoreConfig,
         replica_path: PathBuf,
         label_configs: Labels<Tree>,
+        options: Phase2Options,
     ) -> Result<TransformedLayers<Tree, G>> {
         trace!("transform_and_replicate_layers");
         let nodes_count = graph.size();
@@ -1320,12 +1712,13 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         );
 
         // A default 'rows_to_discard' value will be chosen for tree_r_last, unless the user overrides this value via the
-        // environment setting (FIL_PROOFS_ROWS_TO_DISCARD).  If this value is specified, no checking is done on it and it may
+        // environment setting (FIL_PROOFS_ROWS_TO_DISCARD) or the caller chooses one.  If this value is specified, no checking is done on it and it may
         // result in a broken configuration.  Use with caution.  It must be noted that if/when this unchecked value is passed
         // through merkle_light, merkle_light now does a check that does not allow us to discard more rows than is possible
         // to discard.
-        tree_r_last_config.rows_to_discard =
-            default_rows_to_discard(nodes_count, Tree::Arity::to_usize());
+        tree_r_last_config.rows_to_discard = options
+            .tree_r_last_rows_to_discard
+            .unwrap_or_else(|| default_rows_to_discard(nodes_count, Tree::Arity::to_usize()));
         trace!(
             "tree_r_last using rows_to_discard={}",
             tree_r_last_config.rows_to_discard
@@ -1350,6 +1743,67 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             None => error!("Failed to raise the fd limit"),
         };
 
+        // Build the MerkleTree over the original data (if needed).
+        let tree_d = match data_tree {
+            Some(t) => {
+                trace!("using existing original data merkle tree");
+                assert_eq!(t.len(), 2 * (data.len() / NODE_SIZE) - 1);
+
+                t
+            }
+            None => {
+                trace!("building merkle tree for the original data");
+                data.ensure_data()?;
+                measure_op(Operation::CommD, || {
+                    Self::build_binary_tree::<G>(data.as_ref(), tree_d_config.clone())
+                })?
+            }
+        };
+        tree_d_config.size = Some(tree_d.len());
+        assert_eq!(
+            tree_d_config.size.expect("config size failure"),
+            tree_d.len()
+        );
+        let tree_d_root = tree_d.root();
+        drop(tree_d);
+
+        // Encode original data into the last layer, unless that already happened in phase1.
+        let last_layer_labels = labels.labels_for_last_layer()?;
+        data.ensure_data()?;
+        let callback: Option<PrepareTreeRDataCallback<Tree>> =
+            match read_replica_encoding(&config.path)? {
+                ReplicaEncoding::Original => None,
+                ReplicaEncoding::Encoded => {
+                    info!("replica was already encoded during phase1");
+                    Some(Self::prepare_tree_r_data_encoded)
+                }
+                ReplicaEncoding::Partial => {
+                    bail!("replica may be partially encoded, phase1 must be run again")
+                }
+            };
+
+        // Base trees recorded by a previous PC2 of the sector are reused, see `tree_records`.
+        let tree_r_last_configs = split_config(tree_r_last_config.clone(), tree_count)?;
+        let (pending_tree_c, pending_tree_r_last) =
+            if tenant::settings().pc2_tree_records || control::has_deadline() {
+                (
+                    PendingTrees::resume(&configs, false)?,
+                    PendingTrees::resume(&tree_r_last_configs, callback.is_none())?,
+                )
+            } else {
+                (
+                    PendingTrees::unrecorded(&configs, false)?,
+                    PendingTrees::unrecorded(&tree_r_last_configs, callback.is_none())?,
+                )
+            };
+
+        control::checkpoint("tree_c")?;
+        emit_device_choice(
+            "tree_c",
+            Self::use_gpu_column_builder_on(&options.tree_c_devices)
+                && tenant::settings().tree_c_memory_budget == 0,
+            Self::custom_tree_builder(),
+        );
         let tree_c_root = match layers {
             2 => {
                 let tree_c = Self::generate_tree_c::<U2, Tree::Arity>(
@@ -1357,7 +1811,9 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     nodes_count,
                     tree_count,
                     configs,
+                    &pending_tree_c,
                     &labels,
+                    &options.tree_c_devices,
                 )?;
                 tree_c.root()
             }
@@ -1367,7 +1823,9 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     nodes_count,
                     tree_count,
                     configs,
+                    &pending_tree_c,
                     &labels,
+                    &options.tree_c_devices,
                 )?;
                 tree_c.root()
             }
@@ -1377,7 +1835,9 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
                     nodes_count,
                     tree_count,
                     configs,
+                    &pending_tree_c,
                     &labels,
+                    &options.tree_c_devices,
                 )?;
                 tree_c.root()
             }
@@ -1385,44 +1845,24 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         };
         info!("tree_c done");
 
-        // Build the MerkleTree over the original data (if needed).
-        let tree_d = match data_tree {
-            Some(t) => {
-                trace!("using existing original data merkle tree");
-                assert_eq!(t.len(), 2 * (data.len() / NODE_SIZE) - 1);
-
-                t
-            }
-            None => {
-                trace!("building merkle tree for the original data");
-                data.ensure_data()?;
-                measure_op(Operation::CommD, || {
-                    Self::build_binary_tree::<G>(data.as_ref(), tree_d_config.clone())
-                })?
-            }
-        };
-        tree_d_config.size = Some(tree_d.len());
-        assert_eq!(
-            tree_d_config.size.expect("config size failure"),
-            tree_d.len()
+        control::checkpoint("tree_r_last")?;
+        emit_device_choice(
+            "tree_r_last",
+            Self::use_gpu_tree_builder(),
+            Self::custom_tree_builder(),
         );
-        let tree_d_root = tree_d.root();
-        drop(tree_d);
-
-        // Encode original data into the last layer.
-        let last_layer_labels = labels.labels_for_last_layer()?;
-        data.ensure_data()?;
-
         info!("building tree_r_last");
         let tree_r_last = measure_op(Operation::GenerateTreeRLast, || {
-            Self::generate_tree_r_last::<Tree::Arity>(
+            Self::generate_tree_r_last_pending::<Tree::Arity>(
                 &mut data,
                 nodes_count,
                 tree_count,
                 tree_r_last_config.clone(),
                 replica_path.clone(),
                 last_layer_labels,
-                None,
+                callback,
+                &pending_tree_r_last,
+                &ReplicaStream::new(options.replica_sink.clone()),
             )
             .context("failed to generate tree_r_last")
         })?;
@@ -1461,14 +1901,152 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         pp: &'a PublicParams<Tree>,
         replica_id: &<Tree::Hasher as Hasher>::Domain,
         config: StoreConfig,
+    ) -> Result<Labels<Tree>> {
+        Self::replicate_phase1_with_options(pp, replica_id, config, &LabelingOptions::default())
+    }
+
+    /// Phase1 of replication, labeling with `options`.
+    pub fn replicate_phase1_with_options(
+        pp: &'a PublicParams<Tree>,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        options: &LabelingOptions,
     ) -> Result<Labels<Tree>> {
         info!("replicate_phase1");
 
         let labels = measure_op(Operation::EncodeWindowTimeAll, || {
-            Self::generate_labels_for_encoding(&pp.graph, &pp.layer_challenges, replica_id, config)
+            Self::generate_labels_and_encode(
+                &pp.graph,
+                &pp.layer_challenges,
+                replica_id,
+                config,
+                None,
+                options,
+            )
+        })?
+        .0;
+
+        Ok(labels)
+    }
+
+    /// Phase1 of replication for several sectors sharing these public parameters, which are
+    /// labeled in lockstep with `options`, sharing the parent cache reads. Yields the same labels
+    /// as [`replicate_phase1`](Self::replicate_phase1) for each of the sectors.
+    pub fn replicate_phase1_batch(
+        pp: &'a PublicParams<Tree>,
+        replica_ids: &[<Tree::Hasher as Hasher>::Domain],
+        configs: Vec<StoreConfig>,
+        options: &LabelingOptions,
+    ) -> Result<Vec<Labels<Tree>>> {
+        info!("replicate_phase1_batch: {} sectors", replica_ids.len());
+        // The trees of previous layers must not be reused by PC2.
+        for config in &configs {
+            clear_records(&config.path)?;
+        }
+
+        let mut parent_cache = pp.graph.parent_cache()?;
+        let labels = measure_op(Operation::EncodeWindowTimeAll, || {
+            #[cfg(feature = "gpu-sdr")]
+            {
+                if tenant::settings().use_gpu_sdr {
+                    match create_label::gpu::create_labels_for_encoding::<Tree, _>(
+                        &pp.graph,
+                        &mut parent_cache,
+                        pp.layer_challenges.layers(),
+                        replica_ids,
+                        configs.clone(),
+                        options,
+                    ) {
+                        Ok(labels) => {
+                            emit_sdr_device_choice(true);
+                            return Ok(labels);
+                        }
+                        Err(err) => {
+                            warn!("GPU labeling failed, falling back to the CPU: {:?}", err);
+                            events::emit(Event::Fallback {
+                                job: control::current_job(),
+                                operation: "sdr".to_string(),
+                                reason: format!("{}", err),
+                            });
+                        }
+                    }
+                }
+            }
+            emit_sdr_device_choice(false);
+
+            let interleave = tenant::settings().batch_sdr_interleave;
+            if interleave > 1 {
+                return create_label::interleaved::create_labels_for_encoding::<Tree, _>(
+                    &pp.graph,
+                    &mut parent_cache,
+                    pp.layer_challenges.layers(),
+                    replica_ids,
+                    configs,
+                    interleave,
+                    options,
+                );
+            }
+
+            create_label::batch::create_labels_for_encoding::<Tree, _>(
+                &pp.graph,
+                &mut parent_cache,
+                pp.layer_challenges.layers(),
+                replica_ids,
+                configs,
+                options,
+            )
+        })?;
+
+        Ok(labels.into_iter().map(|(labels, _)| labels).collect())
+    }
+
+    /// Phase1 of replication, which also encodes the replica at `replica_path` in place while the
+    /// labels of the last layer are still in memory, saving phase2 a full pass over the replica.
+    /// The replica must contain the original (unsealed) data. The sector is labeled with
+    /// `options`.
+    pub fn replicate_phase1_and_encode(
+        pp: &'a PublicParams<Tree>,
+        replica_id: &<Tree::Hasher as Hasher>::Domain,
+        config: StoreConfig,
+        replica_path: &Path,
+        options: &LabelingOptions,
+    ) -> Result<Labels<Tree>> {
+        info!("replicate_phase1_and_encode");
+
+        let f_data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(replica_path)
+            .with_context(|| format!("could not open replica_path={:?}", replica_path))?;
+        let mut data = unsafe {
+            MmapOptions::new()
+                .map_mut(&f_data)
+                .with_context(|| format!("could not mmap replica_path={:?}", replica_path))?
+        };
+        ensure!(
+            data.len() == pp.graph.size() * NODE_SIZE,
+            "replica size does not match the graph"
+        );
+
+        // Until the marker says otherwise, phase2 must assume the replica is partially encoded.
+        let cache_path = config.path.clone();
+        write_replica_encoding(&cache_path, ReplicaEncoding::Partial)?;
+
+        let labels = measure_op(Operation::EncodeWindowTimeAll, || {
+            Self::generate_labels_and_encode(
+                &pp.graph,
+                &pp.layer_challenges,
+                replica_id,
+                config,
+                Some(&mut data[..]),
+                options,
+            )
         })?
         .0;
 
+        data.flush().context("failed to flush encoded replica")?;
+        write_replica_encoding(&cache_path, ReplicaEncoding::Encoded)?;
+
         Ok(labels)
     }
 
@@ -1487,6 +2065,65 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
     )> {
         info!("replicate_phase2");
 
+        Self::replicate_phase2_with_options(
+            pp,
+            label_configs,
+            data,
+            data_tree,
+            config,
+            replica_path,
+            Phase2Options::default(),
+        )
+    }
+
+    /// Like [`Self::replicate_phase2`], but discards `tree_r_last_rows_to_discard` rows of
+    /// tree_r_last instead of the default. The value is recorded in the returned t_aux.
+    #[allow(clippy::type_complexity)]
+    pub fn replicate_phase2_with_rows_to_discard(
+        pp: &'a PublicParams<Tree>,
+        label_configs: Labels<Tree>,
+        data: Data<'a>,
+        data_tree: BinaryMerkleTree<G>,
+        config: StoreConfig,
+        replica_path: PathBuf,
+        tree_r_last_rows_to_discard: usize,
+    ) -> Result<(
+        <Self as PoRep<'a, Tree::Hasher, G>>::Tau,
+        <Self as PoRep<'a, Tree::Hasher, G>>::ProverAux,
+    )> {
+        info!(
+            "replicate_phase2: tree_r_last rows_to_discard={}",
+            tree_r_last_rows_to_discard
+        );
+
+        Self::replicate_phase2_with_options(
+            pp,
+            label_configs,
+            data,
+            data_tree,
+            config,
+            replica_path,
+            Phase2Options {
+                tree_r_last_rows_to_discard: Some(tree_r_last_rows_to_discard),
+                ..Phase2Options::default()
+            },
+        )
+    }
+
+    /// Like [`Self::replicate_phase2`], with the given `options`.
+    #[allow(clippy::type_complexity)]
+    pub fn replicate_phase2_with_options(
+        pp: &'a PublicParams<Tree>,
+        label_configs: Labels<Tree>,
+        data: Data<'a>,
+        data_tree: BinaryMerkleTree<G>,
+        config: StoreConfig,
+        replica_path: PathBuf,
+        options: Phase2Options,
+    ) -> Result<(
+        <Self as PoRep<'a, Tree::Hasher, G>>::Tau,
+        <Self as PoRep<'a, Tree::Hasher, G>>::ProverAux,
+    )> {
         let (tau, paux, taux) = Self::transform_and_replicate_layers_inner(
             &pp.graph,
             &pp.layer_challenges,
@@ -1495,6 +2132,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             config,
             replica_path,
             label_configs,
+            options,
         )?;
 
         Ok((tau, (paux, taux)))
@@ -1502,28 +2140,19 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
     // Assumes data is all zeros.
     // Replica path is used to create configs, but is not read.
-    // Instead new zeros are provided (hence the need for replica to be all zeros).
-    #[cfg(any(feature = "cuda", feature = "opencl"))]
-    fn generate_fake_tree_r_last<TreeArity>(
+    // Every row of a tree of zeros holds a single value, so the base trees are written from one
+    // hash per row instead of being built, which takes seconds even for 64GiB sectors.
+    fn generate_fake_tree_r_last(
         nodes_count: usize,
         tree_count: usize,
         tree_r_last_config: StoreConfig,
         replica_path: PathBuf,
-    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
-    where
-        TreeArity: PoseidonArity,
-    {
-        use std::fs::OpenOptions;
-        use std::io::Write;
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+        use std::io::{BufWriter, Write};
 
-        use ff::Field;
-        use fr32::fr_into_bytes;
-        use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_leafs};
-        use neptune::{
-            batch_hasher::Batcher,
-            tree_builder::{TreeBuilder, TreeBuilderTrait},
-        };
+        use merkletree::{hash::Algorithm, merkle::get_merkle_tree_cache_size};
 
+        let arity = Tree::Arity::to_usize();
         let (configs, replica_config) = split_config_and_replica(
             tree_r_last_config.clone(),
             replica_path,
@@ -1531,143 +2160,49 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
             tree_count,
         )?;
 
-        if Self::use_gpu_tree_builder() {
-            info!("generating tree r last using the GPU");
-            let max_gpu_tree_batch_size = SETTINGS.max_gpu_tree_batch_size as usize;
-
-            let _gpu_lock = GPU_LOCK.lock().expect("failed to get gpu lock");
-            let batcher = match Batcher::pick_gpu(max_gpu_tree_batch_size) {
-                Ok(b) => Some(b),
-                Err(err) => {
-                    warn!("no GPU found, falling back to CPU tree builder: {}", err);
-                    None
-                }
-            };
-            let mut tree_builder = TreeBuilder::<Tree::Arity>::new(
-                batcher,
-                nodes_count,
-                tree_r_last_config.rows_to_discard,
-            )
-            .expect("failed to create TreeBuilder");
-
-            // Allocate zeros once and reuse.
-            let zero_leaves: Vec<Fr> = vec![Fr::zero(); max_gpu_tree_batch_size];
-            for (i, config) in configs.iter().enumerate() {
-                let mut consumed = 0;
-                while consumed < nodes_count {
-                    let batch_size = usize::min(max_gpu_tree_batch_size, nodes_count - consumed);
-
-                    consumed += batch_size;
-
-                    if consumed != nodes_count {
-                        tree_builder
-                            .add_leaves(&zero_leaves[0..batch_size])
-                            .expect("failed to add leaves");
-                        continue;
-                    };
-
-                    // If we get here, this is a final leaf batch: build a sub-tree.
-                    info!(
-                        "building base tree_r_last with GPU {}/{}",
-                        i + 1,
-                        tree_count
-                    );
-
-                    let (_, tree_data) = tree_builder
-                        .add_final_leaves(&zero_leaves[0..batch_size])
-                        .expect("failed to add final leaves");
-                    let tree_data_len = tree_data.len();
-                    let cache_size = get_merkle_tree_cache_size(
-                        get_merkle_tree_leafs(
-                            config.size.expect("config size failure"),
-                            Tree::Arity::to_usize(),
-                        )
-                        .expect("failed to get merkle tree leaves"),
-                        Tree::Arity::to_usize(),
-                        config.rows_to_discard,
-                    )
-                    .expect("failed to get merkle tree cache size");
-                    assert_eq!(tree_data_len, cache_size);
+        // The rows above the leaves, as (width, value), from the bottom up to the root.
+        let mut rows = Vec::new();
+        let mut value = <Tree::Hasher as Hasher>::Domain::default();
+        let mut width = nodes_count;
+        while width > 1 {
+            value = <Tree::Hasher as Hasher>::Function::default()
+                .multi_node(&vec![value; arity], rows.len());
+            width /= arity;
+            rows.push((width, value));
+        }
 
-                    let flat_tree_data: Vec<_> = tree_data
-                        .into_par_iter()
-                        .flat_map(|el| fr_into_bytes(&el))
-                        .collect();
+        // The store keeps the rows from the top down to the rows to discard.
+        let cache_size =
+            get_merkle_tree_cache_size(nodes_count, arity, tree_r_last_config.rows_to_discard)?;
+        let mut kept = 0;
+        let mut first_kept = rows.len();
+        while kept < cache_size && first_kept > 0 {
+            first_kept -= 1;
+            kept += rows[first_kept].0;
+        }
+        ensure!(
+            kept == cache_size,
+            "no rows of tree_r_last make up its cache of {} nodes",
+            cache_size
+        );
 
-                    // Persist the data to the store based on the current config.
-                    let tree_r_last_path = StoreConfig::data_path(&config.path, &config.id);
-                    trace!(
-                        "persisting tree r of len {} with {} rows to discard at path {:?}",
-                        tree_data_len,
-                        config.rows_to_discard,
-                        tree_r_last_path
-                    );
-                    let mut f = OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(&tree_r_last_path)
-                        .expect("failed to open file for tree_r_last");
-                    f.write_all(&flat_tree_data)
-                        .expect("failed to wrote tree_r_last data");
+        info!("generating tree r last of zeros");
+        for (i, config) in configs.iter().enumerate() {
+            info!("writing base tree_r_last {}/{}", i + 1, tree_count);
+            let tree_r_last_path = StoreConfig::data_path(&config.path, &config.id);
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tree_r_last_path)
+                .with_context(|| format!("could not create {:?}", tree_r_last_path))?;
+            let mut writer = BufWriter::new(file);
+            for (width, value) in &rows[first_kept..] {
+                for _ in 0..*width {
+                    writer.write_all(value.as_ref())?;
                 }
             }
-        } else {
-            info!("generating tree r last using the CPU");
-            for (i, config) in configs.iter().enumerate() {
-                let encoded_data = vec![<Tree::Hasher as Hasher>::Domain::default(); nodes_count];
-
-                info!(
-                    "building base tree_r_last with CPU {}/{}",
-                    i + 1,
-                    tree_count
-                );
-                LCTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_par_iter_with_config(
-                    encoded_data,
-                    config.clone(),
-                )?;
-            }
-        };
-
-        create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
-            tree_r_last_config.size.expect("config size failure"),
-            &configs,
-            &replica_config,
-        )
-    }
-
-    // Assumes data is all zeros.
-    // Replica path is used to create configs, but is not read.
-    // Instead new zeros are provided (hence the need for replica to be all zeros).
-    #[cfg(not(any(feature = "cuda", feature = "opencl")))]
-    fn generate_fake_tree_r_last<TreeArity>(
-        nodes_count: usize,
-        tree_count: usize,
-        tree_r_last_config: StoreConfig,
-        replica_path: PathBuf,
-    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
-    where
-        TreeArity: PoseidonArity,
-    {
-        let (configs, replica_config) = split_config_and_replica(
-            tree_r_last_config.clone(),
-            replica_path,
-            nodes_count,
-            tree_count,
-        )?;
-
-        info!("generating tree r last using the CPU");
-        for (i, config) in configs.iter().enumerate() {
-            let encoded_data = vec![<Tree::Hasher as Hasher>::Domain::default(); nodes_count];
-
-            info!(
-                "building base tree_r_last with CPU {}/{}",
-                i + 1,
-                tree_count
-            );
-            LCTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_par_iter_with_config(
-                encoded_data,
-                config.clone(),
-            )?;
+            writer.flush()?;
         }
 
         create_lc_tree::<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>(
@@ -1705,7 +2240,7 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
 
         // Encode original data into the last layer.
         info!("building tree_r_last");
-        let tree_r_last = Self::generate_fake_tree_r_last::<Tree::Arity>(
+        let tree_r_last = Self::generate_fake_tree_r_last(
             nodes_count,
             tree_count,
             tree_r_last_config,
@@ -1755,3 +2290,141 @@ impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tr
         Ok((comm_r, p_aux))
     }
 }
+
+/// The batch size of the GPU tree builder of arity `TreeArity`, tuned to the first GPU (the one
+/// `Batcher::pick_gpu` picks) with `FIL_PROOFS_GPU_TREE_BATCH_SIZE_AUTO`.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub(crate) fn gpu_tree_batch_size<TreeArity: PoseidonArity>() -> usize {
+    let settings = tenant::settings();
+    let max_gpu_tree_batch_size = settings.max_gpu_tree_batch_size as usize;
+    if !settings.gpu_tree_batch_size_auto {
+        return max_gpu_tree_batch_size;
+    }
+
+    super::gpu_tuning::tree_batch_size::<TreeArity>(0).unwrap_or_else(|err| {
+        warn!(
+            "failed to tune the GPU tree batch size, using {}: {:?}",
+            max_gpu_tree_batch_size, err
+        );
+        events::emit(Event::Fallback {
+            job: control::current_job(),
+            operation: "gpu_tree_batch_size".to_string(),
+            reason: format!("{}", err),
+        });
+        max_gpu_tree_batch_size
+    })
+}
+
+/// The batch size of the GPU tree builder of tree_r_last for trees of `arity`, tuned like
+/// `gpu_tree_batch_size`.
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub fn tree_r_last_gpu_batch_size(arity: usize) -> Result<usize> {
+    use generic_array::typenum::U4;
+
+    match arity {
+        2 => Ok(gpu_tree_batch_size::<U2>()),
+        4 => Ok(gpu_tree_batch_size::<U4>()),
+        8 => Ok(gpu_tree_batch_size::<U8>()),
+        _ => bail!("no tree builder for arity {}", arity),
+    }
+}
+
+#[cfg(not(any(feature = "cuda", feature = "opencl")))]
+pub fn tree_r_last_gpu_batch_size(_arity: usize) -> Result<usize> {
+    Ok(tenant::settings().max_gpu_tree_batch_size as usize)
+}
+
+/// Binds the calling thread, which feeds the tree builders on the device at `pci_address`, to a
+/// free core group on the NUMA node of the device, until the returned group is dropped on the
+/// calling thread.
+#[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+fn bind_feeder(
+    pci_address: Option<String>,
+) -> Option<crate::stacked::vanilla::cores::BoundCoreGroup> {
+    use crate::stacked::vanilla::cores::{bind_near, CoreLocality};
+
+    bind_near(&CoreLocality::PciDevice(pci_address?))
+}
+
+#[cfg(not(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity")))]
+fn bind_feeder(_pci_address: Option<String>) -> Option<()> {
+    None
+}
+
+fn emit_device_choice(operation: &str, use_gpu: bool, backend: Option<Backend>) {
+    let gpu = use_gpu && cfg!(any(feature = "cuda", feature = "opencl"));
+    let device = match backend {
+        Some(backend) => backend.name(),
+        None => if gpu { "gpu" } else { "cpu" }.to_string(),
+    };
+    events::emit(Event::DeviceChoice {
+        job: control::current_job(),
+        operation: operation.to_string(),
+        device,
+    });
+}
+
+fn emit_sdr_device_choice(gpu: bool) {
+    events::emit(Event::DeviceChoice {
+        job: control::current_job(),
+        operation: "sdr".to_string(),
+        device: if gpu { "gpu" } else { "cpu" }.to_string(),
+    });
+}
+
+/// How far the replica in a sector's cache directory has been encoded ahead of phase2.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReplicaEncoding {
+    /// The replica holds the original data, phase2 encodes it.
+    Original,
+    /// Encoding during phase1 was started but may not have finished.
+    Partial,
+    /// The replica was fully encoded during phase1.
+    Encoded,
+}
+
+fn replica_encoding_path(cache_path: &Path) -> PathBuf {
+    cache_path.join(CacheKey::ReplicaEncoding.to_string())
+}
+
+/// Reads the encoding state of the replica belonging to `cache_path`.
+pub fn read_replica_encoding(cache_path: &Path) -> Result<ReplicaEncoding> {
+    let path = replica_encoding_path(cache_path);
+    if !path.exists() {
+        return Ok(ReplicaEncoding::Original);
+    }
+
+    let state = fs::read_to_string(&path).with_context(|| format!("could not read {:?}", path))?;
+    match state.trim() {
+        "encoded" => Ok(ReplicaEncoding::Encoded),
+        _ => Ok(ReplicaEncoding::Partial),
+    }
+}
+
+fn write_replica_encoding(cache_path: &Path, state: ReplicaEncoding) -> Result<()> {
+    let path = replica_encoding_path(cache_path);
+    let contents = match state {
+        ReplicaEncoding::Original => return clear_replica_encoding(cache_path),
+        ReplicaEncoding::Partial => "partial",
+        ReplicaEncoding::Encoded => "encoded",
+    };
+
+    // Write atomically, so that a torn write is never read as `encoded`.
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents).with_context(|| format!("could not write {:?}", tmp_path))?;
+    fs::rename(&tmp_path, &path).with_context(|| format!("could not rename {:?}", tmp_path))?;
+
+    Ok(())
+}
+
+/// Marks the replica belonging to `cache_path` as holding the original data again, which must be
+/// done whenever the unsealed data is copied over it. Trees built from the previous data are not
+/// reused by PC2 either.
+pub fn clear_replica_encoding(cache_path: &Path) -> Result<()> {
+    let path = replica_encoding_path(cache_path);
+    if path.exists() {
+        fs::remove_file(&path).with_context(|| format!("could not remove {:?}", path))?;
+    }
+
+    clear_records(cache_path)
+}
@@ -0,0 +1,96 @@
+//! Streaming of the encoded replica to a sink.
+//!
+//! The replica of a sector built with the `replica_sink` of its `Phase2Options` is also written
+//! to the sink while tree_r_last is built, as its base trees encode their parts of
+//! the replica (or read them, if PC1 encoded the replica already). The sink receives every byte of
+//! the encoded replica exactly once and in order, including the parts of base trees reused from an
+//! earlier PC2, e.g. to write it to a raw block device or over the network while it is sealed.
+//! This is a second copy: the replica file is written as well, as tree_r_last refers to it.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+
+/// A sink the encoded replica is streamed to, shared with the threads building tree_r_last.
+pub type ReplicaSink = Arc<Mutex<dyn Write + Send>>;
+
+/// Takes back a sink shared as a [`ReplicaSink`] once the replica is sealed. Fails if the sink is
+/// still used by a thread left running, or a thread panicked while writing to it.
+pub fn unwrap_replica_sink<W>(sink: Arc<Mutex<W>>) -> Result<W> {
+    let sink = Arc::try_unwrap(sink).map_err(|_| anyhow!("replica sink still in use"))?;
+    sink.into_inner()
+        .map_err(|_| anyhow!("replica sink poisoned"))
+}
+
+/// Streams the encoded replica to a sink, if any.
+#[derive(Clone, Default)]
+pub(crate) struct ReplicaStream {
+    sink: Option<ReplicaSink>,
+}
+
+impl ReplicaStream {
+    pub(crate) fn new(sink: Option<ReplicaSink>) -> Self {
+        ReplicaStream { sink }
+    }
+
+    /// Whether there is a sink to stream to.
+    pub(crate) fn is_active(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Writes the next `bytes` of the encoded replica.
+    pub(crate) fn write(&self, bytes: &[u8]) -> Result<()> {
+        if let Some(sink) = &self.sink {
+            sink.lock()
+                .expect("replica sink poisoned")
+                .write_all(bytes)
+                .context("failed to stream the replica")?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the sink, once the whole replica is written.
+    pub(crate) fn flush(&self) -> Result<()> {
+        if let Some(sink) = &self.sink {
+            sink.lock()
+                .expect("replica sink poisoned")
+                .flush()
+                .context("failed to flush the replica sink")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replica_stream() {
+        let sink = Arc::new(Mutex::new(Vec::new()));
+        let stream = ReplicaStream::new(Some(sink.clone()));
+        assert!(stream.is_active());
+        stream.write(b"enc").expect("write failed");
+        stream.clone().write(b"oded").expect("write failed");
+        stream.flush().expect("flush failed");
+
+        drop(stream);
+        assert_eq!(
+            unwrap_replica_sink(sink).expect("replica sink failure"),
+            b"encoded".to_vec()
+        );
+
+        // A sink still held by a stream is not handed back.
+        let leaked = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let _stream = ReplicaStream::new(Some(leaked.clone()));
+        assert!(unwrap_replica_sink(leaked).is_err());
+
+        // Nothing is streamed without a sink.
+        let stream = ReplicaStream::default();
+        assert!(!stream.is_active());
+        stream.write(b"encoded").expect("write failed");
+    }
+}
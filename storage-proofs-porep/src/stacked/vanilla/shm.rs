@@ -0,0 +1,185 @@
+//! Parent caches loaded into shared memory.
+//!
+//! With the `parent_cache_shm` setting pointing to a directory on a hugetlbfs mount (e.g.
+//! `/dev/hugepages`) or on tmpfs (e.g. `/dev/shm`), the first process of the host which uses a
+//! parent cache copies it there, and all sealing processes map that segment as a whole instead of
+//! mapping windows of the cache file. The cache is then read from the same memory by all of them,
+//! which saves the page faults of the windows and, with huge pages, most of the TLB misses. On
+//! tmpfs, transparent huge pages are requested with `madvise`, which takes effect if
+//! `/sys/kernel/mm/transparent_hugepage/shmem_enabled` is set to `advise` (or `always`).
+//!
+//! A segment holds the cache followed by its digest, which is written last. A segment without the
+//! digest of the cache it is loaded for, e.g. one left by an interrupted copy or one of a cache
+//! which was regenerated since, is replaced. Processes which still map a replaced segment keep
+//! reading the old one, as it is unlinked rather than overwritten.
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use fs2::FileExt;
+use log::{debug, info};
+use mapr::{Mmap, MmapOptions};
+
+const HUGETLBFS_MAGIC: u32 = 0x9584_58f6;
+
+/// Returns the path of the segment of the cache file `source` in `dir`, loading `len` bytes of it
+/// first unless the segment already holds the cache with `digest`.
+pub(crate) fn load(source: &Path, len: usize, digest: &str, dir: &Path) -> Result<PathBuf> {
+    ensure!(!digest.is_empty(), "the digest of the cache is unknown");
+    let file_name = source
+        .file_name()
+        .context("parent cache path has no file name")?;
+    let path = dir.join(file_name);
+
+    // The processes of the host load a segment one at a time, the lock is released when it is
+    // dropped.
+    let mut lock_name = file_name.to_os_string();
+    lock_name.push(".lock");
+    let lock = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(dir.join(lock_name))
+        .with_context(|| format!("could not open the lock of {}", path.display()))?;
+    lock.lock_exclusive()?;
+
+    if path.exists() && holds(&path, len, digest)? {
+        debug!("parent cache: {} is loaded", path.display());
+        return Ok(path);
+    }
+
+    info!(
+        "parent cache: loading {} into {}",
+        source.display(),
+        path.display()
+    );
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("could not create {}", path.display()))?;
+    let size = segment_size(&path, len + digest.len())?;
+    file.set_len(size as u64)?;
+    // Reserves the memory now, running out of it while copying would end in a SIGBUS.
+    if let Err(err) = file.allocate(size as u64) {
+        drop(file);
+        fs::remove_file(&path)?;
+        return Err(err).with_context(|| format!("could not allocate {} bytes", size));
+    }
+
+    let mut data = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+    advise_huge_pages(&data);
+    File::open(source)
+        .and_then(|mut source| source.read_exact(&mut data[..len]))
+        .with_context(|| format!("could not read {}", source.display()))?;
+    data[len..len + digest.len()].copy_from_slice(digest.as_bytes());
+    data.flush()?;
+    info!("parent cache: loaded {}", path.display());
+
+    Ok(path)
+}
+
+/// Maps the first `len` bytes of the segment `file`, which are shared by all processes mapping it.
+pub(crate) fn map(file: &File, len: usize) -> Result<Mmap> {
+    let data = unsafe { MmapOptions::new().len(len).map(file)? };
+    advise_huge_pages(&data);
+
+    Ok(data)
+}
+
+/// Whether the segment at `path` holds the cache of `len` bytes with `digest`.
+fn holds(path: &Path, len: usize, digest: &str) -> Result<bool> {
+    let file = File::open(path)?;
+    if (file.metadata()?.len() as usize) < len + digest.len() {
+        return Ok(false);
+    }
+    let data = unsafe { MmapOptions::new().len(len + digest.len()).map(&file)? };
+
+    Ok(&data[len..] == digest.as_bytes())
+}
+
+/// The size of a segment of at least `len` bytes at `path`, files on hugetlbfs are made of whole
+/// huge pages.
+fn segment_size(path: &Path, len: usize) -> Result<usize> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    let result = unsafe { libc::statfs(c_path.as_ptr(), &mut stat) };
+    ensure!(
+        result == 0,
+        "statfs {} failed: {}",
+        path.display(),
+        io::Error::last_os_error()
+    );
+
+    if stat.f_type as u32 == HUGETLBFS_MAGIC {
+        // The block size of hugetlbfs is its huge page size.
+        let page_size = stat.f_bsize as usize;
+        Ok((len + page_size - 1) / page_size * page_size)
+    } else {
+        Ok(len)
+    }
+}
+
+/// Requests transparent huge pages for a mapping of a tmpfs segment, mappings of hugetlbfs are
+/// made of huge pages anyway.
+fn advise_huge_pages(data: &[u8]) {
+    let result = unsafe {
+        libc::madvise(
+            data.as_ptr() as *mut libc::c_void,
+            data.len(),
+            libc::MADV_HUGEPAGE,
+        )
+    };
+    if result != 0 {
+        debug!(
+            "parent cache: madvise(MADV_HUGEPAGE) failed: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let shm_dir = tempfile::tempdir().expect("tempdir failure");
+        let source = dir.path().join("parents.cache");
+        let cache: Vec<u8> = (0..1000u32).map(|x| x as u8).collect();
+        fs::write(&source, &cache).expect("write failure");
+
+        let path = load(&source, cache.len(), "digest", shm_dir.path()).expect("load failure");
+        assert_eq!(path, shm_dir.path().join("parents.cache"));
+        let file = File::open(&path).expect("open failure");
+        assert_eq!(
+            &map(&file, cache.len()).expect("map failure")[..],
+            &cache[..]
+        );
+
+        // A loaded segment is kept, one of another digest is replaced.
+        fs::write(&source, vec![7u8; cache.len()]).expect("write failure");
+        load(&source, cache.len(), "digest", shm_dir.path()).expect("load failure");
+        assert_eq!(
+            &fs::read(&path).expect("read failure")[..cache.len()],
+            &cache[..]
+        );
+        load(&source, cache.len(), "other digest", shm_dir.path()).expect("load failure");
+        let segment = fs::read(&path).expect("read failure");
+        assert!(segment[..cache.len()].iter().all(|byte| *byte == 7));
+        assert_eq!(&segment[cache.len()..], b"other digest");
+
+        assert!(load(&source, cache.len(), "", shm_dir.path()).is_err());
+    }
+}
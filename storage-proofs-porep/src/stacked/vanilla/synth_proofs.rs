@@ -0,0 +1,175 @@
+//! Synthetic PoRep.
+//!
+//! With a synthetic PoRep, the proofs of all synthetic challenges of a sector (see
+//! [`SynthChallenges`]) are generated right after PC2, while the layers are still on disk, and
+//! stored in its cache directory. The challenges of the commit, which the seed selects out of the
+//! synthetic ones, are then proven with the stored proofs, so that the layers can be deleted after
+//! PC2 instead of being kept until the commit.
+//!
+//! The file of the proofs holds the serialized proofs, in the order of their challenges, followed
+//! by the offset of every proof and the end of the last one, and then by the number of proofs
+//! (all `u64`, little endian).
+
+use std::fs::{rename, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Context};
+use bincode::{deserialize, serialize};
+use filecoin_hashers::Hasher;
+use log::info;
+use storage_proofs_core::{control, drgraph::Graph, error::Result, merkle::MerkleTreeTrait};
+
+use crate::stacked::vanilla::{
+    challenges::SynthChallenges,
+    params::{PersistentAux, Proof, PublicInputs, PublicParams, TemporaryAuxCache},
+    proof::StackedDrg,
+};
+
+/// Synthetic challenges proven at once.
+const PROOFS_CHUNK: usize = 1 << 10;
+
+impl<'a, Tree: 'static + MerkleTreeTrait, G: 'static + Hasher> StackedDrg<'a, Tree, G> {
+    /// Proves all synthetic challenges of the replica of `pub_inputs` from the layers and trees of
+    /// `t_aux`, and writes the proofs to `path`.
+    pub fn write_synth_proofs(
+        path: &Path,
+        pub_params: &PublicParams<Tree>,
+        pub_inputs: &PublicInputs<<Tree::Hasher as Hasher>::Domain, <G as Hasher>::Domain>,
+        p_aux: &PersistentAux<<Tree::Hasher as Hasher>::Domain>,
+        t_aux: &TemporaryAuxCache<Tree, G>,
+    ) -> Result<()> {
+        let synth_count = pub_params
+            .layer_challenges
+            .synth_count()
+            .context("the challenges are not those of a synthetic PoRep")?;
+        let comm_r = &pub_inputs
+            .tau
+            .as_ref()
+            .context("synthetic challenges require comm_r")?
+            .comm_r;
+        let synth = SynthChallenges::new(
+            pub_params.graph.size(),
+            &pub_inputs.replica_id,
+            comm_r,
+            synth_count,
+        );
+        info!("proving {} synthetic challenges", synth_count);
+
+        // Written to `.tmp` and renamed, so that a file at `path` always holds all proofs.
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(
+            File::create(&tmp_path)
+                .with_context(|| format!("could not create {}", tmp_path.display()))?,
+        );
+        let mut offsets = Vec::with_capacity(synth_count + 1);
+        let mut offset = 0u64;
+        let challenges: Vec<usize> = synth.iter().collect();
+        for chunk in challenges.chunks(PROOFS_CHUNK) {
+            control::checkpoint("synthetic proofs")?;
+            let proofs = Self::prove_challenges(
+                &pub_params.graph,
+                pub_inputs,
+                p_aux,
+                t_aux,
+                pub_params.layer_challenges.layers(),
+                chunk,
+            )?;
+            for proof in &proofs {
+                let bytes = serialize(proof)?;
+                writer.write_all(&bytes)?;
+                offsets.push(offset);
+                offset += bytes.len() as u64;
+            }
+        }
+        offsets.push(offset);
+        for offset in offsets {
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        writer.write_all(&(synth_count as u64).to_le_bytes())?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        rename(&tmp_path, path).context("failed to rename the synthetic proofs")?;
+        info!("stored synthetic proofs in {}", path.display());
+
+        Ok(())
+    }
+
+    /// Reads the proofs of the synthetic challenges at `indexes` from `path`, as written by
+    /// [`StackedDrg::write_synth_proofs`].
+    pub fn read_synth_proofs(path: &Path, indexes: &[usize]) -> Result<Vec<Proof<Tree, G>>> {
+        let mut file =
+            File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        let len = file.metadata()?.len();
+
+        ensure!(len >= 8, "invalid synthetic proofs {}", path.display());
+        let count = read_u64(&mut file, len - 8)?;
+        let table = count
+            .checked_add(2)
+            .and_then(|entries| entries.checked_mul(8))
+            .filter(|table| *table <= len)
+            .with_context(|| format!("invalid synthetic proofs {}", path.display()))?;
+        let table_start = len - table;
+
+        indexes
+            .iter()
+            .map(|&index| {
+                ensure!(
+                    (index as u64) < count,
+                    "synthetic challenge {} out of {}",
+                    index,
+                    count
+                );
+                let start = read_u64(&mut file, table_start + 8 * index as u64)?;
+                let end = read_u64(&mut file, table_start + 8 * (index as u64 + 1))?;
+                ensure!(
+                    start <= end && end <= table_start,
+                    "invalid synthetic proof {} in {}",
+                    index,
+                    path.display()
+                );
+                let mut bytes = vec![0u8; (end - start) as usize];
+                file.seek(SeekFrom::Start(start))?;
+                file.read_exact(&mut bytes)?;
+
+                Ok(deserialize(&bytes)?)
+            })
+            .collect()
+    }
+
+    /// The proofs of all partitions of the synthetic PoRep of `pub_inputs`, read from the
+    /// synthetic proofs at `path` rather than generated from the layers.
+    pub fn prove_all_partitions_synth(
+        path: &Path,
+        pub_params: &PublicParams<Tree>,
+        pub_inputs: &PublicInputs<<Tree::Hasher as Hasher>::Domain, <G as Hasher>::Domain>,
+        partition_count: usize,
+    ) -> Result<Vec<Vec<Proof<Tree, G>>>> {
+        ensure!(partition_count > 0, "partitions must not be 0");
+        ensure!(
+            pub_params.layer_challenges.synth_count().is_some(),
+            "the challenges are not those of a synthetic PoRep"
+        );
+
+        (0..partition_count)
+            .map(|k| {
+                let indexes = pub_params.layer_challenges.derive_synth_indexes(
+                    &pub_inputs.replica_id,
+                    &pub_inputs.seed,
+                    k as u8,
+                );
+                Self::read_synth_proofs(path, &indexes)
+            })
+            .collect()
+    }
+}
+
+fn read_u64(file: &mut File, offset: u64) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
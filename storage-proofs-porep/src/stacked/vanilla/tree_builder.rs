@@ -0,0 +1,432 @@
+//! Pluggable building of the base trees of tree_c and tree_r_last.
+//!
+//! The base trees are built by the neptune tree builders on the GPU (`NeptuneBackend`) if
+//! `FIL_PROOFS_USE_GPU_COLUMN_BUILDER` and `FIL_PROOFS_USE_GPU_TREE_BUILDER` are set, and on the
+//! CPU otherwise. Trees built within [`with_tree_builder_backend`] on the calling thread are built
+//! by the given backend instead, e.g. on an FPGA or by a remote service, without forking the
+//! sealing. A backend is fed the leaves of one base tree after the other in batches (the columns
+//! of the labels for tree_c, the encoded replica for tree_r_last), and returns the rows above them.
+
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use anyhow::Result;
+use blstrs::Scalar as Fr;
+use storage_proofs_core::scoped::with_local;
+
+/// Builds the base trees of tree_c and tree_r_last, with Poseidon.
+pub trait TreeBuilderBackend: Send + Sync {
+    /// The name of the backend, the device of the `device_choice` events of the trees it builds.
+    fn name(&self) -> String;
+
+    /// A builder of the base trees of tree_c of a sector, with `nodes_count` columns of `layers`
+    /// labels and the given `arity`.
+    fn column_tree_builder(
+        &self,
+        layers: usize,
+        arity: usize,
+        nodes_count: usize,
+    ) -> Result<Box<dyn BaseColumnTreeBuilder>>;
+
+    /// A builder of the base trees of tree_r_last of a sector, with `nodes_count` leaves and the
+    /// given `arity`, of which `rows_to_discard` rows above the leaves aren't stored.
+    fn tree_builder(
+        &self,
+        arity: usize,
+        nodes_count: usize,
+        rows_to_discard: usize,
+    ) -> Result<Box<dyn BaseTreeBuilder>>;
+
+    /// The PCI address of the device the trees are built on, e.g. `0000:3b:00.0`, if any. The
+    /// threads feeding the builders then run on cores next to it, see `cores`.
+    fn pci_address(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Builds base trees of tree_c one after the other.
+pub trait BaseColumnTreeBuilder {
+    /// Adds the next columns of the current base tree, the labels of each column one after the
+    /// other.
+    fn add_columns(&mut self, columns: &[Fr]) -> Result<()>;
+
+    /// Adds the last columns of the current base tree, and returns its leaves (the hashes of the
+    /// columns) and its rows above them, up to the root. Further columns start the next base tree.
+    fn add_final_columns(&mut self, columns: &[Fr]) -> Result<(Vec<Fr>, Vec<Fr>)>;
+}
+
+/// Builds base trees of tree_r_last one after the other.
+pub trait BaseTreeBuilder {
+    /// Adds the next leaves of the current base tree.
+    fn add_leaves(&mut self, leaves: &[Fr]) -> Result<()>;
+
+    /// Adds the last leaves of the current base tree, and returns its stored rows above them, up
+    /// to the root. Further leaves start the next base tree.
+    fn add_final_leaves(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>>;
+}
+
+pub(crate) type Backend = Arc<dyn TreeBuilderBackend>;
+
+thread_local! {
+    static BACKEND: RefCell<Option<Backend>> = RefCell::new(None);
+}
+
+/// Runs `f`, building the trees of the sectors it seals on this thread with `backend`.
+pub fn with_tree_builder_backend<T, F: FnOnce() -> T>(backend: Backend, f: F) -> T {
+    with_local(&BACKEND, Some(backend), f)
+}
+
+/// The backend of the current thread, if any.
+pub(crate) fn tree_builder_backend() -> Option<Backend> {
+    BACKEND.with(|cell| cell.borrow().clone())
+}
+
+/// The device memory a neptune batcher uses for batches of `batch_size` preimages of `arity`
+/// elements: every preimage holds its elements, the state of the permutation and the digest on the
+/// device.
+pub fn gpu_batch_bytes(arity: usize, batch_size: usize) -> u64 {
+    ((2 * arity + 2) * std::mem::size_of::<Fr>()) as u64 * batch_size as u64
+}
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub use self::neptune_backend::NeptuneBackend;
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+pub(crate) use self::neptune_backend::{acquire_tree_gpu, tree_c_backends};
+
+#[cfg(any(feature = "cuda", feature = "opencl"))]
+mod neptune_backend {
+    use std::sync::Arc;
+
+    use anyhow::{anyhow, bail, ensure, Result};
+    use blstrs::Scalar as Fr;
+    use filecoin_hashers::PoseidonArity;
+    use generic_array::{
+        typenum::{Unsigned, U11, U2, U4, U8},
+        GenericArray,
+    };
+    use log::warn;
+    use neptune::{
+        batch_hasher::Batcher,
+        column_tree_builder::{ColumnTreeBuilder, ColumnTreeBuilderTrait},
+        tree_builder::{TreeBuilder, TreeBuilderTrait},
+    };
+    use rust_gpu_tools::Device;
+    use storage_proofs_core::{
+        control,
+        gpu_framework::{enter_gpu_framework, gpu_framework, GpuFramework},
+        gpu_scheduler::{gpu_scheduler, GpuGrant, GpuRequest, GpuWork},
+        priority::current_priority_class,
+        tenant,
+    };
+
+    use super::{Backend, BaseColumnTreeBuilder, BaseTreeBuilder, TreeBuilderBackend};
+    use crate::stacked::vanilla::proof::gpu_tree_batch_size;
+
+    /// Acquires the GPU for building trees from the GPU scheduler of the calling thread, see
+    /// `gpu_scheduler`, which releases it when the grant is dropped.
+    pub(crate) fn acquire_tree_gpu() -> Result<Box<dyn GpuGrant>> {
+        gpu_scheduler().acquire(&GpuRequest {
+            work: GpuWork::TreeBuilding,
+            class: current_priority_class(),
+            priority: false,
+            circuits: 0,
+            job: control::current_job(),
+        })
+    }
+
+    /// The neptune tree builders, on a GPU or on the CPU if there is none. The GPU work runs on
+    /// the GPU framework of the process, see `enter_gpu_framework`, which is the framework of the
+    /// first backend building a tree if none is set yet.
+    #[derive(Clone, Copy, Debug)]
+    pub struct NeptuneBackend {
+        device: Option<usize>,
+        framework: Option<GpuFramework>,
+    }
+
+    impl Default for NeptuneBackend {
+        fn default() -> Self {
+            NeptuneBackend {
+                device: None,
+                framework: gpu_framework(),
+            }
+        }
+    }
+
+    impl NeptuneBackend {
+        /// The builders on the GPU `index` (an index into the GPUs found). They don't wait for
+        /// the other builders on the GPU, unlike those of the default backend.
+        pub fn on_device(index: usize) -> Result<Self> {
+            let device_count = Device::all().len();
+            ensure!(
+                index < device_count,
+                "GPU {} assigned to tree building, but there are {}",
+                index,
+                device_count
+            );
+
+            Ok(NeptuneBackend {
+                device: Some(index),
+                framework: gpu_framework(),
+            })
+        }
+
+        /// The GPU granted to the builders of the default backend, see `acquire_tree_gpu`.
+        fn gpu_grant(&self) -> Result<Option<Box<dyn GpuGrant>>> {
+            match self.device {
+                Some(_) => Ok(None),
+                None => acquire_tree_gpu().map(Some),
+            }
+        }
+
+        fn batcher(&self, max_batch_size: usize) -> Option<Batcher> {
+            let batcher = match self.device {
+                Some(index) => Batcher::new(Device::all()[index], max_batch_size),
+                None => Batcher::pick_gpu(max_batch_size),
+            };
+            match batcher {
+                Ok(b) => Some(b),
+                Err(err) => {
+                    warn!("no GPU found, falling back to CPU tree builder: {}", err);
+                    None
+                }
+            }
+        }
+
+        fn column_tree_builder_of<ColumnArity, TreeArity>(
+            &self,
+            nodes_count: usize,
+        ) -> Result<Box<dyn BaseColumnTreeBuilder>>
+        where
+            ColumnArity: 'static + PoseidonArity,
+            TreeArity: 'static + PoseidonArity,
+        {
+            let settings = tenant::settings();
+            enter_gpu_framework(self.framework);
+            let gpu_grant = self.gpu_grant()?;
+            let tree_batcher = self.batcher(settings.max_gpu_tree_batch_size as usize);
+            let column_batcher = self.batcher(settings.max_gpu_column_batch_size as usize);
+            let builder = ColumnTreeBuilder::<ColumnArity, TreeArity>::new(
+                column_batcher,
+                tree_batcher,
+                nodes_count,
+            )
+            .map_err(|err| anyhow!("failed to create ColumnTreeBuilder: {:?}", err))?;
+
+            Ok(Box::new(NeptuneColumnTreeBuilder {
+                builder,
+                _gpu_grant: gpu_grant,
+            }))
+        }
+
+        fn tree_builder_of<TreeArity: 'static + PoseidonArity>(
+            &self,
+            nodes_count: usize,
+            rows_to_discard: usize,
+        ) -> Result<Box<dyn BaseTreeBuilder>> {
+            enter_gpu_framework(self.framework);
+            // The batch size is tuned before the GPU is acquired, as tuning acquires it.
+            let max_batch_size = match self.device {
+                Some(_) => tenant::settings().max_gpu_tree_batch_size as usize,
+                None => gpu_tree_batch_size::<TreeArity>(),
+            };
+            let gpu_grant = self.gpu_grant()?;
+            let builder = TreeBuilder::<TreeArity>::new(
+                self.batcher(max_batch_size),
+                nodes_count,
+                rows_to_discard,
+            )
+            .map_err(|err| anyhow!("failed to create TreeBuilder: {:?}", err))?;
+
+            Ok(Box::new(NeptuneTreeBuilder {
+                builder,
+                _gpu_grant: gpu_grant,
+            }))
+        }
+    }
+
+    impl TreeBuilderBackend for NeptuneBackend {
+        fn name(&self) -> String {
+            "gpu".to_string()
+        }
+
+        fn column_tree_builder(
+            &self,
+            layers: usize,
+            arity: usize,
+            nodes_count: usize,
+        ) -> Result<Box<dyn BaseColumnTreeBuilder>> {
+            match (layers, arity) {
+                (2, 2) => self.column_tree_builder_of::<U2, U2>(nodes_count),
+                (2, 4) => self.column_tree_builder_of::<U2, U4>(nodes_count),
+                (2, 8) => self.column_tree_builder_of::<U2, U8>(nodes_count),
+                (11, 2) => self.column_tree_builder_of::<U11, U2>(nodes_count),
+                (11, 4) => self.column_tree_builder_of::<U11, U4>(nodes_count),
+                (11, 8) => self.column_tree_builder_of::<U11, U8>(nodes_count),
+                _ => bail!(
+                    "no column tree builder for {} layers and arity {}",
+                    layers,
+                    arity
+                ),
+            }
+        }
+
+        fn tree_builder(
+            &self,
+            arity: usize,
+            nodes_count: usize,
+            rows_to_discard: usize,
+        ) -> Result<Box<dyn BaseTreeBuilder>> {
+            match arity {
+                2 => self.tree_builder_of::<U2>(nodes_count, rows_to_discard),
+                4 => self.tree_builder_of::<U4>(nodes_count, rows_to_discard),
+                8 => self.tree_builder_of::<U8>(nodes_count, rows_to_discard),
+                _ => bail!("no tree builder for arity {}", arity),
+            }
+        }
+
+        fn pci_address(&self) -> Option<String> {
+            // The default backend builds on the first GPU, as neptune picks it.
+            let devices = Device::all();
+            let device = devices.get(self.device.unwrap_or(0))?;
+            // rust-gpu-tools identifies devices by bus and device, the function is always 0.
+            Some(format!("{}.0", device.pci_id()))
+        }
+    }
+
+    struct NeptuneColumnTreeBuilder<ColumnArity: PoseidonArity, TreeArity: PoseidonArity> {
+        builder: ColumnTreeBuilder<ColumnArity, TreeArity>,
+        _gpu_grant: Option<Box<dyn GpuGrant>>,
+    }
+
+    impl<ColumnArity: PoseidonArity, TreeArity: PoseidonArity>
+        NeptuneColumnTreeBuilder<ColumnArity, TreeArity>
+    {
+        fn columns(columns: &[Fr]) -> Result<Vec<GenericArray<Fr, ColumnArity>>> {
+            let layers = ColumnArity::to_usize();
+            ensure!(
+                columns.len() % layers == 0,
+                "{} labels are no columns of {} layers",
+                columns.len(),
+                layers
+            );
+
+            Ok(columns
+                .chunks(layers)
+                .map(GenericArray::clone_from_slice)
+                .collect())
+        }
+    }
+
+    impl<ColumnArity: PoseidonArity, TreeArity: PoseidonArity> BaseColumnTreeBuilder
+        for NeptuneColumnTreeBuilder<ColumnArity, TreeArity>
+    {
+        fn add_columns(&mut self, columns: &[Fr]) -> Result<()> {
+            self.builder
+                .add_columns(&Self::columns(columns)?)
+                .map_err(|err| anyhow!("failed to add columns: {:?}", err))
+        }
+
+        fn add_final_columns(&mut self, columns: &[Fr]) -> Result<(Vec<Fr>, Vec<Fr>)> {
+            self.builder
+                .add_final_columns(&Self::columns(columns)?)
+                .map_err(|err| anyhow!("failed to add final columns: {:?}", err))
+        }
+    }
+
+    struct NeptuneTreeBuilder<TreeArity: PoseidonArity> {
+        builder: TreeBuilder<TreeArity>,
+        _gpu_grant: Option<Box<dyn GpuGrant>>,
+    }
+
+    impl<TreeArity: PoseidonArity> BaseTreeBuilder for NeptuneTreeBuilder<TreeArity> {
+        fn add_leaves(&mut self, leaves: &[Fr]) -> Result<()> {
+            self.builder
+                .add_leaves(leaves)
+                .map_err(|err| anyhow!("failed to add leaves: {:?}", err))
+        }
+
+        fn add_final_leaves(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>> {
+            let (_, tree_data) = self
+                .builder
+                .add_final_leaves(leaves)
+                .map_err(|err| anyhow!("failed to add final leaves: {:?}", err))?;
+
+            Ok(tree_data)
+        }
+    }
+
+    /// The backends building tree_c, one for each of the assigned `devices`, or the default one.
+    pub(crate) fn tree_c_backends(devices: &[usize]) -> Result<Vec<Backend>> {
+        if devices.is_empty() {
+            return Ok(vec![Arc::new(NeptuneBackend::default())]);
+        }
+
+        devices
+            .iter()
+            .map(|&index| Ok(Arc::new(NeptuneBackend::on_device(index)?) as Backend))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::bail;
+
+    struct NamedBackend(&'static str);
+
+    impl TreeBuilderBackend for NamedBackend {
+        fn name(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn column_tree_builder(
+            &self,
+            _layers: usize,
+            _arity: usize,
+            _nodes_count: usize,
+        ) -> Result<Box<dyn BaseColumnTreeBuilder>> {
+            bail!("unsupported")
+        }
+
+        fn tree_builder(
+            &self,
+            _arity: usize,
+            _nodes_count: usize,
+            _rows_to_discard: usize,
+        ) -> Result<Box<dyn BaseTreeBuilder>> {
+            bail!("unsupported")
+        }
+    }
+
+    fn current_name() -> Option<String> {
+        tree_builder_backend().map(|backend| backend.name())
+    }
+
+    #[test]
+    fn test_with_tree_builder_backend() {
+        assert_eq!(current_name(), None);
+        let names = with_tree_builder_backend(Arc::new(NamedBackend("outer")), || {
+            let outer = current_name();
+            let inner = with_tree_builder_backend(Arc::new(NamedBackend("inner")), current_name);
+            (outer, inner, current_name())
+        });
+        assert_eq!(
+            names,
+            (
+                Some("outer".to_string()),
+                Some("inner".to_string()),
+                Some("outer".to_string())
+            )
+        );
+
+        // The backend only applies within `with_tree_builder_backend`, also when `f` panics.
+        assert_eq!(current_name(), None);
+        let panicked = std::panic::catch_unwind(|| {
+            with_tree_builder_backend(Arc::new(NamedBackend("failing")), || panic!("PC2 failed"))
+        });
+        assert!(panicked.is_err());
+        assert_eq!(current_name(), None);
+    }
+}
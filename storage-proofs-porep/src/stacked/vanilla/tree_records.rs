@@ -0,0 +1,304 @@
+//! Records of the complete base trees of tree_c and tree_r_last, with which PC2 resumes.
+//!
+//! With `FIL_PROOFS_PC2_TREE_RECORDS`, or when PC2 runs with a deadline, so that it can be resumed
+//! once it paused, base trees are recorded: once a base tree is written during PC2, it is synced,
+//! and the length and SHA-256 digest of its file are recorded next to it (in `<file>.digest`),
+//! and a later PC2 of the sector only builds the base trees whose files don't match their records,
+//! e.g. after it died while building them. A base tree of tree_r_last also encodes its part of the
+//! replica (unless PC1 encoded it already), which is recorded (in `<file>.encoding`) before the
+//! first node is encoded, and stays recorded once the base tree is complete: a part which may be
+//! encoded already can't be encoded again, and PC2 fails, so that the data is staged again.
+//!
+//! The records are discarded by PC1 and whenever data is staged into the replica, as trees of the
+//! previous layers or data must not be reused.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use log::info;
+use merkletree::store::StoreConfig;
+use sha2::{Digest, Sha256};
+
+const DIGEST_EXTENSION: &str = "digest";
+const ENCODING_EXTENSION: &str = "encoding";
+
+/// The base trees of a tree which are still to be built.
+#[derive(Debug)]
+pub(crate) struct PendingTrees {
+    indexes: Vec<usize>,
+    recorded: bool,
+    encodes: bool,
+}
+
+impl PendingTrees {
+    /// All `count` base trees, without records.
+    pub(crate) fn all(count: usize) -> Self {
+        PendingTrees {
+            indexes: (0..count).collect(),
+            recorded: false,
+            encodes: false,
+        }
+    }
+
+    /// The base trees of `configs` whose files don't match their records, which are recorded once
+    /// built. With `encodes`, building a base tree encodes its part of the replica.
+    pub(crate) fn resume(configs: &[StoreConfig], encodes: bool) -> Result<Self> {
+        let mut indexes = Vec::with_capacity(configs.len());
+        for (i, config) in configs.iter().enumerate() {
+            let path = StoreConfig::data_path(&config.path, &config.id);
+            if is_complete(config) {
+                info!("reusing complete base tree {}", path.display());
+                continue;
+            }
+            // The encoding record of a complete base tree is kept, as its part of the replica is
+            // encoded: it only fails the resume if the tree is incomplete, hence checked second.
+            ensure!(
+                !encodes || !record_path(config, ENCODING_EXTENSION).exists(),
+                "the replica may be partially encoded by {}, the data must be staged again",
+                path.display()
+            );
+
+            remove_record(config, DIGEST_EXTENSION)?;
+            indexes.push(i);
+        }
+
+        Ok(PendingTrees {
+            indexes,
+            recorded: true,
+            encodes,
+        })
+    }
+
+    /// All base trees of `configs`, which are not recorded once built, as if they had no records.
+    /// Fails like [`resume`](Self::resume) if `encodes` and the replica may be partially encoded.
+    pub(crate) fn unrecorded(configs: &[StoreConfig], encodes: bool) -> Result<Self> {
+        for config in configs {
+            ensure!(
+                !encodes || !record_path(config, ENCODING_EXTENSION).exists(),
+                "the replica may be partially encoded by {}, the data must be staged again",
+                StoreConfig::data_path(&config.path, &config.id).display()
+            );
+            remove_record(config, DIGEST_EXTENSION)?;
+        }
+
+        Ok(PendingTrees {
+            encodes,
+            ..PendingTrees::all(configs.len())
+        })
+    }
+
+    /// The indexes of the base trees to build, in order.
+    pub(crate) fn indexes(&self) -> &[usize] {
+        &self.indexes
+    }
+
+    /// Records that building the base tree of `config` starts.
+    pub(crate) fn start(&self, config: &StoreConfig) -> Result<()> {
+        if self.recorded && self.encodes {
+            let path = record_path(config, ENCODING_EXTENSION);
+            fs::write(&path, b"").with_context(|| format!("could not write {:?}", path))?;
+        }
+
+        Ok(())
+    }
+
+    /// Records that the base tree of `config` is complete.
+    pub(crate) fn complete(&self, config: &StoreConfig) -> Result<()> {
+        if !self.recorded {
+            return Ok(());
+        }
+
+        let tree_path = StoreConfig::data_path(&config.path, &config.id);
+        File::open(&tree_path)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("could not sync {:?}", tree_path))?;
+        let record = file_record(&tree_path)?;
+
+        // Written atomically, so that a torn record never matches.
+        let path = record_path(config, DIGEST_EXTENSION);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, record).with_context(|| format!("could not write {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("could not rename {:?}", tmp_path))?;
+
+        Ok(())
+    }
+}
+
+/// Discards the records of all trees in `cache_path`.
+pub(crate) fn clear_records(cache_path: &Path) -> Result<()> {
+    let entries = match fs::read_dir(cache_path) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let is_record = path.extension().map_or(false, |extension| {
+            extension == DIGEST_EXTENSION || extension == ENCODING_EXTENSION
+        });
+        if is_record {
+            fs::remove_file(&path).with_context(|| format!("could not remove {:?}", path))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn record_path(config: &StoreConfig, extension: &str) -> PathBuf {
+    let mut path = StoreConfig::data_path(&config.path, &config.id).into_os_string();
+    path.push(".");
+    path.push(extension);
+
+    path.into()
+}
+
+fn remove_record(config: &StoreConfig, extension: &str) -> Result<()> {
+    let path = record_path(config, extension);
+    if path.exists() {
+        fs::remove_file(&path).with_context(|| format!("could not remove {:?}", path))?;
+    }
+
+    Ok(())
+}
+
+/// Whether the file of the base tree of `config` matches its record.
+fn is_complete(config: &StoreConfig) -> bool {
+    let record = match fs::read_to_string(record_path(config, DIGEST_EXTENSION)) {
+        Ok(record) => record,
+        Err(_) => return false,
+    };
+    let tree_path = StoreConfig::data_path(&config.path, &config.id);
+
+    file_record(&tree_path).map_or(false, |current| current == record)
+}
+
+/// The length and the SHA-256 digest of the file at `path`.
+fn file_record(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    let mut len = 0u64;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        len += read as u64;
+    }
+
+    Ok(format!("{} {}", len, hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, id: &str) -> StoreConfig {
+        StoreConfig::new(dir, id.to_string(), 0)
+    }
+
+    fn write_tree(config: &StoreConfig, contents: &[u8]) {
+        fs::write(StoreConfig::data_path(&config.path, &config.id), contents)
+            .expect("failed to write tree");
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let configs: Vec<_> = (0..3)
+            .map(|i| config(dir.path(), &format!("tree-c-{}", i)))
+            .collect();
+
+        let pending = PendingTrees::resume(&configs, false).expect("resume failed");
+        assert_eq!(pending.indexes(), &[0, 1, 2]);
+
+        for config in &configs {
+            write_tree(config, b"tree");
+        }
+        pending.complete(&configs[0]).expect("complete failed");
+        pending.complete(&configs[2]).expect("complete failed");
+        assert_eq!(
+            PendingTrees::resume(&configs, false)
+                .expect("resume failed")
+                .indexes(),
+            &[1]
+        );
+
+        // A tree which doesn't match its record is built again.
+        write_tree(&configs[2], b"torn");
+        assert_eq!(
+            PendingTrees::resume(&configs, false)
+                .expect("resume failed")
+                .indexes(),
+            &[1, 2]
+        );
+
+        clear_records(dir.path()).expect("clear_records failed");
+        assert_eq!(
+            PendingTrees::resume(&configs, false)
+                .expect("resume failed")
+                .indexes(),
+            &[0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_resume_encoding() {
+        let dir = tempfile::tempdir().expect("tempdir failure");
+        let configs: Vec<_> = (0..2)
+            .map(|i| config(dir.path(), &format!("tree-r-last-{}", i)))
+            .collect();
+
+        let pending = PendingTrees::resume(&configs, true).expect("resume failed");
+        pending.start(&configs[0]).expect("start failed");
+        write_tree(&configs[0], b"tree");
+        pending.complete(&configs[0]).expect("complete failed");
+
+        // The complete tree is reused despite its encoding record.
+        assert_eq!(
+            PendingTrees::resume(&configs, true)
+                .expect("resume failed")
+                .indexes(),
+            &[1]
+        );
+        pending.start(&configs[1]).expect("start failed");
+
+        // The part of the replica of the second tree may be partially encoded.
+        assert!(PendingTrees::resume(&configs, true).is_err());
+        assert_eq!(
+            PendingTrees::resume(&configs, false)
+                .expect("resume failed")
+                .indexes(),
+            &[1]
+        );
+
+        // Nor does building the trees without records.
+        assert!(PendingTrees::unrecorded(&configs, true).is_err());
+        let unrecorded = PendingTrees::unrecorded(&configs, false).expect("unrecorded failed");
+        assert_eq!(unrecorded.indexes(), &[0, 1]);
+        unrecorded.start(&configs[0]).expect("start failed");
+        unrecorded.complete(&configs[0]).expect("complete failed");
+        assert_eq!(
+            PendingTrees::resume(&configs, false)
+                .expect("resume failed")
+                .indexes(),
+            &[0, 1]
+        );
+
+        // Trees built without records don't record anything.
+        let all = PendingTrees::all(2);
+        assert_eq!(all.indexes(), &[0, 1]);
+        clear_records(dir.path()).expect("clear_records failed");
+        all.start(&configs[1]).expect("start failed");
+        write_tree(&configs[1], b"tree");
+        all.complete(&configs[1]).expect("complete failed");
+        assert_eq!(
+            PendingTrees::resume(&configs, true)
+                .expect("resume failed")
+                .indexes(),
+            &[0, 1]
+        );
+    }
+}
@@ -1,31 +1,45 @@
 use std::fs::remove_file;
+use std::path::Path;
+use std::sync::Arc;
 
+use anyhow::Result;
 use blstrs::Scalar as Fr;
 use ff::{Field, PrimeField};
 use filecoin_hashers::{
-    blake2s::Blake2sHasher, poseidon::PoseidonHasher, sha256::Sha256Hasher, Domain, Hasher,
+    blake2s::Blake2sHasher, poseidon::PoseidonHasher, sha256::Sha256Hasher, Domain, HashFunction,
+    Hasher, POSEIDON_CONSTANTS_11, POSEIDON_CONSTANTS_8,
 };
 use fr32::fr_into_bytes;
-use generic_array::typenum::{U0, U2, U4, U8};
+use generic_array::typenum::{Unsigned, U0, U2, U4, U8};
 use glob::glob;
-use merkletree::store::{Store, StoreConfig};
+use merkletree::{
+    merkle::{get_merkle_tree_cache_size, get_merkle_tree_len},
+    store::{Store, StoreConfig},
+};
+use neptune::poseidon::Poseidon;
 use rand::{Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
 use storage_proofs_core::{
     api_version::ApiVersion,
     cache_key::CacheKey,
     drgraph::BASE_DEGREE,
-    merkle::{get_base_tree_count, DiskTree, MerkleTreeTrait},
+    merkle::{
+        create_lc_tree, get_base_tree_count, split_config_and_replica, DiskTree, MerkleProofTrait,
+        MerkleTreeTrait,
+    },
     proof::ProofScheme,
-    table_tests,
+    settings::{Settings, SETTINGS},
+    table_tests, tenant,
     test_helper::setup_replica,
     util::{default_rows_to_discard, NODE_SIZE},
     TEST_SEED,
 };
 use storage_proofs_porep::{
     stacked::{
-        LayerChallenges, PrivateInputs, PublicInputs, SetupParams, StackedBucketGraph, StackedDrg,
-        TemporaryAux, TemporaryAuxCache, BINARY_ARITY, EXP_DEGREE,
+        clear_replica_encoding, read_replica_encoding, with_tree_builder_backend,
+        BaseColumnTreeBuilder, BaseTreeBuilder, LabelingOptions, LabelingStrategy, LayerChallenges,
+        PrivateInputs, PublicInputs, ReplicaEncoding, SetupParams, StackedBucketGraph, StackedDrg,
+        TemporaryAux, TemporaryAuxCache, TreeBuilderBackend, BINARY_ARITY, EXP_DEGREE,
     },
     PoRep,
 };
@@ -307,6 +321,330 @@ fn test_stacked_porep_resume_seal() {
     cache_dir.close().expect("Failed to remove cache dir");
 }
 
+#[test]
+fn test_stacked_porep_encode_in_phase1() {
+    type Tree = DiskTree<PoseidonHasher, U8, U0, U0>;
+
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let replica_id = <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+    let nodes = 64 * get_base_tree_count::<Tree>();
+
+    let data: Vec<u8> = (0..nodes)
+        .flat_map(|_| {
+            let v = <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+            v.into_bytes()
+        })
+        .collect();
+
+    let layer_challenges = LayerChallenges::new(DEFAULT_STACKED_LAYERS, 5);
+    let sp = SetupParams {
+        nodes,
+        degree: BASE_DEGREE,
+        expansion_degree: EXP_DEGREE,
+        porep_id: [32; 32],
+        layer_challenges,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+
+    let cache_dir1 = tempdir().expect("tempdir failure");
+    let cache_dir2 = tempdir().expect("tempdir failure");
+    let config = |cache_dir: &Path| {
+        StoreConfig::new(
+            cache_dir,
+            CacheKey::CommDTree.to_string(),
+            default_rows_to_discard(nodes, BINARY_ARITY),
+        )
+    };
+
+    // Encoding as part of phase2.
+    let replica_path1 = cache_dir1.path().join("replica-path");
+    let mut mmapped_data1 = setup_replica(&data, &replica_path1);
+    let (tau1, _) = StackedDrg::<Tree, Blake2sHasher>::replicate(
+        &pp,
+        &replica_id,
+        (mmapped_data1.as_mut()).into(),
+        None,
+        config(cache_dir1.path()),
+        replica_path1,
+    )
+    .expect("replication failed");
+
+    // Encoding as part of phase1.
+    let replica_path2 = cache_dir2.path().join("replica-path");
+    let mut mmapped_data2 = setup_replica(&data, &replica_path2);
+    let data_tree = StackedDrg::<Tree, Blake2sHasher>::build_binary_tree::<Blake2sHasher>(
+        &data,
+        config(cache_dir2.path()),
+    )
+    .expect("failed to build data tree");
+    let labels = StackedDrg::<Tree, Blake2sHasher>::replicate_phase1_and_encode(
+        &pp,
+        &replica_id,
+        config(cache_dir2.path()),
+        &replica_path2,
+        &LabelingOptions::default(),
+    )
+    .expect("replicate_phase1_and_encode failed");
+    assert_eq!(
+        read_replica_encoding(cache_dir2.path()).expect("failed to read replica encoding"),
+        ReplicaEncoding::Encoded
+    );
+    assert_eq!(&mmapped_data1[..], &mmapped_data2[..]);
+
+    let (tau2, _) = StackedDrg::<Tree, Blake2sHasher>::replicate_phase2(
+        &pp,
+        labels,
+        (mmapped_data2.as_mut()).into(),
+        data_tree,
+        config(cache_dir2.path()),
+        replica_path2,
+    )
+    .expect("replicate_phase2 failed");
+
+    assert_eq!(tau1, tau2);
+    assert_eq!(&mmapped_data1[..], &mmapped_data2[..]);
+
+    clear_replica_encoding(cache_dir2.path()).expect("failed to clear replica encoding");
+    assert_eq!(
+        read_replica_encoding(cache_dir2.path()).expect("failed to read replica encoding"),
+        ReplicaEncoding::Original
+    );
+}
+
+#[test]
+fn test_stacked_porep_phase1_batch() {
+    test_stacked_porep_phase1_batch_interleaving(0);
+}
+
+#[test]
+fn test_stacked_porep_phase1_batch_interleaved() {
+    test_stacked_porep_phase1_batch_interleaving(2);
+}
+
+fn test_stacked_porep_phase1_batch_interleaving(interleave: usize) {
+    type Tree = DiskTree<PoseidonHasher, U8, U0, U0>;
+
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let nodes = 64 * get_base_tree_count::<Tree>();
+    let replica_ids: Vec<_> = (0..3)
+        .map(|_| <PoseidonHasher as Hasher>::Domain::random(&mut rng))
+        .collect();
+
+    let layer_challenges = LayerChallenges::new(DEFAULT_STACKED_LAYERS, 5);
+    let sp = SetupParams {
+        nodes,
+        degree: BASE_DEGREE,
+        expansion_degree: EXP_DEGREE,
+        porep_id: [32; 32],
+        layer_challenges,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+
+    let config = |cache_dir: &Path| {
+        StoreConfig::new(
+            cache_dir,
+            CacheKey::CommDTree.to_string(),
+            default_rows_to_discard(nodes, BINARY_ARITY),
+        )
+    };
+    let single_dirs: Vec<_> = replica_ids
+        .iter()
+        .map(|_| tempdir().expect("tempdir failure"))
+        .collect();
+    let batch_dirs: Vec<_> = replica_ids
+        .iter()
+        .map(|_| tempdir().expect("tempdir failure"))
+        .collect();
+
+    let tenant_name = format!("test-phase1-batch-interleave-{}", interleave);
+    let settings = Settings {
+        batch_sdr_interleave: interleave,
+        ..(*SETTINGS).clone()
+    };
+    tenant::register_tenant(&tenant_name, settings, None).expect("register_tenant failed");
+    let batch_labels = tenant::with_tenant(&tenant_name, || {
+        StackedDrg::<Tree, Blake2sHasher>::replicate_phase1_batch(
+            &pp,
+            &replica_ids,
+            batch_dirs.iter().map(|dir| config(dir.path())).collect(),
+            &LabelingOptions::default(),
+        )
+    })
+    .expect("with_tenant failed")
+    .expect("replicate_phase1_batch failed");
+    tenant::remove_tenant(&tenant_name);
+    assert_eq!(batch_labels.len(), replica_ids.len());
+
+    for ((replica_id, single_dir), batch_labels) in
+        replica_ids.iter().zip(&single_dirs).zip(&batch_labels)
+    {
+        let single_labels = StackedDrg::<Tree, Blake2sHasher>::replicate_phase1(
+            &pp,
+            replica_id,
+            config(single_dir.path()),
+        )
+        .expect("replicate_phase1 failed");
+
+        assert_eq!(single_labels.labels.len(), batch_labels.labels.len());
+        for (single, batch) in single_labels.labels.iter().zip(&batch_labels.labels) {
+            let read = |config: &StoreConfig| {
+                std::fs::read(StoreConfig::data_path(&config.path, &config.id))
+                    .expect("failed to read layer")
+            };
+            assert_eq!(read(single), read(batch));
+        }
+    }
+}
+
+#[test]
+fn test_stacked_porep_phase1_strategy() {
+    type Tree = DiskTree<PoseidonHasher, U8, U0, U0>;
+
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let nodes = 64 * get_base_tree_count::<Tree>();
+    let replica_id = <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+
+    let layer_challenges = LayerChallenges::new(DEFAULT_STACKED_LAYERS, 5);
+    let sp = SetupParams {
+        nodes,
+        degree: BASE_DEGREE,
+        expansion_degree: EXP_DEGREE,
+        porep_id: [32; 32],
+        layer_challenges,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+
+    let label = |options: LabelingOptions| -> Result<Vec<Vec<u8>>> {
+        let cache_dir = tempdir()?;
+        let config = StoreConfig::new(
+            cache_dir.path(),
+            CacheKey::CommDTree.to_string(),
+            default_rows_to_discard(nodes, BINARY_ARITY),
+        );
+        let labels = StackedDrg::<Tree, Blake2sHasher>::replicate_phase1_with_options(
+            &pp,
+            &replica_id,
+            config,
+            &options,
+        )?;
+        labels
+            .labels
+            .iter()
+            .map(|config| {
+                std::fs::read(StoreConfig::data_path(&config.path, &config.id)).map_err(Into::into)
+            })
+            .collect()
+    };
+
+    let with_strategy = |strategy: LabelingStrategy| LabelingOptions {
+        strategy,
+        ..LabelingOptions::default()
+    };
+
+    // Both strategies yield the labels of the configured one.
+    let expected = label(LabelingOptions::default()).expect("labeling failed");
+    assert_eq!(
+        label(with_strategy(LabelingStrategy::SingleCore)).expect("single core labeling failed"),
+        expected
+    );
+    let multi_core = label(with_strategy(LabelingStrategy::MultiCore));
+
+    #[cfg(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity"))]
+    {
+        use storage_proofs_porep::stacked::MulticoreSdrSizing;
+
+        assert_eq!(multi_core.expect("multicore labeling failed"), expected);
+
+        // So do the multicore SDR buffers of any size.
+        let sized = LabelingOptions {
+            sizing: MulticoreSdrSizing {
+                lookahead: Some(16),
+                producer_stride: Some(4),
+                cache_window_nodes: Some(32),
+            },
+            ..with_strategy(LabelingStrategy::MultiCore)
+        };
+        assert_eq!(label(sized).expect("sized labeling failed"), expected);
+    }
+
+    #[cfg(not(any(feature = "multicore-sdr", feature = "multicore-sdr-os-affinity")))]
+    assert!(multi_core.is_err());
+}
+
+#[test]
+#[cfg(all(feature = "gpu-sdr", feature = "isolated-testing"))]
+fn test_stacked_porep_phase1_batch_gpu() {
+    use storage_proofs_porep::stacked::create_label;
+
+    type Tree = DiskTree<PoseidonHasher, U8, U0, U0>;
+
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let nodes = 64 * get_base_tree_count::<Tree>();
+    let replica_ids: Vec<_> = (0..3)
+        .map(|_| <PoseidonHasher as Hasher>::Domain::random(&mut rng))
+        .collect();
+
+    let layer_challenges = LayerChallenges::new(DEFAULT_STACKED_LAYERS, 5);
+    let sp = SetupParams {
+        nodes,
+        degree: BASE_DEGREE,
+        expansion_degree: EXP_DEGREE,
+        porep_id: [32; 32],
+        layer_challenges,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+
+    let config = |cache_dir: &Path| {
+        StoreConfig::new(
+            cache_dir,
+            CacheKey::CommDTree.to_string(),
+            default_rows_to_discard(nodes, BINARY_ARITY),
+        )
+    };
+    let cpu_dirs: Vec<_> = replica_ids
+        .iter()
+        .map(|_| tempdir().expect("tempdir failure"))
+        .collect();
+    let gpu_dirs: Vec<_> = replica_ids
+        .iter()
+        .map(|_| tempdir().expect("tempdir failure"))
+        .collect();
+
+    let mut parent_cache = pp.graph.parent_cache().expect("parent_cache failed");
+    let gpu_labels = create_label::gpu::create_labels_for_encoding::<Tree, _>(
+        &pp.graph,
+        &mut parent_cache,
+        DEFAULT_STACKED_LAYERS,
+        &replica_ids,
+        gpu_dirs.iter().map(|dir| config(dir.path())).collect(),
+        &LabelingOptions::default(),
+    )
+    .expect("GPU labeling failed");
+    let cpu_labels = create_label::batch::create_labels_for_encoding::<Tree, _>(
+        &pp.graph,
+        &mut parent_cache,
+        DEFAULT_STACKED_LAYERS,
+        &replica_ids,
+        cpu_dirs.iter().map(|dir| config(dir.path())).collect(),
+        &LabelingOptions::default(),
+    )
+    .expect("CPU labeling failed");
+
+    for ((gpu, _), (cpu, _)) in gpu_labels.iter().zip(&cpu_labels) {
+        for (gpu, cpu) in gpu.labels.iter().zip(&cpu.labels) {
+            let read = |config: &StoreConfig| {
+                std::fs::read(StoreConfig::data_path(&config.path, &config.id))
+                    .expect("failed to read layer")
+            };
+            assert_eq!(read(gpu), read(cpu));
+        }
+    }
+}
+
 table_tests! {
     test_prove_verify_fixed {
        test_stacked_porep_prove_verify(64);
@@ -440,6 +778,114 @@ fn test_prove_verify<Tree: 'static + MerkleTreeTrait>(n: usize, challenges: Laye
     cache_dir.close().expect("Failed to remove cache dir");
 }
 
+#[test]
+fn test_stacked_porep_prove_verify_non_interactive() {
+    let challenges = LayerChallenges::new_non_interactive(DEFAULT_STACKED_LAYERS, 5);
+
+    test_prove_verify::<DiskTree<PoseidonHasher, U8, U0, U0>>(64, challenges.clone());
+    test_prove_verify::<DiskTree<PoseidonHasher, U8, U8, U2>>(64, challenges);
+}
+
+#[test]
+fn test_stacked_porep_prove_verify_synthetic() {
+    type Tree = DiskTree<PoseidonHasher, U8, U0, U0>;
+
+    let nodes = 64 * get_base_tree_count::<Tree>();
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+
+    let replica_id: <PoseidonHasher as Hasher>::Domain =
+        <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+    let data: Vec<u8> = (0..nodes)
+        .flat_map(|_| fr_into_bytes(&Fr::random(&mut rng)))
+        .collect();
+
+    let cache_dir = tempdir().expect("tempdir failure");
+    let config = StoreConfig::new(
+        cache_dir.path(),
+        CacheKey::CommDTree.to_string(),
+        default_rows_to_discard(nodes, BINARY_ARITY),
+    );
+    let replica_path = cache_dir.path().join("replica-path");
+    let mut mmapped_data = setup_replica(&data, &replica_path);
+
+    let partitions = 2;
+    let sp = SetupParams {
+        nodes,
+        degree: BASE_DEGREE,
+        expansion_degree: EXP_DEGREE,
+        porep_id: [92; 32],
+        layer_challenges: LayerChallenges::new_synthetic(DEFAULT_STACKED_LAYERS, 5, 32),
+        api_version: ApiVersion::V1_1_0,
+    };
+
+    let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+    let (tau, (p_aux, t_aux)) = StackedDrg::<Tree, Blake2sHasher>::replicate(
+        &pp,
+        &replica_id,
+        (mmapped_data.as_mut()).into(),
+        None,
+        config,
+        replica_path.clone(),
+    )
+    .expect("replication failed");
+
+    // The synthetic proofs are generated before the seed is known.
+    let mut pub_inputs = PublicInputs {
+        replica_id,
+        seed: [0; 32],
+        tau: Some(tau),
+        k: None,
+    };
+    let t_aux_cache = TemporaryAuxCache::<Tree, Blake2sHasher>::new(&t_aux, replica_path)
+        .expect("failed to restore contents of t_aux");
+    let synth_path = cache_dir.path().join(CacheKey::SynthProofs.to_string());
+    StackedDrg::<Tree, Blake2sHasher>::write_synth_proofs(
+        &synth_path,
+        &pp,
+        &pub_inputs,
+        &p_aux,
+        &t_aux_cache,
+    )
+    .expect("failed to write synthetic proofs");
+    drop(t_aux_cache);
+
+    // The layers aren't needed anymore.
+    TemporaryAux::<Tree, Blake2sHasher>::clear_labels(&t_aux).expect("labels delete failed");
+    for config in &t_aux.labels.labels {
+        assert!(!Path::new(&StoreConfig::data_path(&config.path, &config.id)).exists());
+    }
+
+    pub_inputs.seed = rng.gen();
+    let all_partition_proofs = StackedDrg::<Tree, Blake2sHasher>::prove_all_partitions_synth(
+        &synth_path,
+        &pp,
+        &pub_inputs,
+        partitions,
+    )
+    .expect("failed to read synthetic proofs");
+
+    let proofs_are_valid = StackedDrg::<Tree, Blake2sHasher>::verify_all_partitions(
+        &pp,
+        &pub_inputs,
+        &all_partition_proofs,
+    )
+    .expect("failed to verify partition proofs");
+    assert!(proofs_are_valid);
+
+    // The proofs of one seed don't prove the challenges of another.
+    pub_inputs.seed = rng.gen();
+    let proofs_are_valid = StackedDrg::<Tree, Blake2sHasher>::verify_all_partitions(
+        &pp,
+        &pub_inputs,
+        &all_partition_proofs,
+    )
+    .expect("failed to verify partition proofs");
+    assert!(!proofs_are_valid);
+
+    TemporaryAux::<Tree, Blake2sHasher>::clear_temp(t_aux).expect("t_aux delete failed");
+    cache_dir.close().expect("Failed to remove cache dir");
+}
+
 // We are seeing a bug, in which setup never terminates for some sector sizes. This test is to
 // debug that and should remain as a regression test.
 #[test]
@@ -579,3 +1025,294 @@ fn test_generate_labels_aux(
 
     assert_eq!(expected_last_label.to_repr(), last_label.0);
 }
+
+#[test]
+fn test_stacked_porep_fake_replicate_poseidon_base_8() {
+    test_fake_replicate::<DiskTree<PoseidonHasher, U8, U0, U0>>();
+}
+
+#[test]
+fn test_stacked_porep_fake_replicate_poseidon_sub_8_4() {
+    test_fake_replicate::<DiskTree<PoseidonHasher, U8, U4, U0>>();
+}
+
+#[test]
+fn test_stacked_porep_fake_replicate_poseidon_top_8_8_2() {
+    test_fake_replicate::<DiskTree<PoseidonHasher, U8, U8, U2>>();
+}
+
+fn test_fake_replicate<Tree: 'static + MerkleTreeTrait>() {
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let nodes_count = 512;
+    let tree_count = get_base_tree_count::<Tree>();
+    let sector_size = nodes_count * tree_count * NODE_SIZE;
+
+    let cache_dir = tempdir().expect("tempdir failure");
+    let replica_path = cache_dir.path().join("replica-path");
+    std::fs::write(&replica_path, vec![0u8; sector_size]).expect("failed to write replica");
+
+    let comm_c = <Tree::Hasher as Hasher>::Domain::random(&mut rng);
+    let (comm_r, p_aux) = StackedDrg::<Tree, Sha256Hasher>::fake_replicate_phase2(
+        comm_c,
+        &replica_path,
+        cache_dir.path(),
+        sector_size,
+    )
+    .expect("fake_replicate_phase2 failed");
+    assert_eq!(
+        comm_r,
+        <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &p_aux.comm_r_last)
+    );
+
+    // The written rows must be those of a tree of zeros, and the discarded ones are rebuilt from
+    // the replica.
+    let rows_to_discard = default_rows_to_discard(nodes_count, Tree::Arity::to_usize());
+    let config = StoreConfig::new(
+        cache_dir.path(),
+        CacheKey::CommRLastTree.to_string(),
+        rows_to_discard,
+    );
+    let (configs, replica_config) =
+        split_config_and_replica(config, replica_path, nodes_count, tree_count)
+            .expect("split_config_and_replica failed");
+    let tree_r_last = create_lc_tree::<Tree>(
+        get_merkle_tree_len(nodes_count, Tree::Arity::to_usize()).expect("invalid tree"),
+        &configs,
+        &replica_config,
+    )
+    .expect("failed to open tree_r_last");
+    assert_eq!(tree_r_last.root(), p_aux.comm_r_last);
+
+    for &i in &[0, 1, nodes_count - 1, nodes_count * tree_count - 1] {
+        let proof = tree_r_last
+            .gen_cached_proof(i, Some(rows_to_discard))
+            .expect("gen_cached_proof failed");
+        assert!(proof.validate(i));
+        assert_eq!(proof.leaf(), <Tree::Hasher as Hasher>::Domain::default());
+        assert_eq!(proof.root(), p_aux.comm_r_last);
+    }
+}
+
+#[test]
+fn test_stacked_porep_tree_c_memory_budget() {
+    type Tree = DiskTree<PoseidonHasher, U8, U2, U0>;
+
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let replica_id = <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+    let nodes = 64 * get_base_tree_count::<Tree>();
+
+    let data: Vec<u8> = (0..nodes)
+        .flat_map(|_| {
+            let v = <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+            v.into_bytes()
+        })
+        .collect();
+
+    let layer_challenges = LayerChallenges::new(DEFAULT_STACKED_LAYERS, 5);
+    let sp = SetupParams {
+        nodes,
+        degree: BASE_DEGREE,
+        expansion_degree: EXP_DEGREE,
+        porep_id: [33; 32],
+        layer_challenges,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+
+    let replicate = |cache_dir: &Path| {
+        let config = StoreConfig::new(
+            cache_dir,
+            CacheKey::CommDTree.to_string(),
+            default_rows_to_discard(nodes, BINARY_ARITY),
+        );
+        let replica_path = cache_dir.join("replica-path");
+        let mut mmapped_data = setup_replica(&data, &replica_path);
+        StackedDrg::<Tree, Blake2sHasher>::replicate(
+            &pp,
+            &replica_id,
+            (mmapped_data.as_mut()).into(),
+            None,
+            config,
+            replica_path,
+        )
+        .expect("replication failed")
+    };
+
+    let cache_dir1 = tempdir().expect("tempdir failure");
+    let (tau1, (p_aux1, _)) = replicate(cache_dir1.path());
+
+    // Windows of 10 columns, which don't divide the base trees of 64 nodes.
+    let tenant_name = "test-tree-c-memory-budget";
+    let settings = Settings {
+        tree_c_memory_budget: ((DEFAULT_STACKED_LAYERS + 1) * NODE_SIZE * 10) as u64,
+        ..(*SETTINGS).clone()
+    };
+    tenant::register_tenant(tenant_name, settings, None).expect("register_tenant failed");
+    let cache_dir2 = tempdir().expect("tempdir failure");
+    let (tau2, (p_aux2, _)) = tenant::with_tenant(tenant_name, || replicate(cache_dir2.path()))
+        .expect("with_tenant failed");
+    tenant::remove_tenant(tenant_name);
+
+    assert_eq!(p_aux1.comm_c, p_aux2.comm_c);
+    assert_eq!(tau1, tau2);
+}
+
+/// A tree builder backend hashing the base trees on the CPU, row after row.
+struct RowBackend;
+
+impl TreeBuilderBackend for RowBackend {
+    fn name(&self) -> String {
+        "rows".to_string()
+    }
+
+    fn column_tree_builder(
+        &self,
+        layers: usize,
+        arity: usize,
+        nodes_count: usize,
+    ) -> Result<Box<dyn BaseColumnTreeBuilder>> {
+        assert_eq!((layers, arity), (DEFAULT_STACKED_LAYERS, 8));
+        Ok(Box::new(RowTreeBuilder {
+            nodes_count,
+            rows_to_discard: 0,
+            leaves: Vec::new(),
+        }))
+    }
+
+    fn tree_builder(
+        &self,
+        arity: usize,
+        nodes_count: usize,
+        rows_to_discard: usize,
+    ) -> Result<Box<dyn BaseTreeBuilder>> {
+        assert_eq!(arity, 8);
+        Ok(Box::new(RowTreeBuilder {
+            nodes_count,
+            rows_to_discard,
+            leaves: Vec::new(),
+        }))
+    }
+}
+
+struct RowTreeBuilder {
+    nodes_count: usize,
+    rows_to_discard: usize,
+    leaves: Vec<Fr>,
+}
+
+impl RowTreeBuilder {
+    fn add_columns(&mut self, columns: &[Fr]) {
+        self.leaves.extend(
+            columns
+                .chunks(DEFAULT_STACKED_LAYERS)
+                .map(|column| Poseidon::new_with_preimage(column, &*POSEIDON_CONSTANTS_11).hash()),
+        );
+    }
+
+    /// The rows above the leaves of the current base tree up to its root.
+    fn rows(&self) -> Vec<Fr> {
+        assert_eq!(self.leaves.len(), self.nodes_count);
+        let mut rows: Vec<Fr> = Vec::new();
+        let mut row = self.leaves.clone();
+        while row.len() > 1 {
+            row = row
+                .chunks(8)
+                .map(|children| {
+                    Poseidon::new_with_preimage(children, &*POSEIDON_CONSTANTS_8).hash()
+                })
+                .collect();
+            rows.extend(&row);
+        }
+
+        rows
+    }
+}
+
+impl BaseColumnTreeBuilder for RowTreeBuilder {
+    fn add_columns(&mut self, columns: &[Fr]) -> Result<()> {
+        RowTreeBuilder::add_columns(self, columns);
+        Ok(())
+    }
+
+    fn add_final_columns(&mut self, columns: &[Fr]) -> Result<(Vec<Fr>, Vec<Fr>)> {
+        RowTreeBuilder::add_columns(self, columns);
+        let rows = self.rows();
+
+        Ok((std::mem::take(&mut self.leaves), rows))
+    }
+}
+
+impl BaseTreeBuilder for RowTreeBuilder {
+    fn add_leaves(&mut self, leaves: &[Fr]) -> Result<()> {
+        self.leaves.extend_from_slice(leaves);
+        Ok(())
+    }
+
+    fn add_final_leaves(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>> {
+        self.leaves.extend_from_slice(leaves);
+        let mut rows = self.rows();
+        self.leaves.clear();
+
+        // Only the top rows are stored.
+        let cache_size = get_merkle_tree_cache_size(self.nodes_count, 8, self.rows_to_discard)?;
+        Ok(rows.split_off(rows.len() - cache_size))
+    }
+}
+
+#[test]
+fn test_stacked_porep_tree_builder_backend() {
+    type Tree = DiskTree<PoseidonHasher, U8, U2, U0>;
+
+    let mut rng = XorShiftRng::from_seed(TEST_SEED);
+    let replica_id = <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+    let nodes = 64 * get_base_tree_count::<Tree>();
+
+    let data: Vec<u8> = (0..nodes)
+        .flat_map(|_| {
+            let v = <PoseidonHasher as Hasher>::Domain::random(&mut rng);
+            v.into_bytes()
+        })
+        .collect();
+
+    let layer_challenges = LayerChallenges::new(DEFAULT_STACKED_LAYERS, 5);
+    let sp = SetupParams {
+        nodes,
+        degree: BASE_DEGREE,
+        expansion_degree: EXP_DEGREE,
+        porep_id: [34; 32],
+        layer_challenges,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let pp = StackedDrg::<Tree, Blake2sHasher>::setup(&sp).expect("setup failed");
+
+    let replicate = |cache_dir: &Path| {
+        let config = StoreConfig::new(
+            cache_dir,
+            CacheKey::CommDTree.to_string(),
+            default_rows_to_discard(nodes, BINARY_ARITY),
+        );
+        let replica_path = cache_dir.join("replica-path");
+        let mut mmapped_data = setup_replica(&data, &replica_path);
+        StackedDrg::<Tree, Blake2sHasher>::replicate(
+            &pp,
+            &replica_id,
+            (mmapped_data.as_mut()).into(),
+            None,
+            config,
+            replica_path,
+        )
+        .expect("replication failed")
+    };
+
+    let cache_dir1 = tempdir().expect("tempdir failure");
+    let (tau1, (p_aux1, _)) = replicate(cache_dir1.path());
+
+    let cache_dir2 = tempdir().expect("tempdir failure");
+    let (tau2, (p_aux2, _)) =
+        with_tree_builder_backend(Arc::new(RowBackend), || replicate(cache_dir2.path()));
+
+    // The backend builds the same trees as the default builders.
+    assert_eq!(p_aux1.comm_c, p_aux2.comm_c);
+    assert_eq!(p_aux1.comm_r_last, p_aux2.comm_r_last);
+    assert_eq!(tau1, tau2);
+}
@@ -95,6 +95,8 @@ pub struct PrivateSector<'a, Tree: MerkleTreeTrait> {
     >,
     pub comm_c: <Tree::Hasher as Hasher>::Domain,
     pub comm_r_last: <Tree::Hasher as Hasher>::Domain,
+    /// The 'rows_to_discard' the tree was built with, if it isn't the default.
+    pub rows_to_discard: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -173,6 +175,7 @@ where
     _t: PhantomData<&'a Tree>,
 }
 
+/// Generates `challenge_count` sector challenges, see [`generate_sector_challenge`].
 pub fn generate_sector_challenges<T: Domain>(
     randomness: T,
     challenge_count: usize,
@@ -184,7 +187,13 @@ pub fn generate_sector_challenges<T: Domain>(
         .collect()
 }
 
-/// Generate a single sector challenge.
+/// Generate a single sector challenge, i.e. the index of the challenged sector within a set of
+/// `sector_set_len` sectors.
+///
+/// ```text
+/// h = SHA256(prover_id || randomness || n)            (n as u64, little endian)
+/// sector_index = (h[..8] as little endian u64) mod sector_set_len
+/// ```
 pub fn generate_sector_challenge<T: Domain>(
     randomness: T,
     n: usize,
@@ -227,6 +236,15 @@ pub fn generate_leaf_challenges<T: Domain>(
 }
 
 /// Generates challenge, such that the range fits into the sector.
+///
+/// ```text
+/// h = SHA256(randomness || sector_id || leaf_challenge_index)    (u64s, little endian)
+/// leaf = (h[..8] as little endian u64) mod (sector_size / 32)
+/// ```
+///
+/// For Window and Winning PoSt, `leaf_challenge_index` counts the challenges of all sectors
+/// proven together: it is `(partition_index * sector_count + i) * challenge_count + n` for
+/// challenge `n` of the i-th sector of a partition.
 pub fn generate_leaf_challenge<T: Domain>(
     pub_params: &PublicParams,
     randomness: T,
@@ -270,7 +288,9 @@ pub fn vanilla_proof<Tree: MerkleTreeTrait>(
     let tree = priv_sector.tree;
 
     let tree_leafs = tree.leafs();
-    let rows_to_discard = default_rows_to_discard(tree_leafs, Tree::Arity::to_usize());
+    let rows_to_discard = priv_sector
+        .rows_to_discard
+        .unwrap_or_else(|| default_rows_to_discard(tree_leafs, Tree::Arity::to_usize()));
 
     trace!(
         "Generating proof for tree leafs {} and arity {}",
@@ -381,8 +401,9 @@ impl<'a, Tree: 'a + MerkleTreeTrait> ProofScheme<'a> for FallbackPoSt<'a, Tree>
                     let sector_id = pub_sector.id;
                     let tree = priv_sector.tree;
                     let tree_leafs = tree.leafs();
-                    let rows_to_discard =
-                        default_rows_to_discard(tree_leafs, Tree::Arity::to_usize());
+                    let rows_to_discard = priv_sector.rows_to_discard.unwrap_or_else(|| {
+                        default_rows_to_discard(tree_leafs, Tree::Arity::to_usize())
+                    });
 
                     trace!(
                         "Generating proof for tree leafs {} and arity {}",
@@ -96,6 +96,7 @@ fn test_fallback_post<Tree: 'static + MerkleTreeTrait>(
             tree,
             comm_c,
             comm_r_last,
+            rows_to_discard: None,
         });
 
         let comm_r = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &comm_r_last);
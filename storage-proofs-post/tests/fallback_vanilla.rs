@@ -1,4 +1,6 @@
-use filecoin_hashers::{poseidon::PoseidonHasher, Domain, HashFunction, Hasher};
+use filecoin_hashers::{
+    poseidon::PoseidonHasher, sha256::Sha256Domain, Domain, HashFunction, Hasher,
+};
 use generic_array::typenum::{U0, U2, U4, U8};
 use rand::SeedableRng;
 use rand_xorshift::XorShiftRng;
@@ -151,6 +153,7 @@ fn test_fallback_post<Tree: MerkleTreeTrait>(
             tree,
             comm_c,
             comm_r_last,
+            rows_to_discard: None,
         });
 
         let comm_r = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &comm_r_last);
@@ -333,6 +336,7 @@ fn test_invalid_fallback_post<Tree: MerkleTreeTrait>(
             tree: if make_faulty { &wrong_tree } else { tree },
             comm_c,
             comm_r_last,
+            rows_to_discard: None,
         });
 
         let comm_r = <Tree::Hasher as Hasher>::Function::hash2(&comm_c, &comm_r_last);
@@ -379,3 +383,29 @@ fn test_invalid_fallback_post<Tree: MerkleTreeTrait>(
         },
     };
 }
+
+#[test]
+fn test_fallback_post_challenge_test_vectors() {
+    let randomness = Sha256Domain::from([3u8; 32]);
+    let prover_id = Sha256Domain::from([4u8; 32]);
+
+    let sector_challenges = fallback::generate_sector_challenges(randomness, 3, 100, prover_id)
+        .expect("generate_sector_challenges failure");
+    assert_eq!(sector_challenges, vec![7, 52, 89]);
+
+    let pub_params = fallback::PublicParams {
+        sector_size: 1 << 35,
+        challenge_count: 4,
+        sector_count: 1,
+        api_version: ApiVersion::V1_1_0,
+    };
+    let leaf_challenges = fallback::generate_leaf_challenges(&pub_params, randomness, 7, 4);
+    assert_eq!(
+        leaf_challenges,
+        vec![791887664, 937822264, 974533212, 825316506]
+    );
+    assert_eq!(
+        fallback::generate_leaf_challenge(&pub_params, randomness, 7, 3),
+        825316506
+    );
+}
@@ -31,10 +31,11 @@ pub const SECTOR_SIZE_32_KIB: usize = 1 << 10;
 pub const SECTOR_SIZE_8_MIB: usize = 1 << 18;
 pub const SECTOR_SIZE_16_MIB: usize = 1 << 19;
 pub const SECTOR_SIZE_512_MIB: usize = 1 << 24;
+pub const SECTOR_SIZE_16_GIB: usize = 1 << 29;
 pub const SECTOR_SIZE_32_GIB: usize = 1 << 30;
 pub const SECTOR_SIZE_64_GIB: usize = 1 << 31;
 
-pub const ALLOWED_SECTOR_SIZES: [usize; 11] = [
+pub const ALLOWED_SECTOR_SIZES: [usize; 12] = [
     // testing sector-sizes
     SECTOR_SIZE_1_KIB,
     SECTOR_SIZE_2_KIB,
@@ -45,6 +46,8 @@ pub const ALLOWED_SECTOR_SIZES: [usize; 11] = [
     SECTOR_SIZE_8_MIB,
     SECTOR_SIZE_16_MIB,
     SECTOR_SIZE_512_MIB,
+    // unpublished sector-sizes
+    SECTOR_SIZE_16_GIB,
     // published sector-sizes
     SECTOR_SIZE_32_GIB,
     SECTOR_SIZE_64_GIB,
@@ -126,6 +129,7 @@ pub fn validate_tree_r_shape<TreeR: MerkleTreeTrait>(sector_nodes: usize) {
         SECTOR_SIZE_8_MIB => (8, 0, 0),
         SECTOR_SIZE_16_MIB => (8, 2, 0),
         SECTOR_SIZE_512_MIB => (8, 0, 0),
+        SECTOR_SIZE_16_GIB => (8, 4, 0),
         SECTOR_SIZE_32_GIB => (8, 8, 0),
         SECTOR_SIZE_64_GIB => (8, 8, 2),
         _ => unreachable!(),
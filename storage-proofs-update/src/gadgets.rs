@@ -296,7 +296,9 @@ mod tests {
     fn test_gen_challenge_bits_gadget() {
         let mut rng = XorShiftRng::from_seed(TEST_SEED);
 
-        let num_constraints_expected = [568, 568, 568, 568, 568, 568, 568, 568, 4544, 5680, 5680];
+        let num_constraints_expected = [
+            568, 568, 568, 568, 568, 568, 568, 568, 4544, 5112, 5680, 5680,
+        ];
 
         for (sector_nodes, constraints_expected) in ALLOWED_SECTOR_SIZES
             .iter()
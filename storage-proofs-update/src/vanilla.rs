@@ -1,4 +1,6 @@
-use std::fs::{metadata, OpenOptions};
+use std::cmp::min;
+use std::fs::{metadata, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
 use std::iter::FromIterator;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
@@ -18,7 +20,7 @@ use merkletree::{
 use neptune::Poseidon;
 use rayon::{
     iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator},
-    slice::ParallelSliceMut,
+    slice::{ParallelSlice, ParallelSliceMut},
 };
 use serde::{Deserialize, Serialize};
 use storage_proofs_core::{
@@ -43,6 +45,9 @@ use crate::{
 };
 
 const CHUNK_SIZE_MIN: usize = 4096;
+// The number of nodes of the windows of the files that are streamed rather than mapped in memory.
+// Streaming holds five windows at once: the two being read, the two being mapped and the output.
+const STREAM_WINDOW_NODES: usize = 1 << 20;
 const FR_SIZE: usize = std::mem::size_of::<Fr>() as usize;
 
 #[derive(Clone)]
@@ -547,6 +552,102 @@ fn mmap_write(path: &Path) -> Result<MmapMut, Error> {
     }
 }
 
+// Reads `nodes_count` nodes from both `first` and `second`, in windows of `STREAM_WINDOW_NODES`
+// nodes, and writes `f(node_index, first_node, second_node)` of each of them to `out`, in order.
+// The next windows of the inputs are read while the current ones are mapped and written.
+fn map_node_streams<A, B, W, F>(
+    nodes_count: usize,
+    mut first: A,
+    mut second: B,
+    out: &mut W,
+    f: F,
+) -> Result<(), Error>
+where
+    A: Read + Send,
+    B: Read + Send,
+    W: Write + Send,
+    F: Fn(usize, Fr, Fr) -> Fr + Sync,
+{
+    let window_len = |start: usize| min(nodes_count - start, STREAM_WINDOW_NODES) * FR_SIZE;
+    let mut current = (vec![0u8; window_len(0)], vec![0u8; window_len(0)]);
+    let mut next = (vec![0u8; window_len(0)], vec![0u8; window_len(0)]);
+    let mut out_window = vec![0u8; window_len(0)];
+
+    read_windows(&mut first, &mut second, &mut current, window_len(0))?;
+
+    let mut start = 0;
+    while start < nodes_count {
+        let len = window_len(start);
+        let next_start = start + len / FR_SIZE;
+        let next_len = window_len(next_start);
+
+        let (mapped, read) = rayon::join(
+            || -> Result<()> {
+                out_window[..len]
+                    .par_chunks_mut(FR_SIZE)
+                    .zip(current.0[..len].par_chunks(FR_SIZE))
+                    .zip(current.1[..len].par_chunks(FR_SIZE))
+                    .enumerate()
+                    .try_for_each(|(i, ((out_node, first_node), second_node))| -> Result<()> {
+                        let out_fr = f(
+                            start + i,
+                            bytes_into_fr(first_node)?,
+                            bytes_into_fr(second_node)?,
+                        );
+                        fr_into_bytes_slice(&out_fr, out_node);
+
+                        Ok(())
+                    })?;
+                out.write_all(&out_window[..len])?;
+
+                Ok(())
+            },
+            || read_windows(&mut first, &mut second, &mut next, next_len),
+        );
+        mapped?;
+        read?;
+
+        std::mem::swap(&mut current, &mut next);
+        start = next_start;
+    }
+
+    Ok(())
+}
+
+// Reads the next `len` bytes of `first` and `second` into `windows`.
+fn read_windows<A: Read, B: Read>(
+    first: &mut A,
+    second: &mut B,
+    windows: &mut (Vec<u8>, Vec<u8>),
+    len: usize,
+) -> Result<(), Error> {
+    first
+        .read_exact(&mut windows.0[..len])
+        .context("failed to read the first input")?;
+    second
+        .read_exact(&mut windows.1[..len])
+        .context("failed to read the second input")?;
+
+    Ok(())
+}
+
+// Writes everything written to it to both of its writers.
+struct TeeWriter<A: Write, B: Write>(A, B);
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::from_iter_instead_of_collect)]
 impl<TreeR> EmptySectorUpdate<TreeR>
@@ -1060,7 +1161,8 @@ where
         Ok(())
     }
 
-    /// Removes encoded data and outputs the sector_key.
+    /// Removes encoded data and outputs the sector_key. The replica and the data are read and the
+    /// sector key written in windows, rather than mapped in memory.
     pub fn remove_encoded_data(
         nodes_count: usize,
         sector_key_path: &Path,
@@ -1073,6 +1175,39 @@ where
         comm_d_new: TreeDDomain,
         comm_sector_key: TreeRDomain,
         h: usize,
+    ) -> Result<TreeRDomain> {
+        Self::remove_encoded_data_with_sink(
+            nodes_count,
+            sector_key_path,
+            sector_key_cache_path,
+            replica_path,
+            replica_cache_path,
+            data_path,
+            tree_r_last_new_config,
+            comm_c,
+            comm_d_new,
+            comm_sector_key,
+            h,
+            &mut io::sink(),
+        )
+    }
+
+    /// Like `remove_encoded_data`, also writing the sector key to `sink` as it is written to
+    /// `sector_key_path`, e.g. to send it elsewhere without reading it back. The file is still
+    /// written, as tree_r_last is built from it.
+    pub fn remove_encoded_data_with_sink<W: Write + Send>(
+        nodes_count: usize,
+        sector_key_path: &Path,
+        sector_key_cache_path: &Path,
+        replica_path: &Path,
+        replica_cache_path: &Path,
+        data_path: &Path,
+        tree_r_last_new_config: StoreConfig,
+        comm_c: TreeRDomain,
+        comm_d_new: TreeDDomain,
+        comm_sector_key: TreeRDomain,
+        h: usize,
+        sink: &mut W,
     ) -> Result<TreeRDomain> {
         // Sanity check all input path types.
         ensure!(
@@ -1084,9 +1219,6 @@ where
             "replica_cache_path must be a directory"
         );
 
-        let tree_count = get_base_tree_count::<TreeR>();
-        let base_tree_nodes_count = nodes_count / tree_count;
-
         let data_path_metadata = metadata(data_path)?;
         let replica_path_metadata = metadata(replica_path)?;
         let sector_key_path_metadata = metadata(sector_key_path)?;
@@ -1109,6 +1241,10 @@ where
             replica_path_metadata.len() <= data_path_metadata.len(),
             "Replica and data file size mis-match (must be equal or less than)"
         );
+        ensure!(
+            replica_path_metadata.len() == (nodes_count * FR_SIZE) as u64,
+            "Replica file size mis-match (must be that of the sector)"
+        );
 
         info!(
             "data path {:?}, len {}",
@@ -1126,24 +1262,98 @@ where
             sector_key_path_metadata.len()
         );
 
-        // Setup writable mmap for new_replica_path output.
-        let mut sector_key_data = mmap_write(sector_key_path)?;
+        let data = File::open(data_path)
+            .with_context(|| format!("could not open path={:?}", data_path))?;
+        let sector_key = OpenOptions::new()
+            .write(true)
+            .open(sector_key_path)
+            .with_context(|| format!("could not open path={:?}", sector_key_path))?;
+        Self::write_sector_key(
+            nodes_count,
+            sector_key,
+            replica_path,
+            data,
+            sink,
+            comm_c,
+            comm_d_new,
+            comm_sector_key,
+            h,
+        )?;
 
-        // Setup read-only mmaps for sector_key_path and staged_data_path inputs.
-        let replica_data = mmap_read(replica_path)?;
-        let data = mmap_read(data_path)?;
+        let sector_key_data = mmap_read(sector_key_path)?;
+        Self::sector_key_tree_r_last(
+            nodes_count,
+            &sector_key_data,
+            sector_key_cache_path,
+            tree_r_last_new_config,
+        )
+    }
 
-        let comm_r_old = <TreeRHasher as Hasher>::Function::hash2(&comm_c, &comm_sector_key);
-        let phi = phi(&comm_d_new, &comm_r_old);
+    /// Like `remove_encoded_data`, with the data read from `data` rather than from a file.
+    pub fn remove_encoded_data_from_reader<R: Read + Send>(
+        nodes_count: usize,
+        sector_key_path: &Path,
+        sector_key_cache_path: &Path,
+        replica_path: &Path,
+        data: R,
+        tree_r_last_new_config: StoreConfig,
+        comm_c: TreeRDomain,
+        comm_d_new: TreeDDomain,
+        comm_sector_key: TreeRDomain,
+        h: usize,
+    ) -> Result<TreeRDomain> {
+        ensure!(
+            metadata(sector_key_cache_path)?.is_dir(),
+            "sector_key_cache_path must be a directory"
+        );
+        let replica_path_metadata = metadata(replica_path)?;
+        ensure!(
+            replica_path_metadata.is_file(),
+            "replica_path must be a file"
+        );
+        ensure!(
+            replica_path_metadata.len() == (nodes_count * FR_SIZE) as u64,
+            "Replica file size mis-match (must be that of the sector)"
+        );
 
-        let end = replica_path_metadata.len() as u64;
+        let sector_key = File::create(sector_key_path)
+            .with_context(|| format!("could not create path={:?}", sector_key_path))?;
+        Self::write_sector_key(
+            nodes_count,
+            sector_key,
+            replica_path,
+            data,
+            &mut io::sink(),
+            comm_c,
+            comm_d_new,
+            comm_sector_key,
+            h,
+        )?;
 
-        // chunk_size is the number of Fr elements to process in parallel chunks.
-        let chunk_size: usize = std::cmp::min(base_tree_nodes_count, CHUNK_SIZE_MIN);
+        let sector_key_data = mmap_read(sector_key_path)?;
+        Self::sector_key_tree_r_last(
+            nodes_count,
+            &sector_key_data,
+            sector_key_cache_path,
+            tree_r_last_new_config,
+        )
+    }
 
-        // data_block_size is the segment length that we're processing
-        // in Fr elements (i.e. chunk_size * sizeof(Fr)).
-        let data_block_size: usize = chunk_size * FR_SIZE;
+    // Streams the sector key of the replica at `replica_path`, whose deal data is read from
+    // `data`, to both `sector_key` and `sink`, and syncs `sector_key`.
+    fn write_sector_key<R: Read + Send, W: Write + Send>(
+        nodes_count: usize,
+        sector_key: File,
+        replica_path: &Path,
+        data: R,
+        sink: &mut W,
+        comm_c: TreeRDomain,
+        comm_d_new: TreeDDomain,
+        comm_sector_key: TreeRDomain,
+        h: usize,
+    ) -> Result<()> {
+        let comm_r_old = <TreeRHasher as Hasher>::Function::hash2(&comm_c, &comm_sector_key);
+        let phi = phi(&comm_d_new, &comm_r_old);
 
         // Right-shift each node-index by `get_high_bits_shr` to get its `h` high bits.
         let node_index_bit_len = nodes_count.trailing_zeros() as usize;
@@ -1152,37 +1362,99 @@ where
         // Precompute all rho values.
         let rhos = rhos(h, &phi);
 
-        Vec::from_iter((0..end).step_by(data_block_size))
-            .into_par_iter()
-            .zip(sector_key_data.par_chunks_mut(data_block_size))
-            .try_for_each(|(chunk_index, skey_data)| -> Result<()> {
-                for i in (0..data_block_size as u64).step_by(FR_SIZE) {
-                    let input_index = (chunk_index as usize) + i as usize;
-                    let output_index = i as usize;
+        let replica = File::open(replica_path)
+            .with_context(|| format!("could not open path={:?}", replica_path))?;
+        let mut out = TeeWriter(BufWriter::new(&sector_key), sink);
+        map_node_streams(
+            nodes_count,
+            replica,
+            data,
+            &mut out,
+            |node_index, replica_fr, data_fr| {
+                replica_fr - (data_fr * rhos[node_index >> get_high_bits_shr])
+            },
+        )?;
+        out.flush()?;
+        sector_key.sync_all()?;
 
-                    // Get the `h` high bits from the node-index.
-                    let node_index = input_index / FR_SIZE;
-                    let high = node_index >> get_high_bits_shr;
-                    let rho = rhos[high];
+        Ok(())
+    }
 
-                    let data_fr = bytes_into_fr(&data[input_index..input_index + FR_SIZE])?;
-                    let replica_data_fr =
-                        bytes_into_fr(&replica_data[input_index..input_index + FR_SIZE])?;
+    /// Like `decode_from`, writing the decoded data to `out` rather than to a file. The replica and
+    /// the sector key are read in windows, rather than mapped in memory.
+    pub fn decode_into<W: Write + Send>(
+        nodes_count: usize,
+        out: &mut W,
+        replica_path: &Path,
+        sector_key_path: &Path,
+        comm_c: TreeRDomain,
+        comm_d_new: TreeDDomain,
+        comm_sector_key: TreeRDomain,
+        h: usize,
+    ) -> Result<()> {
+        let replica_path_metadata = metadata(replica_path)?;
+        let sector_key_path_metadata = metadata(sector_key_path)?;
+        ensure!(
+            replica_path_metadata.is_file(),
+            "replica_path must be a file"
+        );
+        ensure!(
+            sector_key_path_metadata.is_file(),
+            "sector_key_path must be a file"
+        );
+        ensure!(
+            replica_path_metadata.len() == sector_key_path_metadata.len(),
+            "Replica and sector key file size mis-match (must be equal)"
+        );
+        ensure!(
+            replica_path_metadata.len() == (nodes_count * FR_SIZE) as u64,
+            "Replica file size mis-match (must be that of the sector)"
+        );
 
-                    let sector_key_fr = replica_data_fr - (data_fr * rho);
-                    fr_into_bytes_slice(
-                        &sector_key_fr,
-                        &mut skey_data[output_index..output_index + FR_SIZE],
-                    );
-                }
+        let comm_r_old = <TreeRHasher as Hasher>::Function::hash2(&comm_c, &comm_sector_key);
+        let phi = phi(&comm_d_new, &comm_r_old);
 
-                Ok(())
-            })?;
-        sector_key_data.flush()?;
+        // Right-shift each node-index by `get_high_bits_shr` to get its `h` high bits.
+        let node_index_bit_len = nodes_count.trailing_zeros() as usize;
+        let get_high_bits_shr = node_index_bit_len - h;
+
+        // Precompute all rho^-1 values.
+        let rho_invs: Vec<Fr> = rhos(h, &phi)
+            .into_iter()
+            .map(|rho| rho.invert().unwrap())
+            .collect();
+
+        let replica = File::open(replica_path)
+            .with_context(|| format!("could not open path={:?}", replica_path))?;
+        let sector_key = File::open(sector_key_path)
+            .with_context(|| format!("could not open path={:?}", sector_key_path))?;
+        map_node_streams(
+            nodes_count,
+            replica,
+            sector_key,
+            out,
+            |node_index, replica_fr, sector_key_fr| {
+                (replica_fr - sector_key_fr) * rho_invs[node_index >> get_high_bits_shr]
+            },
+        )?;
+        out.flush()?;
+
+        Ok(())
+    }
+
+    // Builds the tree_r_last of the regenerated sector key and returns its root.
+    fn sector_key_tree_r_last(
+        nodes_count: usize,
+        sector_key_data: &[u8],
+        sector_key_cache_path: &Path,
+        tree_r_last_new_config: StoreConfig,
+    ) -> Result<TreeRDomain> {
+        let tree_count = get_base_tree_count::<TreeR>();
+        let base_tree_nodes_count = nodes_count / tree_count;
 
         // Open the new written sector_key data as a DiskStore.
         let sector_key_store: DiskStore<TreeRDomain> =
-            DiskStore::new_from_slice(nodes_count, &sector_key_data[0..])?;
+            DiskStore::new_from_slice(nodes_count, sector_key_data)?;
 
         // This argument is currently unused by this invocation, but required for the API.
         let mut unused_data = Data::empty();